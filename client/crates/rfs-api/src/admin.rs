@@ -0,0 +1,86 @@
+// API di amministrazione del server: utenti e gruppi. Il server le riserva all'amministratore
+// (403 per gli altri utenti) e le annuncia con la capability `admin`.
+
+use crate::HttpBackend;
+use reqwest::Method;
+use rfs_models::BackendError;
+use serde::Deserialize;
+use serde::de::IgnoredAny;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdminUser {
+    pub uid: u32,
+    /// gruppo dell'utente, se ne ha uno
+    #[serde(default)]
+    pub gid: Option<u32>,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdminGroup {
+    pub gid: u32,
+    /// uid dei membri
+    #[serde(default)]
+    pub members: Vec<u32>,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl HttpBackend {
+    pub fn list_users(&mut self) -> Result<Vec<AdminUser>, BackendError> {
+        self.require(self.capabilities.admin, "admin")?;
+        self.request_response::<Vec<AdminUser>, ()>(Method::GET, "api/admin/users", None)
+    }
+
+    /// Crea un utente con la sua home directory, eventualmente già in un gruppo esistente
+    pub fn add_user(&mut self, uid: u32, password: &str, gid: Option<u32>) -> Result<(), BackendError> {
+        self.require(self.capabilities.admin, "admin")?;
+        let body = serde_json::json!({ "uid": uid, "password": password, "gid": gid });
+        self.request_response::<IgnoredAny, _>(Method::POST, "api/admin/users", Some(&body)).map(|_| ())
+    }
+
+    /// Rimuove un utente; il server rifiuta (Conflict) se possiede ancora dei file
+    pub fn delete_user(&mut self, uid: u32) -> Result<(), BackendError> {
+        self.require(self.capabilities.admin, "admin")?;
+        self.request_response::<IgnoredAny, ()>(Method::DELETE, &format!("api/admin/users/{}", uid), None).map(|_| ())
+    }
+
+    /// Assegna il nome mostrato al posto dell'uid; None lo toglie
+    pub fn set_user_name(&mut self, uid: u32, name: Option<&str>) -> Result<(), BackendError> {
+        self.require(self.capabilities.admin, "admin")?;
+        let body = serde_json::json!({ "name": name });
+        self.request_response::<IgnoredAny, _>(Method::PUT, &format!("api/admin/users/{}/name", uid), Some(&body)).map(|_| ())
+    }
+
+    pub fn list_groups(&mut self) -> Result<Vec<AdminGroup>, BackendError> {
+        self.require(self.capabilities.admin, "admin")?;
+        self.request_response::<Vec<AdminGroup>, ()>(Method::GET, "api/admin/groups", None)
+    }
+
+    pub fn add_group(&mut self, gid: u32) -> Result<(), BackendError> {
+        self.require(self.capabilities.admin, "admin")?;
+        let body = serde_json::json!({ "gid": gid });
+        self.request_response::<IgnoredAny, _>(Method::POST, "api/admin/groups", Some(&body)).map(|_| ())
+    }
+
+    /// Assegna il nome mostrato al posto del gid; None lo toglie
+    pub fn set_group_name(&mut self, gid: u32, name: Option<&str>) -> Result<(), BackendError> {
+        self.require(self.capabilities.admin, "admin")?;
+        let body = serde_json::json!({ "name": name });
+        self.request_response::<IgnoredAny, _>(Method::PUT, &format!("api/admin/groups/{}/name", gid), Some(&body)).map(|_| ())
+    }
+
+    /// Rimuove un gruppo; il server rifiuta (Conflict) se è ancora assegnato a dei file
+    pub fn delete_group(&mut self, gid: u32) -> Result<(), BackendError> {
+        self.require(self.capabilities.admin, "admin")?;
+        self.request_response::<IgnoredAny, ()>(Method::DELETE, &format!("api/admin/groups/{}", gid), None).map(|_| ())
+    }
+
+    /// Aggiunge (`member`) o toglie un utente da un gruppo
+    pub fn set_group_member(&mut self, gid: u32, uid: u32, member: bool) -> Result<(), BackendError> {
+        self.require(self.capabilities.admin, "admin")?;
+        let method = if member { Method::PUT } else { Method::DELETE };
+        self.request_response::<IgnoredAny, ()>(method, &format!("api/admin/groups/{}/members/{}", gid, uid), None).map(|_| ())
+    }
+}
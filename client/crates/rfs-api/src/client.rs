@@ -0,0 +1,206 @@
+// Client asincrono per i programmi che non montano il filesystem (sottocomandi come `ls` e `cp`, o altri
+// programmi Rust): lavora per path remoti invece che per ino e non dipende da fuse o WinFsp. Ogni chiamata
+// usa un backend indipendente sul runtime dell'HttpBackend, così più operazioni procedono in parallelo.
+
+use crate::HttpBackend;
+use rfs_models::{BackendError, EntryType, FileEntry, OwnerNames, ROOT_INO, RemoteBackend, SetAttrRequest};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Byte letti o scritti con una singola richiesta da upload e download
+pub const TRANSFER_CHUNK: u64 = 4 * 1024 * 1024;
+
+#[derive(Clone)]
+pub struct RfsClient {
+    base: Arc<HttpBackend>,
+}
+
+// path remoto diviso in directory padre e nome dell'ultimo componente
+fn split_path(path: &str) -> Result<(&str, &str), BackendError> {
+    let path = path.trim_end_matches('/');
+    match path.rsplit_once('/') {
+        Some((parent, name)) if !name.is_empty() => Ok((if parent.is_empty() { "/" } else { parent }, name)),
+        _ => Err(BackendError::Other(format!("{:?} is not a path inside the remote filesystem", path))),
+    }
+}
+
+// voce di un path remoto, risolto componente per componente dalla root
+fn resolve(backend: &mut HttpBackend, path: &str) -> Result<FileEntry, BackendError> {
+    let mut entry = backend.get_attr(ROOT_INO)?;
+    for name in path.split('/').filter(|c| !c.is_empty()) {
+        entry = backend.lookup(entry.ino, name)?;
+    }
+    Ok(entry)
+}
+
+// cancella una directory dopo il suo contenuto
+fn remove_tree(backend: &mut HttpBackend, dir: &FileEntry) -> Result<(), BackendError> {
+    for child in backend.list_dir(dir.ino)? {
+        if child.kind == EntryType::Directory {
+            remove_tree(backend, &child)?;
+            backend.delete_dir(dir.ino, &child.name)?;
+        } else {
+            backend.delete_file(dir.ino, &child.name)?;
+        }
+    }
+    Ok(())
+}
+
+impl RfsClient {
+    /// Client che usa la sessione di `backend` (dopo handshake e login)
+    pub fn new(backend: HttpBackend) -> Self {
+        Self { base: Arc::new(backend) }
+    }
+
+    // esegue `op` su un backend dedicato, fuori dai thread del runtime
+    async fn run<R: Send + 'static>(&self, op: impl FnOnce(&mut HttpBackend) -> Result<R, BackendError> + Send + 'static) -> Result<R, BackendError> {
+        let mut backend = self.base.fetcher();
+        self.base.runtime.spawn_blocking(move || op(&mut backend)).await
+            .map_err(|e| BackendError::Other(format!("client task failed: {}", e)))?
+    }
+
+    pub async fn stat(&self, path: &str) -> Result<FileEntry, BackendError> {
+        let path = path.to_string();
+        self.run(move |backend| resolve(backend, &path)).await
+    }
+
+    /// Voci di una directory; per un file restituisce solo la sua voce, come `ls`
+    pub async fn list(&self, path: &str) -> Result<Vec<FileEntry>, BackendError> {
+        let path = path.to_string();
+        self.run(move |backend| {
+            let entry = resolve(backend, &path)?;
+            if entry.kind == EntryType::Directory { backend.list_dir(entry.ino) } else { Ok(vec![entry]) }
+        }).await
+    }
+
+    /// Nomi di utenti e gruppi del server; senza nomi (o con un errore) tutti restano numerici
+    pub async fn owner_names(&self) -> OwnerNames {
+        self.run(|backend| backend.owner_names()).await.unwrap_or_default()
+    }
+
+    pub async fn mkdir(&self, path: &str) -> Result<FileEntry, BackendError> {
+        let path = path.to_string();
+        self.run(move |backend| {
+            let (parent, name) = split_path(&path)?;
+            let parent = resolve(backend, parent)?;
+            backend.create_dir(parent.ino, name)
+        }).await
+    }
+
+    /// Crea un file vuoto; se esiste già viene troncato
+    pub async fn create(&self, path: &str) -> Result<FileEntry, BackendError> {
+        let path = path.to_string();
+        self.run(move |backend| {
+            let (parent, name) = split_path(&path)?;
+            let parent = resolve(backend, parent)?;
+            match backend.lookup(parent.ino, name) {
+                Ok(existing) if existing.kind == EntryType::Directory => Err(BackendError::IsADirectory(path.clone())),
+                Ok(existing) => backend.set_attr(existing.ino, SetAttrRequest { size: Some(0), ..SetAttrRequest::default() }),
+                Err(BackendError::NotFound(_)) => backend.create_file(parent.ino, name),
+                Err(e) => Err(e),
+            }
+        }).await
+    }
+
+    /// Fino a `size` byte del file `ino` a partire da `offset`
+    pub async fn read_at(&self, ino: u64, offset: u64, size: u64) -> Result<Vec<u8>, BackendError> {
+        self.run(move |backend| backend.read_chunk(ino, offset, size)).await
+    }
+
+    pub async fn write_at(&self, ino: u64, offset: u64, data: Vec<u8>) -> Result<u64, BackendError> {
+        self.run(move |backend| backend.write_chunk(ino, offset, data)).await
+    }
+
+    /// Porta il file `ino` a `size` byte, troncandolo o estendendolo con zeri
+    pub async fn set_size(&self, ino: u64, size: u64) -> Result<FileEntry, BackendError> {
+        self.run(move |backend| backend.set_attr(ino, SetAttrRequest { size: Some(size), ..SetAttrRequest::default() })).await
+    }
+
+    /// Imposta la data di modifica di un path remoto
+    pub async fn set_mtime(&self, path: &str, mtime: SystemTime) -> Result<FileEntry, BackendError> {
+        let path = path.to_string();
+        self.run(move |backend| {
+            let entry = resolve(backend, &path)?;
+            backend.set_attr(entry.ino, SetAttrRequest { mtime: Some(mtime), ..SetAttrRequest::default() })
+        }).await
+    }
+
+    /// SHA-256 (esadecimale) dei blocchi `blocks` di `block_size` byte; None se il server non li calcola
+    pub async fn block_hashes(&self, ino: u64, block_size: u64, blocks: Vec<u64>) -> Result<Option<HashMap<u64, String>>, BackendError> {
+        self.run(move |backend| backend.block_hashes(ino, block_size, &blocks)).await
+    }
+
+    /// Scarica un file remoto in `local`; restituisce i byte scritti
+    pub async fn download(&self, path: &str, local: PathBuf) -> Result<u64, BackendError> {
+        let path = path.to_string();
+        self.run(move |backend| {
+            let entry = resolve(backend, &path)?;
+            if entry.kind == EntryType::Directory {
+                return Err(BackendError::IsADirectory(path));
+            }
+            let mut file = File::create(&local).map_err(|e| BackendError::Other(format!("{}: {}", local.display(), e)))?;
+            let mut offset = 0;
+            loop {
+                let data = backend.read_chunk(entry.ino, offset, TRANSFER_CHUNK)?;
+                if data.is_empty() {
+                    break;
+                }
+                file.write_all(&data).map_err(|e| BackendError::Other(format!("{}: {}", local.display(), e)))?;
+                offset += data.len() as u64;
+            }
+            Ok(offset)
+        }).await
+    }
+
+    /// Carica `local` nel path remoto, creandolo o sostituendone il contenuto; restituisce i byte inviati
+    pub async fn upload(&self, local: PathBuf, path: &str) -> Result<u64, BackendError> {
+        let entry = self.create(path).await?;
+        self.run(move |backend| {
+            let mut file = File::open(&local).map_err(|e| BackendError::Other(format!("{}: {}", local.display(), e)))?;
+            let mut offset = 0;
+            loop {
+                let mut chunk = Vec::with_capacity(TRANSFER_CHUNK as usize);
+                let read = Read::by_ref(&mut file).take(TRANSFER_CHUNK).read_to_end(&mut chunk)
+                    .map_err(|e| BackendError::Other(format!("{}: {}", local.display(), e)))?;
+                if read == 0 {
+                    break;
+                }
+                backend.write_chunk(entry.ino, offset, chunk)?;
+                offset += read as u64;
+            }
+            Ok(offset)
+        }).await
+    }
+
+    /// Cancella un file o una directory; una directory non vuota solo con `recursive`
+    pub async fn remove(&self, path: &str, recursive: bool) -> Result<(), BackendError> {
+        let path = path.to_string();
+        self.run(move |backend| {
+            let (parent, name) = split_path(&path)?;
+            let parent = resolve(backend, parent)?;
+            let entry = backend.lookup(parent.ino, name)?;
+            if entry.kind != EntryType::Directory {
+                return backend.delete_file(parent.ino, name);
+            }
+            if recursive {
+                remove_tree(backend, &entry)?;
+            }
+            backend.delete_dir(parent.ino, name)
+        }).await
+    }
+
+    pub async fn rename(&self, from: &str, to: &str) -> Result<FileEntry, BackendError> {
+        let (from, to) = (from.to_string(), to.to_string());
+        self.run(move |backend| {
+            let (old_parent, old_name) = split_path(&from)?;
+            let (new_parent, new_name) = split_path(&to)?;
+            let old_parent = resolve(backend, old_parent)?;
+            let new_parent = resolve(backend, new_parent)?;
+            backend.rename(old_parent.ino, old_name, new_parent.ino, new_name)
+        }).await
+    }
+}
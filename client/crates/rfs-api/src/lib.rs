@@ -1,20 +1,34 @@
 use httpdate::fmt_http_date;
 use reqwest::cookie::Jar;
 use reqwest::header::{self, HeaderMap, HeaderValue, CONTENT_TYPE};
-use reqwest::{Client, Method, Response, StatusCode, Url, Body};
-use rfs_models::{BackendError, EntryType, FileEntry, RemoteBackend, SetAttrRequest};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode, Url, Body};
+use rfs_models::{BackendError, BatchWrite, CancellationToken, Capabilities, name_matches, DirPage, DiskUsage, DIR_PAGE_SIZE, EntryType, FileEntry, Identity, Lease, LeaseKind, ByteLock, LockKind, OwnerNames, RemoteBackend, SearchQuery, ServerLimits, SetAttrRequest, to_millis};
 use rpassword::read_password;
 use serde::de::DeserializeOwned;
-use serde::{Deserialize, Deserializer, Serialize};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::io::Cursor;
+use lru::LruCache;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use std::str::{ FromStr};
-use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::runtime::Runtime;
 use tokio_stream::StreamExt;
-use tokio_util::io::ReaderStream;
+
+mod admin;
+mod client;
+mod limits;
+mod parse;
+pub mod session;
+
+pub use admin::{AdminGroup, AdminUser};
+pub use client::{RfsClient, TRANSFER_CHUNK};
+pub use limits::RequestLimits;
+
+use parse::{ACCEPT_BODIES, MSGPACK, Wire, default_nlinks, default_time, lenient_millis, lenient_u64, parse_body};
 
 
 #[derive(Deserialize, Debug)]
@@ -30,24 +44,34 @@ pub struct Credentials {
 
 #[derive(Deserialize,Debug)]
 struct FileServerResponse {
-    ino: String,
+    #[serde(deserialize_with = "lenient_u64")]
+    ino: u64,
     path: PathBuf,
     name:String,
     owner: u32,
+    #[serde(default)]
     group: Option<u32>,
     #[serde(rename = "type")]
     kind: EntryType,
     permissions: u16,
-    size: String,
+    #[serde(deserialize_with = "lenient_u64")]
+    size: u64,
+    #[serde(default = "default_nlinks")]
     nlinks:u32,
-    #[serde(deserialize_with = "deserialize_systemtime_from_millis")]
+    #[serde(default = "default_time", deserialize_with = "lenient_millis")]
     atime: SystemTime,
-    #[serde(deserialize_with = "deserialize_systemtime_from_millis")]
+    #[serde(deserialize_with = "lenient_millis")]
     mtime: SystemTime,
-    #[serde(deserialize_with = "deserialize_systemtime_from_millis")]
+    #[serde(default = "default_time", deserialize_with = "lenient_millis")]
     ctime: SystemTime,
-    #[serde(deserialize_with = "deserialize_systemtime_from_millis")]
+    #[serde(default = "default_time", deserialize_with = "lenient_millis")]
     btime: SystemTime,
+    #[serde(default)]
+    etag: Option<String>,
+    #[serde(default)]
+    flags: u32,
+    #[serde(default, rename = "fileFlags")]
+    file_flags: u32,
 }
 
 #[derive(Deserialize,Debug)]
@@ -55,106 +79,475 @@ struct ReadLinkResponse {
     target: String,
 }
 
+#[derive(Deserialize,Debug)]
+struct WriteResponse {
+    #[serde(default, deserialize_with = "lenient_u64")]
+    bytes: u64,
+}
+
+#[derive(Deserialize)]
+struct NamedUser {
+    uid: u32,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct NamedGroup {
+    gid: u32,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct NamesResponse {
+    #[serde(default)]
+    users: Vec<NamedUser>,
+    #[serde(default)]
+    groups: Vec<NamedGroup>,
+}
+
+// esito di una write di POST /api/batch, con lo stato HTTP che avrebbe avuto da sola
+#[derive(Deserialize,Debug)]
+struct BatchItemResponse {
+    status: u16,
+    #[serde(default, deserialize_with = "lenient_u64")]
+    bytes: u64,
+    #[serde(default)]
+    etag: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Deserialize,Debug)]
+struct BatchResponse {
+    results: Vec<BatchItemResponse>,
+}
+
+#[derive(Deserialize,Debug)]
+struct AppendResponse {
+    #[serde(deserialize_with = "lenient_u64")]
+    offset: u64,
+}
+
+#[derive(Deserialize,Debug)]
+struct LeaseResponse {
+    ttl: u64, // millisecondi
+}
+
+#[derive(Deserialize,Debug)]
+struct LockTestResponse {
+    conflict: Option<LockHolder>,
+}
+
+// lock di un altro client che impedisce quello chiesto
+#[derive(Deserialize,Debug)]
+struct LockHolder {
+    #[serde(rename = "type")]
+    kind: String,
+    start: u64,
+    end: Option<u64>, // null = fino alla fine del file
+    pid: u32,
+}
+
+#[derive(Deserialize,Debug)]
+struct RecallResponse {
+    recalled: Vec<String>,
+}
+
+#[derive(Deserialize,Debug)]
+struct BlockHashesResponse {
+    hashes: HashMap<String, String>,
+}
+
 #[derive(Deserialize,Debug)]
 struct SizeResponse {
     total: u64,
     available: u64,
 }
 
+// ino di cui si ricorda l'etag; per quelli scartati le scritture partono senza If-Match
+const ETAG_CACHE_SIZE: usize = 65_536;
+
+fn etag_cache() -> LruCache<u64, String> {
+    LruCache::new(NonZeroUsize::new(ETAG_CACHE_SIZE).expect("non-zero capacity"))
+}
+
 pub struct HttpBackend {
     runtime: Arc<Runtime>, // from tokio, used to manage async calls
     base_url: Url,
     client: Client,
-    credentials: Credentials
+    cookies: Arc<Jar>, // cookie di sessione, da riusare se il client va ricostruito
+    credentials: Credentials,
+    etags: LruCache<u64, String>, // ultimo etag visto per gli ino usati di recente, inviato come If-Match sulle scritture
+    cancel: Option<CancellationToken>, // token della richiesta FUSE in corso, per interromperla
+    capabilities: Capabilities, // funzionalità del server, aggiornate dall'handshake
+    identity: Option<Identity>, // utente della sessione e suoi gruppi, letti al mount
+    read_only: bool, // mount in sola lettura: le modifiche vengono rifiutate prima di arrivare in rete
+    session_expires: SessionExpiry, // scadenza del cookie di sessione, dall'ultimo Set-Cookie del server
+    relogin: Relogin, // ultimo login rifatto, condiviso perché più 401 contemporanei facciano un solo login
+    logins: Arc<AtomicU64>, // login rifatti finora: a ogni nuova sessione il server ha dimenticato i lease
+    limits: Option<RequestLimits>, // richieste in volo al massimo, condivise coi fetcher; None = senza limiti
+    mandatory_locks: bool, // le write su intervalli bloccati da altri client vengono rifiutate dal server
+}
+
+// Ultimo login rifatto dopo un 401: quando è partito e com'è andato (Err(None): credenziali rifiutate).
+// Il lock resta preso durante il login, così chi riceve un 401 mentre un altro thread sta già rifacendo
+// il login ne aspetta l'esito e lo condivide, riuscito o fallito, invece di ripeterlo
+#[derive(Default)]
+struct ReloginState {
+    started: Option<Instant>,
+    outcome: Option<Result<(), Option<String>>>,
+}
+
+type Relogin = Arc<tokio::sync::Mutex<ReloginState>>;
+
+// scadenza della sessione condivisa tra il backend, i suoi fetcher e il thread che la rinnova
+type SessionExpiry = Arc<Mutex<Option<SystemTime>>>;
+
+// aggiorna la scadenza della sessione con il cookie connect.sid della risposta, se c'è
+fn note_session(expiry: &Mutex<Option<SystemTime>>, resp: &Response) {
+    let Some(cookie) = resp.cookies().find(|c| c.name() == "connect.sid") else { return };
+    if let Some(expires) = cookie.max_age().map(|age| SystemTime::now() + age).or(cookie.expires()) {
+        *expiry.lock().expect("Mutex poisoned") = Some(expires);
+    }
+}
+
+// login con le credenziali del mount; il nuovo cookie finisce nel jar condiviso dal client. Se un altro
+// thread ha rifatto il login dopo `since` (l'invio della richiesta rifiutata) vale il suo esito
+async fn login_with(client: &Client, base_url: &Url, credentials: &Credentials, expiry: &Mutex<Option<SystemTime>>, relogin: &tokio::sync::Mutex<ReloginState>, logins: &AtomicU64, since: Instant) -> Result<(), BackendError> {
+    let mut state = relogin.lock().await;
+    if let (Some(started), Some(outcome)) = (state.started, state.outcome.as_ref()) && started >= since {
+        return outcome.clone().map_err(|e| e.map_or(BackendError::Unauthorized, BackendError::Other));
+    }
+    state.started = Some(Instant::now());
+    let login_url = base_url.join("api/login").map_err(|e| BackendError::Other(e.to_string()))?;
+    let outcome = match client.post(login_url).header(REQUEST_ID_HEADER, new_request_id()).json(credentials).send().await {
+        Ok(resp) if resp.status() == StatusCode::OK => {
+            note_session(expiry, &resp);
+            logins.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+        Ok(resp) if resp.status() == StatusCode::UNAUTHORIZED => Err(None),
+        Ok(resp) => Err(Some(format!("HTTP {}", resp.status()))),
+        Err(e) => Err(Some(e.to_string())),
+    };
+    state.outcome = Some(outcome.clone());
+    outcome.map_err(|e| e.map_or(BackendError::Unauthorized, BackendError::Other))
+}
+
+// ogni quanto il thread della sessione controlla la scadenza, e quanto prima della scadenza la rinnova
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const SESSION_REFRESH_MARGIN: Duration = Duration::from_secs(10 * 60);
+
+// Ascolta i recall dei lease con una long-poll; condivide client e cookie di sessione con l'HttpBackend
+pub struct RecallListener {
+    runtime: Arc<Runtime>,
+    base_url: Url,
+    client: Client,
+}
+
+impl RecallListener {
+    /// Blocca finché il server non richiama almeno un lease (o scade la long-poll) e restituisce gli ino richiamati
+    pub fn wait(&self) -> Result<Vec<u64>, BackendError> {
+        let endpoint = "api/leases/recalls";
+        let url = self.base_url.join(endpoint).map_err(|e| BackendError::Other(e.to_string()))?;
+        let id = new_request_id();
+        let req = self.client.get(url).header(REQUEST_ID_HEADER, id.as_str());
+        let resp = self.runtime.block_on(async { req.send().await }).map_err(|e| BackendError::Other(format!("{} (request {})", e, id)))?;
+        match resp.status() {
+            StatusCode::OK => {
+                let wire = wire_of(&resp);
+                let body = self.runtime.block_on(async { resp.bytes().await }).map_err(|e| BackendError::Other(e.to_string()))?;
+                let r: RecallResponse = parse_body(&format!("{} (request {})", endpoint, id), wire, &body)?;
+                Ok(r.recalled.iter().filter_map(|ino| ino.parse::<u64>().ok()).collect())
+            }
+            StatusCode::UNAUTHORIZED => Err(BackendError::Unauthorized),
+            StatusCode::NOT_FOUND => Err(BackendError::NotFound(endpoint.to_string())),
+            other => Err(BackendError::Other(format!("HTTP {} (request {})", other, request_id(&resp)))),
+        }
+    }
+}
+
+/// Perché un login non è riuscito: chi monta distingue le cause con codici di uscita diversi
+#[derive(Debug)]
+pub enum LoginError {
+    /// indirizzo del server non valido
+    InvalidAddress(String),
+    /// server irraggiungibile (DNS, connessione rifiutata, timeout)
+    Unreachable(String),
+    /// credenziali rifiutate
+    Rejected(String),
+    /// il server rifiuta i login dell'utente per troppi tentativi falliti
+    Locked(String),
+    Other(String),
+}
+
+impl std::fmt::Display for LoginError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginError::InvalidAddress(msg) | LoginError::Unreachable(msg) | LoginError::Rejected(msg) | LoginError::Locked(msg) | LoginError::Other(msg) => f.write_str(msg),
+        }
+    }
+}
+
+// tentativi di login rimasti prima che il server blocchi l'utente
+const ATTEMPTS_LEFT_HEADER: &str = "login-attempts-remaining";
+
+// chiede un valore sul terminale; le password senza eco
+fn prompt(label: &str, secret: bool) -> Result<String, LoginError> {
+    use std::io::{stdin, stdout, Write};
+
+    print!("{}", label);
+    stdout().flush().ok();
+    if secret {
+        let password = read_password().map_err(|e| LoginError::Other(format!("Failed to read password: {}", e)))?;
+        println!();
+        return Ok(password);
+    }
+    let mut value = String::new();
+    stdin().read_line(&mut value).map_err(|e| LoginError::Other(format!("Failed to read username: {}", e)))?;
+    Ok(value.trim().to_owned())
+}
+
+// messaggio di un login rifiutato con 429: quello del server e il tempo di attesa di Retry-After
+fn lockout_message(rt: &Runtime, resp: Response) -> String {
+    let retry = resp.headers().get(header::RETRY_AFTER).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u64>().ok());
+    let message = rt.block_on(resp.json::<Value>()).ok()
+        .and_then(|body| body.get("message").and_then(|m| m.as_str()).map(str::to_string))
+        .unwrap_or_else(|| "Too many failed logins".to_string());
+    match retry {
+        Some(secs) if !message.contains("retry") => format!("{} (retry in {} seconds)", message, secs),
+        _ => message,
+    }
+}
+
+/// Controlla l'indirizzo di un server: URL http o https con un host
+pub fn parse_address(address: &str) -> Result<Url, String> {
+    let url = Url::from_str(address).map_err(|e| format!("Invalid server address {}: {}", address, e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("Invalid server address {}: the scheme must be http or https", address));
+    }
+    if url.host_str().is_none_or(|host| host.is_empty()) {
+        return Err(format!("Invalid server address {}: missing host", address));
+    }
+    Ok(url)
 }
 
 impl Credentials {
 
-    pub fn first_authentication(address: &str) -> Result<(Credentials, String), String> {
-        use std::io::{stdin, stdout, Write};
+    // credenziali di una sessione ripresa da disco: la password non viene salvata
+    pub(crate) fn resumed(username: String) -> Self {
+        Self { username, password: String::new() }
+    }
+
+    pub fn first_authentication(address: &str) -> Result<(Credentials, session::StoredSession), LoginError> {
+        Self::authenticate_as(address, None, None)
+    }
+
+    /// Login interattivo; con `username` viene chiesta solo la password di quell'utente. Con `password`
+    /// (letta da stdin negli script) non viene chiesto nulla e le credenziali si provano una volta sola
+    pub fn authenticate_as(address: &str, username: Option<&str>, password: Option<String>) -> Result<(Credentials, session::StoredSession), LoginError> {
         use std::time::Duration;
 
-        let base_url = Url::from_str(address).map_err(|e| format!("Invalid base URL: {e}"))?;
-        let login_url = base_url.join("api/login").expect("Invalid login URL");
+        let base_url = parse_address(address).map_err(LoginError::InvalidAddress)?;
+        let login_url = base_url.join("api/login").map_err(|e| LoginError::InvalidAddress(e.to_string()))?;
         let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("Unable to build a Runtime object");
         let client = Client::builder().timeout(Duration::from_secs(15)).build().expect("Failed to create HTTP client");
 
-        let mut username = String::new();
-        print!("username: ");
-        stdout().flush().ok();
-        stdin().read_line(&mut username).expect("Failed to read username");
-        username = username.trim().to_owned();
-
-        print!("password: ");
-        stdout().flush().ok();
-        let mut password = read_password().expect("Failed to read password");
-        println!();
+        let fixed_user = username.is_some();
+        let scripted = password.is_some();
+        let mut username = match username {
+            Some(user) => user.to_string(),
+            None => prompt("username: ", false)?,
+        };
+        let mut password = match password {
+            Some(password) => password,
+            None if fixed_user => prompt(&format!("password for {}: ", username), true)?,
+            None => prompt("password: ", true)?,
+        };
 
         const MAX_ATTEMPTS: u8 = 3;
-        let mut attempts: u8 = 0;
+        let (mut rejected, mut unreachable) = (0u8, 0u8);
         loop {
-            attempts += 1;
-
-            let resp = match rt.block_on(async {client.post(login_url.clone()).json(&serde_json::json!({ "username": username, "password": password })).send().await}) {
+            let resp = match rt.block_on(async {client.post(login_url.clone()).header(REQUEST_ID_HEADER, new_request_id()).json(&serde_json::json!({ "username": username, "password": password })).send().await}) {
                 Ok(r) => r,
                 Err(e) => {
                     // server not reachable / timeout / DNS / connection
                     if e.is_timeout() || e.is_connect() || e.is_request() {
-                        eprintln!("[auth] Server not reachable: {e}, retrying...");
-                        if attempts >= MAX_ATTEMPTS {
-                            return Err(format!("Server not reachable after {MAX_ATTEMPTS} attempts"));
+                        unreachable += 1;
+                        if unreachable >= MAX_ATTEMPTS {
+                            return Err(LoginError::Unreachable(format!("Server {} not reachable after {MAX_ATTEMPTS} attempts: {e}", address)));
                         }
+                        eprintln!("[auth] Server not reachable: {e}, retrying...");
                         std::thread::sleep(Duration::from_secs(2));
                         continue;
                     }
-                    panic!("HTTP error: {e}");
+                    return Err(LoginError::Other(format!("HTTP error: {e}")));
                 }
             };
 
             match resp.status() {
                 StatusCode::OK => {
-                    let sid = resp.cookies().find(|c| c.name() == "connect.sid").map(|c| c.value().to_string()).expect("No session cookie in response");
+                    let session = session::StoredSession::from_response(username.clone(), &resp)
+                        .ok_or_else(|| LoginError::Other("No session cookie in the login response".to_string()))?;
                     let creds = Self { username, password };
-                    return Ok((creds, sid));
+                    return Ok((creds, session));
                 }
+                StatusCode::TOO_MANY_REQUESTS => return Err(LoginError::Locked(lockout_message(&rt, resp))),
                 StatusCode::UNAUTHORIZED => {
-                    eprintln!("[auth] Credentials invalid.");
-                    if attempts >= MAX_ATTEMPTS {
-                        return Err("Too many invalid credentials (3 attempts)".to_string());
+                    rejected += 1;
+                    // tentativi che il server concede ancora prima di bloccare l'utente
+                    let left = resp.headers().get(ATTEMPTS_LEFT_HEADER).and_then(|v| v.to_str().ok()).and_then(|v| v.parse::<u32>().ok());
+                    if left == Some(0) {
+                        return Err(LoginError::Locked(format!("Invalid credentials: the server now refuses logins as {} for a while", username)));
+                    }
+                    if scripted {
+                        return Err(LoginError::Rejected("Invalid credentials".to_string()));
+                    }
+                    if rejected >= MAX_ATTEMPTS {
+                        return Err(LoginError::Rejected(format!("Invalid credentials ({MAX_ATTEMPTS} attempts)")));
                     }
-                    username.clear();
-                    print!("username (retry): ");
-                    stdout().flush().ok();
-                    stdin().read_line(&mut username).expect("Failed to read username");
-                    username = username.trim().to_owned();
-                    print!("password (retry): ");
-                    stdout().flush().ok();
-                    password = read_password().expect("Failed to read password");
-                    println!();
+                    match left {
+                        Some(left) => eprintln!("[auth] Credentials invalid ({} attempt(s) left before the server locks the account).", left),
+                        None => eprintln!("[auth] Credentials invalid."),
+                    }
+                    if !fixed_user {
+                        username = prompt("username (retry): ", false)?;
+                    }
+                    password = prompt("password (retry): ", true)?;
                     continue;
                 }
-                other => return Err(format!("Login failed: HTTP {}", other).into())
+                other => return Err(LoginError::Other(format!("Login failed: HTTP {}", other)))
+            }
+        }
+    }
+
+    /// Chiede la nuova password (due volte) e la imposta sul server usando `session`.
+    /// Il server rigenera la sessione e invalida le altre dell'utente: viene restituita quella nuova.
+    pub fn change_password(&mut self, address: &str, session: &session::StoredSession) -> Result<session::StoredSession, String> {
+        use std::io::{stdout, Write};
+
+        print!("new password: ");
+        stdout().flush().ok();
+        let new_password = read_password().map_err(|e| format!("Failed to read password: {e}"))?;
+        println!();
+        print!("new password (again): ");
+        stdout().flush().ok();
+        let again = read_password().map_err(|e| format!("Failed to read password: {e}"))?;
+        println!();
+        if again != new_password {
+            return Err("The passwords do not match".to_string());
+        }
+
+        let base_url = Url::from_str(address).map_err(|e| format!("Invalid base URL: {e}"))?;
+        let passwd_url = base_url.join("api/passwd").map_err(|e| e.to_string())?;
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("Unable to build a Runtime object");
+        let client = session::session_client(&base_url, &session.sid);
+        let body = serde_json::json!({ "oldPassword": self.password, "newPassword": new_password });
+        let resp = rt.block_on(async { client.post(passwd_url).header(REQUEST_ID_HEADER, new_request_id()).json(&body).send().await })
+            .map_err(|e| format!("Server not reachable: {e}"))?;
+        match resp.status() {
+            StatusCode::OK => {
+                let renewed = session::StoredSession::from_response(self.username.clone(), &resp).ok_or("No session cookie in response")?;
+                self.password = new_password;
+                Ok(renewed)
             }
+            StatusCode::FORBIDDEN => Err("Wrong password".to_string()),
+            StatusCode::BAD_REQUEST => {
+                let msg = rt.block_on(async { resp.json::<Value>().await }).ok()
+                    .and_then(|v| v["message"].as_str().map(str::to_string))
+                    .unwrap_or_else(|| "Bad request".to_string());
+                Err(msg)
+            }
+            other => Err(format!("Password change failed: HTTP {}", other)),
         }
     }
 }
 
-fn deserialize_systemtime_from_millis<'de, D>(deserializer: D) -> Result<SystemTime, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let millis: u64 = Deserialize::deserialize(deserializer)?;
-    Ok(UNIX_EPOCH + Duration::from_millis(millis))
+const SNAPSHOT_HEADER: &str = "x-snapshot";
+const MANDATORY_LOCKS_HEADER: &str = "x-mandatory-locks";
+const REQUEST_ID_HEADER: &str = "x-request-id";
+const IDEMPOTENCY_HEADER: &str = "idempotency-key";
+const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+// tentativi ulteriori dopo un timeout o una connessione fallita, con attesa crescente
+const SEND_RETRIES: u32 = 2;
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+// identificativo di una singola chiamata: prefisso del processo più un contatore, così
+// un errore visto dall'utente si ritrova nella riga di log corrispondente del server
+fn new_request_id() -> String {
+    static SESSION: OnceLock<u32> = OnceLock::new();
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    let session = SESSION.get_or_init(|| {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        nanos ^ std::process::id().rotate_left(16)
+    });
+    format!("{:08x}-{}", session, NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+// chiave di una modifica: casuale (128 bit), perché il server la ricorda fra sessioni e processi diversi
+// e una chiave già vista farebbe rispondere con l'esito di un'altra richiesta
+fn idempotency_key() -> Result<String, BackendError> {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes).map_err(|e| BackendError::Other(e.to_string()))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// id ripetuto dal server nella risposta ("-" se il server non lo gestisce)
+fn request_id(resp: &Response) -> String {
+    resp.headers().get(REQUEST_ID_HEADER).and_then(|v| v.to_str().ok()).unwrap_or("-").to_string()
+}
+
+fn wire_of(resp: &Response) -> Wire {
+    Wire::of(resp.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()))
+}
+
+fn build_client(cookies: Arc<Jar>, mut headers: HeaderMap) -> Client {
+    headers.insert(header::ACCEPT, HeaderValue::from_static(ACCEPT_BODIES));
+    reqwest::Client::builder()
+        .cookie_provider(cookies)
+        .default_headers(headers)
+        .timeout(Duration::from_secs(300)) // 5 mins
+        .build()
+        .expect("Unable to build the Client object")
+}
+
+// corpo multipart/form-data con le parti (nome, content type, dati); restituisce boundary e corpo.
+// Il boundary viene allungato finché non compare in nessuna parte
+fn multipart_body(parts: Vec<(String, &str, Vec<u8>)>) -> (String, Vec<u8>) {
+    let mut boundary = format!("rfs-batch-{}", new_request_id());
+    while parts.iter().any(|(_, _, data)| data.windows(boundary.len()).any(|w| w == boundary.as_bytes())) {
+        boundary.push_str(&new_request_id());
+    }
+    let mut body = Vec::with_capacity(parts.iter().map(|(_, _, data)| data.len() + 128).sum());
+    for (name, content_type, data) in parts {
+        body.extend_from_slice(format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\nContent-Type: {}\r\n\r\n", boundary, name, content_type).as_bytes());
+        body.extend_from_slice(&data);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    (boundary, body)
+}
+
+// errore di una singola operazione di un batch, con lo stesso significato che ha in decode_error
+fn item_error(status: u16, message: Option<String>, context: &str) -> BackendError {
+    BackendError::from_status(status, context, message.as_deref())
+}
+
+#[derive(Deserialize)]
+struct DirPageResponse {
+    entries: Vec<FileServerResponse>,
+    next: Option<String>,
 }
 
 fn response_to_entry(file: FileServerResponse) -> FileEntry {
     let gid = file.group.unwrap_or(file.owner);
     FileEntry {
-        ino: file.ino.parse::<u64>().unwrap_or(0),
+        ino: file.ino,
         path: file.path.to_string_lossy().to_string(),
         name: file.name,
         kind: file.kind,
-        size: file.size.parse::<u64>().unwrap_or(0),
+        size: file.size,
         perms: file.permissions,
         nlinks: file.nlinks,
         atime: file.atime,
@@ -163,56 +556,278 @@ fn response_to_entry(file: FileServerResponse) -> FileEntry {
         btime: file.btime,
         uid: file.owner,
         gid,
+        etag: file.etag,
+        flags: file.flags,
+        file_flags: file.file_flags,
     }
 }
 
 impl HttpBackend {
     pub fn new(address: String, credentials: Credentials, sid: String, rt: Arc<Runtime>) -> Result<Self, BackendError> {
-        let base_url = Url::from_str(&address).expect("Invalid url");
+        let base_url = parse_address(&address).map_err(BackendError::Other)?;
         let cookie_jar = Arc::new(Jar::default());
         let cookie_str = format!("connect.sid={}", sid.trim());
         cookie_jar.add_cookie_str(&cookie_str, &base_url);
-        let client = reqwest::Client::builder()
-            .cookie_provider(cookie_jar)
-            .timeout(Duration::from_secs(300)) // 5 mins
-            .build()
-            .expect("Unable to build the Client object");
+        let client = build_client(cookie_jar.clone(), HeaderMap::new());
 
         let httpb = Self {
             runtime: rt,
             base_url,
             client,
-            credentials
+            cookies: cookie_jar,
+            credentials,
+            etags: etag_cache(),
+            cancel: None,
+            capabilities: Capabilities::CURRENT,
+            identity: None,
+            read_only: false,
+            session_expires: SessionExpiry::default(),
+            relogin: Relogin::default(),
+            logins: Arc::default(),
+            limits: None,
+            mandatory_locks: false,
         };
 
         Ok(httpb)
     }
 
-    fn authenticate(&self) -> Result<(), BackendError> {
-        let login_url= self.base_url.join("api/login").map_err(|e| BackendError::Other(e.to_string()))?;
-        let client = self.client.clone();
-        let credentials = self.credentials.clone();
+    /// Legge il filesystem com'era all'istante `at`: ogni richiesta porta l'header X-Snapshot e il server
+    /// risponde dalla copia più recente presa entro quell'istante, rifiutando le modifiche
+    pub fn with_snapshot(mut self, at: SystemTime) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(SNAPSHOT_HEADER, HeaderValue::from(to_millis(at)));
+        self.client = build_client(self.cookies.clone(), headers);
+        self
+    }
 
-        self.runtime.block_on(async move{
-            let resp = client.post(login_url).json(&credentials).send().await
-                .map_err(|e| BackendError::Other(e.to_string()))?;
-            match resp.status(){
-                StatusCode::OK => Ok(()),
-                StatusCode::UNAUTHORIZED => Err(BackendError::Unauthorized),
-                s => Err(BackendError::Other(format!("HTTP {}", s))),
-            }
+    /// Rifiuta con BackendError::ReadOnly ogni operazione che modifica il filesystem, indipendentemente
+    /// dai permessi sul server: utile per ispezionare dati di produzione senza rischi
+    pub fn with_read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// I lock degli altri client diventano obbligatori per le write di questo backend: il server rifiuta
+    /// con BackendError::Locked quelle su un intervallo bloccato (i lock di Windows sono obbligatori)
+    pub fn with_mandatory_locks(mut self) -> Self {
+        self.mandatory_locks = true;
+        self
+    }
+
+    /// Limita le richieste in volo di questo backend e dei suoi fetcher (vedi limits.rs)
+    pub fn with_request_limits(mut self, limits: RequestLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    pub fn recall_listener(&self) -> RecallListener {
+        RecallListener {
+            runtime: self.runtime.clone(),
+            base_url: self.base_url.clone(),
+            client: self.client.clone(),
+        }
+    }
+
+    /// Backend indipendente che condivide client e cookie di sessione, per scaricamenti in parallelo
+    pub fn fetcher(&self) -> HttpBackend {
+        HttpBackend {
+            runtime: self.runtime.clone(),
+            base_url: self.base_url.clone(),
+            client: self.client.clone(),
+            cookies: self.cookies.clone(),
+            credentials: self.credentials.clone(),
+            etags: etag_cache(),
+            cancel: None,
+            capabilities: self.capabilities,
+            identity: self.identity.clone(),
+            read_only: self.read_only,
+            session_expires: self.session_expires.clone(),
+            relogin: self.relogin.clone(),
+            logins: self.logins.clone(),
+            limits: self.limits.clone(),
+            mandatory_locks: self.mandatory_locks,
+        }
+    }
+
+    /// Rinnova la sessione in un thread prima che scada, così le operazioni del mount non incontrano un 401:
+    /// a ridosso della scadenza una GET /api/me la prolunga (il cookie del server è rolling) e se il server
+    /// non la riconosce più viene rifatto il login con le credenziali del mount. Il thread termina quando
+    /// il backend e i suoi fetcher non esistono più
+    pub fn keep_session_alive(&self) {
+        let expiry = Arc::downgrade(&self.session_expires);
+        let (runtime, client, base_url, credentials) = (self.runtime.clone(), self.client.clone(), self.base_url.clone(), self.credentials.clone());
+        let (relogin, logins) = (self.relogin.clone(), self.logins.clone());
+        let spawned = std::thread::Builder::new()
+            .name("rfs-session".to_string())
+            .spawn(move || {
+                let mut warned = false;
+                loop {
+                    std::thread::sleep(SESSION_CHECK_INTERVAL);
+                    let Some(expiry) = expiry.upgrade() else { return };
+                    let Some(expires) = *expiry.lock().expect("Mutex poisoned") else { continue };
+                    if expires.duration_since(SystemTime::now()).is_ok_and(|left| left > SESSION_REFRESH_MARGIN) {
+                        continue;
+                    }
+                    let Ok(me_url) = base_url.join("api/me") else { return };
+                    let checked = Instant::now();
+                    let resp = runtime.block_on(async { client.get(me_url).header(REQUEST_ID_HEADER, new_request_id()).send().await });
+                    match resp {
+                        Ok(resp) if resp.status() == StatusCode::OK => note_session(&expiry, &resp),
+                        Ok(resp) if resp.status() == StatusCode::UNAUTHORIZED => {
+                            if credentials.password.is_empty() {
+                                if !warned {
+                                    eprintln!("Session expired and cannot be renewed without a password: remount to log in again");
+                                    warned = true;
+                                }
+                                continue;
+                            }
+                            if let Err(e) = runtime.block_on(login_with(&client, &base_url, &credentials, &expiry, &relogin, &logins, checked)) {
+                                eprintln!("Unable to renew the session: {}", e);
+                            }
+                        }
+                        Ok(resp) => eprintln!("Unable to renew the session: HTTP {}", resp.status()),
+                        Err(e) => eprintln!("Unable to renew the session: {}", e),
+                    }
+                }
+            });
+        if let Err(e) = spawned {
+            eprintln!("Unable to start the session refresh thread: {}", e);
+        }
+    }
+
+    /// Chiede al server le funzionalità supportate e adatta le richieste successive; un server
+    /// precedente all'handshake risponde 404 e viene trattato come Capabilities::LEGACY
+    pub fn handshake(&mut self) -> Result<Capabilities, BackendError> {
+        let endpoint = "api/capabilities";
+        let resp = self.raw_request::<()>(Method::GET, endpoint, None)?;
+        self.capabilities = match resp.status() {
+            StatusCode::OK => self.read_body(resp, endpoint)?,
+            StatusCode::NOT_FOUND => Capabilities::LEGACY,
+            _ => return Err(self.decode_error(resp, endpoint)),
+        };
+        Ok(self.capabilities)
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Legge uid e gruppi dell'utente della sessione (GET /api/me) e li conserva nel backend.
+    /// Un server che non li riporta restituisce comunque l'uid, con la lista dei gruppi vuota.
+    pub fn load_identity(&mut self) -> Result<Identity, BackendError> {
+        let endpoint = "api/me";
+        let resp = self.raw_request::<()>(Method::GET, endpoint, None)?;
+        let identity: Identity = match resp.status() {
+            StatusCode::OK => self.read_body(resp, endpoint)?,
+            _ => return Err(self.decode_error(resp, endpoint)),
+        };
+        self.identity = Some(identity.clone());
+        Ok(identity)
+    }
+
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    /// Nomi di utenti e gruppi (GET /api/names); un server che non li conosce li lascia tutti numerici
+    pub fn owner_names(&mut self) -> Result<OwnerNames, BackendError> {
+        if !self.capabilities.names {
+            return Ok(OwnerNames::default());
+        }
+        let names: NamesResponse = self.request_response::<NamesResponse, ()>(Method::GET, "api/names", None)?;
+        Ok(OwnerNames {
+            users: names.users.into_iter().map(|u| (u.uid, u.name)).collect(),
+            groups: names.groups.into_iter().map(|g| (g.gid, g.name)).collect(),
         })
     }
 
+    /// Utente della sessione
+    pub fn username(&self) -> &str {
+        &self.credentials.username
+    }
+
+    fn require(&self, supported: bool, feature: &str) -> Result<(), BackendError> {
+        if supported { Ok(()) } else { Err(BackendError::Unsupported(feature.to_string())) }
+    }
+
+    fn writable(&self) -> Result<(), BackendError> {
+        if self.read_only { Err(BackendError::ReadOnly) } else { Ok(()) }
+    }
+
+    // attende una risposta HTTP; se la richiesta in corso viene interrotta la connessione viene abbandonata
+    fn wait<F: Future>(&self, fut: F) -> Result<F::Output, BackendError> {
+        self.runtime.block_on(rfs_models::cancellable(self.cancel.as_ref(), fut))
+    }
+
+    // rifà il login dopo un 401 ricevuto da una richiesta inviata a `sent`
+    fn authenticate(&self, sent: Instant) -> Result<(), BackendError> {
+        if self.credentials.password.is_empty() {
+            // sessione ripresa da disco e scaduta: senza password serve un nuovo login
+            eprintln!("Session expired, remount to log in again");
+            return Err(BackendError::Unauthorized);
+        }
+        self.runtime.block_on(login_with(&self.client, &self.base_url, &self.credentials, &self.session_expires, &self.relogin, &self.logins, sent))
+    }
+
+    // invia la richiesta con un nuovo X-Request-Id per tentativo, riportato anche negli errori di rete.
+    // Dopo un timeout o un errore di connessione la richiesta viene ritentata: le letture sempre, le
+    // modifiche solo se il server gestisce l'Idempotency-Key, che resta la stessa in tutti i tentativi
+    // così una modifica già applicata non viene ripetuta (e non finisce in un Conflict)
+    fn send(&self, req: RequestBuilder) -> Result<Response, BackendError> {
+        let mut request = req.build().map_err(|e| BackendError::Other(e.to_string()))?;
+        let mutating = !matches!(*request.method(), Method::GET | Method::HEAD);
+        if mutating && let Ok(key) = HeaderValue::from_str(&idempotency_key()?) {
+            request.headers_mut().insert(IDEMPOTENCY_HEADER, key);
+        }
+        if mutating && self.mandatory_locks {
+            request.headers_mut().insert(MANDATORY_LOCKS_HEADER, HeaderValue::from_static("1"));
+        }
+        let retriable = !mutating || self.capabilities.idempotency;
+        // tenuto per tutti i tentativi, fino alla risposta
+        let _permit = match &self.limits {
+            Some(limits) => {
+                let permit = self.wait(limits.for_url(request.url()).acquire())?;
+                Some(permit.map_err(|e| BackendError::Other(e.to_string()))?)
+            }
+            None => None,
+        };
+        let mut attempt = 0;
+        loop {
+            let id = new_request_id();
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                request.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            // i corpi in streaming non si possono ripetere
+            let next = if retriable && attempt < SEND_RETRIES { request.try_clone() } else { None };
+            match (self.wait(self.client.execute(request))?, next) {
+                (Ok(resp), _) => {
+                    note_session(&self.session_expires, &resp);
+                    if resp.headers().contains_key(REPLAYED_HEADER) {
+                        eprintln!("Request {} had already been applied, using the original response", id);
+                    }
+                    return Ok(resp);
+                }
+                (Err(e), Some(next)) if e.is_timeout() || e.is_connect() => {
+                    attempt += 1;
+                    eprintln!("{} (request {}), retrying ({}/{})", e, id, attempt, SEND_RETRIES);
+                    std::thread::sleep(RETRY_BACKOFF * attempt);
+                    request = next;
+                }
+                (Err(e), _) => return Err(BackendError::Other(format!("{} (request {})", e, id))),
+            }
+        }
+    }
+
     fn raw_request<B: Serialize>(&self, method: Method, endpoint: &str, body: Option<&B>) -> Result<Response, BackendError> {
         let mut retried = false;
         loop {
             let url = self.base_url.join(endpoint).map_err(|e| BackendError::Other(e.to_string()))?;
             let mut req = self.client.request(method.clone(), url);
-            if let Some(b) = body { req = req.json(b); }
-            let resp= self.runtime.block_on(async {req.send().await}).map_err(|e| BackendError::Other(e.to_string()))?;
+            if let Some(b) = body { req = self.encode_body(req, b)?; }
+            let sent = Instant::now();
+            let resp= self.send(req)?;
             if resp.status() == StatusCode::UNAUTHORIZED && !retried{
-                self.authenticate()?;
+                self.authenticate(sent)?;
                 retried=true;
                 continue;
             }
@@ -224,43 +839,167 @@ impl HttpBackend {
         let resp=self.raw_request(method, endpoint, body)?;
         match resp.status(){
             StatusCode::OK | StatusCode::CREATED =>{
-                self.runtime.block_on(async{resp.json().await}).map_err(|_| BackendError::BadAnswerFormat)
+                self.read_body(resp, endpoint)
             }
             _ => Err(self.decode_error(resp, endpoint)),
         }
     }
 
+    fn read_body<R: DeserializeOwned>(&self, resp: Response, endpoint: &str) -> Result<R, BackendError> {
+        let id = request_id(&resp);
+        let wire = wire_of(&resp);
+        let body = self.wait(async { resp.bytes().await })?.map_err(|e| BackendError::Other(format!("{} (request {})", e, id)))?;
+        parse_body(&format!("{} (request {})", endpoint, id), wire, &body)
+    }
+
+    // corpo in MessagePack se il server l'ha annunciato nell'handshake, altrimenti JSON
+    fn encode_body<B: Serialize + ?Sized>(&self, req: RequestBuilder, body: &B) -> Result<RequestBuilder, BackendError> {
+        if !self.capabilities.msgpack {
+            return Ok(req.json(body));
+        }
+        let raw = rmp_serde::to_vec_named(body).map_err(|e| BackendError::Other(e.to_string()))?;
+        Ok(req.header(CONTENT_TYPE, HeaderValue::from_static(MSGPACK)).body(raw))
+    }
+
+    // messaggio di errore del server, in qualunque codifica sia arrivato
+    fn error_message(&self, resp: Response) -> Option<String> {
+        let wire = wire_of(&resp);
+        let body = self.runtime.block_on(async { resp.bytes().await }).ok()?;
+        parse_body::<ErrorResponse>("", wire, &body).ok().map(|e| e.error)
+    }
+
+    fn track(&mut self, entry: FileEntry) -> FileEntry {
+        match &entry.etag {
+            Some(etag) => { self.etags.put(entry.ino, etag.clone()); }
+            None => { self.etags.pop(&entry.ino); }
+        }
+        entry
+    }
+
+    // dopo una scrittura il server restituisce il nuovo etag nell'header
+    fn track_header(&mut self, ino: u64, resp: &Response) {
+        match resp.headers().get(header::ETAG).and_then(|v| v.to_str().ok()) {
+            Some(etag) => { self.etags.put(ino, etag.to_string()); }
+            None => { self.etags.pop(&ino); }
+        }
+    }
+
+    fn if_match(&self, ino: u64) -> Option<HeaderValue> {
+        if !self.capabilities.conditional {
+            return None;
+        }
+        self.etags.peek(&ino).and_then(|etag| HeaderValue::from_str(etag).ok())
+    }
+
     fn decode_error(&self, resp:Response, endpoint: &str) -> BackendError {
-        match resp.status() {
-            StatusCode::UNAUTHORIZED => BackendError::Unauthorized,
-            StatusCode::FORBIDDEN => BackendError::Forbidden,
-            StatusCode::NOT_FOUND => BackendError::NotFound(endpoint.to_string()),
-            StatusCode::CONFLICT => {
-                let msg = self.runtime.block_on(async { resp.json::<ErrorResponse>().await.ok().map(|e| e.error) }).unwrap_or_else(|| "Conflict".to_string());
-                BackendError::Conflict(msg)
-            }
-            StatusCode::INTERNAL_SERVER_ERROR => BackendError::InternalServerError,
-            StatusCode::BAD_REQUEST => BackendError::BadAnswerFormat,
-            StatusCode::SERVICE_UNAVAILABLE => BackendError::ServerUnreachable,
-            other => BackendError::Other(format!("HTTP {}", other)),
+        let id = request_id(&resp);
+        // un 404 è l'esito normale di una lookup, gli altri errori vanno nel log con l'id per il confronto col server
+        if resp.status() != StatusCode::NOT_FOUND {
+            eprintln!("{} failed: HTTP {} (request {})", endpoint, resp.status(), id);
+        }
+        let status = resp.status().as_u16();
+        // il codice errno del server distingue ad esempio ENOTDIR da un 400 qualsiasi
+        let error = if status == 404 { None } else { self.error_message(resp) };
+        match BackendError::from_status(status, endpoint, error.as_deref()) {
+            BackendError::Conflict(msg) => BackendError::Conflict(format!("{} (request {})", msg, id)),
+            BackendError::Other(msg) => BackendError::Other(format!("{} (request {})", msg, id)),
+            e => e,
         }
     }
 }
 
 impl RemoteBackend for HttpBackend {
     fn list_dir(&mut self, ino: u64) -> Result<Vec<FileEntry>, BackendError> {
-        let endpoint = format!("api/directories/{}/entries", ino);
-        let files: Vec<FileServerResponse> = self.request_response::<Vec<FileServerResponse>, ()>(Method::GET, &endpoint, None)?;
-        Ok(files.into_iter().map(response_to_entry).collect())
+        // la lista viene assemblata pagina per pagina, così il server non deve serializzare tutta la directory
+        let mut entries = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = self.list_dir_page(ino, None, cursor.as_deref(), DIR_PAGE_SIZE)?;
+            entries.extend(page.entries);
+            match page.next {
+                Some(next) => cursor = Some(next),
+                None => return Ok(entries),
+            }
+        }
+    }
+
+    fn disk_usage(&mut self, ino: u64) -> Result<DiskUsage, BackendError> {
+        let endpoint = format!("api/files/{}/usage", ino);
+        self.request_response::<DiskUsage, ()>(Method::GET, &endpoint, None)
+    }
+
+    fn search(&mut self, root_ino: u64, query: &SearchQuery) -> Result<Vec<FileEntry>, BackendError> {
+        let mut url = self.base_url.join(&format!("api/directories/{}/search", root_ino)).map_err(|e| BackendError::Other(e.to_string()))?;
+        {
+            let mut params = url.query_pairs_mut();
+            if let Some(name) = &query.name {
+                params.append_pair("name", name);
+            }
+            if let Some(min) = query.min_size {
+                params.append_pair("minSize", &min.to_string());
+            }
+            if let Some(max) = query.max_size {
+                params.append_pair("maxSize", &max.to_string());
+            }
+            if let Some(after) = query.modified_after {
+                params.append_pair("modifiedAfter", &to_millis(after).to_string());
+            }
+            if let Some(before) = query.modified_before {
+                params.append_pair("modifiedBefore", &to_millis(before).to_string());
+            }
+            if let Some(limit) = query.limit {
+                params.append_pair("limit", &limit.to_string());
+            }
+        }
+        let files: Vec<FileServerResponse> = self.request_response::<Vec<FileServerResponse>, ()>(Method::GET, url.as_str(), None)?;
+        Ok(files.into_iter().map(|f| self.track(response_to_entry(f))).collect())
+    }
+
+    fn list_dir_page(&mut self, ino: u64, pattern: Option<&str>, cursor: Option<&str>, limit: u32) -> Result<DirPage, BackendError> {
+        if !self.capabilities.pagination {
+            // il server restituisce tutta la directory in una volta; il filtro lo applichiamo qui
+            let endpoint = format!("api/directories/{}/entries", ino);
+            let list: Vec<FileServerResponse> = self.request_response::<Vec<FileServerResponse>, ()>(Method::GET, &endpoint, None)?;
+            let entries = list.into_iter()
+                .filter(|f| pattern.is_none_or(|p| name_matches(p, &f.name)))
+                .map(|f| self.track(response_to_entry(f)))
+                .collect();
+            return Ok(DirPage { entries, next: None });
+        }
+        let mut url = self.base_url.join(&format!("api/directories/{}/entries", ino)).map_err(|e| BackendError::Other(e.to_string()))?;
+        {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("limit", &limit.to_string());
+            if let Some(cursor) = cursor {
+                query.append_pair("cursor", cursor);
+            }
+            if let Some(pattern) = pattern {
+                query.append_pair("pattern", pattern);
+            }
+        }
+        let page: DirPageResponse = self.request_response::<DirPageResponse, ()>(Method::GET, url.as_str(), None)?;
+        Ok(DirPage {
+            entries: page.entries.into_iter().map(|f| self.track(response_to_entry(f))).collect(),
+            next: page.next,
+        })
     }
 
     fn create_dir(&mut self, parent_ino:u64, name:&str) -> Result<FileEntry, BackendError> {
+        self.writable()?;
         let endpoint = format!("api/directories/{}/dirs/{}", parent_ino, name);
         let f: FileServerResponse = self.request_response::<FileServerResponse, ()>(Method::POST, &endpoint, None)?;
-        Ok(response_to_entry(f))
+        Ok(self.track(response_to_entry(f)))
+    }
+
+    fn create_dir_with_mode(&mut self, parent_ino:u64, name:&str, mode: u32) -> Result<FileEntry, BackendError> {
+        self.writable()?;
+        let endpoint = format!("api/directories/{}/dirs/{}", parent_ino, name);
+        let f: FileServerResponse = self.request_response(Method::POST, &endpoint, Some(&serde_json::json!({ "mode": mode })))?;
+        Ok(self.track(response_to_entry(f)))
     }
 
     fn delete_dir(&mut self, parent_ino:u64, name:&str) -> Result<(), BackendError> {
+        self.writable()?;
         let endpoint = format!("api/directories/{}/dirs/{}", parent_ino, name);
         let resp=self.raw_request::<()>(Method::DELETE, &endpoint,None)?;
         match resp.status(){
@@ -272,30 +1011,34 @@ impl RemoteBackend for HttpBackend {
     fn lookup(&mut self, parent_ino:u64, name:&str) -> Result<FileEntry, BackendError> {
         let endpoint = format!("api/directories/{}/entries/lookup?name={}", parent_ino, name);
         let f: FileServerResponse = self.request_response::<FileServerResponse, ()>(Method::GET, &endpoint, None)?;
-        Ok(response_to_entry(f))
+        Ok(self.track(response_to_entry(f)))
     }
 
     fn get_attr(&mut self, ino: u64) -> Result<FileEntry, BackendError> {
         let endpoint = format!("api/files/{}/attributes", ino);
         let f: FileServerResponse = self.request_response::<FileServerResponse, ()>(Method::GET, &endpoint, None)?;
-        Ok(response_to_entry(f))
+        Ok(self.track(response_to_entry(f)))
     }
 
     fn get_attr_if_modified_since(&mut self, ino: u64, since: SystemTime) -> Result<Option<FileEntry>, BackendError> {
+        if !self.capabilities.conditional {
+            return self.get_attr(ino).map(Some);
+        }
         let endpoint = format!("api/files/{}/attributes", ino);
         let mut retried = false;
         loop {
             let url = self.base_url.join(&endpoint).map_err(|e| BackendError::Other(e.to_string()))?;
             let req = self.client.get(url).header(header::IF_MODIFIED_SINCE, fmt_http_date(since));
-            let resp = self.runtime.block_on(async { req.send().await }).map_err(|e| BackendError::Other(e.to_string()))?;
+            let sent = Instant::now();
+            let resp = self.send(req)?;
             match resp.status() {
                 StatusCode::OK => {
-                    let f = self.runtime.block_on(async { resp.json().await }).map_err(|_| BackendError::BadAnswerFormat)?;
-                    return Ok(Some(response_to_entry(f)));
+                    let f = self.read_body(resp, &endpoint)?;
+                    return Ok(Some(self.track(response_to_entry(f))));
                 }
                 StatusCode::NOT_MODIFIED => return Ok(None),
                 StatusCode::UNAUTHORIZED if !retried => {
-                    self.authenticate()?;
+                    self.authenticate(sent)?;
                     retried = true;
                     continue;
                 }
@@ -305,12 +1048,21 @@ impl RemoteBackend for HttpBackend {
     }
 
     fn create_file(&mut self, parent_ino:u64, name:&str) -> Result<FileEntry, BackendError> {
+        self.writable()?;
         let endpoint = format!("api/directories/{}/files/{}", parent_ino, name);
         let f: FileServerResponse = self.request_response::<FileServerResponse, ()>(Method::POST, &endpoint, None)?;
-        Ok(response_to_entry(f))
+        Ok(self.track(response_to_entry(f)))
+    }
+
+    fn create_file_with_mode(&mut self, parent_ino:u64, name:&str, mode: u32) -> Result<FileEntry, BackendError> {
+        self.writable()?;
+        let endpoint = format!("api/directories/{}/files/{}", parent_ino, name);
+        let f: FileServerResponse = self.request_response(Method::POST, &endpoint, Some(&serde_json::json!({ "mode": mode })))?;
+        Ok(self.track(response_to_entry(f)))
     }
 
     fn delete_file(&mut self, parent_ino:u64, name:&str) -> Result<(), BackendError> {
+        self.writable()?;
         let endpoint = format!("api/directories/{}/files/{}", parent_ino, name);
         let resp=self.raw_request::<()>(Method::DELETE, &endpoint, None)?;
         match resp.status(){
@@ -324,8 +1076,7 @@ impl RemoteBackend for HttpBackend {
         let resp= self.raw_request::<()>(Method::GET, &endpoint, None)?;
         match resp.status(){
             StatusCode::OK | StatusCode::PARTIAL_CONTENT => {
-                let bytes = self.runtime
-                    .block_on(async { resp.bytes().await })
+                let bytes = self.wait(async { resp.bytes().await })?
                     .map_err(|e| BackendError::Other(e.to_string()))?;
                 Ok(bytes.to_vec())
             }
@@ -334,73 +1085,180 @@ impl RemoteBackend for HttpBackend {
     }
 
     fn write_chunk(&mut self, ino: u64, offset: u64, data: Vec<u8>) -> Result<u64, BackendError> {
+        self.writable()?;
         let endpoint = format!("api/files/{}?offset={}", ino, offset);
         let url= self.base_url.join(&endpoint).map_err(|e| BackendError::Other(e.to_string()))?;
-        let req=self.client.request(Method::PUT, url).header(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream")).body(data);
-        let resp= self.runtime.block_on(async {req.send().await}).map_err(|e| BackendError::Other(e.to_string()))?;
+        let mut req=self.client.request(Method::PUT, url).header(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream")).body(data);
+        if let Some(etag) = self.if_match(ino) {
+            req = req.header(header::IF_MATCH, etag);
+        }
+        let resp= self.send(req)?;
         match resp.status() {
             StatusCode::OK | StatusCode::CREATED => {
-                let risp : serde_json::Value = self.runtime.block_on(async { resp.json().await }).map_err(|_| BackendError::BadAnswerFormat)?;
-                Ok(risp["bytes"].as_u64().unwrap_or(0))
+                self.track_header(ino, &resp);
+                let risp: WriteResponse = self.read_body(resp, &endpoint)?;
+                Ok(risp.bytes)
             },
             _ => Err(self.decode_error(resp, &endpoint)),
         }
     }
 
+    fn write_append(&mut self, ino: u64, data: Vec<u8>) -> Result<u64, BackendError> {
+        self.writable()?;
+        // niente If-Match: l'offset lo sceglie il server, le append concorrenti non sono un conflitto
+        let endpoint = format!("api/files/{}/append", ino);
+        let url= self.base_url.join(&endpoint).map_err(|e| BackendError::Other(e.to_string()))?;
+        let req=self.client.request(Method::POST, url).header(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream")).body(data);
+        let resp= self.send(req)?;
+        match resp.status() {
+            StatusCode::OK => {
+                self.track_header(ino, &resp);
+                let risp: AppendResponse = self.read_body(resp, &endpoint)?;
+                Ok(risp.offset)
+            },
+            _ => Err(self.decode_error(resp, &endpoint)),
+        }
+    }
+
+    // le write viaggiano in un'unica richiesta multipart: una parte "ops" con ino, offset e If-Match di
+    // ognuna, seguita da una parte "data<i>" con i byte della i-esima
+    fn write_batch(&mut self, writes: Vec<BatchWrite>) -> Result<Vec<Result<u64, BackendError>>, BackendError> {
+        self.writable()?;
+        if !self.capabilities.batch {
+            return Ok(writes.into_iter().map(|w| self.write_chunk(w.ino, w.offset, w.data)).collect());
+        }
+        let endpoint = "api/batch";
+        let ops: Vec<Value> = writes.iter().map(|w| serde_json::json!({
+            "ino": w.ino,
+            "offset": w.offset,
+            "ifMatch": self.if_match(w.ino).and_then(|v| v.to_str().ok().map(str::to_string)),
+        })).collect();
+        let inos: Vec<u64> = writes.iter().map(|w| w.ino).collect();
+        let ops = serde_json::to_vec(&ops).map_err(|e| BackendError::Other(e.to_string()))?;
+        let mut parts = vec![("ops".to_string(), "application/json", ops)];
+        parts.extend(writes.into_iter().enumerate().map(|(i, w)| (format!("data{}", i), "application/octet-stream", w.data)));
+        let (boundary, body) = multipart_body(parts);
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+
+        let url = self.base_url.join(endpoint).map_err(|e| BackendError::Other(e.to_string()))?;
+        let mut retried = false;
+        let resp = loop {
+            let req = self.client.post(url.clone()).header(CONTENT_TYPE, content_type.as_str()).body(body.clone());
+            let sent = Instant::now();
+            let resp = self.send(req)?;
+            match resp.status() {
+                StatusCode::OK => break resp,
+                StatusCode::UNAUTHORIZED if !retried => {
+                    self.authenticate(sent)?;
+                    retried = true;
+                }
+                _ => return Err(self.decode_error(resp, endpoint)),
+            }
+        };
+        let batch: BatchResponse = self.read_body(resp, endpoint)?;
+        if batch.results.len() != inos.len() {
+            return Err(BackendError::BadAnswerFormat(format!("{}: {} results for {} writes", endpoint, batch.results.len(), inos.len())));
+        }
+        Ok(inos.into_iter().zip(batch.results).map(|(ino, item)| {
+            if item.status != 200 {
+                return Err(item_error(item.status, item.error, &format!("{} (ino {})", endpoint, ino)));
+            }
+            match item.etag {
+                Some(etag) => { self.etags.put(ino, etag); }
+                None => { self.etags.pop(&ino); }
+            }
+            Ok(item.bytes)
+        }).collect())
+    }
+
     fn rename(&mut self, old_parent_ino:u64, old_name: &str, new_parent_ino: u64, new_name: &str) -> Result<FileEntry, BackendError> {
+        self.writable()?;
         let endpoint = format!("api/directories/{}/entries/{}", old_parent_ino, old_name);
         let body = serde_json::json!({
             "newParentIno": new_parent_ino,
             "newName": new_name
         });
         let f: FileServerResponse = self.request_response::<FileServerResponse, Value>(Method::PATCH, &endpoint, Some(&body))?;
-        Ok(response_to_entry(f))
+        Ok(self.track(response_to_entry(f)))
     }
 
     fn set_attr(&mut self,ino: u64,attrs: SetAttrRequest) -> Result<FileEntry, BackendError> {
+        self.writable()?;
         let endpoint = format!("api/files/{}/attributes", ino);
-        let body = serde_json::to_value(attrs).map_err(|e| BackendError::Other(e.to_string()))?;
-        let f: FileServerResponse = self.request_response::<FileServerResponse, Value>(Method::PATCH, &endpoint, Some(&body))?;
-        Ok(response_to_entry(f))
+        let mut retried = false;
+        loop {
+            let url = self.base_url.join(&endpoint).map_err(|e| BackendError::Other(e.to_string()))?;
+            let mut req = self.encode_body(self.client.patch(url), &attrs)?;
+            if let Some(etag) = self.if_match(ino) {
+                req = req.header(header::IF_MATCH, etag);
+            }
+            let sent = Instant::now();
+            let resp = self.send(req)?;
+            match resp.status() {
+                StatusCode::OK => {
+                    let f: FileServerResponse = self.read_body(resp, &endpoint)?;
+                    return Ok(self.track(response_to_entry(f)));
+                }
+                StatusCode::UNAUTHORIZED if !retried => {
+                    self.authenticate(sent)?;
+                    retried = true;
+                    continue;
+                }
+                _ => return Err(self.decode_error(resp, &endpoint)),
+            }
+        }
     }
 
-    fn read_stream(&mut self, ino: u64, offset: u64) -> Result<rfs_models::ByteStream, BackendError> {
-        let endpoint = format!("api/files/stream/{}?offset={}", ino, offset);
+    fn read_stream(&mut self, ino: u64, offset: u64, len: Option<u64>) -> Result<rfs_models::ByteStream, BackendError> {
+        self.require(self.capabilities.streams, "streams")?;
+        // un server che non conosce length manda tutto fino alla fine: chi legge si ferma comunque
+        let endpoint = match len {
+            Some(len) => format!("api/files/stream/{}?offset={}&length={}", ino, offset, len),
+            None => format!("api/files/stream/{}?offset={}", ino, offset),
+        };
         let resp= self.raw_request::<()>(Method::GET, &endpoint, None)?;
         match resp.status() {
             StatusCode::OK => {
                 let stream=resp.bytes_stream().map(|r| r.map_err(|e| BackendError::Other(e.to_string())));
-                return Ok(Box::pin(stream));
+                Ok(Box::pin(stream))
             },
             _ => Err(self.decode_error(resp, &endpoint)),
         }
     }
 
-    fn write_stream(&mut self, ino: u64, offset: u64, data: Vec<u8>) -> Result<(), BackendError> {
+    fn write_stream(&mut self, ino: u64, offset: u64, data: rfs_models::ByteStream) -> Result<(), BackendError> {
+        self.writable()?;
+        self.require(self.capabilities.streams, "streams")?;
         let endpoint = format!("api/files/stream/{}?offset={}", ino, offset);
-        
-        // using Cursor to transform Vec<u8> into a reader
-        let cursor = Cursor::new(data);
-        let reader_stream = ReaderStream::new(cursor);
-        let body = Body::wrap_stream(reader_stream);
+
+        // il body legge i chunk dallo stream solo quando la connessione è pronta ad inviarli
+        let body = Body::wrap_stream(data);
 
         let mut headers = HeaderMap::new();
         headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
         headers.insert("x-chunk-offset", HeaderValue::from(offset));
+        if let Some(etag) = self.if_match(ino) {
+            headers.insert(header::IF_MATCH, etag);
+        }
 
         let req = self.client
             .put(self.base_url.join(&endpoint).map_err(|e| BackendError::Other(e.to_string()))?)
             .headers(headers)
             .body(body);
 
-        let resp = self.runtime.block_on(async { req.send().await }).map_err(|e| BackendError::Other(e.to_string()))?;
-        return match resp.status() {
-            StatusCode::OK => Ok(()),
+        let resp = self.send(req)?;
+        match resp.status() {
+            StatusCode::OK => {
+                self.track_header(ino, &resp);
+                Ok(())
+            },
             _ => Err(self.decode_error(resp, &endpoint)),
         }
     }
     
     fn link(&mut self, target_ino: u64, link_parent_ino: u64, link_name: &str) -> Result<FileEntry, BackendError> {
+        self.writable()?;
+        self.require(self.capabilities.links, "links")?;
         let endpoint = format!("api/links/{}", target_ino);
         let body = serde_json::json!({
             "linkParentIno": link_parent_ino,
@@ -408,11 +1266,13 @@ impl RemoteBackend for HttpBackend {
         });
         
         let f: FileServerResponse = self.request_response::<FileServerResponse, Value>(Method::POST, &endpoint, Some(&body))?;
-        Ok(response_to_entry(f))
+        Ok(self.track(response_to_entry(f)))
     }
     
     fn symlink(&mut self, target_path: &str, link_parent_ino: u64, link_name: &str) -> Result<FileEntry, BackendError> {
-        let endpoint = format!("api/symlinks");
+        self.writable()?;
+        self.require(self.capabilities.links, "links")?;
+        let endpoint = "api/symlinks".to_string();
         let body = serde_json::json!({
             "targetPath": target_path,
             "linkParentIno": link_parent_ino,
@@ -420,10 +1280,11 @@ impl RemoteBackend for HttpBackend {
         });
         
         let f: FileServerResponse = self.request_response::<FileServerResponse, Value>(Method::POST, &endpoint, Some(&body))?;
-        Ok(response_to_entry(f))
+        Ok(self.track(response_to_entry(f)))
     }
     
     fn readlink(&mut self, ino: u64) -> Result<String, BackendError> {
+        self.require(self.capabilities.links, "links")?;
         let endpoint = format!("api/symlinks/{}", ino);
 
         let rlr = self.request_response::<ReadLinkResponse, ()>(Method::GET, &endpoint, None)?;
@@ -431,10 +1292,140 @@ impl RemoteBackend for HttpBackend {
     }
 
     fn get_size(&mut self) -> Result<(u64, u64), BackendError> {
-        let endpoint = format!("api/size");
+        let endpoint = "api/size".to_string();
 
         let resp = self.request_response::<SizeResponse, ()>(Method::GET, &endpoint, None)?;
         Ok((resp.total, resp.available))
     }
-    
+
+    fn server_limits(&mut self) -> Result<Option<ServerLimits>, BackendError> {
+        let endpoint = "api/limits".to_string();
+        let resp = self.raw_request::<()>(Method::GET, &endpoint, None)?;
+        match resp.status() {
+            StatusCode::OK => Ok(Some(self.read_body(resp, &endpoint)?)),
+            // server precedente ai limiti negoziati
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Err(self.decode_error(resp, &endpoint)),
+        }
+    }
+
+    fn acquire_lease(&mut self, ino: u64, kind: LeaseKind) -> Result<Option<Lease>, BackendError> {
+        if kind == LeaseKind::Write {
+            self.writable()?;
+        }
+        let endpoint = format!("api/files/{}/lease", ino);
+        let body = serde_json::json!({
+            "type": match kind { LeaseKind::Read => "read", LeaseKind::Write => "write" }
+        });
+        let resp = self.raw_request::<Value>(Method::POST, &endpoint, Some(&body))?;
+        match resp.status() {
+            StatusCode::OK => {
+                let l: LeaseResponse = self.read_body(resp, &endpoint)?;
+                Ok(Some(Lease { ino, kind, expires: Instant::now() + Duration::from_millis(l.ttl) }))
+            }
+            // un altro client ha un lease in conflitto, oppure il server non supporta i lease
+            StatusCode::CONFLICT | StatusCode::NOT_FOUND => Ok(None),
+            _ => Err(self.decode_error(resp, &endpoint)),
+        }
+    }
+
+    fn block_hashes(&mut self, ino: u64, block_size: u64, blocks: &[u64]) -> Result<Option<HashMap<u64, String>>, BackendError> {
+        let list = blocks.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(",");
+        let endpoint = format!("api/files/{}/blocks?size={}&blocks={}", ino, block_size, list);
+        let resp = self.raw_request::<()>(Method::GET, &endpoint, None)?;
+        match resp.status() {
+            StatusCode::OK => {
+                let r: BlockHashesResponse = self.read_body(resp, &endpoint)?;
+                let hashes = r.hashes.into_iter().filter_map(|(idx, hash)| idx.parse::<u64>().ok().map(|idx| (idx, hash))).collect();
+                Ok(Some(hashes))
+            }
+            _ => Err(self.decode_error(resp, &endpoint)),
+        }
+    }
+
+    fn release_lease(&mut self, ino: u64) -> Result<(), BackendError> {
+        let endpoint = format!("api/files/{}/lease", ino);
+        let resp = self.raw_request::<()>(Method::DELETE, &endpoint, None)?;
+        match resp.status() {
+            StatusCode::OK | StatusCode::NOT_FOUND => Ok(()),
+            _ => Err(self.decode_error(resp, &endpoint)),
+        }
+    }
+
+    fn hold_open(&mut self, ino: u64) -> Result<(), BackendError> {
+        if !self.capabilities.open_handles {
+            return Ok(());
+        }
+        let endpoint = format!("api/files/{}/open", ino);
+        let resp = self.raw_request::<()>(Method::PUT, &endpoint, None)?;
+        match resp.status() {
+            StatusCode::OK => Ok(()),
+            _ => Err(self.decode_error(resp, &endpoint)),
+        }
+    }
+
+    fn session_epoch(&self) -> u64 {
+        self.logins.load(Ordering::SeqCst)
+    }
+
+    fn release_open(&mut self, ino: u64) -> Result<(), BackendError> {
+        if !self.capabilities.open_handles {
+            return Ok(());
+        }
+        let endpoint = format!("api/files/{}/open", ino);
+        let resp = self.raw_request::<()>(Method::DELETE, &endpoint, None)?;
+        match resp.status() {
+            StatusCode::OK => Ok(()),
+            _ => Err(self.decode_error(resp, &endpoint)),
+        }
+    }
+
+    fn test_lock(&mut self, ino: u64, lock: &ByteLock) -> Result<Option<ByteLock>, BackendError> {
+        self.require(self.capabilities.locks, "byte-range locks")?;
+        let kind = match lock.kind { LockKind::Read => "read", LockKind::Write => "write", LockKind::Unlock => return Ok(None) };
+        let end = lock.end.map(|end| end.to_string()).unwrap_or_default();
+        let endpoint = format!("api/files/{}/locks?type={}&start={}&end={}&owner={}", ino, kind, lock.start, end, lock.owner);
+        let r: LockTestResponse = self.request_response::<LockTestResponse, ()>(Method::GET, &endpoint, None)?;
+        Ok(r.conflict.map(|holder| ByteLock {
+            kind: if holder.kind == "write" { LockKind::Write } else { LockKind::Read },
+            start: holder.start,
+            end: holder.end,
+            owner: 0,
+            pid: holder.pid,
+        }))
+    }
+
+    fn set_lock(&mut self, ino: u64, lock: &ByteLock) -> Result<(), BackendError> {
+        self.require(self.capabilities.locks, "byte-range locks")?;
+        let endpoint = format!("api/files/{}/locks", ino);
+        // l'owner è un u64: in JSON come stringa, perché il server lo legge come numero a virgola mobile
+        let body = serde_json::json!({
+            "type": match lock.kind { LockKind::Read => "read", LockKind::Write => "write", LockKind::Unlock => "unlock" },
+            "start": lock.start,
+            "end": lock.end,
+            "owner": lock.owner.to_string(),
+            "pid": lock.pid,
+        });
+        let resp = self.raw_request(Method::POST, &endpoint, Some(&body))?;
+        match resp.status() {
+            StatusCode::OK => Ok(()),
+            _ => Err(self.decode_error(resp, &endpoint)),
+        }
+    }
+
+    fn set_cancel_token(&mut self, token: Option<CancellationToken>) {
+        self.cancel = token;
+    }
+
+    fn prepare_write(&mut self, _ino: u64, _offset: u64) -> Result<(), BackendError> {
+        self.writable()
+    }
+
+    fn invalidate(&mut self, ino: u64) {
+        self.etags.pop(&ino);
+    }
+
+    fn validator(&self, ino: u64) -> Option<String> {
+        self.etags.peek(&ino).cloned()
+    }
 }
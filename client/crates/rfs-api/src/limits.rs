@@ -0,0 +1,36 @@
+// Richieste HTTP in volo al massimo per mount, contate a parte per metadati e dati: una build parallela
+// fa centinaia di lookup e getattr insieme e può far scattare i limiti di frequenza del server. Il limite
+// è condiviso dal backend del mount e da tutti i suoi fetcher. Una richiesta tiene il permesso finché non
+// arriva la risposta (per gli invii, finché il corpo non è partito tutto): il download di un corpo, e in
+// particolare uno stream fermo perché l'applicazione non legge, non blocca le altre richieste.
+
+use reqwest::Url;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+#[derive(Clone)]
+pub struct RequestLimits {
+    metadata: Arc<Semaphore>,
+    data: Arc<Semaphore>,
+}
+
+impl RequestLimits {
+    pub fn new(metadata: usize, data: usize) -> Self {
+        Self { metadata: Arc::new(Semaphore::new(metadata)), data: Arc::new(Semaphore::new(data)) }
+    }
+
+    // semaforo della richiesta per `url`
+    pub(crate) fn for_url(&self, url: &Url) -> &Semaphore {
+        if is_data(url) { &self.data } else { &self.metadata }
+    }
+}
+
+// richieste che trasferiscono il contenuto dei file: read, write, append, stream, batch di write e hash dei blocchi
+fn is_data(url: &Url) -> bool {
+    let Some((_, api)) = url.path().split_once("/api/") else { return false };
+    api.starts_with("files/stream/")
+        || api == "batch"
+        || api.ends_with("/append")
+        || api.ends_with("/blocks")
+        || api.strip_prefix("files/").is_some_and(|ino| !ino.is_empty() && ino.bytes().all(|b| b.is_ascii_digit()))
+}
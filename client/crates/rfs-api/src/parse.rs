@@ -0,0 +1,125 @@
+// Decodifica delle risposte del server, in JSON o in MessagePack a seconda del Content-Type.
+// In caso di errore riporta endpoint, campo incriminato (es. `entries[3].mtime`) e l'inizio
+// del corpo, invece del solo BadAnswerFormat.
+// I campi sconosciuti vengono ignorati, quelli opzionali mancanti prendono un default.
+
+use rfs_models::{BackendError, from_millis};
+use serde::de::{self, DeserializeOwned, Deserializer, Visitor};
+use std::fmt::{self, Display, Write};
+use std::time::SystemTime;
+
+// caratteri (o byte, per MessagePack) del corpo riportati nell'errore
+const SNIPPET_LEN: usize = 200;
+
+pub(crate) const MSGPACK: &str = "application/msgpack";
+// il client preferisce MessagePack; un server che non lo conosce ignora l'header e risponde in JSON
+pub(crate) const ACCEPT_BODIES: &str = "application/msgpack, application/json;q=0.9";
+
+/// Codifica di un corpo, ricavata dal Content-Type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Wire {
+    Json,
+    MsgPack,
+}
+
+impl Wire {
+    pub(crate) fn of(content_type: Option<&str>) -> Self {
+        match content_type {
+            Some(ct) if ct.trim_start().starts_with(MSGPACK) => Wire::MsgPack,
+            _ => Wire::Json,
+        }
+    }
+}
+
+pub(crate) fn parse_body<R: DeserializeOwned>(endpoint: &str, wire: Wire, body: &[u8]) -> Result<R, BackendError> {
+    match wire {
+        Wire::Json => {
+            let mut de = serde_json::Deserializer::from_slice(body);
+            let value = serde_path_to_error::deserialize(&mut de).map_err(|e| {
+                let field = e.path().to_string();
+                bad_answer(endpoint, &field, e.into_inner(), snippet(body))
+            })?;
+            de.end().map_err(|e| bad_answer(endpoint, ".", e, snippet(body)))?;
+            Ok(value)
+        }
+        Wire::MsgPack => {
+            let mut de = rmp_serde::Deserializer::new(body);
+            serde_path_to_error::deserialize(&mut de).map_err(|e| {
+                let field = e.path().to_string();
+                bad_answer(endpoint, &field, e.into_inner(), hex_snippet(body))
+            })
+        }
+    }
+}
+
+fn bad_answer(endpoint: &str, field: &str, err: impl Display, snippet: String) -> BackendError {
+    BackendError::BadAnswerFormat(format!("{} (field `{}`): {}; body: {}", endpoint, field, err, snippet))
+}
+
+fn snippet(body: &[u8]) -> String {
+    let text = String::from_utf8_lossy(body);
+    match text.char_indices().nth(SNIPPET_LEN) {
+        Some((cut, _)) => format!("{}...", &text[..cut]),
+        None => text.into_owned(),
+    }
+}
+
+fn hex_snippet(body: &[u8]) -> String {
+    let mut hex = String::new();
+    for b in body.iter().take(SNIPPET_LEN) {
+        let _ = write!(hex, "{:02x}", b);
+    }
+    if body.len() > SNIPPET_LEN {
+        hex.push_str("...");
+    }
+    hex
+}
+
+/// Interi che il server può mandare come numero o come stringa (ino e size, che in JS possono superare 2^53).
+pub(crate) fn lenient_u64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    struct U64Visitor;
+
+    impl Visitor<'_> for U64Visitor {
+        type Value = u64;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an unsigned integer or a string containing one")
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<u64, E> {
+            Ok(v)
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<u64, E> {
+            u64::try_from(v).map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+        }
+
+        fn visit_f64<E: de::Error>(self, v: f64) -> Result<u64, E> {
+            // i timestamp di JS a volte arrivano come 1.7e12
+            if v >= 0.0 && v.fract() == 0.0 && v < u64::MAX as f64 {
+                Ok(v as u64)
+            } else {
+                Err(E::invalid_value(de::Unexpected::Float(v), &self))
+            }
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<u64, E> {
+            v.trim().parse().map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+        }
+    }
+
+    deserializer.deserialize_any(U64Visitor)
+}
+
+/// Timestamp in millisecondi dall'epoch, come numero o come stringa.
+pub(crate) fn lenient_millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+    lenient_u64(deserializer).map(from_millis)
+}
+
+pub(crate) fn default_nlinks() -> u32 {
+    1
+}
+
+pub(crate) fn default_time() -> SystemTime {
+    SystemTime::UNIX_EPOCH
+}
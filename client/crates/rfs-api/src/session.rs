@@ -0,0 +1,177 @@
+// Sessioni salvate su disco, una per server, così un nuovo mount entro la durata della sessione
+// non chiede di nuovo le credenziali. Nel file finisce solo il cookie di sessione (mai la password),
+// scritto con permessi 0600. Le sessioni scadute o non più riconosciute dal server vengono scartate.
+
+use crate::{Credentials, REQUEST_ID_HEADER, new_request_id};
+use reqwest::cookie::Jar;
+use reqwest::{Client, Response, StatusCode, Url};
+use rfs_models::{from_millis, to_millis};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+// durata assunta se il server non indica la scadenza del cookie
+const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(12 * 60 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredSession {
+    pub username: String,
+    /// valore del cookie connect.sid
+    pub sid: String,
+    /// scadenza in millisecondi dall'epoch
+    pub expires: u64,
+}
+
+impl StoredSession {
+    // sessione dal cookie connect.sid di una risposta (login o cambio password)
+    pub(crate) fn from_response(username: String, resp: &Response) -> Option<Self> {
+        let cookie = resp.cookies().find(|c| c.name() == "connect.sid")?;
+        let expires = cookie.max_age().map(|age| SystemTime::now() + age).or(cookie.expires())
+            .unwrap_or_else(|| SystemTime::now() + DEFAULT_SESSION_TTL);
+        Some(Self { username, sid: cookie.value().to_string(), expires: to_millis(expires) })
+    }
+
+    pub fn is_expired(&self) -> bool {
+        from_millis(self.expires) <= SystemTime::now()
+    }
+}
+
+/// File delle sessioni salvate, indicizzate per indirizzo del server
+pub struct SessionStore {
+    path: PathBuf,
+    user: Option<String>,
+}
+
+impl SessionStore {
+    pub fn open(path: PathBuf) -> Self {
+        Self { path, user: None }
+    }
+
+    /// Sessioni di un utente montato in aggiunta a quello principale (--mount-as): nello stesso file,
+    /// ma separate da quella principale del server
+    pub fn for_user(mut self, username: &str) -> Self {
+        self.user = Some(username.to_string());
+        self
+    }
+
+    // l'indirizzo normalizzato, così "http://host:3000" e "http://host:3000/" coincidono;
+    // per gli utenti aggiuntivi l'utente entra nell'indirizzo (http://utente@host:3000/)
+    fn key(&self, address: &str) -> String {
+        let user = self.user.as_deref();
+        let Ok(mut url) = Url::from_str(address) else {
+            return user.map_or_else(|| address.to_string(), |user| format!("{}@{}", user, address));
+        };
+        if let Some(user) = user && url.set_username(user).is_err() {
+            return format!("{}@{}", user, address);
+        }
+        url.to_string()
+    }
+
+    fn read(&self) -> HashMap<String, StoredSession> {
+        fs::read(&self.path).ok().and_then(|raw| serde_json::from_slice(&raw).ok()).unwrap_or_default()
+    }
+
+    fn write(&self, sessions: &HashMap<String, StoredSession>) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let raw = serde_json::to_vec_pretty(sessions).map_err(io::Error::other)?;
+        let tmp = self.path.with_extension("tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(&tmp)?.write_all(&raw)?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    /// Sessione salvata per `address`, se non è scaduta; quelle scadute vengono rimosse dal file
+    pub fn load(&self, address: &str) -> Option<StoredSession> {
+        let mut sessions = self.read();
+        let before = sessions.len();
+        sessions.retain(|_, s| !s.is_expired());
+        if sessions.len() != before && let Err(e) = self.write(&sessions) {
+            eprintln!("Unable to update {}: {}", self.path.display(), e);
+        }
+        sessions.remove(&self.key(address))
+    }
+
+    pub fn save(&self, address: &str, session: StoredSession) {
+        let mut sessions = self.read();
+        sessions.insert(self.key(address), session);
+        if let Err(e) = self.write(&sessions) {
+            eprintln!("Unable to save the session in {}: {}", self.path.display(), e);
+        }
+    }
+
+    pub fn remove(&self, address: &str) -> Option<StoredSession> {
+        let mut sessions = self.read();
+        let removed = sessions.remove(&self.key(address));
+        if removed.is_some() && let Err(e) = self.write(&sessions) {
+            eprintln!("Unable to update {}: {}", self.path.display(), e);
+        }
+        removed
+    }
+}
+
+// client con il cookie di sessione, per le richieste fatte prima del mount
+pub(crate) fn session_client(base_url: &Url, sid: &str) -> Client {
+    let jar = Arc::new(Jar::default());
+    jar.add_cookie_str(&format!("connect.sid={}", sid), base_url);
+    Client::builder().cookie_provider(jar).timeout(Duration::from_secs(15)).build().expect("Failed to create HTTP client")
+}
+
+/// Riprende la sessione salvata per `address` se il server la riconosce ancora (GET /api/me);
+/// altrimenti la rimuove e restituisce None. Senza password, alla scadenza la sessione non può
+/// essere rinnovata in automatico: serve un nuovo login.
+pub fn resume(address: &str, store: &SessionStore) -> Option<(Credentials, String)> {
+    let session = store.load(address)?;
+    let base_url = Url::from_str(address).ok()?;
+    let me_url = base_url.join("api/me").ok()?;
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("Unable to build a Runtime object");
+    let client = session_client(&base_url, &session.sid);
+    let resp = rt.block_on(async { client.get(me_url).header(REQUEST_ID_HEADER, new_request_id()).send().await });
+    match resp.map(|r| r.status()) {
+        Ok(StatusCode::OK) => Some((Credentials::resumed(session.username), session.sid)),
+        Ok(StatusCode::UNAUTHORIZED) => {
+            println!("Saved session expired on the server, logging in again");
+            store.remove(address);
+            None
+        }
+        Ok(other) => {
+            eprintln!("Cannot check the saved session: HTTP {}", other);
+            None
+        }
+        Err(e) => {
+            eprintln!("Cannot check the saved session: {}", e);
+            None
+        }
+    }
+}
+
+/// Cancella la sessione salvata per `address` e la chiude anche sul server.
+/// Restituisce false se non c'era nessuna sessione salvata.
+pub fn logout(address: &str, store: &SessionStore) -> Result<bool, String> {
+    let Some(session) = store.remove(address) else { return Ok(false) };
+    if session.is_expired() {
+        return Ok(true);
+    }
+    let base_url = Url::from_str(address).map_err(|e| format!("Invalid base URL: {e}"))?;
+    let logout_url = base_url.join("api/logout").map_err(|e| e.to_string())?;
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("Unable to build a Runtime object");
+    let client = session_client(&base_url, &session.sid);
+    let resp = rt.block_on(async { client.post(logout_url).header(REQUEST_ID_HEADER, new_request_id()).send().await })
+        .map_err(|e| format!("Session removed locally, but the server could not be reached: {e}"))?;
+    match resp.status() {
+        StatusCode::OK => Ok(true),
+        other => Err(format!("Session removed locally, but the server logout failed: HTTP {}", other)),
+    }
+}
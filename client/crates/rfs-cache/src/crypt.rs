@@ -0,0 +1,145 @@
+// Cifratura a riposo della cache su disco (--encrypt-cache): blocchi, liste e metadati vengono scritti con
+// ChaCha20-Poly1305 e una chiave casuale conservata nel portachiavi del sistema, mai su disco accanto alla
+// cache. Un file cifrato inizia con SEALED_MAGIC seguito dal nonce, e il nome del file è legato al contenuto
+// come dati associati: un file spostato o scambiato con un altro non si decifra. I file in chiaro (una cache
+// creata senza cifratura) o nel formato precedente si leggono solo finché l'archivio non è stato convertito
+// tutto, cosa che l'archivio segna con SEALED_MARKER; dopo vengono rifiutati.
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+const SEALED_MAGIC: &[u8] = b"RFSENC2\0";
+// formato precedente, senza dati associati
+const LEGACY_MAGIC: &[u8] = b"RFSENC1\0";
+/// File nella cartella di un archivio cifrato: c'è solo quando tutto il contenuto è stato cifrato
+pub(crate) const SEALED_MARKER: &str = ".sealed";
+const NONCE_LEN: usize = 12;
+const CACHE_KEY_LEN: usize = 32;
+
+#[derive(Clone)]
+pub struct CacheKey {
+    key: Key,
+}
+
+// la chiave non finisce mai nei log
+impl fmt::Debug for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CacheKey(..)")
+    }
+}
+
+impl CacheKey {
+    pub fn generate() -> Self {
+        Self { key: ChaCha20Poly1305::generate_key(&mut OsRng) }
+    }
+
+    /// Chiave dalla sua forma esadecimale (quella salvata nel portachiavi)
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        if hex.len() != CACHE_KEY_LEN * 2 || !hex.is_ascii() {
+            return None;
+        }
+        let mut key = Key::default();
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+        Some(Self { key })
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.key.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn seal(&self, plain: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = ChaCha20Poly1305::new(&self.key).encrypt(&nonce, Payload { msg: plain, aad })
+            .map_err(|_| io::Error::other("cache encryption failed"))?;
+        let mut out = Vec::with_capacity(SEALED_MAGIC.len() + NONCE_LEN + sealed.len());
+        out.extend_from_slice(SEALED_MAGIC);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&sealed);
+        Ok(out)
+    }
+
+    // `body` è il file senza il prefisso
+    fn open(&self, body: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        if body.len() < NONCE_LEN {
+            return Err(io::Error::new(ErrorKind::InvalidData, "truncated encrypted cache file"));
+        }
+        let (nonce, sealed) = body.split_at(NONCE_LEN);
+        ChaCha20Poly1305::new(&self.key).decrypt(Nonce::from_slice(nonce), Payload { msg: sealed, aad })
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "encrypted cache file does not match the cache key or its name"))
+    }
+
+    // HMAC-SHA256: i nomi dei blocchi non rivelano a chi non ha la chiave quale contenuto custodiscono
+    pub(crate) fn block_name(&self, data: &[u8]) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(data);
+        mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+// dati associati di un file: il suo nome
+fn aad(path: &Path) -> &[u8] {
+    path.file_name().map(|name| name.as_encoded_bytes()).unwrap_or_default()
+}
+
+/// Il file è cifrato nel formato attuale
+pub(crate) fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(SEALED_MAGIC)
+}
+
+/// Contenuto in chiaro del file `path` della cache: i file cifrati richiedono la chiave. Con la chiave, un
+/// file in chiaro o nel formato precedente si legge solo se l'archivio non è ancora tutto cifrato
+/// (`migrated` falso): dopo può esserci finito solo per mano di qualcun altro.
+pub(crate) fn unseal(key: Option<&CacheKey>, data: Vec<u8>, path: &Path, migrated: bool) -> io::Result<Vec<u8>> {
+    let legacy = data.starts_with(LEGACY_MAGIC);
+    match key {
+        Some(key) if is_sealed(&data) => key.open(&data[SEALED_MAGIC.len()..], aad(path)),
+        Some(_) if migrated => Err(io::Error::new(ErrorKind::InvalidData, "unencrypted file in an encrypted disk cache")),
+        Some(key) if legacy => key.open(&data[LEGACY_MAGIC.len()..], &[]),
+        Some(_) => Ok(data),
+        None if is_sealed(&data) || legacy => Err(io::Error::new(ErrorKind::PermissionDenied, "the disk cache is encrypted: enable --encrypt-cache to use it")),
+        None => Ok(data),
+    }
+}
+
+/// Contenuto da scrivere su disco nel file `path`: cifrato se c'è la chiave
+pub(crate) fn seal(key: Option<&CacheKey>, data: Vec<u8>, path: &Path) -> io::Result<Vec<u8>> {
+    match key {
+        Some(key) => key.seal(&data, aad(path)),
+        None => Ok(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_file_does_not_open_under_another_name() {
+        let key = CacheKey::generate();
+        let sealed = seal(Some(&key), b"data".to_vec(), Path::new("blocks/ab/first")).unwrap();
+        assert_eq!(unseal(Some(&key), sealed.clone(), Path::new("blocks/ab/first"), true).unwrap(), b"data");
+        assert_eq!(unseal(Some(&key), sealed, Path::new("blocks/cd/second"), true).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn plain_files_are_read_only_before_migration() {
+        let key = CacheKey::generate();
+        let path = Path::new("7.json");
+        assert_eq!(unseal(Some(&key), b"plain".to_vec(), path, false).unwrap(), b"plain");
+        assert_eq!(unseal(Some(&key), b"plain".to_vec(), path, true).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn block_names_depend_on_the_key() {
+        let (first, second) = (CacheKey::generate(), CacheKey::generate());
+        assert_eq!(first.block_name(b"data"), first.block_name(b"data"));
+        assert_ne!(first.block_name(b"data"), second.block_name(b"data"));
+    }
+}
@@ -0,0 +1,53 @@
+// Stato del collegamento con il server visto dalla cache. Quando il server non risponde il mount
+// diventa "degradato": letture e getattr vengono servite dai dati già in cache, finché una
+// richiesta non torna ad andare a buon fine.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+#[derive(Debug, Default)]
+pub struct Health {
+    degraded_since: Mutex<Option<SystemTime>>,
+    // risposte date dalla cache durante l'interruzione corrente
+    served_offline: AtomicU64,
+    // interruzioni dall'avvio del mount
+    outages: AtomicU64,
+}
+
+impl Health {
+    /// Istante da cui il server non risponde, None se il mount è online
+    pub fn degraded_since(&self) -> Option<SystemTime> {
+        *self.degraded_since.lock().expect("Mutex poisoned")
+    }
+
+    pub fn served_offline(&self) -> u64 {
+        self.served_offline.load(Ordering::Relaxed)
+    }
+
+    pub fn outages(&self) -> u64 {
+        self.outages.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn offline(&self) {
+        let mut since = self.degraded_since.lock().expect("Mutex poisoned");
+        if since.is_none() {
+            *since = Some(SystemTime::now());
+            self.served_offline.store(0, Ordering::Relaxed);
+            self.outages.fetch_add(1, Ordering::Relaxed);
+            eprintln!("Server unreachable: serving cached data (degraded mode)");
+        }
+    }
+
+    pub(crate) fn served_from_cache(&self) {
+        self.offline();
+        self.served_offline.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn online(&self) {
+        let mut since = self.degraded_since.lock().expect("Mutex poisoned");
+        if since.take().is_some() {
+            eprintln!("Server reachable again after serving {} requests from the cache", self.served_offline());
+        }
+    }
+}
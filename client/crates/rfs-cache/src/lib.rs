@@ -1,12 +1,61 @@
 use lru::LruCache;
-use rfs_models::{RemoteBackend, FileEntry, BackendError, SetAttrRequest, BLOCK_SIZE};
+use rfs_models::{RemoteBackend, FileEntry, BackendError, BatchWrite, EntryType, Hydration, SetAttrRequest, BLOCK_SIZE, FILE_FLAG_APPEND, FILE_FLAG_IMMUTABLE, DIR_PAGE_SIZE, DirPage, DiskUsage, SearchQuery, Lease, LeaseKind, ByteLock, CancellationToken, ServerLimits};
+use std::collections::{HashMap, HashSet};
 use std::num::NonZeroUsize;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 use rfs_models::ByteStream;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use sha2::{Digest, Sha256};
+
+mod crypt;
+mod health;
+mod listings;
+mod pins;
+#[cfg(test)]
+mod tests;
+pub use crypt::CacheKey;
+pub use health::Health;
+pub use listings::{ListingStore, StoredListing};
+pub use pins::{PinStore, PinnedEntry};
+
+// dimensione delle letture usate per scaricare per intero un file pinnato (limite del server: 1 MB)
+const PIN_FETCH_SIZE: u64 = 1024 * 1024;
+
+// margine sulla scadenza dei lease, per non fidarsi della cache proprio allo scadere
+const LEASE_MARGIN: Duration = Duration::from_secs(5);
+// oltre questo numero di lease registrati si scartano quelli scaduti
+const LEASE_PRUNE_THRESHOLD: usize = 4096;
+// per quanto ricordare un recall: più a lungo di qualsiasi richiesta di lease ancora in viaggio
+const RECALL_MEMORY: Duration = Duration::from_secs(5 * 60);
+// oltre questo numero di relazioni figlio -> padre si scartano quelle di ino non più in cache
+const PARENTS_PRUNE_THRESHOLD: usize = 65536;
+// una read che riprende dove è finita la precedente entro questa finestra è considerata sequenziale
+const COALESCE_WINDOW: Duration = Duration::from_millis(200);
+// blocchi massimi scaricati con una sola richiesta
+const MAX_COALESCED_BLOCKS: u64 = 16;
 
 type FileIno = u64;
 
+/// Stato in cache di un ino (Cache::cached_state)
+#[derive(Debug, Clone, Default)]
+pub struct CachedState {
+    /// metadati in memoria
+    pub entry: Option<FileEntry>,
+    /// ETag ricordato dal backend, inviato come validatore delle scritture
+    pub validator: Option<String>,
+    /// voci della lista in memoria, per le directory
+    pub children: Option<usize>,
+    pub listing_on_disk: bool,
+    /// indici dei blocchi in memoria
+    pub blocks: Vec<u64>,
+    /// lease valido e tempo che gli resta
+    pub lease: Option<(LeaseKind, Duration)>,
+    pub pinned: Option<PinnedEntry>,
+    /// dimensione della copia su disco dei file pinnati
+    pub pinned_len: Option<u64>,
+}
+
 pub struct Cache <B:RemoteBackend>{
     // chiamata al backend remoto
     http_backend: B,
@@ -14,15 +63,64 @@ pub struct Cache <B:RemoteBackend>{
     meta: LruCache<FileIno, Arc<FileEntry>>,
     // cache tra ino e lista dei figli (solo ino). Gli attributi dei figli sono in attr_cache
     dir_child: LruCache<FileIno, Arc<Vec<FileIno>>>,
+    // liste in costruzione pagina per pagina: cursore atteso per la prossima pagina e figli ricevuti finora
+    partial_dirs: LruCache<FileIno, (String, Vec<FileEntry>)>,
     // mappa tra ino e cache dei blocchi del file, lru su idx del blocco e i dati
     file_blocks: LruCache<FileIno,LruCache<u64,Arc<Vec<u8>>>>,
-    file_block_cap: NonZeroUsize // capacità massima della lru cache per ciascun file
+    file_block_cap: NonZeroUsize, // capacità massima della lru cache per ciascun file
+    block_size: usize, // dimensione di ciascun blocco
+    // lease concessi dal server: con un lease valido metadati e blocchi si usano senza rivalidare
+    leases: HashMap<FileIno, Lease>,
+    // ultimo recall ricevuto per ino: un lease chiesto prima di quell'istante è già stato richiamato
+    recalled: HashMap<FileIno, Instant>,
+    // ino richiamati dal server (recall listener); senza listener i lease non vengono richiesti
+    recalls: Option<Mutex<Receiver<FileIno>>>,
+    // sessione con cui sono stati ottenuti i lease: dopo un nuovo login il server non li conosce più
+    lease_epoch: u64,
+    // file e directory pinnati: copia completa su disco, mai soggetta a eviction e disponibile offline
+    pin_store: Option<PinStore>,
+    pinned: HashMap<FileIno, PinnedEntry>,
+    // fine dell'ultima read per file (primo blocco successivo e istante), per accorpare le read sequenziali
+    recent_reads: LruCache<FileIno, (u64, Instant)>,
+    // flag chflags non nulli degli ino visti, per rifiutare le modifiche senza chiedere al server
+    file_flags: HashMap<FileIno, u32>,
+    // server raggiungibile o mount degradato, letto dal comando status
+    health: Arc<Health>,
+    // liste delle directory salvate su disco, riusate dopo un rimontaggio se la directory non è cambiata
+    listing_store: Option<ListingStore>,
+    // il server riporta come dimensione di una directory il numero di voci: la aggiorniamo noi a ogni modifica
+    dir_sizes: bool,
+    // ino -> directory in cui è stato visto, e l'indice inverso, per trovare i discendenti di una directory spostata
+    parents: HashMap<FileIno, FileIno>,
+    children: HashMap<FileIno, HashSet<FileIno>>,
+}
+
+// errori per cui conviene ripiegare sulla copia locale dei file pinnati
+#[inline]
+fn is_offline(error: &BackendError) -> bool {
+    matches!(error, BackendError::ServerUnreachable | BackendError::Other(_))
+}
+
+fn pin_error(e: std::io::Error) -> BackendError {
+    BackendError::Other(format!("pin store: {}", e))
+}
+
+// stesso ino ma oggetto diverso (ino riassegnati dal server, es. dopo un ripristino): come il numero di
+// generazione di NFS, tipo e data di creazione restano gli stessi per tutta la vita di un oggetto
+#[inline]
+fn same_object(cached: &FileEntry, fresh: &FileEntry) -> bool {
+    cached.kind == fresh.kind && cached.btime == fresh.btime
+}
+
+// path remoto di `name` dentro la directory `parent`
+fn child_path(parent: &str, name: &str) -> String {
+    format!("{}/{}", parent.trim_end_matches('/'), name)
 }
 
 #[inline]
-fn block_span(offset:u64, len:u64) -> (u64,u64){
-    let start = offset / BLOCK_SIZE as u64;
-    let end = (offset + len.saturating_sub(1)) / BLOCK_SIZE as u64;
+fn block_span(offset:u64, len:u64, block_size: usize) -> (u64,u64){
+    let start = offset / block_size as u64;
+    let end = (offset + len.saturating_sub(1)) / block_size as u64;
     (start, end)
 }
 
@@ -32,46 +130,682 @@ impl <B:RemoteBackend> Cache<B> {
             http_backend,
             meta: LruCache::new(NonZeroUsize::new(attr_cap).expect("attr_cap must be non-zero")),
             dir_child: LruCache::new(NonZeroUsize::new(dir_cap).expect("dir_cap must be non-zero")),
+            partial_dirs: LruCache::new(NonZeroUsize::new(dir_cap).expect("dir_cap must be non-zero")),
             file_blocks: LruCache::new(NonZeroUsize::new(file_num).expect("file_num must be non-zero")),
             file_block_cap: NonZeroUsize::new(file_block_cap).expect("file_block_cap must be non-zero"),
+            block_size: BLOCK_SIZE,
+            leases: HashMap::new(),
+            recalled: HashMap::new(),
+            recalls: None,
+            lease_epoch: 0,
+            pin_store: None,
+            pinned: HashMap::new(),
+            recent_reads: LruCache::new(NonZeroUsize::new(file_num).expect("file_num must be non-zero")),
+            file_flags: HashMap::new(),
+            health: Arc::new(Health::default()),
+            listing_store: None,
+            dir_sizes: false,
+            parents: HashMap::new(),
+            children: HashMap::new(),
+        }
+    }
+
+    /// Imposta la dimensione dei blocchi (default BLOCK_SIZE); va scelta prima di usare la cache.
+    pub fn with_block_size(mut self, block_size: usize) -> Self {
+        assert!(block_size > 0, "block_size must be non-zero");
+        self.block_size = block_size;
+        self
+    }
+
+    /// Con `dir_sizes` (Capabilities::dir_sizes) la dimensione in cache delle directory segue le voci create e
+    /// cancellate da questo client; senza, cambia solo il mtime.
+    pub fn with_dir_sizes(mut self, dir_sizes: bool) -> Self {
+        self.dir_sizes = dir_sizes;
+        self
+    }
+
+    /// Abilita il pinning su disco, ricaricando i pin delle sessioni precedenti.
+    pub fn with_pin_store(mut self, store: PinStore) -> Self {
+        match store.load() {
+            Ok(pinned) => self.pinned = pinned,
+            Err(e) => eprintln!("Unable to load pinned entries: {}", e),
+        }
+        self.pin_store = Some(store);
+        self
+    }
+
+    /// Salva su disco le liste complete delle directory e le riusa, dopo una sola richiesta condizionale,
+    /// quando non sono più in memoria (anche in un mount successivo).
+    pub fn with_listing_store(mut self, store: ListingStore) -> Self {
+        self.listing_store = Some(store);
+        self
+    }
+
+    /// Stato del collegamento con il server, condiviso con chi deve mostrarlo (socket di controllo)
+    pub fn health(&self) -> Arc<Health> {
+        self.health.clone()
+    }
+
+    // registra l'esito di una chiamata al server: un successo chiude l'eventuale interruzione
+    fn track<T>(&self, res: Result<T, BackendError>) -> Result<T, BackendError> {
+        match &res {
+            Ok(_) => self.health.online(),
+            Err(e) if is_offline(e) => self.health.offline(),
+            Err(_) => {}
+        }
+        res
+    }
+
+    pub fn is_pinned(&self, ino: FileIno) -> bool {
+        self.pinned.contains_key(&ino)
+    }
+
+    /// Pinna una voce: per i file scarica tutto il contenuto, per le directory salva la lista dei figli.
+    pub fn pin(&mut self, ino: FileIno) -> Result<PinnedEntry, BackendError> {
+        self.store_pin(ino, false)
+    }
+
+    // scarica e salva la voce; `hydrated` per le copie scaricate alla prima lettura
+    fn store_pin(&mut self, ino: FileIno, hydrated: bool) -> Result<PinnedEntry, BackendError> {
+        if self.pin_store.is_none() {
+            return Err(BackendError::Other("pinning requires a disk cache directory".to_string()));
+        }
+        let entry = self.http_backend.get_attr(ino)?;
+        let children = match entry.kind {
+            EntryType::Directory => {
+                let list = self.http_backend.list_dir(ino)?;
+                Some(list.iter().map(|e| e.ino).collect())
+            }
+            EntryType::File => {
+                self.fetch_whole(ino)?;
+                None
+            }
+            EntryType::Symlink => None,
+        };
+
+        let store = self.pin_store.as_ref().expect("checked above");
+        let pinned = PinnedEntry { entry, children, stale: false, hydrated };
+        store.save(&pinned).map_err(pin_error)?;
+        self.pinned.insert(ino, pinned.clone());
+        Ok(pinned)
+    }
+
+    /// Toglie il pin (ricorsivamente per le directory) e cancella la copia su disco.
+    /// Restituisce il numero di voci rimosse, 0 se l'ino non era pinnato.
+    pub fn unpin(&mut self, ino: FileIno) -> usize {
+        let Some(pinned) = self.pinned.remove(&ino) else { return 0 };
+        if let Some(store) = self.pin_store.as_ref() && let Err(e) = store.remove(ino) {
+            eprintln!("Unable to remove pinned data of ino {}: {}", ino, e);
+        }
+        1 + pinned.children.unwrap_or_default().into_iter().map(|child| self.unpin(child)).sum::<usize>()
+    }
+
+    /// Capacità della cache dei blocchi: (numero di file, blocchi per file).
+    pub fn block_capacity(&self) -> (usize, usize) {
+        (self.file_blocks.cap().get(), self.file_block_cap.get())
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Cosa la cache sa di `ino`, per `cache stat` e `cache ls`: letto senza chiedere nulla al server e
+    /// senza cambiare l'ordine delle LRU.
+    pub fn cached_state(&self, ino: FileIno) -> CachedState {
+        let mut blocks: Vec<u64> = self.file_blocks.peek(&ino).map(|lru| lru.iter().map(|(idx, _)| *idx).collect()).unwrap_or_default();
+        blocks.sort_unstable();
+        let pinned = self.pinned.get(&ino).cloned();
+        CachedState {
+            entry: self.meta.peek(&ino).map(|e| (**e).clone()),
+            validator: self.http_backend.validator(ino),
+            children: self.dir_child.peek(&ino).map(|c| c.len()),
+            listing_on_disk: self.listing_store.as_ref().is_some_and(|store| store.contains(ino)),
+            blocks,
+            lease: self.leases.get(&ino).filter(|l| l.expires > Instant::now())
+                .map(|l| (l.kind, l.expires.saturating_duration_since(Instant::now()))),
+            pinned_len: pinned.as_ref().and_then(|_| self.pin_store.as_ref()?.data_len(ino)),
+            pinned,
+        }
+    }
+
+    /// Figli di `ino` nella lista in memoria, con il nome se ne sono in cache anche i metadati
+    pub fn cached_entries(&self, ino: FileIno) -> Option<Vec<(FileIno, Option<String>)>> {
+        let children = self.dir_child.peek(&ino)?;
+        Some(children.iter().map(|c| (*c, self.meta.peek(c).map(|e| e.name.clone()))).collect())
+    }
+
+    /// Ino di `name` dentro `parent` se lo conosce già la cache (lista in memoria o pin), senza lookup
+    pub fn peek_child(&self, parent: FileIno, name: &str) -> Option<FileIno> {
+        let in_listing = self.dir_child.peek(&parent)
+            .and_then(|children| children.iter().find(|c| self.meta.peek(*c).is_some_and(|e| e.name == name)).copied());
+        in_listing.or_else(|| {
+            let children = self.pinned.get(&parent)?.children.as_ref()?;
+            children.iter().find(|c| self.pinned.get(*c).is_some_and(|p| p.entry.name == name)).copied()
+        })
+    }
+
+    /// Scarta quello che la cache sa di `ino` (metadati, lista anche su disco, blocchi, etag), che verrà
+    /// riletto dal server al prossimo accesso; la copia dei file pinnati resta. false se non c'era nulla.
+    pub fn evict(&mut self, ino: FileIno) -> bool {
+        let state = self.cached_state(ino);
+        let found = state.entry.is_some() || state.children.is_some() || state.listing_on_disk || !state.blocks.is_empty()
+            || state.validator.is_some() || self.partial_dirs.contains(&ino);
+        self.meta.pop(&ino);
+        self.forget_listing(ino);
+        self.file_blocks.pop(&ino);
+        self.recent_reads.pop(&ino);
+        self.http_backend.invalidate(ino);
+        found
+    }
+
+    /// Inserisce blocchi scaricati fuori dalla cache (warm), `data` parte dall'offset 0 del file.
+    /// Se nel frattempo il file è cambiato i dati vengono scartati.
+    pub fn prime_blocks(&mut self, entry: &FileEntry, data: &[u8]) {
+        self.prime_range(entry, 0, data);
+    }
+
+    // scarica il file direttamente nell'archivio dei pin, un pezzo alla volta
+    fn fetch_whole(&mut self, ino: FileIno) -> Result<(), BackendError> {
+        let store = self.pin_store.as_ref().expect("pinning requires a disk cache directory");
+        let mut writer = store.data_writer(ino);
+        let mut offset = 0;
+        loop {
+            let chunk = self.http_backend.read_chunk(ino, offset, PIN_FETCH_SIZE)?;
+            writer.push(&chunk).map_err(pin_error)?;
+            offset += chunk.len() as u64;
+            if (chunk.len() as u64) < PIN_FETCH_SIZE {
+                return writer.commit().map_err(pin_error);
+            }
+        }
+    }
+
+    // aggiorna i metadati di una voce pinnata; se il file è cambiato sul server (e non per una nostra
+    // scrittura) la copia locale va riscaricata
+    fn refresh_pinned(&mut self, entry: &FileEntry, changed_by_us: bool) {
+        let Some(pinned) = self.pinned.get_mut(&entry.ino) else { return };
+        let changed = pinned.entry.mtime != entry.mtime || pinned.entry.size != entry.size;
+        if !changed && !changed_by_us {
+            return;
+        }
+        if changed && !changed_by_us && entry.kind == EntryType::File {
+            pinned.stale = true;
+        }
+        pinned.entry = entry.clone();
+        if let Some(store) = self.pin_store.as_ref() && let Err(e) = store.save(pinned) {
+            eprintln!("Unable to update pinned entry {}: {}", entry.ino, e);
+        }
+    }
+
+    // riporta sulla copia pinnata una scrittura appena confermata dal server
+    fn sync_pinned_write(&mut self, ino: FileIno, offset: u64, data: &[u8]) {
+        if !self.pinned.contains_key(&ino) {
+            return;
+        }
+        let store = self.pin_store.as_ref().expect("pinned entries require a pin store");
+        if let Err(e) = store.write_at(ino, offset, data) {
+            eprintln!("Unable to update pinned data of ino {}: {}", ino, e);
+            if let Some(pinned) = self.pinned.get_mut(&ino) {
+                pinned.stale = true;
+            }
+            return;
+        }
+        if let Ok(entry) = self.http_backend.get_attr(ino) {
+            self.refresh_pinned(&entry, true);
+        }
+    }
+
+    fn pinned_entry(&self, ino: FileIno) -> Option<FileEntry> {
+        self.pinned.get(&ino).map(|p| p.entry.clone())
+    }
+
+    // scarta la lista in cache, quella eventualmente in costruzione e quella salvata su disco
+    fn forget_listing(&mut self, ino: FileIno) {
+        self.dir_child.pop(&ino);
+        self.partial_dirs.pop(&ino);
+        if let Some(store) = self.listing_store.as_ref() {
+            store.remove(ino);
+        }
+    }
+
+    // lista salvata su disco (anche da un mount precedente), se la directory non è cambiata da allora;
+    // torna anche in memoria insieme ai metadati dei figli che mancano
+    fn restored_listing(&mut self, ino: FileIno) -> Result<Option<Vec<FileEntry>>, BackendError> {
+        let Some(mut stored) = self.listing_store.as_ref().and_then(|store| store.load(ino)) else { return Ok(None) };
+        let res = self.http_backend.get_attr_if_modified_since(ino, stored.dir.mtime);
+        if self.track(res)?.is_some() {
+            self.forget_listing(ino);
+            return Ok(None);
+        }
+        // la directory (o una sua antenata) può essere stata spostata: i path dei figli seguono quello attuale
+        if let Some(dir) = self.meta.peek(&ino) {
+            stored.dir.path = dir.path.clone();
+        }
+        for child in stored.children.iter_mut() {
+            child.path = child_path(&stored.dir.path, &child.name);
+        }
+        for entry in std::iter::once(&stored.dir).chain(stored.children.iter()) {
+            if !self.meta.contains(&entry.ino) {
+                self.remember_meta(entry);
+            }
+        }
+        for child in &stored.children {
+            self.remember_parent(child.ino, ino);
+        }
+        self.dir_child.put(ino, Arc::new(stored.children.iter().map(|e| e.ino).collect()));
+        Ok(Some(stored.children))
+    }
+
+    // salva su disco una lista appena completata, con la directory com'era in quel momento
+    fn store_listing(&self, ino: FileIno, children: &[FileEntry]) {
+        let Some(store) = self.listing_store.as_ref() else { return };
+        let Some(dir) = self.meta.peek(&ino) else { return };
+        let listing = StoredListing { dir: (**dir).clone(), children: children.to_vec() };
+        if let Err(e) = store.save(&listing) {
+            eprintln!("Unable to save the listing of ino {}: {}", ino, e);
+        }
+    }
+
+    // `parent` ha guadagnato (`added` > 0) o perso voci all'istante `mtime`: la lista in cache non vale più,
+    // mtime e dimensione della directory vengono aggiornati subito invece che alla prossima rivalidazione
+    fn dir_changed(&mut self, parent: FileIno, added: i64, mtime: SystemTime) {
+        self.forget_listing(parent);
+        let Some(cached) = self.meta.peek(&parent) else { return };
+        let mut entry = (**cached).clone();
+        entry.mtime = entry.mtime.max(mtime);
+        entry.ctime = entry.ctime.max(mtime);
+        if self.dir_sizes {
+            entry.size = entry.size.saturating_add_signed(added);
+        }
+        self.meta.put(parent, Arc::new(entry));
+    }
+
+    fn pinned_listing(&self, ino: FileIno) -> Option<Vec<FileEntry>> {
+        let children = self.pinned.get(&ino)?.children.as_ref()?;
+        Some(children.iter().filter_map(|c| self.pinned_entry(*c)).collect())
+    }
+
+    // lista di una directory ricostruita solo dalla cache, senza rivalidarla: serve quando il server non risponde
+    fn cached_children(&self, ino: FileIno) -> Option<Vec<FileEntry>> {
+        let children = self.dir_child.peek(&ino)?;
+        children.iter().map(|c| self.meta.peek(c).map(|e| (**e).clone())).collect()
+    }
+
+    fn offline_listing(&self, ino: FileIno) -> Option<Vec<FileEntry>> {
+        let listing = self.pinned_listing(ino).or_else(|| self.cached_children(ino))
+            .or_else(|| self.listing_store.as_ref()?.load(ino).map(|stored| stored.children))?;
+        self.health.served_from_cache();
+        Some(listing)
+    }
+
+    // byte richiesti presi dai blocchi in cache, None se ne manca qualcuno (o mancano i metadati)
+    fn cached_range(&mut self, ino: FileIno, offset: u64, size: u64) -> Option<Vec<u8>> {
+        let end = (offset + size).min(self.meta.peek(&ino)?.size);
+        if offset >= end {
+            return Some(Vec::new());
+        }
+        let (start_block, end_block) = block_span(offset, end - offset, self.block_size);
+        let file_lru = self.file_blocks.get_mut(&ino)?;
+        let mut result = Vec::with_capacity((end - offset) as usize);
+        for block_idx in start_block..=end_block {
+            let block = file_lru.get(&block_idx)?;
+            let block_offset = block_idx * self.block_size as u64;
+            let start = offset.saturating_sub(block_offset) as usize;
+            let stop = ((end - block_offset) as usize).min(block.len());
+            if start < stop {
+                result.extend_from_slice(&block[start..stop]);
+            }
+        }
+        Some(result)
+    }
+
+    fn read_pinned(&mut self, ino: FileIno, offset: u64, size: u64) -> Result<Vec<u8>, BackendError> {
+        match self.revalidate_meta(ino) {
+            Ok(_) => {}
+            Err(e) if is_offline(&e) => {} // server non raggiungibile: usiamo la copia locale
+            Err(e) => return Err(e),
+        }
+        let hydrated = self.pinned.get(&ino).is_some_and(|p| p.hydrated);
+        if self.pinned.get(&ino).is_some_and(|p| p.stale) && let Err(e) = self.store_pin(ino, hydrated) {
+            if !is_offline(&e) {
+                return Err(e);
+            }
+            eprintln!("Serving stale pinned copy of ino {}: {}", ino, e);
+        }
+        let store = self.pin_store.as_ref().expect("pinned entries require a pin store");
+        store.read_at(ino, offset, size).map_err(pin_error)
+    }
+
+    /// Abilita i lease: `recalls` riceve gli ino che il server richiama.
+    pub fn with_recalls(mut self, recalls: Receiver<FileIno>) -> Self {
+        self.recalls = Some(Mutex::new(recalls));
+        self
+    }
+
+    fn apply_recalls(&mut self) {
+        let Some(recalls) = self.recalls.as_ref() else { return };
+        let epoch = self.http_backend.session_epoch();
+        if epoch != self.lease_epoch {
+            self.lease_epoch = epoch;
+            self.leases.clear();
+        }
+        let recalled: Vec<FileIno> = recalls.lock().expect("Mutex poisoned").try_iter().collect();
+        let now = Instant::now();
+        if self.recalled.len() >= LEASE_PRUNE_THRESHOLD {
+            self.recalled.retain(|_, at| now.duration_since(*at) < RECALL_MEMORY);
+        }
+        for ino in recalled {
+            self.leases.remove(&ino);
+            self.recalled.insert(ino, now);
+            self.meta.pop(&ino);
+            self.file_blocks.pop(&ino);
+            self.forget_listing(ino); // per le directory il recall segnala voci create, rimosse o rinominate
+        }
+    }
+
+    fn has_lease(&mut self, ino: FileIno) -> bool {
+        self.apply_recalls();
+        match self.leases.get(&ino) {
+            Some(lease) if lease.expires > Instant::now() + LEASE_MARGIN => true,
+            Some(_) => {
+                self.leases.remove(&ino);
+                false
+            }
+            None => false,
+        }
+    }
+
+    // serve chiedere un lease: non ne abbiamo già uno adatto e il server è raggiungibile
+    fn lease_wanted(&mut self, ino: FileIno, kind: LeaseKind) -> bool {
+        // a server irraggiungibile la richiesta fallirebbe comunque
+        if self.recalls.is_none() || self.health.degraded_since().is_some() {
+            return false;
+        }
+        !(self.has_lease(ino) && (kind == LeaseKind::Read || self.leases.get(&ino).is_some_and(|l| l.kind == LeaseKind::Write)))
+    }
+
+    // registra la risposta a una richiesta di lease partita ad `asked`; None: negato
+    fn install_lease(&mut self, ino: FileIno, lease: Option<Lease>, asked: Instant) {
+        self.apply_recalls();
+        match lease {
+            // richiamato mentre la risposta era in viaggio
+            Some(_) if self.recalled.get(&ino).is_some_and(|at| *at >= asked) => {}
+            Some(lease) => {
+                if self.leases.len() >= LEASE_PRUNE_THRESHOLD {
+                    let now = Instant::now();
+                    self.leases.retain(|_, l| l.expires > now);
+                }
+                self.leases.insert(ino, lease);
+            }
+            None => { self.leases.remove(&ino); }
+        }
+    }
+
+    // chiede il lease se non ne abbiamo già uno adatto; se il server lo nega si continua a rivalidare
+    fn ensure_lease(&mut self, ino: FileIno, kind: LeaseKind) {
+        if !self.lease_wanted(ino, kind) {
+            return;
+        }
+        let asked = Instant::now();
+        match self.http_backend.acquire_lease(ino, kind) {
+            Ok(lease) => self.install_lease(ino, lease, asked),
+            Err(e) => eprintln!("Lease request for ino {} failed: {}", ino, e),
         }
     }
 
     #[inline]
     fn remember_meta(&mut self, entry: &FileEntry) {
+        if entry.file_flags != 0 {
+            self.file_flags.insert(entry.ino, entry.file_flags);
+        } else {
+            self.file_flags.remove(&entry.ino);
+        }
         self.meta.put(entry.ino, Arc::new(entry.clone()));
     }
 
+    fn remember_parent(&mut self, ino: FileIno, parent: FileIno) {
+        if self.parents.len() > PARENTS_PRUNE_THRESHOLD {
+            let stale: Vec<FileIno> = self.parents.keys()
+                .filter(|ino| !self.meta.contains(ino) && !self.dir_child.contains(ino))
+                .copied()
+                .collect();
+            for ino in stale {
+                self.forget_parent(ino);
+            }
+        }
+        self.forget_parent(ino);
+        self.parents.insert(ino, parent);
+        self.children.entry(parent).or_default().insert(ino);
+    }
+
+    fn forget_parent(&mut self, ino: FileIno) {
+        if let Some(parent) = self.parents.remove(&ino)
+            && let Some(siblings) = self.children.get_mut(&parent)
+        {
+            siblings.remove(&ino);
+            if siblings.is_empty() {
+                self.children.remove(&parent);
+            }
+        }
+    }
+
+    // discendenti noti di una directory, scendendo l'indice padre -> figli; le relazioni ormai vecchie
+    // possono formare un ciclo, per questo ogni ino si visita una volta sola
+    fn descendants(&self, dir: FileIno) -> HashSet<FileIno> {
+        let mut found = HashSet::new();
+        let mut pending = vec![dir];
+        while let Some(current) = pending.pop() {
+            for &child in self.children.get(&current).into_iter().flatten() {
+                if child != dir && found.insert(child) {
+                    pending.push(child);
+                }
+            }
+        }
+        found
+    }
+
+    // dopo un rename: le voci in cache con il vecchio ino del sorgente o con quello di un file sovrascritto non
+    // esistono più; se il server ha conservato l'ino, le voci sotto una directory spostata (anche pinnate)
+    // prendono il nuovo path, mentre i blocchi restano associati all'ino perché il contenuto non cambia
+    fn moved(&mut self, old_path: Option<&str>, entry: &FileEntry) {
+        let gone: Vec<FileIno> = self.meta.iter()
+            .filter(|(ino, cached)| **ino != entry.ino && (cached.path == entry.path || Some(cached.path.as_str()) == old_path))
+            .map(|(ino, _)| *ino)
+            .collect();
+        for ino in gone {
+            self.meta.pop(&ino);
+            self.file_blocks.pop(&ino);
+            self.leases.remove(&ino);
+            self.forget_parent(ino);
+        }
+
+        let descendants = if entry.kind == EntryType::Directory { self.descendants(entry.ino) } else { HashSet::new() };
+        // le liste in costruzione e quelle su disco riportano i path dei figli: vanno riscaricate
+        for ino in std::iter::once(entry.ino).chain(descendants.iter().copied()) {
+            self.partial_dirs.pop(&ino);
+            if let Some(store) = self.listing_store.as_ref() {
+                store.remove(ino);
+            }
+        }
+        let Some(old_path) = old_path else {
+            // senza il vecchio path i discendenti non si possono riscrivere
+            for ino in &descendants {
+                self.meta.pop(ino);
+            }
+            return;
+        };
+        if old_path == entry.path {
+            return;
+        }
+        let prefix = format!("{}/", old_path.trim_end_matches('/'));
+        let renamed = |path: &str| path.strip_prefix(&prefix).map(|rest| child_path(&entry.path, rest));
+        let mut stale = Vec::new();
+        for (ino, cached) in self.meta.iter_mut() {
+            match renamed(&cached.path) {
+                Some(path) => Arc::make_mut(cached).path = path,
+                // discendente con un path già vecchio prima del rename
+                None if descendants.contains(ino) => stale.push(*ino),
+                None => {}
+            }
+        }
+        for ino in stale {
+            self.meta.pop(&ino);
+        }
+        for pinned in self.pinned.values_mut() {
+            if pinned.entry.ino == entry.ino {
+                pinned.entry.name = entry.name.clone();
+                pinned.entry.path = entry.path.clone();
+            } else if let Some(path) = renamed(&pinned.entry.path) {
+                pinned.entry.path = path;
+            } else {
+                continue;
+            }
+            if let Some(store) = self.pin_store.as_ref() && let Err(e) = store.save(pinned) {
+                eprintln!("Unable to update pinned entry {}: {}", pinned.entry.ino, e);
+            }
+        }
+    }
+
+    // rifiuta l'operazione se l'ino ha uno dei flag `forbidden`
+    fn check_flags(&self, ino: FileIno, forbidden: u32) -> Result<(), BackendError> {
+        match self.file_flags.get(&ino).map(|flags| flags & forbidden) {
+            Some(set) if set & FILE_FLAG_IMMUTABLE != 0 => Err(BackendError::NotPermitted(format!("ino {} is immutable", ino))),
+            Some(set) if set != 0 => Err(BackendError::NotPermitted(format!("ino {} is append-only", ino))),
+            _ => Ok(()),
+        }
+    }
+
+    // come check_flags per la voce `name` di `parent`; il lookup serve solo se qualche ino ha dei flag
+    fn check_entry_flags(&mut self, parent_ino: FileIno, name: &str, forbidden: u32) -> Result<(), BackendError> {
+        if !self.file_flags.is_empty() && let Ok(entry) = self.lookup(parent_ino, name) {
+            self.check_flags(entry.ino, forbidden)?;
+        }
+        Ok(())
+    }
+
+    // rimuovere o spostare una voce richiede che né lei né la directory siano immutabili o append-only
+    fn check_removable(&mut self, parent_ino: FileIno, name: &str) -> Result<(), BackendError> {
+        self.check_flags(parent_ino, FILE_FLAG_IMMUTABLE | FILE_FLAG_APPEND)?;
+        self.check_entry_flags(parent_ino, name, FILE_FLAG_IMMUTABLE | FILE_FLAG_APPEND)
+    }
+
+    // un file append-only accetta solo scritture che partono dalla fine (offset None: in coda)
+    fn check_write(&mut self, ino: FileIno, offset: Option<u64>) -> Result<(), BackendError> {
+        self.check_flags(ino, FILE_FLAG_IMMUTABLE)?;
+        let append_only = self.file_flags.get(&ino).is_some_and(|flags| flags & FILE_FLAG_APPEND != 0);
+        if let Some(offset) = offset && append_only && offset < self.get_attr(ino)?.size {
+            return Err(BackendError::NotPermitted(format!("ino {} is append-only", ino)));
+        }
+        Ok(())
+    }
+
+    // contenuto scritto senza passare dalla cache: metadati e blocchi vanno riletti dal server
+    fn forget_content(&mut self, ino: u64) {
+        self.meta.pop(&ino);
+        self.file_blocks.pop(&ino);
+        // la copia pinnata verrà riscaricata al prossimo accesso
+        if let Some(pinned) = self.pinned.get_mut(&ino) {
+            pinned.stale = true;
+            if let Some(store) = self.pin_store.as_ref() && let Err(e) = store.save(pinned) {
+                eprintln!("Unable to update pinned entry {}: {}", ino, e);
+            }
+        }
+    }
+
+    // l'ino è stato riassegnato dal server: niente di quello che la cache sa di lui vale per il nuovo oggetto
+    fn forget_stale(&mut self, ino: FileIno) {
+        eprintln!("ino {} was reassigned by the server, dropping its cached data", ino);
+        self.forget_content(ino);
+        self.forget_listing(ino);
+        self.leases.remove(&ino);
+        self.file_flags.remove(&ino);
+        self.recent_reads.pop(&ino);
+    }
+
+    // dopo una write riuscita: aggiorna la copia pinnata e scarta blocchi e metadati ormai vecchi
+    fn written(&mut self, ino: FileIno, offset: u64, bytes_written: u64, pinned_copy: Option<Vec<u8>>) {
+        if let Some(data) = pinned_copy {
+            self.sync_pinned_write(ino, offset, &data[..(bytes_written as usize).min(data.len())]);
+        }
+        let (start_block, end_block) = block_span(offset, bytes_written, self.block_size);
+        if let Some(file_lru) = self.file_blocks.get_mut(&ino){
+            for block_idx in start_block..=end_block {
+                file_lru.pop(&block_idx);
+            }
+        }
+        // forzo la rivalidazione dei metadati al prossimo accesso
+        self.meta.pop(&ino);
+    }
+
+    // un altro client ha modificato il file: metadati e blocchi in cache non sono più validi
+    fn forget_on_conflict(&mut self, ino: u64, error: &BackendError) {
+        if let BackendError::PreconditionFailed = error {
+            self.meta.pop(&ino);
+            self.file_blocks.pop(&ino);
+        }
+    }
+
     #[inline]
     fn get_cached_mtime(&mut self, ino: u64) -> Option<SystemTime> {
         self.meta.get(&ino).map(|e| e.mtime)
     }
 
     fn revalidate_meta(&mut self, ino:u64) -> Result<FileEntry, BackendError> {
+        if self.has_lease(ino) && let Some(cached) = self.meta.get(&ino) {
+            return Ok((**cached).clone());
+        }
         let since= self.get_cached_mtime(ino).unwrap_or(SystemTime::UNIX_EPOCH);
-        match self.http_backend.get_attr_if_modified_since(ino, since)? {
+        let res = self.http_backend.get_attr_if_modified_since(ino, since);
+        match self.track(res)? {
             Some(entry) => {
-                if let Some(prev) = self.get_cached_mtime(ino) {
-                    if entry.mtime > prev {
-                        self.file_blocks.pop(&ino); // il file è cambiato, invalidiamo i blocchi
-                    }
+                if let Some(cached) = self.meta.peek(&ino) && !same_object(cached, &entry) {
+                    let path = cached.path.clone();
+                    self.forget_stale(ino);
+                    return Err(BackendError::Stale(path));
+                }
+                if let Some(prev) = self.get_cached_mtime(ino) && entry.mtime > prev {
+                    self.revalidate_blocks(ino); // il file è cambiato, rivalidiamo i blocchi
                 }
+                self.refresh_pinned(&entry, false);
                 self.remember_meta(&entry);
-                return Ok(entry);
+                Ok(entry)
             },
             None => {
                 if let Some(cached) = self.meta.get(&ino) {
-                    return Ok((**cached).clone());
+                    Ok((**cached).clone())
                 }
                 else{
-                    let entry = self.http_backend.get_attr(ino)?;
+                    let res = self.http_backend.get_attr(ino);
+                    let entry = self.track(res)?;
                     self.remember_meta(&entry);
-                    return Ok(entry);
+                    Ok(entry)
                 }
             }
         }
     }
 
+    // il file è cambiato: teniamo solo i blocchi in cache il cui hash coincide con quello del server
+    fn revalidate_blocks(&mut self, ino: FileIno) {
+        let cached: Vec<(u64, Arc<Vec<u8>>)> = match self.file_blocks.peek(&ino) {
+            Some(file_lru) if !file_lru.is_empty() => file_lru.iter().map(|(idx, block)| (*idx, block.clone())).collect(),
+            _ => return,
+        };
+        let idxs: Vec<u64> = cached.iter().map(|(idx, _)| *idx).collect();
+        let hashes = match self.http_backend.block_hashes(ino, self.block_size as u64, &idxs) {
+            Ok(Some(hashes)) => hashes,
+            _ => {
+                self.file_blocks.pop(&ino); // niente validatori, invalidiamo tutto
+                return;
+            }
+        };
+        let Some(file_lru) = self.file_blocks.get_mut(&ino) else { return };
+        for (idx, block) in cached {
+            let local: String = Sha256::digest(block.as_slice()).iter().map(|b| format!("{:02x}", b)).collect();
+            if hashes.get(&idx) != Some(&local) {
+                file_lru.pop(&idx);
+            }
+        }
+    }
+
     fn get_or_create_file_lru(&mut self, ino: u64) -> &mut LruCache<u64, Arc<Vec<u8>>> {
         if !self.file_blocks.contains(&ino) {
             self.file_blocks.put(ino, LruCache::new(self.file_block_cap));
@@ -79,113 +813,282 @@ impl <B:RemoteBackend> Cache<B> {
         self.file_blocks.get_mut(&ino).unwrap()
     }
 
-    fn read_block_aligned(&mut self, ino: u64, block_idx: u64) -> Result<Arc<Vec<u8>>, BackendError> {
-        let off = block_idx * BLOCK_SIZE as u64;
-        let buf = self.http_backend.read_chunk(ino, off, BLOCK_SIZE as u64)?;
-        Ok(Arc::new(buf))
-    }
-}
-
-impl <B:RemoteBackend> RemoteBackend for Cache<B> {
-    fn list_dir(&mut self, ino: u64) -> Result<Vec<FileEntry>, BackendError> {
-        // se abbiamo la lista in cache, usiamola
-
-        if let Some(cached) = self.dir_child.get(&ino).cloned() {
-            let mtime=self.get_cached_mtime(ino).unwrap_or(SystemTime::UNIX_EPOCH);
-            match self.http_backend.get_attr_if_modified_since(ino, mtime)? {
+    // lista della directory ricostruita dalla cache, None se manca o non è più valida
+    fn cached_listing(&mut self, ino: u64, revalidate: bool) -> Result<Option<Vec<FileEntry>>, BackendError> {
+        // con il lease sulla directory il server ci avvisa di ogni modifica alla lista
+        self.ensure_lease(ino, LeaseKind::Read);
+        let Some(cached) = self.dir_child.get(&ino).cloned() else { return self.restored_listing(ino) };
+        let mtime=self.get_cached_mtime(ino).unwrap_or(SystemTime::UNIX_EPOCH);
+        let changed = if !revalidate || self.has_lease(ino) { None } else { self.http_backend.get_attr_if_modified_since(ino, mtime)? };
+        if changed.is_some() {
+            // la directory è cambiata, invalidiamo la cache
+            self.forget_listing(ino);
+            return Ok(None);
+        }
+        // proviamo a ricostruire la cache dai dati esistenti
+        let mut result = Vec::with_capacity(cached.len());
+        for child_ino in cached.iter() {
+            match self.meta.get(child_ino).cloned() {
+                Some(child_entry) => result.push((*child_entry).clone()),
                 None => {
-                    // proviamo a ricostruire la cache dai dati esistenti
-                    let mut result = Vec::with_capacity(cached.len());
-                    let mut missing=false;
-                    for child_ino in cached.iter() {
-                        if let Some(child_entry) = self.meta.get(child_ino).cloned() {
-                            result.push((*child_entry).clone());
-                        } else {
-                            // se manca qualche metadato, dobbiamo rifare la lista
-                            missing = true;
-                            break;
-                        }
-                    }
-                    if !missing {
-                        return Ok(result);
-                    }
-                    self.dir_child.pop(&ino);
-                }
-                Some(_) => {
-                    // la directory è cambiata, invalidiamo la cache
+                    // se manca qualche metadato la lista in memoria non basta: resta quella su disco, o va rifatta
                     self.dir_child.pop(&ino);
+                    return self.restored_listing(ino);
                 }
             }
         }
-        // gestiamo il miss o il caso di cache invalida, richiamiamo il backend
-        let entries = self.http_backend.list_dir(ino)?;
-        for e in &entries {
+        Ok(Some(result))
+    }
+
+    fn list_dir_online(&mut self, ino: u64) -> Result<Vec<FileEntry>, BackendError> {
+        // se abbiamo la lista in cache, usiamola
+        if let Some(entries) = self.cached_listing(ino, true)? {
+            return Ok(entries);
+        }
+        // gestiamo il miss o il caso di cache invalida, richiamiamo il backend una pagina alla volta
+        let mut entries = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = self.list_dir_page_online(ino, None, cursor.as_deref(), DIR_PAGE_SIZE)?;
+            entries.extend(page.entries);
+            match page.next {
+                Some(next) => cursor = Some(next),
+                None => return Ok(entries),
+            }
+        }
+    }
+
+    // scarica una pagina e la aggiunge alla lista in costruzione; l'ultima pagina completa la lista in cache
+    fn list_dir_page_online(&mut self, ino: u64, pattern: Option<&str>, cursor: Option<&str>, limit: u32) -> Result<DirPage, BackendError> {
+        let res = self.http_backend.list_dir_page(ino, pattern, cursor, limit);
+        let page = self.track(res)?;
+        self.store_page(ino, pattern, cursor, &page);
+        Ok(page)
+    }
+
+    // metadati delle voci di una pagina scaricata; senza filtro la pagina continua (o ricomincia) la lista in
+    // costruzione, e l'ultima la completa
+    fn store_page(&mut self, ino: FileIno, pattern: Option<&str>, cursor: Option<&str>, page: &DirPage) {
+        for e in &page.entries {
             // facciamo un meccanismo di cache on write
             self.remember_meta(e);
+            self.remember_parent(e.ino, ino);
+        }
+        if pattern.is_some() {
+            return; // le pagine filtrate non formano la lista completa
+        }
+
+        // una pagina che non continua la lista in costruzione (prima pagina o cursore diverso) la ricomincia
+        let mut children = match (cursor, self.partial_dirs.pop(&ino)) {
+            (Some(cursor), Some((expected, children))) if expected == cursor => children,
+            (None, _) => Vec::new(),
+            _ => return,
+        };
+        children.extend(page.entries.iter().cloned());
+        match &page.next {
+            Some(next) => { self.partial_dirs.put(ino, (next.clone(), children)); }
+            None => {
+                self.store_listing(ino, &children);
+                self.dir_child.put(ino, Arc::new(children.iter().map(|e| e.ino).collect()));
+            }
+        }
+    }
+
+    // blocchi da scaricare in anticipo: solo se la read continua quella appena finita sullo stesso file
+    fn coalesce_ahead(&mut self, ino: FileIno, start_block: u64) -> u64 {
+        match self.recent_reads.peek(&ino) {
+            // letture più piccole di un blocco ripartono dal blocco in cui è finita la precedente
+            Some((next, at)) if (start_block == *next || start_block + 1 == *next) && at.elapsed() < COALESCE_WINDOW => MAX_COALESCED_BLOCKS - 1,
+            _ => 0,
         }
+    }
 
-        let child_inos: Vec<u64> = entries.iter().map(|e| e.ino).collect();
-        self.dir_child.put(ino, Arc::new(child_inos));
-        Ok(entries)
+    // ultimo blocco della sequenza di blocchi mancanti che parte da first, senza superare limit
+    fn missing_run_end(&mut self, ino: FileIno, first: u64, limit: u64) -> u64 {
+        let limit = limit.min(first + MAX_COALESCED_BLOCKS.min(self.file_block_cap.get() as u64) - 1);
+        let file_lru = self.file_blocks.peek(&ino);
+        let mut last = first;
+        while last < limit && !file_lru.is_some_and(|lru| lru.contains(&(last + 1))) {
+            last += 1;
+        }
+        last
+    }
+
+    // scarica i blocchi da first a last con una sola richiesta e restituisce il primo (vuoto se oltre EOF)
+    fn read_blocks(&mut self, ino: u64, first: u64, last: u64) -> Result<Arc<Vec<u8>>, BackendError> {
+        let block_size = self.block_size;
+        let off = first * block_size as u64;
+        let res = self.http_backend.read_chunk(ino, off, (last - first + 1) * block_size as u64);
+        let data = self.track(res)?;
+        if data.is_empty() {
+            return Ok(Arc::new(Vec::new()));
+        }
+        let file_lru = self.get_or_create_file_lru(ino);
+        let mut blocks = data.chunks(block_size).enumerate().map(|(i, chunk)| (first + i as u64, Arc::new(chunk.to_vec())));
+        let (_, head) = blocks.next().expect("data is not empty");
+        file_lru.put(first, head.clone());
+        for (idx, block) in blocks {
+            file_lru.put(idx, block);
+        }
+        Ok(head)
+    }
+}
+
+impl <B:RemoteBackend> RemoteBackend for Cache<B> {
+    fn list_dir(&mut self, ino: u64) -> Result<Vec<FileEntry>, BackendError> {
+        match self.list_dir_online(ino) {
+            Err(e) if is_offline(&e) => self.offline_listing(ino).ok_or(e),
+            res => res,
+        }
+    }
+
+    fn disk_usage(&mut self, ino: u64) -> Result<DiskUsage, BackendError> {
+        self.http_backend.disk_usage(ino)
+    }
+
+    fn search(&mut self, root_ino: u64, query: &SearchQuery) -> Result<Vec<FileEntry>, BackendError> {
+        let found = self.http_backend.search(root_ino, query)?;
+        for e in &found {
+            self.remember_meta(e);
+        }
+        Ok(found)
+    }
+
+    fn list_dir_page(&mut self, ino: u64, pattern: Option<&str>, cursor: Option<&str>, limit: u32) -> Result<DirPage, BackendError> {
+        // le pagine successive alla prima si servono dalla lista già validata all'inizio della lettura
+        let res = match self.cached_listing(ino, cursor.is_none()) {
+            Ok(Some(entries)) => return Ok(DirPage::from_listing(entries, pattern, cursor, limit)),
+            Ok(None) => self.list_dir_page_online(ino, pattern, cursor, limit),
+            Err(e) => Err(e),
+        };
+        match res {
+            Err(e) if is_offline(&e) => Ok(DirPage::from_listing(self.offline_listing(ino).ok_or(e)?, pattern, cursor, limit)),
+            res => res,
+        }
     }
 
     fn get_attr(&mut self, ino: u64) -> Result<FileEntry, BackendError> {
-        self.revalidate_meta(ino)
+        match self.revalidate_meta(ino) {
+            Err(e) if is_offline(&e) => {
+                // server irraggiungibile: ultimi metadati noti, dal pin o dalla cache
+                let entry = self.pinned_entry(ino).or_else(|| self.meta.peek(&ino).map(|e| (**e).clone())).ok_or(e)?;
+                self.health.served_from_cache();
+                Ok(entry)
+            }
+            res => res,
+        }
     }
 
     fn lookup(&mut self, parent_ino:u64, name:&str) -> Result<FileEntry, BackendError> {
-        let res = self.http_backend.lookup(parent_ino, name)?;
+        let res = self.http_backend.lookup(parent_ino, name);
+        let res = match self.track(res) {
+            Ok(res) => res,
+            Err(e) if is_offline(&e) => {
+                // offline: cerchiamo tra i figli di una directory pinnata, poi nella lista in cache
+                let found = self.pinned.get(&parent_ino)
+                    .and_then(|p| p.children.as_ref())
+                    .and_then(|children| children.iter().filter_map(|c| self.pinned.get(c)).find(|c| c.entry.name == name))
+                    .map(|c| c.entry.clone())
+                    .or_else(|| self.cached_children(parent_ino)?.into_iter().find(|c| c.name == name));
+                let found = found.ok_or(e)?;
+                self.health.served_from_cache();
+                return Ok(found);
+            }
+            Err(e) => return Err(e),
+        };
+        // l'ino trovato era in cache per un altro oggetto: i suoi blocchi non valgono per questo
+        if self.meta.peek(&res.ino).is_some_and(|cached| !same_object(cached, &res)) {
+            self.forget_stale(res.ino);
+        }
         self.remember_meta(&res);
+        self.remember_parent(res.ino, parent_ino);
         Ok(res)
     }
 
     fn create_file(&mut self, parent_ino:u64, name:&str) -> Result<FileEntry, BackendError> {
+        self.check_flags(parent_ino, FILE_FLAG_IMMUTABLE)?;
         let res= self.http_backend.create_file(parent_ino, name)?;
         self.remember_meta(&res);
-        self.dir_child.pop(&parent_ino);
+        self.dir_changed(parent_ino, 1, res.ctime);
         Ok(res)
     }
 
     fn create_dir(&mut self, parent_ino:u64, name:&str) -> Result<FileEntry, BackendError> {
+        self.check_flags(parent_ino, FILE_FLAG_IMMUTABLE)?;
         let res= self.http_backend.create_dir(parent_ino, name)?;
         self.remember_meta(&res);
-        self.dir_child.pop(&parent_ino);
+        self.remember_parent(res.ino, parent_ino);
+        self.dir_changed(parent_ino, 1, res.ctime);
+        Ok(res)
+    }
+
+    fn create_file_with_mode(&mut self, parent_ino:u64, name:&str, mode: u32) -> Result<FileEntry, BackendError> {
+        self.check_flags(parent_ino, FILE_FLAG_IMMUTABLE)?;
+        let res= self.http_backend.create_file_with_mode(parent_ino, name, mode)?;
+        self.remember_meta(&res);
+        self.dir_changed(parent_ino, 1, res.ctime);
+        Ok(res)
+    }
+
+    fn create_dir_with_mode(&mut self, parent_ino:u64, name:&str, mode: u32) -> Result<FileEntry, BackendError> {
+        self.check_flags(parent_ino, FILE_FLAG_IMMUTABLE)?;
+        let res= self.http_backend.create_dir_with_mode(parent_ino, name, mode)?;
+        self.remember_meta(&res);
+        self.remember_parent(res.ino, parent_ino);
+        self.dir_changed(parent_ino, 1, res.ctime);
         Ok(res)
     }
 
     fn delete_file(&mut self, parent_ino:u64, name:&str) -> Result<(), BackendError> {
+        self.check_removable(parent_ino, name)?;
         self.http_backend.delete_file(parent_ino, name)?;
-        self.dir_child.pop(&parent_ino);
+        // l'orario del server non lo conosciamo: fino alla rivalidazione vale quello locale
+        self.dir_changed(parent_ino, -1, SystemTime::now());
         Ok(())
     }
 
     fn delete_dir(&mut self, parent_ino:u64, name:&str) -> Result<(), BackendError> {
+        self.check_removable(parent_ino, name)?;
         self.http_backend.delete_dir(parent_ino, name)?;
-        self.dir_child.pop(&parent_ino);
+        self.dir_changed(parent_ino, -1, SystemTime::now());
         Ok(())
     }
 
     fn read_chunk(&mut self, ino: u64, offset: u64, size: u64)-> Result<Vec<u8>, BackendError> {
-        let _ = self.revalidate_meta(ino)?; // assicuriamoci che il file sia aggiornato
-        let (start_block, end_block) = block_span(offset, size);
+        self.ensure_lease(ino, LeaseKind::Read);
+        if self.pinned.contains_key(&ino) {
+            return self.read_pinned(ino, offset, size);
+        }
+        // assicuriamoci che il file sia aggiornato; se il server non risponde va bene la copia in cache,
+        // purché contenga tutta la parte richiesta
+        if let Err(e) = self.revalidate_meta(ino) {
+            if !is_offline(&e) {
+                return Err(e);
+            }
+            let data = self.cached_range(ino, offset, size).ok_or(e)?;
+            self.health.served_from_cache();
+            return Ok(data);
+        }
+        let (start_block, end_block) = block_span(offset, size, self.block_size);
+        let ahead = self.coalesce_ahead(ino, start_block);
+        self.recent_reads.put(ino, (end_block + 1, Instant::now()));
         let mut result = Vec::with_capacity(size as usize);
 
         for block_idx in start_block..=end_block {
             let arc= if let Some(cached_block) = self.file_blocks.get_mut(&ino).and_then(|file_lru| file_lru.get(&block_idx)).cloned() {
                 cached_block
             } else {
-                let buf= self.read_block_aligned(ino, block_idx)?;
-                let file_lru= self.get_or_create_file_lru(ino);
-                file_lru.put(block_idx, buf.clone());
-                buf
+                // i blocchi mancanti contigui (più quelli in anticipo per le read sequenziali) in una sola richiesta
+                let last = self.missing_run_end(ino, block_idx, end_block + ahead);
+                self.read_blocks(ino, block_idx, last)?
             };
             if arc.is_empty() {
                 break; // EOF
             }
 
-            let block_offset = block_idx * BLOCK_SIZE as u64;
-            let start = offset.saturating_sub(block_offset).min(BLOCK_SIZE as u64) as usize;
-            let mut end = ((offset + size).saturating_sub(block_offset)).min(BLOCK_SIZE as u64) as usize;
+            let block_offset = block_idx * self.block_size as u64;
+            let start = offset.saturating_sub(block_offset).min(self.block_size as u64) as usize;
+            let mut end = ((offset + size).saturating_sub(block_offset)).min(self.block_size as u64) as usize;
             if end > arc.len() {
                 end = arc.len();
             }
@@ -196,62 +1099,229 @@ impl <B:RemoteBackend> RemoteBackend for Cache<B> {
         Ok(result)
     }
 
+    fn read_direct(&mut self, ino: u64, offset: u64, size: u64) -> Result<Vec<u8>, BackendError> {
+        //passthrough: i blocchi in cache non vengono né letti né aggiornati
+        self.http_backend.read_chunk(ino, offset, size)
+    }
+
+    fn cached_read(&mut self, ino: u64, offset: u64, size: u64) -> Option<Vec<u8>> {
+        // nessuna richiesta al server: il lease lo chiede prima chi legge (wanted_lease / offer_lease)
+        if self.pinned.contains_key(&ino) || !self.has_lease(ino) {
+            return None;
+        }
+        self.cached_range(ino, offset, size)
+    }
+
+    fn has_local_copy(&mut self, ino: u64) -> bool {
+        self.is_pinned(ino)
+    }
+
+    fn hydration(&mut self, ino: u64) -> Hydration {
+        match self.pinned.get(&ino) {
+            None => Hydration::Remote,
+            Some(p) if p.hydrated => Hydration::Hydrated,
+            Some(_) => Hydration::Pinned,
+        }
+    }
+
+    fn hydrate(&mut self, ino: u64, pinned: bool) -> Result<(), BackendError> {
+        let Some(entry) = self.pinned.get_mut(&ino) else {
+            return self.store_pin(ino, !pinned).map(|_| ());
+        };
+        if entry.hydrated != pinned {
+            return Ok(());
+        }
+        entry.hydrated = !pinned;
+        let store = self.pin_store.as_ref().expect("pinned entries require a pin store");
+        store.save(entry).map_err(pin_error)
+    }
+
+    fn dehydrate(&mut self, ino: u64) -> bool {
+        self.unpin(ino) > 0
+    }
+
+    fn prime_range(&mut self, entry: &FileEntry, offset: u64, data: &[u8]) {
+        let block_size = self.block_size;
+        if !offset.is_multiple_of(block_size as u64) || self.meta.peek(&entry.ino).map(|e| e.mtime) != Some(entry.mtime) {
+            return;
+        }
+        let first = offset / block_size as u64;
+        let file_lru = self.get_or_create_file_lru(entry.ino);
+        for (i, block) in data.chunks(block_size).enumerate() {
+            let idx = first + i as u64;
+            let end = idx * block_size as u64 + block.len() as u64;
+            // un blocco corto vale solo a fine file, altrimenti verrebbe letto come EOF
+            if block.len() < block_size && end != entry.size {
+                break;
+            }
+            if !file_lru.contains(&idx) {
+                file_lru.put(idx, Arc::new(block.to_vec()));
+            }
+        }
+    }
+
+    fn known_attr(&mut self, ino: u64) -> Option<FileEntry> {
+        self.meta.peek(&ino).map(|e| (**e).clone())
+    }
+
+    fn offer_attr(&mut self, entry: &FileEntry, parent: Option<u64>) {
+        if self.meta.peek(&entry.ino).is_some_and(|cached| !same_object(cached, entry)) {
+            self.forget_stale(entry.ino);
+        } else if self.get_cached_mtime(entry.ino).is_some_and(|prev| entry.mtime > prev) {
+            // cambiato sul server: blocchi e lista si riscaricano (gli hash costerebbero un'altra richiesta)
+            self.file_blocks.pop(&entry.ino);
+            self.forget_listing(entry.ino);
+        }
+        self.refresh_pinned(entry, false);
+        self.remember_meta(entry);
+        if let Some(parent) = parent {
+            self.remember_parent(entry.ino, parent);
+        }
+    }
+
+    fn cached_page(&mut self, ino: u64, cursor: Option<&str>, limit: u32) -> Option<DirPage> {
+        // la prima pagina vale solo con il lease sulla directory, le successive continuano la lista già validata
+        if cursor.is_none() && !self.has_lease(ino) {
+            return None;
+        }
+        let children = self.cached_children(ino)?;
+        Some(DirPage::from_listing(children, None, cursor, limit))
+    }
+
+    fn offer_page(&mut self, ino: u64, cursor: Option<&str>, page: &DirPage) {
+        self.store_page(ino, None, cursor, page);
+    }
+
     fn write_chunk(&mut self, ino: u64, offset: u64, data: Vec<u8>) -> Result<u64, BackendError> {
-        let bytes_written = self.http_backend.write_chunk(ino, offset, data.clone())?;
-        let (start_block, end_block) = block_span(offset, bytes_written);
+        self.check_write(ino, Some(offset))?;
+        self.ensure_lease(ino, LeaseKind::Write);
+        // la copia serve solo per aggiornare i file pinnati, gli altri dati vanno al backend senza copie
+        let pinned_copy = self.pinned.contains_key(&ino).then(|| data.clone());
+        let bytes_written = self.http_backend.write_chunk(ino, offset, data).inspect_err(|e| self.forget_on_conflict(ino, e))?;
+        self.written(ino, offset, bytes_written, pinned_copy);
+        Ok(bytes_written)
+    }
+
+    fn write_batch(&mut self, writes: Vec<BatchWrite>) -> Result<Vec<Result<u64, BackendError>>, BackendError> {
+        for w in &writes {
+            self.check_write(w.ino, Some(w.offset))?;
+            self.ensure_lease(w.ino, LeaseKind::Write);
+        }
+        let sent: Vec<(u64, u64, Option<Vec<u8>>)> = writes.iter()
+            .map(|w| (w.ino, w.offset, self.pinned.contains_key(&w.ino).then(|| w.data.clone())))
+            .collect();
+        let results = self.http_backend.write_batch(writes)?;
+        for ((ino, offset, pinned_copy), res) in sent.into_iter().zip(results.iter()) {
+            match res {
+                Ok(bytes_written) => self.written(ino, offset, *bytes_written, pinned_copy),
+                Err(e) => self.forget_on_conflict(ino, e),
+            }
+        }
+        Ok(results)
+    }
+
+    fn write_append(&mut self, ino: u64, data: Vec<u8>) -> Result<u64, BackendError> {
+        self.check_write(ino, None)?;
+        self.ensure_lease(ino, LeaseKind::Write);
+        let len = data.len() as u64;
+        let pinned_copy = self.pinned.contains_key(&ino).then(|| data.clone());
+        let offset = self.http_backend.write_append(ino, data)?;
+        if let Some(data) = pinned_copy {
+            self.sync_pinned_write(ino, offset, &data);
+        }
+        let (start_block, end_block) = block_span(offset, len, self.block_size);
         if let Some(file_lru) = self.file_blocks.get_mut(&ino){
             for block_idx in start_block..=end_block {
                 file_lru.pop(&block_idx);
             }
         }
-        // forzo la rivalidazione dei metadati al prossimo accesso
         self.meta.pop(&ino);
-        Ok(bytes_written)
+        Ok(offset)
     }
 
     fn rename(&mut self, old_parent_ino:u64, old_name: &str, new_parent_ino: u64, new_name: &str) -> Result<FileEntry, BackendError> {
+        self.check_removable(old_parent_ino, old_name)?;
+        self.check_flags(new_parent_ino, FILE_FLAG_IMMUTABLE)?;
+        self.check_entry_flags(new_parent_ino, new_name, FILE_FLAG_IMMUTABLE | FILE_FLAG_APPEND)?;
+        let replaced = self.cached_children(new_parent_ino).is_some_and(|children| children.iter().any(|c| c.name == new_name));
+        let target = self.peek_child(new_parent_ino, new_name);
         let res= self.http_backend.rename(old_parent_ino, old_name, new_parent_ino, new_name)?;
+        // la destinazione sostituita (salvataggio atomico) ha perso quel nome: quello che sappiamo di lei è vecchio
+        if let Some(target) = target && target != res.ino {
+            self.meta.pop(&target);
+            self.file_blocks.pop(&target);
+            self.leases.remove(&target);
+            self.unpin(target);
+        }
+        let old_path = self.meta.peek(&old_parent_ino).map(|parent| child_path(&parent.path, old_name))
+            .or_else(|| self.meta.peek(&res.ino).map(|cached| cached.path.clone()));
+        self.moved(old_path.as_deref(), &res);
         self.remember_meta(&res);
-        self.dir_child.pop(&old_parent_ino);
+        self.remember_parent(res.ino, new_parent_ino);
         if old_parent_ino != new_parent_ino {
-            self.dir_child.pop(&new_parent_ino);
+            self.dir_changed(old_parent_ino, -1, res.ctime);
+            self.dir_changed(new_parent_ino, i64::from(!replaced), res.ctime);
+        } else {
+            self.dir_changed(old_parent_ino, -i64::from(replaced), res.ctime);
         }
         Ok(res)
     }
 
     fn set_attr(&mut self, ino:u64, attrs: SetAttrRequest) -> Result<FileEntry, BackendError> {
-        let res= self.http_backend.set_attr(ino, attrs)?;
-        if let Some(prev) = self.get_cached_mtime(ino) {
-            if res.mtime > prev {
-                self.file_blocks.pop(&ino); // il file è cambiato, invalidiamo i blocchi
+        // su un file immutabile si possono cambiare solo i flag stessi
+        let changes_attrs = attrs.perm.is_some() || attrs.uid.is_some() || attrs.gid.is_some() || attrs.size.is_some() || attrs.flags.is_some()
+            || attrs.atime.is_some() || attrs.mtime.is_some() || attrs.btime.is_some();
+        if changes_attrs {
+            self.check_flags(ino, FILE_FLAG_IMMUTABLE)?;
+        }
+        if attrs.size.is_some() {
+            self.check_flags(ino, FILE_FLAG_APPEND)?;
+        }
+        let truncate = attrs.size;
+        let res= self.http_backend.set_attr(ino, attrs).inspect_err(|e| self.forget_on_conflict(ino, e))?;
+        if self.pinned.contains_key(&ino) {
+            if let Some(size) = truncate && let Err(e) = self.pin_store.as_ref().expect("pinned entries require a pin store").truncate(ino, size) {
+                eprintln!("Unable to truncate pinned data of ino {}: {}", ino, e);
             }
+            self.refresh_pinned(&res, true);
+        }
+        if let Some(prev) = self.get_cached_mtime(ino) && res.mtime > prev {
+            self.revalidate_blocks(ino); // il file è cambiato, rivalidiamo i blocchi
         }
         self.remember_meta(&res);
         Ok(res)
     }
 
-    fn read_stream(&mut self, ino: u64, offset: u64) -> Result<ByteStream, BackendError> {
+    fn read_stream(&mut self, ino: u64, offset: u64, len: Option<u64>) -> Result<ByteStream, BackendError> {
         //passthrough
-        self.http_backend.read_stream(ino, offset)
+        self.http_backend.read_stream(ino, offset, len)
     }
 
-    fn write_stream(&mut self, ino: u64, offset: u64, data: Vec<u8>) -> Result<(), BackendError> {
-        //passthrough
-        self.http_backend.write_stream(ino, offset, data)
+    fn write_stream(&mut self, ino: u64, offset: u64, data: ByteStream) -> Result<(), BackendError> {
+        self.check_write(ino, Some(offset))?;
+        self.ensure_lease(ino, LeaseKind::Write);
+        //passthrough: i dati non passano dalla cache, quindi non possiamo aggiornarla con quanto scritto
+        self.http_backend.write_stream(ino, offset, data).inspect_err(|e| self.forget_on_conflict(ino, e))?;
+        self.forget_content(ino);
+        Ok(())
     }
 
     fn link(&mut self, target_ino: u64, link_parent_ino: u64, link_name: &str) -> Result<FileEntry, BackendError> {
+        self.check_flags(target_ino, FILE_FLAG_IMMUTABLE | FILE_FLAG_APPEND)?;
+        self.check_flags(link_parent_ino, FILE_FLAG_IMMUTABLE)?;
         let res= self.http_backend.link(target_ino, link_parent_ino, link_name)?;
         self.meta.pop(&target_ino); // il numero di link è cambiato
         self.remember_meta(&res);
-        self.dir_child.pop(&link_parent_ino);
+        // il ctime di un hard link è quello del file di destinazione: meglio l'orario locale
+        self.dir_changed(link_parent_ino, 1, SystemTime::now());
         Ok(res)
     }
 
     fn symlink(&mut self, target_path: &str, link_parent_ino: u64, link_name: &str) -> Result<FileEntry, BackendError> {
+        self.check_flags(link_parent_ino, FILE_FLAG_IMMUTABLE)?;
         let res = self.http_backend.symlink(target_path, link_parent_ino, link_name)?;
         self.remember_meta(&res);
-        self.dir_child.pop(&link_parent_ino);
+        self.dir_changed(link_parent_ino, 1, res.ctime);
         Ok(res)
     }
 
@@ -262,6 +1332,76 @@ impl <B:RemoteBackend> RemoteBackend for Cache<B> {
 
     // used just in windows
     fn get_size(&mut self) -> Result<(u64, u64), BackendError> {
-        Ok((0, 0))
+        //passthrough
+        self.http_backend.get_size()
+    }
+
+    fn server_limits(&mut self) -> Result<Option<ServerLimits>, BackendError> {
+        self.http_backend.server_limits()
+    }
+
+    fn set_cancel_token(&mut self, token: Option<CancellationToken>) {
+        self.http_backend.set_cancel_token(token)
+    }
+
+    fn hold_open(&mut self, ino: u64) -> Result<(), BackendError> {
+        self.http_backend.hold_open(ino)
+    }
+
+    fn release_open(&mut self, ino: u64) -> Result<(), BackendError> {
+        self.http_backend.release_open(ino)
+    }
+
+    fn test_lock(&mut self, ino: u64, lock: &ByteLock) -> Result<Option<ByteLock>, BackendError> {
+        self.http_backend.test_lock(ino, lock)
+    }
+
+    fn set_lock(&mut self, ino: u64, lock: &ByteLock) -> Result<(), BackendError> {
+        self.http_backend.set_lock(ino, lock)
+    }
+
+    fn acquire_lease(&mut self, ino: u64, kind: LeaseKind) -> Result<Option<Lease>, BackendError> {
+        self.http_backend.acquire_lease(ino, kind)
+    }
+
+    fn wanted_lease(&mut self, ino: u64, kind: LeaseKind) -> bool {
+        self.lease_wanted(ino, kind)
+    }
+
+    fn offer_lease(&mut self, ino: u64, lease: Option<Lease>, asked: Instant) {
+        self.install_lease(ino, lease, asked);
+    }
+
+    fn release_lease(&mut self, ino: u64) -> Result<(), BackendError> {
+        if self.leases.remove(&ino).is_some() {
+            self.http_backend.release_lease(ino)?;
+        }
+        Ok(())
+    }
+
+    fn lease_left(&mut self, ino: u64) -> Option<Duration> {
+        if !self.has_lease(ino) {
+            return None;
+        }
+        self.leases.get(&ino).map(|lease| lease.expires.saturating_duration_since(Instant::now() + LEASE_MARGIN))
+    }
+
+    fn session_epoch(&self) -> u64 {
+        self.http_backend.session_epoch()
+    }
+
+    fn prepare_write(&mut self, ino: u64, offset: u64) -> Result<(), BackendError> {
+        self.check_write(ino, Some(offset))?;
+        self.ensure_lease(ino, LeaseKind::Write);
+        self.http_backend.prepare_write(ino, offset)
+    }
+
+    fn invalidate(&mut self, ino: u64) {
+        self.forget_content(ino);
+        self.http_backend.invalidate(ino);
+    }
+
+    fn validator(&self, ino: u64) -> Option<String> {
+        self.http_backend.validator(ino)
     }
 }
\ No newline at end of file
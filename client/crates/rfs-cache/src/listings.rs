@@ -0,0 +1,92 @@
+// Liste delle directory salvate su disco, così dopo un rimontaggio un albero già visitato non va rilistato
+// directory per directory. Layout: <dir>/<ino>.json (StoredListing). La lista vale finché il mtime della
+// directory sul server è quello salvato: viene controllata con una sola richiesta condizionale, e i
+// metadati dei figli vengono comunque rivalidati alla prima get_attr. Con la chiave della cache le liste
+// sono cifrate; una lista cifrata letta senza chiave viene scartata come le altre illeggibili, e così una lista
+// in chiaro dopo che la cartella è stata cifrata tutta (<dir>/.sealed).
+
+use crate::crypt::{self, CacheKey};
+use rfs_models::FileEntry;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredListing {
+    /// la directory com'era quando è stata listata: il suo mtime è il validatore della lista
+    pub dir: FileEntry,
+    pub children: Vec<FileEntry>,
+}
+
+pub struct ListingStore {
+    dir: PathBuf,
+    key: Option<CacheKey>,
+    // tutte le liste sono cifrate: una in chiaro viene rifiutata
+    migrated: bool,
+}
+
+impl ListingStore {
+    /// Apre le liste in `dir`; con `key` quelle salvate in chiaro vengono cifrate subito.
+    pub fn open(dir: impl AsRef<Path>, key: Option<CacheKey>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let marker = dir.join(crypt::SEALED_MARKER);
+        if key.is_none() {
+            if let Err(e) = fs::remove_file(&marker) && e.kind() != io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        } else if !marker.exists() {
+            for item in fs::read_dir(&dir)? {
+                let path = item?.path();
+                let Ok(raw) = fs::read(&path) else { continue };
+                if path.extension().and_then(|e| e.to_str()) == Some("json") && !crypt::is_sealed(&raw) {
+                    // illeggibile con questa chiave: verrà scartata alla prima lettura
+                    let Ok(raw) = crypt::unseal(key.as_ref(), raw, &path, false) else { continue };
+                    let tmp = path.with_extension("json.tmp");
+                    fs::write(&tmp, crypt::seal(key.as_ref(), raw, &path)?)?;
+                    fs::rename(tmp, &path)?;
+                }
+            }
+            fs::write(&marker, b"")?;
+        }
+        let migrated = key.is_some();
+        Ok(Self { dir, key, migrated })
+    }
+
+    fn path(&self, ino: u64) -> PathBuf {
+        self.dir.join(format!("{}.json", ino))
+    }
+
+    /// Lista salvata per `ino`; una lista illeggibile viene scartata.
+    pub fn load(&self, ino: u64) -> Option<StoredListing> {
+        let path = self.path(ino);
+        let raw = fs::read(&path).ok()?;
+        let listing = crypt::unseal(self.key.as_ref(), raw, &path, self.migrated).ok()
+            .and_then(|raw| serde_json::from_slice::<StoredListing>(&raw).ok())
+            .filter(|l| l.dir.ino == ino);
+        if listing.is_none() {
+            self.remove(ino);
+        }
+        listing
+    }
+
+    pub fn save(&self, listing: &StoredListing) -> io::Result<()> {
+        let path = self.path(listing.dir.ino);
+        let raw = crypt::seal(self.key.as_ref(), serde_json::to_vec(listing).map_err(io::Error::other)?, &path)?;
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, raw)?;
+        fs::rename(tmp, path)
+    }
+
+    pub fn contains(&self, ino: u64) -> bool {
+        self.path(ino).exists()
+    }
+
+    pub fn remove(&self, ino: u64) {
+        match fs::remove_file(self.path(ino)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => eprintln!("Unable to remove saved listing of ino {}: {}", ino, e),
+            _ => {}
+        }
+    }
+}
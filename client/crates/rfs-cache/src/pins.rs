@@ -0,0 +1,473 @@
+// Archivio su disco dei file "pinnati": contenuto completo e metadati, mai soggetti a eviction.
+// Layout: <dir>/<ino>.json (PinnedEntry), <dir>/<ino>.blocks (BlockList, solo per i file regolari) e
+// <dir>/blocks/<xx>/<sha256> con il contenuto dei blocchi. I blocchi sono indirizzati per hash, così quelli
+// uguali in file o versioni diverse (artefatti duplicati, file copiati) occupano spazio una volta sola; i
+// riferimenti a ogni blocco vengono contati all'apertura dalle liste e un blocco senza riferimenti viene
+// cancellato. Le copie nel vecchio formato (<ino>.data) vengono convertite all'apertura. Con la cifratura
+// (crypt.rs) i blocchi prendono il nome da un HMAC, e a conversione finita l'archivio è segnato da
+// <dir>/.sealed.
+
+use crate::crypt::{self, CacheKey};
+use rfs_models::FileEntry;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// dimensione dei blocchi su disco
+const STORE_BLOCK_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinnedEntry {
+    pub entry: FileEntry,
+    /// figli di una directory pinnata, per poterla listare offline
+    pub children: Option<Vec<u64>>,
+    /// il file è cambiato sul server e la copia locale va riscaricata
+    #[serde(default)]
+    pub stale: bool,
+    /// scaricato alla prima lettura (file su richiesta) e non pinnato dall'utente: si può liberare
+    #[serde(default)]
+    pub hydrated: bool,
+}
+
+// contenuto di un file: lunghezza e hash dei suoi blocchi, in ordine
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct BlockList {
+    len: u64,
+    blocks: Vec<String>,
+}
+
+impl BlockList {
+    // una lista letta da disco può essere troncata o corrotta (crash, disco danneggiato)
+    fn checked(self) -> io::Result<Self> {
+        if self.blocks.len() as u64 != self.len.div_ceil(STORE_BLOCK_SIZE) {
+            return Err(corrupt_list());
+        }
+        Ok(self)
+    }
+}
+
+fn corrupt_list() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "cache block list does not match its length")
+}
+
+pub struct PinStore {
+    dir: PathBuf,
+    // riferimenti a ogni blocco dalle liste dei file
+    refs: Mutex<HashMap<String, u32>>,
+    // con la chiave blocchi, liste e voci vengono cifrati
+    key: Option<CacheKey>,
+    // tutto il contenuto è cifrato: un file in chiaro viene rifiutato
+    migrated: bool,
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(tmp, path)
+}
+
+/// Contenuto di un file in scrittura: i blocchi completi vanno subito su disco, in memoria resta solo
+/// l'ultimo parziale. Se viene scartato senza commit i blocchi già salvati vengono rilasciati
+pub struct DataWriter<'a> {
+    store: &'a PinStore,
+    ino: u64,
+    list: BlockList,
+    tail: Vec<u8>,
+}
+
+impl DataWriter<'_> {
+    pub fn push(&mut self, data: &[u8]) -> io::Result<()> {
+        self.tail.extend_from_slice(data);
+        self.list.len += data.len() as u64;
+        let full = self.tail.len() - self.tail.len() % STORE_BLOCK_SIZE as usize;
+        for chunk in self.tail[..full].chunks(STORE_BLOCK_SIZE as usize) {
+            let hash = self.store.put_block(chunk)?;
+            self.list.blocks.push(hash);
+        }
+        self.tail.drain(..full);
+        Ok(())
+    }
+
+    /// Salva la lista dei blocchi al posto di quella vecchia
+    pub fn commit(mut self) -> io::Result<()> {
+        if !self.tail.is_empty() {
+            let hash = self.store.put_block(&self.tail)?;
+            self.list.blocks.push(hash);
+        }
+        let old = self.store.list_or_empty(self.ino)?;
+        self.store.write_list(self.ino, &self.list)?;
+        self.list.blocks.clear(); // ora sono della lista salvata, il drop non deve rilasciarli
+        self.store.release(old.blocks);
+        Ok(())
+    }
+}
+
+impl Drop for DataWriter<'_> {
+    fn drop(&mut self) {
+        self.store.release(std::mem::take(&mut self.list.blocks));
+    }
+}
+
+impl PinStore {
+    /// Apre l'archivio in `dir`; con `key` il contenuto viene cifrato, e quello scritto in chiaro da un
+    /// mount precedente viene convertito. Un archivio cifrato non si apre senza la chiave.
+    pub fn open(dir: impl AsRef<Path>, key: Option<CacheKey>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(dir.join("blocks"))?;
+        let migrated = key.is_some() && dir.join(crypt::SEALED_MARKER).exists();
+        let mut store = Self { dir, refs: Mutex::new(HashMap::new()), key, migrated };
+        let mut refs = HashMap::new();
+        let mut plain = Vec::new();
+        for item in fs::read_dir(&store.dir)? {
+            let path = item?.path();
+            let ext = path.extension().and_then(|e| e.to_str());
+            if ext != Some("blocks") && ext != Some("json") {
+                continue;
+            }
+            let Ok(raw) = fs::read(&path) else { continue };
+            let sealed = crypt::is_sealed(&raw);
+            let raw = match store.unseal(&path, raw) {
+                Err(e) if e.kind() == ErrorKind::InvalidData && !sealed => {
+                    eprintln!("Ignoring unencrypted file {} in an encrypted cache", path.display());
+                    continue;
+                }
+                res => res?,
+            };
+            if !sealed {
+                plain.push(path.clone());
+            }
+            if ext == Some("blocks") && let Ok(list) = serde_json::from_slice::<BlockList>(&raw) {
+                for hash in list.blocks {
+                    *refs.entry(hash).or_insert(0) += 1;
+                }
+            }
+        }
+        *store.refs.lock().expect("Mutex poisoned") = refs;
+        let marker = store.dir.join(crypt::SEALED_MARKER);
+        if store.key.is_none() {
+            store.migrate()?;
+            // senza chiave l'archivio torna in chiaro
+            if let Err(e) = fs::remove_file(&marker) && e.kind() != ErrorKind::NotFound {
+                return Err(e);
+            }
+        } else if !store.migrated {
+            store.migrate()?;
+            store.encrypt(plain)?;
+            fs::write(&marker, b"")?;
+            store.migrated = true;
+        }
+        Ok(store)
+    }
+
+    fn seal(&self, path: &Path, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        crypt::seal(self.key.as_ref(), data, path)
+    }
+
+    fn unseal(&self, path: &Path, raw: Vec<u8>) -> io::Result<Vec<u8>> {
+        crypt::unseal(self.key.as_ref(), raw, path, self.migrated)
+    }
+
+    fn meta_path(&self, ino: u64) -> PathBuf {
+        self.dir.join(format!("{}.json", ino))
+    }
+
+    fn list_path(&self, ino: u64) -> PathBuf {
+        self.dir.join(format!("{}.blocks", ino))
+    }
+
+    fn block_path(&self, hash: &str) -> PathBuf {
+        self.dir.join("blocks").join(&hash[..2]).join(hash)
+    }
+
+    // copie locali nel formato precedente, un file <ino>.data per file pinnato
+    fn migrate(&self) -> io::Result<()> {
+        for item in fs::read_dir(&self.dir)? {
+            let path = item?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("data") || !path.with_extension("json").exists() {
+                continue;
+            }
+            let Some(ino) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u64>().ok()) else { continue };
+            self.replace_data(ino, &fs::read(&path)?)?;
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+
+    // voci e contenuti scritti in chiaro (`paths`), riscritti cifrati con blocchi nuovi
+    fn encrypt(&self, paths: Vec<PathBuf>) -> io::Result<()> {
+        for path in paths {
+            let Some(ino) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u64>().ok()) else { continue };
+            if path.extension().and_then(|e| e.to_str()) == Some("json") {
+                let raw = self.unseal(&path, fs::read(&path)?)?;
+                write_atomic(&path, &self.seal(&path, raw)?)?;
+            } else {
+                let list = self.read_list(ino)?;
+                let data = self.read_list_range(&list, 0, list.len)?;
+                self.replace_data(ino, &data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_list(&self, ino: u64) -> io::Result<BlockList> {
+        let path = self.list_path(ino);
+        let raw = self.unseal(&path, fs::read(&path)?)?;
+        serde_json::from_slice::<BlockList>(&raw).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?.checked()
+    }
+
+    fn write_list(&self, ino: u64, list: &BlockList) -> io::Result<()> {
+        let raw = serde_json::to_vec(list).map_err(io::Error::other)?;
+        let path = self.list_path(ino);
+        write_atomic(&path, &self.seal(&path, raw)?)
+    }
+
+    fn block_name(&self, data: &[u8]) -> String {
+        match self.key.as_ref() {
+            Some(key) => key.block_name(data),
+            None => Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+
+    // salva un blocco (se non c'è già) e ne conta un riferimento in più
+    fn put_block(&self, data: &[u8]) -> io::Result<String> {
+        let hash = self.block_name(data);
+        let mut refs = self.refs.lock().expect("Mutex poisoned");
+        let path = self.block_path(&hash);
+        if !refs.contains_key(&hash) || !path.exists() {
+            fs::create_dir_all(path.parent().expect("block paths have a parent"))?;
+            write_atomic(&path, &self.seal(&path, data.to_vec())?)?;
+        }
+        *refs.entry(hash.clone()).or_insert(0) += 1;
+        Ok(hash)
+    }
+
+    // toglie un riferimento ai blocchi; quelli che non servono più a nessun file vengono cancellati
+    fn release(&self, hashes: impl IntoIterator<Item = String>) {
+        let mut refs = self.refs.lock().expect("Mutex poisoned");
+        for hash in hashes {
+            let Some(count) = refs.get_mut(&hash) else { continue };
+            *count -= 1;
+            if *count == 0 {
+                refs.remove(&hash);
+                if let Err(e) = fs::remove_file(self.block_path(&hash)) && e.kind() != ErrorKind::NotFound {
+                    eprintln!("Unable to remove cache block {}: {}", hash, e);
+                }
+            }
+        }
+    }
+
+    fn read_block(&self, hash: &str) -> io::Result<Vec<u8>> {
+        let path = self.block_path(hash);
+        self.unseal(&path, fs::read(&path)?)
+    }
+
+    // byte da `offset` (al massimo `size`) del contenuto descritto da `list`
+    fn read_list_range(&self, list: &BlockList, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+        let end = offset.saturating_add(size).min(list.len);
+        let mut buf = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        let mut pos = offset;
+        while pos < end {
+            let idx = pos / STORE_BLOCK_SIZE;
+            let block = self.read_block(list.blocks.get(idx as usize).ok_or_else(corrupt_list)?)?;
+            let from = (pos - idx * STORE_BLOCK_SIZE) as usize;
+            let to = ((end - idx * STORE_BLOCK_SIZE) as usize).min(block.len());
+            if from >= to {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "cache block shorter than expected"));
+            }
+            buf.extend_from_slice(&block[from..to]);
+            pos += (to - from) as u64;
+        }
+        Ok(buf)
+    }
+
+    // scrive `data` da `offset` nella lista in memoria, riscrivendo i blocchi toccati; restituisce i blocchi
+    // sostituiti, da rilasciare solo dopo aver salvato la lista
+    fn put_range(&self, list: &mut BlockList, offset: u64, data: &[u8]) -> io::Result<Vec<String>> {
+        let mut replaced = Vec::new();
+        if offset > list.len {
+            replaced.extend(self.grow(list, offset)?);
+        }
+        let first = offset / STORE_BLOCK_SIZE;
+        let start = first * STORE_BLOCK_SIZE;
+        let end = offset + data.len() as u64;
+        let mut region = self.read_list_range(list, start, end.div_ceil(STORE_BLOCK_SIZE) * STORE_BLOCK_SIZE - start)?;
+        region.resize(region.len().max((end - start) as usize), 0);
+        region[(offset - start) as usize..(end - start) as usize].copy_from_slice(data);
+        for (i, chunk) in region.chunks(STORE_BLOCK_SIZE as usize).enumerate() {
+            let hash = self.put_block(chunk)?;
+            match list.blocks.get_mut(first as usize + i) {
+                Some(old) => replaced.push(std::mem::replace(old, hash)),
+                None => list.blocks.push(hash),
+            }
+        }
+        list.len = list.len.max(end);
+        Ok(replaced)
+    }
+
+    // allunga il contenuto fino a `size` con zeri, un blocco alla volta
+    fn grow(&self, list: &mut BlockList, size: u64) -> io::Result<Vec<String>> {
+        let mut replaced = Vec::new();
+        while list.len < size {
+            let step = (STORE_BLOCK_SIZE - list.len % STORE_BLOCK_SIZE).min(size - list.len);
+            replaced.extend(self.put_range(list, list.len, &vec![0u8; step as usize])?);
+        }
+        Ok(replaced)
+    }
+
+    /// Carica tutte le voci pinnate; quelle illeggibili vengono scartate.
+    pub fn load(&self) -> io::Result<HashMap<u64, PinnedEntry>> {
+        let mut pinned = HashMap::new();
+        for item in fs::read_dir(&self.dir)? {
+            let path = item?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let raw = fs::read(&path).and_then(|raw| self.unseal(&path, raw));
+            match raw.ok().and_then(|raw| serde_json::from_slice::<PinnedEntry>(&raw).ok()) {
+                Some(p) => { pinned.insert(p.entry.ino, p); }
+                None => eprintln!("Ignoring unreadable pin record {}", path.display()),
+            }
+        }
+        Ok(pinned)
+    }
+
+    /// File rimasti da un demone terminato male: scritture temporanee interrotte (`*.tmp`), contenuti
+    /// senza più la voce corrispondente e blocchi a cui non fa riferimento nessun file.
+    pub fn leftovers(&self) -> io::Result<Vec<PathBuf>> {
+        let mut found = Vec::new();
+        for item in fs::read_dir(&self.dir)? {
+            let path = item?.path();
+            let orphan = match path.extension().and_then(|e| e.to_str()) {
+                Some("tmp") => true,
+                Some("blocks") | Some("data") => !path.with_extension("json").exists(),
+                _ => false,
+            };
+            if orphan {
+                found.push(path);
+            }
+        }
+        let refs = self.refs.lock().expect("Mutex poisoned");
+        for prefix in fs::read_dir(self.dir.join("blocks"))? {
+            let prefix = prefix?.path();
+            if !prefix.is_dir() {
+                continue;
+            }
+            for item in fs::read_dir(&prefix)? {
+                let path = item?.path();
+                let name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+                if name.ends_with(".tmp") || !refs.contains_key(name) {
+                    found.push(path);
+                }
+            }
+        }
+        found.sort();
+        Ok(found)
+    }
+
+    /// Dimensione della copia locale, None se manca o se manca uno dei suoi blocchi.
+    pub fn data_len(&self, ino: u64) -> Option<u64> {
+        let list = self.read_list(ino).ok()?;
+        list.blocks.iter().all(|hash| self.block_path(hash).is_file()).then_some(list.len)
+    }
+
+    pub fn save(&self, pinned: &PinnedEntry) -> io::Result<()> {
+        let raw = serde_json::to_vec(pinned).map_err(io::Error::other)?;
+        let path = self.meta_path(pinned.entry.ino);
+        write_atomic(&path, &self.seal(&path, raw)?)
+    }
+
+    pub fn remove(&self, ino: u64) -> io::Result<()> {
+        let list = self.read_list(ino).ok();
+        for path in [self.meta_path(ino), self.list_path(ino)] {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        if let Some(list) = list {
+            self.release(list.blocks);
+        }
+        Ok(())
+    }
+
+    // la lista di un file, vuota se non ha ancora contenuto locale
+    fn list_or_empty(&self, ino: u64) -> io::Result<BlockList> {
+        match self.read_list(ino) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(BlockList::default()),
+            res => res,
+        }
+    }
+
+    /// Sostituisce il contenuto locale con `data`.
+    pub fn replace_data(&self, ino: u64, data: &[u8]) -> io::Result<()> {
+        let mut writer = self.data_writer(ino);
+        writer.push(data)?;
+        writer.commit()
+    }
+
+    /// Nuovo contenuto di `ino` da scrivere a pezzi, senza tenerlo tutto in memoria; prende il posto
+    /// di quello vecchio solo con `DataWriter::commit`
+    pub fn data_writer(&self, ino: u64) -> DataWriter<'_> {
+        DataWriter { store: self, ino, list: BlockList::default(), tail: Vec::new() }
+    }
+
+    pub fn read_at(&self, ino: u64, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+        let list = self.read_list(ino)?;
+        self.read_list_range(&list, offset, size)
+    }
+
+    pub fn write_at(&self, ino: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let mut list = self.list_or_empty(ino)?;
+        let replaced = self.put_range(&mut list, offset, data)?;
+        self.write_list(ino, &list)?;
+        self.release(replaced);
+        Ok(())
+    }
+
+    pub fn truncate(&self, ino: u64, size: u64) -> io::Result<()> {
+        let mut list = self.list_or_empty(ino)?;
+        let replaced = if size < list.len {
+            let mut dropped = list.blocks.split_off(size.div_ceil(STORE_BLOCK_SIZE) as usize);
+            let tail = size % STORE_BLOCK_SIZE;
+            if tail != 0 {
+                let last = list.blocks.len().checked_sub(1).ok_or_else(corrupt_list)?;
+                let mut block = self.read_block(&list.blocks[last])?;
+                block.truncate(tail as usize);
+                dropped.push(std::mem::replace(&mut list.blocks[last], self.put_block(&block)?));
+            }
+            list.len = size;
+            dropped
+        } else {
+            self.grow(&mut list, size)?
+        };
+        self.write_list(ino, &list)?;
+        self.release(replaced);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_block_list_is_invalid_data() {
+        let dir = std::env::temp_dir().join(format!("rfs-pins-short-list-{}", std::process::id()));
+        let store = PinStore::open(&dir, None).unwrap();
+        store.replace_data(7, &vec![1u8; STORE_BLOCK_SIZE as usize + 10]).unwrap();
+        let mut list = store.read_list(7).unwrap();
+        list.blocks.pop();
+        store.write_list(7, &list).unwrap();
+        assert_eq!(store.read_at(7, STORE_BLOCK_SIZE, 10).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(store.truncate(7, 5).unwrap_err().kind(), ErrorKind::InvalidData);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
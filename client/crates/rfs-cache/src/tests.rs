@@ -0,0 +1,355 @@
+use super::*;
+use rfs_models::ROOT_INO;
+use std::time::UNIX_EPOCH;
+
+// server in memoria: un albero di voci con il contenuto dei file; il rename conserva l'ino come il server
+// vero, e le letture arrivate vengono contate per sapere se la cache ha risposto da sola
+struct MemServer {
+    entries: HashMap<u64, FileEntry>,
+    parents: HashMap<u64, u64>,
+    data: HashMap<u64, Vec<u8>>,
+    next_ino: u64,
+    reads: usize,
+}
+
+fn entry(ino: u64, name: &str, path: String, kind: EntryType, size: u64) -> FileEntry {
+    FileEntry {
+        ino,
+        name: name.to_string(),
+        path,
+        kind,
+        size,
+        perms: 0o755,
+        uid: 1000,
+        gid: 1000,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        btime: UNIX_EPOCH + Duration::from_secs(ino),
+        nlinks: 1,
+        etag: None,
+        flags: 0,
+        file_flags: 0,
+    }
+}
+
+impl MemServer {
+    fn new() -> Self {
+        let mut entries = HashMap::new();
+        entries.insert(ROOT_INO, entry(ROOT_INO, "", "/".to_string(), EntryType::Directory, 0));
+        MemServer { entries, parents: HashMap::new(), data: HashMap::new(), next_ino: ROOT_INO + 1, reads: 0 }
+    }
+
+    fn add(&mut self, parent: u64, name: &str, kind: EntryType, data: &[u8]) -> u64 {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        let path = child_path(&self.entries[&parent].path, name);
+        self.entries.insert(ino, entry(ino, name, path, kind, data.len() as u64));
+        self.parents.insert(ino, parent);
+        self.data.insert(ino, data.to_vec());
+        ino
+    }
+
+    fn child(&self, parent: u64, name: &str) -> Option<u64> {
+        self.parents.iter()
+            .find(|(ino, p)| **p == parent && self.entries[*ino].name == name)
+            .map(|(ino, _)| *ino)
+    }
+
+    fn remove(&mut self, ino: u64) {
+        self.entries.remove(&ino);
+        self.parents.remove(&ino);
+        self.data.remove(&ino);
+    }
+}
+
+fn not_found(what: impl std::fmt::Display) -> BackendError {
+    BackendError::NotFound(what.to_string())
+}
+
+impl RemoteBackend for MemServer {
+    fn list_dir(&mut self, ino: u64) -> Result<Vec<FileEntry>, BackendError> {
+        let mut children: Vec<FileEntry> = self.parents.iter()
+            .filter(|(_, p)| **p == ino)
+            .map(|(child, _)| self.entries[child].clone())
+            .collect();
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(children)
+    }
+
+    fn get_attr(&mut self, ino: u64) -> Result<FileEntry, BackendError> {
+        self.entries.get(&ino).cloned().ok_or_else(|| not_found(ino))
+    }
+
+    fn lookup(&mut self, parent_ino: u64, name: &str) -> Result<FileEntry, BackendError> {
+        let ino = self.child(parent_ino, name).ok_or_else(|| not_found(name))?;
+        self.get_attr(ino)
+    }
+
+    fn create_file(&mut self, parent_ino: u64, name: &str) -> Result<FileEntry, BackendError> {
+        let ino = self.add(parent_ino, name, EntryType::File, b"");
+        self.get_attr(ino)
+    }
+
+    fn create_dir(&mut self, parent_ino: u64, name: &str) -> Result<FileEntry, BackendError> {
+        let ino = self.add(parent_ino, name, EntryType::Directory, b"");
+        self.get_attr(ino)
+    }
+
+    fn delete_file(&mut self, parent_ino: u64, name: &str) -> Result<(), BackendError> {
+        let ino = self.child(parent_ino, name).ok_or_else(|| not_found(name))?;
+        self.remove(ino);
+        Ok(())
+    }
+
+    fn delete_dir(&mut self, parent_ino: u64, name: &str) -> Result<(), BackendError> {
+        self.delete_file(parent_ino, name)
+    }
+
+    fn read_chunk(&mut self, ino: u64, offset: u64, size: u64) -> Result<Vec<u8>, BackendError> {
+        self.reads += 1;
+        let data = self.data.get(&ino).ok_or_else(|| not_found(ino))?;
+        let start = (offset as usize).min(data.len());
+        let end = (offset + size).min(data.len() as u64) as usize;
+        Ok(data[start..end].to_vec())
+    }
+
+    fn write_chunk(&mut self, _ino: u64, _offset: u64, _data: Vec<u8>) -> Result<u64, BackendError> {
+        Err(BackendError::Unsupported("write".to_string()))
+    }
+
+    fn rename(&mut self, old_parent_ino: u64, old_name: &str, new_parent_ino: u64, new_name: &str) -> Result<FileEntry, BackendError> {
+        let ino = self.child(old_parent_ino, old_name).ok_or_else(|| not_found(old_name))?;
+        if let Some(target) = self.child(new_parent_ino, new_name) && target != ino {
+            self.remove(target);
+        }
+        let old_prefix = format!("{}/", self.entries[&ino].path);
+        let new_path = child_path(&self.entries[&new_parent_ino].path, new_name);
+        let new_prefix = format!("{}/", new_path);
+        for moved in self.entries.values_mut() {
+            if let Some(rest) = moved.path.strip_prefix(&old_prefix) {
+                moved.path = format!("{}{}", new_prefix, rest);
+            }
+        }
+        let moved = self.entries.get_mut(&ino).expect("ino just found");
+        moved.name = new_name.to_string();
+        moved.path = new_path;
+        self.parents.insert(ino, new_parent_ino);
+        self.get_attr(ino)
+    }
+
+    fn set_attr(&mut self, _ino: u64, _attrs: SetAttrRequest) -> Result<FileEntry, BackendError> {
+        Err(BackendError::Unsupported("setattr".to_string()))
+    }
+
+    fn read_stream(&mut self, _ino: u64, _offset: u64, _len: Option<u64>) -> Result<ByteStream, BackendError> {
+        Err(BackendError::Unsupported("stream".to_string()))
+    }
+
+    fn write_stream(&mut self, _ino: u64, _offset: u64, _data: ByteStream) -> Result<(), BackendError> {
+        Err(BackendError::Unsupported("stream".to_string()))
+    }
+
+    fn link(&mut self, _target_ino: u64, _link_parent_ino: u64, _link_name: &str) -> Result<FileEntry, BackendError> {
+        Err(BackendError::Unsupported("link".to_string()))
+    }
+
+    fn symlink(&mut self, _target_path: &str, _link_parent_ino: u64, _link_name: &str) -> Result<FileEntry, BackendError> {
+        Err(BackendError::Unsupported("symlink".to_string()))
+    }
+
+    fn readlink(&mut self, _ino: u64) -> Result<String, BackendError> {
+        Err(BackendError::Unsupported("readlink".to_string()))
+    }
+
+    fn get_size(&mut self) -> Result<(u64, u64), BackendError> {
+        Ok((0, 0))
+    }
+}
+
+fn cache(server: MemServer) -> Cache<MemServer> {
+    Cache::new(server, 64, 64, 64, 64)
+}
+
+// risolve un path dalla radice passando per la cache, come farebbe il kernel prima di aprire
+fn resolve(cache: &mut Cache<MemServer>, path: &str) -> u64 {
+    path.split('/').filter(|name| !name.is_empty())
+        .fold(ROOT_INO, |parent, name| cache.lookup(parent, name).expect("path exists").ino)
+}
+
+fn read_all(cache: &mut Cache<MemServer>, ino: u64) -> Vec<u8> {
+    cache.read_chunk(ino, 0, 1024).expect("readable")
+}
+
+#[test]
+fn rename_across_directories_keeps_open_file_data() {
+    let mut server = MemServer::new();
+    let a = server.add(ROOT_INO, "a", EntryType::Directory, b"");
+    server.add(ROOT_INO, "b", EntryType::Directory, b"");
+    server.add(a, "f", EntryType::File, b"hello");
+    let mut cache = cache(server);
+
+    // handle aperto: il file è stato letto e i blocchi sono in cache
+    let f = resolve(&mut cache, "/a/f");
+    let b = resolve(&mut cache, "/b");
+    assert_eq!(read_all(&mut cache, f), b"hello");
+    let reads = cache.http_backend.reads;
+
+    let renamed = cache.rename(a, "f", b, "g").expect("rename");
+    assert_eq!(renamed.ino, f);
+    assert_eq!(cache.get_attr(f).expect("attr").path, "/b/g");
+    // l'handle continua a leggere dai blocchi in cache, senza riscaricarli
+    assert_eq!(read_all(&mut cache, f), b"hello");
+    assert_eq!(cache.http_backend.reads, reads);
+}
+
+#[test]
+fn rename_of_directory_moves_open_files_inside() {
+    let mut server = MemServer::new();
+    let a = server.add(ROOT_INO, "a", EntryType::Directory, b"");
+    let b = server.add(ROOT_INO, "b", EntryType::Directory, b"");
+    let sub = server.add(a, "sub", EntryType::Directory, b"");
+    server.add(sub, "f", EntryType::File, b"data");
+    let mut cache = cache(server);
+
+    let f = resolve(&mut cache, "/a/sub/f");
+    assert_eq!(read_all(&mut cache, f), b"data");
+    let reads = cache.http_backend.reads;
+
+    cache.rename(ROOT_INO, "a", b, "moved").expect("rename");
+    assert_eq!(cache.meta.peek(&sub).expect("cached").path, "/b/moved/sub");
+    assert_eq!(cache.meta.peek(&f).expect("cached").path, "/b/moved/sub/f");
+    assert_eq!(read_all(&mut cache, f), b"data");
+    assert_eq!(cache.http_backend.reads, reads);
+}
+
+#[test]
+fn rename_over_open_file_drops_the_replaced_data() {
+    let mut server = MemServer::new();
+    server.add(ROOT_INO, "new", EntryType::File, b"new content");
+    server.add(ROOT_INO, "old", EntryType::File, b"old");
+    let mut cache = cache(server);
+
+    let new = resolve(&mut cache, "/new");
+    let old = resolve(&mut cache, "/old");
+    cache.list_dir(ROOT_INO).expect("listing");
+    assert_eq!(read_all(&mut cache, old), b"old");
+    assert_eq!(read_all(&mut cache, new), b"new content");
+
+    // salvataggio atomico: il file nuovo prende il nome di quello aperto
+    cache.rename(ROOT_INO, "new", ROOT_INO, "old").expect("rename");
+    assert!(cache.meta.peek(&old).is_none());
+    assert!(cache.file_blocks.peek(&old).is_none());
+    assert!(matches!(cache.get_attr(old), Err(BackendError::NotFound(_))));
+    assert_eq!(cache.meta.peek(&new).expect("cached").path, "/old");
+    assert_eq!(resolve(&mut cache, "/old"), new);
+    assert_eq!(read_all(&mut cache, new), b"new content");
+}
+
+#[test]
+fn rename_of_nested_tree_rewrites_every_descendant() {
+    let mut server = MemServer::new();
+    let a = server.add(ROOT_INO, "a", EntryType::Directory, b"");
+    let b = server.add(a, "b", EntryType::Directory, b"");
+    let c = server.add(b, "c", EntryType::Directory, b"");
+    let f = server.add(c, "f", EntryType::File, b"deep");
+    let g = server.add(a, "g", EntryType::File, b"shallow");
+    let mut cache = cache(server);
+
+    resolve(&mut cache, "/a/b/c/f");
+    resolve(&mut cache, "/a/g");
+    cache.list_dir(c).expect("listing");
+
+    cache.rename(ROOT_INO, "a", ROOT_INO, "z").expect("rename");
+    for (ino, path) in [(a, "/z"), (b, "/z/b"), (c, "/z/b/c"), (f, "/z/b/c/f"), (g, "/z/g")] {
+        assert_eq!(cache.meta.peek(&ino).expect("cached").path, path);
+    }
+    assert_eq!(cache.descendants(a), HashSet::from([b, c, f, g]));
+    // la lista della directory spostata riporta i nuovi path dei figli
+    assert_eq!(cache.list_dir(c).expect("listing")[0].path, "/z/b/c/f");
+}
+
+#[test]
+fn rename_with_unknown_old_path_forgets_descendants() {
+    let mut server = MemServer::new();
+    let a = server.add(ROOT_INO, "a", EntryType::Directory, b"");
+    let b = server.add(a, "b", EntryType::Directory, b"");
+    let f = server.add(b, "f", EntryType::File, b"");
+    let mut cache = cache(server);
+
+    resolve(&mut cache, "/a/b/f");
+    // né la directory di partenza né quella spostata sono più in cache: il vecchio path non si ricostruisce
+    cache.meta.pop(&ROOT_INO);
+    cache.meta.pop(&a);
+
+    cache.rename(ROOT_INO, "a", ROOT_INO, "z").expect("rename");
+    assert!(cache.meta.peek(&b).is_none());
+    assert!(cache.meta.peek(&f).is_none());
+    assert_eq!(cache.get_attr(f).expect("attr").path, "/z/b/f");
+}
+
+#[test]
+fn stale_parent_cycle_does_not_hang_rename() {
+    let mut server = MemServer::new();
+    let a = server.add(ROOT_INO, "a", EntryType::Directory, b"");
+    let sub = server.add(a, "sub", EntryType::Directory, b"");
+    let x = server.add(ROOT_INO, "x", EntryType::Directory, b"");
+    let y = server.add(ROOT_INO, "y", EntryType::Directory, b"");
+    let mut cache = cache(server);
+
+    resolve(&mut cache, "/a/sub");
+    // relazioni ormai vecchie che formano cicli: una con la directory spostata, una fra due directory estranee
+    cache.remember_parent(a, sub);
+    cache.remember_parent(x, y);
+    cache.remember_parent(y, x);
+    assert_eq!(cache.descendants(a), HashSet::from([sub]));
+    assert_eq!(cache.descendants(x), HashSet::from([y]));
+
+    cache.rename(ROOT_INO, "a", ROOT_INO, "z").expect("rename");
+    assert_eq!(cache.meta.peek(&sub).expect("cached").path, "/z/sub");
+    // il rename ha rimesso la directory sotto la radice: il ciclo non c'è più
+    assert_eq!(cache.parents.get(&a), Some(&ROOT_INO));
+    assert!(!cache.children.get(&sub).is_some_and(|children| children.contains(&a)));
+}
+
+#[test]
+fn offered_attributes_of_a_changed_file_drop_its_blocks() {
+    let mut server = MemServer::new();
+    let f = server.add(ROOT_INO, "f", EntryType::File, b"hello");
+    let mut cache = cache(server);
+
+    assert_eq!(read_all(&mut cache, f), b"hello");
+    assert!(cache.file_blocks.peek(&f).is_some());
+
+    // attributi letti da un altro backend (un worker del layer fuse) dopo una modifica sul server
+    let mut fresh = cache.http_backend.entries[&f].clone();
+    fresh.mtime += Duration::from_secs(1);
+    cache.http_backend.entries.insert(f, fresh.clone());
+    cache.http_backend.data.insert(f, b"HELLO".to_vec());
+    cache.offer_attr(&fresh, Some(ROOT_INO));
+
+    assert!(cache.file_blocks.peek(&f).is_none());
+    assert_eq!(cache.known_attr(f).expect("cached").mtime, fresh.mtime);
+    assert_eq!(read_all(&mut cache, f), b"HELLO");
+}
+
+#[test]
+fn offered_pages_complete_the_listing() {
+    let mut server = MemServer::new();
+    for name in ["a", "b", "c"] {
+        server.add(ROOT_INO, name, EntryType::File, b"");
+    }
+    let mut cache = cache(server);
+
+    let first = cache.http_backend.list_dir_page(ROOT_INO, None, None, 2).expect("page");
+    cache.offer_page(ROOT_INO, None, &first);
+    let second = cache.http_backend.list_dir_page(ROOT_INO, None, first.next.as_deref(), 2).expect("page");
+    cache.offer_page(ROOT_INO, first.next.as_deref(), &second);
+
+    // senza lease la prima pagina va chiesta di nuovo, le successive continuano la lista completata
+    assert!(cache.cached_page(ROOT_INO, None, 2).is_none());
+    let rest = cache.cached_page(ROOT_INO, first.next.as_deref(), 2).expect("cached");
+    let names: Vec<_> = rest.entries.iter().map(|e| e.name.as_str()).collect();
+    assert_eq!(names, ["c"]);
+    assert!(rest.next.is_none());
+}
@@ -0,0 +1,110 @@
+// Sottocomando `log tail`: ultime operazioni registrate nel log di audit del demone (--audit-log), una per
+// riga con ora UTC, utente e processo locali, operazione, path ed esito. Con --follow resta in attesa
+// delle nuove righe e continua anche dopo una rotazione del file.
+
+use rfs_models::AuditRecord;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+// data e ora UTC di un istante in millisecondi dall'epoch (algoritmo civil_from_days)
+fn utc(millis: u64) -> String {
+    let secs = (millis / 1000) as i64;
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}", y, m, d, rem / 3600, rem % 3600 / 60, rem % 60, millis % 1000)
+}
+
+fn format_record(record: &AuditRecord) -> String {
+    let mut who = Vec::new();
+    if let Some(uid) = record.uid {
+        who.push(format!("uid {}", uid));
+    }
+    if let Some(pid) = record.pid {
+        who.push(format!("pid {}", pid));
+    }
+    if let Some(process) = record.process.as_ref() {
+        who.push(format!("({})", process));
+    }
+    let who = if who.is_empty() { "-".to_string() } else { who.join(" ") };
+    let path = match record.target.as_ref() {
+        Some(target) => format!("{} -> {}", record.path, target),
+        None => record.path.clone(),
+    };
+    let result = record.error.as_deref().unwrap_or("ok");
+    format!("{}  {}  {}  {}  {}  {}", utc(record.time), record.mount, who, record.op, path, result)
+}
+
+// righe non riconosciute (scritte da una versione diversa) vengono mostrate così come sono
+fn print_line(line: &str) {
+    match serde_json::from_str::<AuditRecord>(line) {
+        Ok(record) => println!("{}", format_record(&record)),
+        Err(_) => println!("{}", line),
+    }
+}
+
+// legge dalla posizione corrente e stampa le righe complete; quelle a metà restano in `partial`
+fn print_new(file: &mut File, partial: &mut Vec<u8>) -> std::io::Result<u64> {
+    let read = file.read_to_end(partial)?;
+    while let Some(end) = partial.iter().position(|b| *b == b'\n') {
+        let line: Vec<u8> = partial.drain(..=end).collect();
+        print_line(String::from_utf8_lossy(&line).trim_end());
+    }
+    Ok(read as u64)
+}
+
+pub fn tail(path: &Path, lines: usize, follow: bool) -> i32 {
+    let fail = |e: std::io::Error| {
+        eprintln!("Cannot read audit log {}: {}", path.display(), e);
+        1
+    };
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => return fail(e),
+    };
+    let mut raw = Vec::new();
+    if let Err(e) = file.read_to_end(&mut raw) {
+        return fail(e);
+    }
+    let text = String::from_utf8_lossy(&raw);
+    let all: Vec<&str> = text.lines().collect();
+    for line in &all[all.len().saturating_sub(lines)..] {
+        print_line(line);
+    }
+    if !follow {
+        return 0;
+    }
+
+    let mut pos = raw.len() as u64;
+    let mut partial = Vec::new();
+    loop {
+        thread::sleep(FOLLOW_INTERVAL);
+        // un file più corto di quanto già letto è quello nuovo dopo una rotazione: si riparte dall'inizio
+        let len = std::fs::metadata(path).map(|m| m.len()).unwrap_or(pos);
+        if len < pos {
+            file = match File::open(path) {
+                Ok(file) => file,
+                Err(e) => return fail(e),
+            };
+            pos = 0;
+            partial.clear();
+        }
+        let res = file.seek(SeekFrom::Start(pos)).and_then(|_| print_new(&mut file, &mut partial));
+        match res {
+            Ok(read) => pos += read,
+            Err(e) => return fail(e),
+        }
+    }
+}
@@ -0,0 +1,174 @@
+// Sottocomando `check`: confronta con il server lo stato locale lasciato dal demone (journal delle scritture,
+// archivio dei pin e lista dei pin salvati) e segnala quello che non torna; con --repair lo ripara:
+// le write senza commit vengono rigiocate se il file esiste ancora e scartate altrimenti, i pin di oggetti
+// spariti dal server vengono rimossi, quelli cambiati segnati da riscaricare, i file temporanei di
+// scritture interrotte cancellati. Dopo uno spegnimento non pulito il controllo parte da solo al mount.
+
+use crate::control::PinList;
+use rfs_cache::{CacheKey, PinStore};
+use rfs_fuse::WriteJournal;
+use rfs_models::{BackendError, EntryType, FileEntry, ROOT_INO, RemoteBackend};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub problems: usize,
+    pub repaired: usize,
+}
+
+impl CheckReport {
+    pub fn summary(&self) -> String {
+        match (self.problems, self.repaired) {
+            (0, _) => "Local state is consistent with the server".to_string(),
+            (problems, 0) => format!("{} problem(s) found, none repaired", problems),
+            (problems, repaired) => format!("{} problem(s) found, {} repaired", problems, repaired),
+        }
+    }
+}
+
+struct Check {
+    repair: bool,
+    report: CheckReport,
+}
+
+impl Check {
+    // segnala un problema; true se va riparato
+    fn problem(&mut self, message: String) -> bool {
+        self.report.problems += 1;
+        println!("{}", message);
+        self.repair
+    }
+
+    fn fixed(&mut self, what: &str, res: io::Result<()>) {
+        match res {
+            Ok(()) => self.report.repaired += 1,
+            Err(e) => eprintln!("Unable to repair {}: {}", what, e),
+        }
+    }
+}
+
+fn io_error(path: &Path, e: io::Error) -> BackendError {
+    BackendError::Other(format!("{}: {}", path.display(), e))
+}
+
+// voce attuale di `entry` sul server; None se non esiste più o se il suo ino è stato riassegnato
+// (tipo e data di creazione di un oggetto non cambiano mai)
+fn current<B: RemoteBackend>(backend: &mut B, entry: &FileEntry) -> Result<Option<FileEntry>, BackendError> {
+    match backend.get_attr(entry.ino) {
+        Ok(fresh) if fresh.kind == entry.kind && fresh.btime == entry.btime => Ok(Some(fresh)),
+        Ok(_) | Err(BackendError::NotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn check_journal<B: RemoteBackend>(check: &mut Check, backend: &mut B, path: &Path) -> Result<(), BackendError> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let mut journal = WriteJournal::open(path).map_err(|e| io_error(path, e))?;
+    let pending = journal.pending().map_err(|e| io_error(path, e))?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    if pending.torn > 0 {
+        check.problem(format!("journal: {} byte(s) of truncated or corrupted records", pending.torn));
+    }
+    for (ino, bytes) in pending.files.iter() {
+        match backend.get_attr(*ino) {
+            Ok(entry) => check.problem(format!("journal: {} unsent byte(s) of {} (ino {})", bytes, entry.name, ino)),
+            Err(BackendError::NotFound(_)) => check.problem(format!("journal: {} orphaned byte(s) of ino {}, no longer on the server", bytes, ino)),
+            Err(e) => return Err(e),
+        };
+    }
+    if !check.repair {
+        return Ok(());
+    }
+    let report = journal.replay(backend).map_err(|e| io_error(path, e))?;
+    for (ino, bytes) in report.recovered.iter() {
+        println!("journal: recovered {} byte(s) of ino {}", bytes, ino);
+    }
+    for (ino, bytes, reason) in report.unrecoverable.iter() {
+        println!("journal: dropped {} byte(s) of ino {}: {}", bytes, ino, reason);
+    }
+    // il journal è stato svuotato: tutto quello che conteneva è sistemato
+    check.report.repaired += pending.files.len() + usize::from(pending.torn > 0);
+    Ok(())
+}
+
+fn check_pin_store<B: RemoteBackend>(check: &mut Check, backend: &mut B, dir: &Path, key: Option<&CacheKey>) -> Result<(), BackendError> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    let store = PinStore::open(dir, key.cloned()).map_err(|e| io_error(dir, e))?;
+    for path in store.leftovers().map_err(|e| io_error(dir, e))? {
+        if check.problem(format!("pins: leftover file {}", path.display())) {
+            check.fixed(&path.display().to_string(), fs::remove_file(&path));
+        }
+    }
+
+    let mut pinned: Vec<_> = store.load().map_err(|e| io_error(dir, e))?.into_values().collect();
+    pinned.sort_by_key(|p| p.entry.ino);
+    for mut pin in pinned {
+        let (ino, name) = (pin.entry.ino, pin.entry.name.clone());
+        let Some(fresh) = current(backend, &pin.entry)? else {
+            if check.problem(format!("pins: {} (ino {}) no longer exists on the server", name, ino)) {
+                check.fixed(&name, store.remove(ino));
+            }
+            continue;
+        };
+        if pin.stale || pin.entry.kind != EntryType::File {
+            continue;
+        }
+        let reason = if fresh.size != pin.entry.size || fresh.mtime != pin.entry.mtime {
+            "changed on the server"
+        } else if store.data_len(ino) != Some(pin.entry.size) {
+            "has an incomplete local copy"
+        } else {
+            continue;
+        };
+        // la copia locale viene riscaricata alla prima lettura
+        if check.problem(format!("pins: {} (ino {}) {}", name, ino, reason)) {
+            pin.stale = true;
+            check.fixed(&name, store.save(&pin));
+        }
+    }
+    Ok(())
+}
+
+fn check_pin_list<B: RemoteBackend>(check: &mut Check, backend: &mut B, path: &Path) -> Result<(), BackendError> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let pins = PinList::open(path.to_path_buf());
+    'roots: for root in pins.roots() {
+        let mut ino = ROOT_INO;
+        for name in root.split('/').filter(|c| !c.is_empty()) {
+            match backend.lookup(ino, name) {
+                Ok(entry) => ino = entry.ino,
+                Err(BackendError::NotFound(_)) => {
+                    if check.problem(format!("pins: pinned path {} no longer exists on the server", root)) {
+                        pins.update(&root, false);
+                        check.report.repaired += 1;
+                    }
+                    continue 'roots;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Controlla il journal in `journal` e i pin in `cache_dir` (None per i mount senza pin), cifrati con `key`
+/// se la cache è cifrata.
+pub fn check<B: RemoteBackend>(backend: &mut B, journal: &Path, cache_dir: Option<&Path>, key: Option<&CacheKey>, repair: bool) -> Result<CheckReport, BackendError> {
+    let mut check = Check { repair, report: CheckReport::default() };
+    check_journal(&mut check, backend, journal)?;
+    if let Some(cache_dir) = cache_dir {
+        check_pin_store(&mut check, backend, &cache_dir.join("pinned"), key)?;
+        check_pin_list(&mut check, backend, &cache_dir.join("pins.list"))?;
+    }
+    Ok(check.report)
+}
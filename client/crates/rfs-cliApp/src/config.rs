@@ -0,0 +1,158 @@
+// File di configurazione TOML del mount (--config, default ~/.config/remote-fs/config.toml). Contiene i profili
+// di connessione, una tabella [profile.<nome>] per server, scelti con `mount <nome>` o --profile (senza nome
+// vale il profilo "default", o l'unico definito); le opzioni date sulla riga di comando vincono sul profilo:
+//
+//     [profile.work]
+//     url = "https://files.example.com"
+//     auth = "prompt"          # "session" (default) riprende la sessione salvata, "prompt" chiede sempre le credenziali
+//     user = "5001"            # utente del login, chiesto solo se manca
+//     mount_point = "/home/me/work"
+//     cache_dir = "/home/me/.cache/remote-fs/work"
+//     trust_cache = true
+//     encrypt_cache = true     # cache su disco cifrata, chiave nel portachiavi del sistema
+//     attr_refresh = 30
+//
+// e le regole di policy, una tabella [[policy]] per regola, valutate dai layer del filesystem prima del backend:
+//
+//     [[policy]]              # niente eseguibili Windows, né da aprire né da creare
+//     path = "*.exe"
+//     action = "deny"
+//
+//     [[policy]]              # rsync può leggere ma non modificare l'archivio
+//     process = "rsync"
+//     path = "/archive"
+//     action = "read-only"
+//
+// `action` è "read-only", "deny" o "hidden"; tra più regole che valgono vince la più restrittiva.
+//
+// Infine la validità in secondi di attributi e voci date al kernel (solo Unix), per tipo di voce e per path;
+// --attr-ttl e --entry-ttl vincono sui valori di file e directory, le regole per path restano:
+//
+//     [ttl]
+//     attr_file = 7
+//     attr_dir = 3
+//     entry_file = 7
+//     entry_dir = 3
+//
+//     [[ttl.path]]            # l'output dei build cambia spesso: lo si rilegge subito
+//     prefix = "/build"
+//     attr = 0
+//     entry = 1
+//
+// e l'uso delle cache per i dati dei file sotto un path (solo Unix): con `direct = true` ogni open vale come
+// O_DIRECT, con `direct = false` l'O_DIRECT delle applicazioni viene ignorato:
+//
+//     [[direct_io]]           # i log scritti da altri client si leggono sempre dal server
+//     prefix = "/logs"
+//     direct = true
+
+use rfs_models::{Policy, PolicyRule};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    policy: Vec<PolicyRule>,
+    profile: BTreeMap<String, Profile>,
+    ttl: TtlConfig,
+    direct_io: Vec<DirectIoPath>,
+}
+
+/// Validità in secondi di attributi e voci; quelle non indicate restano i default del mount
+#[derive(Debug, Clone, Default, Deserialize)]
+#[cfg_attr(not(unix), allow(dead_code))]
+#[serde(default, deny_unknown_fields)]
+pub struct TtlConfig {
+    pub attr_file: Option<u64>,
+    pub attr_dir: Option<u64>,
+    pub entry_file: Option<u64>,
+    pub entry_dir: Option<u64>,
+    pub path: Vec<TtlPath>,
+}
+
+/// Validità sotto un path remoto; tra più regole vale quella col prefisso più lungo
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(unix), allow(dead_code))]
+#[serde(deny_unknown_fields)]
+pub struct TtlPath {
+    pub prefix: String,
+    pub attr: Option<u64>,
+    pub entry: Option<u64>,
+}
+
+/// O_DIRECT imposto (`direct = true`) o ignorato sotto un path remoto; vale la regola col prefisso più lungo
+#[derive(Debug, Clone, Deserialize)]
+#[cfg_attr(not(unix), allow(dead_code))]
+#[serde(deny_unknown_fields)]
+pub struct DirectIoPath {
+    pub prefix: String,
+    pub direct: bool,
+}
+
+/// Come autenticarsi al server di un profilo
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// riprende la sessione salvata, se ancora valida, e salva quella nuova
+    #[default]
+    Session,
+    /// chiede le credenziali a ogni avvio, senza salvare nulla
+    Prompt,
+}
+
+/// Server e impostazioni di un profilo di connessione
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub url: String,
+    #[serde(default)]
+    pub auth: AuthMode,
+    pub user: Option<String>,
+    pub mount_point: Option<String>,
+    pub cache_dir: Option<String>,
+    #[serde(default)]
+    pub trust_cache: bool,
+    #[serde(default)]
+    pub encrypt_cache: bool,
+    pub attr_refresh: Option<u64>,
+}
+
+impl Config {
+    /// Legge `path`; se manca vale la configurazione vuota, ma solo per il file di default
+    pub fn load(path: &Path, explicit: bool) -> Result<Self, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound && !explicit => return Ok(Self::default()),
+            Err(e) => return Err(format!("Cannot read config file {}: {}", path.display(), e)),
+        };
+        toml::from_str(&text).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+    }
+
+    pub fn policy(&self) -> Policy {
+        Policy::new(self.policy.clone())
+    }
+
+    #[cfg(unix)]
+    pub fn ttl(&self) -> &TtlConfig {
+        &self.ttl
+    }
+
+    #[cfg(unix)]
+    pub fn direct_io(&self) -> &[DirectIoPath] {
+        &self.direct_io
+    }
+
+    /// Profilo `name`; senza nome "default" o l'unico definito. Ok(None) se non ne serve nessuno
+    pub fn profile(&self, name: Option<&str>) -> Result<Option<(&str, &Profile)>, String> {
+        match name {
+            Some(name) => self.profile.get_key_value(name).map(|(n, p)| Some((n.as_str(), p)))
+                .ok_or_else(|| format!("No profile named {} in the config file", name)),
+            None if self.profile.len() == 1 => Ok(self.profile.iter().next().map(|(n, p)| (n.as_str(), p))),
+            None => Ok(self.profile.get_key_value("default").map(|(n, p)| (n.as_str(), p))),
+        }
+    }
+}
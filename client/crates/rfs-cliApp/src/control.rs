@@ -0,0 +1,651 @@
+// Socket di controllo del demone, usata dai sottocomandi `pin`, `unpin`, `warm`, `du`, `find`, `status`,
+// `cache ls/stat/evict` e `flush-all`.
+// Protocollo: il client invia una riga JSON (ControlRequest), il demone risponde con righe JSON
+// di progresso e chiude con un esito finale (ControlReply::Done).
+
+use rfs_cache::{Cache, CachedState, Health};
+use rfs_fuse::{FlushBarrier, Transfers};
+use rfs_models::{BackendError, EntryType, FileEntry, ROOT_INO, RemoteBackend, SearchQuery};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// lunghezza massima del nome della socket, hash compreso (sun_path ha al massimo 108 byte)
+const SOCKET_NAME_MAX: usize = 48;
+
+// dimensione massima di una singola lettura dal server (1 MB)
+const WARM_FETCH_SIZE: u64 = 1024 * 1024;
+const PROGRESS_EVERY: Duration = Duration::from_secs(1);
+// attesa massima di una barriera flush-all, invii compresi
+const FLUSH_ALL_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Crea backend indipendenti per i worker del warm, così gli scaricamenti non si serializzano sul lock della cache.
+pub type Fetcher<F> = Arc<dyn Fn() -> F + Send + Sync>;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlCmd {
+    Pin,
+    Unpin,
+    Warm,
+    Du,
+    Find,
+    Status,
+    #[serde(rename = "flush-all")]
+    FlushAll,
+    #[serde(rename = "cache-ls")]
+    CacheLs,
+    #[serde(rename = "cache-stat")]
+    CacheStat,
+    #[serde(rename = "cache-evict")]
+    CacheEvict,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ControlRequest {
+    pub cmd: ControlCmd,
+    pub path: String,
+    /// scaricamenti in parallelo (solo warm)
+    #[serde(default)]
+    pub jobs: Option<usize>,
+    /// filtri della ricerca (solo find)
+    #[serde(default)]
+    pub query: Option<SearchQuery>,
+    /// elenca anche gli invii in corso (solo status)
+    #[serde(default)]
+    pub transfers: bool,
+    /// scarta anche il sottoalbero in cache (solo cache-evict)
+    #[serde(default)]
+    pub recursive: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ControlReply {
+    Progress { message: String },
+    Done { ok: bool, message: String },
+}
+
+/// Cartella delle socket di controllo: $XDG_RUNTIME_DIR/remote-fs, privata dell'utente, o senza
+/// XDG_RUNTIME_DIR la cartella `run` dentro la cache su disco
+pub fn socket_dir(cache_dir: &str) -> PathBuf {
+    match std::env::var("XDG_RUNTIME_DIR") {
+        Ok(dir) if !dir.is_empty() => Path::new(&dir).join("remote-fs"),
+        _ => Path::new(cache_dir).join("run"),
+    }
+}
+
+/// Socket di controllo del demone montato su `mount_point`, una per mount
+pub fn socket_path(cache_dir: &str, mount_point: &Path) -> PathBuf {
+    let mount_point = std::path::absolute(mount_point).unwrap_or_else(|_| mount_point.to_path_buf());
+    let full = mount_point.to_string_lossy();
+    let name: String = full.trim_matches('/').chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .take(SOCKET_NAME_MAX - 17)
+        .collect();
+    // il nome leggibile non basta (/mnt/a_b e /mnt/a/b coincidono): l'hash del percorso completo li distingue
+    let hash = full.bytes().fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+    socket_dir(cache_dir).join(format!("{}-{:016x}.sock", name, hash))
+}
+
+// ---------- lato client ----------
+
+// socket del mount che contiene `path`; fuori da ogni mount va bene solo se c'è un demone solo
+fn locate(cache_dir: &str, path: &Path) -> Result<PathBuf, String> {
+    if let Some(socket) = path.ancestors().map(|dir| socket_path(cache_dir, dir)).find(|socket| socket.exists()) {
+        return Ok(socket);
+    }
+    let dir = socket_dir(cache_dir);
+    let sockets: Vec<PathBuf> = fs::read_dir(&dir).into_iter().flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|socket| socket.extension().is_some_and(|ext| ext == "sock"))
+        .collect();
+    match sockets.as_slice() {
+        [socket] => Ok(socket.clone()),
+        [] => Err(format!("No Remote-FS daemon is running (no control socket in {})", dir.display())),
+        _ => Err(format!("{} is not under a Remote-FS mount point and several daemons are running", path.display())),
+    }
+}
+
+/// Invia un comando al demone e stampa le risposte; restituisce l'esito finale.
+pub fn send(cache_dir: &str, mut request: ControlRequest) -> Result<bool, String> {
+    // il demone lavora da "/", i percorsi relativi vanno risolti qui
+    let path = std::path::absolute(&request.path).map_err(|e| format!("Invalid path {}: {}", request.path, e))?;
+    let socket = locate(cache_dir, &path)?;
+    let stream = UnixStream::connect(&socket)
+        .map_err(|e| format!("Cannot reach the Remote-FS daemon on {}: {}", socket.display(), e))?;
+
+    request.path = path.to_string_lossy().into_owned();
+    let mut line = serde_json::to_string(&request).map_err(|e| e.to_string())?;
+    line.push('\n');
+    (&stream).write_all(line.as_bytes()).map_err(|e| e.to_string())?;
+
+    for line in BufReader::new(&stream).lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        match serde_json::from_str::<ControlReply>(&line) {
+            Ok(ControlReply::Progress { message }) => println!("{}", message),
+            Ok(ControlReply::Done { ok, message }) => {
+                if ok { println!("{}", message) } else { eprintln!("{}", message) }
+                return Ok(ok);
+            }
+            Err(e) => return Err(format!("Invalid reply from the daemon: {}", e)),
+        }
+    }
+    Err("The daemon closed the connection without replying".to_string())
+}
+
+// ---------- lato demone ----------
+
+/// Percorsi pinnati (relativi alla radice del filesystem remoto), ripinnati a ogni mount
+/// per includere i file aggiunti nel frattempo alle directory pinnate.
+pub struct PinList {
+    path: PathBuf,
+    roots: Mutex<Vec<String>>,
+}
+
+impl PinList {
+    pub fn open(path: PathBuf) -> Self {
+        let roots = fs::read_to_string(&path)
+            .map(|raw| raw.lines().filter(|l| !l.is_empty()).map(str::to_string).collect())
+            .unwrap_or_default();
+        Self { path, roots: Mutex::new(roots) }
+    }
+
+    pub fn roots(&self) -> Vec<String> {
+        self.roots.lock().expect("Mutex poisoned").clone()
+    }
+
+    pub fn update(&self, root: &str, pinned: bool) {
+        let mut roots = self.roots.lock().expect("Mutex poisoned");
+        roots.retain(|r| r != root);
+        if pinned {
+            roots.push(root.to_string());
+        }
+        let mut raw = roots.join("\n");
+        raw.push('\n');
+        if let Err(e) = fs::write(&self.path, raw) {
+            eprintln!("Unable to save pin list {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// stato del mount condiviso dalle connessioni alla socket
+struct Daemon {
+    mount_point: String,
+    pins: Arc<PinList>,
+    health: Arc<Health>,
+    transfers: Transfers,
+    barrier: FlushBarrier,
+}
+
+/// Avvia il thread che accetta i comandi sulla socket di controllo.
+pub fn serve<B, F>(cache: Arc<Mutex<Cache<B>>>, fetcher: Fetcher<F>, socket: &Path, mount_point: String, pins: Arc<PinList>, transfers: Transfers, barrier: FlushBarrier) -> io::Result<()>
+where
+    B: RemoteBackend + Send + 'static,
+    F: RemoteBackend + 'static,
+{
+    if let Some(dir) = socket.parent() {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    if UnixStream::connect(socket).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("another Remote-FS daemon is serving {}", socket.display())));
+    }
+    let _ = fs::remove_file(socket); // socket rimasta da un demone terminato male
+    let listener = UnixListener::bind(socket)?;
+    // letto una volta sola: status deve rispondere anche mentre la cache è bloccata su una richiesta
+    let health = cache.lock().expect("Mutex poisoned").health();
+    let daemon = Arc::new(Daemon { mount_point, pins, health, transfers, barrier });
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let cache = cache.clone();
+                    let fetcher = fetcher.clone();
+                    let daemon = daemon.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle(stream, &cache, &fetcher, &daemon) {
+                            eprintln!("Control connection error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Control socket error: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Ripinna in background le radici salvate, per aggiornare la copia locale dopo il mount.
+pub fn repin_saved<B>(cache: Arc<Mutex<Cache<B>>>, pins: Arc<PinList>)
+where
+    B: RemoteBackend + Send + 'static,
+{
+    thread::spawn(move || {
+        for root in pins.roots() {
+            let res = resolve(&cache, Path::new(&root), false)
+                .and_then(|ino| pin_tree(&cache, ino, &root, &mut |_| {}).map_err(|e| e.to_string()));
+            match res {
+                Ok((entries, bytes)) => println!("Pinned {}: {} entries, {} bytes", root, entries, bytes),
+                Err(e) => eprintln!("Unable to refresh pinned {}: {}", root, e),
+            }
+        }
+    });
+}
+
+fn handle<B: RemoteBackend + Send, F: RemoteBackend>(stream: UnixStream, cache: &Mutex<Cache<B>>, fetcher: &Fetcher<F>, daemon: &Daemon) -> io::Result<()> {
+    let Daemon { mount_point, pins, health, transfers, barrier } = daemon;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let mut out = &stream;
+    let mut reply = |reply: ControlReply| -> io::Result<()> {
+        let mut raw = serde_json::to_string(&reply).map_err(io::Error::other)?;
+        raw.push('\n');
+        out.write_all(raw.as_bytes())
+    };
+
+    let request: ControlRequest = match serde_json::from_str(&line) {
+        Ok(request) => request,
+        Err(e) => return reply(ControlReply::Done { ok: false, message: format!("Invalid request: {}", e) }),
+    };
+    if let ControlCmd::Status = request.cmd {
+        if request.transfers {
+            for message in transfer_lines(transfers) {
+                reply(ControlReply::Progress { message })?;
+            }
+        }
+        return reply(status(mount_point, health));
+    }
+    if let ControlCmd::FlushAll = request.cmd {
+        let report = match barrier.run(Path::new(mount_point), FLUSH_ALL_TIMEOUT) {
+            Ok(report) => report,
+            Err(e) => return reply(ControlReply::Done { ok: false, message: e }),
+        };
+        for (ino, e) in &report.failed {
+            reply(ControlReply::Progress { message: format!("ino {}: not sent: {}", ino, e) })?;
+        }
+        for ino in &report.changed {
+            reply(ControlReply::Progress { message: format!("ino {}: changed on the server while open", ino) })?;
+        }
+        let message = format!("Flushed {} file handle(s), {} bytes ({} failed); revalidated {} open file(s), {} changed on the server",
+            report.handles, report.bytes, report.failed.len(), report.revalidated, report.changed.len());
+        return reply(ControlReply::Done { ok: report.failed.is_empty(), message });
+    }
+    // accettiamo sia percorsi sotto il mount point sia percorsi relativi alla radice remota
+    let path = Path::new(&request.path);
+    let rel = path.strip_prefix(mount_point).unwrap_or(path);
+    let root = format!("/{}", rel.components()
+        .filter_map(|c| match c { Component::Normal(name) => name.to_str(), _ => None })
+        .collect::<Vec<_>>().join("/"));
+
+    let inspect = matches!(request.cmd, ControlCmd::CacheLs | ControlCmd::CacheStat | ControlCmd::CacheEvict);
+    let ino = match resolve(cache, Path::new(&root), inspect) {
+        Ok(ino) => ino,
+        Err(e) => return reply(ControlReply::Done { ok: false, message: format!("Cannot resolve {}: {}", request.path, e) }),
+    };
+
+    match request.cmd {
+        ControlCmd::Pin => {
+            let mut progress_err = None;
+            let res = pin_tree(cache, ino, &root, &mut |message| {
+                if progress_err.is_none() && let Err(e) = reply(ControlReply::Progress { message }) {
+                    progress_err = Some(e); // il client se n'è andato, il pin prosegue comunque
+                }
+            });
+            match res {
+                Ok((entries, bytes)) => {
+                    pins.update(&root, true);
+                    reply(ControlReply::Done { ok: true, message: format!("Pinned {}: {} entries, {} bytes", root, entries, bytes) })
+                }
+                Err(e) => reply(ControlReply::Done { ok: false, message: format!("Unable to pin {}: {}", root, e.for_user()) }),
+            }
+        }
+        ControlCmd::Unpin => {
+            let removed = cache.lock().expect("Mutex poisoned").unpin(ino);
+            pins.update(&root, false);
+            if removed == 0 {
+                reply(ControlReply::Done { ok: false, message: format!("{} is not pinned", root) })
+            } else {
+                reply(ControlReply::Done { ok: true, message: format!("Unpinned {}: {} entries", root, removed) })
+            }
+        }
+        ControlCmd::Warm => {
+            let jobs = request.jobs.unwrap_or(4).clamp(1, 32);
+            let stats = WarmStats::default();
+            let mut progress_err = None;
+            let res = warm_tree(cache, fetcher.as_ref(), ino, jobs, &stats, &mut || {
+                if progress_err.is_none() && let Err(e) = reply(ControlReply::Progress { message: stats.summary() }) {
+                    progress_err = Some(e);
+                }
+            });
+            match res {
+                Ok(()) => reply(ControlReply::Done { ok: stats.errors.load(Ordering::Relaxed) == 0, message: format!("Warmed {}: {}", root, stats.summary()) }),
+                Err(e) => reply(ControlReply::Done { ok: false, message: format!("Unable to warm {}: {} ({})", root, e.for_user(), stats.summary()) }),
+            }
+        }
+        ControlCmd::Du => {
+            let res = cache.lock().expect("Mutex poisoned").disk_usage(ino);
+            match res {
+                Ok(usage) => reply(ControlReply::Done { ok: true, message: format!("{}\t{} ({} files, {} directories)", usage.bytes, root, usage.files, usage.dirs) }),
+                Err(e) => reply(ControlReply::Done { ok: false, message: format!("Unable to compute the usage of {}: {}", root, e.for_user()) }),
+            }
+        }
+        ControlCmd::Status | ControlCmd::FlushAll => unreachable!("answered before resolving the path"),
+        ControlCmd::CacheStat => {
+            let (state, block_size) = {
+                let cache = cache.lock().expect("Mutex poisoned");
+                (cache.cached_state(ino), cache.block_size())
+            };
+            for message in state_lines(&state, block_size, ino, transfers) {
+                reply(ControlReply::Progress { message })?;
+            }
+            reply(ControlReply::Done { ok: true, message: format!("{} (ino {})", root, ino) })
+        }
+        ControlCmd::CacheLs => {
+            let (rows, block_size) = {
+                let cache = cache.lock().expect("Mutex poisoned");
+                let rows = cache.cached_entries(ino).map(|children| children.into_iter()
+                    .map(|(child, name)| (child, name, cache.cached_state(child)))
+                    .collect::<Vec<_>>());
+                (rows, cache.block_size())
+            };
+            let Some(rows) = rows else {
+                return reply(ControlReply::Done { ok: false, message: format!("No listing of {} in the cache", root) });
+            };
+            reply(ControlReply::Progress { message: "META\tBLOCKS\tPINNED\tDIRTY\tNAME".to_string() })?;
+            for (child, name, state) in &rows {
+                let blocks = match state.entry.as_ref() {
+                    Some(entry) => format!("{}/{}", state.blocks.len(), entry.size.div_ceil(block_size as u64)),
+                    None => state.blocks.len().to_string(),
+                };
+                let pinned = match state.pinned.as_ref() {
+                    Some(p) if p.stale => "stale",
+                    Some(_) => "yes",
+                    None => "-",
+                };
+                let name = name.clone().unwrap_or_else(|| format!("(ino {})", child));
+                let meta = if state.entry.is_some() { "yes" } else { "-" };
+                reply(ControlReply::Progress { message: format!("{}\t{}\t{}\t{}\t{}", meta, blocks, pinned, transfers.dirty_of(*child), name) })?;
+            }
+            reply(ControlReply::Done { ok: true, message: format!("{} entries cached under {}", rows.len(), root) })
+        }
+        ControlCmd::CacheEvict => {
+            let mut cache = cache.lock().expect("Mutex poisoned");
+            let mut stack = vec![ino];
+            let (mut evicted, mut pinned) = (0, 0);
+            while let Some(next) = stack.pop() {
+                if request.recursive && let Some(children) = cache.cached_entries(next) {
+                    stack.extend(children.into_iter().map(|(child, _)| child));
+                }
+                pinned += usize::from(cache.is_pinned(next));
+                evicted += usize::from(cache.evict(next));
+            }
+            drop(cache);
+            if evicted == 0 {
+                return reply(ControlReply::Done { ok: false, message: format!("{} is not cached", root) });
+            }
+            let kept = if pinned > 0 { format!("; pinned copies of {} entries kept (see unpin)", pinned) } else { String::new() };
+            reply(ControlReply::Done { ok: true, message: format!("Evicted {}: {} entries{}", root, evicted, kept) })
+        }
+        ControlCmd::Find => {
+            let query = request.query.unwrap_or_default();
+            let res = cache.lock().expect("Mutex poisoned").search(ino, &query);
+            match res {
+                Ok(found) => {
+                    // percorsi sotto il mount point, utilizzabili direttamente dal chiamante
+                    for entry in &found {
+                        reply(ControlReply::Progress { message: format!("{}{}", mount_point.trim_end_matches('/'), entry.path) })?;
+                    }
+                    reply(ControlReply::Done { ok: true, message: format!("{} matches under {}", found.len(), root) })
+                }
+                Err(e) => reply(ControlReply::Done { ok: false, message: format!("Unable to search {}: {}", root, e.for_user()) }),
+            }
+        }
+    }
+}
+
+// stato del mount: online, oppure degradato con le richieste servite dalla cache durante l'interruzione
+fn status(mount_point: &str, health: &Health) -> ControlReply {
+    match health.degraded_since() {
+        None => ControlReply::Done { ok: true, message: format!("{}: online ({} outages since mount)", mount_point, health.outages()) },
+        Some(since) => {
+            let secs = SystemTime::now().duration_since(since).unwrap_or_default().as_secs();
+            ControlReply::Done {
+                ok: false,
+                message: format!("{}: degraded, server unreachable for {}s; {} requests served from the cache", mount_point, secs, health.served_offline()),
+            }
+        }
+    }
+}
+
+// una riga per invio in corso: ino, byte inviati su totali, velocità media e tempo stimato
+fn transfer_lines(transfers: &Transfers) -> Vec<String> {
+    let mut lines = vec![format!("{} byte(s) written and not yet sent", transfers.dirty_bytes())];
+    let active = transfers.snapshot();
+    if active.is_empty() {
+        lines.push("No transfers in progress".to_string());
+        return lines;
+    }
+    lines.extend(active.iter().map(|t| {
+        let percent = (t.sent * 100).checked_div(t.total).unwrap_or(100);
+        let eta = t.eta().map(|eta| format!("{}s", eta.as_secs())).unwrap_or_else(|| "?".to_string());
+        format!("ino {}: {}/{} bytes ({}%), {:.0} bytes/s, ETA {}", t.ino, t.sent, t.total, percent, t.rate(), eta)
+    }));
+    lines
+}
+
+// con `peek` usa prima quello che la cache sa già: ispezionarla non deve riempirla con il path stesso
+fn resolve<B: RemoteBackend>(cache: &Mutex<Cache<B>>, path: &Path, peek: bool) -> Result<u64, String> {
+    let mut ino = ROOT_INO;
+    for component in path.components() {
+        if let Component::Normal(name) = component {
+            let name = name.to_str().ok_or("path is not valid UTF-8")?;
+            let mut cache = cache.lock().expect("Mutex poisoned");
+            ino = match cache.peek_child(ino, name).filter(|_| peek) {
+                Some(child) => child,
+                None => cache.lookup(ino, name).map_err(|e| e.for_user().to_string())?.ino,
+            };
+        }
+    }
+    Ok(ino)
+}
+
+// indici dei blocchi come intervalli: 0-3, 7, 9-10
+fn block_ranges(blocks: &[u64]) -> String {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for &idx in blocks {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == idx => *end = idx,
+            _ => ranges.push((idx, idx)),
+        }
+    }
+    ranges.iter().map(|(start, end)| if start == end { start.to_string() } else { format!("{}-{}", start, end) })
+        .collect::<Vec<_>>().join(", ")
+}
+
+// righe di `cache stat`: metadati e validatore, lista, blocchi, lease, pin e scritture non ancora inviate
+fn state_lines(state: &CachedState, block_size: usize, ino: u64, transfers: &Transfers) -> Vec<String> {
+    let mut lines = Vec::new();
+    match state.entry.as_ref() {
+        Some(entry) => {
+            let mtime = entry.mtime.duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+            lines.push(format!("metadata: {:?}, {} bytes, mtime {} (Unix seconds)", entry.kind, entry.size, mtime));
+        }
+        None => lines.push("metadata: not cached".to_string()),
+    }
+    lines.push(format!("validator: {}", state.validator.as_deref().unwrap_or("none")));
+    if state.children.is_some() || state.listing_on_disk {
+        let memory = state.children.map(|n| format!("{} entries in memory", n)).unwrap_or_else(|| "not in memory".to_string());
+        let disk = if state.listing_on_disk { ", saved on disk" } else { "" };
+        lines.push(format!("listing: {}{}", memory, disk));
+    }
+    let total = state.entry.as_ref().map(|e| format!(" of {}", e.size.div_ceil(block_size as u64))).unwrap_or_default();
+    let ranges = if state.blocks.is_empty() { String::new() } else { format!(" ({})", block_ranges(&state.blocks)) };
+    lines.push(format!("blocks: {}{} cached{}, {} bytes each", state.blocks.len(), total, ranges, block_size));
+    match state.lease {
+        Some((kind, left)) => lines.push(format!("lease: {:?}, {}s left", kind, left.as_secs())),
+        None => lines.push("lease: none".to_string()),
+    }
+    match state.pinned.as_ref() {
+        Some(pinned) => {
+            let mut flags = Vec::new();
+            if pinned.stale {
+                flags.push("stale".to_string());
+            }
+            if pinned.hydrated {
+                flags.push("downloaded on demand".to_string());
+            }
+            if let Some(len) = state.pinned_len {
+                flags.push(format!("local copy {} bytes", len));
+            }
+            lines.push(format!("pinned: yes{}", flags.iter().map(|f| format!(", {}", f)).collect::<String>()));
+        }
+        None => lines.push("pinned: no".to_string()),
+    }
+    let sending: Vec<String> = transfers.snapshot().into_iter().filter(|t| t.ino == ino)
+        .map(|t| format!("; sending {}/{} bytes", t.sent, t.total)).collect();
+    lines.push(format!("dirty: {} bytes not yet sent{}", transfers.dirty_of(ino), sending.concat()));
+    lines
+}
+
+// pinna `ino` e, se è una directory, tutto il suo contenuto; il lock sulla cache viene preso per
+// ogni singola voce, così il filesystem resta utilizzabile durante il download
+fn pin_tree<B: RemoteBackend>(cache: &Mutex<Cache<B>>, ino: u64, path: &str, progress: &mut dyn FnMut(String)) -> Result<(usize, u64), BackendError> {
+    let pinned = cache.lock().expect("Mutex poisoned").pin(ino)?;
+    let mut entries = 1;
+    let mut bytes = 0;
+    match pinned.entry.kind {
+        EntryType::File => {
+            bytes = pinned.entry.size;
+            progress(format!("pinned {} ({} bytes)", path, bytes));
+        }
+        EntryType::Directory => {
+            for child in pinned.children.unwrap_or_default() {
+                let name = cache.lock().expect("Mutex poisoned").get_attr(child)?.name;
+                let (e, b) = pin_tree(cache, child, &format!("{}/{}", path.trim_end_matches('/'), name), progress)?;
+                entries += e;
+                bytes += b;
+            }
+        }
+        EntryType::Symlink => {}
+    }
+    Ok((entries, bytes))
+}
+
+#[derive(Default)]
+struct WarmStats {
+    dirs: AtomicUsize,
+    files: AtomicUsize,
+    // file di cui sono stati messi in cache anche i blocchi
+    warmed: AtomicUsize,
+    bytes: AtomicU64,
+    errors: AtomicUsize,
+}
+
+impl WarmStats {
+    fn summary(&self) -> String {
+        format!("{} directories, {} files scanned; {} files ({} bytes) cached; {} errors",
+            self.dirs.load(Ordering::Relaxed), self.files.load(Ordering::Relaxed),
+            self.warmed.load(Ordering::Relaxed), self.bytes.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed))
+    }
+}
+
+// visita il sottoalbero caricando i metadati nella cache; i primi file (fino alla capacità della
+// cache dei blocchi) vengono passati ai worker, che ne scaricano il contenuto in parallelo
+fn warm_tree<B, F>(cache: &Mutex<Cache<B>>, fetcher: &(dyn Fn() -> F + Send + Sync), ino: u64, jobs: usize, stats: &WarmStats, progress: &mut dyn FnMut()) -> Result<(), BackendError>
+where
+    B: RemoteBackend + Send,
+    F: RemoteBackend,
+{
+    let (file_cap, limit) = {
+        let cache = cache.lock().expect("Mutex poisoned");
+        let (file_cap, blocks_per_file) = cache.block_capacity();
+        (file_cap, (blocks_per_file * cache.block_size()) as u64)
+    };
+    let (tx, rx) = mpsc::channel::<FileEntry>();
+    let rx = Mutex::new(rx);
+
+    thread::scope(|s| {
+        let walker = s.spawn(move || {
+            let mut queued = 0;
+            let mut stack = vec![ino];
+            while let Some(dir) = stack.pop() {
+                let entries = {
+                    let mut cache = cache.lock().expect("Mutex poisoned");
+                    let entry = cache.get_attr(dir)?;
+                    if entry.kind != EntryType::Directory {
+                        vec![entry] // warm di un singolo file
+                    } else {
+                        stats.dirs.fetch_add(1, Ordering::Relaxed);
+                        cache.list_dir(dir)?
+                    }
+                };
+                for entry in entries {
+                    match entry.kind {
+                        EntryType::Directory => stack.push(entry.ino),
+                        EntryType::File => {
+                            stats.files.fetch_add(1, Ordering::Relaxed);
+                            let pinned = cache.lock().expect("Mutex poisoned").is_pinned(entry.ino);
+                            if queued < file_cap && !pinned && entry.size > 0 {
+                                queued += 1;
+                                let _ = tx.send(entry);
+                            }
+                        }
+                        EntryType::Symlink => {}
+                    }
+                }
+            }
+            Ok(())
+        });
+
+        let rx = &rx;
+        let workers: Vec<_> = (0..jobs).map(|_| s.spawn(move || {
+            let mut backend = fetcher();
+            loop {
+                let next = rx.lock().expect("Mutex poisoned").recv();
+                let Ok(entry) = next else { return };
+                match fetch_prefix(&mut backend, &entry, limit) {
+                    Ok(data) => {
+                        stats.warmed.fetch_add(1, Ordering::Relaxed);
+                        stats.bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+                        cache.lock().expect("Mutex poisoned").prime_blocks(&entry, &data);
+                    }
+                    Err(e) => {
+                        stats.errors.fetch_add(1, Ordering::Relaxed);
+                        eprintln!("Unable to warm {}: {}", entry.path, e);
+                    }
+                }
+            }
+        })).collect();
+
+        let mut last = Instant::now();
+        while !walker.is_finished() || workers.iter().any(|w| !w.is_finished()) {
+            thread::sleep(Duration::from_millis(100));
+            if last.elapsed() >= PROGRESS_EVERY {
+                progress();
+                last = Instant::now();
+            }
+        }
+        walker.join().expect("warm walker panicked")
+    })
+}
+
+fn fetch_prefix<F: RemoteBackend>(backend: &mut F, entry: &FileEntry, limit: u64) -> Result<Vec<u8>, BackendError> {
+    let size = entry.size.min(limit);
+    let mut data = Vec::with_capacity(size as usize);
+    while (data.len() as u64) < size {
+        let chunk = backend.read_chunk(entry.ino, data.len() as u64, (size - data.len() as u64).min(WARM_FETCH_SIZE))?;
+        if chunk.is_empty() {
+            break;
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
@@ -1,67 +1,1110 @@
-use clap::{Parser,ArgAction};
-use rfs_api::{HttpBackend,Credentials};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum, ArgAction};
+use clap::parser::ValueSource;
+use rfs_api::{HttpBackend,Credentials,LoginError,RfsClient,RequestLimits};
+use rfs_api::session::{self, SessionStore};
+use rfs_models::{AuditLog, BackendError, CreateModes, DEFAULT_JUNK_FILES, FileEntry, IoSizes, JunkFilter, JunkMode, MODE_BITS, PROTOCOL_VERSION, ROOT_INO, RemoteBackend};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::runtime::{Builder,Runtime};
+use config::Config;
+use startup::StartupError;
+
+mod audit;
+mod bench;
+#[cfg(unix)]
+mod check;
+mod config;
+#[cfg(unix)]
+mod control;
+mod startup;
+mod sync;
+mod transfer;
+#[cfg(unix)]
+mod watchdog;
 
 // ---------- Costanti OS-specifiche ----------
+const DEFAULT_VOLNAME: &str = "Remote-FS";
+// servizio delle voci del portachiavi con le chiavi della cache su disco
+const KEYRING_SERVICE: &str = "remote-fs-cache";
+#[cfg(target_os = "windows")]
+const DEFAULT_MOUNT: &str = "X:";
+
+// Linux: ~/mnt/remote (creata se manca), macOS: /Volumes/<volname> (la crea macFUSE), Windows: lettera di drive
 #[cfg(target_os = "linux")]
-const DEFAULT_MOUNT: &str = "/home/matteo/mnt/remote";
+fn default_mount_point() -> String {
+    match std::env::var("HOME") {
+        Ok(home) if !home.is_empty() => format!("{}/mnt/remote", home.trim_end_matches('/')),
+        _ => "/mnt/remote".to_string(),
+    }
+}
 #[cfg(target_os = "macos")]
-const DEFAULT_MOUNT: &str = "/Volumes/Remote-FS"; //?DA CONTROLLARE
+fn default_mount_point() -> String {
+    format!("/Volumes/{}", DEFAULT_VOLNAME)
+}
 #[cfg(target_os = "windows")]
-const DEFAULT_MOUNT: &str = "X:";
+fn default_mount_point() -> String {
+    DEFAULT_MOUNT.to_string()
+}
+
+// sessioni salvate per server: ~/.config/remote-fs/sessions.json (%APPDATA%\remote-fs su Windows)
+fn default_session_file() -> std::path::PathBuf {
+    let base = std::env::var("XDG_CONFIG_HOME").ok().filter(|d| !d.is_empty())
+        .or_else(|| std::env::var("APPDATA").ok().filter(|d| !d.is_empty()))
+        .or_else(|| std::env::var("HOME").ok().filter(|h| !h.is_empty()).map(|h| format!("{}/.config", h.trim_end_matches('/'))))
+        .unwrap_or_else(|| std::env::temp_dir().to_string_lossy().into_owned());
+    std::path::Path::new(&base).join("remote-fs").join("sessions.json")
+}
+
+// configurazione accanto alle sessioni: ~/.config/remote-fs/config.toml
+fn default_config_file() -> std::path::PathBuf {
+    default_session_file().with_file_name("config.toml")
+}
+
+// log di audit: ~/.local/state/remote-fs/audit.log (%LOCALAPPDATA%\remote-fs su Windows)
+fn default_audit_file() -> String {
+    let base = std::env::var("XDG_STATE_HOME").ok().filter(|d| !d.is_empty())
+        .or_else(|| std::env::var("LOCALAPPDATA").ok().filter(|d| !d.is_empty()))
+        .or_else(|| std::env::var("HOME").ok().filter(|h| !h.is_empty()).map(|h| format!("{}/.local/state", h.trim_end_matches('/'))))
+        .unwrap_or_else(|| std::env::temp_dir().to_string_lossy().into_owned());
+    std::path::Path::new(&base).join("remote-fs").join("audit.log").to_string_lossy().into_owned()
+}
+
+// cartella della cache su disco (file pinnati): ~/.cache/remote-fs
+fn default_cache_dir() -> String {
+    match std::env::var("HOME") {
+        Ok(home) if !home.is_empty() => format!("{}/.cache/remote-fs", home.trim_end_matches('/')),
+        _ => "/tmp/remote-fs-cache".to_string(),
+    }
+}
+
+// permessi in ottale, con o senza prefisso 0o
+fn parse_mode(s: &str) -> Result<u32, String> {
+    let mode = u32::from_str_radix(s.trim_start_matches("0o"), 8).map_err(|e| format!("invalid octal mode: {}", e))?;
+    if mode > MODE_BITS {
+        return Err("mode must be between 0000 and 7777".to_string());
+    }
+    Ok(mode)
+}
+
+// istante di uno snapshot: secondi Unix oppure data UTC `AAAA-MM-GG[THH:MM[:SS]][Z]`
+fn parse_snapshot(s: &str) -> Result<SystemTime, String> {
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+    let invalid = || format!("invalid snapshot time {:?}: use Unix seconds or YYYY-MM-DD[THH:MM[:SS]] (UTC)", s);
+    let s = s.strip_suffix('Z').unwrap_or(s);
+    let (date, time) = s.split_once(['T', ' ']).unwrap_or((s, "00:00"));
+    let date: Vec<i64> = date.split('-').map(str::parse).collect::<Result<_, _>>().map_err(|_| invalid())?;
+    let time: Vec<i64> = time.split(':').map(str::parse).collect::<Result<_, _>>().map_err(|_| invalid())?;
+    let (y, m, d, hh, mm, ss) = match (&date[..], &time[..]) {
+        (&[y, m, d], &[hh, mm]) => (y, m, d, hh, mm, 0),
+        (&[y, m, d], &[hh, mm, ss]) => (y, m, d, hh, mm, ss),
+        _ => return Err(invalid()),
+    };
+    if !(1..=12).contains(&m) || !(1..=31).contains(&d) || !(0..24).contains(&hh) || !(0..60).contains(&mm) || !(0..60).contains(&ss) {
+        return Err(invalid());
+    }
+    // giorni dal 1970-01-01 nel calendario gregoriano (algoritmo days_from_civil)
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400 + hh * 3600 + mm * 60 + ss;
+    u64::try_from(secs).map(|secs| UNIX_EPOCH + Duration::from_secs(secs)).map_err(|_| invalid())
+}
+
+/// Mount aggiuntivo con le credenziali di un altro utente (--mount-as)
+#[derive(Clone, Debug)]
+struct ExtraMount {
+    mount_point: String,
+    username: String,
+}
+
+fn parse_extra_mount(s: &str) -> Result<ExtraMount, String> {
+    match s.rsplit_once('=') {
+        Some((mount_point, username)) if !mount_point.is_empty() && !username.is_empty() => {
+            Ok(ExtraMount { mount_point: mount_point.to_string(), username: username.to_string() })
+        }
+        _ => Err(format!("invalid mount {:?}: use MOUNT_POINT=USER", s)),
+    }
+}
+
+/// Come riconoscere i file eseguibili creati da Windows
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ExecDetect {
+    None,
+    Extension,
+    Shebang,
+    All,
+}
+
+/// Opzioni di mount passate con -o
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum MountFlag {
+    /// Sola lettura: ogni modifica viene rifiutata dal client con EROFS, senza contattare il server
+    Ro,
+    /// Lettura e scrittura (default)
+    Rw,
+}
+
+/// Cosa fare dei file creati dai sistemi operativi (vedi --junk-files)
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum JunkAction {
+    /// Invisibili nelle liste e non creati
+    Hide,
+    /// Visibili se già presenti sul server, ma non creati
+    Block,
+    /// Trattati come gli altri file
+    Pass,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Monta il filesystem con le impostazioni di un profilo del file di configurazione, come --profile
+    Mount { profile: Option<String> },
+    /// Mantiene un file o una directory sempre in cache su disco, disponibile anche offline (solo Unix)
+    Pin { path: String },
+    /// Rimuove il pin e la copia locale (solo Unix)
+    Unpin { path: String },
+    /// Precarica metadati e blocchi di un sottoalbero nella cache del demone (solo Unix)
+    Warm {
+        path: String,
+        /// Numero di file scaricati in parallelo
+        #[arg(short, long, default_value_t = 4)]
+        jobs: usize,
+    },
+    /// Spazio occupato da un sottoalbero, calcolato dal server (solo Unix)
+    Du { path: String },
+    /// Cerca sul server file e directory di un sottoalbero, senza visitarlo dal mount (solo Unix)
+    Find {
+        path: String,
+        /// Nome da cercare, con wildcard `*` e `?`
+        #[arg(long)]
+        name: Option<String>,
+        /// Dimensione minima in byte
+        #[arg(long)]
+        min_size: Option<u64>,
+        /// Dimensione massima in byte
+        #[arg(long)]
+        max_size: Option<u64>,
+        /// Solo le voci modificate negli ultimi N secondi
+        #[arg(long, value_name = "SECS")]
+        newer_than: Option<u64>,
+        /// Solo le voci modificate più di N secondi fa
+        #[arg(long, value_name = "SECS")]
+        older_than: Option<u64>,
+        /// Risultati massimi
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        limit: Option<u32>,
+    },
+    /// Stato del mount: online oppure degradato, con le letture servite dalla cache (solo Unix)
+    Status {
+        /// Mostra i byte scritti non ancora inviati e gli invii al server in corso: byte inviati, velocità e tempo stimato
+        #[arg(long)]
+        transfers: bool,
+    },
+    /// Barriera di coerenza del mount: invia tutte le scritture nei buffer, aspetta gli invii in corso e
+    /// rivalida i file aperti; da usare prima di un backup o di uno snapshot sul server (solo Unix)
+    FlushAll,
+    /// Elenca una directory remota (`remote:/path` o `/path`) senza montare
+    Ls {
+        path: String,
+        /// Tipo, permessi, proprietario, gruppo e dimensione di ogni voce
+        #[arg(short, long)]
+        long: bool,
+    },
+    /// Copia un file tra locale e server senza montare: `cp file remote:/path` o `cp remote:/path file`
+    Cp {
+        from: String,
+        to: String,
+        /// Blocchi trasferiti in parallelo
+        #[arg(short, long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..=64))]
+        jobs: u16,
+        /// Riprende una copia interrotta: i blocchi già uguali nella destinazione non vengono ritrasferiti
+        #[arg(long)]
+        resume: bool,
+    },
+    /// Cancella un file o una directory remota senza montare
+    Rm {
+        path: String,
+        /// Cancella anche il contenuto delle directory
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// Sincronizza una volta, a senso unico, una directory locale e un sottoalbero remoto (`remote:/path`),
+    /// nella direzione degli argomenti: copia i file nuovi o con dimensione o data di modifica diverse
+    Sync {
+        from: String,
+        to: String,
+        /// Cancella dalla destinazione file e directory che non esistono nella sorgente
+        #[arg(long)]
+        delete: bool,
+        /// Mostra cosa verrebbe copiato o cancellato senza farlo
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// File copiati in parallelo
+        #[arg(short, long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..=64))]
+        jobs: u16,
+    },
+    /// Legge più volte per intero un file o un sottoalbero dal mount e misura ogni passata: lanciato su un
+    /// mount con --trust-cache e su uno senza mostra quanto accelera le letture ripetute
+    Bench {
+        path: String,
+        /// Passate di lettura, la prima a freddo
+        #[arg(short, long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(2..))]
+        passes: u32,
+    },
+    /// Confronta con il server il journal delle scritture e la cache dei pin del mount (--mount-point,
+    /// --cache-dir) lasciati da un demone terminato male, e segnala quello che non torna (solo Unix)
+    Check {
+        /// Ripara: rigioca o scarta le scritture rimaste, rimuove i pin orfani e i file temporanei
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Cosa tiene in cache il demone per un path, e scarto mirato di quello che ha (solo Unix)
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+    /// Log di audit scritto dal demone con --audit-log (--audit-file)
+    Log {
+        #[command(subcommand)]
+        action: LogAction,
+    },
+    /// Chiude la sessione salvata per il server (--remote-address) e la cancella dal disco
+    Logout,
+    /// Cambia la password dell'utente sul server (--remote-address)
+    Passwd,
+    /// Gestione di utenti e gruppi sul server (solo amministratore)
+    Admin {
+        #[command(subcommand)]
+        target: AdminTarget,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheAction {
+    /// Voci di una directory nella lista in cache, con metadati, blocchi, pin e byte non ancora inviati
+    Ls { path: String },
+    /// Metadati e validatore, lista, blocchi, lease, pin e scritture non ancora inviate di un path
+    Stat { path: String },
+    /// Scarta metadati, lista, blocchi ed etag di un path, che verranno riletti dal server; la copia dei
+    /// file pinnati resta
+    Evict {
+        path: String,
+        /// Scarta anche quello che è in cache sotto una directory
+        #[arg(short, long)]
+        recursive: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum LogAction {
+    /// Mostra le ultime operazioni registrate
+    Tail {
+        /// Numero di righe
+        #[arg(short = 'n', long, default_value_t = 20)]
+        lines: usize,
+        /// Resta in attesa delle nuove operazioni
+        #[arg(short, long)]
+        follow: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AdminTarget {
+    /// Utenti del server
+    User {
+        #[command(subcommand)]
+        action: UserAction,
+    },
+    /// Gruppi del server
+    Group {
+        #[command(subcommand)]
+        action: GroupAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum UserAction {
+    /// Crea un utente con la sua home directory (la password viene chiesta)
+    Add {
+        uid: u32,
+        /// Gruppo esistente a cui assegnarlo
+        #[arg(long)]
+        group: Option<u32>,
+    },
+    /// Rimuove un utente che non possiede più file
+    Del { uid: u32 },
+    /// Elenca gli utenti e il loro gruppo
+    List,
+    /// Dà all'utente il nome mostrato dai client al posto dell'uid; senza nome lo toglie
+    Name { uid: u32, name: Option<String> },
+}
+
+#[derive(Subcommand, Debug)]
+enum GroupAction {
+    /// Crea un gruppo vuoto
+    Add { gid: u32 },
+    /// Rimuove un gruppo non più assegnato a file
+    Del { gid: u32 },
+    /// Elenca i gruppi e i loro membri
+    List,
+    /// Dà al gruppo il nome mostrato dai client al posto del gid; senza nome lo toglie
+    Name { gid: u32, name: Option<String> },
+    /// Sposta un utente nel gruppo (ogni utente ha un solo gruppo)
+    AddUser { gid: u32, uid: u32 },
+    /// Toglie un utente dal gruppo
+    DelUser { gid: u32, uid: u32 },
+}
 
 #[derive(Parser, Debug)]
 #[command(name = "Remote-FS", version = "0.1.0")]
 struct Cli {
+    /// Comando da inviare al demone già avviato (logout e passwd vanno al server); senza comando monta il filesystem
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Directory di mount del filesystem remoto in locale
-    #[arg(short, long, default_value = DEFAULT_MOUNT)]
+    #[arg(short, long, default_value_t = default_mount_point())]
     mount_point: String,
 
-    /// Indirizzo del backend remoto
-    #[arg(short, long, default_value = "http://fzucca.com:25570")]  //"http://fzucca.com:25570"
-    remote_address: String,
+    /// Indirizzo del backend remoto, es. http://server:3000; obbligatorio se il file di configurazione non
+    /// ha un profilo da usare
+    #[arg(short, long)]
+    remote_address: Option<String>,
+
+    /// Profilo di connessione del file di configurazione (URL, autenticazione e cache); senza, il profilo
+    /// "default" o l'unico definito
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Opzioni di mount separate da virgola, es. `-o ro`; vale l'ultima tra ro e rw
+    #[arg(short = 'o', long = "options", value_enum, value_delimiter = ',')]
+    mount_options: Vec<MountFlag>,
+
+    /// Non riprende né salva la sessione su disco: le credenziali vengono chieste a ogni mount
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_saved_session: bool,
+
+    /// Utente con cui autenticarsi: viene chiesta solo la password
+    #[arg(long, value_name = "USER")]
+    user: Option<String>,
+
+    /// Legge la password di --user dalla prima riga dello standard input, per gli script: un solo
+    /// tentativo, senza domande sul terminale
+    #[arg(long, action = ArgAction::SetTrue, requires = "user")]
+    password_stdin: bool,
 
-    /// Abilita la modalità speed testing (solo Unix)
+    /// Monta anche l'area di un altro utente nello stesso demone, con credenziali e sessione separate,
+    /// es. `--mount-as ~/mnt/servizio=5001`; ripetibile. Senza socket di controllo né pin (solo Unix)
+    #[arg(long = "mount-as", value_name = "MOUNT_POINT=USER", value_parser = parse_extra_mount)]
+    extra_mounts: Vec<ExtraMount>,
+
+    /// Abilita la modalità speed testing: la durata di ogni operazione finisce in remote-fs.speed-test.out
+    /// nella cartella temporanea (/tmp su Unix)
     #[arg(short, long, action = ArgAction::SetTrue)]
     speed_testing: bool,
+
+    /// Secondi massimi di attesa allo smontaggio per inviare le scritture ancora in buffer
+    #[arg(long, default_value_t = 30)]
+    shutdown_timeout: u64,
+
+    /// Tentativi di rimontaggio se la sessione termina senza essere stata smontata (panic in una callback,
+    /// connessione con il kernel persa); il mount point viene comunque liberato (solo Unix)
+    #[arg(long, default_value_t = 0)]
+    auto_remount: u32,
+
+    /// Run indipendenti (e file diversi allo smontaggio) inviate in parallelo durante un flush; con più
+    /// di 1 le run non sono condizionali (If-Match) (solo Unix)
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=64))]
+    flush_jobs: u16,
+
+    /// Thread che servono le operazioni che vanno al server (getattr, lookup, readdir, read, open, ...),
+    /// così un server lento non blocca le altre operazioni sul mount; con 0 tutto passa dal thread
+    /// della sessione fuse (solo Unix)
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(0..=64))]
+    fuse_workers: u16,
+
+    /// Mostra come proprietari gli utenti e i gruppi locali con lo stesso nome di quelli del server (ls -l),
+    /// riletti ogni 10 minuti; chi non ha un corrispondente resta con l'id del server (solo Unix)
+    #[arg(long)]
+    owner_names: bool,
+
+    /// MiB letti in sequenza da un file aperto oltre cui le read passano in streaming invece di andare a
+    /// blocchi dalla cache; un salto le riporta a blocchi. Con 0 non si passa mai allo streaming (solo Unix)
+    #[arg(long, default_value_t = 8)]
+    stream_after: u64,
+
+    /// Richieste di metadati (lookup, getattr, liste, ...) in volo al massimo verso il server per mount
+    #[arg(long, default_value_t = 64, value_parser = clap::value_parser!(u16).range(1..))]
+    max_metadata_requests: u16,
+
+    /// Richieste di dati (read, write, stream) in volo al massimo verso il server per mount
+    #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u16).range(1..))]
+    max_data_requests: u16,
+
+    /// MiB scritti e non ancora inviati, in tutti i file, oltre cui le write aspettano che i buffer vengano
+    /// inviati al server (fino a scendere sotto i tre quarti) (solo Unix)
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u64).range(1..))]
+    dirty_limit: u64,
+
+    /// Millisecondi di latenza media oltre cui i buffer piccoli dei file chiusi vengono raccolti e inviati
+    /// insieme in un'unica richiesta; la close non aspetta l'invio e un errore arriva in ritardo (solo Unix)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    batch_latency: Option<u64>,
+
+    /// Disabilita il journal locale delle scritture in buffer (solo Unix)
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_journal: bool,
+
+    /// Fa controllare i permessi al kernel (default_permissions): file e gruppi dell'utente del server
+    /// vengono mostrati come dell'utente locale che ha montato (solo Unix)
+    #[arg(long, action = ArgAction::SetTrue)]
+    default_permissions: bool,
+
+    /// Disabilita la writeback cache del kernel: ogni write arriva subito al demone (solo Unix)
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_writeback_cache: bool,
+
+    /// Byte massimi per singola write dal kernel, default 1 MiB (solo Unix)
+    #[arg(long)]
+    max_write: Option<u32>,
+
+    /// Byte di readahead del kernel, default 1 MiB (solo Unix)
+    #[arg(long)]
+    max_readahead: Option<u32>,
+
+    /// Richieste in background che il kernel tiene in volo, default 64 (solo Unix)
+    #[arg(long)]
+    max_background: Option<u16>,
+
+    /// Dimensione in byte dei blocchi della cache, default 16 KiB; ridotta al massimo accettato dal server
+    #[arg(long, value_parser = clap::value_parser!(u32).range(rfs_models::MIN_BLOCK_SIZE as i64..))]
+    block_size: Option<u32>,
+
+    /// Dimensione in byte oltre cui letture e scritture passano in streaming, default 100 MiB
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    large_file_size: Option<u64>,
+
+    /// Secondi tra due rivalidazioni degli attributi dei file aperti; senza valore non vengono rivalidati
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    attr_refresh: Option<u64>,
+
+    /// Tiene pagine e attributi nella cache del kernel finché il server non li richiama, invece di
+    /// rileggerli dopo pochi secondi: molto più veloce sulle letture ripetute (ad es. dei build tool)
+    /// (solo Unix)
+    #[arg(long, action = ArgAction::SetTrue)]
+    trust_cache: bool,
+
+    /// Secondi per cui il kernel riusa gli attributi di file e directory senza richiederli, al posto di
+    /// quelli del file di configurazione; 0 li richiede ogni volta (solo Unix)
+    #[arg(long, value_name = "SECS")]
+    attr_ttl: Option<u64>,
+
+    /// Secondi per cui il kernel riusa la risoluzione di un nome (lookup) senza richiederla, come --attr-ttl
+    /// (solo Unix)
+    #[arg(long, value_name = "SECS")]
+    entry_ttl: Option<u64>,
+
+    /// Mostra albero e attributi ma non scarica il contenuto dei file: aprirli in lettura fallisce con
+    /// "No data available" finché non vengono pinnati (`pin`) (solo Unix)
+    #[arg(long, action = ArgAction::SetTrue)]
+    metadata_only: bool,
+
+    /// File su richiesta: le voci compaiono subito, il contenuto viene scaricato alla prima lettura e
+    /// tenuto nella cache su disco; `attrib +P` lo tiene sempre in locale, `attrib +U` libera spazio
+    /// (solo Windows)
+    #[arg(long, action = ArgAction::SetTrue)]
+    files_on_demand: bool,
+
+    /// Monta in sola lettura il filesystem com'era a un istante passato (secondi Unix o data UTC
+    /// AAAA-MM-GG[THH:MM[:SS]]), servito dallo snapshot del server più recente entro quell'istante
+    #[arg(long, value_parser = parse_snapshot)]
+    snapshot: Option<SystemTime>,
+
+    /// Registra in un log locale le operazioni che aprono o modificano file: ora, utente e processo locali
+    /// (su Windows non disponibili), operazione, path ed esito; da leggere con `log tail`
+    #[arg(long, action = ArgAction::SetTrue)]
+    audit_log: bool,
+
+    /// File del log di audit
+    #[arg(long, default_value_t = default_audit_file())]
+    audit_file: String,
+
+    /// MiB oltre cui il log di audit viene ruotato in <file>.1, <file>.2, ...
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    audit_log_size: u64,
+
+    /// Cartella della cache su disco dei file pinnati e delle liste delle directory (su Windows usata solo
+    /// con --files-on-demand)
+    #[arg(long, default_value_t = default_cache_dir())]
+    cache_dir: String,
+
+    /// Non salva su disco le liste delle directory: dopo un rimontaggio ogni directory viene rilistata
+    /// (solo Unix)
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_listing_cache: bool,
+
+    /// Cifra la cache su disco con una chiave conservata nel portachiavi del sistema; una cache già
+    /// scritta in chiaro viene convertita al mount
+    #[arg(long, action = ArgAction::SetTrue)]
+    encrypt_cache: bool,
+
+    /// Rilevamento dei file eseguibili creati da Windows (solo Windows)
+    #[arg(long, value_enum, default_value_t = ExecDetect::All)]
+    exec_detect: ExecDetect,
+
+    /// Permessi in ottale dei file creati, es. 664, al posto di quelli chiesti dal processo (su Windows
+    /// al posto del default del server)
+    #[arg(long, value_parser = parse_mode)]
+    file_mode: Option<u32>,
+
+    /// Permessi in ottale delle directory create, es. 775, come --file-mode
+    #[arg(long, value_parser = parse_mode)]
+    dir_mode: Option<u32>,
+
+    /// Umask in ottale applicata alle creazioni su questo mount al posto di quella del processo, es. 002
+    #[arg(long, value_parser = parse_mode)]
+    umask: Option<u32>,
+
+    /// Nome del volume mostrato dal Finder (solo macOS)
+    #[arg(long, default_value = DEFAULT_VOLNAME)]
+    volname: String,
+
+    /// Marca il volume come locale invece che di rete (solo macOS)
+    #[arg(long, action = ArgAction::SetTrue)]
+    local: bool,
+
+    /// File creati automaticamente dai sistemi operativi, pattern con `*` e `?` separati da virgola
+    #[arg(long, value_delimiter = ',', default_values = DEFAULT_JUNK_FILES)]
+    junk_files: Vec<String>,
+
+    /// Cosa fare dei file in --junk-files
+    #[arg(long, value_enum, default_value_t = JunkAction::Hide)]
+    junk_mode: JunkAction,
+
+    /// Permette la creazione dei file AppleDouble `._*` sul server, anche se sono in --junk-files
+    #[arg(long, action = ArgAction::SetTrue)]
+    apple_double: bool,
+
+    /// Disabilita gli extended attributes com.apple.* (solo macOS)
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_apple_xattr: bool,
+
+    /// File di configurazione TOML con profili e regole di policy, default ~/.config/remote-fs/config.toml
+    #[arg(long = "config", value_name = "FILE")]
+    config_file: Option<String>,
+
+    /// Configurazione letta da --config prima del mount
+    #[arg(skip)]
+    config: Config,
+
+    /// Password letta da stdin con --password-stdin
+    #[arg(skip)]
+    password: Option<String>,
+}
+
+impl Cli {
+    fn read_only(&self) -> bool {
+        self.mount_options.last() == Some(&MountFlag::Ro)
+    }
+
+    // indirizzo del server, dalla riga di comando o dal profilo (controllato in main)
+    fn address(&self) -> &str {
+        self.remote_address.as_deref().expect("remote address resolved at startup")
+    }
+}
+
+// le impostazioni del profilo scelto valgono per le opzioni non date sulla riga di comando
+fn apply_profile(cli: &mut Cli, matches: &ArgMatches) -> Result<(), String> {
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let Some((_, profile)) = cli.config.profile(cli.profile.as_deref())? else { return Ok(()) };
+    let profile = profile.clone();
+    if cli.remote_address.is_none() {
+        cli.remote_address = Some(profile.url);
+    }
+    if !given("no_saved_session") {
+        cli.no_saved_session = profile.auth == config::AuthMode::Prompt;
+    }
+    if cli.user.is_none() {
+        cli.user = profile.user;
+    }
+    if let Some(mount_point) = profile.mount_point.filter(|_| !given("mount_point")) {
+        cli.mount_point = mount_point;
+    }
+    if let Some(cache_dir) = profile.cache_dir.filter(|_| !given("cache_dir")) {
+        cli.cache_dir = cache_dir;
+    }
+    if !given("trust_cache") {
+        cli.trust_cache = profile.trust_cache;
+    }
+    if !given("encrypt_cache") {
+        cli.encrypt_cache = profile.encrypt_cache;
+    }
+    if cli.attr_refresh.is_none() {
+        cli.attr_refresh = profile.attr_refresh;
+    }
+    Ok(())
 }
 
 // su windows settare:
 // $env:PATH += ";C:\Program Files (x86)\WinFsp\bin"
 
 fn main(){
-    let cli = Cli::parse();
-
-    // first authentication
-    let (credentials, sessionid) = match Credentials::first_authentication(&cli.remote_address) {
-        Ok(creds) =>{
-            println!("Authentication successful. Welcome!");
-            creds
-        } ,
-        Err(e) => {
-            eprintln!("Error authenticating: {}", e);
-            eprintln!("Exiting...");
-            return;
-        }
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    // un file di configurazione sbagliato ferma tutto prima del login e del passaggio in background
+    let (config_file, explicit) = match cli.config_file.as_ref() {
+        Some(file) => (std::path::PathBuf::from(file), true),
+        None => (default_config_file(), false),
     };
+    cli.config = Config::load(&config_file, explicit).unwrap_or_else(|e| StartupError::Invalid(e).exit());
+    if let Some(Command::Mount { profile }) = cli.command.take_if(|c| matches!(c, Command::Mount { .. })) {
+        cli.profile = profile.or(cli.profile.take());
+    }
+    if let Err(e) = apply_profile(&mut cli, &matches) {
+        StartupError::Invalid(e).exit();
+    }
+    let needs_server = !matches!(cli.command, Some(Command::Log { .. } | Command::Bench { .. } | Command::Pin { .. } | Command::Unpin { .. } | Command::Warm { .. }
+        | Command::Du { .. } | Command::Find { .. } | Command::Status { .. } | Command::Cache { .. } | Command::FlushAll));
+    if needs_server && cli.remote_address.is_none() {
+        StartupError::Invalid(format!("No server address: pass --remote-address or add a profile to {}", config_file.display())).exit();
+    }
+    if needs_server && let Err(e) = startup::check_address(cli.address()) {
+        e.exit();
+    }
+    if cli.password_stdin {
+        cli.password = Some(read_stdin_password().unwrap_or_else(|e| StartupError::Invalid(e).exit()));
+    }
+
+    if let Some(command) = cli.command.take() {
+        let code = match command {
+            Command::Logout => logout(&cli),
+            Command::Passwd => passwd(&cli),
+            Command::Admin { target } => admin(&cli, target),
+            Command::Log { action: LogAction::Tail { lines, follow } } => audit::tail(std::path::Path::new(&cli.audit_file), lines, follow),
+            Command::Check { repair } => check_state(&cli, repair),
+            Command::Bench { path, passes } => bench::repeated_reads(std::path::Path::new(&path), passes),
+            command @ (Command::Ls { .. } | Command::Cp { .. } | Command::Rm { .. } | Command::Sync { .. }) => direct(&cli, command),
+            command => run_command(&cli, command),
+        };
+        std::process::exit(code);
+    }
+
+    // un mount point sbagliato si scopre prima di chiedere le credenziali
+    let mount_points = std::iter::once(&cli.mount_point).chain(cli.extra_mounts.iter().map(|extra| &extra.mount_point));
+    if let Some(e) = mount_points.filter_map(|mount_point| startup::check_mount_point(mount_point).err()).next() {
+        e.exit();
+    }
+
+    // first authentication, se non c'è una sessione salvata ancora valida
+    let (credentials, sessionid) = login(&cli, None).unwrap_or_else(|e| StartupError::from(e).exit());
+    // le credenziali degli altri utenti vanno chieste ora, prima di passare in background
+    #[cfg(unix)]
+    let extra_logins = extra_logins(&cli);
+    #[cfg(target_os = "windows")]
+    for extra in &cli.extra_mounts {
+        eprintln!("--mount-as is not supported on Windows: {} is not mounted for {}", extra.mount_point, extra.username);
+    }
 
     #[cfg(target_os = "linux")]
     {
         if let Err(e) = demonize() {
-            eprintln!("{}", e);
-            eprintln!("Exiting...");
-            return;
+            StartupError::Mount(e).exit();
         }
     }
 
     let runtime= Arc::new(Builder::new_multi_thread().enable_all().thread_name("rfs-runtime").build().expect("Unable to build a Runtime object"));
-    let http_backend= HttpBackend::new(cli.remote_address.clone(), credentials, sessionid, runtime.clone()).expect("Cannot create the HTTP backend");
+    let (http_backend, io) = connect(&cli, credentials, sessionid, runtime.clone());
 
     #[cfg(unix)]
-    run_unix(cli, http_backend, runtime);
+    {
+        let extras = extra_logins.into_iter().map(|(mount_point, credentials, sessionid)| {
+            let (backend, io) = connect(&cli, credentials, sessionid, runtime.clone());
+            (mount_point, backend, io)
+        }).collect();
+        run_unix(cli, http_backend, extras, runtime, io);
+    }
     #[cfg(target_os = "windows")]
-    run_windows(cli, http_backend, runtime);
+    run_windows(cli, http_backend, runtime, io);
+}
+
+// backend con la sessione dell'utente e le opzioni del mount, più le dimensioni di I/O concordate col server
+fn connect(cli: &Cli, credentials: Credentials, sessionid: String, runtime: Arc<Runtime>) -> (HttpBackend, IoSizes) {
+    let mut http_backend= HttpBackend::new(cli.address().to_string(), credentials, sessionid, runtime).expect("Cannot create the HTTP backend")
+        .with_request_limits(RequestLimits::new(cli.max_metadata_requests as usize, cli.max_data_requests as usize));
+    if cli.read_only() {
+        http_backend = http_backend.with_read_only();
+    }
+    if let Some(at) = cli.snapshot {
+        http_backend = http_backend.with_snapshot(at);
+    }
+    handshake(&mut http_backend);
+    http_backend.keep_session_alive();
+    let io = negotiate_io_sizes(cli, &mut http_backend);
+    (http_backend, io)
+}
+
+// login degli utenti di --mount-as: chi non si autentica non viene montato, gli altri mount proseguono
+#[cfg(unix)]
+fn extra_logins(cli: &Cli) -> Vec<(String, Credentials, String)> {
+    let mut logins = Vec::new();
+    for extra in &cli.extra_mounts {
+        match login(cli, Some(&extra.username)) {
+            Ok((credentials, sessionid)) => logins.push((extra.mount_point.clone(), credentials, sessionid)),
+            Err(e) => eprintln!("Error authenticating {}: {} (not mounting {})", extra.username, e, extra.mount_point),
+        }
+    }
+    logins
+}
+
+// funzionalità del server; se l'handshake fallisce il backend resta sulle funzionalità complete
+fn handshake(backend: &mut HttpBackend) {
+    match backend.handshake() {
+        Ok(caps) => {
+            if caps.version > PROTOCOL_VERSION {
+                println!("Server speaks protocol version {} (client: {}), newer features will not be used", caps.version, PROTOCOL_VERSION);
+            }
+            let missing: Vec<&str> = [("streams", caps.streams), ("links", caps.links), ("conditional requests", caps.conditional), ("paged listings", caps.pagination)]
+                .into_iter().filter(|(_, supported)| !supported).map(|(name, _)| name).collect();
+            if !missing.is_empty() {
+                println!("The server does not support {}: falling back where possible", missing.join(", "));
+            }
+        }
+        Err(e) => eprintln!("Cannot read server capabilities: {} (assuming a current server)", e),
+    }
+    // gruppi dell'utente per i controlli di accesso locali; senza, decide il server a ogni operazione
+    if let Err(e) = backend.load_identity() {
+        eprintln!("Cannot read the user's groups: {} (permission checks left to the server)", e);
+    }
+}
+
+// attributi della root del server, mostrati per il punto di mount anche se il server smette di rispondere
+fn read_root(backend: &mut HttpBackend) -> Option<FileEntry> {
+    backend.get_attr(ROOT_INO)
+        .inspect_err(|e| eprintln!("Cannot read the attributes of the remote root: {} (fetched again on first access)", e))
+        .ok()
+}
+
+// dimensioni richieste da riga di comando, ridotte ai limiti del server se li dichiara
+fn negotiate_io_sizes(cli: &Cli, backend: &mut HttpBackend) -> IoSizes {
+    let defaults = IoSizes::default();
+    let mut wanted = IoSizes {
+        block_size: cli.block_size.map_or(defaults.block_size, |b| b as usize),
+        large_file_size: cli.large_file_size.unwrap_or(defaults.large_file_size),
+    };
+    if !backend.capabilities().streams {
+        wanted = wanted.without_streams();
+    }
+    match backend.server_limits() {
+        Ok(Some(limits)) => wanted.negotiate(&limits),
+        Ok(None) => wanted,
+        Err(e) => {
+            eprintln!("Cannot read server limits: {} (using the requested sizes)", e);
+            wanted
+        }
+    }
+}
+
+// comandi verso il demone in esecuzione, tramite la socket di controllo
+// sessione salvata se ancora valida, altrimenti login interattivo (e salvataggio della nuova sessione)
+// con `extra_user` la sessione è quella di un mount aggiuntivo; come per --user, salvata a parte
+fn login(cli: &Cli, extra_user: Option<&str>) -> Result<(Credentials, String), LoginError> {
+    let remote_address = cli.address();
+    let user = extra_user.or(cli.user.as_deref());
+    let sessions = (!cli.no_saved_session).then(|| session_store(user));
+    if let Some(resumed) = sessions.as_ref().and_then(|store| session::resume(remote_address, store)) {
+        println!("Resumed the saved session. Welcome back!");
+        return Ok(resumed);
+    }
+    // la password di --password-stdin vale solo per l'utente principale
+    let password = if extra_user.is_none() { cli.password.clone() } else { None };
+    let (creds, session) = Credentials::authenticate_as(remote_address, user, password)?;
+    println!("Authentication successful. Welcome!");
+    let sid = session.sid.clone();
+    if let Some(store) = &sessions {
+        store.save(remote_address, session);
+    }
+    Ok((creds, sid))
+}
+
+fn session_store(user: Option<&str>) -> SessionStore {
+    let store = SessionStore::open(default_session_file());
+    match user {
+        Some(user) => store.for_user(user),
+        None => store,
+    }
+}
+
+// una riga di stdin, letta una sola volta all'avvio (prima del passaggio in background)
+fn read_stdin_password() -> Result<String, String> {
+    let mut line = String::new();
+    match std::io::stdin().read_line(&mut line) {
+        Ok(0) => Err("--password-stdin: no password on standard input".to_string()),
+        Ok(_) => Ok(line.trim_end_matches(['\r', '\n']).to_string()),
+        Err(e) => Err(format!("--password-stdin: cannot read standard input: {}", e)),
+    }
+}
+
+// gestione di utenti e gruppi tramite le API di amministrazione del server
+fn admin(cli: &Cli, target: AdminTarget) -> i32 {
+    let remote_address = cli.address();
+    let (credentials, sessionid) = match login(cli, None) {
+        Ok(creds) => creds,
+        Err(e) => {
+            eprintln!("Error authenticating: {}", e);
+            return StartupError::from(e).code();
+        }
+    };
+    let runtime = Arc::new(Builder::new_current_thread().enable_all().build().expect("Unable to build a Runtime object"));
+    let mut backend = HttpBackend::new(remote_address.to_string(), credentials, sessionid, runtime).expect("Cannot create the HTTP backend");
+    if let Err(e) = backend.handshake() {
+        eprintln!("Cannot read server capabilities: {}", e);
+        return 1;
+    }
+
+    let res = match target {
+        AdminTarget::User { action: UserAction::List } => backend.list_users().map(|users| {
+            for user in users {
+                let name = user.name.as_deref().unwrap_or("-");
+                match user.gid {
+                    Some(gid) => println!("{}\t{}\tgroup {}", user.uid, name, gid),
+                    None => println!("{}\t{}", user.uid, name),
+                }
+            }
+        }),
+        AdminTarget::User { action: UserAction::Add { uid, group } } => {
+            let password = match read_new_password() {
+                Ok(password) => password,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return 1;
+                }
+            };
+            backend.add_user(uid, &password, group).map(|_| println!("User {} created", uid))
+        }
+        AdminTarget::User { action: UserAction::Del { uid } } => backend.delete_user(uid).map(|_| println!("User {} deleted", uid)),
+        AdminTarget::User { action: UserAction::Name { uid, name } } => backend.set_user_name(uid, name.as_deref()).map(|_| match name {
+            Some(name) => println!("User {} is now named {}", uid, name),
+            None => println!("User {} has no name", uid),
+        }),
+        AdminTarget::Group { action: GroupAction::List } => backend.list_groups().map(|groups| {
+            for group in groups {
+                let members: Vec<String> = group.members.iter().map(u32::to_string).collect();
+                println!("{}\t{}\t{}", group.gid, group.name.as_deref().unwrap_or("-"), members.join(","));
+            }
+        }),
+        AdminTarget::Group { action: GroupAction::Add { gid } } => backend.add_group(gid).map(|_| println!("Group {} created", gid)),
+        AdminTarget::Group { action: GroupAction::Del { gid } } => backend.delete_group(gid).map(|_| println!("Group {} deleted", gid)),
+        AdminTarget::Group { action: GroupAction::Name { gid, name } } => backend.set_group_name(gid, name.as_deref()).map(|_| match name {
+            Some(name) => println!("Group {} is now named {}", gid, name),
+            None => println!("Group {} has no name", gid),
+        }),
+        AdminTarget::Group { action: GroupAction::AddUser { gid, uid } } => {
+            backend.set_group_member(gid, uid, true).map(|_| println!("User {} added to group {}", uid, gid))
+        }
+        AdminTarget::Group { action: GroupAction::DelUser { gid, uid } } => {
+            backend.set_group_member(gid, uid, false).map(|_| println!("User {} removed from group {}", uid, gid))
+        }
+    };
+    match res {
+        Ok(()) => 0,
+        Err(BackendError::Forbidden) => {
+            eprintln!("Only the server administrator can manage users and groups");
+            1
+        }
+        Err(e) => {
+            eprintln!("{}", e.for_user());
+            1
+        }
+    }
+}
+
+fn read_new_password() -> Result<String, String> {
+    use std::io::{stdout, Write};
+
+    print!("password for the new user: ");
+    stdout().flush().ok();
+    let password = rpassword::read_password().map_err(|e| format!("Failed to read password: {e}"))?;
+    println!();
+    print!("password (again): ");
+    stdout().flush().ok();
+    let again = rpassword::read_password().map_err(|e| format!("Failed to read password: {e}"))?;
+    println!();
+    if password != again {
+        return Err("The passwords do not match".to_string());
+    }
+    Ok(password)
+}
+
+// chiude la sessione salvata, sul server e su disco
+fn logout(cli: &Cli) -> i32 {
+    let remote_address = cli.address();
+    match session::logout(remote_address, &session_store(cli.user.as_deref())) {
+        Ok(true) => {
+            println!("Logged out from {}", remote_address);
+            0
+        }
+        Ok(false) => {
+            println!("No saved session for {}", remote_address);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+// cambio password: login con la password attuale, poi la nuova; la sessione salvata viene
+// sostituita da quella rigenerata dal server
+fn passwd(cli: &Cli) -> i32 {
+    let remote_address = cli.address();
+    let (mut credentials, session) = match Credentials::authenticate_as(remote_address, cli.user.as_deref(), cli.password.clone()) {
+        Ok(login) => login,
+        Err(e) => {
+            eprintln!("Error authenticating: {}", e);
+            return StartupError::from(e).code();
+        }
+    };
+    match credentials.change_password(remote_address, &session) {
+        Ok(renewed) => {
+            if !cli.no_saved_session {
+                session_store(cli.user.as_deref()).save(remote_address, renewed);
+            }
+            println!("Password changed. Other sessions, including running mounts, must log in again");
+            0
+        }
+        Err(e) => {
+            eprintln!("Unable to change the password: {}", e);
+            1
+        }
+    }
+}
+
+// ls, cp, rm e sync: richieste dirette al server, senza demone né mount
+fn direct(cli: &Cli, command: Command) -> i32 {
+    let remote_address = cli.address();
+    let (credentials, sessionid) = match login(cli, None) {
+        Ok(creds) => creds,
+        Err(e) => {
+            eprintln!("Error authenticating: {}", e);
+            return StartupError::from(e).code();
+        }
+    };
+    let runtime = Arc::new(Builder::new_multi_thread().enable_all().build().expect("Unable to build a Runtime object"));
+    let mut backend = HttpBackend::new(remote_address.to_string(), credentials, sessionid, runtime.clone()).expect("Cannot create the HTTP backend");
+    if let Err(e) = backend.handshake() {
+        eprintln!("Cannot read server capabilities: {}", e);
+        return 1;
+    }
+    let client = RfsClient::new(backend);
+    match command {
+        Command::Ls { path, long } => transfer::ls(&runtime, &client, &path, long),
+        Command::Cp { from, to, jobs, resume } => transfer::cp(&runtime, &client, &from, &to, jobs as usize, resume),
+        Command::Rm { path, recursive } => transfer::rm(&runtime, &client, &path, recursive),
+        Command::Sync { from, to, delete, dry_run, jobs } => sync::sync(&runtime, &client, &from, &to, sync::SyncOptions { delete, dry_run, jobs: jobs as usize }),
+        _ => unreachable!("not a direct command"),
+    }
+}
+
+// `check`: stato locale di un mount non attivo confrontato con il server
+#[cfg(unix)]
+fn check_state(cli: &Cli, repair: bool) -> i32 {
+    if std::os::unix::net::UnixStream::connect(control::socket_path(&cli.cache_dir, std::path::Path::new(&cli.mount_point))).is_ok() {
+        eprintln!("The daemon is running: unmount before checking its local state");
+        return 1;
+    }
+    let (credentials, sessionid) = match login(cli, None) {
+        Ok(creds) => creds,
+        Err(e) => {
+            eprintln!("Error authenticating: {}", e);
+            return StartupError::from(e).code();
+        }
+    };
+    let runtime = Arc::new(Builder::new_multi_thread().enable_all().build().expect("Unable to build a Runtime object"));
+    let mut backend = HttpBackend::new(cli.address().to_string(), credentials, sessionid, runtime).expect("Cannot create the HTTP backend");
+    if let Err(e) = backend.handshake() {
+        eprintln!("Cannot read server capabilities: {}", e);
+        return 1;
+    }
+    let key = match cache_key(cli) {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    match check::check(&mut backend, &journal_path(&cli.mount_point), Some(std::path::Path::new(&cli.cache_dir)), key.as_ref(), repair) {
+        Ok(report) => {
+            println!("{}", report.summary());
+            if report.problems > report.repaired { 1 } else { 0 }
+        }
+        Err(e) => {
+            eprintln!("Check interrupted: {}", e.for_user());
+            1
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn check_state(_cli: &Cli, _repair: bool) -> i32 {
+    eprintln!("The check command is not supported on Windows: there is no journal or pin cache to check");
+    1
+}
+
+#[cfg(unix)]
+fn run_command(cli: &Cli, command: Command) -> i32 {
+    use control::{ControlCmd, ControlRequest};
+    use rfs_models::SearchQuery;
+    use std::time::SystemTime;
+
+    let request = |cmd, path| ControlRequest { cmd, path, jobs: None, query: None, transfers: false, recursive: false };
+    let request = match command {
+        Command::Pin { path } => request(ControlCmd::Pin, path),
+        Command::Unpin { path } => request(ControlCmd::Unpin, path),
+        Command::Du { path } => request(ControlCmd::Du, path),
+        // il mount della cartella corrente (o l'unico attivo)
+        Command::Status { transfers } => ControlRequest { transfers, ..request(ControlCmd::Status, ".".to_string()) },
+        Command::FlushAll => request(ControlCmd::FlushAll, ".".to_string()),
+        Command::Warm { path, jobs } => ControlRequest { jobs: Some(jobs), ..request(ControlCmd::Warm, path) },
+        Command::Cache { action: CacheAction::Ls { path } } => request(ControlCmd::CacheLs, path),
+        Command::Cache { action: CacheAction::Stat { path } } => request(ControlCmd::CacheStat, path),
+        Command::Cache { action: CacheAction::Evict { path, recursive } } => ControlRequest { recursive, ..request(ControlCmd::CacheEvict, path) },
+        Command::Find { path, name, min_size, max_size, newer_than, older_than, limit } => {
+            let ago = |secs: u64| SystemTime::now().checked_sub(Duration::from_secs(secs));
+            let query = SearchQuery {
+                name,
+                min_size,
+                max_size,
+                modified_after: newer_than.and_then(ago),
+                modified_before: older_than.and_then(ago),
+                limit,
+            };
+            ControlRequest { query: Some(query), ..request(ControlCmd::Find, path) }
+        }
+        Command::Mount { .. } | Command::Logout | Command::Passwd | Command::Admin { .. } | Command::Check { .. } | Command::Bench { .. } | Command::Log { .. } | Command::Ls { .. } | Command::Cp { .. } | Command::Rm { .. } | Command::Sync { .. } => {
+            unreachable!("handled without the daemon")
+        }
+    };
+    match control::send(&cli.cache_dir, request) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn run_command(_cli: &Cli, _command: Command) -> i32 {
+    eprintln!("Pin, warm, du, find, status, cache and flush-all commands are not supported on Windows yet");
+    1
 }
 
 #[cfg(target_os = "linux")]
@@ -70,16 +1113,15 @@ fn demonize() -> Result<(), String>{
     use daemonize::Daemonize;
 
     const PID_FILE :&str = "/tmp/remote-fs.pid";
-    if std::path::Path::new(PID_FILE).exists() {
-        if let Ok(pid_content) = std::fs::read_to_string(PID_FILE) {
-            if let Ok(pid) = pid_content.trim().parse::<u32>() {
-                let proc_path = format!("/proc/{}", pid);
-                if std::path::Path::new(&proc_path).exists() {
-                    return Err(format!("Remote-FS daemon is already running with PID: {}\nTo stop it, run: kill {}", pid, pid));
-                } else {
-                    let _ = std::fs::remove_file(PID_FILE);
-                }
-            }
+    if std::path::Path::new(PID_FILE).exists()
+        && let Ok(pid_content) = std::fs::read_to_string(PID_FILE)
+        && let Ok(pid) = pid_content.trim().parse::<u32>()
+    {
+        let proc_path = format!("/proc/{}", pid);
+        if std::path::Path::new(&proc_path).exists() {
+            return Err(format!("Remote-FS daemon is already running with PID: {}\nTo stop it, run: kill {}", pid, pid));
+        } else {
+            let _ = std::fs::remove_file(PID_FILE);
         }
     }
 
@@ -96,44 +1138,250 @@ fn demonize() -> Result<(), String>{
     Ok(())
 }
 
+fn print_snapshot(cli: &Cli) {
+    if let Some(at) = cli.snapshot {
+        let secs = at.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        println!("Read-only snapshot at {} (Unix seconds)", secs);
+    }
+}
+
+// log di audit condiviso da tutti i mount del demone, se richiesto
+fn open_audit_log(cli: &Cli) -> Option<AuditLog> {
+    if !cli.audit_log {
+        return None;
+    }
+    match AuditLog::open(&cli.audit_file, cli.audit_log_size * 1024 * 1024) {
+        Ok(log) => {
+            println!("Audit log: {}", cli.audit_file);
+            Some(log)
+        }
+        Err(e) => {
+            eprintln!("Cannot open audit log {}: {} (continuing without it)", cli.audit_file, e);
+            None
+        }
+    }
+}
+
+fn junk_filter(cli: &Cli) -> JunkFilter {
+    let patterns = cli.junk_files.iter().filter(|p| !(cli.apple_double && p.as_str() == "._*"));
+    let mode = match cli.junk_mode {
+        JunkAction::Hide => JunkMode::Hide,
+        JunkAction::Block => JunkMode::Block,
+        JunkAction::Pass => JunkMode::Pass,
+    };
+    JunkFilter::new(patterns, mode)
+}
+
+fn create_modes(cli: &Cli) -> CreateModes {
+    CreateModes { file_mode: cli.file_mode, dir_mode: cli.dir_mode, umask: cli.umask }
+}
+
+// chiave della cache su disco (--encrypt-cache), nel portachiavi del sistema con una voce per cartella della
+// cache; creata al primo uso. None se la cache non va cifrata
+fn cache_key(cli: &Cli) -> Result<Option<rfs_cache::CacheKey>, String> {
+    if !cli.encrypt_cache {
+        return Ok(None);
+    }
+    let dir = std::path::absolute(&cli.cache_dir).unwrap_or_else(|_| std::path::PathBuf::from(&cli.cache_dir));
+    let entry = keyring::Entry::new(KEYRING_SERVICE, &dir.to_string_lossy())
+        .map_err(|e| format!("Cannot access the system keyring: {}", e))?;
+    match entry.get_password() {
+        Ok(hex) => rfs_cache::CacheKey::from_hex(hex.trim()).map(Some)
+            .ok_or_else(|| format!("The key of disk cache {} in the system keyring is not valid", dir.display())),
+        Err(keyring::Error::NoEntry) => {
+            let key = rfs_cache::CacheKey::generate();
+            entry.set_password(&key.to_hex()).map_err(|e| format!("Cannot save the disk cache key in the system keyring: {}", e))?;
+            Ok(Some(key))
+        }
+        Err(e) => Err(format!("Cannot read the disk cache key from the system keyring: {}", e)),
+    }
+}
+
+// liste delle directory di un server: gli ino sono del server, ogni indirizzo ha la sua cartella
 #[cfg(unix)]
-fn run_unix(cli: Cli, http_backend: HttpBackend, runtime: Arc<Runtime>){
-    use fuser::{MountOption,Session};
-    use std::fs::File;
-    use rfs_fuse::RemoteFS;
+fn listing_cache_dir(cache_dir: &std::path::Path, address: &str) -> std::path::PathBuf {
+    let server: String = address.trim_end_matches('/').chars().map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' { c } else { '_' }).collect();
+    cache_dir.join("listings").join(server)
+}
+
+// validità di attributi e voci: --attr-ttl e --entry-ttl, poi il file di configurazione, poi i default
+#[cfg(unix)]
+fn cache_ttl(cli: &Cli) -> rfs_fuse::CacheTtl {
+    use rfs_fuse::{CacheTtl, TtlRule};
+
+    let config = cli.config.ttl();
+    let defaults = CacheTtl::default();
+    let pick = |given: Option<u64>, configured: Option<u64>, default: Duration| given.or(configured).map_or(default, Duration::from_secs);
+    CacheTtl {
+        attr_file: pick(cli.attr_ttl, config.attr_file, defaults.attr_file),
+        attr_dir: pick(cli.attr_ttl, config.attr_dir, defaults.attr_dir),
+        entry_file: pick(cli.entry_ttl, config.entry_file, defaults.entry_file),
+        entry_dir: pick(cli.entry_ttl, config.entry_dir, defaults.entry_dir),
+        rules: config.path.iter().map(|rule| TtlRule {
+            prefix: rule.prefix.clone(),
+            attr: rule.attr.map(Duration::from_secs),
+            entry: rule.entry.map(Duration::from_secs),
+        }).collect(),
+    }
+}
+
+// recall listener dei lease: long-poll sul server, gli ino richiamati arrivano alla cache
+// gli ino richiamati vanno prima alla cache (primo receiver), poi alle notifiche verso le applicazioni (secondo)
+fn spawn_recall_listener(http_backend: &HttpBackend) -> (std::sync::mpsc::Receiver<u64>, std::sync::mpsc::Receiver<u64>) {
+    use rfs_models::BackendError;
+
+    let (recall_tx, recall_rx) = std::sync::mpsc::channel();
+    let (change_tx, change_rx) = std::sync::mpsc::channel();
+    let listener = http_backend.recall_listener();
+    // il server tiene ferma la modifica che ha causato il recall finché il lease non viene rilasciato
+    let mut acker = http_backend.fetcher();
+    std::thread::spawn(move || loop {
+        match listener.wait() {
+            Ok(inos) => {
+                for ino in inos {
+                    if recall_tx.send(ino).is_err() {
+                        return; // cache distrutta
+                    }
+                    let _ = change_tx.send(ino); // le notifiche sono facoltative
+                    if let Err(e) = acker.release_lease(ino) {
+                        eprintln!("Unable to release the recalled lease on ino {}: {}", ino, e);
+                    }
+                }
+            }
+            Err(BackendError::NotFound(_)) => return, // server senza supporto ai lease
+            Err(e) => {
+                eprintln!("Lease recall listener error: {}", e);
+                std::thread::sleep(Duration::from_secs(5));
+            }
+        }
+    });
+    (recall_rx, change_rx)
+}
+
+// stato per mount point in ~/.local/state/remote-fs/<prefix>-<mount point>
+#[cfg(unix)]
+fn state_path(prefix: &str, mount_point: &str) -> std::path::PathBuf {
+    let dir = match std::env::var("HOME") {
+        Ok(home) if !home.is_empty() => std::path::Path::new(&home).join(".local/state/remote-fs"),
+        _ => std::path::PathBuf::from("/tmp/remote-fs-state"),
+    };
+    let name: String = mount_point.trim_matches('/').chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    dir.join(format!("{}-{}", prefix, name))
+}
+
+#[cfg(unix)]
+fn journal_path(mount_point: &str) -> std::path::PathBuf {
+    state_path("journal", mount_point)
+}
+
+// esiste finché il mount è attivo: trovarlo al mount vuol dire che il demone precedente è terminato male
+#[cfg(unix)]
+fn mounted_marker(mount_point: &str) -> std::path::PathBuf {
+    state_path("mounted", mount_point)
+}
+
+#[cfg(unix)]
+type UnixSession = fuser::Session<rfs_fuse::RemoteFS<Arc<std::sync::Mutex<rfs_cache::Cache<HttpBackend>>>>>;
+
+// `extras` sono i mount aggiuntivi (--mount-as): (mount point, backend con la sessione dell'utente, dimensioni di I/O)
+#[cfg(unix)]
+fn run_unix(cli: Cli, http_backend: HttpBackend, extras: Vec<(String, HttpBackend, IoSizes)>, runtime: Arc<Runtime>, io: IoSizes){
     use signal_hook::consts::*;
     use signal_hook::iterator::Signals;
+    use std::panic::{AssertUnwindSafe, catch_unwind};
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, Ordering};
     use std::thread;
-    use rfs_cache::Cache;
 
-    let file_speed= if cli.speed_testing {
-        println!("Speed testing mode enabled. See /tmp/remote-fs.speed-test.out for details.");
-        Some(File::create("/tmp/remote-fs.speed-test.out").expect("Failed to create speed test log file"))
-    }else{
-        None
+    let audit = open_audit_log(&cli);
+    // per rimontare con lo stesso login se la sessione muore
+    let remount_base = http_backend.fetcher();
+    // mount_unix ha già spiegato perché il mount non è riuscito
+    let Some(mut session) = mount_unix(&cli, &cli.mount_point, http_backend, runtime.clone(), io, true, audit.clone()) else {
+        std::process::exit(startup::EXIT_MOUNT);
     };
-
-    let cache = Cache::new(http_backend, 256, 16, 64, 16); // 256 attr, 16 dir, 64 blocchi per file (da 16 Kb), 16 file
-    let fs = RemoteFS::new(cli.mount_point.clone(), cache, runtime.clone(), cli.speed_testing, file_speed);
-    let options = vec![MountOption::FSName("Remote-FS".to_string()), MountOption::RW];
-    let mut session= Session::new(fs, &cli.mount_point, &options).expect("failed to mount");
-
     println!("Remote-FS mounted on {}", cli.mount_point);
-    println!("Remote address: {}", cli.remote_address);
+    print_snapshot(&cli);
+    println!("Remote address: {}", cli.address());
+
+    // gli altri mount girano in background nello stesso processo, ognuno con la propria sessione
+    let unmounters = Arc::new(Mutex::new(vec![session.unmount_callable()]));
+    let mut mounted = vec![cli.mount_point.clone()];
+    let mut background = Vec::new();
+    for (mount_point, backend, io) in extras {
+        let user = backend.username().to_string();
+        let Some(mut extra) = mount_unix(&cli, &mount_point, backend, runtime.clone(), io, false, audit.clone()) else { continue };
+        unmounters.lock().expect("Mutex poisoned").push(extra.unmount_callable());
+        match extra.spawn() {
+            Ok(handle) => {
+                println!("Remote-FS mounted on {} as {}", mount_point, user);
+                mounted.push(mount_point);
+                background.push(handle);
+            }
+            Err(e) => eprintln!("Cannot start the mount on {}: {}", mount_point, e),
+        }
+    }
     println!("All set! Refer to /tmp/remote-fs.pid for killing the daemon.");
 
-    let mut signals = Signals::new(&[SIGINT, SIGTERM, SIGQUIT, SIGHUP]).expect("signals");
-    let mut unmounter = session.unmount_callable();
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGQUIT, SIGHUP]).expect("signals");
     let sig_handle = signals.handle();
-    let sig_thread = thread::spawn(move || {
-        for sig in &mut signals {
-            println!("Signal {} received: unmounting...", sig);
-            let _ = unmounter.unmount();
-            break;
+    let stopping = Arc::new(AtomicBool::new(false));
+    let sig_thread = {
+        let (unmounters, stopping) = (unmounters.clone(), stopping.clone());
+        thread::spawn(move || {
+            if let Some(sig) = signals.forever().next() {
+                println!("Signal {} received: unmounting...", sig);
+                stopping.store(true, Ordering::SeqCst);
+                for unmounter in unmounters.lock().expect("Mutex poisoned").iter_mut() {
+                    let _ = unmounter.unmount();
+                }
+            }
+        })
+    };
+
+    // blocca finché non viene smontato o c’è un errore; una sessione morta da sola viene rimontata
+    let mut session = Some(session);
+    let mut remounts = 0;
+    let mut died = false;
+    let run_res = loop {
+        let current = session.as_mut().expect("session is mounted");
+        let res = catch_unwind(AssertUnwindSafe(|| current.run()))
+            .unwrap_or_else(|_| Err(std::io::Error::other("panic in a filesystem callback")));
+        if stopping.load(Ordering::SeqCst) || (res.is_ok() && !watchdog::is_zombie(&cli.mount_point)) {
+            break res; // smontaggio richiesto, o fatto dall'esterno
         }
-    });
+        died = true;
+        let err = res.err().unwrap_or_else(|| std::io::Error::other("mount point no longer connected"));
+        eprintln!("Session on {} died: {}", cli.mount_point, err);
+        drop(session.take());
+        if !watchdog::release(&cli.mount_point) {
+            eprintln!("Cannot release {}: unmount it by hand (fusermount -uz)", cli.mount_point);
+            break Err(err);
+        }
+        let mut next = None;
+        while next.is_none() && remounts < cli.auto_remount && !stopping.load(Ordering::SeqCst) {
+            thread::sleep(watchdog::backoff(remounts));
+            remounts += 1;
+            println!("Remounting {} (attempt {}/{})", cli.mount_point, remounts, cli.auto_remount);
+            next = mount_unix(&cli, &cli.mount_point, remount_base.fetcher(), runtime.clone(), io, true, audit.clone());
+        }
+        let Some(mut next) = next else { break Err(err) };
+        unmounters.lock().expect("Mutex poisoned")[0] = next.unmount_callable();
+        // segnale arrivato durante il rimontaggio
+        if stopping.load(Ordering::SeqCst) {
+            let _ = unmounters.lock().expect("Mutex poisoned")[0].unmount();
+        }
+        println!("Remote-FS remounted on {}", cli.mount_point);
+        died = false;
+        session = Some(next);
+    };
 
-    let run_res = session.run(); // blocca finché non viene smontato o c’è un errore
+    // il demone vive quanto il mount principale: smontato quello, si chiudono anche gli altri
+    for handle in background {
+        handle.join();
+    }
 
     // Sveglia/chiudi il listener segnali e attendi che termini
     if !sig_handle.is_closed() {
@@ -141,32 +1389,356 @@ fn run_unix(cli: Cli, http_backend: HttpBackend, runtime: Arc<Runtime>){
     }
     sig_thread.join().expect("error joining signal thread");
 
+    // smontaggio pulito: al prossimo mount non serve il controllo dello stato locale
+    for mount_point in mounted {
+        if died && mount_point == cli.mount_point {
+            continue;
+        }
+        let _ = std::fs::remove_file(mounted_marker(&mount_point));
+    }
+
     match run_res {
         Ok(()) => println!("Remote-FS closed successfully."),
         Err(e) => eprintln!("Remote-FS terminated with error: {e}")
     }
 }
 
+// monta `http_backend` su `mount_point`; socket di controllo, pin e speed testing solo per il mount principale
+#[cfg(unix)]
+fn mount_unix(cli: &Cli, mount_point: &str, mut http_backend: HttpBackend, runtime: Arc<Runtime>, io: IoSizes, primary: bool, audit: Option<AuditLog>) -> Option<UnixSession> {
+    use fuser::{MountOption,Session};
+    use std::fs::File;
+    use rfs_fuse::{DirectIoRule, FlushBackend, FlushBackends, FlushBarrier, FsOptions, KernelTuning, RemoteFS, Transfers, WriteJournal, spawn_attr_refresher, spawn_change_notifier, spawn_open_keeper};
+    use rfs_cache::{Cache, ListingStore, PinStore};
+    use std::sync::Mutex;
+
+    let file_speed= if cli.speed_testing && primary {
+        println!("Speed testing mode enabled. See /tmp/remote-fs.speed-test.out for details.");
+        Some(File::create("/tmp/remote-fs.speed-test.out").expect("Failed to create speed test log file"))
+    }else{
+        None
+    };
+
+    #[cfg(target_os = "linux")]
+    if let Err(e) = std::fs::create_dir_all(mount_point) {
+        eprintln!("Cannot create mount point {}: {}", mount_point, e);
+        return None;
+    }
+
+    // uno snapshot non cambia: niente lease, notifiche di modifica, pin né journal
+    let snapshot = cli.snapshot.is_some();
+    let (recall_rx, change_rx) = if snapshot { (None, None) } else {
+        let (recall_rx, change_rx) = spawn_recall_listener(&http_backend);
+        (Some(recall_rx), Some(change_rx))
+    };
+    let fetch_base = http_backend.fetcher();
+    let flush_base = http_backend.fetcher();
+    let owner_ids = cli.owner_names.then(|| owner_ids(&http_backend));
+    // uno snapshot non viene cancellato
+    let open_keeper = (!snapshot && http_backend.capabilities().open_handles).then(|| http_backend.fetcher());
+    let refresh_backend = cli.attr_refresh.filter(|_| !snapshot).map(|secs| (http_backend.fetcher(), secs));
+    let identity = http_backend.identity().cloned();
+    let root = read_root(&mut http_backend);
+    let cache_dir = std::path::Path::new(&cli.cache_dir);
+    // senza la chiave di una cache cifrata i pin e le liste salvate restano inutilizzati
+    let cache_key = if primary && !snapshot {
+        cache_key(cli).map_err(|e| eprintln!("{} (disk cache disabled)", e)).ok()
+    } else {
+        None
+    };
+    if !snapshot && mounted_marker(mount_point).exists() {
+        println!("{} was not unmounted cleanly: checking local state", mount_point);
+        let key = cache_key.clone().flatten();
+        match check::check(&mut http_backend, &journal_path(mount_point), cache_key.is_some().then_some(cache_dir), key.as_ref(), true) {
+            Ok(report) => println!("{}", report.summary()),
+            Err(e) => eprintln!("Unable to check local state: {}", e),
+        }
+    }
+    let dir_sizes = http_backend.capabilities().dir_sizes;
+    let remote_locks = http_backend.capabilities().locks && !snapshot;
+    let mut cache = Cache::new(http_backend, 256, 16, 64, 16).with_block_size(io.block_size).with_dir_sizes(dir_sizes); // 256 attr, 16 dir, 64 blocchi per file, 16 file
+    if let Some(recall_rx) = recall_rx {
+        cache = cache.with_recalls(recall_rx);
+    }
+    if let Some(key) = cache_key {
+        match PinStore::open(cache_dir.join("pinned"), key.clone()) {
+            Ok(store) => cache = cache.with_pin_store(store),
+            Err(e) => eprintln!("Cannot open disk cache {}: {} (pinning disabled)", cache_dir.display(), e),
+        }
+        if !cli.no_listing_cache {
+            let dir = listing_cache_dir(cache_dir, cli.address());
+            match ListingStore::open(&dir, key) {
+                Ok(store) => cache = cache.with_listing_store(store),
+                Err(e) => eprintln!("Cannot open listing cache {}: {} (listings are not saved)", dir.display(), e),
+            }
+        }
+    }
+    let cache = Arc::new(Mutex::new(cache));
+
+    // socket di controllo per pin/unpin/warm e aggiornamento in background dei pin salvati
+    let transfers = Transfers::default();
+    let barrier = FlushBarrier::default();
+    if primary {
+        let pins = Arc::new(control::PinList::open(cache_dir.join("pins.list")));
+        let fetcher: control::Fetcher<HttpBackend> = Arc::new(move || fetch_base.fetcher());
+        let socket = control::socket_path(&cli.cache_dir, std::path::Path::new(mount_point));
+        if let Err(e) = control::serve(cache.clone(), fetcher, &socket, mount_point.to_string(), pins.clone(), transfers.clone(), barrier.clone()) {
+            eprintln!("Cannot open control socket {}: {}", socket.display(), e);
+        }
+        if !snapshot {
+            control::repin_saved(cache.clone(), pins);
+        }
+    }
+
+    let read_only = snapshot || cli.read_only();
+    let mut options = vec![MountOption::FSName("Remote-FS".to_string()), if read_only { MountOption::RO } else { MountOption::RW }];
+    let default_permissions = cli.default_permissions && identity.is_some();
+    if cli.default_permissions && !default_permissions {
+        eprintln!("User groups unknown: permission checks left to the server instead of the kernel");
+    }
+    if default_permissions {
+        options.push(MountOption::DefaultPermissions);
+    }
+    if cfg!(target_os = "macos") {
+        // opzioni specifiche di macFUSE
+        options.push(MountOption::CUSTOM(format!("volname={}", cli.volname)));
+        if cli.local {
+            options.push(MountOption::CUSTOM("local".to_string()));
+        }
+        if !cli.apple_double {
+            options.push(MountOption::CUSTOM("noappledouble".to_string()));
+        }
+        if cli.no_apple_xattr {
+            options.push(MountOption::CUSTOM("noapplexattr".to_string()));
+        }
+    }
+    let journal = if cli.no_journal || read_only {
+        None
+    } else {
+        let path = journal_path(mount_point);
+        match WriteJournal::open(&path) {
+            Ok(journal) => Some(journal),
+            Err(e) => {
+                eprintln!("Cannot open write journal {}: {} (continuing without it)", path.display(), e);
+                None
+            }
+        }
+    };
+
+    let defaults = KernelTuning::default();
+    let kernel = KernelTuning {
+        writeback_cache: !cli.no_writeback_cache,
+        max_write: cli.max_write.unwrap_or(defaults.max_write),
+        max_readahead: cli.max_readahead.unwrap_or(defaults.max_readahead),
+        max_background: cli.max_background.unwrap_or(defaults.max_background),
+    };
+    let fs_options = FsOptions {
+        speed_testing: cli.speed_testing && primary,
+        speed_file: file_speed,
+        junk: junk_filter(cli), // i `._*` anche lato fuse, nel caso macFUSE li lasci passare
+        shutdown_timeout: Duration::from_secs(cli.shutdown_timeout),
+        journal,
+        kernel,
+        io,
+        identity,
+        default_permissions,
+        create_modes: create_modes(cli),
+        transfers,
+        barrier,
+        flush_jobs: cli.flush_jobs as usize,
+        workers: cli.fuse_workers as usize,
+        stream_after: cli.stream_after * 1024 * 1024,
+        flush_backends: Some(Arc::new(move || Box::new(flush_base.fetcher()) as FlushBackend) as FlushBackends),
+        cache_backend: Some({
+            let cache = cache.clone();
+            Arc::new(move || Box::new(cache.clone()) as FlushBackend) as FlushBackends
+        }),
+        root,
+        audit,
+        policy: cli.config.policy(),
+        trust_cache: cli.trust_cache,
+        dirty_limit: cli.dirty_limit * 1024 * 1024,
+        batch_latency: cli.batch_latency.map(Duration::from_millis),
+        metadata_only: cli.metadata_only,
+        ttl: cache_ttl(cli),
+        direct_io: cli.config.direct_io().iter().map(|rule| DirectIoRule { prefix: rule.prefix.clone(), direct: rule.direct }).collect(),
+        owner_ids,
+        remote_locks,
+    };
+    let fs = RemoteFS::new(mount_point.to_string(), cache.clone(), runtime, fs_options);
+    let open_inodes = fs.open_inodes();
+    if let Some(keeper) = open_keeper {
+        spawn_open_keeper(keeper, open_inodes.clone());
+    }
+    let listings = fs.dir_listings();
+    let session = match Session::new(fs, mount_point, &options) {
+        Ok(session) => session,
+        Err(e) => {
+            eprintln!("Failed to mount {}: {}", mount_point, e);
+            return None;
+        }
+    };
+    if !snapshot {
+        let marker = mounted_marker(mount_point);
+        let res = marker.parent().map_or(Ok(()), std::fs::create_dir_all).and_then(|_| std::fs::write(&marker, b""));
+        if let Err(e) = res {
+            eprintln!("Cannot create {}: {} (an unclean shutdown will not be detected)", marker.display(), e);
+        }
+    }
+    if let Some(change_rx) = change_rx {
+        spawn_change_notifier(cache.clone(), change_rx, listings, session.notifier());
+    }
+    if let Some((server, secs)) = refresh_backend {
+        spawn_attr_refresher(server, cache, open_inodes, session.notifier(), Duration::from_secs(secs));
+    }
+    Some(session)
+}
+
+// ogni quanto rileggere i nomi di utenti e gruppi del server con --owner-names
+#[cfg(unix)]
+const OWNER_NAMES_REFRESH: Duration = Duration::from_secs(600);
+
+// corrispondenze fra utenti e gruppi del server e quelli locali, tenute aggiornate finché il mount le usa
+#[cfg(unix)]
+fn owner_ids(http_backend: &HttpBackend) -> Arc<std::sync::RwLock<rfs_fuse::OwnerIds>> {
+    use rfs_fuse::OwnerIds;
+
+    let mut backend = http_backend.fetcher();
+    let load = |backend: &mut HttpBackend| match backend.owner_names() {
+        Ok(names) => Some(OwnerIds::from_names(&names)),
+        Err(e) => {
+            eprintln!("Cannot read the server's user and group names: {} (showing numeric owners)", e);
+            None
+        }
+    };
+    let ids = load(&mut backend).unwrap_or_default();
+    let (users, groups) = ids.mapped();
+    println!("Owner names: {} user(s) and {} group(s) match local accounts", users, groups);
+    let ids = Arc::new(std::sync::RwLock::new(ids));
+    let weak = Arc::downgrade(&ids);
+    let spawned = std::thread::Builder::new().name("rfs-owners".to_string()).spawn(move || loop {
+        std::thread::sleep(OWNER_NAMES_REFRESH);
+        let Some(ids) = weak.upgrade() else { return }; // smontato
+        if let Some(fresh) = load(&mut backend) {
+            *ids.write().expect("RwLock poisoned") = fresh;
+        }
+    });
+    if let Err(e) = spawned {
+        eprintln!("Cannot start the owner names refresher: {}", e);
+    }
+    ids
+}
+
 #[cfg(target_os = "windows")]
-fn run_windows(cli: Cli, http_backend: HttpBackend, runtime: Arc<Runtime>) {
-    use rfs_winfsp::RemoteFS;
+fn run_windows(cli: Cli, mut http_backend: HttpBackend, runtime: Arc<Runtime>, io: IoSizes) {
+    use rfs_cache::{Cache, PinStore};
+    use rfs_winfsp::{ChangeBatch, ExecPolicy, NOTIFY_INTERVAL_MS, RemoteFS};
     use std::sync::{Arc, Condvar, Mutex};
     use winfsp::host::{FileSystemHost, VolumeParams};
 
-    let fs = RemoteFS::new(http_backend, runtime.clone());
+    // WinFsp concede gli oplock nel driver kernel, senza callback verso il filesystem: i dati restano in
+    // cache (la nostra e quella del kernel) finché il server non richiama il lease sul file, e il recall
+    // li rompe tutte e due, la nostra subito e quella del kernel con la notifica di modifica (vedi
+    // RemoteFS::with_change_notifications). Uno snapshot non cambia: niente lease né notifiche
+    let snapshot = cli.snapshot.is_some();
+    let (recall_rx, change_rx) = if snapshot { (None, None) } else {
+        let (recall_rx, change_rx) = spawn_recall_listener(&http_backend);
+        (Some(recall_rx), Some(change_rx))
+    };
+    // i lock di Windows sono obbligatori: anche quelli presi dagli altri client fermano le write
+    // (vedi rfs-winfsp), quelli presi qui restano nel driver di WinFsp
+    if http_backend.capabilities().locks && !snapshot {
+        http_backend = http_backend.with_mandatory_locks();
+    }
+    let fetch_base = http_backend.fetcher();
+    let refresh_backend = http_backend.fetcher();
+    let identity = http_backend.identity().cloned();
+    let root = read_root(&mut http_backend);
+    let dir_sizes = http_backend.capabilities().dir_sizes;
+    let mut cache = Cache::new(http_backend, 256, 16, 64, 16).with_block_size(io.block_size).with_dir_sizes(dir_sizes); // 256 attr, 16 dir, 64 blocchi per file, 16 file
+    if let Some(recall_rx) = recall_rx {
+        cache = cache.with_recalls(recall_rx);
+    }
+    let cache_dir = std::path::Path::new(&cli.cache_dir);
+    let mut on_demand = cli.files_on_demand && !snapshot;
+    let key = if on_demand {
+        cache_key(&cli).unwrap_or_else(|e| {
+            eprintln!("{} (files on demand disabled)", e);
+            on_demand = false;
+            None
+        })
+    } else {
+        None
+    };
+    if on_demand {
+        match PinStore::open(cache_dir.join("pinned"), key) {
+            Ok(store) => cache = cache.with_pin_store(store),
+            Err(e) => {
+                eprintln!("Cannot open disk cache {}: {} (files on demand disabled)", cache_dir.display(), e);
+                on_demand = false;
+            }
+        }
+    }
+    let exec_policy = ExecPolicy {
+        by_extension: matches!(cli.exec_detect, ExecDetect::Extension | ExecDetect::All),
+        by_shebang: matches!(cli.exec_detect, ExecDetect::Shebang | ExecDetect::All),
+    };
+    let mut fs = RemoteFS::new(cache, runtime.clone())
+        .with_exec_policy(exec_policy)
+        .with_create_modes(create_modes(&cli))
+        .with_junk_filter(junk_filter(&cli))
+        .with_policy(cli.config.policy())
+        .with_io_sizes(io)
+        .with_data_backends(move || Box::new(fetch_base.fetcher()));
+    if let Some(change_rx) = change_rx {
+        fs = fs.with_change_notifications(change_rx);
+    }
+    if let Some(identity) = identity {
+        fs = fs.with_identity(identity);
+    }
+    if let Some(root) = root {
+        fs = fs.with_root(root);
+    }
+    if let Some(audit) = open_audit_log(&cli) {
+        fs = fs.with_audit_log(audit, &cli.mount_point);
+    }
+    if on_demand {
+        fs = fs.with_hydration();
+    }
+    if cli.speed_testing {
+        let path = std::env::temp_dir().join("remote-fs.speed-test.out");
+        match std::fs::File::create(&path) {
+            Ok(file) => {
+                println!("Speed testing mode enabled. See {} for details.", path.display());
+                fs = fs.with_speed_log(file);
+            }
+            Err(e) => eprintln!("Cannot create speed test log file {}: {}", path.display(), e),
+        }
+    }
+    let drain = fs.drain_handle();
+    if let Some(secs) = cli.attr_refresh.filter(|_| !snapshot) {
+        fs.attr_refresher(refresh_backend).spawn(Duration::from_secs(secs));
+    }
 
     let mut vp = VolumeParams::default();
     vp.case_preserved_names(true);
     vp.case_sensitive_search(true);
     vp.unicode_on_disk(true);
     vp.reparse_points(true);
+    // le richieste di una sola voce arrivano a get_dir_info_by_name invece che a read_directory
+    vp.pass_query_directory_filename(true);
+    vp.read_only_volume(snapshot || cli.read_only());
 
-    let mut host = FileSystemHost::new(vp, fs).expect("Unable to create a FileSystemHost");
+    // il timer fa controllare periodicamente a WinFsp se ci sono modifiche remote da notificare
+    let mut host = FileSystemHost::new_with_timer::<ChangeBatch, NOTIFY_INTERVAL_MS>(vp, fs).expect("Unable to create a FileSystemHost");
 
-    host.mount(&cli.mount_point).expect("Unable to mount the filesystem");
+    if let Err(e) = host.mount(&cli.mount_point) {
+        StartupError::Mount(format!("{}: {:?}", cli.mount_point, e)).exit();
+    }
 
     println!("Remote-FS mounted on {}", cli.mount_point);
-    println!("Remote address: {}", cli.remote_address);
+    print_snapshot(&cli);
+    println!("Remote address: {}", cli.address());
     println!("All set! Press Ctrl+C to unmount and exit.");
 
     // Coordinazione della terminazione senza busy-wait
@@ -189,7 +1761,23 @@ fn run_windows(cli: Cli, http_backend: HttpBackend, runtime: Arc<Runtime>) {
     }
 
     println!("\nSignal received, unmounting Remote-FS...");
-    host.stop();
+    host.stop(); // nessuna nuova operazione, quelle in corso sono terminate
+
+    // prima di smontare svuota le scritture rimaste in buffer
+    let pending = drain.pending();
+    if pending > 0 {
+        println!("Flushing {} dirty file handle(s) before unmount...", pending);
+        let failed = drain.drain(Duration::from_secs(cli.shutdown_timeout));
+        if failed.is_empty() {
+            println!("All dirty data flushed.");
+        } else {
+            eprintln!("Unable to flush {} file handle(s), their pending writes are lost:", failed.len());
+            for (fh, path, e) in failed.iter() {
+                eprintln!("  fh {} ({}): {}", fh, path, e);
+            }
+        }
+    }
+
     host.unmount();
     println!("Remote-FS unmounted correctly");
 }
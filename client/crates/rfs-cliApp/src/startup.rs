@@ -0,0 +1,101 @@
+// Controlli fatti prima del login e del passaggio in background, ed errori del mount con il loro codice di
+// uscita: chi lancia il client da uno script distingue un indirizzo o un mount point sbagliati, le credenziali
+// rifiutate, il server irraggiungibile e un mount fallito senza leggere i messaggi.
+
+use rfs_api::LoginError;
+use std::fmt;
+
+/// Indirizzo, mount point o configurazione non validi
+pub const EXIT_INVALID: i32 = 2;
+/// Credenziali rifiutate dal server
+pub const EXIT_AUTH: i32 = 3;
+/// Server irraggiungibile
+pub const EXIT_UNREACHABLE: i32 = 4;
+/// Il filesystem non è stato montato
+pub const EXIT_MOUNT: i32 = 5;
+
+#[derive(Debug)]
+pub enum StartupError {
+    Invalid(String),
+    Auth(String),
+    Unreachable(String),
+    Mount(String),
+}
+
+impl StartupError {
+    pub fn code(&self) -> i32 {
+        match self {
+            StartupError::Invalid(_) => EXIT_INVALID,
+            StartupError::Auth(_) => EXIT_AUTH,
+            StartupError::Unreachable(_) => EXIT_UNREACHABLE,
+            StartupError::Mount(_) => EXIT_MOUNT,
+        }
+    }
+
+    /// Stampa l'errore e termina il processo con il codice corrispondente
+    pub fn exit(self) -> ! {
+        eprintln!("{}", self);
+        eprintln!("Exiting...");
+        std::process::exit(self.code())
+    }
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartupError::Invalid(msg) => f.write_str(msg),
+            StartupError::Auth(msg) => write!(f, "Authentication failed: {}", msg),
+            StartupError::Unreachable(msg) => write!(f, "Server unreachable: {}", msg),
+            StartupError::Mount(msg) => write!(f, "Mount failed: {}", msg),
+        }
+    }
+}
+
+impl From<LoginError> for StartupError {
+    fn from(e: LoginError) -> Self {
+        match e {
+            LoginError::InvalidAddress(msg) => StartupError::Invalid(msg),
+            LoginError::Unreachable(msg) => StartupError::Unreachable(msg),
+            LoginError::Rejected(msg) | LoginError::Locked(msg) | LoginError::Other(msg) => StartupError::Auth(msg),
+        }
+    }
+}
+
+pub fn check_address(address: &str) -> Result<(), StartupError> {
+    rfs_api::parse_address(address).map(|_| ()).map_err(StartupError::Invalid)
+}
+
+/// Su Unix il mount point deve essere una directory esistente
+#[cfg(unix)]
+pub fn check_mount_point(mount_point: &str) -> Result<(), StartupError> {
+    match std::fs::metadata(mount_point) {
+        Ok(meta) if meta.is_dir() => Ok(()),
+        Ok(_) => Err(StartupError::Invalid(format!("Mount point {} is not a directory", mount_point))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(StartupError::Invalid(format!("Mount point {} does not exist", mount_point))),
+        Err(e) => Err(StartupError::Invalid(format!("Cannot access mount point {}: {}", mount_point, e))),
+    }
+}
+
+/// Su Windows il mount point è una lettera di unità libera (`X:`) o una directory ancora da creare,
+/// dentro una directory esistente
+#[cfg(target_os = "windows")]
+pub fn check_mount_point(mount_point: &str) -> Result<(), StartupError> {
+    let path = std::path::Path::new(mount_point);
+    let is_drive = mount_point.len() == 2 && mount_point.ends_with(':') && mount_point.as_bytes()[0].is_ascii_alphabetic();
+    if is_drive {
+        return if path.join("\\").exists() {
+            Err(StartupError::Invalid(format!("Drive {} is already in use", mount_point)))
+        } else {
+            Ok(())
+        };
+    }
+    if path.exists() {
+        return Err(StartupError::Invalid(format!("Mount point {} already exists: WinFsp creates it when mounting", mount_point)));
+    }
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && !parent.is_dir() => {
+            Err(StartupError::Invalid(format!("Parent directory of mount point {} does not exist", mount_point)))
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,247 @@
+// Sottocomando `sync`: sincronizzazione a senso unico tra una directory locale e un sottoalbero remoto
+// (in una delle due direzioni, come cp), per chi preferisce sincronizzare periodicamente invece di montare.
+// Un file viene copiato se manca nella destinazione o se dimensione o data di modifica (al secondo) sono
+// diverse; dopo la copia la destinazione prende la data di modifica della sorgente, così al giro successivo
+// risulta aggiornato. Con --delete le voci che non esistono più nella sorgente vengono cancellate.
+
+use crate::transfer::{REMOTE_PREFIX, remote_path};
+use rfs_api::RfsClient;
+use rfs_models::{BackendError, EntryType};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::runtime::Runtime;
+use tokio::task::JoinSet;
+
+pub struct SyncOptions {
+    /// cancella dalla destinazione quello che non c'è nella sorgente
+    pub delete: bool,
+    /// mostra le operazioni senza eseguirle
+    pub dry_run: bool,
+    /// file copiati in parallelo
+    pub jobs: usize,
+}
+
+// voce di uno dei due alberi; la chiave è il path relativo alla radice, con '/' come separatore
+#[derive(Debug, Clone, Copy)]
+struct Item {
+    dir: bool,
+    size: u64,
+    mtime: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Upload,
+    Download,
+}
+
+#[derive(Default)]
+struct Plan {
+    delete: Vec<String>,
+    mkdir: Vec<String>,
+    copy: Vec<(String, SystemTime)>,
+    up_to_date: usize,
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> BackendError {
+    BackendError::Other(format!("{}: {}", path.display(), e))
+}
+
+fn secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn remote_join(root: &str, rel: &str) -> String {
+    if rel.is_empty() { root.to_string() } else { format!("{}/{}", root.trim_end_matches('/'), rel) }
+}
+
+fn local_join(root: &Path, rel: &str) -> PathBuf {
+    rel.split('/').filter(|c| !c.is_empty()).fold(root.to_path_buf(), |path, c| path.join(c))
+}
+
+fn child_rel(dir: &str, name: &str) -> String {
+    if dir.is_empty() { name.to_string() } else { format!("{}/{}", dir, name) }
+}
+
+// albero locale; i link simbolici non vengono seguiti né copiati
+fn local_tree(root: &Path) -> Result<BTreeMap<String, Item>, BackendError> {
+    let mut tree = BTreeMap::new();
+    if !root.exists() {
+        return Ok(tree);
+    }
+    let mut stack = vec![String::new()];
+    while let Some(dir) = stack.pop() {
+        let path = local_join(root, &dir);
+        for child in fs::read_dir(&path).map_err(|e| io_error(&path, e))? {
+            let child = child.map_err(|e| io_error(&path, e))?;
+            let meta = fs::symlink_metadata(child.path()).map_err(|e| io_error(&child.path(), e))?;
+            if meta.file_type().is_symlink() {
+                continue;
+            }
+            let rel = child_rel(&dir, &child.file_name().to_string_lossy());
+            let mtime = meta.modified().unwrap_or(UNIX_EPOCH);
+            if meta.is_dir() {
+                stack.push(rel.clone());
+            }
+            tree.insert(rel, Item { dir: meta.is_dir(), size: meta.len(), mtime });
+        }
+    }
+    Ok(tree)
+}
+
+// albero remoto, visitato una directory alla volta; una radice che non esiste è un albero vuoto
+async fn remote_tree(client: &RfsClient, root: &str) -> Result<BTreeMap<String, Item>, BackendError> {
+    let mut tree = BTreeMap::new();
+    match client.stat(root).await {
+        Ok(entry) if entry.kind == EntryType::Directory => {}
+        Ok(_) => return Err(BackendError::NotADirectory(format!("{}{}", REMOTE_PREFIX, root))),
+        Err(BackendError::NotFound(_)) => return Ok(tree),
+        Err(e) => return Err(e),
+    }
+    let mut stack = vec![String::new()];
+    while let Some(dir) = stack.pop() {
+        for entry in client.list(&remote_join(root, &dir)).await? {
+            if entry.kind == EntryType::Symlink {
+                continue;
+            }
+            let rel = child_rel(&dir, &entry.name);
+            let dir = entry.kind == EntryType::Directory;
+            if dir {
+                stack.push(rel.clone());
+            }
+            tree.insert(rel, Item { dir, size: entry.size, mtime: entry.mtime });
+        }
+    }
+    Ok(tree)
+}
+
+fn plan(src: &BTreeMap<String, Item>, dst: &BTreeMap<String, Item>, delete: bool) -> Plan {
+    let mut plan = Plan::default();
+    for (rel, item) in dst {
+        let replaced = src.get(rel).is_some_and(|s| s.dir != item.dir);
+        if !src.contains_key(rel) || replaced {
+            // una directory cancellata porta con sé il suo contenuto
+            if !delete || plan.delete.iter().any(|d| rel.starts_with(&format!("{}/", d))) {
+                continue;
+            }
+            plan.delete.push(rel.clone());
+        }
+    }
+    for (rel, item) in src {
+        match dst.get(rel) {
+            Some(d) if d.dir != item.dir && !delete => {
+                eprintln!("Skipping {}: a directory on one side and a file on the other (use --delete to replace it)", rel);
+            }
+            Some(d) if d.dir == item.dir && (item.dir || (d.size == item.size && secs(d.mtime) == secs(item.mtime))) => plan.up_to_date += 1,
+            _ if item.dir => plan.mkdir.push(rel.clone()),
+            _ => plan.copy.push((rel.clone(), item.mtime)),
+        }
+    }
+    plan
+}
+
+async fn copy_one(client: RfsClient, direction: Direction, local: PathBuf, remote: String, mtime: SystemTime) -> Result<(), BackendError> {
+    match direction {
+        Direction::Upload => {
+            client.upload(local, &remote).await?;
+            client.set_mtime(&remote, mtime).await?;
+        }
+        Direction::Download => {
+            client.download(&remote, local.clone()).await?;
+            let file = fs::File::options().write(true).open(&local).map_err(|e| io_error(&local, e))?;
+            file.set_modified(mtime).map_err(|e| io_error(&local, e))?;
+        }
+    }
+    Ok(())
+}
+
+async fn run(client: &RfsClient, direction: Direction, local: &Path, remote: &str, options: &SyncOptions) -> Result<(), BackendError> {
+    let local_items = local_tree(local)?;
+    let remote_items = remote_tree(client, remote).await?;
+    let (src, dst) = match direction {
+        Direction::Upload => (&local_items, &remote_items),
+        Direction::Download => (&remote_items, &local_items),
+    };
+    let plan = plan(src, dst, options.delete);
+
+    if options.dry_run {
+        plan.delete.iter().for_each(|rel| println!("would delete {}", rel));
+        plan.mkdir.iter().for_each(|rel| println!("would create {}/", rel));
+        plan.copy.iter().for_each(|(rel, _)| println!("would copy {}", rel));
+        println!("{} to copy, {} to delete, {} up to date", plan.copy.len(), plan.delete.len(), plan.up_to_date);
+        return Ok(());
+    }
+
+    // la radice della destinazione può non esistere ancora
+    match direction {
+        Direction::Upload if remote_items.is_empty() && client.stat(remote).await.is_err() => { client.mkdir(remote).await?; }
+        Direction::Download => fs::create_dir_all(local).map_err(|e| io_error(local, e))?,
+        _ => {}
+    }
+    for rel in &plan.delete {
+        println!("deleting {}", rel);
+        match direction {
+            Direction::Upload => client.remove(&remote_join(remote, rel), true).await?,
+            Direction::Download => {
+                let path = local_join(local, rel);
+                let res = if dst[rel].dir { fs::remove_dir_all(&path) } else { fs::remove_file(&path) };
+                res.map_err(|e| io_error(&path, e))?;
+            }
+        }
+    }
+    for rel in &plan.mkdir {
+        match direction {
+            Direction::Upload => { client.mkdir(&remote_join(remote, rel)).await?; }
+            Direction::Download => {
+                let path = local_join(local, rel);
+                fs::create_dir_all(&path).map_err(|e| io_error(&path, e))?;
+            }
+        }
+    }
+
+    // i file vengono copiati in parallelo; un errore su un file non ferma gli altri
+    let mut pending = plan.copy.iter();
+    let mut running = JoinSet::new();
+    let mut failed = 0;
+    loop {
+        while running.len() < options.jobs.max(1) && let Some((rel, mtime)) = pending.next() {
+            let task = copy_one(client.clone(), direction, local_join(local, rel), remote_join(remote, rel), *mtime);
+            let rel = rel.clone();
+            running.spawn(async move { (rel, task.await) });
+        }
+        let Some(done) = running.join_next().await else { break };
+        match done {
+            Ok((rel, Ok(()))) => println!("{}", rel),
+            Ok((rel, Err(e))) => {
+                eprintln!("Unable to copy {}: {}", rel, e.for_user());
+                failed += 1;
+            }
+            Err(e) => {
+                eprintln!("Copy task failed: {}", e);
+                failed += 1;
+            }
+        }
+    }
+    println!("{} copied, {} deleted, {} up to date", plan.copy.len() - failed, plan.delete.len(), plan.up_to_date);
+    if failed > 0 {
+        return Err(BackendError::Other(format!("{} file(s) could not be copied", failed)));
+    }
+    Ok(())
+}
+
+pub fn sync(rt: &Runtime, client: &RfsClient, from: &str, to: &str, options: SyncOptions) -> i32 {
+    let res = match (remote_path(from), remote_path(to)) {
+        (None, Some(remote)) => rt.block_on(run(client, Direction::Upload, Path::new(from), remote, &options)),
+        (Some(remote), None) => rt.block_on(run(client, Direction::Download, Path::new(to), remote, &options)),
+        _ => Err(BackendError::Other(format!("exactly one of the two paths must be remote ({}/path)", REMOTE_PREFIX))),
+    };
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e.for_user());
+            1
+        }
+    }
+}
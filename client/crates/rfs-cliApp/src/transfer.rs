@@ -0,0 +1,227 @@
+// Sottocomandi `ls`, `cp` e `rm`: parlano direttamente con il server tramite RfsClient, senza montare,
+// così funzionano anche dove fuse non c'è. I path remoti si scrivono `remote:/percorso`.
+// cp divide il file in blocchi da TRANSFER_CHUNK inviati in parallelo; con --resume confronta gli SHA-256
+// dei blocchi già presenti nella destinazione con quelli della sorgente e trasferisce solo quelli diversi.
+
+use rfs_api::{RfsClient, TRANSFER_CHUNK};
+use rfs_models::{BackendError, EntryType, FileEntry, OwnerNames};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tokio::runtime::Runtime;
+use tokio::task::JoinSet;
+
+pub const REMOTE_PREFIX: &str = "remote:";
+
+// hash chiesti al server con una singola richiesta
+const HASH_BATCH: usize = 256;
+
+/// Path remoto di un argomento `remote:/percorso`, None per un path locale
+pub fn remote_path(arg: &str) -> Option<&str> {
+    arg.strip_prefix(REMOTE_PREFIX)
+}
+
+fn io_error(path: &Path, e: io::Error) -> BackendError {
+    BackendError::Other(format!("{}: {}", path.display(), e))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn kind_char(entry: &FileEntry) -> char {
+    match entry.kind {
+        EntryType::Directory => 'd',
+        EntryType::Symlink => 'l',
+        EntryType::File => '-',
+    }
+}
+
+fn report(res: Result<(), BackendError>) -> i32 {
+    match res {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e.for_user());
+            1
+        }
+    }
+}
+
+pub fn ls(rt: &Runtime, client: &RfsClient, path: &str, long: bool) -> i32 {
+    let path = remote_path(path).unwrap_or(path);
+    report(rt.block_on(async {
+        let mut entries = client.list(path).await?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let names = if long { client.owner_names().await } else { OwnerNames::default() };
+        for entry in entries {
+            if long {
+                println!("{}{:o}\t{}\t{}\t{}\t{}", kind_char(&entry), entry.perms, names.user(entry.uid), names.group(entry.gid), entry.size, entry.name);
+            } else {
+                println!("{}", entry.name);
+            }
+        }
+        Ok(())
+    }))
+}
+
+pub fn rm(rt: &Runtime, client: &RfsClient, path: &str, recursive: bool) -> i32 {
+    let path = remote_path(path).unwrap_or(path);
+    report(rt.block_on(client.remove(path, recursive)))
+}
+
+pub fn cp(rt: &Runtime, client: &RfsClient, from: &str, to: &str, jobs: usize, resume: bool) -> i32 {
+    let res = match (remote_path(from), remote_path(to)) {
+        (None, Some(remote)) => rt.block_on(upload(client, Path::new(from), remote, jobs, resume)),
+        (Some(remote), None) => rt.block_on(download(client, remote, Path::new(to), jobs, resume)),
+        _ => Err(BackendError::Other(format!("exactly one of the two paths must be remote ({}/path)", REMOTE_PREFIX))),
+    };
+    report(res.map(|bytes| println!("{} bytes copied", bytes)))
+}
+
+// avanzamento su stderr, riscritto sulla stessa riga
+struct Progress {
+    total: u64,
+    done: u64,
+    started: Instant,
+}
+
+impl Progress {
+    fn add(&mut self, bytes: u64) {
+        self.done += bytes;
+        let percent = (self.done * 100).checked_div(self.total).unwrap_or(100);
+        let rate = self.done as f64 / self.started.elapsed().as_secs_f64().max(0.001);
+        eprint!("\r{}/{} bytes ({}%), {:.0} bytes/s   ", self.done, self.total, percent, rate);
+        if self.done >= self.total {
+            eprintln!();
+        }
+    }
+}
+
+fn chunk_count(size: u64) -> u64 {
+    size.div_ceil(TRANSFER_CHUNK)
+}
+
+// legge il blocco `idx` di un file locale
+fn read_local_chunk(path: &Path, idx: u64) -> Result<Vec<u8>, BackendError> {
+    let mut file = File::open(path).map_err(|e| io_error(path, e))?;
+    file.seek(SeekFrom::Start(idx * TRANSFER_CHUNK)).map_err(|e| io_error(path, e))?;
+    let mut data = Vec::with_capacity(TRANSFER_CHUNK as usize);
+    file.take(TRANSFER_CHUNK).read_to_end(&mut data).map_err(|e| io_error(path, e))?;
+    Ok(data)
+}
+
+// blocchi (tra i primi `present`) identici nella sorgente locale e nel file remoto, da non ritrasferire
+async fn matching_chunks(client: &RfsClient, ino: u64, local: &Path, present: u64) -> Result<HashSet<u64>, BackendError> {
+    let idxs: Vec<u64> = (0..present).collect();
+    let mut same = HashSet::new();
+    for batch in idxs.chunks(HASH_BATCH) {
+        let Some(remote) = client.block_hashes(ino, TRANSFER_CHUNK, batch.to_vec()).await? else {
+            eprintln!("The server does not provide block hashes: copying everything again");
+            return Ok(HashSet::new());
+        };
+        for &idx in batch {
+            let data = read_local_chunk(local, idx)?;
+            if !data.is_empty() && remote.get(&idx) == Some(&sha256_hex(&data)) {
+                same.insert(idx);
+            }
+        }
+    }
+    Ok(same)
+}
+
+// esegue `transfer` per ogni blocco da copiare, con al massimo `jobs` blocchi in volo
+async fn run_chunks<F, Fut>(chunks: Vec<u64>, jobs: usize, mut progress: Progress, transfer: F) -> Result<(), BackendError>
+where
+    F: Fn(u64) -> Fut,
+    Fut: Future<Output = Result<u64, BackendError>> + Send + 'static,
+{
+    let mut pending = chunks.into_iter();
+    let mut running = JoinSet::new();
+    loop {
+        while running.len() < jobs.max(1) && let Some(idx) = pending.next() {
+            running.spawn(transfer(idx));
+        }
+        let Some(done) = running.join_next().await else { break };
+        let bytes = done.map_err(|e| BackendError::Other(format!("transfer task failed: {}", e)))??;
+        progress.add(bytes);
+    }
+    Ok(())
+}
+
+async fn upload(client: &RfsClient, local: &Path, remote: &str, jobs: usize, resume: bool) -> Result<u64, BackendError> {
+    let size = local.metadata().map_err(|e| io_error(local, e))?.len();
+    // verso una directory esistente il file mantiene il suo nome
+    let remote = match client.stat(remote).await {
+        Ok(dir) if dir.kind == EntryType::Directory => {
+            let name = local.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+            format!("{}/{}", remote.trim_end_matches('/'), name)
+        }
+        _ => remote.to_string(),
+    };
+    let existing = if resume { client.stat(&remote).await.ok().filter(|e| e.kind == EntryType::File) } else { None };
+    let entry = match existing {
+        Some(entry) => entry,
+        None => client.create(&remote).await?,
+    };
+
+    let skip = if entry.size > 0 { matching_chunks(client, entry.ino, local, chunk_count(entry.size.min(size))).await? } else { HashSet::new() };
+    if entry.size != size {
+        client.set_size(entry.ino, size).await?;
+    }
+    let chunks: Vec<u64> = (0..chunk_count(size)).filter(|idx| !skip.contains(idx)).collect();
+    let skipped: u64 = skip.iter().map(|&idx| TRANSFER_CHUNK.min(size - idx * TRANSFER_CHUNK)).sum();
+    if skipped > 0 {
+        eprintln!("Resuming: {} bytes already on the server", skipped);
+    }
+
+    let progress = Progress { total: size - skipped, done: 0, started: Instant::now() };
+    let (ino, local) = (entry.ino, local.to_path_buf());
+    run_chunks(chunks, jobs, progress, |idx| {
+        let (client, local) = (client.clone(), local.clone());
+        async move {
+            let data = read_local_chunk(&local, idx)?;
+            let len = data.len() as u64;
+            client.write_at(ino, idx * TRANSFER_CHUNK, data).await?;
+            Ok(len)
+        }
+    }).await?;
+    Ok(size)
+}
+
+async fn download(client: &RfsClient, remote: &str, local: &Path, jobs: usize, resume: bool) -> Result<u64, BackendError> {
+    let entry = client.stat(remote).await?;
+    if entry.kind == EntryType::Directory {
+        return Err(BackendError::IsADirectory(remote.to_string()));
+    }
+    let local: PathBuf = if local.is_dir() { local.join(&entry.name) } else { local.to_path_buf() };
+    let present = if resume { local.metadata().map(|m| m.len()).unwrap_or(0) } else { 0 };
+    let skip = if present > 0 { matching_chunks(client, entry.ino, &local, chunk_count(present.min(entry.size))).await? } else { HashSet::new() };
+
+    let file = OpenOptions::new().write(true).create(true).truncate(!resume).open(&local).map_err(|e| io_error(&local, e))?;
+    file.set_len(entry.size).map_err(|e| io_error(&local, e))?;
+    drop(file);
+
+    let size = entry.size;
+    let chunks: Vec<u64> = (0..chunk_count(size)).filter(|idx| !skip.contains(idx)).collect();
+    let skipped: u64 = skip.iter().map(|&idx| TRANSFER_CHUNK.min(size - idx * TRANSFER_CHUNK)).sum();
+    if skipped > 0 {
+        eprintln!("Resuming: {} bytes already in {}", skipped, local.display());
+    }
+
+    let progress = Progress { total: size - skipped, done: 0, started: Instant::now() };
+    let ino = entry.ino;
+    run_chunks(chunks, jobs, progress, |idx| {
+        let (client, local) = (client.clone(), local.clone());
+        async move {
+            let data = client.read_at(ino, idx * TRANSFER_CHUNK, TRANSFER_CHUNK).await?;
+            let mut file = OpenOptions::new().write(true).open(&local).map_err(|e| io_error(&local, e))?;
+            file.seek(SeekFrom::Start(idx * TRANSFER_CHUNK)).map_err(|e| io_error(&local, e))?;
+            file.write_all(&data).map_err(|e| io_error(&local, e))?;
+            Ok(data.len() as u64)
+        }
+    }).await?;
+    Ok(size)
+}
@@ -0,0 +1,42 @@
+// Sessione fuse morta senza che nessuno abbia chiesto lo smontaggio (panic in una callback, connessione con
+// il kernel interrotta): il mount point resta "zombie" e ogni accesso fallisce con ENOTCONN finché non viene
+// smontato. Il demone lo libera e, se richiesto, rimonta con un numero limitato di tentativi.
+
+use std::io::ErrorKind;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+// attesa massima tra due tentativi di rimontaggio
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Il mount point è ancora montato ma la sessione che lo serviva non c'è più
+pub fn is_zombie(mount_point: &str) -> bool {
+    std::fs::metadata(mount_point).is_err_and(|e| e.kind() == ErrorKind::NotConnected)
+}
+
+/// Smonta un mount point zombie; restituisce false se è ancora inaccessibile
+pub fn release(mount_point: &str) -> bool {
+    if !is_zombie(mount_point) {
+        return true;
+    }
+    // smontaggio "lazy": riesce anche se qualche processo ha ancora file aperti sotto il mount point
+    let attempts: &[(&str, &[&str])] = if cfg!(target_os = "macos") {
+        &[("umount", &["-f"]), ("diskutil", &["unmount", "force"])]
+    } else {
+        &[("fusermount3", &["-uz"]), ("fusermount", &["-uz"]), ("umount", &["-l"])]
+    };
+    for (program, args) in attempts {
+        let status = Command::new(program).args(*args).arg(mount_point)
+            .stdout(Stdio::null()).stderr(Stdio::null())
+            .status();
+        if status.is_ok_and(|s| s.success()) && !is_zombie(mount_point) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Attesa prima del tentativo `attempt` (da 0): 1, 2, 4... secondi fino a MAX_BACKOFF
+pub fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1u64 << attempt.min(6)).min(MAX_BACKOFF)
+}
@@ -0,0 +1,75 @@
+// Barriera di coerenza dell'intero volume (`flush-all`), da chiedere prima di un backup o di uno snapshot
+// sul server: il filesystem svuota tutti i buffer di scrittura e la coda dei batch, aspetta gli invii e
+// rivalida gli attributi dei file aperti. Il filesystem lavora solo quando il kernel lo chiama: chi chiede
+// la barriera la registra qui e apre la radice del mount, e il filesystem la esegue nella opendir prima di
+// rispondere (lo fa anche una opendir arrivata da altri, la barriera resta una sola).
+
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Esito di una barriera
+#[derive(Debug, Clone, Default)]
+pub struct BarrierReport {
+    /// handle con byte da inviare e byte inviati
+    pub handles: usize,
+    pub bytes: u64,
+    /// handle il cui invio è fallito: (ino, errore); l'errore arriva anche alla loro prossima flush
+    pub failed: Vec<(u64, String)>,
+    /// file aperti rivalidati e quelli che sul server erano cambiati
+    pub revalidated: usize,
+    pub changed: Vec<u64>,
+}
+
+#[derive(Default)]
+struct BarrierState {
+    // ultima barriera chiesta e ultima eseguita
+    requested: u64,
+    done: u64,
+    report: BarrierReport,
+}
+
+/// Richieste di barriera condivise tra il filesystem e la socket di controllo
+#[derive(Clone, Default)]
+pub struct FlushBarrier(Arc<(Mutex<BarrierState>, Condvar)>);
+
+impl FlushBarrier {
+    /// Chiede una barriera al filesystem montato in `mount_point` e ne aspetta l'esito, al massimo `timeout`
+    pub fn run(&self, mount_point: &Path, timeout: Duration) -> Result<BarrierReport, String> {
+        let ticket = {
+            let mut state = self.0.0.lock().expect("Mutex poisoned");
+            state.requested += 1;
+            state.requested
+        };
+        wake(mount_point)?;
+        let deadline = Instant::now() + timeout;
+        let mut state = self.0.0.lock().expect("Mutex poisoned");
+        while state.done < ticket {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(format!("The filesystem did not complete the flush within {}s", timeout.as_secs()));
+            }
+            state = self.0.1.wait_timeout(state, left).expect("Mutex poisoned").0;
+        }
+        Ok(state.report.clone())
+    }
+
+    // barriera da eseguire, se ne è stata chiesta una dopo l'ultima
+    pub(crate) fn pending(&self) -> Option<u64> {
+        let state = self.0.0.lock().expect("Mutex poisoned");
+        (state.requested > state.done).then_some(state.requested)
+    }
+
+    pub(crate) fn complete(&self, ticket: u64, report: BarrierReport) {
+        let mut state = self.0.0.lock().expect("Mutex poisoned");
+        state.done = state.done.max(ticket);
+        state.report = report;
+        self.0.1.notify_all();
+    }
+}
+
+// una opendir arriva sempre al filesystem, anche con le liste nella cache del kernel
+fn wake(mount_point: &Path) -> Result<(), String> {
+    std::fs::read_dir(mount_point).map(drop)
+        .map_err(|e| format!("Cannot reach the filesystem mounted on {}: {}", mount_point.display(), e))
+}
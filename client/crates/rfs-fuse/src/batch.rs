@@ -0,0 +1,181 @@
+// Invio raggruppato delle scritture piccole. Quando la latenza verso il server è alta, scompattare un
+// archivio o fare il checkout di un sorgente paga un round trip per ogni file chiuso: se la latenza media
+// degli invii piccoli supera la soglia del mount, il buffer di un file chiuso (una sola run, fino a
+// BATCH_ITEM_MAX byte) viene messo in coda e le write in coda partono insieme con RemoteBackend::write_batch.
+// La coda parte quando è piena, dopo BATCH_MAX_AGE (da un thread con un backend indipendente) e prima di
+// ogni operazione che potrebbe vedere i dati mancanti (open, getattr, setattr, rename, readdir, ...).
+// Gli esiti arrivano al filesystem più tardi: il journal conserva le write finché non sono confermate.
+
+use crate::flush::FlushBackends;
+use rfs_models::{BackendError, BatchWrite, RemoteBackend};
+use std::collections::HashSet;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Byte massimi di una write messa in coda
+pub(crate) const BATCH_ITEM_MAX: u64 = 256 * 1024;
+// limiti di una singola richiesta
+const BATCH_MAX_BYTES: u64 = 4 * 1024 * 1024;
+const BATCH_MAX_WRITES: usize = 128;
+// tempo massimo di una write in coda prima che la coda parta da sola
+const BATCH_MAX_AGE: Duration = Duration::from_millis(200);
+
+// write in coda: handle da cui arriva e dati da inviare
+struct QueuedWrite {
+    fh: u64,
+    write: BatchWrite,
+}
+
+#[derive(Default)]
+struct BatchState {
+    queue: Vec<QueuedWrite>,
+    bytes: u64,
+    oldest: Option<Instant>,
+    // ino delle write inviate e non ancora concluse
+    in_flight: HashSet<u64>,
+    // esiti non ancora raccolti dal filesystem: (fh, ino, esito)
+    done: Vec<(u64, u64, Result<(), BackendError>)>,
+    // media mobile del tempo di risposta degli invii piccoli
+    latency: Option<Duration>,
+}
+
+/// Coda condivisa tra il filesystem e il thread che la invia allo scadere di BATCH_MAX_AGE
+pub(crate) struct WriteBatcher {
+    state: Arc<(Mutex<BatchState>, Condvar)>,
+    threshold: Option<Duration>,
+}
+
+impl WriteBatcher {
+    /// Con `threshold` None la coda non viene mai usata; senza `backends` parte solo quando serve al filesystem
+    pub(crate) fn new(threshold: Option<Duration>, backends: Option<FlushBackends>) -> Self {
+        let state: Arc<(Mutex<BatchState>, Condvar)> = Arc::default();
+        if threshold.is_some() && let Some(backends) = backends {
+            spawn_dispatcher(Arc::downgrade(&state), backends);
+        }
+        Self { state, threshold }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BatchState> {
+        self.state.0.lock().expect("Mutex poisoned")
+    }
+
+    /// Registra il tempo di risposta di un invio piccolo
+    pub(crate) fn sample(&self, elapsed: Duration) {
+        let mut state = self.lock();
+        state.latency = Some(match state.latency {
+            Some(avg) => (avg * 3 + elapsed) / 4,
+            None => elapsed,
+        });
+    }
+
+    /// true se una write di `len` byte va messa in coda invece di essere inviata subito
+    pub(crate) fn accepts(&self, len: u64) -> bool {
+        len > 0 && len <= BATCH_ITEM_MAX
+            && self.threshold.is_some_and(|threshold| self.lock().latency.is_some_and(|latency| latency >= threshold))
+    }
+
+    /// Mette in coda una write; true se la coda è piena e va inviata
+    pub(crate) fn push(&self, fh: u64, write: BatchWrite) -> bool {
+        let mut state = self.lock();
+        state.bytes += write.data.len() as u64;
+        state.oldest.get_or_insert_with(Instant::now);
+        state.queue.push(QueuedWrite { fh, write });
+        state.queue.len() >= BATCH_MAX_WRITES || state.bytes >= BATCH_MAX_BYTES
+    }
+
+    /// Byte in coda o in volo, contati tra quelli non ancora inviati
+    pub(crate) fn bytes(&self) -> u64 {
+        self.lock().bytes
+    }
+
+    /// Byte in coda per ogni write non ancora inviata: (ino, byte)
+    pub(crate) fn queued(&self) -> Vec<(u64, u64)> {
+        self.lock().queue.iter().map(|q| (q.write.ino, q.write.data.len() as u64)).collect()
+    }
+
+    /// true se `ino` ha write in coda o in volo (con None: se ce n'è almeno una)
+    pub(crate) fn holds(&self, ino: Option<u64>) -> bool {
+        let state = self.lock();
+        match ino {
+            Some(ino) => state.in_flight.contains(&ino) || state.queue.iter().any(|q| q.write.ino == ino),
+            None => !state.queue.is_empty() || !state.in_flight.is_empty(),
+        }
+    }
+
+    /// Aspetta l'invio già partito dal thread, poi invia il resto della coda con `backend`: le write dello
+    /// stesso file arrivano al server nell'ordine in cui sono state messe in coda
+    pub(crate) fn send_now(&self, backend: &mut dyn RemoteBackend) {
+        {
+            let mut state = self.lock();
+            while !state.in_flight.is_empty() {
+                state = self.state.1.wait(state).expect("Mutex poisoned");
+            }
+        }
+        send(&self.state, backend);
+    }
+
+    /// Esiti degli invii conclusi, da consegnare agli handle
+    pub(crate) fn take_done(&self) -> Vec<(u64, u64, Result<(), BackendError>)> {
+        std::mem::take(&mut self.lock().done)
+    }
+}
+
+// invia tutta la coda in una richiesta e ne registra gli esiti
+fn send(state: &(Mutex<BatchState>, Condvar), backend: &mut dyn RemoteBackend) {
+    let queue = {
+        let mut guard = state.0.lock().expect("Mutex poisoned");
+        let queue = std::mem::take(&mut guard.queue);
+        guard.oldest = None;
+        guard.in_flight.extend(queue.iter().map(|q| q.write.ino));
+        queue
+    };
+    if queue.is_empty() {
+        return;
+    }
+    let targets: Vec<(u64, u64, u64)> = queue.iter().map(|q| (q.fh, q.write.ino, q.write.data.len() as u64)).collect();
+    let started = Instant::now();
+    let results = backend.write_batch(queue.into_iter().map(|q| q.write).collect());
+    let elapsed = started.elapsed();
+
+    let mut guard = state.0.lock().expect("Mutex poisoned");
+    guard.latency = guard.latency.map(|avg| (avg * 3 + elapsed) / 4);
+    match results {
+        Ok(results) => {
+            for ((fh, ino, _), res) in targets.iter().zip(results) {
+                guard.done.push((*fh, *ino, res.map(|_| ())));
+            }
+        }
+        Err(e) => {
+            eprintln!("Batched write of {} file(s) failed: {}", targets.len(), e);
+            for (fh, ino, _) in targets.iter() {
+                guard.done.push((*fh, *ino, Err(BackendError::Other(e.to_string()))));
+            }
+        }
+    }
+    guard.bytes = guard.bytes.saturating_sub(targets.iter().map(|(_, _, len)| len).sum());
+    for (_, ino, _) in targets.iter() {
+        guard.in_flight.remove(ino);
+    }
+    state.1.notify_all();
+}
+
+// thread che invia la coda quando la write più vecchia ha superato BATCH_MAX_AGE; termina con il filesystem
+fn spawn_dispatcher(state: Weak<(Mutex<BatchState>, Condvar)>, backends: FlushBackends) {
+    let spawned = thread::Builder::new()
+        .name("rfs-write-batch".to_string())
+        .spawn(move || {
+            let mut backend = backends();
+            loop {
+                thread::sleep(BATCH_MAX_AGE / 2);
+                let Some(state) = state.upgrade() else { return };
+                let due = state.0.lock().expect("Mutex poisoned").oldest.is_some_and(|t| t.elapsed() >= BATCH_MAX_AGE);
+                if due {
+                    send(&state, backend.as_mut());
+                }
+            }
+        });
+    if let Err(e) = spawned {
+        eprintln!("Unable to start the write batch dispatcher: {}", e);
+    }
+}
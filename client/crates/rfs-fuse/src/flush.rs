@@ -0,0 +1,111 @@
+// Invio al server dei buffer di scrittura. Le scritture di un file vengono raggruppate in run di byte
+// contigui; run che non si sovrappongono (e file diversi allo smontaggio) possono partire in parallelo,
+// ognuna su un backend indipendente, così close e fsync di un file grande non aspettano una run alla volta.
+// Una run esce dal buffer del file solo quando il server l'ha ricevuta: quelle non inviate vi vengono rimesse.
+
+use crate::transfers::TransferProgress;
+use bytes::Bytes;
+use rfs_models::{BackendError, Deadline, RemoteBackend, shared_chunks, shared_stream};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::runtime::Runtime;
+
+pub type FlushBackend = Box<dyn RemoteBackend>;
+
+/// Crea i backend indipendenti usati dagli invii in parallelo
+pub type FlushBackends = Arc<dyn Fn() -> FlushBackend + Send + Sync>;
+
+/// Run di blocchi contigui da scrivere a partire da `offset`
+pub(crate) struct FlushRun {
+    pub(crate) fh: u64,
+    pub(crate) ino: u64,
+    pub(crate) offset: u64,
+    pub(crate) data: Vec<Bytes>,
+    pub(crate) progress: Arc<TransferProgress>,
+}
+
+/// Run di un buffer di scrittura, con i blocchi condivisi invece che copiati a ogni tentativo di invio
+pub(crate) fn shared_runs(runs: Vec<(u64, Vec<Vec<u8>>)>) -> Vec<(u64, Vec<Bytes>)> {
+    runs.into_iter().map(|(offset, run)| (offset, shared_chunks(run))).collect()
+}
+
+/// Run da rimettere nel buffer dopo un invio fallito
+pub(crate) fn unsent(runs: impl IntoIterator<Item = (u64, Vec<Bytes>)>) -> impl Iterator<Item = (u64, Vec<u8>)> {
+    runs.into_iter().map(|(offset, run)| (offset, run.concat()))
+}
+
+// oltre la soglia di streaming i blocchi vengono inviati in streaming uno alla volta invece di
+// concatenarli in un unico buffer
+pub(crate) fn send_run(backend: &mut dyn RemoteBackend, ino: u64, offset: u64, run: &[Bytes], large_file_size: u64, progress: &TransferProgress) -> Result<(), BackendError> {
+    let len: usize = run.iter().map(Bytes::len).sum();
+    if len as u64 > large_file_size {
+        backend.write_stream(ino, offset, progress.counting(shared_stream(run)))?;
+    } else if len > 0 {
+        backend.write_chunk(ino, offset, run.concat())?;
+        progress.add(len as u64);
+    }
+    Ok(())
+}
+
+/// Invia le run con al massimo `jobs` worker sul runtime, ognuno con il proprio backend.
+/// Restituisce il primo errore di ogni file handle e le run non inviate; le run di un handle già fallito
+/// vengono saltate, allo scadere di `deadline` quella in corso viene interrotta e le altre falliscono.
+pub(crate) fn send_parallel(rt: &Runtime, backends: &FlushBackends, jobs: usize, runs: Vec<FlushRun>, large_file_size: u64, deadline: Option<Instant>) -> (HashMap<u64, BackendError>, Vec<FlushRun>) {
+    let workers = jobs.min(runs.len());
+    let queue = Arc::new(Mutex::new(VecDeque::from(runs)));
+    let failed = Arc::new(Mutex::new(HashMap::<u64, BackendError>::new()));
+    let unsent = Arc::new(Mutex::new(Vec::new()));
+    let deadline = deadline.map(|at| Arc::new(Deadline::start(at)));
+
+    let handles: Vec<_> = (0..workers).map(|_| {
+        let queue = queue.clone();
+        let failed = failed.clone();
+        let unsent = unsent.clone();
+        let backends = backends.clone();
+        let deadline = deadline.clone();
+        rt.spawn_blocking(move || {
+            let mut backend = backends();
+            backend.set_cancel_token(deadline.as_ref().map(|d| d.token().clone()));
+            loop {
+                let Some(run) = queue.lock().expect("Mutex poisoned").pop_front() else { break };
+                let res = if failed.lock().expect("Mutex poisoned").contains_key(&run.fh) {
+                    Ok(false)
+                } else if deadline.as_ref().is_some_and(|d| d.expired()) {
+                    Err(BackendError::Other("shutdown timeout expired".to_string()))
+                } else {
+                    let res = send_run(backend.as_mut(), run.ino, run.offset, &run.data, large_file_size, &run.progress);
+                    // l'etag ricevuto non vale più appena un altro worker scrive sullo stesso file
+                    backend.invalidate(run.ino);
+                    res.map(|_| true).map_err(|e| match &deadline {
+                        Some(deadline) => deadline.error(e),
+                        None => e,
+                    })
+                };
+                match res {
+                    Ok(true) => {}
+                    Ok(false) => unsent.lock().expect("Mutex poisoned").push(run), // saltata: l'handle è già fallito
+                    Err(e) => {
+                        failed.lock().expect("Mutex poisoned").entry(run.fh).or_insert(e);
+                        unsent.lock().expect("Mutex poisoned").push(run);
+                    }
+                }
+            }
+        })
+    }).collect();
+
+    rt.block_on(async {
+        for handle in handles {
+            if let Err(e) = handle.await {
+                eprintln!("Flush worker failed: {}", e);
+            }
+        }
+    });
+    // le run rimaste in coda appartengono a un worker terminato male
+    let mut unsent = std::mem::take(&mut *unsent.lock().expect("Mutex poisoned"));
+    for run in queue.lock().expect("Mutex poisoned").drain(..) {
+        failed.lock().expect("Mutex poisoned").entry(run.fh).or_insert_with(|| BackendError::Other("flush worker failed".to_string()));
+        unsent.push(run);
+    }
+    (std::mem::take(&mut *failed.lock().expect("Mutex poisoned")), unsent)
+}
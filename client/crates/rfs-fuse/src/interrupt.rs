@@ -0,0 +1,85 @@
+// Interruzione delle richieste lunghe (es. Ctrl+C su un `cat` di un file enorme).
+// fuser risponde da solo a FUSE_INTERRUPT senza passarlo al filesystem, e le richieste vengono servite
+// dal loop di fuser o dai worker (workers.rs) senza che arrivi il messaggio. Il kernel manda
+// FUSE_INTERRUPT quando il processo in attesa riceve un segnale, quindi qui si osserva la stessa
+// condizione da /proc/<pid>/status e si cancella il token delle richieste di quel processo: il backend
+// abbandona la chiamata HTTP e il processo riceve EINTR.
+
+use rfs_models::CancellationToken;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// richieste interrompibili in corso: id -> (pid, token)
+type Armed = Arc<Mutex<HashMap<u64, (u32, CancellationToken)>>>;
+
+pub(crate) struct InterruptWatcher {
+    armed: Armed,
+    next_id: AtomicU64,
+    watcher: Thread,
+}
+
+impl InterruptWatcher {
+    pub(crate) fn start() -> Self {
+        let armed: Armed = Arc::default();
+        let watched = armed.clone();
+        let handle = thread::Builder::new()
+            .name("rfs-interrupts".to_string())
+            .spawn(move || loop {
+                let current: Vec<_> = watched.lock().expect("Mutex poisoned").values().cloned().collect();
+                // nessuna richiesta interrompibile in corso: si dorme fino alla prossima
+                if current.is_empty() {
+                    thread::park();
+                    continue;
+                }
+                for (pid, token) in current {
+                    if !token.is_cancelled() && signal_pending(pid) {
+                        token.cancel();
+                    }
+                }
+                thread::park_timeout(POLL_INTERVAL);
+            })
+            .expect("Unable to start the interrupt watcher");
+        Self { armed, next_id: AtomicU64::new(0), watcher: handle.thread().clone() }
+    }
+
+    /// Token per la richiesta del processo `pid`, cancellato se al processo arriva un segnale;
+    /// l'id serve a disarm quando la richiesta è finita.
+    pub(crate) fn arm(&self, pid: u32) -> (u64, CancellationToken) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let token = CancellationToken::new();
+        if pid != 0 { // richieste del kernel stesso (es. readahead), nessun processo da osservare
+            self.armed.lock().expect("Mutex poisoned").insert(id, (pid, token.clone()));
+            self.watcher.unpark();
+        }
+        (id, token)
+    }
+
+    pub(crate) fn disarm(&self, id: u64) {
+        self.armed.lock().expect("Mutex poisoned").remove(&id);
+    }
+}
+
+// segnali pendenti e non bloccati: è la condizione per cui il kernel interromperebbe la richiesta
+#[cfg(target_os = "linux")]
+fn signal_pending(pid: u32) -> bool {
+    let Ok(status) = std::fs::read_to_string(format!("/proc/{}/status", pid)) else { return false };
+    let mask = |key: &str| {
+        status.lines()
+            .find_map(|line| line.strip_prefix(key))
+            .and_then(|v| u64::from_str_radix(v.trim(), 16).ok())
+            .unwrap_or(0)
+    };
+    let pending = mask("SigPnd:") | mask("ShdPnd:");
+    let sigkill = 1u64 << (libc::SIGKILL - 1);
+    pending & sigkill != 0 || pending & !mask("SigBlk:") != 0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn signal_pending(_pid: u32) -> bool {
+    false
+}
@@ -0,0 +1,331 @@
+// Journal locale delle scritture bufferizzate: ogni write viene appesa al file prima di essere
+// confermata al kernel, e quando il buffer di un file handle viene inviato al server si appende un
+// record di commit. Se il demone muore, al mount successivo le write senza commit vengono rigiocate.
+//
+// Formato dei record (little endian):
+//   WRITE : tag 'W' | fh u64 | ino u64 | offset u64 | len u32 | data | checksum u64
+//   COMMIT: tag 'C' | fh u64 | checksum u64
+// Il checksum (FNV-1a) copre tutto il record tranne sé stesso: un record troncato o corrotto
+// (crash a metà scrittura) interrompe la lettura, quello che segue viene considerato perso.
+//
+// Il journal va su disco (sync_data) a ogni flush e a ogni close, non a ogni write. Quando diventa grande
+// viene compattato: restano solo le write degli handle senza commit, riscritte in un file nuovo che
+// prende il posto del vecchio con una rename.
+
+use rfs_models::{BackendError, DirtyRanges, RemoteBackend, join_chunks};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+const TAG_WRITE: u8 = b'W';
+const TAG_COMMIT: u8 = b'C';
+
+// sotto questa dimensione il journal non viene compattato
+const COMPACT_MIN: u64 = 4 * 1024 * 1024;
+
+// write senza commit di un handle: (fh, ino, byte scritti)
+type HandleWrites = (u64, u64, DirtyRanges);
+
+fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Esito del replay del journal al mount.
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    /// (ino, byte) scritti con successo sul server
+    pub recovered: Vec<(u64, usize)>,
+    /// (ino, byte, motivo) che non è stato possibile recuperare
+    pub unrecoverable: Vec<(u64, usize, String)>,
+}
+
+/// Write rimaste senza commit, lette senza toccare il journal (per `rfs check`).
+#[derive(Debug, Default)]
+pub struct PendingWrites {
+    /// (ino, byte) di ogni file handle con write senza commit
+    pub files: Vec<(u64, usize)>,
+    /// byte illeggibili in coda al journal (record troncato o corrotto)
+    pub torn: usize,
+}
+
+impl PendingWrites {
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.torn == 0
+    }
+}
+
+pub struct WriteJournal {
+    path: PathBuf,
+    file: File,
+    // dimensione dopo l'ultima compattazione: la prossima quando il file è cresciuto del doppio
+    compacted: u64,
+}
+
+impl WriteJournal {
+    /// Apre (o crea) il journal in `path`, senza toccare eventuali record rimasti da una sessione precedente.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).read(true).append(true).open(&path)?;
+        let compacted = file.metadata()?.len();
+        Ok(Self { path, file, compacted })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record_write(&mut self, fh: u64, ino: u64, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(&write_record(fh, ino, offset, data))
+    }
+
+    /// Registra che il buffer di `fh` è arrivato al server e, se il journal è cresciuto abbastanza,
+    /// lo compatta; in entrambi i casi il journal è su disco al ritorno.
+    pub fn record_commit(&mut self, fh: u64) -> io::Result<()> {
+        let mut rec = Vec::with_capacity(1 + 8 + 8);
+        rec.push(TAG_COMMIT);
+        rec.extend_from_slice(&fh.to_le_bytes());
+        let sum = checksum(&rec);
+        rec.extend_from_slice(&sum.to_le_bytes());
+        self.file.write_all(&rec)?;
+        let len = self.file.metadata()?.len();
+        if len >= COMPACT_MIN && len >= self.compacted * 2 {
+            return self.compact();
+        }
+        self.sync()
+    }
+
+    /// Porta su disco le write registrate finora (chiusura di un file, flush fallito)
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Riscrive il journal con le sole write senza commit, unite per handle
+    fn compact(&mut self) -> io::Result<()> {
+        let (writes, _torn) = self.load()?; // un record illeggibile in coda è perso comunque
+        let tmp = self.path.with_extension("compact");
+        let mut out = File::create(&tmp)?;
+        for (fh, ino, writes) in writes {
+            for (offset, run) in writes.into_runs() {
+                let mut offset = offset;
+                for data in run {
+                    out.write_all(&write_record(fh, ino, offset, &data))?;
+                    offset += data.len() as u64;
+                }
+            }
+        }
+        out.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?; // la rename è durevole solo con la directory su disco
+        }
+        self.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        self.compacted = self.file.metadata()?.len();
+        Ok(())
+    }
+
+    /// Svuota il journal, da chiamare quando non ci sono più buffer sporchi.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.compacted = 0;
+        self.sync()
+    }
+
+    // write senza commit come (fh, ino, byte scritti) in ordine di file handle, più i byte illeggibili in coda
+    fn load(&self) -> io::Result<(Vec<HandleWrites>, usize)> {
+        let mut raw = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut raw)?;
+
+        // fh -> (ino, byte scritti); le write successive sovrascrivono le parti in comune con le precedenti
+        let mut pending: HashMap<u64, (u64, DirtyRanges)> = HashMap::new();
+        let mut pos = 0usize;
+        while pos < raw.len() {
+            match parse_record(&raw[pos..]) {
+                Some((Record::Write { fh, ino, offset, data }, used)) => {
+                    pending.entry(fh).or_insert_with(|| (ino, DirtyRanges::default())).1.insert(offset, data);
+                    pos += used;
+                }
+                Some((Record::Commit { fh }, used)) => {
+                    pending.remove(&fh);
+                    pos += used;
+                }
+                None => break,
+            }
+        }
+        let mut handles: Vec<u64> = pending.keys().copied().collect();
+        handles.sort();
+        let writes = handles.into_iter()
+            .map(|fh| {
+                let (ino, writes) = pending.remove(&fh).expect("handle just listed");
+                (fh, ino, writes)
+            })
+            .collect();
+        Ok((writes, raw.len() - pos))
+    }
+
+    pub fn pending(&self) -> io::Result<PendingWrites> {
+        let (writes, torn) = self.load()?;
+        let files = writes.iter().map(|(_, ino, writes)| (*ino, writes.bytes() as usize)).collect();
+        Ok(PendingWrites { files, torn })
+    }
+
+    /// Rigioca sul backend le write rimaste senza commit e poi svuota il journal.
+    pub fn replay<B: RemoteBackend>(&mut self, backend: &mut B) -> io::Result<ReplayReport> {
+        let (writes, torn) = self.load()?;
+
+        let mut report = ReplayReport::default();
+        if torn > 0 {
+            report.unrecoverable.push((0, torn, "truncated or corrupted journal record".to_string()));
+        }
+
+        for (_, ino, writes) in writes {
+            let bytes = writes.bytes() as usize;
+            match replay_writes(backend, ino, writes) {
+                Ok(()) => report.recovered.push((ino, bytes)),
+                Err(e) => report.unrecoverable.push((ino, bytes, e.to_string())),
+            }
+        }
+
+        self.reset()?;
+        Ok(report)
+    }
+}
+
+fn write_record(fh: u64, ino: u64, offset: u64, data: &[u8]) -> Vec<u8> {
+    let mut rec = Vec::with_capacity(1 + 8 * 3 + 4 + data.len() + 8);
+    rec.push(TAG_WRITE);
+    rec.extend_from_slice(&fh.to_le_bytes());
+    rec.extend_from_slice(&ino.to_le_bytes());
+    rec.extend_from_slice(&offset.to_le_bytes());
+    rec.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    rec.extend_from_slice(data);
+    let sum = checksum(&rec);
+    rec.extend_from_slice(&sum.to_le_bytes());
+    rec
+}
+
+enum Record {
+    Write { fh: u64, ino: u64, offset: u64, data: Vec<u8> },
+    Commit { fh: u64 },
+}
+
+fn read_u64(buf: &[u8], at: usize) -> Option<u64> {
+    buf.get(at..at + 8).map(|b| u64::from_le_bytes(b.try_into().expect("8 bytes")))
+}
+
+fn parse_record(buf: &[u8]) -> Option<(Record, usize)> {
+    match *buf.first()? {
+        TAG_WRITE => {
+            let fh = read_u64(buf, 1)?;
+            let ino = read_u64(buf, 9)?;
+            let offset = read_u64(buf, 17)?;
+            let len = u32::from_le_bytes(buf.get(25..29)?.try_into().ok()?) as usize;
+            let end = 29 + len;
+            let data = buf.get(29..end)?.to_vec();
+            if read_u64(buf, end)? != checksum(&buf[..end]) {
+                return None;
+            }
+            Some((Record::Write { fh, ino, offset, data }, end + 8))
+        }
+        TAG_COMMIT => {
+            let fh = read_u64(buf, 1)?;
+            if read_u64(buf, 9)? != checksum(&buf[..9]) {
+                return None;
+            }
+            Some((Record::Commit { fh }, 17))
+        }
+        _ => None,
+    }
+}
+
+fn replay_writes<B: RemoteBackend>(backend: &mut B, ino: u64, writes: DirtyRanges) -> Result<(), BackendError> {
+    // il file potrebbe essere stato cancellato nel frattempo
+    backend.get_attr(ino)?;
+    for (offset, run) in writes.into_runs() {
+        backend.write_chunk(ino, offset, join_chunks(run))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn journal(name: &str) -> WriteJournal {
+        let path = std::env::temp_dir().join(format!("rfs-journal-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        WriteJournal::open(path).unwrap()
+    }
+
+    // write senza commit come (fh, ino, [(offset, byte)])
+    type Loaded = (Vec<(u64, u64, Vec<(u64, Vec<u8>)>)>, usize);
+
+    fn loaded(journal: &WriteJournal) -> Loaded {
+        let (writes, torn) = journal.load().unwrap();
+        let writes = writes.into_iter()
+            .map(|(fh, ino, writes)| (fh, ino, writes.into_runs().into_iter().map(|(offset, run)| (offset, run.concat())).collect()))
+            .collect();
+        (writes, torn)
+    }
+
+    #[test]
+    fn torn_last_record_is_reported_and_dropped() {
+        let mut journal = journal("torn");
+        journal.record_write(1, 10, 0, b"kept").unwrap();
+        let rec = write_record(1, 10, 4, b"lost");
+        journal.file.write_all(&rec[..rec.len() - 3]).unwrap();
+        assert_eq!(loaded(&journal), (vec![(1, 10, vec![(0, b"kept".to_vec())])], rec.len() - 3));
+        std::fs::remove_file(journal.path()).unwrap();
+    }
+
+    #[test]
+    fn commit_cancels_the_writes_of_its_handle() {
+        let mut journal = journal("commit");
+        journal.record_write(1, 10, 0, b"sent").unwrap();
+        journal.record_write(2, 20, 0, b"pending").unwrap();
+        journal.record_commit(1).unwrap();
+        assert_eq!(loaded(&journal), (vec![(2, 20, vec![(0, b"pending".to_vec())])], 0));
+        std::fs::remove_file(journal.path()).unwrap();
+    }
+
+    #[test]
+    fn later_overlapping_writes_win() {
+        let mut journal = journal("overlap");
+        journal.record_write(1, 10, 0, b"aaaaaa").unwrap();
+        journal.record_write(1, 10, 2, b"bb").unwrap();
+        journal.record_write(1, 10, 5, b"ccc").unwrap();
+        assert_eq!(loaded(&journal), (vec![(1, 10, vec![(0, b"aabbaccc".to_vec())])], 0));
+        std::fs::remove_file(journal.path()).unwrap();
+    }
+
+    #[test]
+    fn compaction_keeps_only_uncommitted_handles() {
+        let mut journal = journal("compact");
+        journal.record_write(1, 10, 0, b"sent").unwrap();
+        journal.record_write(2, 20, 0, b"xxxx").unwrap();
+        journal.record_write(2, 20, 1, b"yy").unwrap();
+        journal.record_commit(1).unwrap();
+        let before = loaded(&journal);
+        journal.compact().unwrap();
+        assert_eq!(loaded(&journal), before);
+        assert_eq!(before.0, vec![(2, 20, vec![(0, b"xyyx".to_vec())])]);
+        // nel file nuovo non restano né le write dell'handle con commit né il commit stesso
+        let mut raw = Vec::new();
+        File::open(journal.path()).unwrap().read_to_end(&mut raw).unwrap();
+        let mut pos = 0;
+        while let Some((record, used)) = parse_record(&raw[pos..]) {
+            assert!(matches!(record, Record::Write { fh: 2, ino: 20, .. }));
+            pos += used;
+        }
+        assert_eq!(pos, raw.len());
+        std::fs::remove_file(journal.path()).unwrap();
+    }
+}
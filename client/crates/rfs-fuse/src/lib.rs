@@ -1,24 +1,84 @@
 #![cfg(unix)] // questo file viene compilato solo su Linux/macOS
 
-use fuser::{FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow, consts};
-use rfs_models::{FileEntry, RemoteBackend, SetAttrRequest, BackendError, ByteStream, BLOCK_SIZE, EntryType};
-use libc::{EAGAIN, EBADF, EINVAL, ENOENT, O_ACCMODE, O_RDONLY, O_RDWR, O_WRONLY};
-use std::collections::{BTreeMap, HashMap};
+use fuser::{FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,ReplyEntry, ReplyIoctl, ReplyLock, ReplyOpen, ReplyStatfs, ReplyWrite, Request, TimeOrNow, consts};
+use rfs_models::{AuditLog, AuditRecord, BatchWrite, Caller, Policy, PolicyAction, FileEntry, RemoteBackend, SetAttrRequest, BackendError, JunkFilter, FILE_FLAG_APPEND, FILE_FLAG_IMMUTABLE, FILE_FLAG_NODUMP, IoSizes, EntryType, Identity, CreateModes, MODE_BITS, CancellationToken, DirPage, DirtyRanges, DIR_PAGE_SIZE, Deadline, LeaseKind, ByteLock, LockKind, ROOT_INO, join_chunks};
+use libc::{EACCES, EBADF, EINTR, EINVAL, ENOENT, EPERM, O_ACCMODE, O_RDONLY, O_RDWR, O_WRONLY};
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::File;
 use std::path::{Path};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant, SystemTime};
 use tokio::runtime::Runtime;
-use tokio_stream::StreamExt;
+use bytes::Bytes;
+
+mod barrier;
+mod batch;
+mod flush;
+mod interrupt;
+mod journal;
+mod locks;
+mod notify;
+mod owners;
+mod refresh;
+mod stream;
+mod transfers;
+mod workers;
+pub use barrier::{BarrierReport, FlushBarrier};
+pub use flush::{FlushBackend, FlushBackends};
+pub use journal::{PendingWrites, ReplayReport, WriteJournal};
+pub use notify::{DirListings, spawn_change_notifier};
+pub use owners::OwnerIds;
+use owners::Owners;
+pub use refresh::{OpenInodes, spawn_attr_refresher, spawn_open_keeper};
+pub use transfers::{TransferStatus, Transfers};
+use transfers::TransferProgress;
+use interrupt::InterruptWatcher;
+use batch::{BATCH_ITEM_MAX, WriteBatcher};
+use flush::{FlushRun, send_parallel, send_run, shared_runs, unsent};
+use stream::StreamRead;
+use locks::{UNLCK, byte_lock, kernel_lock, wait_for_lock};
+use workers::{PendingOpen, StreamLane, WorkerContext, WorkerPool, reply_statfs};
 
 const TTL_FILE: Duration = Duration::from_secs(7);
 const TTL_DIR: Duration = Duration::from_secs(3);
-const FOPEN_NONSEEKABLE: u32 = 1 << 2; //bit per settare nonseekable flag (controllare meglio abi, non viene codificato in fuser)
-const LARGE_FILE_SIZE: u64 = 100 * 1024 * 1024; // 100 MB
+/// Byte scritti e non ancora inviati oltre cui le write aspettano che i buffer si svuotino
+pub const DEFAULT_DIRTY_LIMIT: u64 = 1024 * 1024 * 1024;
+// macOS non ha O_DIRECT (F_NOCACHE non arriva al filesystem): lì valgono solo le regole per path
+#[cfg(target_os = "linux")]
+const O_DIRECT: i32 = libc::O_DIRECT;
+#[cfg(not(target_os = "linux"))]
+const O_DIRECT: i32 = 0;
+
+// flag di chflags(2) (sys/stat.h di macOS), arrivano in setattr e tornano in FileAttr::flags
+const UF_NODUMP: u32 = 0x1;
+const UF_IMMUTABLE: u32 = 0x2;
+const UF_APPEND: u32 = 0x4;
+const SF_IMMUTABLE: u32 = 0x2_0000;
+const SF_APPEND: u32 = 0x4_0000;
+
+// ioctl di chattr/lsattr su Linux (linux/fs.h): codificate con sizeof(long), ma il kernel passa un intero a 32 bit
+const FS_IOC_GETFLAGS: u32 = 0x8000_6601 | ((std::mem::size_of::<libc::c_long>() as u32) << 16);
+const FS_IOC_SETFLAGS: u32 = 0x4000_6602 | ((std::mem::size_of::<libc::c_long>() as u32) << 16);
+const FS_IMMUTABLE_FL: u32 = 0x10;
+const FS_APPEND_FL: u32 = 0x20;
+const FS_NODUMP_FL: u32 = 0x40;
+
+// corrispondenza tra i FILE_FLAG_* e i flag di un sistema (immutable, append, nodump)
+fn to_system_flags(file_flags: u32, [immutable, append, nodump]: [u32; 3]) -> u32 {
+    [(FILE_FLAG_IMMUTABLE, immutable), (FILE_FLAG_APPEND, append), (FILE_FLAG_NODUMP, nodump)].into_iter()
+        .filter(|(flag, _)| file_flags & flag != 0)
+        .fold(0, |acc, (_, system)| acc | system)
+}
+
+fn from_system_flags(flags: u32, [immutable, append, nodump]: [u32; 3]) -> u32 {
+    [(FILE_FLAG_IMMUTABLE, immutable), (FILE_FLAG_APPEND, append), (FILE_FLAG_NODUMP, nodump)].into_iter()
+        .filter(|(_, system)| flags & system != 0)
+        .fold(0, |acc, (flag, _)| acc | flag)
+}
 
 fn map_error(error: &BackendError) -> libc::c_int {
-    use libc::{EIO, EACCES, EEXIST, EHOSTUNREACH, EPERM, EPROTO};
+    use libc::{EAGAIN, EACCES, EEXIST, EFBIG, EHOSTUNREACH, EINVAL, EIO, EISDIR, ENOSPC, ENOTDIR, ENOTEMPTY, ENOTSUP, EPERM, EPROTO, EROFS, ESTALE};
     match error {
         BackendError::NotFound(_) => {
             ENOENT
@@ -31,6 +91,7 @@ fn map_error(error: &BackendError) -> libc::c_int {
             eprintln!("Forbidden error.");
             EACCES
         },
+        BackendError::NotPermitted(_) => EPERM,
         BackendError::Conflict(err) => {
             eprintln!("Conflict error: {}", err);
             EEXIST
@@ -39,14 +100,45 @@ fn map_error(error: &BackendError) -> libc::c_int {
             eprintln!("Internal server error.");
             EIO
         },
-        BackendError::BadAnswerFormat => {
-            eprintln!("Bad answer format.");
+        BackendError::BadAnswerFormat(err) => {
+            eprintln!("Bad answer format: {}", err);
             EPROTO
         },
         BackendError::ServerUnreachable => {
             eprintln!("Server unreachable.");
             EHOSTUNREACH
         },
+        BackendError::PreconditionFailed => {
+            eprintln!("Precondition failed: file modified by another client.");
+            ESTALE
+        },
+        BackendError::Interrupted => EINTR,
+        BackendError::ReadOnly => EROFS,
+        BackendError::Stale(path) => {
+            eprintln!("Stale handle: {} no longer resolves on the server.", path);
+            ESTALE
+        },
+        BackendError::Unsupported(what) => {
+            eprintln!("Not supported by the server: {}", what);
+            ENOTSUP
+        },
+        BackendError::NotADirectory(_) => ENOTDIR,
+        BackendError::IsADirectory(_) => EISDIR,
+        BackendError::NotEmpty(_) => ENOTEMPTY,
+        BackendError::InvalidArgument(err) => {
+            eprintln!("Invalid argument: {}", err);
+            EINVAL
+        },
+        BackendError::NoSpace(err) => {
+            eprintln!("No space left on the server: {}", err);
+            ENOSPC
+        },
+        BackendError::TooLarge(_) => EFBIG,
+        BackendError::Throttled(err) => {
+            eprintln!("Throttled by the server: {}", err);
+            EAGAIN
+        },
+        BackendError::Locked(_) => EAGAIN,
         BackendError::Other(err) => {
             eprintln!("Backend error: {}", err);
             EIO
@@ -54,13 +146,53 @@ fn map_error(error: &BackendError) -> libc::c_int {
     }
 }
 
+// errore di un'operazione su un file già aperto: se sul server non c'è più l'ha cancellato un altro
+// client, e chi ha il descrittore deve vedere ESTALE e non ENOENT
+fn map_handle_error(error: &BackendError) -> libc::c_int {
+    match error {
+        BackendError::NotFound(_) => libc::ESTALE,
+        e => map_error(e),
+    }
+}
+
 
 #[inline]
-fn entry_to_attr(entry: &FileEntry, req: &Request<'_>) -> FileAttr {
+// processo locale con questo pid; su macOS /proc non c'è e resta sconosciuto
+fn caller_of(pid: u32) -> Caller {
+    Caller {
+        name: std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok().map(|name| name.trim_end().to_string()),
+        exe: std::fs::read_link(format!("/proc/{}/exe", pid)).ok().map(|exe| exe.to_string_lossy().into_owned()),
+    }
+}
+
+// ino a cui il kernel si riferisce ancora dopo che il server lo ha riassegnato (vedi reresolve)
+fn live_ino(replaced: &Mutex<HashMap<u64, u64>>, ino: u64) -> u64 {
+    replaced.lock().expect("Mutex poisoned").get(&ino).copied().unwrap_or(ino)
+}
+
+// il server ha riassegnato l'ino (BackendError::Stale): il path con cui era stato risolto viene risolto
+// di nuovo dalla root e da qui in poi l'ino del kernel indica il nuovo oggetto
+fn reresolve(backend: &mut dyn RemoteBackend, replaced: &Mutex<HashMap<u64, u64>>, ino: u64, path: &str) -> Result<u64, BackendError> {
+    let mut current = ROOT_INO;
+    for name in path.split('/').filter(|c| !c.is_empty()) {
+        current = backend.lookup(current, name)?.ino;
+    }
+    eprintln!("Stale ino {} re-resolved as {} (ino {})", ino, path, current);
+    replaced.lock().expect("Mutex poisoned").insert(ino, current);
+    Ok(current)
+}
+
+// uid e gid del processo che fa la richiesta
+fn caller_ids(req: &Request<'_>) -> (u32, u32) {
+    (req.uid(), req.gid())
+}
+
+fn entry_to_attr(entry: &FileEntry, caller: (u32, u32), block_size: usize, owners: &Owners) -> FileAttr {
+    let (uid, gid) = owners.attr_ids(entry, caller);
     FileAttr {
         ino: entry.ino,
         size: entry.size,
-        blocks: (entry.size + 511) / 512, // i blocchi sono di 512 byte come da specifica posix
+        blocks: entry.size.div_ceil(512), // i blocchi sono di 512 byte come da specifica posix
         atime: entry.atime,
         mtime: entry.mtime,
         ctime: entry.ctime,
@@ -72,37 +204,298 @@ fn entry_to_attr(entry: &FileEntry, req: &Request<'_>) -> FileAttr {
         },
         perm: entry.perms,
         nlink: entry.nlinks,
-        flags:0, // usato per device files, non ci interessa
+        flags: to_system_flags(entry.file_flags, [UF_IMMUTABLE, UF_APPEND, UF_NODUMP]), // chflags, solo macOS
         rdev:0, // non lo usiamo per ora, serve per mac os?
-        blksize:BLOCK_SIZE as u32, // è la dimensione di blocco preferita per le operazioni di I/O, matcha con il layer di cache
-        // su macOS usa l’UID/GID della request, altrove quelli dal backend
-        uid: if cfg!(target_os = "macos") { req.uid() } else { entry.uid },
-        gid: if cfg!(target_os = "macos") { req.gid() } else { entry.gid },
+        blksize:block_size as u32, // è la dimensione di blocco preferita per le operazioni di I/O, matcha con il layer di cache
+        uid,
+        gid,
     }
 }
 
-struct StreamState{
-    //ino: u64,
-    pos: u64,
-    buffer: Vec<u8>,
-    stream: Option<ByteStream>,
-    eof: bool,
+enum ReadMode{
+    SmallPages,
+    Direct, // O_DIRECT: ogni read va al server, senza cache
+    Stream(Arc<StreamLane>), // condiviso coi worker che servono le read dell'handle
 }
 
-impl StreamState{
-    fn new()->Self{
-        Self{
-            pos: 0,
-            buffer: Vec::new(),
-            stream: None,
-            eof: false,
+// modo di lettura di un handle, scelto dalle read stesse: si parte a pagine (blocchi della cache), dopo
+// `stream_after` byte letti in sequenza si passa allo streaming e al primo salto si torna a pagine
+struct ReadHandle {
+    mode: ReadMode,
+    next: u64, // offset a cui continuerebbe una lettura sequenziale
+    sequential: u64, // byte letti in sequenza fin qui
+    streamable: bool,
+}
+
+impl ReadHandle {
+    fn new(mode: ReadMode, streamable: bool) -> Self {
+        Self { mode, next: 0, sequential: 0, streamable }
+    }
+
+    // aggiorna il modo con la read che sta per essere servita
+    fn track(&mut self, offset: u64, size: u32, stream_after: u64) {
+        let sequential = offset == self.next;
+        self.next = offset.saturating_add(size as u64);
+        self.sequential = if sequential { self.sequential.saturating_add(size as u64) } else { size as u64 };
+        match self.mode {
+            // lo stream già aperto non serve più: le read in coda ai worker finiscono comunque
+            ReadMode::Stream(_) if !sequential => self.mode = ReadMode::SmallPages,
+            ReadMode::SmallPages if sequential && self.streamable && stream_after > 0 && self.sequential >= stream_after => {
+                self.mode = ReadMode::Stream(Arc::new(StreamLane::at(offset)));
+            }
+            _ => {}
         }
     }
 }
 
-enum ReadMode{
-    SmallPages,
-    LargeStream(StreamState),
+// lettura di una directory aperta: si tengono solo le voci dalla posizione corrente in poi,
+// le pagine successive si chiedono al backend quando readdir le raggiunge
+#[derive(Default)]
+pub(crate) struct DirStream{
+    base: usize, // indice della prima voce in entries
+    entries: Vec<FileEntry>,
+    next: Option<String>, // cursore della prossima pagina
+    started: bool,
+    done: bool,
+}
+
+impl DirStream{
+    fn get(&self, index: usize) -> Option<&FileEntry> {
+        index.checked_sub(self.base).and_then(|i| self.entries.get(i))
+    }
+}
+
+// riempie `reply` dalla voce `index` in poi; le pagine che mancano le dà `next_page`, a partire dal cursore
+fn fill_dir(stream: &mut DirStream, ino: u64, mut index: usize, reply: &mut ReplyDirectory, listings: &DirListings,
+    mut next_page: impl FnMut(Option<&str>) -> Result<DirPage, BackendError>) -> Result<(), BackendError> {
+    loop {
+        let Some(entry) = stream.get(index) else {
+            if stream.done {
+                return Ok(());
+            }
+            // voci già restituite al kernel non servono più
+            let consumed = (index - stream.base).min(stream.entries.len());
+            stream.entries.drain(..consumed);
+            stream.base += consumed;
+
+            let page = next_page(stream.next.as_deref())?;
+            if !stream.started && page.next.is_none() {
+                // directory letta con una sola pagina: la ricordiamo per notificare le voci che cambiano
+                listings.record(ino, &page.entries);
+            }
+            stream.started = true;
+            stream.done = page.next.is_none();
+            stream.next = page.next;
+            stream.entries.extend(page.entries);
+            continue;
+        };
+        let ftype= match entry.kind {
+            EntryType::File => FileType::RegularFile,
+            EntryType::Directory => FileType::Directory,
+            EntryType::Symlink => FileType::Symlink,
+        };
+        // cookie stabile: 3 + index
+        if reply.add(entry.ino, (index as i64) + 3, ftype, &entry.name) {
+            return Ok(());
+        }
+        index += 1;
+    }
+}
+
+// voci di una pagina da mostrare a readdir: niente file spazzatura già presenti sul server né voci nascoste
+// dalla policy al processo `pid`
+fn visible(entries: &mut Vec<FileEntry>, junk: &JunkFilter, policy: &Policy, pid: u32) {
+    entries.retain(|entry| !junk.hides(&entry.name));
+    if !policy.is_empty() {
+        let caller = policy.needs_caller().then(|| caller_of(pid));
+        entries.retain(|entry| policy.action(&entry.path, caller.as_ref()) != Some(PolicyAction::Hidden));
+    }
+}
+
+/// Opzioni del layer fuse scelte da chi monta il filesystem.
+pub struct FsOptions {
+    pub speed_testing: bool,
+    pub speed_file: Option<File>,
+    /// file creati dai sistemi operativi (desktop.ini, .DS_Store, `._*`, ...) da nascondere o non creare
+    pub junk: JunkFilter,
+    pub shutdown_timeout: Duration,
+    pub journal: Option<WriteJournal>,
+    pub kernel: KernelTuning,
+    /// dimensione dei blocchi e soglia di streaming, le stesse usate dalla cache
+    pub io: IoSizes,
+    /// utente del server e suoi gruppi, per access(); None se il server non li ha forniti
+    pub identity: Option<Identity>,
+    /// montato con default_permissions: proprietario e gruppi dell'utente vanno mostrati come quelli locali
+    pub default_permissions: bool,
+    /// permessi e umask del mount per i file e le directory creati
+    pub create_modes: CreateModes,
+    /// registro degli invii in corso, letto dalla socket di controllo
+    pub transfers: Transfers,
+    /// richieste di barriera di coerenza (`flush-all`) dalla socket di controllo
+    pub barrier: FlushBarrier,
+    /// invii al server in parallelo durante un flush (run indipendenti di un file, file diversi allo
+    /// smontaggio); con 1 o senza `flush_backends` le run partono una alla volta
+    pub flush_jobs: usize,
+    /// backend indipendenti per gli invii in parallelo e per i worker
+    pub flush_backends: Option<FlushBackends>,
+    /// lo stesso backend passato a `RemoteFS::new` (la cache condivisa), da cui i worker prendono quello che
+    /// è già in memoria e a cui consegnano quello che leggono dal server
+    pub cache_backend: Option<FlushBackends>,
+    /// thread che servono le operazioni che vanno al server (getattr, lookup, readdir, read, open, write in
+    /// append, statfs) senza fermare le altre (vedi workers.rs); con 0 o senza `flush_backends` e
+    /// `cache_backend` tutto resta sul thread della sessione
+    pub workers: usize,
+    /// attributi della root letti al mount, serviti a getattr se il server non risponde
+    pub root: Option<FileEntry>,
+    /// log delle operazioni che modificano o aprono file, con utente e processo che le hanno chieste
+    pub audit: Option<AuditLog>,
+    /// regole che nascondono voci o vietano operazioni per path o processo
+    pub policy: Policy,
+    /// pagine e attributi restano nella cache del kernel finché vale il lease sull'ino, che viene chiesto
+    /// a ogni risposta al kernel: il server richiama il lease quando il file cambia, e la coerenza dipende
+    /// dalle notifiche di modifica (spawn_change_notifier). Senza lease valgono le durate di `ttl`
+    pub trust_cache: bool,
+    /// byte nei buffer di scrittura, di tutti i file, oltre cui la write che lo supera aspetta l'invio dei
+    /// buffer fino a scendere sotto i tre quarti
+    pub dirty_limit: u64,
+    /// latenza media degli invii piccoli oltre cui i buffer piccoli dei file chiusi vengono raccolti e
+    /// inviati insieme (vedi batch.rs); None li invia sempre subito
+    pub batch_latency: Option<Duration>,
+    /// albero e attributi completi, ma il contenuto dei file si legge solo se è già in locale (pinnato):
+    /// per esplorare dataset enormi senza scaricare gigabyte per sbaglio
+    pub metadata_only: bool,
+    /// validità di attributi e voci date al kernel; con trust_cache solo per gli ino senza lease
+    pub ttl: CacheTtl,
+    /// byte letti in sequenza da un handle oltre cui le read passano in streaming; 0 non lo fa mai
+    pub stream_after: u64,
+    /// regole per path che impongono o ignorano O_DIRECT
+    pub direct_io: Vec<DirectIoRule>,
+    /// utenti e gruppi locali con lo stesso nome di quelli del server, mostrati al loro posto (vedi owners.rs)
+    pub owner_ids: Option<Arc<RwLock<OwnerIds>>>,
+    /// lock fcntl chiesti al server e validi fra tutti i client (vedi locks.rs); senza li tiene il kernel,
+    /// validi solo su questa macchina
+    pub remote_locks: bool,
+}
+
+/// Per quanto il kernel può riusare quello che il filesystem gli ha risposto senza chiederlo di nuovo:
+/// la voce (il nome risolto da lookup, create, mkdir, link e symlink) e gli attributi (getattr e setattr).
+/// Valori lunghi fanno meno richieste, valori brevi vedono prima le modifiche degli altri client.
+/// Nelle risposte a lookup fuser dà al kernel lo stesso valore per voce e attributi: vale quello della voce.
+#[derive(Debug, Clone)]
+pub struct CacheTtl {
+    pub attr_file: Duration,
+    pub attr_dir: Duration,
+    pub entry_file: Duration,
+    pub entry_dir: Duration,
+    /// valori diversi sotto alcuni path remoti; tra più regole vale quella col prefisso più lungo
+    pub rules: Vec<TtlRule>,
+}
+
+/// Validità di attributi e voci sotto un path remoto; quelle non indicate restano quelle del mount
+#[derive(Debug, Clone)]
+pub struct TtlRule {
+    pub prefix: String,
+    pub attr: Option<Duration>,
+    pub entry: Option<Duration>,
+}
+
+/// Cache dei dati per i file sotto un path remoto: con `direct` ogni open vale come O_DIRECT (niente page
+/// cache del kernel né blocchi in cache, write inviate subito), senza O_DIRECT viene ignorato. Tra più
+/// regole vale quella col prefisso più lungo.
+#[derive(Debug, Clone)]
+pub struct DirectIoRule {
+    pub prefix: String,
+    pub direct: bool,
+}
+
+// `path` è `prefix` o sta sotto di esso; il prefisso vuoto (o "/") vale per tutto
+fn under_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    prefix.is_empty() || path == prefix || path.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/'))
+}
+
+impl Default for CacheTtl {
+    fn default() -> Self {
+        Self { attr_file: TTL_FILE, attr_dir: TTL_DIR, entry_file: TTL_FILE, entry_dir: TTL_DIR, rules: Vec::new() }
+    }
+}
+
+impl CacheTtl {
+    fn rule(&self, path: &str) -> Option<&TtlRule> {
+        self.rules.iter()
+            .filter(|rule| under_prefix(path, &rule.prefix))
+            .max_by_key(|rule| rule.prefix.trim_end_matches('/').len())
+    }
+
+    fn attr(&self, entry: &FileEntry) -> Duration {
+        let default = if entry.kind == EntryType::Directory { self.attr_dir } else { self.attr_file };
+        self.rule(&entry.path).and_then(|rule| rule.attr).unwrap_or(default)
+    }
+
+    fn entry(&self, entry: &FileEntry) -> Duration {
+        let default = if entry.kind == EntryType::Directory { self.entry_dir } else { self.entry_file };
+        self.rule(&entry.path).and_then(|rule| rule.entry).unwrap_or(default)
+    }
+}
+
+/// Parametri negoziati con il kernel in init(); valori pensati per un backend di rete, dove conviene
+/// fare poche richieste grandi.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelTuning {
+    /// le write restano nella page cache del kernel e arrivano al demone a blocchi grandi
+    pub writeback_cache: bool,
+    /// byte massimi per singola write
+    pub max_write: u32,
+    /// byte letti in anticipo dal kernel sulle letture sequenziali
+    pub max_readahead: u32,
+    /// richieste in background (readahead, writeback) che il kernel tiene in volo
+    pub max_background: u16,
+}
+
+impl Default for KernelTuning {
+    fn default() -> Self {
+        Self {
+            writeback_cache: true,
+            max_write: 1024 * 1024,
+            max_readahead: 1024 * 1024,
+            max_background: 64,
+        }
+    }
+}
+
+impl Default for FsOptions {
+    fn default() -> Self {
+        Self {
+            speed_testing: false,
+            speed_file: None,
+            junk: JunkFilter::default(),
+            shutdown_timeout: Duration::from_secs(30),
+            journal: None,
+            kernel: KernelTuning::default(),
+            io: IoSizes::default(),
+            identity: None,
+            default_permissions: false,
+            create_modes: CreateModes::default(),
+            transfers: Transfers::default(),
+            barrier: FlushBarrier::default(),
+            flush_jobs: 1,
+            flush_backends: None,
+            cache_backend: None,
+            workers: 0,
+            root: None,
+            audit: None,
+            policy: Policy::default(),
+            trust_cache: false,
+            dirty_limit: DEFAULT_DIRTY_LIMIT,
+            batch_latency: None,
+            metadata_only: false,
+            ttl: CacheTtl::default(),
+            stream_after: 8 * 1024 * 1024,
+            direct_io: Vec::new(),
+            owner_ids: None,
+            remote_locks: false,
+        }
+    }
 }
 
 pub struct RemoteFS<B: RemoteBackend> {
@@ -111,106 +504,835 @@ pub struct RemoteFS<B: RemoteBackend> {
     rt: Arc<Runtime>, // runtime per eseguire le operazioni asincrone
 
     // inode/path management
-    dir_parent: HashMap<u64, u64>, // mappa inode directory al suo genitore per poter risolvere ".."
+    dir_parent: Arc<Mutex<HashMap<u64, u64>>>, // mappa inode directory al suo genitore per poter risolvere ".." (anche dai worker)
 
     // file handle management
     next_fh: u64, // file handle da allocare, per ora semplicemente incrementale
-    read_file_handles: HashMap<u64, ReadMode>, // mappa file handle, per gestire read in streaming continuo su file già aperti
-    write_buffers: HashMap<u64, BTreeMap<u64, Vec<u8>>>, // buffer di scrittura per ogni file aperto; il valore è la coppia (buffer, offset)
+    read_file_handles: HashMap<u64, ReadHandle>, // fh -> modo di lettura, con lo stream se l'handle legge in sequenza
+    stream_after: u64, // byte sequenziali dopo cui un handle passa allo streaming
+    dir_streams: HashMap<u64, Arc<Mutex<DirStream>>>, // fh -> lettura in corso di una directory aperta, condivisa coi worker
+    write_buffers: HashMap<u64, DirtyRanges>, // byte scritti e non ancora inviati, per ogni file aperto in scrittura
+    dirty_bytes: u64, // totale dei write_buffers
+    dirty_limit: u64, // oltre questo totale le write aspettano gli invii
+    flush_errors: HashMap<u64, libc::c_int>, // errori di invii fatti per conto di altri handle, da dare alla loro flush
+    conflicts: HashSet<u64>, // fh con write rifiutate perché il file è cambiato sul server: non vengono più inviate
+    batch: WriteBatcher, // write piccole dei file chiusi in attesa di partire insieme
+    write_inodes: HashMap<u64, u64>, // fh -> ino dei file aperti in scrittura, serve per svuotare i buffer allo smontaggio
+    open_modes: HashMap<u64, i32>, // fh -> modo di apertura (O_RDONLY, O_WRONLY o O_RDWR)
+    open_inodes: OpenInodes, // fh -> ino di tutti i file aperti, letto dal refresher degli attributi
+    listings: DirListings, // ultime liste servite a readdir, per notificare al kernel le voci cambiate
+    deferred_modes: HashMap<u64, u32>, // fh -> permessi da applicare al release (file creati senza permesso di scrittura)
+    replaced_inodes: Arc<Mutex<HashMap<u64, u64>>>, // ino del kernel -> ino riassegnato dal server, risolto di nuovo per path
+    interrupts: Arc<InterruptWatcher>, // cancella le richieste in corso quando il processo riceve un segnale
+    cancel: Option<(u64, CancellationToken)>, // id e token della richiesta interrompibile in corso
+    workers: Option<WorkerPool>, // pool per le richieste che vanno al server, None = tutto sul thread della sessione
+    shutdown_timeout: Duration, // tempo massimo per svuotare i buffer in destroy
+    journal: Option<WriteJournal>, // copia su disco delle write bufferizzate, rigiocata al mount dopo un crash
+    journal_keep: bool, // un flush è fallito: il journal va conservato fino al prossimo replay
+    kernel: KernelTuning, // parametri da negoziare in init
+    writeback: bool, // writeback cache accettata dal kernel: offset e O_APPEND li gestisce lui
+    io: IoSizes, // blocco annunciato al kernel e soglia oltre cui letture e scritture vanno in streaming
+    owners: Owners, // proprietari da presentare al kernel
+    identity: Option<Identity>, // utente del server e suoi gruppi, per i controlli di access()
+    create_modes: CreateModes, // permessi e umask imposti dal mount alle create e alle mkdir
+    transfers: Transfers, // avanzamento dei flush in corso, per `status --transfers`
+    barrier: FlushBarrier, // barriere di coerenza chieste con `flush-all`
+    flush_jobs: usize, // invii in parallelo al massimo durante un flush
+    flush_backends: Option<FlushBackends>, // backend dei worker di flush, None = invii sequenziali
+    root: Arc<Mutex<Option<FileEntry>>>, // ultimi attributi noti della root, aggiornati a ogni getattr riuscita
+    failed_opens: Arc<Mutex<Vec<u64>>>, // fh di open fallite nei worker, da ripulire: il kernel non manderà release
+    audit: Option<AuditLog>, // log di audit delle operazioni, se attivo
+    policy: Policy, // regole di policy, valutate prima di chiamare il backend
+    trust_cache: bool, // page cache del kernel anche per gli handle in scrittura, invalidata solo dai recall
+    metadata_only: bool, // le open in lettura di file non pinnati falliscono con ENODATA
+    refused_reads: HashSet<u64>, // ino già segnalati nel log come non letti per metadata_only
+    ttl: CacheTtl, // validità di attributi e voci date al kernel
+    direct_io: Vec<DirectIoRule>, // regole per path su O_DIRECT
+    direct_handles: HashSet<u64>, // fh aperti senza cache: read dal server, write inviate subito
+    remote_locks: bool, // lock fcntl inoltrati al server (FUSE_POSIX_LOCKS)
+    locked_owners: HashSet<(u64, u64)>, // (ino, lock owner) che hanno chiesto lock, da rilasciare al flush
 
     // opzioni di testing
     speed_testing: bool,
     speed_file: Option<File>,
+
+    // file spazzatura (AppleDouble `._*`, .DS_Store, ...) nascosti o non mandati al server
+    junk: JunkFilter,
 }
 
 impl<B: RemoteBackend> RemoteFS<B> {
-    pub fn new(mounting_point: String, backend: B,runtime: Arc<Runtime>,speed_testing: bool,speed_file: Option<File>) -> Self {
+    pub fn new(mounting_point: String, backend: B,runtime: Arc<Runtime>,options: FsOptions) -> Self {
+        let FsOptions { speed_testing, speed_file, junk, shutdown_timeout, journal, kernel, io, identity, default_permissions, create_modes, transfers, barrier, flush_jobs, flush_backends, cache_backend, workers, root, audit, policy, trust_cache, dirty_limit, batch_latency, metadata_only, ttl, stream_after, direct_io, owner_ids, remote_locks } = options;
+        let interrupts = Arc::new(InterruptWatcher::start());
+        let owners = Owners { identity: identity.clone().filter(|_| default_permissions), local: owner_ids };
+        let (listings, dir_parent, replaced_inodes, root, failed_opens) = (DirListings::default(), Arc::default(), Arc::default(), Arc::new(Mutex::new(root)), Arc::default());
+        let workers = match (flush_backends.clone(), cache_backend) {
+            (Some(backends), Some(cache)) if workers > 0 => {
+                let ctx = WorkerContext {
+                    cache,
+                    ttl: ttl.clone(),
+                    trust_cache,
+                    block_size: io.block_size,
+                    large_file_size: io.large_file_size,
+                    owners: owners.clone(),
+                    junk: junk.clone(),
+                    policy: policy.clone(),
+                    listings: listings.clone(),
+                    dir_parent: Arc::clone(&dir_parent),
+                    replaced_inodes: Arc::clone(&replaced_inodes),
+                    root: Arc::clone(&root),
+                    failed_opens: Arc::clone(&failed_opens),
+                };
+                Some(WorkerPool::start(workers, backends, ctx, runtime.clone(), interrupts.clone()))
+            }
+            _ => None,
+        };
         Self {
             mounting_point,
             backend,
             rt: runtime,
-            dir_parent: HashMap::new(),
+            dir_parent,
             next_fh: 3, //0,1,2 di solito sono assegnati, da controllare
             read_file_handles: HashMap::new(),
+            stream_after,
+            dir_streams: HashMap::new(),
             write_buffers: HashMap::new(),
+            dirty_bytes: 0,
+            dirty_limit,
+            flush_errors: HashMap::new(),
+            conflicts: HashSet::new(),
+            batch: WriteBatcher::new(batch_latency, flush_backends.clone()),
+            write_inodes: HashMap::new(),
+            open_modes: HashMap::new(),
+            open_inodes: OpenInodes::default(),
+            listings,
+            deferred_modes: HashMap::new(),
+            replaced_inodes,
+            interrupts,
+            cancel: None,
+            workers,
+            shutdown_timeout,
+            journal,
+            journal_keep: false,
+            kernel,
+            writeback: false,
+            io,
+            owners,
+            identity,
+            create_modes,
+            transfers,
+            barrier,
+            flush_jobs,
+            flush_backends,
+            root,
+            failed_opens,
+            audit,
+            policy,
+            trust_cache,
+            metadata_only,
+            refused_reads: HashSet::new(),
+            ttl,
+            direct_io,
+            direct_handles: HashSet::new(),
+            remote_locks,
+            locked_owners: HashSet::new(),
             speed_testing,
             speed_file,
+            junk,
+        }
+    }
+
+    /// File aperti, da passare a `spawn_attr_refresher` e `spawn_open_keeper` prima di montare il filesystem.
+    pub fn open_inodes(&self) -> OpenInodes {
+        self.open_inodes.clone()
+    }
+
+    /// Liste delle directory già servite al kernel, da passare a `spawn_change_notifier`.
+    pub fn dir_listings(&self) -> DirListings {
+        self.listings.clone()
+    }
+
+    fn live_ino(&self, ino: u64) -> u64 {
+        live_ino(&self.replaced_inodes, ino)
+    }
+
+    // get_attr che recupera gli ino riassegnati; ESTALE solo se il vecchio path non si risolve più
+    fn attr_of(&mut self, ino: u64) -> Result<FileEntry, BackendError> {
+        match self.backend.get_attr(self.live_ino(ino)) {
+            Err(BackendError::Stale(path)) => match reresolve(&mut self.backend, &self.replaced_inodes, ino, &path) {
+                Ok(live) => self.backend.get_attr(live),
+                Err(_) => Err(BackendError::Stale(path)),
+            },
+            res => res,
+        }
+    }
+
+    // path remoto di `name` dentro `ino` (o di `ino` stesso) per il log di audit e la policy
+    fn remote_path(&mut self, ino: u64, name: Option<&OsStr>) -> String {
+        let base = self.backend.get_attr(ino).map(|entry| entry.path).unwrap_or_else(|_| format!("<ino {}>", ino));
+        match name {
+            Some(name) => format!("{}/{}", base.trim_end_matches('/'), name.to_string_lossy()),
+            None => base,
+        }
+    }
+
+    // registra nel log di audit, se attivo, l'operazione `op` su `at` (e `to` per rename e link)
+    fn audit(&mut self, req: &Request<'_>, op: &str, at: (u64, Option<&OsStr>), to: Option<(u64, &OsStr)>, res: Result<(), libc::c_int>) {
+        if self.audit.is_none() {
+            return;
+        }
+        let path = self.remote_path(at.0, at.1);
+        let mut record = AuditRecord::new(&self.mounting_point, op, path);
+        record.target = to.map(|(parent, name)| self.remote_path(parent, Some(name)));
+        record.uid = Some(req.uid());
+        record.pid = Some(req.pid());
+        record.process = caller_of(req.pid()).name;
+        record.error = res.err().map(|code| std::io::Error::from_raw_os_error(code).to_string());
+        if let Some(audit) = self.audit.as_ref() {
+            audit.record(&record);
+        }
+    }
+
+    // azione della policy per `name` dentro `ino` (o per `ino` stesso) chiesta da `req`
+    fn policy_action(&mut self, req: &Request<'_>, ino: u64, name: Option<&OsStr>) -> Option<PolicyAction> {
+        if self.policy.is_empty() {
+            return None;
+        }
+        let path = self.remote_path(ino, name);
+        let caller = self.policy.needs_caller().then(|| caller_of(req.pid()));
+        self.policy.action(&path, caller.as_ref())
+    }
+
+    // errore per un'operazione vietata dalla policy; `write` se crea, modifica, rinomina o cancella
+    fn policy_denies(&mut self, req: &Request<'_>, ino: u64, name: Option<&OsStr>, write: bool) -> Option<libc::c_int> {
+        match self.policy_action(req, ino, name)? {
+            PolicyAction::ReadOnly if !write => None,
+            PolicyAction::Hidden if !write => Some(ENOENT),
+            _ => Some(EACCES),
+        }
+    }
+
+    // O_DIRECT chiesto dall'applicazione, salvo una regola per path che lo impone o lo ignora
+    fn is_direct(&mut self, ino: u64, flags: i32) -> bool {
+        let asked = flags & O_DIRECT != 0;
+        if self.direct_io.is_empty() {
+            return asked;
+        }
+        let path = self.remote_path(ino, None);
+        self.direct_io.iter()
+            .filter(|rule| under_prefix(&path, &rule.prefix))
+            .max_by_key(|rule| rule.prefix.trim_end_matches('/').len())
+            .map_or(asked, |rule| rule.direct)
+    }
+
+    // validità di attributi e voci date al kernel. Con la trust cache durano quanto il lease sull'ino, chiesto
+    // qui se manca: i recall arrivano solo a chi ha un lease, e oltre la sua scadenza una modifica di un altro
+    // client non verrebbe notificata
+    fn attr_ttl(&mut self, entry: &FileEntry) -> Duration {
+        self.leased_ttl(entry.ino).unwrap_or_else(|| self.ttl.attr(entry))
+    }
+
+    fn entry_ttl(&mut self, entry: &FileEntry) -> Duration {
+        self.leased_ttl(entry.ino).unwrap_or_else(|| self.ttl.entry(entry))
+    }
+
+    fn leased_ttl(&mut self, ino: u64) -> Option<Duration> {
+        if !self.trust_cache {
+            return None;
+        }
+        if self.backend.wanted_lease(ino, LeaseKind::Read) {
+            let asked = Instant::now();
+            let lease = self.backend.acquire_lease(ino, LeaseKind::Read).unwrap_or_else(|e| {
+                eprintln!("Lease request for ino {} failed: {}", ino, e);
+                None
+            });
+            self.backend.offer_lease(ino, lease, asked);
+        }
+        self.backend.lease_left(ino)
+    }
+
+    // flag di open di un handle scrivibile: con la trust cache le pagine sopravvivono anche a open e close
+    fn write_open_flags(&self) -> u32 {
+        if self.trust_cache {
+            consts::FOPEN_KEEP_CACHE
+        } else if self.writeback {
+            0
+        } else {
+            consts::FOPEN_DIRECT_IO
+        }
+    }
+
+    // modo di apertura effettivo: con le scritture nella page cache il kernel legge anche dagli handle in sola
+    // scrittura (le pagine scritte solo in parte), che vanno quindi trattati come O_RDWR. Le read di un
+    // processo su un fd O_WRONLY le rifiuta comunque il kernel
+    fn access_mode(&self, flags: i32, direct: bool) -> i32 {
+        match flags & O_ACCMODE {
+            O_WRONLY if !direct && (self.writeback || self.trust_cache) => O_RDWR,
+            mode => mode,
+        }
+    }
+
+    // come su un file locale: read su un handle aperto in sola scrittura (e viceversa) danno EBADF
+    fn check_mode(&self, fh: u64, write: bool) -> Result<(), libc::c_int> {
+        match self.open_modes.get(&fh) {
+            None => Err(EBADF),
+            Some(&mode) if mode == if write { O_RDONLY } else { O_WRONLY } => Err(EBADF),
+            Some(_) => Ok(()),
         }
     }
 
     fn flush_file(&mut self, fh: u64, ino: u64) -> Result<(), BackendError> {
+        // un conflitto con un altro client non si risolve riprovando (con l'etag nuovo le write cancellerebbero
+        // le sue modifiche): i dati restano nel buffer, e nel journal dopo il release, finché non li recupera
+        // `check` al prossimo mount
+        if self.conflicts.contains(&fh) && self.write_buffers.get(&fh).is_some_and(|map| !map.is_empty()) {
+            return Err(BackendError::PreconditionFailed);
+        }
+        // le write dello stesso file già in coda devono arrivare prima
+        self.settle(Some(self.live_ino(ino)));
+        let res = self.flush_file_inner(fh, ino);
+        if let Err(BackendError::PreconditionFailed) = res {
+            self.conflicts.insert(fh);
+        }
+        self.record_flush(fh, res.is_ok());
+        res
+    }
 
-        let mut start_offset = 0 as u64;
-        let mut last_offset = 0 as u64;
-        let mut prev_block_size = 0 as u64;
-        // Collect the map's contents into a vector to avoid double mutable borrow
-        let map_entries: Vec<(u64, Vec<u8>)> = {
-            if let Some(map) = self.write_buffers.get_mut(&fh) {
-                let entries = map.iter().map(|(k, v)| (*k, v.clone())).collect();
-                map.clear();
-                entries
+    // esito di un flush nel journal, che torna su disco: dopo l'ultimo buffer svuotato riparte da zero
+    fn record_flush(&mut self, fh: u64, flushed: bool) {
+        self.update_dirty();
+        let all_clean = self.write_buffers.values().all(|map| map.is_empty()) && !self.batch.holds(None);
+        if let Some(journal) = self.journal.as_mut() {
+            let journal_res = if !flushed {
+                self.journal_keep = true;
+                journal.sync()
+            } else if all_clean && !self.journal_keep {
+                journal.reset()
             } else {
-                return Err(BackendError::Other("File handle not found".to_string()));
+                journal.record_commit(fh)
+            };
+            if let Err(e) = journal_res {
+                eprintln!("Write journal error: {}", e);
             }
+        }
+    }
+
+    // i dati escono dal buffer per l'invio e ci tornano se il server non li riceve
+    fn flush_file_inner(&mut self, fh: u64, ino: u64) -> Result<(), BackendError> {
+        let pending = match self.write_buffers.get_mut(&fh) {
+            Some(map) => std::mem::take(map),
+            None => return Err(BackendError::Other("File handle not found".to_string())),
         };
-        
-        let mut buffer = Vec::<u8>::new();
-        for (off, data) in map_entries.iter() {
+        let total = pending.bytes();
+        if total == 0 {
+            return Ok(());
+        }
+        let progress = self.transfers.start(ino, total);
+        let runs = shared_runs(pending.into_runs());
+
+        if runs.len() > 1 && let Some(backends) = self.parallel_flush() {
+            let live = self.live_ino(ino);
+            if let Err(e) = self.backend.prepare_write(live, runs[0].0) {
+                self.restore_runs(fh, runs);
+                return Err(e);
+            }
+            let progress = Arc::new(progress);
+            let runs = runs.into_iter()
+                .map(|(offset, data)| FlushRun { fh, ino: live, offset, data, progress: progress.clone() })
+                .collect();
+            let (mut failed, unsent) = send_parallel(&self.rt, &backends, self.flush_jobs, runs, self.io.large_file_size, None);
+            self.backend.invalidate(live);
+            self.restore_runs(fh, unsent.into_iter().map(|run| (run.offset, run.data)));
+            return failed.remove(&fh).map_or(Ok(()), Err);
+        }
+
+        let started = Instant::now();
+        let mut runs = runs.into_iter();
+        while let Some((offset, run)) = runs.next() {
+            if let Err(e) = self.flush_run(&run, ino, offset, &progress) {
+                self.restore_runs(fh, std::iter::once((offset, run)).chain(runs));
+                return Err(e);
+            }
+        }
+        // gli invii piccoli misurano la latenza che decide se raccoglierli in un batch
+        if total <= BATCH_ITEM_MAX {
+            self.batch.sample(started.elapsed());
+        }
+        Ok(())
+    }
+
+    // run non inviate di nuovo nel buffer dell'handle, per il prossimo flush (o il journal al release)
+    fn restore_runs(&mut self, fh: u64, runs: impl IntoIterator<Item = (u64, Vec<Bytes>)>) {
+        if let Some(map) = self.write_buffers.get_mut(&fh) {
+            map.restore(unsent(runs));
+        }
+    }
 
-            if buffer.is_empty() || last_offset + prev_block_size as u64 == *off {
-                if buffer.is_empty() {
-                    start_offset = *off;
+    // con latenza alta il buffer piccolo di un file che si chiude va in coda invece di partire subito;
+    // false se va inviato come al solito
+    fn defer_flush(&mut self, fh: u64, ino: u64) -> bool {
+        let live = self.live_ino(ino);
+        let Some(map) = self.write_buffers.get_mut(&fh) else { return false };
+        if !self.batch.accepts(map.bytes()) {
+            return false;
+        }
+        let mut full = false;
+        for (offset, run) in std::mem::take(map).into_runs() {
+            full |= self.batch.push(fh, BatchWrite { ino: live, offset, data: join_chunks(run) });
+        }
+        if full {
+            self.batch.send_now(&mut self.backend);
+        }
+        self.collect_batch();
+        self.update_dirty();
+        true
+    }
+
+    // invia la coda dei batch se contiene write di `ino` (con None: se non è vuota), poi ne raccoglie gli esiti
+    fn settle(&mut self, ino: Option<u64>) {
+        if self.batch.holds(ino) {
+            self.batch.send_now(&mut self.backend);
+        }
+        self.collect_batch();
+    }
+
+    // esiti dei batch conclusi: journal, cache e, per gli handle ancora aperti, errore alla prossima flush
+    fn collect_batch(&mut self) {
+        for (fh, ino, res) in self.batch.take_done() {
+            // il batch può essere partito da un backend indipendente
+            self.backend.invalidate(ino);
+            if let Err(e) = &res {
+                eprintln!("Batched write of fh {} (ino {}) failed: {}", fh, ino, e);
+                if self.open_modes.contains_key(&fh) {
+                    self.flush_errors.insert(fh, map_handle_error(e));
                 }
-                buffer.extend_from_slice(&data);
+            }
+            self.record_flush(fh, res.is_ok());
+        }
+    }
+
+    // nuovo handle per una open riuscita, con modo di lettura e buffer di scrittura; i flag di open sono quelli
+    // per un file fino a large_file_size e per uno più grande
+    fn register_open(&mut self, ino: u64, flags: i32, writable: bool) -> (u64, (u32, u32)) {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        let direct_io = consts::FOPEN_DIRECT_IO;
+        let mut fuse_flags = (direct_io, direct_io); // default, non usare cache del kernel
+        let direct = self.is_direct(ino, flags);
+        if direct {
+            self.direct_handles.insert(fh);
+        }
+        let mode = self.access_mode(flags, direct);
+        if mode == O_RDONLY || mode == O_RDWR {
+            // con le scritture nella page cache il kernel legge pagine a offset qualsiasi prima di scriverle: niente stream
+            let page_cache_writes = writable && (self.writeback || self.trust_cache);
+            // i file grandi restano fuori dalla page cache; se e quando passare allo streaming lo decidono le read
+            let (ff, mode) = if direct {
+                ((direct_io, direct_io), ReadMode::Direct)
+            } else if !page_cache_writes {
+                ((consts::FOPEN_KEEP_CACHE, direct_io), ReadMode::SmallPages)
             } else {
-                // Flush the current buffer
-                self.flush_buffer(&mut buffer, ino, start_offset)?;
-                start_offset = *off;
-                buffer.clear();
-                buffer.extend_from_slice(&data);
+                ((consts::FOPEN_KEEP_CACHE, consts::FOPEN_KEEP_CACHE), ReadMode::SmallPages)
+            };
+            fuse_flags = ff;
+            self.read_file_handles.insert(fh, ReadHandle::new(mode, !page_cache_writes));
+        }
+        if writable {
+            self.write_buffers.insert(fh, DirtyRanges::default());
+            self.write_inodes.insert(fh, ino);
+            if !direct {
+                let ff = self.write_open_flags();
+                fuse_flags = (ff, ff);
             }
-            last_offset = *off;
-            prev_block_size = data.len() as u64;
+        }
+        self.open_modes.insert(fh, mode);
+        (fh, fuse_flags)
+    }
+
+    // handle registrati per open poi fallite nei worker: il kernel non li userà né li chiuderà
+    fn drop_failed_opens(&mut self) {
+        let failed = std::mem::take(&mut *self.failed_opens.lock().expect("Mutex poisoned"));
+        for fh in failed {
+            self.read_file_handles.remove(&fh);
+            self.direct_handles.remove(&fh);
+            self.write_buffers.remove(&fh);
+            self.write_inodes.remove(&fh);
+            self.open_modes.remove(&fh);
+            self.open_inodes.closed(fh);
+        }
+    }
+
+    // primo handle sull'ino: il server ne tiene il contenuto anche se viene cancellato mentre è aperto
+    fn track_open(&mut self, fh: u64, ino: u64) {
+        if self.open_inodes.opened(fh, ino) && let Err(e) = self.backend.hold_open(ino) {
+            eprintln!("Cannot register open ino {} on the server: {}", ino, e);
+        }
+    }
+
+    // truncate riuscito: i byte bufferizzati oltre la nuova fine sono stati scritti prima e non vanno più inviati
+    fn truncate_buffers(&mut self, ino: u64, size: u64) {
+        let handles: Vec<u64> = self.write_inodes.iter()
+            .filter(|(_, w)| self.live_ino(**w) == ino)
+            .map(|(fh, _)| *fh)
+            .collect();
+        for fh in handles {
+            if let Some(map) = self.write_buffers.get_mut(&fh) {
+                map.truncate(size);
+            }
+        }
+        self.update_dirty();
+    }
+
+    // totale dei buffer di scrittura, anche per `status --transfers`
+    fn update_dirty(&mut self) {
+        self.dirty_bytes = self.write_buffers.values().map(|map| map.bytes()).sum::<u64>() + self.batch.bytes();
+        let mut by_ino: HashMap<u64, u64> = HashMap::new();
+        for (fh, map) in &self.write_buffers {
+            if let Some(ino) = self.write_inodes.get(fh) {
+                *by_ino.entry(*ino).or_default() += map.bytes();
+            }
+        }
+        for (ino, bytes) in self.batch.queued() {
+            *by_ino.entry(ino).or_default() += bytes;
+        }
+        self.transfers.set_dirty(self.dirty_bytes, by_ino);
+    }
+
+    // oltre il limite la write di `fh` aspetta l'invio dei buffer, dal più grande, finché il totale non scende
+    // sotto i tre quarti: chi scrive più veloce della rete rallenta invece di riempire la memoria.
+    // L'errore dell'invio del buffer di un altro handle viene dato alla prossima flush di quell'handle.
+    fn relieve_pressure(&mut self, fh: u64) -> Result<(), BackendError> {
+        if self.dirty_bytes <= self.dirty_limit {
+            return Ok(());
+        }
+        let low = self.dirty_limit / 4 * 3;
+        let mut dirty: Vec<(u64, u64)> = self.write_buffers.iter()
+            .filter(|(_, map)| !map.is_empty())
+            .map(|(fh, map)| (*fh, map.bytes()))
+            .collect();
+        dirty.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+
+        let mut res = Ok(());
+        for (other, _) in dirty {
+            if self.dirty_bytes <= low {
+                break;
+            }
+            let ino = self.write_inodes.get(&other).copied().unwrap_or(0);
+            match self.flush_file(other, ino) {
+                Ok(()) => {}
+                Err(e) if other == fh => res = Err(e),
+                Err(e) => {
+                    eprintln!("Flush of fh {} under memory pressure failed: {}", other, e);
+                    self.flush_errors.insert(other, map_handle_error(&e));
+                }
+            }
+        }
+        res
+    }
+
+    // backend per i worker di flush, se il mount ne prevede più di uno
+    fn parallel_flush(&self) -> Option<FlushBackends> {
+        self.flush_backends.clone().filter(|_| self.flush_jobs > 1)
+    }
+
+    /// Svuota tutti i buffer di scrittura ancora sporchi, fermandosi allo scadere di `timeout`.
+    /// Restituisce gli handle che non è stato possibile scrivere sul server, con il relativo errore.
+    fn drain_write_buffers(&mut self, timeout: Duration) -> Vec<(u64, u64, BackendError)> {
+        let deadline = Instant::now() + timeout;
+        let mut failed = Vec::new();
+
+        let mut dirty: Vec<u64> = self.write_buffers.iter()
+            .filter(|(_, map)| !map.is_empty())
+            .map(|(fh, _)| *fh)
+            .collect();
+        dirty.sort();
+
+        if let Some(backends) = self.parallel_flush() {
+            return self.drain_parallel(dirty, deadline, backends);
         }
 
-        // flushing last bytes
-        if !buffer.is_empty() {
-            self.flush_buffer(&mut buffer, ino, start_offset)?;
+        // la scadenza interrompe anche l'invio in corso, non solo i successivi
+        let timer = Deadline::start(deadline);
+        self.backend.set_cancel_token(Some(timer.token().clone()));
+        for fh in dirty {
+            let ino = self.write_inodes.get(&fh).copied().unwrap_or(0);
+            if timer.expired() {
+                failed.push((fh, ino, BackendError::Other("shutdown timeout expired".to_string())));
+                continue;
+            }
+            if let Err(e) = self.flush_file(fh, ino) {
+                failed.push((fh, ino, timer.error(e)));
+            }
+        }
+        self.backend.set_cancel_token(self.cancel.as_ref().map(|(_, token)| token.clone()));
+        failed
+    }
+
+    // come drain_write_buffers, ma le run di tutti i file partono insieme sui worker di flush
+    fn drain_parallel(&mut self, dirty: Vec<u64>, deadline: Instant, backends: FlushBackends) -> Vec<(u64, u64, BackendError)> {
+        let mut failed = Vec::new();
+        let mut runs = Vec::new();
+        let mut sent = Vec::new();
+        for fh in dirty {
+            let ino = self.write_inodes.get(&fh).copied().unwrap_or(0);
+            let pending = self.write_buffers.get_mut(&fh).map(std::mem::take).unwrap_or_default();
+            let total = pending.bytes();
+            if total == 0 {
+                self.record_flush(fh, true);
+                continue;
+            }
+            let live = self.live_ino(ino);
+            let file_runs = shared_runs(pending.into_runs());
+            if let Err(e) = self.backend.prepare_write(live, file_runs[0].0) {
+                self.restore_runs(fh, file_runs);
+                self.record_flush(fh, false);
+                failed.push((fh, ino, e));
+                continue;
+            }
+            let progress = Arc::new(self.transfers.start(ino, total));
+            runs.extend(file_runs.into_iter().map(|(offset, data)| FlushRun { fh, ino: live, offset, data, progress: progress.clone() }));
+            sent.push((fh, ino, live));
+        }
+
+        let (mut errors, unsent) = send_parallel(&self.rt, &backends, self.flush_jobs, runs, self.io.large_file_size, Some(deadline));
+        for run in unsent {
+            self.restore_runs(run.fh, [(run.offset, run.data)]);
+        }
+        let mut invalidated = HashSet::new();
+        for (fh, ino, live) in sent {
+            if invalidated.insert(live) {
+                self.backend.invalidate(live);
+            }
+            let error = errors.remove(&fh);
+            if let Some(BackendError::PreconditionFailed) = error {
+                self.conflicts.insert(fh);
+            }
+            self.record_flush(fh, error.is_none());
+            if let Some(e) = error {
+                failed.push((fh, ino, e));
+            }
+        }
+        failed.sort_by_key(|(fh, _, _)| *fh);
+        failed
+    }
+
+    // barriera di coerenza: tutti i buffer e la coda dei batch inviati, poi gli attributi dei file aperti
+    // riletti dal server (il refresher degli attributi fa vedere al kernel quelli cambiati)
+    fn run_barrier(&mut self) -> BarrierReport {
+        let mut report = BarrierReport {
+            handles: self.write_buffers.values().filter(|map| !map.is_empty()).count(),
+            bytes: self.dirty_bytes,
+            ..BarrierReport::default()
+        };
+        self.settle(None);
+        for (fh, ino, e) in self.drain_write_buffers(self.shutdown_timeout) {
+            eprintln!("Flush of fh {} (ino {}) for flush-all failed: {}", fh, ino, e);
+            self.flush_errors.insert(fh, map_handle_error(&e));
+            report.failed.push((ino, e.to_string()));
+        }
+        for ino in self.open_inodes.inos() {
+            let live = self.live_ino(ino);
+            let before = self.backend.get_attr(live).ok().map(|e| (e.size, e.mtime));
+            self.backend.invalidate(live);
+            match self.backend.get_attr(live) {
+                Ok(entry) => {
+                    report.revalidated += 1;
+                    if before != Some((entry.size, entry.mtime)) {
+                        report.changed.push(ino);
+                    }
+                }
+                Err(e) => eprintln!("Unable to revalidate ino {} for flush-all: {}", ino, e),
+            }
+        }
+        report
+    }
+
+    // da qui a end_interruptible le chiamate al backend falliscono con Interrupted se al processo arriva un segnale
+    fn begin_interruptible(&mut self, req: &Request<'_>) {
+        let (id, token) = self.interrupts.arm(req.pid());
+        self.backend.set_cancel_token(Some(token.clone()));
+        self.cancel = Some((id, token));
+    }
+
+    fn end_interruptible(&mut self) {
+        if let Some((id, _)) = self.cancel.take() {
+            self.interrupts.disarm(id);
+        }
+        self.backend.set_cancel_token(None);
+    }
+
+    // le read possono essere interrotte (Ctrl+C): vedi interrupt.rs
+    fn read_data(&mut self, ino: u64, fh: u64, offset: i64, size: u32, flags: i32, reply: ReplyData) {
+        let timer_start = Instant::now();
+        let ino = self.live_ino(ino);
+
+        if size == 0 { //come se avessi letto eof
+            reply.data(&[]);
+            return;
+        }
+        
+        if offset < 0 {
+            reply.error(EINVAL);
+            return;
+        }
+
+        if let Err(e) = self.check_mode(fh, false) {
+            reply.error(e);
+            return;
+        }
+        let Some(handle) = self.read_file_handles.get(&fh) else {
+            reply.error(EBADF);
+            return;
+        };
+        
+        match &handle.mode {
+            ReadMode::Stream(lane) => {
+                let cancel = self.cancel.as_ref().map(|(_, token)| token);
+                let read = StreamRead { ino, offset: offset as u64, size, flags, pid: 0 }; // il token è quello di begin_interruptible
+                match lane.state().read(&mut self.backend, &self.rt, cancel, &read) {
+                    Ok(data) => reply.data(&data),
+                    Err(code) => reply.error(code),
+                }
+            }
+            ReadMode::Direct => match self.backend.read_direct(ino, offset as u64, size as u64) {
+                Ok(data) => reply.data(&data[..data.len().min(size as usize)]),
+                Err(e) => reply.error(map_handle_error(&e)),
+            },
+            ReadMode::SmallPages => {
+                let want = size as u64;
+                match self.backend.read_chunk(ino, offset as u64, want) {
+                    Ok(mut data) => {
+                        if data.len() > want as usize {data.truncate(want as usize);}
+                        reply.data(&data);
+                    }
+                    Err(e) => reply.error(map_handle_error(&e)),
+                }
+            },
+        }
+
+        if self.speed_testing {
+            let duration = timer_start.elapsed();
+            if let Some(file) = self.speed_file.as_mut() {
+                use std::io::Write;
+                writeln!(file, "[speed] read of ino {} at offset {} with size {} duration: {:?}", ino, offset, size, duration).ok();
+            }
         }
+    }
 
-        Ok(())
+    // scrive una run di blocchi contigui che parte da offset
+    fn flush_run(&mut self, run: &[Bytes], ino: u64, offset: u64, progress: &TransferProgress) -> Result<(), BackendError> {
+        let ino = self.live_ino(ino);
+        send_run(&mut self.backend, ino, offset, run, self.io.large_file_size, progress)
     }
+}
 
-    fn flush_buffer(&mut self, buffer: &mut Vec<u8>, ino: u64, offset: u64) -> Result<(), BackendError> {
-        if !buffer.is_empty() {
-            if buffer.len() > LARGE_FILE_SIZE as usize {
-                self.backend.write_stream(ino, offset, buffer.clone())?
-            } else {
-                self.backend.write_chunk(ino, offset, buffer.clone())?;
+impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
+    fn init(&mut self,_req: &Request<'_>,config: &mut fuser::KernelConfig) -> Result<(), libc::c_int> { 
+        self.dir_parent.lock().expect("Mutex poisoned").insert(ROOT_INO, ROOT_INO); // la root ha come genitore se stessa
+        // O_TRUNC arriva nei flag di open invece che come setattr separata: troncamento e attributi in una sola chiamata
+        let _ = config.add_capabilities(consts::FUSE_ATOMIC_O_TRUNC);
+        // lock fcntl passati al filesystem e da lì al server; se il kernel non li accetta restano locali
+        if self.remote_locks && config.add_capabilities(consts::FUSE_POSIX_LOCKS).is_err() {
+            eprintln!("POSIX locks not supported by the kernel, fcntl locks stay local to this machine");
+        }
+
+        let tuning = self.kernel;
+        if tuning.writeback_cache {
+            self.writeback = config.add_capabilities(consts::FUSE_WRITEBACK_CACHE).is_ok();
+            if !self.writeback {
+                eprintln!("Writeback cache not supported by the kernel, writes go straight to the daemon");
+            }
+        }
+        // se il valore è fuori dai limiti si usa il più vicino accettato
+        if let Err(nearest) = config.set_max_write(tuning.max_write) {
+            let _ = config.set_max_write(nearest);
+        }
+        if let Err(nearest) = config.set_max_readahead(tuning.max_readahead) {
+            let _ = config.set_max_readahead(nearest);
+        }
+        let background = match config.set_max_background(tuning.max_background) {
+            Ok(_) => tuning.max_background,
+            Err(nearest) => {
+                let _ = config.set_max_background(nearest);
+                nearest
+            }
+        };
+        let _ = config.set_congestion_threshold(background / 4 * 3);
+
+        // write rimaste nel journal da una sessione terminata male
+        if let Some(journal) = self.journal.as_mut() {
+            match journal.replay(&mut self.backend) {
+                Ok(report) => {
+                    for (ino, bytes) in report.recovered.iter() {
+                        println!("Journal: recovered {} byte(s) of ino {}", bytes, ino);
+                    }
+                    for (ino, bytes, reason) in report.unrecoverable.iter() {
+                        eprintln!("Journal: lost {} byte(s) of ino {}: {}", bytes, ino, reason);
+                    }
+                }
+                Err(e) => eprintln!("Unable to replay write journal {}: {}", journal.path().display(), e),
             }
         }
-        buffer.clear();
         Ok(())
     }
-}
 
-impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
-    fn init(&mut self,_req: &Request<'_>,_config: &mut fuser::KernelConfig) -> Result<(), libc::c_int> { 
-        self.dir_parent.insert(1,1); // la root ha come genitore se stessa
-        Ok(())
+    // il kernel ha scartato l'inode: lo stato tenuto per lui non serve più
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, _nlookup: u64) {
+        self.replaced_inodes.lock().expect("Mutex poisoned").remove(&ino);
+        if ino != ROOT_INO {
+            self.dir_parent.lock().expect("Mutex poisoned").remove(&ino);
+        }
     }
 
     fn destroy(&mut self) {
-        // pulizia finale, se necessaria
+        // chiamata quando la sessione termina: le scritture non ancora inviate vanno svuotate prima di uscire
+        self.settle(None);
+        let pending = self.write_buffers.values().filter(|map| !map.is_empty()).count();
+        if pending > 0 {
+            println!("Flushing {} dirty file handle(s) before unmount...", pending);
+            let failed = self.drain_write_buffers(self.shutdown_timeout);
+            if failed.is_empty() {
+                println!("All dirty data flushed.");
+            } else {
+                match self.journal.as_ref() {
+                    Some(journal) => eprintln!("Unable to flush {} file handle(s), their pending writes are kept in {} and will be replayed at next mount:", failed.len(), journal.path().display()),
+                    None => eprintln!("Unable to flush {} file handle(s), their pending writes are lost:", failed.len()),
+                }
+                for (fh, ino, e) in failed.iter() {
+                    eprintln!("  fh {} (ino {}): {}", fh, ino, e);
+                }
+            }
+        }
+        self.write_buffers.clear();
+        self.update_dirty();
+        self.write_inodes.clear();
+        self.open_modes.clear();
         eprintln!("Fuse layer destroyed.");
     }
 
     fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
         let timer_start = Instant::now();
 
-        let metadata=match self.backend.lookup(parent,&name.to_string_lossy()) {
+        if self.junk.hides(&name.to_string_lossy()) || self.policy_action(req, parent, Some(name)) == Some(PolicyAction::Hidden) {
+            reply.error(ENOENT);
+            return;
+        }
+
+        // un file con write in coda ha sul server una dimensione vecchia: prima partono le write, sulla sessione
+        if let Some(workers) = &self.workers && !self.batch.holds(None) {
+            workers.lookup(parent, name.to_string_lossy().into_owned(), caller_ids(req), reply);
+            return;
+        }
+        let res = self.backend.lookup(parent, &name.to_string_lossy()).and_then(|entry| {
+            if !self.batch.holds(Some(entry.ino)) {
+                return Ok(entry);
+            }
+            self.settle(Some(entry.ino));
+            self.backend.get_attr(entry.ino)
+        });
+        let metadata=match res {
             Ok(entry) => {
-                self.dir_parent.insert(entry.ino, parent); // aggiorna la mappa del genitore
+                self.dir_parent.lock().expect("Mutex poisoned").insert(entry.ino, parent); // aggiorna la mappa del genitore
                 entry
             },
             Err(e) => {
@@ -219,8 +1341,8 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
             }
         };
 
-        let attr=entry_to_attr(&metadata,req);
-        reply.entry(&TTL_FILE, &attr, 0);
+        let attr=entry_to_attr(&metadata, caller_ids(req), self.io.block_size, &self.owners);
+        reply.entry(&self.entry_ttl(&metadata), &attr, 0);
         if self.speed_testing {
             let duration = timer_start.elapsed();
             if let Some(file) = self.speed_file.as_mut() {
@@ -233,11 +1355,26 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
     fn getattr(&mut self, req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
         let timer_start = Instant::now();
         //fh serve poi quando si fa read/write
-        match self.backend.get_attr(ino) {
+        let live = self.live_ino(ino);
+        self.settle(Some(live));
+        // senza lease la cache chiederebbe gli attributi al server: li chiede un worker
+        if let Some(workers) = &self.workers && self.backend.lease_left(live).is_none() {
+            workers.getattr(ino, caller_ids(req), reply);
+            return;
+        }
+        let res = match self.attr_of(ino) {
+            Ok(entry) if ino == ROOT_INO => {
+                *self.root.lock().expect("Mutex poisoned") = Some(entry.clone());
+                Ok(entry)
+            }
+            // la root non può sparire: offline si mostrano gli ultimi attributi noti invece di un errore
+            Err(e) if ino == ROOT_INO => self.root.lock().expect("Mutex poisoned").clone().ok_or(e),
+            res => res,
+        };
+        match res {
             Ok(entry) => {
-                let attr = entry_to_attr(&entry, req);
-                let ttl= if attr.kind == FileType::Directory { TTL_DIR } else { TTL_FILE };
-                reply.attr(&ttl, &attr);
+                let attr = entry_to_attr(&entry, caller_ids(req), self.io.block_size, &self.owners);
+                reply.attr(&self.attr_ttl(&entry), &attr);
             },
             Err(e) => {
                 reply.error(map_error(&e));
@@ -254,21 +1391,53 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
         }
     }
 
-    fn readdir(&mut self,_req: &Request<'_>,ino: u64,_fh: u64,offset: i64,mut reply: ReplyDirectory) {
-        let timer_start = Instant::now();
+    // spazio del volume dal server (GET /api/size), in blocchi della dimensione annunciata al kernel;
+    // il server non conta gli inode, quindi files e ffree restano a 0
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let bsize = self.io.block_size as u64;
+        match &self.workers {
+            Some(workers) => workers.statfs(bsize, reply),
+            None => reply_statfs(&mut self.backend, bsize, reply),
+        }
+    }
 
-        let entries = match self.backend.list_dir(ino) {
-            Ok(entries) => entries,
-            Err(e) => {
-                reply.error(map_error(&e));
-                return;
-            }
-        };
+    // access(2) valutato con uid e gruppi dell'utente del server: i processi locali agiscono tutti come lui.
+    // Senza identità risponde sempre ok e il controllo resta al server.
+    fn access(&mut self, _req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        match self.attr_of(ino) {
+            Ok(entry) => match &self.identity {
+                Some(me) if !me.allows(&entry, (mask & 0o7) as u16) => reply.error(libc::EACCES),
+                _ => reply.ok(),
+            },
+            Err(e) => reply.error(map_error(&e)),
+        }
+    }
 
-        // entries.sort_by(|a, b| a.name.cmp(&b.name)); // ordina le voci per nome
-        let mut off = offset;
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        self.settle(None);
+        if let Some(ticket) = self.barrier.pending() {
+            let report = self.run_barrier();
+            self.barrier.complete(ticket, report);
+        }
+        if let Some(code) = self.policy_denies(req, ino, None, false) {
+            reply.error(code);
+            return;
+        }
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.dir_streams.insert(fh, Arc::default());
+        reply.opened(fh, 0);
+    }
+
+    fn releasedir(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
+        self.dir_streams.remove(&fh);
+        reply.ok();
+    }
 
+    fn readdir(&mut self,req: &Request<'_>,ino: u64,fh: u64,offset: i64,mut reply: ReplyDirectory) {
+        let timer_start = Instant::now();
 
+        let mut off = offset;
         if off == 0 {
             // . 
             if reply.add(ino, 1, FileType::Directory, ".") {
@@ -276,7 +1445,7 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
                 return;
             }
 
-            let parent_ino = *self.dir_parent.get(&ino).unwrap_or(&ino);
+            let parent_ino = self.dir_parent.lock().expect("Mutex poisoned").get(&ino).copied().unwrap_or(ino);
             if reply.add(parent_ino, 2, FileType::Directory, "..") {
                 reply.ok();
                 return;
@@ -284,20 +1453,36 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
             off = 2;
         }
 
-        let start = (off - 2).max(0) as usize;
-        for (i, entry) in entries.iter().enumerate().skip(start) {
-            let ftype= match entry.kind {
-                EntryType::File => FileType::RegularFile,
-                EntryType::Directory => FileType::Directory,
-                EntryType::Symlink => FileType::Symlink,
-            };
-            // cookie stabile: 3 + index
-            if reply.add(entry.ino, (i as i64) + 3, ftype, &entry.name) {
-                break;
+        let stream = self.dir_streams.entry(fh).or_default().clone();
+        let index = (off - 2).max(0) as usize;
+        {
+            let mut stream = stream.lock().expect("Mutex poisoned");
+            if index < stream.base || offset == 0 {
+                *stream = DirStream::default(); // rewinddir o seek all'indietro: si riparte dalla prima pagina
             }
         }
+        if let Some(workers) = &self.workers {
+            workers.readdir(ino, stream, index, req.pid(), reply);
+            return;
+        }
 
-        reply.ok();
+        self.begin_interruptible(req);
+        let mut stream = stream.lock().expect("Mutex poisoned");
+        let (backend, junk, policy) = (&mut self.backend, &self.junk, &self.policy);
+        let res = fill_dir(&mut stream, ino, index, &mut reply, &self.listings, |cursor| {
+            let mut page = backend.list_dir_page(ino, None, cursor, DIR_PAGE_SIZE)?;
+            visible(&mut page.entries, junk, policy, req.pid());
+            Ok(page)
+        });
+        drop(stream);
+        self.end_interruptible();
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                reply.error(map_error(&e));
+                return;
+            }
+        }
 
         if self.speed_testing {
             let duration = timer_start.elapsed();
@@ -308,19 +1493,74 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
         }
     }
 
-    fn create(&mut self,req: &Request<'_>, parent: u64,name: &OsStr,_mode: u32,_umask: u32,_flags: i32,reply: ReplyCreate,) {
+    fn create(&mut self,req: &Request<'_>, parent: u64,name: &OsStr,mode: u32,umask: u32,flags: i32,reply: ReplyCreate,) {
         let timer_start = Instant::now();
 
-        match self.backend.create_file(parent, &name.to_string_lossy()) {
+        if self.junk.blocks(&name.to_string_lossy()) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        if let Some(code) = self.policy_denies(req, parent, Some(name), true) {
+            reply.error(code);
+            self.audit(req, "create", (parent, Some(name)), None, Err(code));
+            return;
+        }
+
+        let perm = self.create_modes.file(mode, umask);
+        // un file creato senza permesso di scrittura deve restare scrivibile dal file handle appena aperto:
+        // lo creiamo scrivibile per il proprietario e applichiamo i permessi richiesti al release
+        let mut deferred = perm & 0o200 == 0;
+        let create_perm = if deferred { perm | 0o200 } else { perm };
+        // la create lato server è atomica (fallisce se il file esiste), quindi O_EXCL è garantito dal server
+        let res = match self.backend.create_file_with_mode(parent, &name.to_string_lossy(), create_perm) {
+            Ok(mut entry) => {
+                entry.perms = perm as u16;
+                Ok(entry)
+            }
+            Err(BackendError::Conflict(_)) if flags & libc::O_EXCL == 0 => {
+                // creato da un altro client dopo la lookup del kernel: senza O_EXCL si apre quello esistente
+                deferred = false;
+                self.backend.lookup(parent, &name.to_string_lossy()).and_then(|entry| {
+                    if flags & libc::O_TRUNC != 0 {
+                        self.backend.set_attr(entry.ino, SetAttrRequest { size: Some(0), ..Default::default() })
+                    } else {
+                        Ok(entry)
+                    }
+                })
+            }
+            Err(e) => Err(e),
+        };
+        match res {
             Ok(entry) => {
-                let attr = entry_to_attr(&entry,req);
+                let attr = entry_to_attr(&entry, caller_ids(req), self.io.block_size, &self.owners);
                 let fh=self.next_fh;
-                self.write_buffers.insert(fh, BTreeMap::new()); // used for buffering writes
+                if deferred {
+                    self.deferred_modes.insert(fh, perm);
+                }
+                self.write_buffers.insert(fh, DirtyRanges::default()); // used for buffering writes
+                self.write_inodes.insert(fh, entry.ino);
+                let direct = self.is_direct(entry.ino, flags);
+                self.open_modes.insert(fh, self.access_mode(flags, direct));
+                self.track_open(fh, entry.ino);
                 self.next_fh += 1; // incrementa il file handle per il prossimo file
-                self.read_file_handles.insert(fh, ReadMode::SmallPages); // inizializza il
-                reply.created(&TTL_FILE, &attr, 0, fh, fuser::consts::FOPEN_DIRECT_IO); // FOPEN_KEEP_CACHE se vuoi mantenere la cache del kernel
+                let fuse_flags = if direct {
+                    self.direct_handles.insert(fh);
+                    self.read_file_handles.insert(fh, ReadHandle::new(ReadMode::Direct, false));
+                    consts::FOPEN_DIRECT_IO
+                } else {
+                    // con le scritture nella page cache il kernel legge pagine a offset qualsiasi: niente stream
+                    let streamable = !self.writeback && !self.trust_cache;
+                    self.read_file_handles.insert(fh, ReadHandle::new(ReadMode::SmallPages, streamable));
+                    self.write_open_flags()
+                };
+                reply.created(&self.entry_ttl(&entry), &attr, 0, fh, fuse_flags); // FOPEN_KEEP_CACHE se vuoi mantenere la cache del kernel
+                self.audit(req, "create", (parent, Some(name)), None, Ok(()));
+            }
+            Err(e) => {
+                let code = map_error(&e);
+                reply.error(code);
+                self.audit(req, "create", (parent, Some(name)), None, Err(code));
             }
-            Err(e) => reply.error(map_error(&e)),
         }
         if self.speed_testing {
             let duration = timer_start.elapsed();
@@ -331,16 +1571,34 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
         }
     }
 
-    fn mkdir(&mut self,req: &Request<'_>,parent: u64,name: &OsStr,_mode: u32,_umask: u32,reply: ReplyEntry) {
+    fn mkdir(&mut self,req: &Request<'_>,parent: u64,name: &OsStr,mode: u32,umask: u32,reply: ReplyEntry) {
         let timer_start = Instant::now();
 
-        match self.backend.create_dir(parent, &name.to_string_lossy()) {
+        if self.junk.blocks(&name.to_string_lossy()) {
+            reply.error(libc::EPERM);
+            return;
+        }
+
+        if let Some(code) = self.policy_denies(req, parent, Some(name), true) {
+            reply.error(code);
+            self.audit(req, "mkdir", (parent, Some(name)), None, Err(code));
+            return;
+        }
+
+        let perm = self.create_modes.dir(mode, umask);
+        let res = match self.backend.create_dir_with_mode(parent, &name.to_string_lossy(), perm) {
             Ok(entry) => {
-                let attr = entry_to_attr(&entry,req);
-                reply.entry(&TTL_DIR, &attr, 0);
+                let attr = entry_to_attr(&entry, caller_ids(req), self.io.block_size, &self.owners);
+                reply.entry(&self.entry_ttl(&entry), &attr, 0);
+                Ok(())
             }
-            Err(e) => reply.error(map_error(&e)),
-        }
+            Err(e) => {
+                let code = map_error(&e);
+                reply.error(code);
+                Err(code)
+            }
+        };
+        self.audit(req, "mkdir", (parent, Some(name)), None, res);
 
         if self.speed_testing {
             let duration = timer_start.elapsed();
@@ -351,15 +1609,19 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
         }
     }
 
-    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let timer_start = Instant::now();
 
-        match self.backend.delete_file(parent, &name.to_string_lossy()) {
-            Ok(_) => {
-                reply.ok();
-            }
-            Err(e) => reply.error(map_error(&e)),
+        self.settle(None);
+        let res = match self.policy_denies(req, parent, Some(name), true) {
+            Some(code) => Err(code),
+            None => self.backend.delete_file(parent, &name.to_string_lossy()).map_err(|e| map_error(&e)),
+        };
+        match res {
+            Ok(_) => reply.ok(),
+            Err(code) => reply.error(code),
         }
+        self.audit(req, "unlink", (parent, Some(name)), None, res);
 
         if self.speed_testing {
             let duration = timer_start.elapsed();
@@ -370,15 +1632,19 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
         }
     }
 
-    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let timer_start = Instant::now();
 
-        match self.backend.delete_dir(parent, &name.to_string_lossy()) {
-            Ok(_) => {
-                reply.ok();
-            }
-            Err(e) => reply.error(map_error(&e)),
+        self.settle(None);
+        let res = match self.policy_denies(req, parent, Some(name), true) {
+            Some(code) => Err(code),
+            None => self.backend.delete_dir(parent, &name.to_string_lossy()).map_err(|e| map_error(&e)),
+        };
+        match res {
+            Ok(_) => reply.ok(),
+            Err(code) => reply.error(code),
         }
+        self.audit(req, "rmdir", (parent, Some(name)), None, res);
 
         if self.speed_testing {
             let duration = timer_start.elapsed();
@@ -389,48 +1655,68 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
         }
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
         let timer_start = Instant::now();
-
-        if (flags & libc::O_TRUNC) != 0 {
-            let req = SetAttrRequest {
-                perm: None,
-                uid: None,
-                gid: None,
-                size: Some(0),
-                flags: None,
-            };
-            if let Err(e) = self.backend.set_attr(ino, req) {
-                reply.error(map_error(&e));
-                return;
+        self.drop_failed_opens();
+        let ino = self.live_ino(ino);
+        self.settle(Some(ino));
+
+        let writable = (flags & O_ACCMODE) == O_WRONLY || (flags & O_ACCMODE) == O_RDWR;
+        let op = if writable { "open-write" } else { "open" };
+        if let Some(code) = self.policy_denies(req, ino, None, writable) {
+            reply.error(code);
+            self.audit(req, op, (ino, None), None, Err(code));
+            return;
+        }
+        // attributi e controlli sul file li fa un worker, con l'handle già registrato. Restano qui il troncamento,
+        // che cambia il file, e le open da registrare nell'audit o da rifiutare per metadata_only
+        if self.workers.is_some() && (flags & libc::O_TRUNC == 0 || !writable) && self.audit.is_none() && !self.metadata_only {
+            let (fh, fuse_flags) = self.register_open(ino, flags, writable);
+            let open = PendingOpen { ino, fh, flags, hold: self.open_inodes.opened(fh, ino), fuse_flags };
+            if let Some(workers) = &self.workers {
+                workers.open(open, reply);
             }
+            return;
         }
-
-        let size = match self.backend.get_attr(ino) {
-            Ok(entry) => entry.size as u64,
+        // come chattr +i / +a: niente scritture su un file immutabile, solo in append su uno append-only
+        let protected = |entry: &FileEntry| writable
+            && (entry.file_flags & FILE_FLAG_IMMUTABLE != 0 || (entry.file_flags & FILE_FLAG_APPEND != 0 && flags & libc::O_APPEND == 0));
+        // il controllo sui flag va fatto prima di troncare, sugli attributi letti dal server
+        let res = self.backend.get_attr(ino).and_then(|entry| {
+            if (flags & libc::O_TRUNC) != 0 && writable && !protected(&entry) {
+                self.backend.set_attr(ino, SetAttrRequest { size: Some(0), ..Default::default() })
+            } else {
+                Ok(entry)
+            }
+        });
+        let size = match res {
+            Ok(entry) if protected(&entry) => {
+                reply.error(EPERM);
+                self.audit(req, op, (ino, None), None, Err(EPERM));
+                return;
+            }
+            // senza dati: un file non vuoto si legge solo dopo averlo pinnato
+            Ok(entry) if self.metadata_only && (flags & O_ACCMODE) != O_WRONLY && entry.size > 0 && !self.backend.has_local_copy(ino) => {
+                if self.refused_reads.insert(ino) {
+                    eprintln!("{}: content not fetched in a metadata-only mount, pin it to read it", entry.path);
+                }
+                reply.error(libc::ENODATA);
+                self.audit(req, op, (ino, None), None, Err(libc::ENODATA));
+                return;
+            }
+            Ok(entry) => entry.size,
             Err(e) => {
-                reply.error(map_error(&e));
+                let code = map_error(&e);
+                reply.error(code);
+                self.audit(req, op, (ino, None), None, Err(code));
                 return;
             }
         };
 
-        let fh = self.next_fh;
-        self.next_fh += 1;
-        let mut fuse_flags = consts::FOPEN_DIRECT_IO; // default, non usare cache del kernel
-        if (flags & O_ACCMODE) == O_RDONLY || (flags & O_ACCMODE) == O_RDWR {
-            let (ff, mode) = if size > LARGE_FILE_SIZE {
-                (consts::FOPEN_DIRECT_IO | FOPEN_NONSEEKABLE, ReadMode::LargeStream(StreamState::new()))
-            } else {
-                (consts::FOPEN_KEEP_CACHE, ReadMode::SmallPages)
-            };
-            fuse_flags = ff;
-            self.read_file_handles.insert(fh, mode);
-        }
-        if (flags & O_ACCMODE) == O_WRONLY || (flags & O_ACCMODE) == O_RDWR {
-            self.write_buffers.insert(fh, BTreeMap::new());
-            fuse_flags = consts::FOPEN_DIRECT_IO;
-        }
-        reply.opened(fh, fuse_flags); 
+        let (fh, fuse_flags) = self.register_open(ino, flags, writable);
+        self.track_open(fh, ino);
+        reply.opened(fh, if size > self.io.large_file_size { fuse_flags.1 } else { fuse_flags.0 }); 
+        self.audit(req, op, (ino, None), None, Ok(()));
 
         if self.speed_testing {
             let duration = timer_start.elapsed();
@@ -441,144 +1727,136 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
         }
     }
 
-    fn read(&mut self,_req: &Request<'_>,ino: u64,fh: u64,offset: i64,size: u32,flags: i32,_lock_owner: Option<u64>,reply: ReplyData,) {
-        let timer_start = Instant::now();
-
-        if size == 0 { //come se avessi letto eof
-            reply.data(&[]);
-            return;
+    fn read(&mut self,req: &Request<'_>,ino: u64,fh: u64,offset: i64,size: u32,flags: i32,_lock_owner: Option<u64>,reply: ReplyData,) {
+        if offset >= 0 && size > 0 && let Some(handle) = self.read_file_handles.get_mut(&fh) {
+            handle.track(offset as u64, size, self.stream_after);
         }
-        
-        if offset < 0 {
-            reply.error(EINVAL);
+        // con i worker le read in streaming non occupano la sessione: vedi workers.rs
+        if let (Some(workers), Some(ReadHandle { mode: ReadMode::Stream(lane), .. })) = (&self.workers, self.read_file_handles.get(&fh))
+            && size > 0 && offset >= 0 && self.check_mode(fh, false).is_ok()
+        {
+            let read = StreamRead { ino: self.live_ino(ino), offset: offset as u64, size, flags, pid: req.pid() };
+            // il worker registra anche la durata per lo speed testing
+            let speed_file = self.speed_file.as_ref().filter(|_| self.speed_testing).and_then(|f| f.try_clone().ok());
+            workers.stream_read(lane, read, reply, speed_file);
             return;
         }
-
-        let Some(mut handle) = self.read_file_handles.get_mut(&fh) else {
-            if self.write_buffers.contains_key(&fh) {
-                reply.error(EBADF);
-                return;
-            }
-            reply.error(ENOENT);
-            return;
-        };
-        
-        match &mut handle {
-            ReadMode::LargeStream(state) => {
-                let need= size as usize;
-                if offset as u64 != state.pos { 
-                    reply.error(libc::ESPIPE); 
-                    return; 
-                }
-                if state.stream.is_none() && !state.eof {
-                    match self.backend.read_stream(ino, state.pos) {
-                        Ok(stream) => {
-                            state.stream = Some(stream);
-                            state.buffer.clear(); // Pulisci il buffer per il nuovo stream
-                        }
-                        Err(e) => {
-                            reply.error(map_error(&e));
-                            return;
-                        }
-                    }
-                }
-
-                if (flags & libc::O_NONBLOCK) != 0 && state.buffer.is_empty() && !state.eof {
-                    reply.error(EAGAIN);
-                    return;
-                }
-
-                while state.buffer.len() < need && !state.eof {
-                    let Some(stream)=state.stream.as_mut() else {break};
-                    let next = self.rt.block_on(async { stream.next().await });
-                    match next {
-                        Some(Ok(bytes))=> {
-                            if !bytes.is_empty() {
-                                state.buffer.extend_from_slice(&bytes);
-                            }
-                        },
-                        Some(Err(e)) => { reply.error(map_error(&e)); return; }
-                        None => { // EOF server side
-                            state.eof = true;
-                            break;
-                        }
-                    }
-                }
-
-                if state.buffer.is_empty() {
-                    if !state.eof  && (flags & libc::O_NONBLOCK) != 0 {
-                        reply.error(EAGAIN);
-                    }
-                    else {
-                        reply.data(&[]);
-                    }
+        // a pagine e O_DIRECT: quello che la cache non ha già in memoria (o su disco, se pinnato) lo legge un worker
+        let direct = self.read_file_handles.get(&fh).and_then(|handle| match handle.mode {
+            ReadMode::Direct => Some(true),
+            ReadMode::SmallPages => Some(false),
+            ReadMode::Stream(_) => None,
+        });
+        if let (Some(workers), Some(direct)) = (&self.workers, direct) && size > 0 && offset >= 0 && self.check_mode(fh, false).is_ok() {
+            let read = StreamRead { ino: self.live_ino(ino), offset: offset as u64, size, flags, pid: req.pid() };
+            if direct || !self.backend.has_local_copy(read.ino) {
+                if !direct && let Some(data) = self.backend.cached_read(read.ino, read.offset, size as u64) {
+                    reply.data(&data);
                     return;
                 }
-
-                let take = need.min(state.buffer.len());
-                let out:Vec<u8>  = state.buffer.drain(..take).collect();
-                state.pos = state.pos.saturating_add(take as u64);
-                reply.data(&out);
+                let entry = if direct { None } else { self.backend.known_attr(read.ino) };
+                let speed_file = self.speed_file.as_ref().filter(|_| self.speed_testing).and_then(|f| f.try_clone().ok());
+                workers.read(read, entry, direct, reply, speed_file);
+                return;
             }
-            ReadMode::SmallPages => {
-                let want = size as u64;
-                match self.backend.read_chunk(ino, offset as u64, want) {
-                    Ok(mut data) => {
-                        if data.len() > want as usize {data.truncate(want as usize);}
-                        reply.data(&data);
-                    }
-                    Err(e) => reply.error(map_error(&e)),
-                }
-            },
         }
+        self.begin_interruptible(req);
+        self.read_data(ino, fh, offset, size, flags, reply);
+        self.end_interruptible();
+    }
 
-        if self.speed_testing {
-            let duration = timer_start.elapsed();
-            if let Some(file) = self.speed_file.as_mut() {
-                use std::io::Write;
-                writeln!(file, "[speed] read of ino {} at offset {} with size {} duration: {:?}", ino, offset, size, duration).ok();
+    fn release(&mut self, _req: &Request<'_>, ino: u64, fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
+        let ino = self.live_ino(ino);
+        // di norma flush è già arrivato, ma su smontaggio/abort il kernel può mandare solo release
+        let dirty = self.write_buffers.get(&fh).is_some_and(|map| !map.is_empty());
+        let mut res = if dirty { self.flush_file(fh, ino) } else { Ok(()) };
+        if let Some(perm) = self.deferred_modes.remove(&fh) && res.is_ok() {
+            self.settle(Some(ino));
+            res = self.backend.set_attr(ino, SetAttrRequest { perm: Some(perm), ..Default::default() }).map(|_| ());
+        }
+
+        if self.conflicts.remove(&fh) {
+            match self.journal.as_ref() {
+                Some(journal) => eprintln!("Writes of fh {} (ino {}) conflict with another client: kept in {}, run `check` to review them", fh, ino, journal.path().display()),
+                None => eprintln!("Writes of fh {} (ino {}) conflict with another client and are lost (journal disabled)", fh, ino),
             }
         }
-    }
 
-    fn release(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _flags: i32, _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
         // Rimuoviamo il file handle dalla mappa, basta per fare drop automatico della stream e chiuderla immediatamente
         self.read_file_handles.remove(&fh);
+        self.direct_handles.remove(&fh);
         self.write_buffers.remove(&fh); // rimuove anche il buffer di scrittura, se esiste
-        reply.ok();
+        self.write_inodes.remove(&fh);
+        self.open_modes.remove(&fh);
+        // ultimo handle: se nel frattempo è stato cancellato, il server può eliminarne il contenuto, e il
+        // lease non serve più a nessuno (con la trust cache sì: il kernel tiene le pagine fino alla sua scadenza)
+        if let Some(ino) = self.open_inodes.closed(fh) {
+            if let Err(e) = self.backend.release_open(ino) {
+                eprintln!("Cannot release open ino {} on the server: {}", ino, e);
+            }
+            if !self.trust_cache && let Err(e) = self.backend.release_lease(ino) {
+                eprintln!("Cannot release the lease on ino {}: {}", ino, e);
+            }
+        }
+        self.update_dirty();
+        // un invio fallito mentre la memoria era piena va comunque riportato a chi chiude il file
+        if let Some(code) = self.flush_errors.remove(&fh) && res.is_ok() {
+            reply.error(code);
+            return;
+        }
+        match res {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                eprintln!("Flush on release of fh {} failed: {}", fh, e);
+                reply.error(map_handle_error(&e));
+            }
+        }
     }
 
     fn write(&mut self,_req: &Request<'_>,ino: u64, fh: u64,offset: i64,data: &[u8],_write_flags: u32,flags: i32,_lock_owner: Option<u64>,reply: ReplyWrite,) {
         let timer_start = Instant::now();
 
-        if !self.write_buffers.contains_key(&fh) {
-            reply.error(ENOENT);
+        if let Err(e) = self.check_mode(fh, true) {
+            reply.error(e);
             return;
         }
 
-        let mut off= offset as u64;
-        if flags & libc::O_APPEND != 0 {
-            match self.backend.get_attr(ino) {
-                Ok(entry) => off = entry.size,
-                Err(e) => {
-                    reply.error(map_error(&e));
-                    return;
-                }
+        let ino = self.live_ino(ino);
+        let off= offset as u64;
+        // con la writeback cache gli offset delle append li calcola già il kernel
+        if flags & libc::O_APPEND != 0 && !self.writeback {
+            // in append l'offset lo sceglie il server: niente buffer, la scrittura va subito in coda al file
+            let dirty = self.write_buffers.get(&fh).is_some_and(|map| !map.is_empty());
+            if !dirty && let Some(workers) = &self.workers {
+                workers.append(ino, data.to_vec(), reply);
+                return;
             }
-        }
-        
-        if self.write_buffers.get(&fh).is_none() {
+            let res = if dirty { self.flush_file(fh, ino) } else { Ok(()) };
+            match res.and_then(|_| self.backend.write_append(ino, data.to_vec())) {
+                Ok(_) => reply.written(data.len() as u32),
+                Err(e) => reply.error(map_handle_error(&e)),
+            }
+        } else if !self.write_buffers.contains_key(&fh) {
             reply.error(EBADF); // File handle not found
         } else {
             // Scope to limit the mutable borrow of write_buffers
             if let Some(buffer)= self.write_buffers.get_mut(&fh) {
                 buffer.insert(off, data.to_vec());
+                if let Some(journal) = self.journal.as_mut() && let Err(e) = journal.record_write(fh, ino, off, data) {
+                    eprintln!("Write journal error: {}", e);
+                }
             }
             else{
                 reply.error(EBADF);
                 return;
             }
-            reply.written(data.len() as u32);
+            self.update_dirty();
+            // senza cache la write arriva al server prima della risposta
+            let res = if self.direct_handles.contains(&fh) { self.flush_file(fh, ino) } else { self.relieve_pressure(fh) };
+            match res {
+                Ok(()) => reply.written(data.len() as u32),
+                Err(e) => reply.error(map_handle_error(&e)),
+            }
         }
         
         if self.speed_testing {
@@ -590,15 +1868,36 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
         }
     }
 
-    fn rename(&mut self,_req: &Request<'_>,parent: u64,name: &OsStr,new_parent: u64,new_name: &OsStr,_flags: u32,reply: ReplyEmpty,) {
+    fn rename(&mut self,req: &Request<'_>,parent: u64,name: &OsStr,new_parent: u64,new_name: &OsStr,_flags: u32,reply: ReplyEmpty,) {
         let timer_start = Instant::now();
+        self.settle(None);
+        let (name_str, new_name_str) = (name.to_string_lossy(), new_name.to_string_lossy());
+        if self.junk.blocks(&new_name_str) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        // la voce cambia nome in entrambe le posizioni
+        let denied = self.policy_denies(req, parent, Some(name), true).or_else(|| self.policy_denies(req, new_parent, Some(new_name), true));
+        if let Some(code) = denied {
+            reply.error(code);
+            self.audit(req, "rename", (parent, Some(name)), Some((new_parent, new_name)), Err(code));
+            return;
+        }
 
-        match self.backend.rename(parent, &name.to_string_lossy(), new_parent, &new_name.to_string_lossy()) {
-            Ok(_) => {
-                reply.ok();
+        // sopra una destinazione esistente (salvataggio atomico degli editor) il server fa una rename atomica:
+        // l'ino resta quello del file rinominato e gli handle aperti continuano a funzionare
+        let res = self.backend.rename(parent, &name_str, new_parent, &new_name_str).map(|entry| {
+            // una directory spostata altrove ha un nuovo ".."
+            if entry.kind == EntryType::Directory {
+                self.dir_parent.lock().expect("Mutex poisoned").insert(entry.ino, new_parent);
             }
-            Err(e) => reply.error(map_error(&e)),
+        });
+        let res = res.map_err(|e| map_error(&e));
+        match res {
+            Ok(()) => reply.ok(),
+            Err(code) => reply.error(code),
         }
+        self.audit(req, "rename", (parent, Some(name)), Some((new_parent, new_name)), res);
 
         if self.speed_testing {
             let duration = timer_start.elapsed();
@@ -628,25 +1927,46 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
         reply: ReplyAttr,
     ) {
         let timer_start = Instant::now();
+        let ino = self.live_ino(ino);
+        self.settle(Some(ino));
 
-        let perm=mode.map(|m| m & 0o777); // mantiengo solo i permessi, non il setuid/setgid
+        let perm = mode.map(|m| m & MODE_BITS); // setuid, setgid e sticky compresi
 
+        let (uid, gid) = self.owners.server_ids(uid, gid);
         let new_set_attr = SetAttrRequest {
             perm,
             uid,
             gid,
             size,
-            flags, // flags non sono supportati in questo momento, ancora da implementare
+            flags: None, // attributi DOS, li imposta solo Windows
+            file_flags: flags.map(|f| from_system_flags(f, [UF_IMMUTABLE | SF_IMMUTABLE, UF_APPEND | SF_APPEND, UF_NODUMP])),
+            atime: None,
+            mtime: None,
+            btime: None,
         };
 
-        match self.backend.set_attr(ino, new_set_attr) {
+        let op = if size.is_some() { "truncate" } else { "setattr" };
+        if let Some(code) = self.policy_denies(req, ino, None, true) {
+            reply.error(code);
+            self.audit(req, op, (ino, None), None, Err(code));
+            return;
+        }
+        let res = match self.backend.set_attr(ino, new_set_attr) {
             Ok(entry) => {
-                let attr = entry_to_attr(&entry,req);
-                let ttl= if entry.kind == EntryType::Directory {TTL_DIR} else {TTL_FILE};
-                reply.attr(&ttl, &attr);
+                if let Some(size) = size {
+                    self.truncate_buffers(ino, size);
+                }
+                let attr = entry_to_attr(&entry, caller_ids(req), self.io.block_size, &self.owners);
+                reply.attr(&self.attr_ttl(&entry), &attr);
+                Ok(())
             }
-            Err(e) => reply.error(map_error(&e)),
-        }
+            Err(e) => {
+                let code = map_error(&e);
+                reply.error(code);
+                Err(code)
+            }
+        };
+        self.audit(req, op, (ino, None), None, res);
 
         if self.speed_testing {
             let duration = timer_start.elapsed();
@@ -657,23 +1977,65 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
         }
     }
 
+    // lsattr/chattr su Linux: il kernel traduce FS_IOC_GETFLAGS/SETFLAGS in ioctl verso il filesystem
+    fn ioctl(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, _flags: u32, cmd: u32, in_data: &[u8], _out_size: u32, reply: ReplyIoctl) {
+        let ino = self.live_ino(ino);
+        let linux_flags = [FS_IMMUTABLE_FL, FS_APPEND_FL, FS_NODUMP_FL];
+        match cmd {
+            FS_IOC_GETFLAGS => match self.backend.get_attr(ino) {
+                Ok(entry) => reply.ioctl(0, &to_system_flags(entry.file_flags, linux_flags).to_ne_bytes()),
+                Err(e) => reply.error(map_error(&e)),
+            },
+            FS_IOC_SETFLAGS => {
+                let Some(bytes) = in_data.get(..4) else {
+                    reply.error(EINVAL);
+                    return;
+                };
+                let requested = u32::from_ne_bytes(bytes.try_into().expect("4 bytes"));
+                if requested & !(FS_IMMUTABLE_FL | FS_APPEND_FL | FS_NODUMP_FL) != 0 {
+                    reply.error(libc::EOPNOTSUPP);
+                    return;
+                }
+                let attrs = SetAttrRequest { file_flags: Some(from_system_flags(requested, linux_flags)), ..Default::default() };
+                match self.backend.set_attr(ino, attrs) {
+                    Ok(_) => reply.ioctl(0, &[]),
+                    Err(e) => reply.error(map_error(&e)),
+                }
+            }
+            _ => reply.error(libc::ENOTTY),
+        }
+    }
+
     // called when a fd closes (and not only!)
-    fn flush(&mut self,_req: &Request<'_>, ino: u64, fh: u64,_lock_owner: u64, reply: ReplyEmpty) {
+    fn flush(&mut self,_req: &Request<'_>, ino: u64, fh: u64,lock_owner: u64, reply: ReplyEmpty) {
 
         let timer_start = Instant::now();
         
-        if self.write_buffers.contains_key(&fh) {
+        let outcome = if self.defer_flush(fh, ino) {
+            // il close ritorna prima dell'invio: le write devono almeno essere su disco nel journal
+            if let Some(journal) = self.journal.as_mut() && let Err(e) = journal.sync() {
+                eprintln!("Write journal error: {}", e);
+            }
+            self.flush_errors.remove(&fh)
+        } else if self.write_buffers.contains_key(&fh) {
             match self.flush_file(fh, ino) {
-                Ok(_bytes_written) => {
-                    reply.ok()
-                },
-                Err(e) => {
-                    reply.error(map_error(&e));
-                }
+                // un invio fatto prima, mentre la memoria era piena, può essere fallito
+                Ok(_bytes_written) => self.flush_errors.remove(&fh),
+                Err(e) => Some(map_handle_error(&e)),
             }
-
         } else {
-            reply.ok(); // nothing to flush
+            None // nothing to flush
+        };
+        // chiudere un descrittore rilascia i lock fcntl del processo sul file, dopo l'invio delle sue write
+        if self.locked_owners.remove(&(ino, lock_owner)) {
+            let unlock = ByteLock { kind: LockKind::Unlock, start: 0, end: None, owner: lock_owner, pid: 0 };
+            if let Err(e) = self.backend.set_lock(self.live_ino(ino), &unlock) {
+                eprintln!("Cannot release the locks on ino {}: {}", ino, e);
+            }
+        }
+        match outcome {
+            Some(code) => reply.error(code),
+            None => reply.ok(),
         }
 
         if self.speed_testing {
@@ -686,20 +2048,69 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
 
     }
 
+    fn getlk(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, reply: ReplyLock) {
+        let Some(lock) = byte_lock(typ, start, end, lock_owner, pid) else {
+            reply.error(EINVAL);
+            return;
+        };
+        match self.backend.test_lock(self.live_ino(ino), &lock) {
+            Ok(Some(holder)) => {
+                let (typ, end) = kernel_lock(&holder);
+                reply.locked(holder.start, end, typ, holder.pid);
+            }
+            Ok(None) => reply.locked(start, end, UNLCK, pid),
+            Err(e) => reply.error(map_error(&e)),
+        }
+    }
+
+    fn setlk(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, lock_owner: u64, start: u64, end: u64, typ: i32, pid: u32, sleep: bool, reply: ReplyEmpty) {
+        let Some(lock) = byte_lock(typ, start, end, lock_owner, pid) else {
+            reply.error(EINVAL);
+            return;
+        };
+        // anche un lock ancora in attesa va rilasciato al close, se nel frattempo viene concesso
+        if lock.kind != LockKind::Unlock {
+            self.locked_owners.insert((ino, lock_owner));
+        }
+        let live = self.live_ino(ino);
+        match self.backend.set_lock(live, &lock) {
+            Ok(()) => reply.ok(),
+            Err(BackendError::Locked(_)) if sleep => match self.flush_backends.clone() {
+                Some(backends) => wait_for_lock(backends, self.interrupts.clone(), live, lock, reply),
+                None => reply.error(libc::EAGAIN),
+            },
+            Err(e) => reply.error(map_error(&e)),
+        }
+    }
+
     fn link(&mut self, req: &Request<'_>, ino: u64, new_parent: u64, new_name: &OsStr,reply: ReplyEntry) {
         let timer_start = Instant::now();
+        let ino = self.live_ino(ino);
+        self.settle(Some(ino));
+        if self.junk.blocks(&new_name.to_string_lossy()) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        if let Some(code) = self.policy_denies(req, new_parent, Some(new_name), true) {
+            reply.error(code);
+            self.audit(req, "link", (ino, None), Some((new_parent, new_name)), Err(code));
+            return;
+        }
 
         let entry = match self.backend.link(ino, new_parent, &new_name.to_string_lossy()) {
             Ok(entry) => entry,
             Err(e) => {
-                reply.error(map_error(&e));
+                let code = map_error(&e);
+                reply.error(code);
+                self.audit(req, "link", (ino, None), Some((new_parent, new_name)), Err(code));
                 return;
             }
         };
+        self.audit(req, "link", (ino, None), Some((new_parent, new_name)), Ok(()));
 
-        let attr = entry_to_attr(&entry, req);
+        let attr = entry_to_attr(&entry, caller_ids(req), self.io.block_size, &self.owners);
 
-        reply.entry(&TTL_FILE, &attr, 0);
+        reply.entry(&self.entry_ttl(&entry), &attr, 0);
 
         if self.speed_testing {
             let duration = timer_start.elapsed();
@@ -712,6 +2123,15 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
 
     fn symlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, link: &Path, reply: ReplyEntry) {
         let timer_start = Instant::now();
+        if self.junk.blocks(&name.to_string_lossy()) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        if let Some(code) = self.policy_denies(req, parent, Some(name), true) {
+            reply.error(code);
+            self.audit(req, "symlink", (parent, Some(name)), None, Err(code));
+            return;
+        }
 
         let mount_root = self.mounting_point.clone();
         let link_str = link.to_string_lossy();
@@ -730,14 +2150,17 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
         let entry = match self.backend.symlink(&target_path, parent, &name.to_string_lossy()) {
             Ok(entry) => entry,
             Err(e) => {
-                reply.error(map_error(&e));
+                let code = map_error(&e);
+                reply.error(code);
+                self.audit(req, "symlink", (parent, Some(name)), None, Err(code));
                 return;
             }
         };
+        self.audit(req, "symlink", (parent, Some(name)), None, Ok(()));
 
-        let attr = entry_to_attr(&entry, req);
+        let attr = entry_to_attr(&entry, caller_ids(req), self.io.block_size, &self.owners);
 
-        reply.entry(&TTL_FILE, &attr, 0);
+        reply.entry(&self.entry_ttl(&entry), &attr, 0);
 
         if self.speed_testing {
             let duration = timer_start.elapsed();
@@ -777,3 +2200,39 @@ impl<B: RemoteBackend> Filesystem for RemoteFS<B> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{map_error, map_handle_error};
+    use rfs_models::BackendError;
+
+    // risposta del server -> errno visto dal processo, sul path e su un file già aperto
+    #[test]
+    fn status_to_errno_table() {
+        let table: [(u16, Option<&str>, libc::c_int, libc::c_int); 16] = [
+            (404, None, libc::ENOENT, libc::ESTALE),
+            (410, None, libc::ENOENT, libc::ESTALE),
+            (400, Some("ENOTDIR"), libc::ENOTDIR, libc::ENOTDIR),
+            (400, Some("EISDIR"), libc::EISDIR, libc::EISDIR),
+            (400, None, libc::EINVAL, libc::EINVAL),
+            (401, None, libc::EPERM, libc::EPERM),
+            (403, Some("EPERM"), libc::EPERM, libc::EPERM),
+            (403, None, libc::EACCES, libc::EACCES),
+            (409, Some("ENOTEMPTY"), libc::ENOTEMPTY, libc::ENOTEMPTY),
+            (409, Some("EEXIST"), libc::EEXIST, libc::EEXIST),
+            (412, None, libc::ESTALE, libc::ESTALE),
+            (413, None, libc::EFBIG, libc::EFBIG),
+            (429, None, libc::EAGAIN, libc::EAGAIN),
+            (501, None, libc::ENOTSUP, libc::ENOTSUP),
+            (503, None, libc::EHOSTUNREACH, libc::EHOSTUNREACH),
+            (507, None, libc::ENOSPC, libc::ENOSPC),
+        ];
+        for (status, error, by_path, on_handle) in table {
+            let e = BackendError::from_status(status, "/f", error);
+            assert_eq!(map_error(&e), by_path, "HTTP {} with {:?}", status, error);
+            assert_eq!(map_handle_error(&e), on_handle, "HTTP {} with {:?} on a handle", status, error);
+        }
+        assert_eq!(map_error(&BackendError::from_status(500, "/f", None)), libc::EIO);
+        assert_eq!(map_error(&BackendError::from_status(418, "/f", None)), libc::EIO);
+    }
+}
@@ -0,0 +1,98 @@
+// Lock fcntl (F_GETLK, F_SETLK, F_SETLKW) condivisi fra i client. Con FUSE_POSIX_LOCKS il kernel non tiene
+// più i lock per conto suo e li passa tutti al filesystem, che li chiede al server: un lock preso su una
+// macchina blocca anche i processi degli altri mount. Il server distingue gli owner della stessa sessione,
+// quindi anche i conflitti fra processi dello stesso mount passano da lì.
+// F_SETLKW non può fermare il thread della sessione: il lock viene richiesto di nuovo da un thread a parte,
+// con un backend indipendente, finché non viene concesso o al processo non arriva un segnale.
+
+use crate::flush::FlushBackends;
+use crate::interrupt::InterruptWatcher;
+use fuser::ReplyEmpty;
+use rfs_models::{BackendError, ByteLock, LockKind};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// attesa fra due richieste di un F_SETLKW, raddoppiata fino al massimo
+const RETRY_MIN: Duration = Duration::from_millis(50);
+const RETRY_MAX: Duration = Duration::from_millis(500);
+// fine dell'intervallo che il kernel usa per "fino alla fine del file" (OFFSET_MAX)
+const TO_EOF: u64 = i64::MAX as u64;
+// tipi dei lock come li passa fuser; su macOS libc li definisce come c_short
+#[allow(clippy::unnecessary_cast)]
+const RDLCK: i32 = libc::F_RDLCK as i32;
+#[allow(clippy::unnecessary_cast)]
+const WRLCK: i32 = libc::F_WRLCK as i32;
+#[allow(clippy::unnecessary_cast)]
+pub(crate) const UNLCK: i32 = libc::F_UNLCK as i32;
+
+/// Lock descritto dal kernel; None se il tipo non è F_RDLCK, F_WRLCK o F_UNLCK
+pub(crate) fn byte_lock(typ: i32, start: u64, end: u64, owner: u64, pid: u32) -> Option<ByteLock> {
+    let kind = match typ {
+        RDLCK => LockKind::Read,
+        WRLCK => LockKind::Write,
+        UNLCK => LockKind::Unlock,
+        _ => return None,
+    };
+    Some(ByteLock { kind, start, end: (end < TO_EOF).then_some(end), owner, pid })
+}
+
+/// Tipo e fine dell'intervallo di `lock` come li vuole ReplyLock
+pub(crate) fn kernel_lock(lock: &ByteLock) -> (i32, u64) {
+    let typ = match lock.kind {
+        LockKind::Read => RDLCK,
+        LockKind::Write => WRLCK,
+        LockKind::Unlock => UNLCK,
+    };
+    (typ, lock.end.unwrap_or(TO_EOF))
+}
+
+/// F_SETLKW in conflitto: risponde al kernel quando il server concede il lock, con EINTR se il processo
+/// riceve un segnale prima
+pub(crate) fn wait_for_lock(backends: FlushBackends, interrupts: Arc<InterruptWatcher>, ino: u64, lock: ByteLock, reply: ReplyEmpty) {
+    let spawned = thread::Builder::new()
+        .name("rfs-lock-wait".to_string())
+        .spawn(move || {
+            let mut backend = backends();
+            let (id, token) = interrupts.arm(lock.pid);
+            let mut wait = RETRY_MIN;
+            let outcome = loop {
+                thread::sleep(wait);
+                if token.is_cancelled() {
+                    break Err(libc::EINTR);
+                }
+                match backend.set_lock(ino, &lock) {
+                    Ok(()) => break Ok(()),
+                    Err(BackendError::Locked(_)) => wait = (wait * 2).min(RETRY_MAX),
+                    Err(e) => {
+                        eprintln!("Cannot lock ino {}: {}", ino, e);
+                        break Err(crate::map_error(&e));
+                    }
+                }
+            };
+            interrupts.disarm(id);
+            match outcome {
+                Ok(()) => reply.ok(),
+                Err(code) => reply.error(code),
+            }
+        });
+    if let Err(e) = spawned {
+        eprintln!("Cannot wait for the lock on ino {}: {}", ino, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whole_file_lock_has_no_end() {
+        let lock = byte_lock(WRLCK, 0, TO_EOF, 7, 42).expect("valid lock");
+        assert_eq!(lock, ByteLock { kind: LockKind::Write, start: 0, end: None, owner: 7, pid: 42 });
+        assert_eq!(kernel_lock(&lock), (WRLCK, TO_EOF));
+
+        let range = byte_lock(RDLCK, 10, 19, 7, 42).expect("valid lock");
+        assert_eq!(range.end, Some(19));
+        assert!(byte_lock(-1, 0, 0, 7, 42).is_none());
+    }
+}
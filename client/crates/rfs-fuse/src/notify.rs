@@ -0,0 +1,91 @@
+// Notifiche al kernel delle modifiche fatte da altri client, guidate dal canale dei recall del server:
+// il server richiama il lease su un file quando cambia e su una directory quando cambia la sua lista.
+// Per un file si invalidano attributi e pagine (inval_inode); per una directory la nuova lista viene
+// confrontata con l'ultima servita a readdir: le voci sparite vengono notificate con delete, che il
+// kernel riporta agli watcher inotify, quelle nuove con inval_entry, così una lookup negativa rimasta
+// in cache non le nasconde.
+
+use fuser::Notifier;
+use lru::LruCache;
+use rfs_models::{FileEntry, RemoteBackend};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::ErrorKind;
+use std::num::NonZeroUsize;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// directory di cui ricordiamo l'ultima lista servita
+const LISTINGS_CAP: usize = 1024;
+
+/// Ultima lista (nome -> ino) servita a readdir per ciascuna directory
+#[derive(Clone)]
+pub struct DirListings(Arc<Mutex<LruCache<u64, HashMap<String, u64>>>>);
+
+impl Default for DirListings {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(LISTINGS_CAP).expect("non-zero capacity")))))
+    }
+}
+
+impl DirListings {
+    pub(crate) fn record(&self, dir: u64, entries: &[FileEntry]) {
+        let names = entries.iter().map(|e| (e.name.clone(), e.ino)).collect();
+        self.0.lock().expect("Mutex poisoned").put(dir, names);
+    }
+
+    fn previous(&self, dir: u64) -> Option<HashMap<String, u64>> {
+        self.0.lock().expect("Mutex poisoned").peek(&dir).cloned()
+    }
+
+    fn forget(&self, dir: u64) {
+        self.0.lock().expect("Mutex poisoned").pop(&dir);
+    }
+}
+
+/// Avvia il thread che traduce gli ino richiamati dal server in notifiche al kernel.
+/// Termina quando il canale dei recall viene chiuso.
+pub fn spawn_change_notifier<B: RemoteBackend + 'static>(mut backend: B, recalls: Receiver<u64>, listings: DirListings, notifier: Notifier) -> JoinHandle<()> {
+    thread::Builder::new()
+        .name("rfs-change-notify".to_string())
+        .spawn(move || {
+            for ino in recalls {
+                if let Some(previous) = listings.previous(ino) {
+                    match backend.list_dir(ino) {
+                        Ok(entries) => {
+                            notify_dir_changes(&notifier, ino, &previous, &entries);
+                            listings.record(ino, &entries);
+                        }
+                        Err(e) => {
+                            eprintln!("Cannot list changed directory {}: {}", ino, e);
+                            listings.forget(ino);
+                        }
+                    }
+                }
+                report(notifier.inval_inode(ino, 0, 0), ino);
+            }
+        })
+        .expect("Unable to start the change notifier")
+}
+
+fn notify_dir_changes(notifier: &Notifier, dir: u64, previous: &HashMap<String, u64>, entries: &[FileEntry]) {
+    let current: HashMap<&str, u64> = entries.iter().map(|e| (e.name.as_str(), e.ino)).collect();
+    for (name, child) in previous {
+        if current.get(name.as_str()) != Some(child) {
+            report(notifier.delete(dir, *child, OsStr::new(name)), dir);
+        }
+    }
+    for (name, child) in current {
+        if previous.get(name) != Some(&child) {
+            report(notifier.inval_entry(dir, OsStr::new(name)), dir);
+        }
+    }
+}
+
+// ENOENT vuol dire che il kernel non aveva la voce in cache: niente da invalidare
+fn report(res: std::io::Result<()>, ino: u64) {
+    if let Err(e) = res && e.kind() != ErrorKind::NotFound {
+        eprintln!("Change notification for ino {} failed: {}", ino, e);
+    }
+}
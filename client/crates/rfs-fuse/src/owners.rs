@@ -0,0 +1,99 @@
+// Proprietari mostrati dal mount. Il kernel (e quindi ls -l) conosce solo uid e gid numerici e li traduce
+// con gli utenti locali: gli id del server non dicono niente sul client. Con i nomi del server un utente o
+// un gruppo che ha lo stesso nome in locale (/etc/passwd, /etc/group) viene mostrato con l'id locale, e un
+// chown verso l'id locale torna a quello del server; senza un corrispondente resta l'id del server.
+
+use rfs_models::{FileEntry, Identity, OwnerNames};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Corrispondenze fra gli id del server e quelli locali con lo stesso nome
+#[derive(Debug, Clone, Default)]
+pub struct OwnerIds {
+    // server -> locale e locale -> server
+    users: HashMap<u32, u32>,
+    groups: HashMap<u32, u32>,
+    local_users: HashMap<u32, u32>,
+    local_groups: HashMap<u32, u32>,
+}
+
+impl OwnerIds {
+    pub fn from_names(names: &OwnerNames) -> Self {
+        let local_users = local_ids("/etc/passwd");
+        let local_groups = local_ids("/etc/group");
+        let users: HashMap<u32, u32> = names.users.iter()
+            .filter_map(|(uid, name)| local_users.get(name).map(|local| (*uid, *local)))
+            .collect();
+        let groups: HashMap<u32, u32> = names.groups.iter()
+            .filter_map(|(gid, name)| local_groups.get(name).map(|local| (*gid, *local)))
+            .collect();
+        Self {
+            local_users: users.iter().map(|(server, local)| (*local, *server)).collect(),
+            local_groups: groups.iter().map(|(server, local)| (*local, *server)).collect(),
+            users,
+            groups,
+        }
+    }
+
+    /// Quanti utenti e gruppi del server hanno un corrispondente locale
+    pub fn mapped(&self) -> (usize, usize) {
+        (self.users.len(), self.groups.len())
+    }
+}
+
+// nome -> id dai file in formato passwd/group (`nome:x:id:...`)
+fn local_ids(path: &str) -> HashMap<String, u32> {
+    let Ok(raw) = std::fs::read_to_string(path) else { return HashMap::new() };
+    raw.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let id = fields.nth(1)?.parse().ok()?;
+            Some((name.to_string(), id))
+        })
+        .collect()
+}
+
+/// Come presentare i proprietari al kernel
+#[derive(Clone)]
+pub(crate) struct Owners {
+    // con default_permissions: uid e gruppi del server da presentare come quelli del processo
+    pub(crate) identity: Option<Identity>,
+    // aggiornate da chi ha montato il filesystem
+    pub(crate) local: Option<Arc<RwLock<OwnerIds>>>,
+}
+
+impl Owners {
+    /// uid e gid con cui mostrare `entry` al processo che li chiede (`caller`: i suoi uid e gid)
+    pub(crate) fn attr_ids(&self, entry: &FileEntry, caller: (u32, u32)) -> (u32, u32) {
+        // su macOS usa l’UID/GID della request; con default_permissions i file e i gruppi dell'utente del
+        // server diventano quelli del processo, così il kernel applica i bit giusti
+        if cfg!(target_os = "macos") {
+            return caller;
+        }
+        let (mut uid, mut gid) = (entry.uid, entry.gid);
+        if let Some(local) = &self.local {
+            let local = local.read().expect("RwLock poisoned");
+            uid = local.users.get(&uid).copied().unwrap_or(uid);
+            gid = local.groups.get(&gid).copied().unwrap_or(gid);
+        }
+        match &self.identity {
+            Some(me) => (
+                if entry.uid == me.uid { caller.0 } else { uid },
+                if me.in_group(entry.gid) { caller.1 } else { gid },
+            ),
+            None => (uid, gid),
+        }
+    }
+
+    /// uid e gid del server per un chown verso id locali
+    pub(crate) fn server_ids(&self, uid: Option<u32>, gid: Option<u32>) -> (Option<u32>, Option<u32>) {
+        let Some(local) = &self.local else { return (uid, gid) };
+        let local = local.read().expect("RwLock poisoned");
+        (
+            uid.map(|uid| local.local_users.get(&uid).copied().unwrap_or(uid)),
+            gid.map(|gid| local.local_groups.get(&gid).copied().unwrap_or(gid)),
+        )
+    }
+}
@@ -0,0 +1,108 @@
+// Aggiornamento in background degli attributi dei file aperti. Kernel e cache tengono gli attributi
+// fino alla scadenza del TTL (o del lease), e con FOPEN_KEEP_CACHE anche le pagine: un file che cresce
+// per le scritture di un altro client resterebbe della vecchia dimensione finché è aperto. Un thread
+// rivalida a intervalli gli ino aperti e, se dimensione o mtime cambiano, invalida attributi e pagine
+// nel kernel, che alla prossima richiesta li chiede di nuovo al filesystem.
+
+use fuser::Notifier;
+use rfs_models::{BackendError, RemoteBackend};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+/// File handle aperti e relativo ino, condivisi tra il filesystem e il refresher
+#[derive(Clone, Default)]
+pub struct OpenInodes(Arc<Mutex<HashMap<u64, u64>>>);
+
+impl OpenInodes {
+    /// true se è il primo handle aperto sull'ino
+    pub(crate) fn opened(&self, fh: u64, ino: u64) -> bool {
+        let mut open = self.0.lock().expect("Mutex poisoned");
+        let first = !open.values().any(|i| *i == ino);
+        open.insert(fh, ino);
+        first
+    }
+
+    pub(crate) fn inos(&self) -> HashSet<u64> {
+        self.0.lock().expect("Mutex poisoned").values().copied().collect()
+    }
+
+    /// L'ino di `fh` se era l'ultimo handle aperto su di esso
+    pub(crate) fn closed(&self, fh: u64) -> Option<u64> {
+        let mut open = self.0.lock().expect("Mutex poisoned");
+        let ino = open.remove(&fh)?;
+        (!open.values().any(|i| *i == ino)).then_some(ino)
+    }
+}
+
+// il server tiene la registrazione di un file aperto per 5 minuti
+const OPEN_RENEW: Duration = Duration::from_secs(120);
+
+/// Avvia il thread che rinnova sul server la registrazione degli ino aperti, così un file cancellato mentre
+/// è aperto resta disponibile finché non viene chiuso. Il thread termina quando il filesystem viene distrutto.
+pub fn spawn_open_keeper<B: RemoteBackend + 'static>(mut backend: B, open: OpenInodes) -> JoinHandle<()> {
+    let open: Weak<Mutex<HashMap<u64, u64>>> = Arc::downgrade(&open.0);
+    thread::Builder::new()
+        .name("rfs-open-keeper".to_string())
+        .spawn(move || loop {
+            thread::sleep(OPEN_RENEW);
+            let Some(open) = open.upgrade() else { return };
+            let inos: HashSet<u64> = open.lock().expect("Mutex poisoned").values().copied().collect();
+            drop(open);
+            for ino in inos {
+                if let Err(e) = backend.hold_open(ino) {
+                    eprintln!("Cannot renew open ino {} on the server: {}", ino, e);
+                }
+            }
+        })
+        .expect("Unable to start the open file keeper")
+}
+
+/// Avvia il thread che ogni `interval` rilegge dal server (con `server`, senza passare dalla cache) gli
+/// attributi degli ino aperti; se sono cambiati scarta quello che `cache` sa del file e invalida la cache
+/// del kernel. Il thread termina quando il filesystem viene distrutto.
+pub fn spawn_attr_refresher<S, C>(mut server: S, mut cache: C, open: OpenInodes, notifier: Notifier, interval: Duration) -> JoinHandle<()>
+where
+    S: RemoteBackend + 'static,
+    C: RemoteBackend + 'static,
+{
+    let open: Weak<Mutex<HashMap<u64, u64>>> = Arc::downgrade(&open.0);
+    thread::Builder::new()
+        .name("rfs-attr-refresh".to_string())
+        .spawn(move || {
+            let mut seen: HashMap<u64, (u64, SystemTime)> = HashMap::new();
+            loop {
+                thread::sleep(interval);
+                let Some(open) = open.upgrade() else { return };
+                let inos: HashSet<u64> = open.lock().expect("Mutex poisoned").values().copied().collect();
+                drop(open);
+                seen.retain(|ino, _| inos.contains(ino));
+
+                for ino in inos {
+                    let entry = match server.get_attr(ino) {
+                        Ok(entry) => entry,
+                        Err(BackendError::NotFound(_)) => continue, // rimosso mentre era aperto
+                        Err(e) => {
+                            eprintln!("Attribute refresh of ino {} failed: {}", ino, e);
+                            continue;
+                        }
+                    };
+                    let current = (entry.size, entry.mtime);
+                    // la prima volta si confronta con quello che ha in memoria la cache
+                    let changed = match seen.insert(ino, current) {
+                        Some(prev) => prev != current,
+                        None => cache.get_attr(ino).is_ok_and(|cached| (cached.size, cached.mtime) != current),
+                    };
+                    if !changed {
+                        continue;
+                    }
+                    cache.invalidate(ino);
+                    if let Err(e) = notifier.inval_inode(ino, 0, 0) {
+                        eprintln!("Cannot invalidate kernel cache of ino {}: {}", ino, e);
+                    }
+                }
+            }
+        })
+        .expect("Unable to start the attribute refresher")
+}
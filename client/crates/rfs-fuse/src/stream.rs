@@ -0,0 +1,255 @@
+// Read in streaming di un handle. Lo stream del server viene letto in anticipo da un task sul runtime, a
+// pezzi da STREAM_PIECE, mentre l'applicazione consuma quelli già arrivati; quando STREAM_AHEAD byte
+// aspettano una read il task smette di leggere (il server rallenta da sé via TCP) e riprende appena la read
+// li consuma. Così un'applicazione che legge piano non fa crescere la memoria del file aperto.
+// Lo stream viene chiesto per una finestra di byte, non fino alla fine del file: chi legge solo l'inizio
+// di un file enorme non lo scarica tutto. A fine finestra lo stream si riapre con una finestra doppia.
+// Uno stream abbandonato (close dell'handle, salto, riapertura) ferma subito il suo task, che chiude la
+// risposta HTTP senza aspettare il prossimo chunk: reqwest interrompe la connessione di un body non finito.
+
+use crate::map_handle_error;
+use bytes::Bytes;
+use rfs_models::{BackendError, ByteStream, CancellationToken, RemoteBackend, cancellable};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{self, Receiver, error::TryRecvError};
+use tokio_stream::StreamExt;
+
+const STREAM_PIECE: usize = 64 * 1024;
+const STREAM_AHEAD: usize = 4 * 1024 * 1024;
+const FIRST_WINDOW: u64 = 16 * 1024 * 1024;
+const MAX_WINDOW: u64 = 1024 * 1024 * 1024;
+
+/// Richiesta di lettura su uno stream
+pub(crate) struct StreamRead {
+    pub(crate) ino: u64,
+    pub(crate) offset: u64,
+    pub(crate) size: u32,
+    pub(crate) flags: i32,
+    pub(crate) pid: u32, // processo da osservare per le interruzioni
+}
+
+pub(crate) struct StreamState {
+    pos: u64,
+    pieces: Option<Prefetch>, // None: stream da aprire (o riaprire dopo un errore)
+    current: Bytes, // resto del pezzo consumato in parte
+    window: u64, // byte chiesti all'apertura dello stream
+    left: u64, // byte della finestra non ancora arrivati
+    eof: bool,
+}
+
+impl StreamState {
+    pub(crate) fn at(pos: u64) -> Self {
+        Self { pos, pieces: None, current: Bytes::new(), window: FIRST_WINDOW / 2, left: 0, eof: false }
+    }
+
+    /// Prossimi byte dello stream, che deve essere già arrivato all'offset della read
+    pub(crate) fn read(&mut self, backend: &mut dyn RemoteBackend, rt: &Runtime, cancel: Option<&CancellationToken>, read: &StreamRead) -> Result<Vec<u8>, libc::c_int> {
+        if read.offset != self.pos {
+            return Err(libc::ESPIPE);
+        }
+
+        let need = read.size as usize;
+        let nonblock = read.flags & libc::O_NONBLOCK != 0;
+        let mut out = Vec::with_capacity(need.min(STREAM_AHEAD));
+        while out.len() < need {
+            if self.current.is_empty() {
+                if self.pieces.is_none() && !self.eof {
+                    let pos = self.pos + out.len() as u64;
+                    self.window = (self.window * 2).min(MAX_WINDOW);
+                    let stream = match backend.read_stream(read.ino, pos, Some(self.window)) {
+                        Ok(stream) => stream,
+                        Err(e) if out.is_empty() => return Err(map_handle_error(&e)),
+                        Err(_) => break,
+                    };
+                    self.pieces = Some(prefetch(rt, stream));
+                    self.left = self.window;
+                }
+                let Some(Prefetch { pieces, .. }) = self.pieces.as_mut() else { break };
+                let next = if nonblock {
+                    match pieces.try_recv() {
+                        Ok(next) => Some(next),
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => None,
+                    }
+                } else {
+                    match rt.block_on(cancellable(cancel, pieces.recv())) {
+                        Ok(next) => next,
+                        Err(e) if out.is_empty() => return Err(map_handle_error(&e)),
+                        Err(_) => break, // interrotta: intanto i byte già presi
+                    }
+                };
+                match next {
+                    Some(Ok(piece)) => {
+                        self.left = self.left.saturating_sub(piece.len() as u64);
+                        self.current = piece;
+                    }
+                    Some(Err(e)) => {
+                        // la prossima read riapre lo stream da dove si è fermato
+                        self.pieces = None;
+                        if out.is_empty() {
+                            return Err(map_handle_error(&e));
+                        }
+                        break;
+                    }
+                    None => {
+                        // finestra esaurita: il file continua, si riapre da qui; altrimenti EOF server side
+                        self.eof = self.left > 0;
+                        self.pieces = None;
+                        if self.eof {
+                            break;
+                        }
+                    }
+                }
+            }
+            let take = (need - out.len()).min(self.current.len());
+            out.extend_from_slice(&self.current.split_to(take));
+        }
+
+        if out.is_empty() && !self.eof && nonblock {
+            return Err(libc::EAGAIN);
+        }
+        self.pos = self.pos.saturating_add(out.len() as u64);
+        Ok(out)
+    }
+}
+
+// pezzi letti in anticipo da uno stream; il drop ferma il task e con lui il download
+struct Prefetch {
+    pieces: Receiver<Result<Bytes, BackendError>>,
+    abort: CancellationToken,
+}
+
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        self.abort.cancel();
+    }
+}
+
+// task che legge lo stream in anticipo; finisce con lo stream o quando l'handle lo abbandona
+fn prefetch(rt: &Runtime, mut stream: ByteStream) -> Prefetch {
+    let (tx, pieces) = mpsc::channel(STREAM_AHEAD / STREAM_PIECE);
+    let abort = CancellationToken::new();
+    let aborted = abort.clone();
+    rt.spawn(async move {
+        // ogni attesa si interrompe col drop di Prefetch; uscendo lo stream viene chiuso
+        while let Ok(Some(next)) = cancellable(Some(&aborted), stream.next()).await {
+            let mut chunk = match next {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    let _ = cancellable(Some(&aborted), tx.send(Err(e))).await;
+                    return;
+                }
+            };
+            while !chunk.is_empty() {
+                let piece = chunk.split_to(chunk.len().min(STREAM_PIECE));
+                // coda piena: lo stream resta fermo finché una read non consuma
+                if !matches!(cancellable(Some(&aborted), tx.send(Ok(piece))).await, Ok(Ok(()))) {
+                    return;
+                }
+            }
+        }
+    });
+    Prefetch { pieces, abort }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rfs_models::{FileEntry, SetAttrRequest};
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
+    use tokio_stream::Stream;
+
+    // corpo di una risposta lenta: consegna SLOW_AFTER pezzi e poi non arriva più nulla, così il task resta
+    // fermo sull'attesa del prossimo chunk; conta i pezzi consegnati e segnala quando viene chiuso
+    const SLOW_AFTER: usize = 8;
+
+    struct Body {
+        served: Arc<AtomicUsize>,
+        closed: Arc<AtomicBool>,
+    }
+
+    impl Stream for Body {
+        type Item = Result<Bytes, BackendError>;
+
+        fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            if self.served.load(Ordering::SeqCst) >= SLOW_AFTER {
+                return Poll::Pending;
+            }
+            self.served.fetch_add(1, Ordering::SeqCst);
+            Poll::Ready(Some(Ok(Bytes::from(vec![0u8; STREAM_PIECE]))))
+        }
+    }
+
+    impl Drop for Body {
+        fn drop(&mut self) {
+            self.closed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    // backend che sa solo aprire lo stream
+    #[derive(Default)]
+    struct StreamServer {
+        served: Arc<AtomicUsize>,
+        closed: Arc<AtomicBool>,
+    }
+
+    fn unsupported<T>() -> Result<T, BackendError> {
+        Err(BackendError::Unsupported("test backend".to_string()))
+    }
+
+    impl RemoteBackend for StreamServer {
+        fn read_stream(&mut self, _ino: u64, _offset: u64, _len: Option<u64>) -> Result<ByteStream, BackendError> {
+            Ok(Box::pin(Body { served: self.served.clone(), closed: self.closed.clone() }))
+        }
+        fn list_dir(&mut self, _ino: u64) -> Result<Vec<FileEntry>, BackendError> { unsupported() }
+        fn get_attr(&mut self, _ino: u64) -> Result<FileEntry, BackendError> { unsupported() }
+        fn lookup(&mut self, _parent_ino: u64, _name: &str) -> Result<FileEntry, BackendError> { unsupported() }
+        fn create_file(&mut self, _parent_ino: u64, _name: &str) -> Result<FileEntry, BackendError> { unsupported() }
+        fn create_dir(&mut self, _parent_ino: u64, _name: &str) -> Result<FileEntry, BackendError> { unsupported() }
+        fn delete_file(&mut self, _parent_ino: u64, _name: &str) -> Result<(), BackendError> { unsupported() }
+        fn delete_dir(&mut self, _parent_ino: u64, _name: &str) -> Result<(), BackendError> { unsupported() }
+        fn read_chunk(&mut self, _ino: u64, _offset: u64, _size: u64) -> Result<Vec<u8>, BackendError> { unsupported() }
+        fn write_chunk(&mut self, _ino: u64, _offset: u64, _data: Vec<u8>) -> Result<u64, BackendError> { unsupported() }
+        fn rename(&mut self, _old_parent_ino: u64, _old_name: &str, _new_parent_ino: u64, _new_name: &str) -> Result<FileEntry, BackendError> { unsupported() }
+        fn set_attr(&mut self, _ino: u64, _attrs: SetAttrRequest) -> Result<FileEntry, BackendError> { unsupported() }
+        fn write_stream(&mut self, _ino: u64, _offset: u64, _data: ByteStream) -> Result<(), BackendError> { unsupported() }
+        fn link(&mut self, _target_ino: u64, _link_parent_ino: u64, _link_name: &str) -> Result<FileEntry, BackendError> { unsupported() }
+        fn symlink(&mut self, _target_path: &str, _link_parent_ino: u64, _link_name: &str) -> Result<FileEntry, BackendError> { unsupported() }
+        fn readlink(&mut self, _ino: u64) -> Result<String, BackendError> { unsupported() }
+        fn get_size(&mut self) -> Result<(u64, u64), BackendError> { unsupported() }
+    }
+
+    // aspetta che `done` diventi vero, fallendo dopo un secondo
+    fn wait_for(what: &str, done: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !done() {
+            assert!(start.elapsed() < Duration::from_secs(1), "timed out waiting for {}", what);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn dropped_stream_stops_downloading() {
+        let rt = tokio::runtime::Builder::new_multi_thread().worker_threads(1).build().expect("runtime");
+        let mut server = StreamServer::default();
+        let (served, closed) = (server.served.clone(), server.closed.clone());
+
+        let mut state = StreamState::at(0);
+        let read = StreamRead { ino: 2, offset: 0, size: STREAM_PIECE as u32 * 2, flags: 0, pid: 0 };
+        assert_eq!(state.read(&mut server, &rt, None, &read).expect("read").len(), STREAM_PIECE * 2);
+
+        // il task ha letto in anticipo quello che c'era ed è fermo sull'attesa del prossimo chunk
+        wait_for("the prefetch to catch up", || served.load(Ordering::SeqCst) == SLOW_AFTER);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!closed.load(Ordering::SeqCst));
+
+        // close dell'handle a metà stream: il task non aspetta il chunk e la risposta viene chiusa
+        drop(state);
+        wait_for("the response to be closed", || closed.load(Ordering::SeqCst));
+        assert_eq!(served.load(Ordering::SeqCst), SLOW_AFTER);
+    }
+}
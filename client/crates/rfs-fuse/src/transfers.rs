@@ -0,0 +1,126 @@
+// Avanzamento degli invii al server (flush dei buffer di scrittura). Un flush di un file grande può
+// durare minuti senza dare segni di vita: il registro tiene byte inviati e totali per ogni invio in
+// corso, letti dalla socket di controllo con `status --transfers`, insieme ai byte scritti e non ancora inviati.
+
+use rfs_models::ByteStream;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_stream::StreamExt;
+
+struct Transfer {
+    ino: u64,
+    total: u64,
+    sent: Arc<AtomicU64>,
+    started: Instant,
+}
+
+/// Invii in corso, condivisi tra il filesystem e la socket di controllo
+#[derive(Clone, Default)]
+pub struct Transfers(Arc<Mutex<TransfersInner>>);
+
+#[derive(Default)]
+struct TransfersInner {
+    next_id: u64,
+    active: HashMap<u64, Transfer>,
+    dirty: u64,
+    // byte non ancora inviati per ino
+    dirty_inos: HashMap<u64, u64>,
+}
+
+/// Fotografia di un invio in corso
+#[derive(Debug, Clone)]
+pub struct TransferStatus {
+    pub ino: u64,
+    pub sent: u64,
+    pub total: u64,
+    pub elapsed: Duration,
+}
+
+impl TransferStatus {
+    /// Byte al secondo dall'inizio dell'invio
+    pub fn rate(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 { self.sent as f64 / secs } else { 0.0 }
+    }
+
+    /// Tempo stimato alla fine, alla velocità media finora; None finché non è partito nessun byte
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.rate();
+        if rate <= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(self.total.saturating_sub(self.sent) as f64 / rate))
+    }
+}
+
+impl Transfers {
+    /// Invii in corso, dal più vecchio
+    pub fn snapshot(&self) -> Vec<TransferStatus> {
+        let inner = self.0.lock().expect("Mutex poisoned");
+        let mut list: Vec<(u64, TransferStatus)> = inner.active.iter().map(|(id, t)| (*id, TransferStatus {
+            ino: t.ino,
+            sent: t.sent.load(Ordering::Relaxed),
+            total: t.total,
+            elapsed: t.started.elapsed(),
+        })).collect();
+        list.sort_by_key(|(id, _)| *id);
+        list.into_iter().map(|(_, status)| status).collect()
+    }
+
+    /// Byte scritti nei buffer e non ancora inviati al server
+    pub fn dirty_bytes(&self) -> u64 {
+        self.0.lock().expect("Mutex poisoned").dirty
+    }
+
+    /// Byte scritti e non ancora inviati di un singolo file
+    pub fn dirty_of(&self, ino: u64) -> u64 {
+        self.0.lock().expect("Mutex poisoned").dirty_inos.get(&ino).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn set_dirty(&self, bytes: u64, by_ino: HashMap<u64, u64>) {
+        let mut inner = self.0.lock().expect("Mutex poisoned");
+        inner.dirty = bytes;
+        inner.dirty_inos = by_ino;
+    }
+
+    pub(crate) fn start(&self, ino: u64, total: u64) -> TransferProgress {
+        let sent = Arc::new(AtomicU64::new(0));
+        let mut inner = self.0.lock().expect("Mutex poisoned");
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.active.insert(id, Transfer { ino, total, sent: sent.clone(), started: Instant::now() });
+        TransferProgress { transfers: self.clone(), id, sent }
+    }
+}
+
+/// Avanzamento di un invio; l'invio esce dal registro quando viene rilasciato
+pub(crate) struct TransferProgress {
+    transfers: Transfers,
+    id: u64,
+    sent: Arc<AtomicU64>,
+}
+
+impl TransferProgress {
+    pub(crate) fn add(&self, bytes: u64) {
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Conta i byte man mano che lo stream li consegna alla connessione
+    pub(crate) fn counting(&self, stream: ByteStream) -> ByteStream {
+        let sent = self.sent.clone();
+        Box::pin(stream.map(move |chunk| {
+            if let Ok(bytes) = &chunk {
+                sent.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+            }
+            chunk
+        }))
+    }
+}
+
+impl Drop for TransferProgress {
+    fn drop(&mut self) {
+        self.transfers.0.lock().expect("Mutex poisoned").active.remove(&self.id);
+    }
+}
@@ -0,0 +1,382 @@
+// Worker che servono le richieste lente senza fermare il loop di fuser: la sessione legge un messaggio
+// alla volta, quindi una chiamata a un server lento bloccava tutte le altre operazioni. Le richieste che
+// vanno al server (getattr e lookup, le pagine di readdir, le read che la cache non copre, open e write in
+// append) vengono date a un pool di thread, ognuno con un backend indipendente, e la risposta al kernel parte
+// dal worker. Come in rfs-winfsp la rete si usa fuori dal lock della cache, a cui il worker consegna dopo
+// attributi, voci e blocchi letti (offer_attr, offer_page, prime_range); a server irraggiungibile risponde la
+// cache con le sue copie. Sulla sessione restano le risposte già in memoria e le operazioni che cambiano
+// lo stato del filesystem (create, rename, setattr, flush, ...), che vanno servite in ordine.
+// Le read in streaming dello stesso handle passano da una coda per handle e vengono servite una alla volta
+// nell'ordine di arrivo: lo stream avanza solo in avanti.
+
+use crate::flush::{FlushBackend, FlushBackends};
+use crate::interrupt::InterruptWatcher;
+use crate::notify::DirListings;
+use crate::owners::Owners;
+use crate::{CacheTtl, DirStream, entry_to_attr, fill_dir, live_ino, map_error, map_handle_error, reresolve, visible};
+use fuser::{ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite};
+use crate::stream::{StreamRead, StreamState};
+use libc::{EPERM, O_ACCMODE, O_RDWR, O_WRONLY};
+use rfs_models::{BackendError, DirPage, FileEntry, JunkFilter, LeaseKind, Policy, RemoteBackend, DIR_PAGE_SIZE, FILE_FLAG_APPEND, FILE_FLAG_IMMUTABLE, ROOT_INO};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+type Job = Box<dyn FnOnce(&mut dyn RemoteBackend) + Send>;
+
+/// Read in streaming di un handle, con le richieste in attesa di un worker
+pub(crate) struct StreamLane {
+    // read in coda e se un worker le sta già servendo
+    pending: Mutex<(VecDeque<(StreamRead, ReplyData)>, bool)>,
+    state: Mutex<StreamState>,
+}
+
+impl StreamLane {
+    /// Stream che parte da `pos`, aperto alla prima read
+    pub(crate) fn at(pos: u64) -> Self {
+        Self { pending: Mutex::default(), state: Mutex::new(StreamState::at(pos)) }
+    }
+
+    pub(crate) fn state(&self) -> std::sync::MutexGuard<'_, StreamState> {
+        self.state.lock().expect("Mutex poisoned")
+    }
+}
+
+/// Stato e opzioni del filesystem con cui i worker rispondono al posto della sessione
+pub(crate) struct WorkerContext {
+    pub(crate) cache: FlushBackends, // il backend della sessione, a cui vanno consegnati i risultati
+    pub(crate) ttl: CacheTtl,
+    pub(crate) trust_cache: bool,
+    pub(crate) block_size: usize,
+    pub(crate) large_file_size: u64,
+    pub(crate) owners: Owners,
+    pub(crate) junk: JunkFilter,
+    pub(crate) policy: Policy,
+    pub(crate) listings: DirListings,
+    pub(crate) dir_parent: Arc<Mutex<HashMap<u64, u64>>>,
+    pub(crate) replaced_inodes: Arc<Mutex<HashMap<u64, u64>>>,
+    pub(crate) root: Arc<Mutex<Option<FileEntry>>>,
+    pub(crate) failed_opens: Arc<Mutex<Vec<u64>>>, // fh registrati dalla sessione per open poi fallite
+}
+
+impl WorkerContext {
+    // attributi dal server e poi alla cache; come RemoteFS::attr_of recupera gli ino riassegnati
+    fn fetch_attr(&self, backend: &mut dyn RemoteBackend, ino: u64) -> Result<FileEntry, BackendError> {
+        let live = live_ino(&self.replaced_inodes, ino);
+        let res = match backend.get_attr(live) {
+            Err(BackendError::Stale(path)) => {
+                (self.cache)().invalidate(live);
+                match reresolve(backend, &self.replaced_inodes, ino, &path) {
+                    Ok(live) => backend.get_attr(live),
+                    Err(_) => Err(BackendError::Stale(path)),
+                }
+            }
+            res => res,
+        };
+        match res {
+            Ok(entry) => {
+                (self.cache)().offer_attr(&entry, None);
+                Ok(entry)
+            }
+            Err(BackendError::ServerUnreachable) => (self.cache)().get_attr(live),
+            Err(e) => Err(e),
+        }
+    }
+
+    // pagina dalla cache se la ha già, altrimenti dal server
+    fn fetch_page(&self, backend: &mut dyn RemoteBackend, ino: u64, cursor: Option<&str>) -> Result<DirPage, BackendError> {
+        if let Some(page) = (self.cache)().cached_page(ino, cursor, DIR_PAGE_SIZE) {
+            return Ok(page);
+        }
+        match backend.list_dir_page(ino, None, cursor, DIR_PAGE_SIZE) {
+            Ok(page) => {
+                (self.cache)().offer_page(ino, cursor, &page);
+                Ok(page)
+            }
+            Err(BackendError::ServerUnreachable) => (self.cache)().list_dir_page(ino, None, cursor, DIR_PAGE_SIZE),
+            Err(e) => Err(e),
+        }
+    }
+
+    // blocchi che coprono la read, lasciati alla cache come validi per `entry` (gli ultimi attributi noti)
+    fn fetch_blocks(&self, backend: &mut dyn RemoteBackend, read: &StreamRead, entry: Option<&FileEntry>) -> Result<Vec<u8>, BackendError> {
+        self.read_lease(backend, read.ino);
+        let block = self.block_size as u64;
+        let (offset, size) = (read.offset, read.size as u64);
+        let start = offset / block * block;
+        let end = (offset + size).div_ceil(block) * block;
+        let data = match backend.read_chunk(read.ino, start, end - start) {
+            Err(BackendError::ServerUnreachable) => return (self.cache)().read_chunk(read.ino, offset, size),
+            res => res?,
+        };
+        if let Some(entry) = entry {
+            (self.cache)().prime_range(entry, start, &data);
+        }
+        let from = ((offset - start) as usize).min(data.len());
+        let to = ((offset + size - start) as usize).min(data.len());
+        Ok(data[from..to].to_vec())
+    }
+
+    // lease di lettura chiesto con il backend del worker, poi consegnato alla cache
+    fn read_lease(&self, backend: &mut dyn RemoteBackend, ino: u64) {
+        let mut cache = (self.cache)();
+        if !cache.wanted_lease(ino, LeaseKind::Read) {
+            return;
+        }
+        let asked = Instant::now();
+        let lease = backend.acquire_lease(ino, LeaseKind::Read).unwrap_or_else(|e| {
+            eprintln!("Lease request for ino {} failed: {}", ino, e);
+            None
+        });
+        cache.offer_lease(ino, lease, asked);
+    }
+
+    // come RemoteFS::leased_ttl
+    fn leased_ttl(&self, backend: &mut dyn RemoteBackend, ino: u64) -> Option<Duration> {
+        if !self.trust_cache {
+            return None;
+        }
+        self.read_lease(backend, ino);
+        (self.cache)().lease_left(ino)
+    }
+}
+
+/// Open già registrata dalla sessione sull'handle `fh`: al worker restano attributi e controlli sul file
+pub(crate) struct PendingOpen {
+    pub(crate) ino: u64,
+    pub(crate) fh: u64,
+    pub(crate) flags: i32,
+    pub(crate) hold: bool, // primo handle sull'ino: va registrato sul server (hold_open)
+    pub(crate) fuse_flags: (u32, u32), // flag per i file fino a large_file_size e per quelli più grandi
+}
+
+pub(crate) struct WorkerPool {
+    jobs: Sender<Job>,
+    rt: Arc<Runtime>,
+    interrupts: Arc<InterruptWatcher>,
+    ctx: Arc<WorkerContext>,
+}
+
+impl WorkerPool {
+    pub(crate) fn start(workers: usize, backends: FlushBackends, ctx: WorkerContext, rt: Arc<Runtime>, interrupts: Arc<InterruptWatcher>) -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..workers {
+            let queue = queue.clone();
+            let backends = backends.clone();
+            thread::Builder::new()
+                .name(format!("rfs-worker-{}", i))
+                .spawn(move || work(queue, backends))
+                .expect("Unable to start a filesystem worker");
+        }
+        Self { jobs, rt, interrupts, ctx: Arc::new(ctx) }
+    }
+
+    fn submit(&self, job: Job) {
+        // i worker terminano solo quando il pool viene distrutto
+        let _ = self.jobs.send(job);
+    }
+
+    /// Accoda una read in streaming; la risposta parte dal worker che la serve
+    pub(crate) fn stream_read(&self, lane: &Arc<StreamLane>, read: StreamRead, reply: ReplyData, speed_file: Option<File>) {
+        {
+            let mut pending = lane.pending.lock().expect("Mutex poisoned");
+            pending.0.push_back((read, reply));
+            if pending.1 {
+                return; // il worker che serve l'handle la troverà in coda
+            }
+            pending.1 = true;
+        }
+        let lane = lane.clone();
+        let rt = self.rt.clone();
+        let interrupts = self.interrupts.clone();
+        let mut speed_file = speed_file;
+        self.submit(Box::new(move |backend| loop {
+            let (read, reply) = {
+                let mut pending = lane.pending.lock().expect("Mutex poisoned");
+                let Some(next) = pending.0.pop_front() else {
+                    pending.1 = false;
+                    return;
+                };
+                next
+            };
+            let timer_start = Instant::now();
+            let (id, token) = interrupts.arm(read.pid);
+            backend.set_cancel_token(Some(token.clone()));
+            let res = lane.state().read(backend, &rt, Some(&token), &read);
+            backend.set_cancel_token(None);
+            interrupts.disarm(id);
+            match res {
+                Ok(data) => reply.data(&data),
+                Err(code) => reply.error(code),
+            }
+            if let Some(file) = speed_file.as_mut() {
+                use std::io::Write;
+                writeln!(file, "[speed] read of ino {} at offset {} with size {} duration: {:?}", read.ino, read.offset, read.size, timer_start.elapsed()).ok();
+            }
+        }));
+    }
+
+    pub(crate) fn statfs(&self, block_size: u64, reply: ReplyStatfs) {
+        self.submit(Box::new(move |backend| reply_statfs(backend, block_size, reply)));
+    }
+
+    /// getattr di un ino senza lease, che la cache dovrebbe chiedere al server
+    pub(crate) fn getattr(&self, ino: u64, caller: (u32, u32), reply: ReplyAttr) {
+        let ctx = self.ctx.clone();
+        self.submit(Box::new(move |backend| {
+            let res = match ctx.fetch_attr(backend, ino) {
+                Ok(entry) if ino == ROOT_INO => {
+                    *ctx.root.lock().expect("Mutex poisoned") = Some(entry.clone());
+                    Ok(entry)
+                }
+                // la root non può sparire: offline si mostrano gli ultimi attributi noti invece di un errore
+                Err(e) if ino == ROOT_INO => ctx.root.lock().expect("Mutex poisoned").clone().ok_or(e),
+                res => res,
+            };
+            match res {
+                Ok(entry) => {
+                    let ttl = ctx.leased_ttl(backend, entry.ino).unwrap_or_else(|| ctx.ttl.attr(&entry));
+                    reply.attr(&ttl, &entry_to_attr(&entry, caller, ctx.block_size, &ctx.owners));
+                }
+                Err(e) => reply.error(map_error(&e)),
+            }
+        }));
+    }
+
+    pub(crate) fn lookup(&self, parent: u64, name: String, caller: (u32, u32), reply: ReplyEntry) {
+        let ctx = self.ctx.clone();
+        self.submit(Box::new(move |backend| {
+            let res = match backend.lookup(parent, &name) {
+                Ok(entry) => {
+                    (ctx.cache)().offer_attr(&entry, Some(parent));
+                    Ok(entry)
+                }
+                Err(BackendError::ServerUnreachable) => (ctx.cache)().lookup(parent, &name),
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(entry) => {
+                    ctx.dir_parent.lock().expect("Mutex poisoned").insert(entry.ino, parent);
+                    let ttl = ctx.leased_ttl(backend, entry.ino).unwrap_or_else(|| ctx.ttl.entry(&entry));
+                    reply.entry(&ttl, &entry_to_attr(&entry, caller, ctx.block_size, &ctx.owners), 0);
+                }
+                Err(e) => reply.error(map_error(&e)),
+            }
+        }));
+    }
+
+    /// Continua readdir dalla voce `index`; le letture dello stesso handle si aspettano sul lock dello stream
+    pub(crate) fn readdir(&self, ino: u64, stream: Arc<Mutex<DirStream>>, index: usize, pid: u32, mut reply: ReplyDirectory) {
+        let ctx = self.ctx.clone();
+        let interrupts = self.interrupts.clone();
+        self.submit(Box::new(move |backend| {
+            let (id, token) = interrupts.arm(pid);
+            backend.set_cancel_token(Some(token));
+            let mut stream = stream.lock().expect("Mutex poisoned");
+            let res = fill_dir(&mut stream, ino, index, &mut reply, &ctx.listings, |cursor| {
+                let mut page = ctx.fetch_page(backend, ino, cursor)?;
+                visible(&mut page.entries, &ctx.junk, &ctx.policy, pid);
+                Ok(page)
+            });
+            backend.set_cancel_token(None);
+            interrupts.disarm(id);
+            match res {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(map_error(&e)),
+            }
+        }));
+    }
+
+    /// read a pagine che la cache non ha in memoria, o di un handle O_DIRECT (`direct`, senza cache)
+    pub(crate) fn read(&self, read: StreamRead, entry: Option<FileEntry>, direct: bool, reply: ReplyData, speed_file: Option<File>) {
+        let ctx = self.ctx.clone();
+        let interrupts = self.interrupts.clone();
+        let mut speed_file = speed_file;
+        self.submit(Box::new(move |backend| {
+            let timer_start = Instant::now();
+            let (id, token) = interrupts.arm(read.pid);
+            backend.set_cancel_token(Some(token));
+            let res = if direct {
+                backend.read_chunk(read.ino, read.offset, read.size as u64)
+            } else {
+                ctx.fetch_blocks(backend, &read, entry.as_ref())
+            };
+            backend.set_cancel_token(None);
+            interrupts.disarm(id);
+            match res {
+                Ok(data) => reply.data(&data[..data.len().min(read.size as usize)]),
+                Err(e) => reply.error(map_handle_error(&e)),
+            }
+            if let Some(file) = speed_file.as_mut() {
+                use std::io::Write;
+                writeln!(file, "[speed] read of ino {} at offset {} with size {} duration: {:?}", read.ino, read.offset, read.size, timer_start.elapsed()).ok();
+            }
+        }));
+    }
+
+    /// Completa una open registrata dalla sessione; se fallisce l'handle va ripulito (failed_opens)
+    pub(crate) fn open(&self, open: PendingOpen, reply: ReplyOpen) {
+        let ctx = self.ctx.clone();
+        self.submit(Box::new(move |backend| {
+            let writable = (open.flags & O_ACCMODE) == O_WRONLY || (open.flags & O_ACCMODE) == O_RDWR;
+            let res = match ctx.fetch_attr(backend, open.ino) {
+                // come chattr +i / +a: niente scritture su un file immutabile, solo in append su uno append-only
+                Ok(entry) if writable && (entry.file_flags & FILE_FLAG_IMMUTABLE != 0 || (entry.file_flags & FILE_FLAG_APPEND != 0 && open.flags & libc::O_APPEND == 0)) => Err(EPERM),
+                Ok(entry) => Ok(entry.size),
+                Err(e) => Err(map_error(&e)),
+            };
+            match res {
+                Ok(size) => {
+                    if open.hold && let Err(e) = backend.hold_open(open.ino) {
+                        eprintln!("Cannot register open ino {} on the server: {}", open.ino, e);
+                    }
+                    reply.opened(open.fh, if size > ctx.large_file_size { open.fuse_flags.1 } else { open.fuse_flags.0 });
+                }
+                Err(code) => {
+                    ctx.failed_opens.lock().expect("Mutex poisoned").push(open.fh);
+                    reply.error(code);
+                }
+            }
+        }));
+    }
+
+    /// write in O_APPEND senza byte bufferizzati da inviare prima: l'offset lo sceglie il server
+    pub(crate) fn append(&self, ino: u64, data: Vec<u8>, reply: ReplyWrite) {
+        let ctx = self.ctx.clone();
+        self.submit(Box::new(move |backend| {
+            let len = data.len() as u32;
+            match backend.write_append(ino, data) {
+                Ok(_) => {
+                    // scritto da un backend indipendente: la cache rilegge metadati e blocchi
+                    (ctx.cache)().invalidate(ino);
+                    reply.written(len);
+                }
+                Err(e) => reply.error(map_handle_error(&e)),
+            }
+        }));
+    }
+}
+
+// risposta a statfs, con lo spazio del volume in blocchi da `bsize`
+pub(crate) fn reply_statfs(backend: &mut dyn RemoteBackend, bsize: u64, reply: ReplyStatfs) {
+    match backend.get_size() {
+        Ok((total, available)) => reply.statfs(total / bsize, available / bsize, available / bsize, 0, 0, bsize as u32, 255, bsize as u32),
+        Err(e) => reply.error(map_error(&e)),
+    }
+}
+
+fn work(queue: Arc<Mutex<Receiver<Job>>>, backends: FlushBackends) {
+    let mut backend: FlushBackend = backends();
+    loop {
+        let job = queue.lock().expect("Mutex poisoned").recv();
+        match job {
+            Ok(job) => job(backend.as_mut()),
+            Err(_) => break, // pool distrutto insieme al filesystem
+        }
+    }
+}
@@ -0,0 +1,115 @@
+// Log di audit delle operazioni sul filesystem: una riga JSON per operazione (chi, quando, cosa, su quale
+// path e con quale esito), aggiunta in coda al file. Quando il file supera la dimensione massima viene
+// ruotato in <file>.1, <file>.2, ... e il più vecchio oltre AUDIT_KEEP viene scartato.
+
+use crate::to_millis;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// File ruotati conservati oltre a quello corrente
+pub const AUDIT_KEEP: usize = 5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// millisecondi dall'epoch
+    pub time: u64,
+    /// mount point su cui è avvenuta l'operazione
+    pub mount: String,
+    /// utente e processo locali che hanno chiesto l'operazione, se il sistema li fornisce
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process: Option<String>,
+    pub op: String,
+    /// path remoto; per rename anche la destinazione
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// None se l'operazione è riuscita
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(mount: &str, op: &str, path: String) -> Self {
+        Self {
+            time: to_millis(SystemTime::now()),
+            mount: mount.to_string(),
+            uid: None,
+            pid: None,
+            process: None,
+            op: op.to_string(),
+            path,
+            target: None,
+            error: None,
+        }
+    }
+}
+
+struct AuditFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl AuditFile {
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..AUDIT_KEEP).rev() {
+            match fs::rename(rotated_path(&self.path, n), rotated_path(&self.path, n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Log condiviso tra i layer del filesystem (e tra i mount dello stesso demone)
+#[derive(Clone)]
+pub struct AuditLog {
+    inner: Arc<Mutex<AuditFile>>,
+}
+
+/// `<path>.<n>`, l'n-esimo file ruotato
+pub fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+impl AuditLog {
+    /// Apre (o crea) il log in `path`, ruotato quando supera `max_size` byte
+    pub fn open(path: impl AsRef<Path>, max_size: u64) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { inner: Arc::new(Mutex::new(AuditFile { path, file, size, max_size })) })
+    }
+
+    /// Aggiunge il record al log; un errore di scrittura viene solo segnalato, l'operazione è già avvenuta
+    pub fn record(&self, record: &AuditRecord) {
+        let Ok(mut line) = serde_json::to_string(record) else { return };
+        line.push('\n');
+        let mut log = self.inner.lock().expect("Mutex poisoned");
+        if log.size > 0 && log.size + line.len() as u64 > log.max_size && let Err(e) = log.rotate() {
+            eprintln!("Unable to rotate audit log {}: {}", log.path.display(), e);
+        }
+        match log.file.write_all(line.as_bytes()) {
+            Ok(()) => log.size += line.len() as u64,
+            Err(e) => eprintln!("Unable to write audit log {}: {}", log.path.display(), e),
+        }
+    }
+}
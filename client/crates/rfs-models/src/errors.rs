@@ -0,0 +1,105 @@
+// Errori come li vede l'utente: un codice stabile (da cercare nella documentazione o da confrontare negli
+// script) e un messaggio senza dettagli interni. Il Display di BackendError resta quello dei log, con
+// endpoint, id delle richieste e inizio dei corpi che servono per il confronto con i log del server.
+
+use crate::BackendError;
+use std::fmt;
+
+impl BackendError {
+    /// Codice stabile dell'errore, sul modello di errno
+    pub fn code(&self) -> &'static str {
+        match self {
+            BackendError::NotFound(_) => "ENOENT",
+            BackendError::Unauthorized => "EAUTH",
+            BackendError::Forbidden => "EACCES",
+            BackendError::NotPermitted(_) => "EPERM",
+            BackendError::Conflict(_) => "EEXIST",
+            BackendError::InternalServerError => "ESERVER",
+            BackendError::BadAnswerFormat(_) => "EPROTO",
+            BackendError::ServerUnreachable => "EHOSTUNREACH",
+            BackendError::PreconditionFailed => "ECHANGED",
+            BackendError::Interrupted => "EINTR",
+            BackendError::Unsupported(_) => "ENOTSUP",
+            BackendError::ReadOnly => "EROFS",
+            BackendError::Stale(_) => "ESTALE",
+            BackendError::NotADirectory(_) => "ENOTDIR",
+            BackendError::IsADirectory(_) => "EISDIR",
+            BackendError::NotEmpty(_) => "ENOTEMPTY",
+            BackendError::InvalidArgument(_) => "EINVAL",
+            BackendError::NoSpace(_) => "ENOSPC",
+            BackendError::TooLarge(_) => "EFBIG",
+            BackendError::Throttled(_) => "EAGAIN",
+            BackendError::Locked(_) => "ELOCKED",
+            BackendError::Other(_) => "EIO",
+        }
+    }
+
+    /// L'errore come va mostrato all'utente: `<messaggio>[: <dettaglio>] [<codice>]`
+    pub fn for_user(&self) -> UserError<'_> {
+        UserError(self)
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            BackendError::NotFound(_) => "No such file or directory",
+            BackendError::Unauthorized => "Not logged in or session expired",
+            BackendError::Forbidden => "Permission denied",
+            BackendError::NotPermitted(_) => "Operation not permitted",
+            BackendError::Conflict(_) => "Already exists",
+            BackendError::InternalServerError => "The server failed to complete the request",
+            BackendError::BadAnswerFormat(_) => "Unexpected answer from the server",
+            BackendError::ServerUnreachable => "Server unreachable",
+            BackendError::PreconditionFailed => "File modified by another client",
+            BackendError::Interrupted => "Interrupted",
+            BackendError::Unsupported(_) => "Not supported by the server",
+            BackendError::ReadOnly => "Read-only mount",
+            BackendError::Stale(_) => "File changed on the server",
+            BackendError::NotADirectory(_) => "Not a directory",
+            BackendError::IsADirectory(_) => "Is a directory",
+            BackendError::NotEmpty(_) => "Directory not empty",
+            BackendError::InvalidArgument(_) => "Invalid argument",
+            BackendError::NoSpace(_) => "No space left on the server",
+            BackendError::TooLarge(_) => "File too large",
+            BackendError::Throttled(_) => "Too many requests, retry later",
+            BackendError::Locked(_) => "Locked by another client",
+            BackendError::Other(_) => "Error",
+        }
+    }
+
+    // dettaglio mostrabile all'utente: path, motivo o messaggio del server, senza endpoint né corpi
+    fn detail(&self) -> Option<&str> {
+        let detail = match self {
+            BackendError::NotFound(d) | BackendError::NotPermitted(d) | BackendError::Conflict(d)
+            | BackendError::Unsupported(d) | BackendError::Stale(d) | BackendError::NotADirectory(d)
+            | BackendError::IsADirectory(d) | BackendError::NotEmpty(d) | BackendError::InvalidArgument(d)
+            | BackendError::NoSpace(d) | BackendError::TooLarge(d) | BackendError::Throttled(d)
+            | BackendError::Locked(d)
+            | BackendError::Other(d) => d.as_str(),
+            _ => return None,
+        };
+        // l'id della richiesta resta nei log
+        let detail = detail.find(" (request ").map_or(detail, |at| &detail[..at]);
+        // gli endpoint ("api/files/12") non dicono niente all'utente, il messaggio che li segue sì
+        let detail = match detail.strip_prefix("api/") {
+            Some(rest) => rest.split_once(": ").map_or("", |(_, msg)| msg),
+            None => detail,
+        };
+        // un codice errno da solo ripete quello che dice già il messaggio
+        let errno = detail.len() > 1 && detail.starts_with('E') && detail.bytes().all(|b| b.is_ascii_uppercase());
+        Some(detail.trim()).filter(|d| !d.is_empty() && !errno)
+    }
+}
+
+/// Errore formattato per l'utente, vedi `BackendError::for_user`
+pub struct UserError<'a>(&'a BackendError);
+
+impl fmt::Display for UserError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.0, self.0.detail()) {
+            // Other porta già il messaggio completo (errori locali, argomenti sbagliati)
+            (BackendError::Other(_), Some(detail)) => write!(f, "{} [{}]", detail, self.0.code()),
+            (_, Some(detail)) => write!(f, "{}: {} [{}]", self.0.description(), detail, self.0.code()),
+            (_, None) => write!(f, "{} [{}]", self.0.description(), self.0.code()),
+        }
+    }
+}
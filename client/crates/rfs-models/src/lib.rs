@@ -1,14 +1,471 @@
-use std::{pin::Pin, time::SystemTime};
+use std::{collections::{BTreeMap, HashMap}, pin::Pin, sync::{Arc, Mutex}, task::Poll, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 use thiserror::Error;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tokio_stream::Stream;
 use bytes::Bytes;
-use serde_repr::Deserialize_repr;
+use serde_repr::{Deserialize_repr, Serialize_repr};
+pub use tokio_util::sync::CancellationToken;
 
-pub const BLOCK_SIZE: usize = 16 * 1024; // 16KB
+mod audit;
+pub use audit::{AUDIT_KEEP, AuditLog, AuditRecord, rotated_path};
+mod errors;
+pub use errors::UserError;
 
-// Modello di dominio per una voce di file system remoto, da utilizzare internamente e per caching
+pub const BLOCK_SIZE: usize = 16 * 1024; // 16KB, dimensione di default dei blocchi
+pub const LARGE_FILE_SIZE: u64 = 100 * 1024 * 1024; // 100 MB, soglia di default per lo streaming
+pub const MIN_BLOCK_SIZE: usize = 512; // blocchi più piccoli non hanno senso, anche se il server li chiede
+
+/// Dimensioni dell'I/O condivise da cache e filesystem, da configurare allo stesso modo in tutti i layer
+#[derive(Debug, Clone, Copy)]
+pub struct IoSizes {
+    /// dimensione dei blocchi della cache, e blocco preferito annunciato al sistema operativo
+    pub block_size: usize,
+    /// oltre questa dimensione letture e scritture passano in streaming
+    pub large_file_size: u64,
+}
+
+impl Default for IoSizes {
+    fn default() -> Self {
+        Self { block_size: BLOCK_SIZE, large_file_size: LARGE_FILE_SIZE }
+    }
+}
+
+impl IoSizes {
+    /// Riduce le dimensioni ai limiti dichiarati dal server, senza scendere sotto MIN_BLOCK_SIZE
+    pub fn negotiate(self, limits: &ServerLimits) -> Self {
+        let max_block_size = usize::try_from(limits.max_block_size).unwrap_or(usize::MAX).max(MIN_BLOCK_SIZE);
+        Self {
+            block_size: self.block_size.min(max_block_size),
+            // una write più grande del body accettato dal server deve andare in streaming
+            large_file_size: self.large_file_size.min(limits.max_chunk_size),
+        }
+    }
+
+    /// Senza streaming sul server letture e scritture passano sempre a chunk
+    pub fn without_streams(self) -> Self {
+        Self { large_file_size: u64::MAX, ..self }
+    }
+}
+
+/// Versione del protocollo parlata dal client
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// ino della root del server, lo stesso che fuse riserva alla root del mount
+pub const ROOT_INO: u64 = 1;
+
+/// Funzionalità dichiarate dal server con GET /api/capabilities, lette al mount.
+/// Le funzionalità non nominate dal server si considerano assenti.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Capabilities {
+    pub version: u32,
+    /// read_stream e write_stream
+    pub streams: bool,
+    /// hard link e symlink
+    pub links: bool,
+    /// attributi estesi
+    pub xattrs: bool,
+    /// richieste condizionali (If-Match, If-Modified-Since)
+    pub conditional: bool,
+    /// readdir a pagine con cursore
+    pub pagination: bool,
+    /// corpi di richieste e risposte in MessagePack
+    pub msgpack: bool,
+    /// gestione di utenti e gruppi (API /api/admin)
+    pub admin: bool,
+    /// Idempotency-Key: una richiesta ritentata dopo essere già stata applicata riceve la risposta originale
+    pub idempotency: bool,
+    /// più write in un'unica richiesta multipart (POST /api/batch)
+    pub batch: bool,
+    /// la dimensione di una directory è il numero di voci che contiene
+    #[serde(rename = "dirSizes")]
+    pub dir_sizes: bool,
+    /// nomi di utenti e gruppi (GET /api/names)
+    pub names: bool,
+    /// file aperti registrati sul server (PUT /api/files/:ino/open): un file cancellato mentre è aperto resta
+    /// leggibile e scrivibile finché non viene chiuso
+    #[serde(rename = "openHandles")]
+    pub open_handles: bool,
+    /// lock su intervalli di byte condivisi fra i client (GET e POST /api/files/:ino/locks)
+    pub locks: bool,
+}
+
+impl Capabilities {
+    /// Tutto ciò che il client sa usare, assunto finché non c'è stato l'handshake; i corpi delle
+    /// richieste restano in JSON finché il server non annuncia MessagePack
+    pub const CURRENT: Self = Self { version: PROTOCOL_VERSION, streams: true, links: true, xattrs: true, conditional: true, pagination: true, msgpack: false, admin: true, idempotency: true, batch: true, dir_sizes: true, names: true, open_handles: true, locks: true };
+    /// Server precedente all'handshake: stream e link, readdir in un'unica risposta
+    pub const LEGACY: Self = Self { version: 0, streams: true, links: true, xattrs: false, conditional: false, pagination: false, msgpack: false, admin: false, idempotency: false, batch: false, dir_sizes: false, names: false, open_handles: false, locks: false };
+}
+
+/// uid dell'amministratore, a cui il server concede qualsiasi operazione
+pub const ADMIN_UID: u32 = 5000;
+
+/// Nomi di utenti e gruppi del server (GET /api/names), mostrati al posto di uid e gid; chi non ha un
+/// nome, o un server che non li conosce, resta numerico
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OwnerNames {
+    pub users: HashMap<u32, String>,
+    pub groups: HashMap<u32, String>,
+}
+
+impl OwnerNames {
+    pub fn user(&self, uid: u32) -> String {
+        self.users.get(&uid).cloned().unwrap_or_else(|| uid.to_string())
+    }
+
+    pub fn group(&self, gid: u32) -> String {
+        self.groups.get(&gid).cloned().unwrap_or_else(|| gid.to_string())
+    }
+}
+
+/// Permessi richiesti in una verifica di accesso, con i valori di access(2)
+pub const ACCESS_READ: u16 = 0o4;
+pub const ACCESS_WRITE: u16 = 0o2;
+pub const ACCESS_EXEC: u16 = 0o1;
+
+/// Utente autenticato e gruppi di cui fa parte, letti con GET /api/me al mount.
+/// Servono a valutare in locale i permessi di gruppo, di cui i FileEntry riportano solo il gid.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize)]
+pub struct Identity {
+    pub uid: u32,
+    /// gruppo principale, assente se l'utente non ne ha
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// tutti i gruppi dell'utente, principale compreso
+    #[serde(default)]
+    pub groups: Vec<u32>,
+}
+
+impl Identity {
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == Some(gid) || self.groups.contains(&gid)
+    }
+
+    /// Stessa regola del server: basta che una fra proprietario, gruppo e altri conceda tutti i
+    /// permessi di `mask` (combinazione di ACCESS_*); l'amministratore passa sempre
+    pub fn allows(&self, entry: &FileEntry, mask: u16) -> bool {
+        let mask = mask & 0o7;
+        if mask == 0 || self.uid == ADMIN_UID {
+            return true;
+        }
+        (entry.uid == self.uid && entry.perms & (mask << 6) == mask << 6)
+            || (self.in_group(entry.gid) && entry.perms & (mask << 3) == mask << 3)
+            || entry.perms & mask == mask
+    }
+}
+
+/// Bit del modo trasmessi al server: permessi più setuid (0o4000), setgid (0o2000) e sticky (0o1000)
+pub const MODE_BITS: u32 = 0o7777;
+
+/// Permessi dei file e delle directory creati da un mount, configurabili per imporre ad esempio file
+/// scrivibili dal gruppo su un mount di team, qualunque sia l'umask dei processi locali
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CreateModes {
+    /// permessi dei nuovi file, al posto di quelli chiesti dal processo (o del default del server)
+    pub file_mode: Option<u32>,
+    /// permessi delle nuove directory, come sopra
+    pub dir_mode: Option<u32>,
+    /// umask del mount, al posto di quella del processo
+    pub umask: Option<u32>,
+}
+
+impl CreateModes {
+    /// Permessi di un nuovo file, dati quelli chiesti e l'umask del processo (0 dove non esiste)
+    pub fn file(&self, requested: u32, process_umask: u32) -> u32 {
+        self.file_mode.unwrap_or(requested) & !self.umask.unwrap_or(process_umask) & MODE_BITS
+    }
+
+    /// Permessi di una nuova directory, come per `file`
+    pub fn dir(&self, requested: u32, process_umask: u32) -> u32 {
+        self.dir_mode.unwrap_or(requested) & !self.umask.unwrap_or(process_umask) & MODE_BITS
+    }
+
+    /// Toglie da `mode` i bit esclusi dall'umask del mount, se configurata
+    pub fn masked(&self, mode: u32) -> u32 {
+        mode & !self.umask.unwrap_or(0) & MODE_BITS
+    }
+}
+
+/// Dimensioni massime accettate dal server
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerLimits {
+    /// body massimo di una write non in streaming
+    pub max_chunk_size: u64,
+    /// blocco massimo per block_hashes
+    pub max_block_size: u64,
+}
+
+/// Confronta un nome con un pattern con wildcard `*` (qualsiasi sequenza) e `?` (un carattere),
+/// come quelli passati da Windows alle ricerche in una directory
+pub fn name_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None; // posizione dopo l'ultimo `*` e caratteri che ha consumato
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // l'ultimo `*` prende un carattere in più
+                Some((star_p, star_n)) => {
+                    backtrack = Some((star_p, star_n + 1));
+                    p = star_p;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// File creati automaticamente da Explorer e dal Finder, filtrati di default
+pub const DEFAULT_JUNK_FILES: &[&str] = &["desktop.ini", "Thumbs.db", ".DS_Store", "._*"];
+
+/// Cosa fare dei file che rispettano i pattern di un JunkFilter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JunkMode {
+    /// invisibili nelle liste e nei lookup, e non vengono creati
+    Hide,
+    /// visibili se già presenti sul server, ma non vengono creati
+    Block,
+    /// trattati come tutti gli altri file
+    #[default]
+    Pass,
+}
+
+/// Lista di pattern (wildcard come in `name_matches`, senza distinguere maiuscole e minuscole) con la
+/// modalità da applicare, la stessa nei layer FUSE e WinFsp
+#[derive(Debug, Clone, Default)]
+pub struct JunkFilter {
+    patterns: Vec<String>,
+    mode: JunkMode,
+}
+
+impl JunkFilter {
+    pub fn new<S: AsRef<str>>(patterns: impl IntoIterator<Item = S>, mode: JunkMode) -> Self {
+        let patterns = patterns.into_iter().map(|p| p.as_ref().to_lowercase()).collect();
+        Self { patterns, mode }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        let name = name.to_lowercase();
+        self.patterns.iter().any(|p| name_matches(p, &name))
+    }
+
+    /// La voce non va mostrata, né nelle liste né nei lookup
+    pub fn hides(&self, name: &str) -> bool {
+        self.mode == JunkMode::Hide && self.matches(name)
+    }
+
+    /// La voce non va creata (create, mkdir, link o rename verso quel nome)
+    pub fn blocks(&self, name: &str) -> bool {
+        self.mode != JunkMode::Pass && self.matches(name)
+    }
+}
+
+/// Effetto di una regola di policy, dal meno al più restrittivo
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PolicyAction {
+    /// la voce si legge ma non si crea, modifica, rinomina o cancella
+    ReadOnly,
+    /// la voce si vede nelle liste ma non si apre
+    Deny,
+    /// la voce non viene mostrata, né nelle liste né nei lookup
+    Hidden,
+}
+
+/// Regola del file di configurazione: senza `path` vale per tutte le voci, senza `process` per tutti i processi
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRule {
+    /// pattern con `*` e `?`: senza '/' confrontato con i nomi, con '/' con il path remoto completo; vale anche
+    /// per tutto quello che sta sotto una directory che lo rispetta
+    #[serde(default)]
+    pub path: Option<String>,
+    /// nome o path dell'eseguibile che chiede l'operazione (non disponibile su Windows)
+    #[serde(default)]
+    pub process: Option<String>,
+    pub action: PolicyAction,
+}
+
+/// Processo locale che ha chiesto un'operazione
+#[derive(Debug, Clone, Default)]
+pub struct Caller {
+    /// nome breve, es. `bash`
+    pub name: Option<String>,
+    /// path dell'eseguibile
+    pub exe: Option<String>,
+}
+
+/// Regole di policy valutate dai layer del filesystem prima di chiamare il backend; tra più regole che
+/// valgono per la stessa operazione vince la più restrittiva. I confronti non distinguono maiuscole e minuscole.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    rules: Vec<PolicyRule>,
+}
+
+// il path o una delle directory che lo contengono rispetta il pattern
+fn path_matches(pattern: &str, path: &str) -> bool {
+    if !pattern.contains('/') {
+        return path.split('/').any(|c| !c.is_empty() && name_matches(pattern, c));
+    }
+    let mut current = path.trim_end_matches('/');
+    while !current.is_empty() {
+        if name_matches(pattern, current) {
+            return true;
+        }
+        current = &current[..current.rfind('/').unwrap_or(0)];
+    }
+    false
+}
+
+fn caller_matches(pattern: &str, caller: &Caller) -> bool {
+    let exe_name = caller.exe.as_deref().and_then(|e| e.rsplit('/').next());
+    [caller.name.as_deref(), caller.exe.as_deref(), exe_name].into_iter().flatten()
+        .any(|candidate| name_matches(pattern, &candidate.to_lowercase()))
+}
+
+impl Policy {
+    pub fn new(rules: Vec<PolicyRule>) -> Self {
+        let rules = rules.into_iter().map(|r| PolicyRule {
+            path: r.path.map(|p| p.to_lowercase()),
+            process: r.process.map(|p| p.to_lowercase()),
+            action: r.action,
+        }).collect();
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Qualche regola dipende dal processo: solo allora vale la pena ricavarlo
+    pub fn needs_caller(&self) -> bool {
+        self.rules.iter().any(|r| r.process.is_some())
+    }
+
+    /// Azione per il path remoto `path` chiesto da `caller`; le regole con un processo non valgono se il
+    /// processo non è noto
+    pub fn action(&self, path: &str, caller: Option<&Caller>) -> Option<PolicyAction> {
+        let path = path.to_lowercase();
+        self.rules.iter()
+            .filter(|r| r.path.as_deref().is_none_or(|p| path_matches(p, &path)))
+            .filter(|r| r.process.as_deref().is_none_or(|p| caller.is_some_and(|c| caller_matches(p, c))))
+            .map(|r| r.action)
+            .max()
+    }
+
+    /// Errore per un'operazione su `path`: `write` se crea, modifica, rinomina o cancella
+    pub fn check(&self, path: &str, caller: Option<&Caller>, write: bool) -> Result<(), PolicyAction> {
+        match self.action(path, caller) {
+            Some(PolicyAction::ReadOnly) if !write => Ok(()),
+            Some(action) => Err(action),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Voci richieste per ogni pagina di una lista di directory
+pub const DIR_PAGE_SIZE: u32 = 1000;
+
+/// Pagina di una lista di directory, ordinata per nome
 #[derive(Debug, Clone)]
+pub struct DirPage {
+    pub entries: Vec<FileEntry>,
+    /// cursore per la pagina successiva (l'ultimo nome di questa), None se la lista è finita
+    pub next: Option<String>,
+}
+
+impl DirPage {
+    /// Ritaglia da una lista completa la pagina che segue `cursor`, tenendo solo i nomi che rispettano `pattern`
+    pub fn from_listing(mut entries: Vec<FileEntry>, pattern: Option<&str>, cursor: Option<&str>, limit: u32) -> Self {
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        entries.retain(|e| cursor.is_none_or(|c| e.name.as_str() > c) && pattern.is_none_or(|p| name_matches(p, &e.name)));
+        let limit = limit.max(1) as usize;
+        let next = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|e| e.name.clone())
+        } else {
+            None
+        };
+        DirPage { entries, next }
+    }
+}
+
+/// Spazio occupato da un sottoalbero
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DiskUsage {
+    /// byte dei file, contando una volta sola i file con più hard link
+    pub bytes: u64,
+    pub files: u64,
+    /// directory visitate, compresa la radice
+    pub dirs: u64,
+}
+
+/// Risultati massimi di una ricerca se la query non ne indica
+pub const SEARCH_LIMIT: u32 = 1000;
+
+/// Filtri di una ricerca nel sottoalbero di una directory; i filtri assenti non escludono nulla
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchQuery {
+    /// pattern del nome con wildcard `*` e `?`
+    pub name: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_after: Option<SystemTime>,
+    pub modified_before: Option<SystemTime>,
+    /// risultati massimi, default SEARCH_LIMIT
+    pub limit: Option<u32>,
+}
+
+impl SearchQuery {
+    pub fn matches(&self, entry: &FileEntry) -> bool {
+        self.name.as_deref().is_none_or(|p| name_matches(p, &entry.name))
+            && self.min_size.is_none_or(|min| entry.size >= min)
+            && self.max_size.is_none_or(|max| entry.size <= max)
+            && self.modified_after.is_none_or(|t| entry.mtime >= t)
+            && self.modified_before.is_none_or(|t| entry.mtime <= t)
+    }
+}
+
+/// Flag di file in stile chflags/chattr (FileEntry::file_flags), salvati dal server e applicati dal client.
+/// Immutabile: niente scritture, truncate, cambi di attributi, rename o cancellazione (né dei figli, per le directory)
+pub const FILE_FLAG_IMMUTABLE: u32 = 1 << 0;
+/// Solo append: scritture solo in coda, niente truncate, rename o cancellazione (per le directory: solo creazioni)
+pub const FILE_FLAG_APPEND: u32 = 1 << 1;
+/// Escluso dai backup: solo informativo
+pub const FILE_FLAG_NODUMP: u32 = 1 << 2;
+/// Tutti i flag supportati
+pub const FILE_FLAGS: u32 = FILE_FLAG_IMMUTABLE | FILE_FLAG_APPEND | FILE_FLAG_NODUMP;
+
+/// Millisecondi dall'epoch, il formato dei tempi del server; i tempi precedenti al 1970 valgono 0
+pub fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+/// Inverso di `to_millis`
+pub fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// Versione della forma serializzata di FileEntry (pin su disco, socket di controllo, registrazioni).
+/// Va incrementata a ogni cambio incompatibile; le voci senza versione sono del formato 0.
+pub const FILE_ENTRY_VERSION: u32 = 1;
+
+// Modello di dominio per una voce di file system remoto, da utilizzare internamente e per caching
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(into = "FileEntryRecord", try_from = "FileEntryRecord")]
 pub struct FileEntry {
     /// inode assegnato dal server
     pub ino: u64,
@@ -20,7 +477,7 @@ pub struct FileEntry {
     pub kind: EntryType,
     /// dimensione in byte
     pub size: u64,
-    /// permessi in formato octale (es. 0o755)
+    /// permessi in formato octale (es. 0o755), con setuid, setgid e sticky (MODE_BITS)
     pub perms: u16,
     /// user ID
     pub uid: u32,
@@ -36,9 +493,111 @@ pub struct FileEntry {
     pub btime: SystemTime,
     /// numero di link
     pub nlinks: u32,
+    /// versione del contenuto lato server, usata per If-Match sulle scritture
+    pub etag: Option<String>,
+    /// attributi DOS impostati da Windows (FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_SYSTEM)
+    #[serde(default)]
+    pub flags: u32,
+    /// flag chflags (FILE_FLAG_*)
+    #[serde(default)]
+    pub file_flags: u32,
+}
+
+impl FileEntry {
+    /// Voce in JSON, nella forma versionata (vedi FILE_ENTRY_VERSION)
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("FileEntry is always serializable")
+    }
+
+    /// Legge una voce scritta da `to_json`, anche con un formato precedente
+    pub fn from_json(raw: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(raw)
+    }
+}
+
+// Forma serializzata di FileEntry: tempi in nanosecondi dall'epoch e tipo come numero (vedi EntryType).
+// Il formato 0 salvava i tempi come {secs_since_epoch, nanos_since_epoch} e viene ancora letto.
+#[derive(Serialize, Deserialize)]
+struct FileEntryRecord {
+    #[serde(default)]
+    version: u32,
+    ino: u64,
+    name: String,
+    path: String,
+    kind: EntryType,
+    size: u64,
+    perms: u16,
+    uid: u32,
+    gid: u32,
+    #[serde(with = "record_time")]
+    atime: SystemTime,
+    #[serde(with = "record_time")]
+    mtime: SystemTime,
+    #[serde(with = "record_time")]
+    ctime: SystemTime,
+    #[serde(with = "record_time")]
+    btime: SystemTime,
+    nlinks: u32,
+    etag: Option<String>,
+    #[serde(default)]
+    flags: u32,
+    #[serde(default)]
+    file_flags: u32,
+}
+
+impl From<FileEntry> for FileEntryRecord {
+    fn from(e: FileEntry) -> Self {
+        Self {
+            version: FILE_ENTRY_VERSION,
+            ino: e.ino, name: e.name, path: e.path, kind: e.kind, size: e.size, perms: e.perms, uid: e.uid, gid: e.gid,
+            atime: e.atime, mtime: e.mtime, ctime: e.ctime, btime: e.btime,
+            nlinks: e.nlinks, etag: e.etag, flags: e.flags, file_flags: e.file_flags,
+        }
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Deserialize_repr)]
+impl TryFrom<FileEntryRecord> for FileEntry {
+    type Error = String;
+
+    fn try_from(r: FileEntryRecord) -> Result<Self, String> {
+        if r.version > FILE_ENTRY_VERSION {
+            return Err(format!("FileEntry format {} is newer than the supported {}", r.version, FILE_ENTRY_VERSION));
+        }
+        Ok(Self {
+            ino: r.ino, name: r.name, path: r.path, kind: r.kind, size: r.size, perms: r.perms, uid: r.uid, gid: r.gid,
+            atime: r.atime, mtime: r.mtime, ctime: r.ctime, btime: r.btime,
+            nlinks: r.nlinks, etag: r.etag, flags: r.flags, file_flags: r.file_flags,
+        })
+    }
+}
+
+// tempi di FileEntryRecord: nanosecondi dall'epoch (i precedenti al 1970 valgono 0)
+mod record_time {
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Nanos(u64),
+        Legacy { secs_since_epoch: u64, nanos_since_epoch: u32 }, // formato 0
+    }
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        let nanos = time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        serializer.serialize_u64(nanos)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        Ok(match Stored::deserialize(deserializer)? {
+            Stored::Nanos(nanos) => UNIX_EPOCH + Duration::from_nanos(nanos),
+            Stored::Legacy { secs_since_epoch, nanos_since_epoch } => UNIX_EPOCH + Duration::new(secs_since_epoch, nanos_since_epoch),
+        })
+    }
+}
+
+/// Tipo di una voce. I valori numerici sono quelli del server e della forma serializzata di FileEntry:
+/// non vanno cambiati né riusati
+#[derive(Debug, Clone, PartialEq, Serialize_repr, Deserialize_repr)]
 #[repr(u8)]
 pub enum EntryType {
     File = 0,
@@ -46,13 +605,80 @@ pub enum EntryType {
     Symlink = 2,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl TryFrom<u8> for EntryType {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        match value {
+            0 => Ok(EntryType::File),
+            1 => Ok(EntryType::Directory),
+            2 => Ok(EntryType::Symlink),
+            other => Err(other),
+        }
+    }
+}
+
+impl From<EntryType> for u8 {
+    fn from(kind: EntryType) -> u8 {
+        kind as u8
+    }
+}
+
+/// Stato della copia locale di un file nei mount con file su richiesta
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hydration {
+    /// solo sul server: il contenuto viene scaricato alla prima lettura
+    Remote,
+    /// scaricato alla prima lettura, può essere liberato
+    Hydrated,
+    /// tenuto sempre in locale per scelta dell'utente
+    Pinned,
+}
+
+/// Scrittura piccola da inviare insieme ad altre con RemoteBackend::write_batch
+#[derive(Debug, Clone)]
+pub struct BatchWrite {
+    pub ino: u64,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct SetAttrRequest {
+    /// nuovi permessi, tutti i bit di MODE_BITS
     pub perm: Option<u32>,
     pub uid: Option<u32>,
     pub gid: Option<u32>,
     pub size: Option<u64>,
+    /// attributi DOS (vedi FileEntry::flags)
     pub flags: Option<u32>,
+    /// flag chflags (vedi FileEntry::file_flags)
+    #[serde(default, rename = "fileFlags")]
+    pub file_flags: Option<u32>,
+    #[serde(default, with = "millis")]
+    pub atime: Option<SystemTime>,
+    #[serde(default, with = "millis")]
+    pub mtime: Option<SystemTime>,
+    /// data di creazione, il server la applica solo se il suo filesystem lo permette
+    #[serde(default, with = "millis")]
+    pub btime: Option<SystemTime>,
+}
+
+// tempi di SetAttrRequest in millisecondi dall'epoch, come nelle risposte del server
+mod millis {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(time: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error> {
+        match time {
+            Some(t) => serializer.serialize_some(&to_millis(*t)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<SystemTime>, D::Error> {
+        let millis: Option<u64> = Option::deserialize(deserializer)?;
+        Ok(millis.map(from_millis))
+    }
 }
 
 #[derive(Debug, Error)]
@@ -65,18 +691,303 @@ pub enum BackendError {
     Conflict(String),
     #[error("Forbidden")]
     Forbidden,
+    #[error("Operation not permitted: {0}")]
+    NotPermitted(String),
     #[error("Internal server error")]
     InternalServerError,
-    #[error("Bad answer format")]
-    BadAnswerFormat,
+    /// risposta del server non decodificabile: endpoint, campo e inizio del corpo
+    #[error("Bad answer format: {0}")]
+    BadAnswerFormat(String),
     #[error("Server unreachable")]
     ServerUnreachable,
+    #[error("Precondition failed: file modified by another client")]
+    PreconditionFailed,
+    #[error("Interrupted")]
+    Interrupted,
+    #[error("Not supported by the server: {0}")]
+    Unsupported(String),
+    /// modifica rifiutata dal client su un mount in sola lettura, senza contattare il server
+    #[error("Read-only mount")]
+    ReadOnly,
+    /// l'ino in cache indica ora un altro oggetto sul server (ino riassegnati); contiene il path
+    /// con cui era stato risolto, per risolverlo di nuovo
+    #[error("Stale handle: {0} now refers to another object")]
+    Stale(String),
+    #[error("Not a directory: {0}")]
+    NotADirectory(String),
+    #[error("Is a directory: {0}")]
+    IsADirectory(String),
+    #[error("Directory not empty: {0}")]
+    NotEmpty(String),
+    /// richiesta rifiutata dal server come non valida (400 senza un codice più preciso)
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("No space left on the server: {0}")]
+    NoSpace(String),
+    #[error("File too large: {0}")]
+    TooLarge(String),
+    /// troppe richieste: il server chiede di riprovare più tardi
+    #[error("Throttled by the server: {0}")]
+    Throttled(String),
+    /// intervallo bloccato dal lock di un altro client
+    #[error("Locked by another client: {0}")]
+    Locked(String),
     #[error("Other: {0}")]
     Other(String),
 }
 
+impl BackendError {
+    /// Errore di una risposta HTTP non riuscita. `context` è l'endpoint (o il file) a cui si riferisce,
+    /// `error` il campo `error` del corpo, dove il server mette il codice errno (es. "ENOTDIR") o un
+    /// messaggio. Unica tabella per le risposte singole e per le operazioni dei batch: gli stati senza un
+    /// significato proprio finiscono in Other, che sul mount diventa EIO.
+    pub fn from_status(status: u16, context: &str, error: Option<&str>) -> BackendError {
+        let detail = || match error {
+            Some(error) => format!("{}: {}", context, error),
+            None => context.to_string(),
+        };
+        match (status, error) {
+            (400, Some("ENOTDIR")) => BackendError::NotADirectory(context.to_string()),
+            (400, Some("EISDIR")) => BackendError::IsADirectory(context.to_string()),
+            (400 | 416 | 422, _) => BackendError::InvalidArgument(detail()),
+            (401, _) => BackendError::Unauthorized,
+            // EPERM: ad esempio una voce di un altro utente in una directory sticky
+            (403, Some("EPERM")) => BackendError::NotPermitted(context.to_string()),
+            (403, _) => BackendError::Forbidden,
+            // 404 dopo un'apertura è un file cancellato da un altro client: sul mount diventa ESTALE
+            (404 | 410, _) => BackendError::NotFound(context.to_string()),
+            (405 | 501, _) => BackendError::Unsupported(detail()),
+            (409, Some("ENOTEMPTY")) => BackendError::NotEmpty(context.to_string()),
+            (409, _) => BackendError::Conflict(error.unwrap_or("Conflict").to_string()),
+            (412, _) => BackendError::PreconditionFailed,
+            (413, _) => BackendError::TooLarge(context.to_string()),
+            (423, _) => BackendError::Locked(context.to_string()),
+            (429, _) => BackendError::Throttled(detail()),
+            (507, _) => BackendError::NoSpace(context.to_string()),
+            (408 | 502 | 503 | 504, _) => BackendError::ServerUnreachable,
+            (500, _) => BackendError::InternalServerError,
+            (status, _) => BackendError::Other(format!("{}: HTTP {}", context, status)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaseKind {
+    Read,
+    Write,
+}
+
+// Lease concesso dal server: finché è valido il client può usare la cache senza rivalidare
+#[derive(Debug, Clone)]
+pub struct Lease {
+    pub ino: u64,
+    pub kind: LeaseKind,
+    pub expires: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    Read,
+    Write,
+    Unlock,
+}
+
+/// Lock su un intervallo di byte (fcntl), condiviso con gli altri client tramite il server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteLock {
+    pub kind: LockKind,
+    pub start: u64,
+    /// ultimo byte incluso; None fino alla fine del file
+    pub end: Option<u64>,
+    /// lock owner del kernel; 0 nei lock di altri client restituiti da `test_lock`
+    pub owner: u64,
+    pub pid: u32,
+}
+
 pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, BackendError>> + Send>>;
 
+/// Stream che consegna i chunk uno alla volta, liberando ciascuno dopo l'invio
+pub fn chunk_stream(chunks: Vec<Vec<u8>>) -> ByteStream {
+    Box::pin(tokio_stream::iter(chunks.into_iter().map(|chunk| Ok(Bytes::from(chunk)))))
+}
+
+/// Blocchi da inviare senza perderli se l'invio fallisce: gli stream di `shared_stream` li condividono
+/// invece di copiarli
+pub fn shared_chunks(chunks: Vec<Vec<u8>>) -> Vec<Bytes> {
+    chunks.into_iter().map(Bytes::from).collect()
+}
+
+pub fn shared_stream(chunks: &[Bytes]) -> ByteStream {
+    let chunks: Vec<Bytes> = chunks.to_vec();
+    Box::pin(tokio_stream::iter(chunks.into_iter().map(Ok)))
+}
+
+/// Unisce i chunk in un unico buffer riusando il primo e liberando gli altri man mano che vengono
+/// copiati, così la memoria non raddoppia come con `concat()`
+pub fn join_chunks(chunks: Vec<Vec<u8>>) -> Vec<u8> {
+    let total: usize = chunks.iter().map(Vec::len).sum();
+    let mut chunks = chunks.into_iter();
+    let Some(mut joined) = chunks.next() else { return Vec::new() };
+    joined.reserve_exact(total - joined.len());
+    for chunk in chunks {
+        joined.extend_from_slice(&chunk);
+    }
+    joined
+}
+
+/// Byte scritti e non ancora inviati al server, tenuti come intervalli disgiunti ordinati per offset.
+/// Una scrittura che si sovrappone a dati già presenti li sostituisce: vince sempre l'ultima, come
+/// su un file locale, qualunque sia l'ordine degli offset.
+#[derive(Debug, Default)]
+pub struct DirtyRanges {
+    ranges: BTreeMap<u64, Vec<u8>>,
+    bytes: u64, // somma delle lunghezze degli intervalli
+}
+
+impl DirtyRanges {
+    pub fn insert(&mut self, offset: u64, data: Vec<u8>) {
+        if data.is_empty() {
+            return;
+        }
+        let end = offset + data.len() as u64;
+
+        // intervallo che inizia prima e arriva dentro (o oltre) quello nuovo: resta la parte prima,
+        // più quella dopo la fine se lo scavalca
+        if let Some((&start, prev)) = self.ranges.range_mut(..offset).next_back() {
+            let prev_end = start + prev.len() as u64;
+            if prev_end > offset {
+                self.bytes -= prev_end.min(end) - offset;
+                let tail = if prev_end > end { prev.split_off((end - start) as usize) } else { Vec::new() };
+                prev.truncate((offset - start) as usize);
+                if !tail.is_empty() {
+                    self.ranges.insert(end, tail);
+                }
+            }
+        }
+
+        // intervalli che iniziano dentro quello nuovo: spariscono, tranne l'eventuale coda oltre la fine
+        let covered: Vec<u64> = self.ranges.range(offset..end).map(|(start, _)| *start).collect();
+        for start in covered {
+            let mut old = self.ranges.remove(&start).expect("range just listed");
+            self.bytes -= (start + old.len() as u64).min(end) - start;
+            if start + old.len() as u64 > end {
+                self.ranges.insert(end, old.split_off((end - start) as usize));
+            }
+        }
+        self.bytes += data.len() as u64;
+        self.ranges.insert(offset, data);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Byte in attesa di essere inviati
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Run di byte contigui, come (offset, blocchi) in ordine di offset; i dati escono senza copie
+    pub fn into_runs(self) -> Vec<(u64, Vec<Vec<u8>>)> {
+        let mut runs: Vec<(u64, Vec<Vec<u8>>)> = Vec::new();
+        let mut next_offset = 0_u64;
+        for (offset, data) in self.ranges {
+            let len = data.len() as u64;
+            match runs.last_mut() {
+                Some((_, run)) if offset == next_offset => run.push(data),
+                _ => runs.push((offset, vec![data])),
+            }
+            next_offset = offset + len;
+        }
+        runs
+    }
+
+    /// Scarta i byte da `len` in poi (truncate del file): una flush successiva non deve riallungarlo
+    pub fn truncate(&mut self, len: u64) {
+        for data in self.ranges.split_off(&len).into_values() {
+            self.bytes -= data.len() as u64;
+        }
+        if let Some((&start, last)) = self.ranges.iter_mut().next_back()
+            && start + last.len() as u64 > len
+        {
+            self.bytes -= start + last.len() as u64 - len;
+            last.truncate((len - start) as usize);
+        }
+    }
+
+    /// Rimette nel buffer le run che non è stato possibile inviare: le write arrivate nel frattempo
+    /// restano sopra di esse, come se fossero state fatte dopo
+    pub fn restore(&mut self, runs: impl IntoIterator<Item = (u64, Vec<u8>)>) {
+        let newer = std::mem::take(self);
+        for (offset, data) in runs {
+            self.insert(offset, data);
+        }
+        for (offset, data) in newer.ranges {
+            self.insert(offset, data);
+        }
+    }
+}
+
+/// Scadenza per un gruppo di richieste (lo svuotamento dei buffer allo smontaggio): il token viene
+/// cancellato allo scadere, così si interrompe anche la richiesta in corso e non solo le successive.
+/// Il thread del timer termina appena la scadenza viene rilasciata.
+pub struct Deadline {
+    at: Instant,
+    token: CancellationToken,
+    _stop: std::sync::mpsc::Sender<()>,
+}
+
+impl Deadline {
+    pub fn start(deadline: Instant) -> Self {
+        let token = CancellationToken::new();
+        let (stop, stopped) = std::sync::mpsc::channel::<()>();
+        let expired = token.clone();
+        let spawned = std::thread::Builder::new()
+            .name("rfs-deadline".to_string())
+            .spawn(move || {
+                let wait = deadline.saturating_duration_since(Instant::now());
+                if let Err(std::sync::mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(wait) {
+                    expired.cancel();
+                }
+            });
+        if let Err(e) = spawned {
+            // senza timer la scadenza viene comunque controllata tra una richiesta e l'altra
+            eprintln!("Unable to start the deadline timer: {}", e);
+        }
+        Self { at: deadline, token, _stop: stop }
+    }
+
+    /// Token da passare a `RemoteBackend::set_cancel_token`
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    pub fn expired(&self) -> bool {
+        self.token.is_cancelled() || Instant::now() >= self.at
+    }
+
+    /// Errore da riportare: una richiesta interrotta dalla scadenza diventa un timeout
+    pub fn error(&self, error: BackendError) -> BackendError {
+        match error {
+            BackendError::Interrupted if self.expired() => BackendError::Other("shutdown timeout expired".to_string()),
+            e => e,
+        }
+    }
+}
+
+/// Attende `fut`, abbandonandola con `Interrupted` se il token viene cancellato prima
+pub async fn cancellable<F: Future>(token: Option<&CancellationToken>, fut: F) -> Result<F::Output, BackendError> {
+    let Some(token) = token else { return Ok(fut.await) };
+    let mut fut = std::pin::pin!(fut);
+    let mut cancelled = std::pin::pin!(token.cancelled());
+    std::future::poll_fn(|cx| {
+        if cancelled.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Err(BackendError::Interrupted));
+        }
+        fut.as_mut().poll(cx).map(Ok)
+    }).await
+}
+
 pub trait RemoteBackend: Send + Sync {
     /// Lista il contenuto di una directory
     fn list_dir(&mut self, ino: u64) -> Result<Vec<FileEntry>, BackendError>;
@@ -101,10 +1012,12 @@ pub trait RemoteBackend: Send + Sync {
     /// Imposta gli attributi di un file o directory
     fn set_attr(&mut self, ino:u64, attrs: SetAttrRequest) -> Result<FileEntry, BackendError>;
 
-    /// legge un file intero come stream di byte (per file molto grandi)
-    fn read_stream(&mut self, ino: u64, offset: u64) -> Result<ByteStream, BackendError>;
-    /// scrive un file intero come stream di byte (per file molto grandi)
-    fn write_stream(&mut self, ino: u64, offset: u64, data: Vec<u8>) -> Result<(), BackendError>;
+    /// legge un file come stream di byte (per file molto grandi) da offset fino alla fine, o solo per `len`
+    /// byte: lo stream può finire prima se il file è più corto
+    fn read_stream(&mut self, ino: u64, offset: u64, len: Option<u64>) -> Result<ByteStream, BackendError>;
+    /// scrive un file come stream di byte a partire da offset (per file molto grandi): i chunk vengono
+    /// inviati man mano che lo stream li produce, senza raccoglierli in un unico buffer
+    fn write_stream(&mut self, ino: u64, offset: u64, data: ByteStream) -> Result<(), BackendError>;
 
     /// crea un hard link a un file esistente
     fn link(&mut self, target_ino: u64, link_parent_ino: u64, link_name: &str) -> Result<FileEntry, BackendError>;
@@ -112,8 +1025,480 @@ pub trait RemoteBackend: Send + Sync {
     fn readlink(&mut self, ino: u64) -> Result<String, BackendError>;
 
     fn get_size(&mut self) -> Result<(u64, u64), BackendError>;
+    /// limiti del server sulle dimensioni delle richieste; None se non li dichiara
+    fn server_limits(&mut self) -> Result<Option<ServerLimits>, BackendError> {
+        Ok(None)
+    }
+
+    /// Cerca nel sottoalbero di `root_ino` le voci che rispettano `query`;
+    /// di default visita l'albero con list_dir
+    fn search(&mut self, root_ino: u64, query: &SearchQuery) -> Result<Vec<FileEntry>, BackendError> {
+        let limit = query.limit.unwrap_or(SEARCH_LIMIT) as usize;
+        let mut found = Vec::new();
+        let mut stack = vec![root_ino];
+        while let Some(dir) = stack.pop() {
+            for entry in self.list_dir(dir)? {
+                if entry.kind == EntryType::Directory {
+                    stack.push(entry.ino);
+                }
+                if query.matches(&entry) {
+                    found.push(entry);
+                    if found.len() >= limit {
+                        return Ok(found);
+                    }
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Spazio occupato dal sottoalbero di `ino`; di default visita l'albero con list_dir
+    fn disk_usage(&mut self, ino: u64) -> Result<DiskUsage, BackendError> {
+        let root = self.get_attr(ino)?;
+        if root.kind != EntryType::Directory {
+            let files = (root.kind == EntryType::File) as u64;
+            return Ok(DiskUsage { bytes: root.size * files, files, dirs: 0 });
+        }
+        let mut usage = DiskUsage::default();
+        let mut seen = std::collections::HashSet::new();
+        let mut stack = vec![ino];
+        while let Some(dir) = stack.pop() {
+            usage.dirs += 1;
+            for entry in self.list_dir(dir)? {
+                match entry.kind {
+                    EntryType::Directory => stack.push(entry.ino),
+                    EntryType::File if seen.insert(entry.ino) => {
+                        usage.bytes += entry.size;
+                        usage.files += 1;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Lista una pagina di al massimo `limit` voci dopo `cursor` (None per la prima), con i soli nomi
+    /// che rispettano `pattern` se indicato; di default ritaglia la lista completa
+    fn list_dir_page(&mut self, ino: u64, pattern: Option<&str>, cursor: Option<&str>, limit: u32) -> Result<DirPage, BackendError> {
+        Ok(DirPage::from_listing(self.list_dir(ino)?, pattern, cursor, limit))
+    }
+
+    /// Crea un file vuoto con i permessi indicati; di default crea e poi imposta i permessi
+    fn create_file_with_mode(&mut self, parent_ino:u64, name:&str, mode: u32) -> Result<FileEntry, BackendError> {
+        let entry = self.create_file(parent_ino, name)?;
+        self.set_attr(entry.ino, SetAttrRequest { perm: Some(mode), ..Default::default() })
+    }
+    /// Crea una directory con i permessi indicati; di default crea e poi imposta i permessi
+    fn create_dir_with_mode(&mut self, parent_ino:u64, name:&str, mode: u32) -> Result<FileEntry, BackendError> {
+        let entry = self.create_dir(parent_ino, name)?;
+        self.set_attr(entry.ino, SetAttrRequest { perm: Some(mode), ..Default::default() })
+    }
+
+    /// Scrive in coda al file (O_APPEND) e restituisce l'offset a cui sono finiti i dati; di default usa la
+    /// dimensione letta con get_attr, che non è atomica rispetto ad altri scrittori
+    fn write_append(&mut self, ino: u64, data: Vec<u8>) -> Result<u64, BackendError> {
+        let offset = self.get_attr(ino)?.size;
+        self.write_chunk(ino, offset, data)?;
+        Ok(offset)
+    }
+
+    /// Invia più scritture piccole (anche su file diversi) con un solo round trip, restituendo l'esito di
+    /// ognuna nello stesso ordine; l'errore esterno riguarda la richiesta intera. Di default una alla volta
+    fn write_batch(&mut self, writes: Vec<BatchWrite>) -> Result<Vec<Result<u64, BackendError>>, BackendError> {
+        Ok(writes.into_iter().map(|w| self.write_chunk(w.ino, w.offset, w.data)).collect())
+    }
 
     fn get_attr_if_modified_since(&mut self, ino: u64, _since: SystemTime) -> Result<Option<FileEntry>, BackendError> {
         Ok(Some(self.get_attr(ino)?))
     }
+
+    /// chiede un lease sull'ino; None se il server non lo concede (conflitto o non supportato)
+    fn acquire_lease(&mut self, _ino: u64, _kind: LeaseKind) -> Result<Option<Lease>, BackendError> {
+        Ok(None)
+    }
+    /// hash sha256 (hex) dei blocchi indicati, calcolati dal server; None se non supportato
+    fn block_hashes(&mut self, _ino: u64, _block_size: u64, _blocks: &[u64]) -> Result<Option<HashMap<u64, String>>, BackendError> {
+        Ok(None)
+    }
+    /// true se conviene chiedere un lease su `ino` prima di leggere dalla cache: va chiesto con
+    /// `acquire_lease` (anche da un altro backend, fuori da ogni lock) e consegnato con `offer_lease`
+    fn wanted_lease(&mut self, _ino: u64, _kind: LeaseKind) -> bool {
+        false
+    }
+    /// risposta a una richiesta di lease partita ad `asked`; None se il server l'ha negato
+    fn offer_lease(&mut self, _ino: u64, _lease: Option<Lease>, _asked: Instant) {}
+    /// rilascia un lease prima della scadenza
+    fn release_lease(&mut self, _ino: u64) -> Result<(), BackendError> {
+        Ok(())
+    }
+    /// tempo per cui il lease su `ino` vale ancora (già tolto il margine di sicurezza); None senza lease
+    fn lease_left(&mut self, _ino: u64) -> Option<Duration> {
+        None
+    }
+    /// cambia a ogni nuova sessione col server: i lease ottenuti con la sessione precedente non valgono più
+    fn session_epoch(&self) -> u64 {
+        0
+    }
+    /// segnala al server che l'ino è aperto (o lo è ancora): se viene cancellato il contenuto resta
+    /// disponibile fino a `release_open`
+    fn hold_open(&mut self, _ino: u64) -> Result<(), BackendError> {
+        Ok(())
+    }
+    /// l'ultimo handle sull'ino è stato chiuso
+    fn release_open(&mut self, _ino: u64) -> Result<(), BackendError> {
+        Ok(())
+    }
+    /// primo lock di un altro owner che impedirebbe `lock` (F_GETLK); None se si può prendere
+    fn test_lock(&mut self, _ino: u64, _lock: &ByteLock) -> Result<Option<ByteLock>, BackendError> {
+        Err(BackendError::Unsupported("byte-range locks".to_string()))
+    }
+    /// prende, cambia o rilascia (LockKind::Unlock) un lock; Locked se un altro owner lo impedisce
+    fn set_lock(&mut self, _ino: u64, _lock: &ByteLock) -> Result<(), BackendError> {
+        Err(BackendError::Unsupported("byte-range locks".to_string()))
+    }
+    /// token della richiesta in corso: se viene cancellato le chiamate in volo falliscono con Interrupted
+    fn set_cancel_token(&mut self, _token: Option<CancellationToken>) {}
+
+    /// Legge dai dati già in memoria senza chiamate lente; None se serve andare in rete
+    fn cached_read(&mut self, _ino: u64, _offset: u64, _size: u64) -> Option<Vec<u8>> {
+        None
+    }
+    /// Legge dal server senza usare né riempire le cache locali (handle aperti con O_DIRECT)
+    fn read_direct(&mut self, ino: u64, offset: u64, size: u64) -> Result<Vec<u8>, BackendError> {
+        self.read_chunk(ino, offset, size)
+    }
+    /// Offre dati letti da un altro backend (offset allineato ai blocchi della cache), validi per la versione `entry`
+    fn prime_range(&mut self, _entry: &FileEntry, _offset: u64, _data: &[u8]) {}
+    /// Ultimi metadati noti di `ino`, senza chiamate lente; None se il backend non li tiene
+    fn known_attr(&mut self, _ino: u64) -> Option<FileEntry> {
+        None
+    }
+    /// Offre metadati letti da un altro backend, con la directory in cui è stata trovata la voce (lookup)
+    fn offer_attr(&mut self, _entry: &FileEntry, _parent: Option<u64>) {}
+    /// Pagina della lista di `ino` servita da quanto è già in memoria, senza chiamate lente; None se va chiesta
+    fn cached_page(&mut self, _ino: u64, _cursor: Option<&str>, _limit: u32) -> Option<DirPage> {
+        None
+    }
+    /// Offre una pagina della lista di `ino` letta da un altro backend a partire da `cursor`
+    fn offer_page(&mut self, _ino: u64, _cursor: Option<&str>, _page: &DirPage) {}
+    /// true se il contenuto di `ino` è disponibile in locale per intero, senza scaricarlo (es. file pinnato)
+    fn has_local_copy(&mut self, _ino: u64) -> bool {
+        false
+    }
+    /// Stato della copia locale di `ino` nella cache persistente
+    fn hydration(&mut self, _ino: u64) -> Hydration {
+        Hydration::Remote
+    }
+    /// Scarica tutto il contenuto di `ino` nella cache persistente; con `pinned` viene tenuto sempre,
+    /// altrimenti resta una copia che si può liberare. Se la copia c'è già ne cambia solo lo stato
+    fn hydrate(&mut self, _ino: u64, _pinned: bool) -> Result<(), BackendError> {
+        Err(BackendError::Other("hydration requires a disk cache".to_string()))
+    }
+    /// Scarta la copia locale di `ino`, che torna solo sul server; false se non c'era
+    fn dehydrate(&mut self, _ino: u64) -> bool {
+        false
+    }
+
+    /// Controlli e lease per scritture su `ino` a partire da `offset` che verranno inviate da backend
+    /// indipendenti (flush in parallelo)
+    fn prepare_write(&mut self, _ino: u64, _offset: u64) -> Result<(), BackendError> {
+        Ok(())
+    }
+    /// Dimentica quanto sa di `ino` (metadati, dati, etag): il file è stato modificato da un altro backend
+    fn invalidate(&mut self, _ino: u64) {}
+    /// Validatore (ETag) ricordato per `ino`, se il backend ne tiene
+    fn validator(&self, _ino: u64) -> Option<String> {
+        None
+    }
+}
+
+// Backend condiviso tra il filesystem e altri thread (es. socket di controllo): ogni chiamata prende il lock
+impl<B: RemoteBackend> RemoteBackend for Arc<Mutex<B>> {
+    fn list_dir(&mut self, ino: u64) -> Result<Vec<FileEntry>, BackendError> {
+        self.lock().expect("Mutex poisoned").list_dir(ino)
+    }
+    fn get_attr(&mut self, ino: u64) -> Result<FileEntry, BackendError> {
+        self.lock().expect("Mutex poisoned").get_attr(ino)
+    }
+    fn lookup(&mut self, parent_ino:u64, name:&str) -> Result<FileEntry, BackendError> {
+        self.lock().expect("Mutex poisoned").lookup(parent_ino, name)
+    }
+    fn create_file(&mut self, parent_ino:u64, name:&str) -> Result<FileEntry, BackendError> {
+        self.lock().expect("Mutex poisoned").create_file(parent_ino, name)
+    }
+    fn create_dir(&mut self, parent_ino:u64, name:&str) -> Result<FileEntry, BackendError> {
+        self.lock().expect("Mutex poisoned").create_dir(parent_ino, name)
+    }
+    fn create_file_with_mode(&mut self, parent_ino:u64, name:&str, mode: u32) -> Result<FileEntry, BackendError> {
+        self.lock().expect("Mutex poisoned").create_file_with_mode(parent_ino, name, mode)
+    }
+    fn create_dir_with_mode(&mut self, parent_ino:u64, name:&str, mode: u32) -> Result<FileEntry, BackendError> {
+        self.lock().expect("Mutex poisoned").create_dir_with_mode(parent_ino, name, mode)
+    }
+    fn delete_file(&mut self, parent_ino:u64, name:&str) -> Result<(), BackendError> {
+        self.lock().expect("Mutex poisoned").delete_file(parent_ino, name)
+    }
+    fn delete_dir(&mut self, parent_ino:u64, name:&str) -> Result<(), BackendError> {
+        self.lock().expect("Mutex poisoned").delete_dir(parent_ino, name)
+    }
+    fn read_chunk(&mut self, ino: u64, offset: u64, size: u64)-> Result<Vec<u8>, BackendError> {
+        self.lock().expect("Mutex poisoned").read_chunk(ino, offset, size)
+    }
+    fn write_chunk(&mut self, ino: u64, offset: u64, data: Vec<u8>) -> Result<u64, BackendError> {
+        self.lock().expect("Mutex poisoned").write_chunk(ino, offset, data)
+    }
+    fn rename(&mut self, old_parent_ino:u64, old_name: &str, new_parent_ino: u64, new_name: &str) -> Result<FileEntry, BackendError> {
+        self.lock().expect("Mutex poisoned").rename(old_parent_ino, old_name, new_parent_ino, new_name)
+    }
+    fn write_append(&mut self, ino: u64, data: Vec<u8>) -> Result<u64, BackendError> {
+        self.lock().expect("Mutex poisoned").write_append(ino, data)
+    }
+    fn write_batch(&mut self, writes: Vec<BatchWrite>) -> Result<Vec<Result<u64, BackendError>>, BackendError> {
+        self.lock().expect("Mutex poisoned").write_batch(writes)
+    }
+    fn set_attr(&mut self, ino:u64, attrs: SetAttrRequest) -> Result<FileEntry, BackendError> {
+        self.lock().expect("Mutex poisoned").set_attr(ino, attrs)
+    }
+    fn read_stream(&mut self, ino: u64, offset: u64, len: Option<u64>) -> Result<ByteStream, BackendError> {
+        self.lock().expect("Mutex poisoned").read_stream(ino, offset, len)
+    }
+    fn write_stream(&mut self, ino: u64, offset: u64, data: ByteStream) -> Result<(), BackendError> {
+        self.lock().expect("Mutex poisoned").write_stream(ino, offset, data)
+    }
+    fn link(&mut self, target_ino: u64, link_parent_ino: u64, link_name: &str) -> Result<FileEntry, BackendError> {
+        self.lock().expect("Mutex poisoned").link(target_ino, link_parent_ino, link_name)
+    }
+    fn symlink(&mut self, target_path: &str, link_parent_ino: u64, link_name: &str) -> Result<FileEntry, BackendError> {
+        self.lock().expect("Mutex poisoned").symlink(target_path, link_parent_ino, link_name)
+    }
+    fn readlink(&mut self, ino: u64) -> Result<String, BackendError> {
+        self.lock().expect("Mutex poisoned").readlink(ino)
+    }
+    fn get_size(&mut self) -> Result<(u64, u64), BackendError> {
+        self.lock().expect("Mutex poisoned").get_size()
+    }
+    fn server_limits(&mut self) -> Result<Option<ServerLimits>, BackendError> {
+        self.lock().expect("Mutex poisoned").server_limits()
+    }
+    fn disk_usage(&mut self, ino: u64) -> Result<DiskUsage, BackendError> {
+        self.lock().expect("Mutex poisoned").disk_usage(ino)
+    }
+    fn search(&mut self, root_ino: u64, query: &SearchQuery) -> Result<Vec<FileEntry>, BackendError> {
+        self.lock().expect("Mutex poisoned").search(root_ino, query)
+    }
+    fn list_dir_page(&mut self, ino: u64, pattern: Option<&str>, cursor: Option<&str>, limit: u32) -> Result<DirPage, BackendError> {
+        self.lock().expect("Mutex poisoned").list_dir_page(ino, pattern, cursor, limit)
+    }
+    fn get_attr_if_modified_since(&mut self, ino: u64, since: SystemTime) -> Result<Option<FileEntry>, BackendError> {
+        self.lock().expect("Mutex poisoned").get_attr_if_modified_since(ino, since)
+    }
+    fn acquire_lease(&mut self, ino: u64, kind: LeaseKind) -> Result<Option<Lease>, BackendError> {
+        self.lock().expect("Mutex poisoned").acquire_lease(ino, kind)
+    }
+    fn block_hashes(&mut self, ino: u64, block_size: u64, blocks: &[u64]) -> Result<Option<HashMap<u64, String>>, BackendError> {
+        self.lock().expect("Mutex poisoned").block_hashes(ino, block_size, blocks)
+    }
+    fn wanted_lease(&mut self, ino: u64, kind: LeaseKind) -> bool {
+        self.lock().expect("Mutex poisoned").wanted_lease(ino, kind)
+    }
+    fn offer_lease(&mut self, ino: u64, lease: Option<Lease>, asked: Instant) {
+        self.lock().expect("Mutex poisoned").offer_lease(ino, lease, asked)
+    }
+    fn release_lease(&mut self, ino: u64) -> Result<(), BackendError> {
+        self.lock().expect("Mutex poisoned").release_lease(ino)
+    }
+    fn lease_left(&mut self, ino: u64) -> Option<Duration> {
+        self.lock().expect("Mutex poisoned").lease_left(ino)
+    }
+    fn session_epoch(&self) -> u64 {
+        self.lock().expect("Mutex poisoned").session_epoch()
+    }
+    fn hold_open(&mut self, ino: u64) -> Result<(), BackendError> {
+        self.lock().expect("Mutex poisoned").hold_open(ino)
+    }
+    fn release_open(&mut self, ino: u64) -> Result<(), BackendError> {
+        self.lock().expect("Mutex poisoned").release_open(ino)
+    }
+    fn test_lock(&mut self, ino: u64, lock: &ByteLock) -> Result<Option<ByteLock>, BackendError> {
+        self.lock().expect("Mutex poisoned").test_lock(ino, lock)
+    }
+    fn set_lock(&mut self, ino: u64, lock: &ByteLock) -> Result<(), BackendError> {
+        self.lock().expect("Mutex poisoned").set_lock(ino, lock)
+    }
+    fn set_cancel_token(&mut self, token: Option<CancellationToken>) {
+        self.lock().expect("Mutex poisoned").set_cancel_token(token)
+    }
+    fn cached_read(&mut self, ino: u64, offset: u64, size: u64) -> Option<Vec<u8>> {
+        self.lock().expect("Mutex poisoned").cached_read(ino, offset, size)
+    }
+    fn read_direct(&mut self, ino: u64, offset: u64, size: u64) -> Result<Vec<u8>, BackendError> {
+        self.lock().expect("Mutex poisoned").read_direct(ino, offset, size)
+    }
+    fn prime_range(&mut self, entry: &FileEntry, offset: u64, data: &[u8]) {
+        self.lock().expect("Mutex poisoned").prime_range(entry, offset, data)
+    }
+    fn known_attr(&mut self, ino: u64) -> Option<FileEntry> {
+        self.lock().expect("Mutex poisoned").known_attr(ino)
+    }
+    fn offer_attr(&mut self, entry: &FileEntry, parent: Option<u64>) {
+        self.lock().expect("Mutex poisoned").offer_attr(entry, parent)
+    }
+    fn cached_page(&mut self, ino: u64, cursor: Option<&str>, limit: u32) -> Option<DirPage> {
+        self.lock().expect("Mutex poisoned").cached_page(ino, cursor, limit)
+    }
+    fn offer_page(&mut self, ino: u64, cursor: Option<&str>, page: &DirPage) {
+        self.lock().expect("Mutex poisoned").offer_page(ino, cursor, page)
+    }
+    fn has_local_copy(&mut self, ino: u64) -> bool {
+        self.lock().expect("Mutex poisoned").has_local_copy(ino)
+    }
+    fn hydration(&mut self, ino: u64) -> Hydration {
+        self.lock().expect("Mutex poisoned").hydration(ino)
+    }
+    fn hydrate(&mut self, ino: u64, pinned: bool) -> Result<(), BackendError> {
+        self.lock().expect("Mutex poisoned").hydrate(ino, pinned)
+    }
+    fn dehydrate(&mut self, ino: u64) -> bool {
+        self.lock().expect("Mutex poisoned").dehydrate(ino)
+    }
+    fn prepare_write(&mut self, ino: u64, offset: u64) -> Result<(), BackendError> {
+        self.lock().expect("Mutex poisoned").prepare_write(ino, offset)
+    }
+    fn invalidate(&mut self, ino: u64) {
+        self.lock().expect("Mutex poisoned").invalidate(ino)
+    }
+    fn validator(&self, ino: u64) -> Option<String> {
+        self.lock().expect("Mutex poisoned").validator(ino)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BackendError, DirtyRanges, IoSizes, MIN_BLOCK_SIZE, ServerLimits};
+
+    // run come (offset, byte uniti), per confrontarle in un colpo solo
+    fn runs(ranges: DirtyRanges) -> Vec<(u64, Vec<u8>)> {
+        ranges.into_runs().into_iter().map(|(offset, run)| (offset, run.concat())).collect()
+    }
+
+    fn dirty(writes: &[(u64, &[u8])]) -> DirtyRanges {
+        let mut ranges = DirtyRanges::default();
+        for (offset, data) in writes {
+            ranges.insert(*offset, data.to_vec());
+        }
+        ranges
+    }
+
+    #[test]
+    fn negotiation_never_goes_below_the_minimum_block() {
+        let sizes = IoSizes::default().negotiate(&ServerLimits { max_chunk_size: 1 << 20, max_block_size: 0 });
+        assert_eq!(sizes.block_size, MIN_BLOCK_SIZE);
+        let sizes = IoSizes::default().negotiate(&ServerLimits { max_chunk_size: 1 << 20, max_block_size: 4096 });
+        assert_eq!(sizes.block_size, 4096);
+    }
+
+    #[test]
+    fn disjoint_writes_stay_separate() {
+        let ranges = dirty(&[(10, b"bb"), (0, b"aa")]);
+        assert_eq!(ranges.bytes(), 4);
+        assert_eq!(runs(ranges), vec![(0, b"aa".to_vec()), (10, b"bb".to_vec())]);
+    }
+
+    #[test]
+    fn adjacent_writes_form_one_run() {
+        let ranges = dirty(&[(0, b"aa"), (2, b"bb"), (4, b"cc")]);
+        assert_eq!(ranges.bytes(), 6);
+        assert_eq!(runs(ranges), vec![(0, b"aabbcc".to_vec())]);
+    }
+
+    #[test]
+    fn later_write_wins_on_overlap() {
+        // la seconda copre la coda della prima, la terza sta dentro entrambe
+        let ranges = dirty(&[(0, b"aaaa"), (2, b"bbbb"), (3, b"c")]);
+        assert_eq!(ranges.bytes(), 6);
+        assert_eq!(runs(ranges), vec![(0, b"aabcbb".to_vec())]);
+    }
+
+    #[test]
+    fn later_write_wins_at_lower_offset() {
+        let ranges = dirty(&[(4, b"bbbb"), (2, b"aaaa")]);
+        assert_eq!(ranges.bytes(), 6);
+        assert_eq!(runs(ranges), vec![(2, b"aaaabb".to_vec())]);
+    }
+
+    #[test]
+    fn write_covering_several_ranges_replaces_them() {
+        let ranges = dirty(&[(0, b"a"), (2, b"b"), (4, b"c"), (0, b"xxxxxx")]);
+        assert_eq!(ranges.bytes(), 6);
+        assert_eq!(runs(ranges), vec![(0, b"xxxxxx".to_vec())]);
+    }
+
+    #[test]
+    fn empty_write_is_ignored() {
+        let ranges = dirty(&[(3, b"")]);
+        assert!(ranges.is_empty());
+        assert_eq!(ranges.bytes(), 0);
+    }
+
+    #[test]
+    fn truncate_drops_bytes_past_the_end() {
+        let mut ranges = dirty(&[(0, b"aaaa"), (6, b"bbbb"), (20, b"cc")]);
+        ranges.truncate(8);
+        assert_eq!(ranges.bytes(), 6);
+        assert_eq!(runs(ranges), vec![(0, b"aaaa".to_vec()), (6, b"bb".to_vec())]);
+
+        let mut ranges = dirty(&[(4, b"aaaa")]);
+        ranges.truncate(4);
+        assert!(ranges.is_empty());
+        assert_eq!(ranges.bytes(), 0);
+    }
+
+    #[test]
+    fn restore_keeps_newer_writes_on_top() {
+        let mut ranges = dirty(&[(2, b"nn")]);
+        ranges.restore([(0, b"oooo".to_vec()), (6, b"oo".to_vec())]);
+        assert_eq!(ranges.bytes(), 6);
+        assert_eq!(runs(ranges), vec![(0, b"oonn".to_vec()), (6, b"oo".to_vec())]);
+    }
+
+    #[test]
+    fn from_status_table() {
+        use BackendError::*;
+        let ctx = "/a/b";
+        let table: Vec<(u16, Option<&str>, BackendError)> = vec![
+            (400, Some("ENOTDIR"), NotADirectory(ctx.into())),
+            (400, Some("EISDIR"), IsADirectory(ctx.into())),
+            (400, Some("EINVAL"), InvalidArgument(format!("{}: EINVAL", ctx))),
+            (400, None, InvalidArgument(ctx.into())),
+            (416, None, InvalidArgument(ctx.into())),
+            (422, Some("bad name"), InvalidArgument(format!("{}: bad name", ctx))),
+            (401, None, Unauthorized),
+            (403, Some("EPERM"), NotPermitted(ctx.into())),
+            (403, Some("EACCES"), Forbidden),
+            (403, None, Forbidden),
+            (404, None, NotFound(ctx.into())),
+            (410, Some("ENOENT"), NotFound(ctx.into())),
+            (405, None, Unsupported(ctx.into())),
+            (501, Some("no streams"), Unsupported(format!("{}: no streams", ctx))),
+            (409, Some("ENOTEMPTY"), NotEmpty(ctx.into())),
+            (409, Some("EEXIST"), Conflict("EEXIST".into())),
+            (409, None, Conflict("Conflict".into())),
+            (412, None, PreconditionFailed),
+            (413, None, TooLarge(ctx.into())),
+            (429, None, Throttled(ctx.into())),
+            (423, Some("EAGAIN"), Locked(ctx.into())),
+            (507, Some("ENOSPC"), NoSpace(ctx.into())),
+            (408, None, ServerUnreachable),
+            (502, None, ServerUnreachable),
+            (503, None, ServerUnreachable),
+            (504, None, ServerUnreachable),
+            (500, None, InternalServerError),
+            (418, None, Other(format!("{}: HTTP 418", ctx))),
+        ];
+        for (status, error, expected) in table {
+            let got = BackendError::from_status(status, ctx, error);
+            assert_eq!(format!("{:?}", got), format!("{:?}", expected), "HTTP {} with {:?}", status, error);
+        }
+    }
 }
@@ -1,26 +1,55 @@
 #![cfg(windows)] // questo file è compilato solo su Windows
 
-use std::collections::{BTreeMap, HashMap};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
 use std::ffi::c_void;
+use std::fs::File;
 use std::io::ErrorKind;
 use std::path::{Path};
 use std::sync::atomic::AtomicU64;
-use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
 use glob::Pattern;
-use rfs_models::{BackendError, ByteStream, EntryType, FileEntry, RemoteBackend, SetAttrRequest};
+use lru::LruCache;
+use rfs_models::{AuditLog, AuditRecord, BackendError, ByteStream, DIR_PAGE_SIZE, EntryType, FILE_FLAG_IMMUTABLE, CreateModes, Deadline, DirtyRanges, FileEntry, Hydration, Identity, IoSizes, LeaseKind, ACCESS_EXEC, ACCESS_READ, ACCESS_WRITE, JunkFilter, Policy, PolicyAction, ROOT_INO, RemoteBackend, SetAttrRequest, chunk_stream, shared_chunks, shared_stream};
+use bytes::Bytes;
 use tokio::runtime::Runtime;
 use tokio_stream::StreamExt;
-use winapi::um::winnt::{FILE_ATTRIBUTE_ARCHIVE, FILE_ATTRIBUTE_DIRECTORY, FILE_ATTRIBUTE_REPARSE_POINT, IO_REPARSE_TAG_SYMLINK};
+use winapi::um::winnt::{FILE_ACTION_ADDED, FILE_ACTION_MODIFIED, FILE_ACTION_REMOVED, FILE_NOTIFY_CHANGE_DIR_NAME, FILE_NOTIFY_CHANGE_FILE_NAME, FILE_NOTIFY_CHANGE_LAST_WRITE, FILE_NOTIFY_CHANGE_SIZE, FILE_APPEND_DATA, FILE_EXECUTE, FILE_READ_DATA, FILE_WRITE_DATA, FILE_ATTRIBUTE_ARCHIVE, FILE_ATTRIBUTE_DIRECTORY, FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_PINNED, FILE_ATTRIBUTE_READONLY, FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS, FILE_ATTRIBUTE_REPARSE_POINT, FILE_ATTRIBUTE_SYSTEM, FILE_ATTRIBUTE_UNPINNED, IO_REPARSE_TAG_SYMLINK};
 use winfsp::filesystem::{DirBuffer, DirInfo, DirMarker, FileInfo, FileSecurity, FileSystemContext, OpenFileInfo, VolumeInfo, WideNameInfo};
+use winfsp::filesystem::notify::{Notifier, NotifyInfo, NotifyingFileSystemContext};
 use winfsp::{FspError, Result as FspResult, U16CStr};
 use winfsp_sys::{FILE_ACCESS_RIGHTS, FILE_FLAGS_AND_ATTRIBUTES};
 use winfsp::constants::FspCleanupFlags;
+use windows_sys::Win32::Foundation::ERROR_LOCK_VIOLATION;
 
 const SDDL_ALLOW_ALL: &str = "O:BA G:SY D:(A;;FA;;;WD)";
-const LARGE_FILE_SIZE: u64 = 100 * 1024 * 1024; // 100 MB
+// descrittori ridotti, scelti con i permessi unix valutati per l'utente del server (vedi security_for)
+const SDDL_READ_EXECUTE: &str = "O:BA G:SY D:(A;;FRFX;;;WD)";
+const SDDL_READ_ONLY: &str = "O:BA G:SY D:(A;;FR;;;WD)";
+// solo attributi e descrittore: Explorer mostra ancora la voce ma non può aprirla
+const SDDL_ATTRIBUTES_ONLY: &str = "O:BA G:SY D:(A;;0x120080;;;WD)";
 const WINDOWS_TICKS_PER_SEC: u64 = 10_000_000;
 const UNIX_EPOCH_TO_WINDOWS_SECS: u64 = 11_644_473_600;
+// attributi DOS salvati sul server (FileEntry::flags); readonly e directory derivano da tipo e permessi
+const STORED_ATTRIBUTES: u32 = FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM;
+const INVALID_FILE_ATTRIBUTES: u32 = u32::MAX;
+// path risolti tenuti in memoria; quelli scartati vengono risolti di nuovo con il backend
+const LOOKUP_CACHE_SIZE: usize = 65_536;
+// directory di cui ricordiamo l'ultima lista servita, per notificare le voci aggiunte o rimosse
+const LISTINGS_CAP: usize = 1024;
+// caratteri UTF-16 del path di una notifica: copre i path lunghi oltre MAX_PATH (260)
+const NOTIFY_PATH_CAP: usize = 16_384;
+// i caratteri non validi nei nomi Windows diventano NAME_ESCAPE_BASE + codice (area private-use)
+const NAME_ESCAPE_BASE: u32 = 0xF000;
+
+/// Ino richiamati dal server e raccolti tra un giro del timer di notifica e il successivo
+pub type ChangeBatch = Vec<u64>;
+/// Ogni quanti millisecondi WinFsp chiede se ci sono modifiche remote da notificare
+pub const NOTIFY_INTERVAL_MS: u32 = 1000;
 
 fn sd_from_sddl(sddl: &str, dest: Option<&mut [c_void]>) -> Result<u64, FspError> {
     use windows_permissions::{LocalBox, SecurityDescriptor};
@@ -63,6 +92,7 @@ fn map_error(error: &BackendError) -> FspError {
             eprintln!("Forbidden error.");
             FspError::IO(ErrorKind::PermissionDenied)
         },
+        BackendError::NotPermitted(_) => FspError::IO(ErrorKind::PermissionDenied),
         BackendError::Conflict(err) => {
             eprintln!("Conflict error: {}", err);
             FspError::IO(ErrorKind::AlreadyExists)
@@ -71,14 +101,46 @@ fn map_error(error: &BackendError) -> FspError {
             eprintln!("Internal server error.");
             FspError::IO(ErrorKind::Other)
         },
-        BackendError::BadAnswerFormat => {
-            eprintln!("Bad answer format.");
+        BackendError::BadAnswerFormat(err) => {
+            eprintln!("Bad answer format: {}", err);
             FspError::IO(ErrorKind::InvalidData)
         },
         BackendError::ServerUnreachable => {
             eprintln!("Server unreachable.");
             FspError::IO(ErrorKind::TimedOut)
         },
+        BackendError::PreconditionFailed => {
+            eprintln!("Precondition failed: file modified by another client.");
+            FspError::IO(ErrorKind::ResourceBusy)
+        },
+        BackendError::Interrupted => FspError::IO(ErrorKind::Interrupted),
+        BackendError::ReadOnly => FspError::IO(ErrorKind::ReadOnlyFilesystem),
+        BackendError::Stale(path) => {
+            eprintln!("Stale handle: {} no longer resolves on the server.", path);
+            FspError::IO(ErrorKind::StaleNetworkFileHandle)
+        },
+        BackendError::Unsupported(what) => {
+            eprintln!("Not supported by the server: {}", what);
+            FspError::IO(ErrorKind::Unsupported)
+        },
+        BackendError::NotADirectory(_) => FspError::IO(ErrorKind::NotADirectory),
+        BackendError::IsADirectory(_) => FspError::IO(ErrorKind::IsADirectory),
+        BackendError::NotEmpty(_) => FspError::IO(ErrorKind::DirectoryNotEmpty),
+        BackendError::InvalidArgument(err) => {
+            eprintln!("Invalid argument: {}", err);
+            FspError::IO(ErrorKind::InvalidInput)
+        },
+        BackendError::NoSpace(err) => {
+            eprintln!("No space left on the server: {}", err);
+            FspError::IO(ErrorKind::StorageFull)
+        },
+        BackendError::TooLarge(_) => FspError::IO(ErrorKind::FileTooLarge),
+        BackendError::Throttled(err) => {
+            eprintln!("Throttled by the server: {}", err);
+            FspError::IO(ErrorKind::ResourceBusy)
+        },
+        // come un LockFile locale in conflitto
+        BackendError::Locked(_) => FspError::WIN32(ERROR_LOCK_VIOLATION),
         BackendError::Other(err) => {
             eprintln!("Backend error: {}", err);
             FspError::IO(ErrorKind::InvalidData) 
@@ -98,6 +160,15 @@ fn system_time_to_filetime(time: SystemTime) -> u64 {
     }
 }
 
+// Windows FILETIME → SystemTime; 0 indica "non modificare"
+fn filetime_to_system_time(filetime: u64) -> Option<SystemTime> {
+    if filetime == 0 {
+        return None;
+    }
+    let ticks = filetime.checked_sub(UNIX_EPOCH_TO_WINDOWS_SECS * WINDOWS_TICKS_PER_SEC)?; // prima del 1970: ignorato
+    Some(SystemTime::UNIX_EPOCH + Duration::new(ticks / WINDOWS_TICKS_PER_SEC, ((ticks % WINDOWS_TICKS_PER_SEC) * 100) as u32))
+}
+
 #[inline]
 fn entry_to_file_info(file_info: &mut FileInfo, entry: &FileEntry) -> () {
     
@@ -106,6 +177,13 @@ fn entry_to_file_info(file_info: &mut FileInfo, entry: &FileEntry) -> () {
         EntryType::File => FILE_ATTRIBUTE_ARCHIVE,
         EntryType::Symlink => FILE_ATTRIBUTE_REPARSE_POINT,
     };
+    file_info.file_attributes |= entry.flags & STORED_ATTRIBUTES;
+    if entry.name.starts_with('.') { // dotfile → nascosto, come su Unix
+        file_info.file_attributes |= FILE_ATTRIBUTE_HIDDEN;
+    }
+    if (entry.kind == EntryType::File && entry.perms & 0o222 == 0) || entry.file_flags & FILE_FLAG_IMMUTABLE != 0 {
+        file_info.file_attributes |= FILE_ATTRIBUTE_READONLY;
+    }
     
     file_info.file_size = entry.size;
     file_info.allocation_size = if entry.kind == EntryType::Directory {
@@ -130,6 +208,36 @@ fn entry_to_file_info(file_info: &mut FileInfo, entry: &FileEntry) -> () {
 }
 
 
+// estensioni considerate eseguibili dalla policy by_extension
+const EXEC_EXTENSIONS: &[&str] = &["sh", "bash", "zsh", "ksh", "csh", "fish", "py", "pl", "rb", "command", "run", "appimage"];
+
+/// Come assegnare i permessi ai file creati da Windows, che non ha il concetto di bit di esecuzione.
+#[derive(Debug, Clone)]
+pub struct ExecPolicy {
+    /// file con estensione da script (.sh, .py, ...) creati eseguibili
+    pub by_extension: bool,
+    /// file creati da questo client e scritti con uno shebang (`#!`) all'offset 0 resi eseguibili
+    pub by_shebang: bool,
+}
+
+impl Default for ExecPolicy {
+    fn default() -> Self {
+        Self { by_extension: true, by_shebang: true }
+    }
+}
+
+// aggiunge x dove c'è r (0o644 → 0o755)
+#[inline]
+fn with_exec_bits(mode: u32) -> u32 {
+    mode | ((mode & 0o444) >> 2)
+}
+
+fn is_script_name(name: &str) -> bool {
+    Path::new(name).extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| EXEC_EXTENSIONS.iter().any(|x| x.eq_ignore_ascii_case(e)))
+}
+
 struct StreamState{
     pos: u64,
     buffer: Vec<u8>,
@@ -153,131 +261,647 @@ enum ReadMode{
     LargeStream(StreamState),
 }
 
+pub type DataBackend = Box<dyn RemoteBackend>;
+
+// backend indipendenti per scaricare i dati senza tenere il lock della cache, così letture
+// di file diversi procedono in parallelo; se ne crea uno nuovo quando sono tutti occupati
+struct DataPool {
+    factory: Box<dyn Fn() -> DataBackend + Send + Sync>,
+    idle: Mutex<Vec<DataBackend>>,
+}
+
+impl DataPool {
+    fn with<R>(&self, f: impl FnOnce(&mut dyn RemoteBackend) -> R) -> R {
+        let idle = self.idle.lock().expect("Mutex poisoned").pop();
+        let mut backend = idle.unwrap_or_else(|| (self.factory)());
+        let res = f(backend.as_mut());
+        self.idle.lock().expect("Mutex poisoned").push(backend);
+        res
+    }
+}
+
 pub struct RemoteFS<B: RemoteBackend> {
-    backend: Mutex<B>,
+    backend: Arc<Mutex<B>>,
     rt: Arc<Runtime>, // runtime per eseguire le operazioni asincrone
 
     // inode/path management
-    lookup_ino: Mutex<HashMap<String, u64>>,
+    lookup_ino: Mutex<LruCache<String, u64>>, // cache path -> ino, limitata (vedi resolve_ino)
 
     // file handle management
     next_fh: AtomicU64, // file handle da allocare
-    fh_to_entry: Mutex<HashMap<u64, FileEntry>>,
-    read_file_handles: Mutex<HashMap<u64, ReadMode>>, // mappa file handle, per gestire read in streaming continuo su file già aperti
-    write_buffers: Mutex<HashMap<u64, BTreeMap<u64, Vec<u8>>>>, // buffer di scrittura per ogni file aperto; il valore è la coppia (buffer, offset)
+    fh_to_entry: Arc<Mutex<HashMap<u64, FileEntry>>>,
+    read_file_handles: Mutex<HashMap<u64, Arc<Mutex<ReadMode>>>>, // mappa file handle, per gestire read in streaming continuo su file già aperti
+    write_buffers: Arc<Mutex<HashMap<u64, DirtyRanges>>>, // byte scritti e non ancora inviati, per ogni file aperto in scrittura
     files_to_delete: Mutex<HashMap<u64, String>>, // fh -> path (set by set_delete, used by cleanup)
+    granted_access: Mutex<HashMap<u64, FILE_ACCESS_RIGHTS>>, // fh -> diritti concessi all'apertura
+
+    exec_policy: ExecPolicy,
+    create_modes: CreateModes, // permessi e umask dei file e delle directory creati; senza, i default del server
+    shebang_pending: Mutex<HashSet<u64>>, // ino creati qui e non ancora scritti all'offset 0 (policy by_shebang)
+    data_pool: Option<DataPool>, // se assente le read passano tutte dalla cache
+    io: IoSizes, // blocchi della cache e soglia oltre cui letture e scritture vanno in streaming
+    changes: Option<Mutex<Receiver<u64>>>, // ino richiamati dal server: cache da rompere e modifiche da notificare
+    listings: Mutex<LruCache<u64, (String, HashMap<String, u64>)>>, // dir ino -> (path, nome -> ino) dell'ultima read_directory
+    junk: JunkFilter, // desktop.ini, Thumbs.db, ...: nascosti o non creati
+    identity: Option<Identity>, // utente del server e suoi gruppi; senza, i descrittori concedono tutto e decide il server
+    root: Mutex<Option<FileEntry>>, // ultimi attributi noti della root, letti al mount e aggiornati a ogni lettura
+    audit: Option<(AuditLog, String)>, // log di audit e mount point riportato nei record
+    policy: Policy, // regole che nascondono voci o vietano operazioni, valutate prima di chiamare il backend
+    hydration: bool, // file su richiesta: contenuto scaricato alla prima lettura e tenuto nella cache persistente
+    speed_file: Option<Mutex<File>>, // speed testing: durata di ogni operazione, come sul mount FUSE
+}
+
+// durata di un'operazione, scritta nel log di speed testing quando la guardia esce di scope
+struct SpeedTimer<'a> {
+    file: &'a Mutex<File>,
+    what: String,
+    started: Instant,
+}
+
+impl Drop for SpeedTimer<'_> {
+    fn drop(&mut self) {
+        use std::io::Write;
+        let mut file = self.file.lock().expect("Mutex poisoned");
+        writeln!(file, "[speed] {} duration: {:?}", self.what, self.started.elapsed()).ok();
+    }
 }
 
 impl<B: RemoteBackend> RemoteFS<B> {
     pub fn new(backend: B,runtime: Arc<Runtime>) -> Self {
-        let mut ino_map=HashMap::new();
-        ino_map.insert(String::from("\\"), 1u64); // root directory
         Self {
-            backend: Mutex::new(backend),
+            backend: Arc::new(Mutex::new(backend)),
             rt: runtime,
-            lookup_ino: Mutex::new(ino_map),
+            lookup_ino: Mutex::new(LruCache::new(NonZeroUsize::new(LOOKUP_CACHE_SIZE).expect("non-zero capacity"))),
             next_fh: AtomicU64::new(3), //0,1,2 di solito sono assegnati, da controllare
-            fh_to_entry: Mutex::new(HashMap::new()),
+            fh_to_entry: Arc::new(Mutex::new(HashMap::new())),
             read_file_handles: Mutex::new(HashMap::new()),
-            write_buffers: Mutex::new(HashMap::new()),
+            write_buffers: Arc::new(Mutex::new(HashMap::new())),
             files_to_delete: Mutex::new(HashMap::new()),
+            granted_access: Mutex::new(HashMap::new()),
+            exec_policy: ExecPolicy::default(),
+            create_modes: CreateModes::default(),
+            shebang_pending: Mutex::new(HashSet::new()),
+            data_pool: None,
+            io: IoSizes::default(),
+            changes: None,
+            listings: Mutex::new(LruCache::new(NonZeroUsize::new(LISTINGS_CAP).expect("non-zero capacity"))),
+            junk: JunkFilter::default(),
+            identity: None,
+            root: Mutex::new(None),
+            audit: None,
+            policy: Policy::default(),
+            hydration: false,
+            speed_file: None,
+        }
+    }
+
+    /// Canale degli ino di cui il server ha richiamato il lease perché un altro client li modifica. Per i file
+    /// è la rottura dell'oplock: si scartano i dati letti in anticipo dagli handle aperti e la notifica di
+    /// modifica fa invalidare a WinFsp le sue cache sul file; per le directory diventa la notifica delle voci
+    /// cambiate (serve un host creato con `FileSystemHost::new_with_timer`)
+    pub fn with_change_notifications(mut self, changes: Receiver<u64>) -> Self {
+        self.changes = Some(Mutex::new(changes));
+        self
+    }
+
+    /// File creati dai sistemi operativi (desktop.ini, Thumbs.db, ...) da nascondere o non creare
+    pub fn with_junk_filter(mut self, junk: JunkFilter) -> Self {
+        self.junk = junk;
+        self
+    }
+
+    /// Utente del server e gruppi di cui fa parte, usati per tradurre i permessi unix nei security descriptor
+    pub fn with_identity(mut self, identity: Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Attributi della root letti al mount, usati se il server non risponde quando vengono richiesti
+    pub fn with_root(self, root: FileEntry) -> Self {
+        *self.root.lock().expect("Mutex poisoned") = Some(root);
+        self
+    }
+
+    /// Log in cui registrare le operazioni che aprono o modificano file
+    pub fn with_audit_log(mut self, audit: AuditLog, mount_point: &str) -> Self {
+        self.audit = Some((audit, mount_point.to_string()));
+        self
+    }
+
+    /// Regole di policy per path; quelle legate a un processo non valgono, WinFsp non dice chi chiede l'operazione
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// File su richiesta, come OneDrive: le voci compaiono subito, il contenuto viene scaricato nella cache
+    /// persistente alla prima lettura (i file oltre la soglia di streaming solo se pinnati) e lo stato della
+    /// copia locale è negli attributi. `attrib +P` tiene sempre il file in locale, `attrib +U` libera la copia.
+    /// Serve una cache con archivio dei pin.
+    pub fn with_hydration(mut self) -> Self {
+        self.hydration = true;
+        self
+    }
+
+    /// Speed testing: la durata di ogni operazione viene scritta in `file`, una riga per operazione
+    pub fn with_speed_log(mut self, file: File) -> Self {
+        self.speed_file = Some(Mutex::new(file));
+        self
+    }
+
+    // con lo speed testing attivo, misura l'operazione descritta da `what` fino alla fine dello scope
+    fn speed(&self, what: impl FnOnce() -> String) -> Option<SpeedTimer<'_>> {
+        let file = self.speed_file.as_ref()?;
+        Some(SpeedTimer { file, what: what(), started: Instant::now() })
+    }
+
+    // ino del file aperto con l'handle `fh`, per i log (0 se l'handle non è più noto)
+    fn handle_ino(&self, fh: u64) -> u64 {
+        self.fh_to_entry.lock().expect("Mutex poisoned").get(&fh).map_or(0, |entry| entry.ino)
+    }
+
+    // errore per un'operazione sul path remoto `path` vietata dalla policy; `write` se crea, modifica, rinomina o cancella
+    fn policy_check(&self, path: &str, write: bool) -> Result<(), BackendError> {
+        match self.policy.check(path, None, write) {
+            Ok(()) => Ok(()),
+            Err(PolicyAction::Hidden) if !write => Err(BackendError::NotFound(path.to_string())),
+            Err(_) => Err(BackendError::NotPermitted(format!("{} denied by policy", path))),
         }
     }
 
-    fn get_parent_ino_and_fname<'a>(&self, path: &String) -> Result<(u64, String), FspError> {
+    fn policy_hides(&self, path: &str) -> bool {
+        self.policy.action(path, None) == Some(PolicyAction::Hidden)
+    }
 
-        let path_obj = Path::new(&path);
-        let parent_path = match path_obj.parent() {
-            Some(p) => p.to_string_lossy().to_string(),
-            None => "\\".to_string(), // root directory
+    // attributi della voce, con lo stato della copia locale se i file sono su richiesta
+    fn fill_file_info(&self, file_info: &mut FileInfo, entry: &FileEntry) {
+        entry_to_file_info(file_info, entry);
+        if !self.hydration || entry.kind != EntryType::File || entry.size == 0 {
+            return;
+        }
+        file_info.file_attributes |= match self.backend.lock().expect("Mutex poisoned").hydration(entry.ino) {
+            Hydration::Remote => FILE_ATTRIBUTE_RECALL_ON_DATA_ACCESS,
+            Hydration::Hydrated => 0,
+            Hydration::Pinned => FILE_ATTRIBUTE_PINNED,
         };
-        let f_name = match path_obj.file_name() {
-            Some(name) => name.to_string_lossy().to_string(),
-            None => match parent_path.as_str() {
-                "\\" => "".to_string(),
-                _ => return Err(FspError::IO(ErrorKind::InvalidInput))
-            },
+    }
+
+    // true se `entry` va letto dalla copia locale: la prima lettura di un file non in streaming lo scarica
+    // per intero nella cache persistente
+    fn hydrate_for_read(&self, entry: &FileEntry) -> Result<bool, BackendError> {
+        if !self.hydration {
+            return Ok(false);
+        }
+        let mut backend = self.backend.lock().expect("Mutex poisoned");
+        if backend.has_local_copy(entry.ino) {
+            return Ok(true);
+        }
+        if entry.size > self.io.large_file_size {
+            return Ok(false);
+        }
+        backend.hydrate(entry.ino, false)?;
+        Ok(true)
+    }
+
+    // PINNED (attrib +P, "Mantieni sempre su questo dispositivo") pinna il file, UNPINNED (attrib +U, "Libera
+    // spazio") scarta la copia locale; togliere PINNED lascia la copia ma permette di liberarla
+    fn set_hydration(&self, entry: &FileEntry, attributes: u32) {
+        let pin = attributes & FILE_ATTRIBUTE_PINNED != 0;
+        let unpin = attributes & FILE_ATTRIBUTE_UNPINNED != 0;
+        let mut backend = self.backend.lock().expect("Mutex poisoned");
+        let state = backend.hydration(entry.ino);
+        if unpin && (state == Hydration::Pinned || (state == Hydration::Hydrated && !pin)) {
+            backend.dehydrate(entry.ino);
+            drop(backend);
+            self.audit("unpin", &entry.path, None, &Ok::<(), BackendError>(()));
+        } else if pin && state != Hydration::Pinned {
+            let res = backend.hydrate(entry.ino, true);
+            drop(backend);
+            self.audit("pin", &entry.path, None, &res);
+            if let Err(e) = res {
+                eprintln!("Unable to pin {}: {}", entry.path, e);
+            }
+        } else if !pin && state == Hydration::Pinned && let Err(e) = backend.hydrate(entry.ino, false) {
+            eprintln!("Unable to unpin {}: {}", entry.path, e);
+        }
+    }
+
+    // registra `op` nel log di audit, se attivo; WinFsp non dice quale processo ha chiesto l'operazione
+    fn audit<T, E: std::fmt::Debug>(&self, op: &str, path: &str, target: Option<&str>, res: &Result<T, E>) {
+        let Some((audit, mount)) = self.audit.as_ref() else { return };
+        let mut record = AuditRecord::new(mount, op, path.replace('\\', "/"));
+        record.target = target.map(|t| t.replace('\\', "/"));
+        record.error = res.as_ref().err().map(|e| format!("{:?}", e));
+        audit.record(&record);
+    }
+
+    // attributi della root dal server; offline gli ultimi noti, così "\\" non diventa irraggiungibile
+    fn root_attr(&self) -> Result<FileEntry, BackendError> {
+        match self.backend.lock().expect("Mutex poisoned").get_attr(ROOT_INO) {
+            Ok(entry) => {
+                *self.root.lock().expect("Mutex poisoned") = Some(entry.clone());
+                Ok(entry)
+            }
+            Err(e) => self.root.lock().expect("Mutex poisoned").clone().ok_or(e),
+        }
+    }
+
+    // descrittore di sicurezza di una voce: Everyone riceve i diritti che l'utente del server ha sul file
+    fn security_for(&self, entry: &FileEntry) -> &'static str {
+        let Some(me) = self.identity.as_ref() else { return SDDL_ALLOW_ALL };
+        if matches!(entry.kind, EntryType::Symlink) || me.allows(entry, ACCESS_WRITE) {
+            SDDL_ALLOW_ALL
+        } else if !me.allows(entry, ACCESS_READ) {
+            SDDL_ATTRIBUTES_ONLY
+        } else if me.allows(entry, ACCESS_EXEC) {
+            SDDL_READ_EXECUTE
+        } else {
+            SDDL_READ_ONLY
+        }
+    }
+
+    /// Dimensioni di I/O, da tenere uguali a quelle della cache sottostante
+    pub fn with_io_sizes(mut self, io: IoSizes) -> Self {
+        self.io = io;
+        self
+    }
+
+    /// Backend usati per scaricare i blocchi mancanti in parallelo, fuori dal lock del backend principale
+    pub fn with_data_backends(mut self, factory: impl Fn() -> DataBackend + Send + Sync + 'static) -> Self {
+        self.data_pool = Some(DataPool { factory: Box::new(factory), idle: Mutex::new(Vec::new()) });
+        self
+    }
+
+    // legge dalla rete i blocchi che la cache non ha, poi glieli consegna per le letture successive
+    // lease di lettura chiesto con un backend del pool: la richiesta non tiene il lock della cache
+    fn read_lease(&self, ino: u64) {
+        if !self.backend.lock().expect("Mutex poisoned").wanted_lease(ino, LeaseKind::Read) {
+            return;
+        }
+        let asked = Instant::now();
+        let lease = match self.data_pool.as_ref() {
+            Some(pool) => pool.with(|backend| backend.acquire_lease(ino, LeaseKind::Read)),
+            None => self.backend.lock().expect("Mutex poisoned").acquire_lease(ino, LeaseKind::Read),
         };
+        match lease {
+            Ok(lease) => self.backend.lock().expect("Mutex poisoned").offer_lease(ino, lease, asked),
+            Err(e) => eprintln!("Lease request for ino {} failed: {}", ino, e),
+        }
+    }
 
-        let parent_ino = match self.lookup_ino.lock().expect("Mutex poisoned").get(&parent_path) {
-            Some(&ino) => ino,
-            None => return Err(FspError::IO(ErrorKind::NotFound))
+    fn read_uncached(&self, entry: &FileEntry, offset: u64, size: u64) -> Result<Vec<u8>, BackendError> {
+        let Some(pool) = self.data_pool.as_ref() else {
+            return self.backend.lock().expect("Mutex poisoned").read_chunk(entry.ino, offset, size);
         };
-        Ok((parent_ino, f_name))
+        let block = self.io.block_size as u64;
+        let start = offset / block * block;
+        let end = (offset + size).div_ceil(block) * block;
+        let data = match pool.with(|backend| backend.read_chunk(entry.ino, start, end - start)) {
+            // offline la cache può ancora rispondere con le copie locali
+            Err(BackendError::ServerUnreachable) => return self.backend.lock().expect("Mutex poisoned").read_chunk(entry.ino, offset, size),
+            res => res?,
+        };
+        self.backend.lock().expect("Mutex poisoned").prime_range(entry, start, &data);
+        let from = ((offset - start) as usize).min(data.len());
+        let to = ((offset + size - start) as usize).min(data.len());
+        Ok(data[from..to].to_vec())
     }
 
-    fn flush_file(&self, fh: u64) -> Result<(), BackendError> {
+    pub fn with_exec_policy(mut self, policy: ExecPolicy) -> Self {
+        self.exec_policy = policy;
+        self
+    }
 
-        let mut start_offset = 0u64;
-        let mut last_offset = 0u64;
-        let mut prev_block_size = 0u64;
+    /// Permessi e umask dei file e delle directory creati da questo mount
+    pub fn with_create_modes(mut self, modes: CreateModes) -> Self {
+        self.create_modes = modes;
+        self
+    }
 
-        let ino = match self.fh_to_entry.lock().expect("Mutex poisoned").get(&fh) {
-            Some(e) => e.ino,
-            None => return Err(BackendError::NotFound(String::from("File handle associated to no ino"))),
-        };
+    // imposta i permessi di un file appena creato secondo la policy
+    fn apply_create_mode(&self, entry: FileEntry) -> Result<FileEntry, BackendError> {
+        let mut mode = self.create_modes.file(entry.perms as u32, 0);
+        if self.exec_policy.by_extension && is_script_name(&entry.name) {
+            mode = self.create_modes.masked(with_exec_bits(mode));
+        }
+        if self.exec_policy.by_shebang {
+            self.shebang_pending.lock().expect("Mutex poisoned").insert(entry.ino);
+        }
+        if mode == entry.perms as u32 {
+            return Ok(entry);
+        }
+        self.set_mode(entry.ino, mode)
+    }
 
-        // Collect the map's contents into a vector to avoid double mutable borrow
-        let map_entries: Vec<(u64, Vec<u8>)> = {
-            let write_buffers_lock = self.write_buffers.lock().expect("mutex poisoned");
-            let mut write_buffers = write_buffers_lock;
-            let map: &mut BTreeMap<u64, Vec<u8>> = write_buffers.get_mut(&fh).unwrap();
-            let entries = map.iter().map(|(k, v)| (*k, v.clone())).collect();
-            map.clear();
-            entries
+    fn set_mode(&self, ino: u64, mode: u32) -> Result<FileEntry, BackendError> {
+        let attribute = SetAttrRequest {
+            size: None,
+            perm: Some(mode),
+            uid: None,
+            gid: None,
+            flags: None,
+            file_flags: None,
+            atime: None,
+            mtime: None,
+            btime: None,
         };
-        
-        let mut buffer = Vec::<u8>::new();
-        for (off, data) in map_entries.iter() {
+        self.backend.lock().expect("Mutex poisoned").set_attr(ino, attribute)
+    }
 
-            if buffer.is_empty() || last_offset + prev_block_size as u64 == *off {
-                if buffer.is_empty() {
-                    start_offset = *off;
-                }
-                buffer.extend_from_slice(&data);
-            } else {
-                // Flush the current buffer
-                self.flush_buffer(&mut buffer, ino, start_offset)?;
-                start_offset = *off;
-                buffer.clear();
-                buffer.extend_from_slice(&data);
+    // ino della directory padre e nome remoto dell'ultimo componente. Il path è diviso sul separatore
+    // e non con Path, che darebbe un significato a caratteri (es. ':') ammessi nei nomi remoti
+    fn get_parent_ino_and_fname(&self, path: &str) -> Result<(u64, String), FspError> {
+        let path = path.trim_end_matches('\\');
+        let (parent_path, f_name) = path.rsplit_once('\\').unwrap_or(("", path));
+        let parent_path = if parent_path.is_empty() { "\\" } else { parent_path }; // root directory
+
+        let parent_ino = self.resolve_ino(parent_path)?;
+        Ok((parent_ino, from_windows_name(f_name).into_owned()))
+    }
+
+    // ino di un path: dalla cache se c'è, altrimenti risolto componente per componente con il backend
+    fn resolve_ino(&self, path: &str) -> Result<u64, FspError> {
+        if path == "\\" || path.is_empty() {
+            return Ok(ROOT_INO); // root directory
+        }
+        if let Some(&ino) = self.lookup_ino.lock().expect("Mutex poisoned").get(path) {
+            return Ok(ino);
+        }
+        let (parent_ino, f_name) = self.get_parent_ino_and_fname(path)?;
+        let entry = self.backend.lock().expect("Mutex poisoned").lookup(parent_ino, &f_name).map_err(|e| map_error(&e))?;
+        self.lookup_ino.lock().expect("Mutex poisoned").put(path.to_string(), entry.ino);
+        Ok(entry.ino)
+    }
+
+    // attributi di un path; se il server ha riassegnato l'ino in cache (BackendError::Stale) il path viene
+    // risolto di nuovo, e l'errore arriva a Windows solo se non si risolve più
+    fn attr_by_path(&self, path: &str) -> Result<FileEntry, FspError> {
+        let ino = self.resolve_ino(path)?;
+        if ino == ROOT_INO {
+            return self.root_attr().map_err(|e| map_error(&e));
+        }
+        let res = self.backend.lock().expect("Mutex poisoned").get_attr(ino);
+        match res {
+            Err(BackendError::Stale(_)) => {
+                self.forget_path(path);
+                let ino = self.resolve_ino(path)?;
+                self.backend.lock().expect("Mutex poisoned").get_attr(ino).map_err(|e| map_error(&e))
             }
-            last_offset = *off;
-            prev_block_size = data.len() as u64;
+            res => res.map_err(|e| map_error(&e)),
         }
+    }
 
-        // flushing last bytes
-        if !buffer.is_empty() {
-            self.flush_buffer(&mut buffer, ino, start_offset)?;
+    // dimentica un path e tutto quello che sta sotto (rename o cancellazione di una directory)
+    fn forget_path(&self, path: &str) {
+        let prefix = format!("{}\\", path.trim_end_matches('\\'));
+        let mut lookup_cache = self.lookup_ino.lock().expect("Mutex poisoned");
+        let stale: Vec<String> = lookup_cache.iter()
+            .map(|(p, _)| p)
+            .filter(|p| p.as_str() == path || p.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for p in stale {
+            lookup_cache.pop(&p);
         }
+    }
 
-        Ok(())
+    // rename di una directory: le voci sotto il vecchio path mantengono il loro ino e vengono spostate sotto
+    // il nuovo, invece di restare irraggiungibili o di essere risolte di nuovo componente per componente.
+    // `from` e `to` sono i path della directory sul server, quelli delle voci degli handle aperti
+    fn move_path(&self, old_path: &str, new_path: &str, from: &FileEntry, to: &FileEntry) {
+        let old_prefix = format!("{}\\", old_path.trim_end_matches('\\'));
+        let new_prefix = format!("{}\\", new_path.trim_end_matches('\\'));
+        let moved = |p: &str| p.strip_prefix(&old_prefix).map(|rest| format!("{}{}", new_prefix, rest));
+
+        let mut lookup_cache = self.lookup_ino.lock().expect("Mutex poisoned");
+        let children: Vec<(String, u64)> = lookup_cache.iter()
+            .filter(|(p, _)| p.starts_with(&old_prefix))
+            .map(|(p, &ino)| (p.clone(), ino))
+            .collect();
+        for (p, ino) in children {
+            lookup_cache.pop(&p);
+            if let Some(new_p) = moved(&p) {
+                lookup_cache.put(new_p, ino);
+            }
+        }
+        drop(lookup_cache);
+
+        // le liste ricordate per le notifiche seguono la directory, altrimenti verrebbero notificati i vecchi path
+        for (_, (dir_path, _)) in self.listings.lock().expect("Mutex poisoned").iter_mut() {
+            if *dir_path == old_path {
+                *dir_path = new_path.to_string();
+            } else if let Some(new_p) = moved(dir_path.as_str()) {
+                *dir_path = new_p;
+            }
+        }
+
+        // path remoti degli handle aperti sotto la directory (usati da known_paths e dalle liste)
+        let old_remote_prefix = format!("{}/", from.path.trim_end_matches('/'));
+        for entry in self.fh_to_entry.lock().expect("Mutex poisoned").values_mut() {
+            if let Some(rest) = entry.path.strip_prefix(&old_remote_prefix) {
+                entry.path = format!("{}/{}", to.path.trim_end_matches('/'), rest);
+            }
+        }
     }
 
-    fn flush_buffer(&self, buffer: &mut Vec<u8>, ino: u64, offset: u64) -> Result<(), BackendError> {
-        if !buffer.is_empty() {
-            if buffer.len() > LARGE_FILE_SIZE as usize {
-                self.backend.lock().expect("Mutex poisoned").write_stream(ino, offset, buffer.clone())?
-            } else {
-                self.backend.lock().expect("Mutex poisoned").write_chunk(ino, offset, buffer.clone())?;
+    // handle con la cancellazione alla chiusura sotto il vecchio path: a cleanup va cancellato il nuovo
+    fn move_pending_deletes(&self, old_path: &str, new_path: &str) {
+        let old_prefix = format!("{}\\", old_path.trim_end_matches('\\'));
+        for path in self.files_to_delete.lock().expect("Mutex poisoned").values_mut() {
+            if path == old_path {
+                *path = new_path.to_string();
+            } else if let Some(rest) = path.strip_prefix(&old_prefix) {
+                *path = format!("{}\\{}", new_path.trim_end_matches('\\'), rest);
             }
         }
-        buffer.clear();
-        Ok(())
     }
 
+    // reparse point IO_REPARSE_TAG_SYMLINK del symlink `entry`, che si trova in `path`, scritto in `buffer`
+    fn symlink_reparse_data(&self, path: &str, entry: &FileEntry, buffer: &mut [u8]) -> FspResult<u64> {
+        if entry.kind != EntryType::Symlink {
+            return Err(FspError::IO(ErrorKind::InvalidInput));
+        }
+        let target = self.backend.lock().expect("Mutex poisoned").readlink(entry.ino).map_err(|e| map_error(&e))?;
+        let data = symlink_reparse_buffer(&windows_link_target(path, &target));
+        let out = buffer.get_mut(..data.len()).ok_or(FspError::IO(ErrorKind::InvalidInput))?;
+        out.copy_from_slice(&data);
+        Ok(data.len() as u64)
+    }
+
+    // come su NTFS: read e write non coperte dai diritti concessi all'apertura dell'handle vengono rifiutate
+    fn check_access(&self, fh: u64, rights: FILE_ACCESS_RIGHTS) -> FspResult<()> {
+        match self.granted_access.lock().expect("Mutex poisoned").get(&fh) {
+            Some(granted) if granted & rights != 0 => Ok(()),
+            _ => Err(FspError::IO(ErrorKind::PermissionDenied)),
+        }
+    }
+
+    fn flush_file(&self, fh: u64) -> Result<(), BackendError> {
+        flush_handle(&self.backend, &self.fh_to_entry, &self.write_buffers, fh, self.io.large_file_size)
+    }
+
+    /// Aggiornamento in background degli attributi dei file aperti, da avviare con `AttrRefresher::spawn`;
+    /// `server` li rilegge senza passare dalla cache.
+    pub fn attr_refresher<S: RemoteBackend>(&self, server: S) -> AttrRefresher<B, S> {
+        AttrRefresher {
+            server,
+            backend: self.backend.clone(),
+            fh_to_entry: Arc::downgrade(&self.fh_to_entry),
+        }
+    }
+
+    /// Handle da usare allo smontaggio per svuotare i buffer di scrittura rimasti,
+    /// anche dopo che il `FileSystemHost` ha preso possesso del filesystem.
+    pub fn drain_handle(&self) -> DrainHandle<B> {
+        DrainHandle {
+            backend: self.backend.clone(),
+            fh_to_entry: self.fh_to_entry.clone(),
+            write_buffers: self.write_buffers.clone(),
+            large_file_size: self.io.large_file_size,
+        }
+    }
+
+}
+
+/// Rivalida a intervalli gli attributi dei file aperti, così le modifiche di altri client (es. un file
+/// che cresce) si vedono anche dagli handle già aperti; le voci aggiornate finiscono nella mappa degli handle
+/// e quello che la cache sapeva del file viene scartato.
+pub struct AttrRefresher<B: RemoteBackend, S: RemoteBackend> {
+    server: S,
+    backend: Arc<Mutex<B>>,
+    fh_to_entry: Weak<Mutex<HashMap<u64, FileEntry>>>,
+}
+
+impl<B: RemoteBackend + 'static, S: RemoteBackend + 'static> AttrRefresher<B, S> {
+    /// Avvia il thread di aggiornamento; termina quando il filesystem viene distrutto.
+    pub fn spawn(mut self, interval: Duration) -> JoinHandle<()> {
+        thread::Builder::new()
+            .name("rfs-attr-refresh".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                let Some(fh_to_entry) = self.fh_to_entry.upgrade() else { return };
+                let inos: HashSet<u64> = fh_to_entry.lock().expect("Mutex poisoned").values()
+                    .filter(|e| e.kind == EntryType::File)
+                    .map(|e| e.ino)
+                    .collect();
+                for ino in inos {
+                    let fresh = match self.server.get_attr(ino) {
+                        Ok(entry) => entry,
+                        Err(BackendError::NotFound(_)) => continue, // rimosso mentre era aperto
+                        Err(e) => {
+                            eprintln!("Attribute refresh of ino {} failed: {}", ino, e);
+                            continue;
+                        }
+                    };
+                    // solo versioni più recenti: una write locale appena conclusa ha già aggiornato la voce
+                    let mut handles = fh_to_entry.lock().expect("Mutex poisoned");
+                    let mut changed = false;
+                    for entry in handles.values_mut().filter(|e| e.ino == ino && fresh.mtime > e.mtime) {
+                        // nome e path restano quelli con cui l'handle è stato aperto
+                        *entry = FileEntry { name: entry.name.clone(), path: entry.path.clone(), ..fresh.clone() };
+                        changed = true;
+                    }
+                    drop(handles);
+                    if changed {
+                        self.backend.lock().expect("Mutex poisoned").invalidate(ino);
+                    }
+                }
+            })
+            .expect("Unable to start the attribute refresher")
+    }
+}
+
+pub struct DrainHandle<B: RemoteBackend> {
+    backend: Arc<Mutex<B>>,
+    fh_to_entry: Arc<Mutex<HashMap<u64, FileEntry>>>,
+    write_buffers: Arc<Mutex<HashMap<u64, DirtyRanges>>>,
+    large_file_size: u64,
+}
+
+impl<B: RemoteBackend> DrainHandle<B> {
+    /// Numero di file handle con scritture non ancora inviate al server.
+    pub fn pending(&self) -> usize {
+        self.write_buffers.lock().expect("Mutex poisoned").values().filter(|map| !map.is_empty()).count()
+    }
+
+    /// Svuota tutti i buffer sporchi entro `timeout`; restituisce (fh, path, errore) per quelli non scritti.
+    pub fn drain(&self, timeout: Duration) -> Vec<(u64, String, BackendError)> {
+        let deadline = Instant::now() + timeout;
+        let mut failed = Vec::new();
+
+        let mut dirty: Vec<u64> = {
+            let write_buffers = self.write_buffers.lock().expect("Mutex poisoned");
+            write_buffers.iter().filter(|(_, map)| !map.is_empty()).map(|(fh, _)| *fh).collect()
+        };
+        dirty.sort();
+
+        // la scadenza interrompe anche l'invio in corso, non solo i successivi
+        let timer = Deadline::start(deadline);
+        self.backend.lock().expect("Mutex poisoned").set_cancel_token(Some(timer.token().clone()));
+        for fh in dirty {
+            let path = self.fh_to_entry.lock().expect("Mutex poisoned").get(&fh).map(|e| e.path.clone()).unwrap_or_default();
+            if timer.expired() {
+                failed.push((fh, path, BackendError::Other("shutdown timeout expired".to_string())));
+                continue;
+            }
+            if let Err(e) = flush_handle(&self.backend, &self.fh_to_entry, &self.write_buffers, fh, self.large_file_size) {
+                failed.push((fh, path, timer.error(e)));
+            }
+        }
+        self.backend.lock().expect("Mutex poisoned").set_cancel_token(None);
+        failed
+    }
+}
+
+fn flush_handle<B: RemoteBackend>(backend: &Mutex<B>, fh_to_entry: &Mutex<HashMap<u64, FileEntry>>, write_buffers: &Mutex<HashMap<u64, DirtyRanges>>, fh: u64, large_file_size: u64) -> Result<(), BackendError> {
+
+    let ino = match fh_to_entry.lock().expect("Mutex poisoned").get(&fh) {
+        Some(e) => e.ino,
+        None => return Err(BackendError::NotFound(String::from("File handle associated to no ino"))),
+    };
+
+    // i dati escono dalla mappa senza copie (le scritture contigue formano un'unica run) e ci tornano se
+    // il server non li riceve, sotto a quelli scritti nel frattempo
+    let pending = write_buffers.lock().expect("mutex poisoned").get_mut(&fh).map(std::mem::take).unwrap_or_default();
+    let mut runs = pending.into_runs().into_iter().map(|(offset, run)| (offset, shared_chunks(run)));
+    while let Some((offset, run)) = runs.next() {
+        if let Err(e) = flush_run(backend, &run, ino, offset, large_file_size) {
+            if let Some(map) = write_buffers.lock().expect("mutex poisoned").get_mut(&fh) {
+                map.restore(std::iter::once((offset, run)).chain(runs).map(|(offset, run)| (offset, run.concat())));
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+// scrive una run di blocchi contigui; oltre large_file_size i blocchi vengono inviati in streaming uno alla volta
+fn flush_run<B: RemoteBackend>(backend: &Mutex<B>, run: &[Bytes], ino: u64, offset: u64, large_file_size: u64) -> Result<(), BackendError> {
+    let len: usize = run.iter().map(Bytes::len).sum();
+    if len > large_file_size as usize {
+        backend.lock().expect("Mutex poisoned").write_stream(ino, offset, shared_stream(run))?;
+    } else if len > 0 {
+        backend.lock().expect("Mutex poisoned").write_chunk(ino, offset, run.concat())?;
+    }
+    Ok(())
 }
 
+// Byte-range lock (LockFile/UnlockFileEx): WinFsp li gestisce nel driver kernel, senza callback
+// lock/unlock verso il filesystem user mode, quindi valgono fra i processi di questa macchina.
+// I lock degli altri client (fcntl sui mount fuse, tenuti dal server) valgono invece anche qui:
+// il backend del mount è creato con HttpBackend::with_mandatory_locks e il server rifiuta le
+// write su un intervallo bloccato, che arrivano all'applicazione come ERROR_LOCK_VIOLATION.
 impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
     type FileContext = u64; // file handle
 
-    fn get_security_by_name(&self,file_name: &U16CStr,security_descriptor: Option<&mut [c_void]>,_reparse_point_resolver: impl FnOnce(&U16CStr) -> Option<FileSecurity>) -> FspResult<FileSecurity> {
+    fn get_security_by_name(&self,file_name: &U16CStr,security_descriptor: Option<&mut [c_void]>,reparse_point_resolver: impl FnOnce(&U16CStr) -> Option<FileSecurity>) -> FspResult<FileSecurity> {
         let path = file_name.to_string_lossy();
         //println!("get_security_by_name: path='{}'", path);
 
         if path == "\\" {
-            // root directory
-            let secdesc_len = sd_from_sddl(SDDL_ALLOW_ALL, security_descriptor)?;
+            // root directory: permessi veri della root del server, senza decide il server
+            let sddl = self.root_attr().map_or(SDDL_ALLOW_ALL, |root| self.security_for(&root));
+            let secdesc_len = sd_from_sddl(sddl, security_descriptor)?;
             return Ok(FileSecurity {
                 reparse: false,
                 sz_security_descriptor: secdesc_len,
@@ -285,15 +909,20 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
             });
         }
 
-        if path.ends_with("\\desktop.ini") {
+        if self.junk.hides(path.rsplit('\\').next().unwrap_or_default()) || self.policy_hides(&from_windows_path(&path)) {
             return Err(FspError::IO(ErrorKind::NotFound));
         }
         
-        let (parent_ino, f_name) = self.get_parent_ino_and_fname(&path)?;
+        // un componente intermedio che non si risolve può essere un symlink: WinFsp cerca il reparse point
+        // con get_reparse_point_by_name e fa riaprire il path con il target
+        let (parent_ino, f_name) = match self.get_parent_ino_and_fname(&path) {
+            Ok(parent) => parent,
+            Err(e) => return reparse_point_resolver(file_name).ok_or(e),
+        };
         let entry: FileEntry = self.backend.lock().expect("Mutex poisoned").lookup(parent_ino, &f_name).map_err(|err| map_error(&err))?;
-        self.lookup_ino.lock().expect("Mutex poisoned").insert(path.clone(), entry.ino);
+        self.lookup_ino.lock().expect("Mutex poisoned").put(path.clone(), entry.ino);
 
-        let secdesc_len = sd_from_sddl(SDDL_ALLOW_ALL, security_descriptor)?;
+        let secdesc_len = sd_from_sddl(self.security_for(&entry), security_descriptor)?;
         Ok(FileSecurity {
             reparse: matches!(entry.kind, EntryType::Symlink),
             sz_security_descriptor: secdesc_len,
@@ -305,31 +934,47 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
         })
     }
 
-    fn open(&self,file_name: &U16CStr,_create_options: u32,_granted_access: FILE_ACCESS_RIGHTS,file_info: &mut OpenFileInfo) -> FspResult<Self::FileContext> {
+    fn get_reparse_point_by_name(&self, file_name: &U16CStr, _is_directory: bool, buffer: &mut [u8]) -> FspResult<u64> {
+        let path = file_name.to_string_lossy();
+        let entry = self.attr_by_path(&path)?;
+        self.symlink_reparse_data(&path, &entry, buffer)
+    }
+
+    fn get_reparse_point(&self, context: &Self::FileContext, file_name: &U16CStr, buffer: &mut [u8]) -> FspResult<u64> {
+        let entry = self.fh_to_entry.lock().expect("Mutex poisoned").get(context).cloned().ok_or(FspError::IO(ErrorKind::NotFound))?;
+        self.symlink_reparse_data(&file_name.to_string_lossy(), &entry, buffer)
+    }
+
+    fn open(&self,file_name: &U16CStr,_create_options: u32,granted_access: FILE_ACCESS_RIGHTS,file_info: &mut OpenFileInfo) -> FspResult<Self::FileContext> {
+        let _speed = self.speed(|| format!("open of {}", file_name.to_string_lossy()));
         let path = file_name.to_string_lossy();
         //println!("open: path='{}'", path);
     
-        // lookup
-        let ino = *self.lookup_ino.lock().expect("Mutex poisoned").get(&path).ok_or(FspError::IO(ErrorKind::NotFound))?;
-        // getattr
-        let entry = self.backend.lock().expect("Mutex poisoned").get_attr(ino).map_err(|err| map_error(&err))?;
+        // lookup + getattr
+        let write = granted_access & (FILE_WRITE_DATA | FILE_APPEND_DATA) != 0;
+        let entry = self.policy_check(&from_windows_path(&path), write).map_err(|e| map_error(&e)).and_then(|_| self.attr_by_path(&path));
+        self.audit(if write { "open-write" } else { "open" }, &path, None, &entry);
+        let entry = entry?;
 
         // updating OpenFileInfo with file's metadata
         let file_info_data = file_info.as_mut();
-        entry_to_file_info(file_info_data, &entry);
+        self.fill_file_info(file_info_data, &entry);
 
         let fh = self.next_fh.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         //println!("  → Assigned file handle: {}", fh);
         
         self.fh_to_entry.lock().expect("Mutex poisoned").insert(fh, entry.clone());
+        self.granted_access.lock().expect("Mutex poisoned").insert(fh, granted_access);
         
         if entry.kind != EntryType::Directory {
-            if entry.size > LARGE_FILE_SIZE {
-                self.read_file_handles.lock().expect("Mutex poisoned").insert(fh, ReadMode::LargeStream(StreamState::new()));
+            // una copia locale si legge a pagine anche se il file è grande
+            let local = self.hydration && self.backend.lock().expect("Mutex poisoned").has_local_copy(entry.ino);
+            if entry.size > self.io.large_file_size && !local {
+                self.read_file_handles.lock().expect("Mutex poisoned").insert(fh, Arc::new(Mutex::new(ReadMode::LargeStream(StreamState::new()))));
             } else {
-                self.read_file_handles.lock().expect("Mutex poisoned").insert(fh, ReadMode::SmallPages);
+                self.read_file_handles.lock().expect("Mutex poisoned").insert(fh, Arc::new(Mutex::new(ReadMode::SmallPages)));
             }
-            self.write_buffers.lock().expect("Mutex poisoned").insert(fh, BTreeMap::new());
+            self.write_buffers.lock().expect("Mutex poisoned").insert(fh, DirtyRanges::default());
         }
         
         Ok(fh)
@@ -346,28 +991,48 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
             }
         }
 
-        self.fh_to_entry.lock().expect("Mutex poisoned").remove(&fh);
+        if let Some(entry) = self.fh_to_entry.lock().expect("Mutex poisoned").remove(&fh) {
+            self.shebang_pending.lock().expect("Mutex poisoned").remove(&entry.ino);
+        }
         self.read_file_handles.lock().expect("Mutex poisoned").remove(&fh);
         self.write_buffers.lock().expect("Mutex poisoned").remove(&fh);
+        self.files_to_delete.lock().expect("Mutex poisoned").remove(&fh);
+        self.granted_access.lock().expect("Mutex poisoned").remove(&fh);
     }
 
     fn create(&self,file_name: &U16CStr,create_options: u32,granted_access: FILE_ACCESS_RIGHTS,file_attributes: FILE_FLAGS_AND_ATTRIBUTES,_security_descriptor: Option<&[c_void]>,_allocation_size: u64,
         _extra_buffer: Option<&[u8]>,_extra_buffer_is_reparse_point: bool,file_info: &mut OpenFileInfo) -> FspResult<Self::FileContext> {
+        let _speed = self.speed(|| format!("create of {}", file_name.to_string_lossy()));
         //println!("create");
         
         let path = file_name.to_string_lossy();
         let (parent_ino, f_name) = self.get_parent_ino_and_fname(&path)?;
-        let entry = if (file_attributes & FILE_ATTRIBUTE_DIRECTORY) != 0 {
-            self.backend.lock().expect("Mutex poisoned").create_dir(parent_ino, &f_name).map_err(|err| map_error(&err))?
+        if self.junk.blocks(&f_name) {
+            return Err(FspError::IO(ErrorKind::PermissionDenied));
+        }
+        let dir = (file_attributes & FILE_ATTRIBUTE_DIRECTORY) != 0;
+        let denied = self.policy_check(&from_windows_path(&path), true);
+        let entry = if let Err(e) = denied {
+            Err(e)
+        } else if dir {
+            let created = self.backend.lock().expect("Mutex poisoned").create_dir(parent_ino, &f_name);
+            created.and_then(|entry| {
+                let mode = self.create_modes.dir(entry.perms as u32, 0);
+                if mode == entry.perms as u32 { Ok(entry) } else { self.set_mode(entry.ino, mode) }
+            })
         } else {
-            self.backend.lock().expect("Mutex poisoned").create_file(parent_ino, &f_name).map_err(|err| map_error(&err))?
+            let created = self.backend.lock().expect("Mutex poisoned").create_file(parent_ino, &f_name);
+            created.and_then(|entry| self.apply_create_mode(entry))
         };
-        self.lookup_ino.lock().expect("Mutex poisoned").insert(path.to_string(), entry.ino);
+        self.audit(if dir { "mkdir" } else { "create" }, &path, None, &entry);
+        let entry = entry.map_err(|err| map_error(&err))?;
+        self.lookup_ino.lock().expect("Mutex poisoned").put(path.to_string(), entry.ino);
         self.open(file_name, create_options, granted_access, file_info)
     }
 
     /// Clean up a file.
     fn cleanup(&self, context: &Self::FileContext, _file_name: Option<&U16CStr>, flags: u32) {
+        let _speed = self.speed(|| format!("cleanup of ino {}", self.handle_ino(*context)));
         //println!("cleanup: '{}'", self.fh_to_entry.lock().expect("Mutex poisoned").get(context).unwrap().name);
         let fh = *context;
 
@@ -409,25 +1074,17 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
                 }
             };
 
-            match entry.kind {
-                EntryType::Directory => {
-                    if let Err(e) = self.backend.lock().expect("Mutex poisoned").delete_dir(parent_ino, &filename)
-                    {
-                        eprintln!("cleanup: delete_dir('{}') failed: {}", path, e);
-                    }
-                }
-                _ => {
-                    if let Err(e) = self.backend.lock().expect("Mutex poisoned").delete_file(parent_ino, &filename)
-                    {
-                        eprintln!("cleanup: delete_file('{}') failed: {}", path, e);
-                    }
-                }
+            let (op, res) = match entry.kind {
+                EntryType::Directory => ("rmdir", self.backend.lock().expect("Mutex poisoned").delete_dir(parent_ino, &filename)),
+                _ => ("unlink", self.backend.lock().expect("Mutex poisoned").delete_file(parent_ino, &filename)),
+            };
+            if let Err(e) = res.as_ref() {
+                eprintln!("cleanup: {}('{}') failed: {}", op, path, e);
             }
+            self.audit(op, &path, None, &res);
 
-            // 5) Ripulisci la cache path->ino (togliamo l'ino dell'entry eliminata)
-            let ino = entry.ino;
-            let mut lookup_cache = self.lookup_ino.lock().expect("Mutex poisoned");
-            lookup_cache.retain(|_, v| *v != ino);
+            // 5) Ripulisci la cache path->ino (il path eliminato e, per le directory, quelli sotto)
+            self.forget_path(&path);
         }
     }
 
@@ -435,6 +1092,10 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
     ///
     /// If `context` is `None`, the request is to flush the entire volume.
     fn flush(&self, context: Option<&Self::FileContext>, file_info: &mut FileInfo) -> FspResult<()> {
+        let _speed = self.speed(|| match context {
+            Some(fh) => format!("flush of ino {}", self.handle_ino(*fh)),
+            None => "flush of the volume".to_string(),
+        });
         //println!("flush");
 
         match context {
@@ -479,6 +1140,7 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
     }
 
     fn get_file_info(&self, context: &Self::FileContext, file_info: &mut FileInfo) -> FspResult<()> {
+        let _speed = self.speed(|| format!("getattr of ino {}", self.handle_ino(*context)));
         //println!("get_file_info: {}", self.fh_to_entry.lock().expect("Mutex poisoned").get(context).unwrap().name);
         
         let fh = *context;
@@ -499,18 +1161,23 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
             Err(e) => return Err(map_error(&e)),
         };
         
-        entry_to_file_info(file_info, &fresh_entry);
+        self.fill_file_info(file_info, &fresh_entry);
         
         Ok(())
     }
 
     /// Get file or directory security descriptor.
-    fn get_security(&self,_context: &Self::FileContext,security_descriptor: Option<&mut [c_void]>) -> FspResult<u64> {
-        sd_from_sddl(SDDL_ALLOW_ALL, security_descriptor)
+    fn get_security(&self,context: &Self::FileContext,security_descriptor: Option<&mut [c_void]>) -> FspResult<u64> {
+        let sddl = match self.fh_to_entry.lock().expect("Mutex poisoned").get(context) {
+            Some(entry) => self.security_for(entry),
+            None => SDDL_ALLOW_ALL, // handle senza voce: decide il server
+        };
+        sd_from_sddl(sddl, security_descriptor)
     }
 
     /// Overwrite a file.
     fn overwrite(&self,context: &Self::FileContext,_file_attributes: FILE_FLAGS_AND_ATTRIBUTES,_replace_file_attributes: bool,_allocation_size: u64,_extra_buffer: Option<&[u8]>,file_info: &mut FileInfo) -> FspResult<()> {
+        let _speed = self.speed(|| format!("overwrite of ino {}", self.handle_ino(*context)));
         let fh = *context;
 
         // prendi l’entry legata a questo handle
@@ -530,17 +1197,24 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
             uid: None,
             gid: None,
             flags: None,
+            file_flags: None,
+            atime: None,
+            mtime: None,
+            btime: None,
         };
-        entry=self.backend.lock().expect("Mutex poisoned").set_attr(entry.ino, attribute).map_err(|e| map_error(&e))?;
+        let res = self.policy_check(&entry.path, true).and_then(|_| self.backend.lock().expect("Mutex poisoned").set_attr(entry.ino, attribute));
+        self.audit("truncate", &entry.path, None, &res);
+        entry = res.map_err(|e| map_error(&e))?;
 
         self.fh_to_entry.lock().expect("Mutex poisoned").insert(fh, entry.clone());
-        entry_to_file_info(file_info, &entry);
+        self.fill_file_info(file_info, &entry);
 
         Ok(())
     }
 
     /// Read directory entries from a directory handle.
     fn read_directory(&self,context: &Self::FileContext,pattern: Option<&U16CStr>,marker: DirMarker,buffer: &mut [u8]) -> FspResult<u32> {
+        let _speed = self.speed(|| format!("readdir of ino {}", self.handle_ino(*context)));
         //println!("read_directory: {}", self.fh_to_entry.lock().expect("Mutex poisoned").get(context).unwrap().name);
 
         if !marker.is_none() {
@@ -557,33 +1231,56 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
             return Err(FspError::IO(ErrorKind::NotADirectory));
         }
 
-        let entries = self.backend.lock().expect("Mutex poisoned").list_dir(dir_entry.ino).map_err(|e|{map_error(&e)})?;
-
         let pattern_str = pattern.map(|p| p.to_string_lossy().to_string());
+        // il server confronta il pattern con i nomi remoti, il controllo locale con quelli mostrati a Windows
+        let remote_pattern = pattern_str.as_deref().map(from_windows_name);
 
         let dir_buffer = DirBuffer::new();
-        let buffer_lock = dir_buffer.acquire(true, Some(entries.len() as u32))?;
-
-        for entry in entries.iter() {
+        let buffer_lock = dir_buffer.acquire(true, None)?;
+
+        // le voci arrivano una pagina alla volta e vanno subito nel buffer, senza tenere tutta la lista;
+        // il pattern filtra già sul server, il controllo locale resta per i server che lo ignorano
+        let mut cursor: Option<String> = None;
+        let mut first_page = true;
+        loop {
+            let page = self.backend.lock().expect("Mutex poisoned").list_dir_page(dir_entry.ino, remote_pattern.as_deref(), cursor.as_deref(), DIR_PAGE_SIZE).map_err(|e|{map_error(&e)})?;
+
+            for entry in page.entries.iter() {
+                if self.junk.hides(&entry.name) || self.policy_hides(&entry.path) {
+                    continue;
+                }
+                let name = to_windows_name(&entry.name);
 
-            // filter
-            if let Some(ref pat) = pattern_str {
-                match Pattern::new(pat) {
-                    Ok(p) => if !p.matches(&entry.name){
-                        continue;
-                    },
-                    Err(_) => return Err(FspError::IO(ErrorKind::InvalidInput)), // invalid pattern
+                // filter
+                if let Some(ref pat) = pattern_str {
+                    match Pattern::new(pat) {
+                        Ok(p) => if !p.matches(&name){
+                            continue;
+                        },
+                        Err(_) => return Err(FspError::IO(ErrorKind::InvalidInput)), // invalid pattern
+                    }
                 }
-            }
 
 
-            let mut dir_info = DirInfo::<255>::new();
-            dir_info.set_name(&entry.name)?;
+                let mut dir_info = DirInfo::<255>::new();
+                dir_info.set_name(&*name)?;
 
-            let file_info = dir_info.file_info_mut();
-            entry_to_file_info(file_info, entry);
+                let file_info = dir_info.file_info_mut();
+                self.fill_file_info(file_info, entry);
+
+                buffer_lock.write(&mut dir_info)?;
+            }
 
-            buffer_lock.write(&mut dir_info)?;
+            // solo una lista completa in una pagina viene ricordata, per confrontarla con quella nuova quando la directory cambia
+            if first_page && page.next.is_none() && pattern_str.is_none() {
+                let names = page.entries.iter().filter(|e| !self.junk.hides(&e.name) && !self.policy_hides(&e.path)).map(|e| (e.name.clone(), e.ino)).collect();
+                self.listings.lock().expect("Mutex poisoned").put(dir_entry.ino, (to_windows_path(&dir_entry.path), names));
+            }
+            first_page = false;
+            match page.next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
         }
 
         drop(buffer_lock);
@@ -591,8 +1288,40 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
         Ok(dir_buffer.read(marker, buffer))
     }
 
+    /// Get directory information for a single file or directory within a parent directory.
+    // Explorer chiede spesso una sola voce di una directory: basta una lookup invece di tutta la lista
+    fn get_dir_info_by_name(&self,context: &Self::FileContext,file_name: &U16CStr,out_dir_info: &mut DirInfo) -> FspResult<()> {
+        let _speed = self.speed(|| format!("lookup for name {}", file_name.to_string_lossy()));
+        let dir_entry = match self.fh_to_entry.lock().expect("Mutex poisoned").get(context) {
+            Some(entry) => entry.clone(),
+            None => return Err(FspError::IO(ErrorKind::NotFound)),
+        };
+        if dir_entry.kind != EntryType::Directory {
+            return Err(FspError::IO(ErrorKind::NotADirectory));
+        }
+
+        let name = from_windows_name(&file_name.to_string_lossy()).into_owned();
+        if self.junk.hides(&name) {
+            return Err(FspError::IO(ErrorKind::NotFound));
+        }
+        let entry = self.backend.lock().expect("Mutex poisoned").lookup(dir_entry.ino, &name).map_err(|e| map_error(&e))?;
+        if self.policy_hides(&entry.path) {
+            return Err(FspError::IO(ErrorKind::NotFound));
+        }
+
+        let shown = to_windows_name(&entry.name);
+        let dir_path = to_windows_path(&dir_entry.path);
+        let path = format!("{}\\{}", dir_path.trim_end_matches('\\'), shown);
+        self.lookup_ino.lock().expect("Mutex poisoned").put(path, entry.ino);
+
+        out_dir_info.set_name(&*shown)?;
+        self.fill_file_info(out_dir_info.file_info_mut(), &entry);
+        Ok(())
+    }
+
     /// Renames a file or directory.
     fn rename(&self,context: &Self::FileContext,file_name: &U16CStr,new_file_name: &U16CStr,_replace_if_exists: bool) -> FspResult<()> {
+        let _speed = self.speed(|| format!("rename from {} to {}", file_name.to_string_lossy(), new_file_name.to_string_lossy()));
         //println!("rename");
         
         let fh = *context;
@@ -609,15 +1338,40 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
         let (old_parent_ino, old_filename) = self.get_parent_ino_and_fname(&old_path)?;
         // new file path (destination)
         let (new_parent_ino, new_filename) = self.get_parent_ino_and_fname(&new_path)?;
+        if self.junk.blocks(&new_filename) {
+            return Err(FspError::IO(ErrorKind::PermissionDenied));
+        }
+        // la voce cambia nome in entrambe le posizioni
+        let denied = self.policy_check(&from_windows_path(&old_path), true).and_then(|_| self.policy_check(&from_windows_path(&new_path), true));
+        if denied.is_err() {
+            self.audit("rename", &old_path, Some(new_path.as_str()), &denied);
+        }
+        denied.map_err(|e| map_error(&e))?;
 
-        let new_entry = self.backend.lock().expect("Mutex poisoned").rename(old_parent_ino, &old_filename, new_parent_ino, &new_filename).map_err(|e|{map_error(&e)})?;
+        // le scritture ancora nel buffer devono arrivare al server prima che il contenuto venga spostato
+        let need_flush = { self.write_buffers.lock().expect("Mutex").contains_key(&fh) };
+        if need_flush {
+            self.flush_file(fh).map_err(|e| map_error(&e))?;
+        }
+
+        // sopra una destinazione esistente (salvataggio atomico degli editor) il server fa una rename atomica
+        let new_entry = self.backend.lock().expect("Mutex poisoned").rename(old_parent_ino, &old_filename, new_parent_ino, &new_filename);
+        self.audit("rename", &old_path, Some(new_path.as_str()), &new_entry);
+        let new_entry = new_entry.map_err(|e| map_error(&e))?;
 
         //println!("Rename successful: new ino={}, new name='{}'", new_entry.ino, new_entry.name);
-        self.fh_to_entry.lock().expect("Mutex poisoned").insert(fh, new_entry.clone());
-        let mut lookup_cache = self.lookup_ino.lock().expect("Mutex poisoned");
-        lookup_cache.retain(|_, &mut ino| ino != entry.ino);
-        lookup_cache.insert(new_path.to_string(), new_entry.ino);
-        
+        // tutti gli handle sullo stesso ino seguono la voce, non solo quello che ha chiesto il rename
+        for handle in self.fh_to_entry.lock().expect("Mutex poisoned").values_mut().filter(|e| e.ino == entry.ino) {
+            *handle = new_entry.clone();
+        }
+        self.move_pending_deletes(&old_path, &new_path);
+        // quello che c'era sotto la destinazione non esiste più; sotto una directory spostata le voci restano le stesse
+        self.forget_path(&new_path);
+        if new_entry.kind == EntryType::Directory {
+            self.move_path(&old_path, &new_path, &entry, &new_entry);
+        }
+        self.forget_path(&old_path);
+        self.lookup_ino.lock().expect("Mutex poisoned").put(new_path.to_string(), new_entry.ino);
 
         Ok(())
     }
@@ -629,6 +1383,7 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
     /// set a flag to indicate that the file is to be deleted later by
     /// [`FileSystemContext::cleanup`](crate::filesystem::FileSystemContext::cleanup).
     fn set_delete(&self,context: &Self::FileContext,file_name: &U16CStr,delete_file: bool) -> FspResult<()> {
+        let _speed = self.speed(|| format!("set_delete of {}", file_name.to_string_lossy()));
         //println!("set_delete: '{}'", file_name.to_string_lossy());
         let fh = *context;
 
@@ -639,6 +1394,7 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
         };
 
         if delete_file {
+            self.policy_check(&entry.path, true).map_err(|e| map_error(&e))?;
             // se è directory, verifica che sia vuota ORA (fallisci qui, non in cleanup)
             if entry.kind == EntryType::Directory {
                 let items = self.backend.lock().expect("Mutex poisoned").list_dir(entry.ino).map_err(|e| map_error(&e))?;
@@ -656,8 +1412,74 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
         Ok(())
     }
 
+    /// Set file attributes and times.
+    fn set_basic_info(&self,context: &Self::FileContext,file_attributes: u32,creation_time: u64,last_access_time: u64,last_write_time: u64,_last_change_time: u64,file_info: &mut FileInfo) -> FspResult<()> {
+        let _speed = self.speed(|| format!("setattr of ino {}", self.handle_ino(*context)));
+        let fh = *context;
+
+        let mut entry = {
+            let map = self.fh_to_entry.lock().map_err(|_| FspError::IO(std::io::ErrorKind::Other))?;
+            map.get(&fh).cloned().ok_or(FspError::IO(std::io::ErrorKind::NotFound))?
+        };
+
+        // il change time non è impostabile, lo aggiorna il server
+        let mut attribute = SetAttrRequest {
+            size: None,
+            perm: None,
+            uid: None,
+            gid: None,
+            flags: None,
+            file_flags: None,
+            atime: filetime_to_system_time(last_access_time),
+            mtime: filetime_to_system_time(last_write_time),
+            btime: filetime_to_system_time(creation_time),
+        };
+
+        // INVALID_FILE_ATTRIBUTES: gli attributi non vanno cambiati
+        if file_attributes != INVALID_FILE_ATTRIBUTES {
+            // readonly ↔ bit di scrittura: togliendolo si rimuovono tutti i permessi di scrittura,
+            // rimettendolo si ridà la scrittura al solo proprietario
+            if entry.kind == EntryType::File {
+                let perms = entry.perms as u32;
+                let new_perms = if file_attributes & FILE_ATTRIBUTE_READONLY != 0 {
+                    perms & !0o222
+                } else if perms & 0o222 == 0 {
+                    perms | 0o200
+                } else {
+                    perms
+                };
+                if new_perms != perms {
+                    attribute.perm = Some(new_perms);
+                }
+            }
+            let flags = file_attributes & STORED_ATTRIBUTES;
+            if flags != entry.flags {
+                attribute.flags = Some(flags);
+            }
+            if self.hydration && entry.kind == EntryType::File {
+                self.set_hydration(&entry, file_attributes);
+            }
+        }
+
+        let times = attribute.atime.is_some() || attribute.mtime.is_some() || attribute.btime.is_some();
+        if times {
+            // le scritture ancora in buffer sovrascriverebbero il write time appena impostato
+            self.flush_file(fh).map_err(|e| map_error(&e))?;
+        }
+
+        if times || attribute.perm.is_some() || attribute.flags.is_some() {
+            let res = self.policy_check(&entry.path, true).and_then(|_| self.backend.lock().expect("Mutex").set_attr(entry.ino, attribute));
+            self.audit("setattr", &entry.path, None, &res);
+            entry = res.map_err(|e| map_error(&e))?;
+            self.fh_to_entry.lock().expect("Mutex").insert(fh, entry.clone());
+        }
+        self.fill_file_info(file_info, &entry);
+        Ok(())
+    }
+
     /// Set the file or allocation size.
     fn set_file_size(&self,context: &Self::FileContext,new_size: u64,set_allocation_size: bool,file_info: &mut FileInfo) -> FspResult<()> {
+        let _speed = self.speed(|| format!("truncate of ino {} to size {}", self.handle_ino(*context), new_size));
         let fh = *context;
 
         let mut entry = {
@@ -672,7 +1494,7 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
         // Se è una richiesta di *allocation size*, NON cambiare la dimensione logica del file.
         if set_allocation_size {
             // opzionale: potresti passare un hint di preallocazione al backend qui.
-            entry_to_file_info(file_info, &entry);
+            self.fill_file_info(file_info, &entry);
             return Ok(());
         }
 
@@ -682,17 +1504,38 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
             uid: None,
             gid: None,
             flags: None,
+            file_flags: None,
+            atime: None,
+            mtime: None,
+            btime: None,
         };
 
-        entry=self.backend.lock().expect("Mutex").set_attr(entry.ino, attribute).map_err(|e| map_error(&e))?;
+        let res = self.policy_check(&entry.path, true).and_then(|_| self.backend.lock().expect("Mutex").set_attr(entry.ino, attribute));
+        self.audit("truncate", &entry.path, None, &res);
+        entry = res.map_err(|e| map_error(&e))?;
+
+        // i byte bufferizzati oltre la nuova fine sono stati scritti prima e non vanno più inviati
+        let handles: Vec<u64> = self.fh_to_entry.lock().expect("Mutex").iter()
+            .filter(|(_, e)| e.ino == entry.ino)
+            .map(|(fh, _)| *fh)
+            .collect();
+        {
+            let mut write_buffers = self.write_buffers.lock().expect("Mutex poisoned");
+            for other in handles {
+                if let Some(map) = write_buffers.get_mut(&other) {
+                    map.truncate(new_size);
+                }
+            }
+        }
 
         self.fh_to_entry.lock().expect("Mutex").insert(fh, entry.clone());
-        entry_to_file_info(file_info, &entry);
+        self.fill_file_info(file_info, &entry);
         Ok(())
     }
 
     /// Read from a file. Return the number of bytes read,
     fn read(&self, context: &Self::FileContext, buffer: &mut [u8], offset: u64) -> FspResult<u32> {
+        let _speed = self.speed(|| format!("read of ino {} at offset {} with size {}", self.handle_ino(*context), offset, buffer.len()));
         //println!("read");
 
         let fh = *context;
@@ -707,6 +1550,8 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
         if entry.kind == EntryType::Directory {
             return Err(FspError::IO(ErrorKind::IsADirectory));
         }
+        // anche l'esecuzione legge il file
+        self.check_access(fh, FILE_READ_DATA | FILE_EXECUTE)?;
 
         // Check bounds
         if offset >= entry.size {
@@ -717,15 +1562,17 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
         if read_size == 0 {
             return Ok(0);
         }
+        let local = self.hydrate_for_read(&entry).map_err(|e| map_error(&e))?;
 
-        // Get the read mode for this file handle
-        let mut read_handles = self.read_file_handles.lock().map_err(|_| FspError::IO(ErrorKind::Other))?;
-        let read_mode = match read_handles.get_mut(&fh) {
-            Some(rm) => rm,
+        // Get the read mode for this file handle: il lock della mappa è tenuto solo per clonare lo stato,
+        // così le read su handle diversi non si aspettano a vicenda
+        let read_state = match self.read_file_handles.lock().map_err(|_| FspError::IO(ErrorKind::Other))?.get(&fh) {
+            Some(rm) => rm.clone(),
             None => return Err(FspError::IO(ErrorKind::NotFound)),
         };
-        
-        match read_mode {
+        let mut read_mode = read_state.lock().map_err(|_| FspError::IO(ErrorKind::Other))?;
+
+        match &mut *read_mode {
             ReadMode::LargeStream(state) => {
                 let need= buffer.len() as usize;
                 if offset as u64 != state.pos { 
@@ -733,7 +1580,7 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
                 }
 
                 if state.stream.is_none() && !state.eof {
-                    match self.backend.lock().expect("Mutex poisoned").read_stream(entry.ino, state.pos) {
+                    match self.backend.lock().expect("Mutex poisoned").read_stream(entry.ino, state.pos, None) {
                         Ok(stream) => {
                             state.stream = Some(stream);
                             state.buffer.clear(); // clean the buffer for the new stram
@@ -771,8 +1618,18 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
                 Ok(take as u32)
             }
             ReadMode::SmallPages => {
-                // chunk reading
-                match self.backend.lock().expect("Mutex poisoned").read_chunk(entry.ino, offset as u64, read_size as u64) {
+                // chunk reading: prima i blocchi in cache, altrimenti la rete senza bloccare gli altri file
+                let res = if local {
+                    self.backend.lock().expect("Mutex poisoned").read_chunk(entry.ino, offset, read_size as u64)
+                } else {
+                    self.read_lease(entry.ino);
+                    let cached = self.backend.lock().expect("Mutex poisoned").cached_read(entry.ino, offset, read_size as u64);
+                    match cached {
+                        Some(data) => Ok(data),
+                        None => self.read_uncached(&entry, offset, read_size as u64),
+                    }
+                };
+                match res {
                     Ok(data) => {
                         let bytes_read = if data.len() < buffer.len() { data.len() } else { buffer.len() };
                         buffer[..bytes_read].copy_from_slice(&data[..bytes_read]);
@@ -787,6 +1644,7 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
 
     /// Write to a file. Return the number of bytes written.
     fn write(&self,context: &Self::FileContext,buffer: &[u8],offset: u64,write_to_eof: bool,_constrained_io: bool,file_info: &mut FileInfo) -> FspResult<u32> {
+        let _speed = self.speed(|| format!("write of ino {} at offset {} with size {}", self.handle_ino(*context), offset, buffer.len()));
         //println!("write");
 
         let fh = *context;
@@ -801,24 +1659,31 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
         if entry.kind == EntryType::Directory {
             return Err(FspError::IO(ErrorKind::IsADirectory));
         }
+        // un handle aperto solo in append può scrivere solo in coda
+        self.check_access(fh, if write_to_eof { FILE_WRITE_DATA | FILE_APPEND_DATA } else { FILE_WRITE_DATA })?;
 
-        // 2) Calcolo l'offset reale (supporto write_to_eof)
-        let off = if write_to_eof { entry.size } else { offset };
+        // 2) Offset richiesto; con write_to_eof lo sceglie il server al momento della scrittura
+        let mut off = offset;
 
         // 3) Se non c’è nulla da scrivere, esco subito
         if buffer.is_empty() {
-            entry_to_file_info(file_info, &entry);
+            self.fill_file_info(file_info, &entry);
             return Ok(0);
         }
 
         // 4) Scrittura immediata al backend (nessun passaggio in write_buffers)
         let ino = entry.ino;
-        // NB: LARGE_FILE_SIZE è già definita nel tuo file
-        let write_res = if buffer.len() > LARGE_FILE_SIZE as usize {
+        let write_res = if write_to_eof {
+            self.backend
+                .lock()
+                .expect("Mutex poisoned")
+                .write_append(ino, buffer.to_vec())
+                .map(|written_at| off = written_at)
+        } else if buffer.len() > self.io.large_file_size as usize {
             self.backend
                 .lock()
                 .expect("Mutex poisoned")
-                .write_stream(ino, off, buffer.to_vec())
+                .write_stream(ino, off, chunk_stream(vec![buffer.to_vec()]))
         } else {
             self.backend
                 .lock()
@@ -836,13 +1701,24 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
                 }
                 entry.mtime = SystemTime::now();
 
+                // primo contenuto di un file creato qui: se è uno script lo rendiamo eseguibile
+                if off == 0 && self.shebang_pending.lock().expect("Mutex poisoned").remove(&ino) && buffer.starts_with(b"#!") {
+                    let mode = self.create_modes.masked(with_exec_bits(entry.perms as u32));
+                    if mode != entry.perms as u32 {
+                        match self.set_mode(ino, mode) {
+                            Ok(updated) => entry.perms = updated.perms,
+                            Err(e) => eprintln!("Unable to mark {} as executable: {}", entry.path, e),
+                        }
+                    }
+                }
+
                 // salvo l'entry aggiornata nella mappa del FH
                 self.fh_to_entry
                     .lock()
                     .expect("Mutex poisoned")
                     .insert(fh, entry.clone());
 
-                entry_to_file_info(file_info, &entry);
+                self.fill_file_info(file_info, &entry);
                 Ok(buffer.len() as u32)
             }
             Err(e) => Err(map_error(&e)),
@@ -864,3 +1740,228 @@ impl<B: RemoteBackend> FileSystemContext for RemoteFS<B> {
     }
 
 }
+
+// i path del server usano '/', quelli visti da WinFsp '\\'
+// Nomi remoti non validi su Windows (caratteri riservati o di controllo, punto o spazio finale, tipici dei
+// file creati da Linux): ogni carattere problematico diventa U+F000 + codice, come fanno Cygwin e WSL, così
+// la voce resta visibile e gestibile e al ritorno si ricostruisce il nome originale. Nei nomi di dispositivo
+// riservati (CON, NUL, COM1...) viene sostituito l'ultimo carattere prima dell'estensione. La mappatura non è
+// reversibile solo per i nomi remoti che contengono già quei caratteri dell'area private-use.
+fn needs_escape(c: char, last: bool) -> bool {
+    matches!(c, '\u{1}'..='\u{1f}' | '<' | '>' | ':' | '"' | '\\' | '|' | '?' | '*') || (last && matches!(c, '.' | ' '))
+}
+
+// posizione del carattere da sostituire se il nome è un dispositivo riservato, con o senza estensione
+fn reserved_device_at(name: &str) -> Option<usize> {
+    let stem = name.split('.').next().unwrap_or(name).trim_end_matches(' ').to_ascii_uppercase();
+    let reserved = matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL")
+        || (stem.len() == 4 && (stem.starts_with("COM") || stem.starts_with("LPT")) && matches!(stem.as_bytes()[3], b'1'..=b'9'));
+    reserved.then(|| stem.len() - 1)
+}
+
+// nome remoto → nome mostrato a Windows
+fn to_windows_name(name: &str) -> Cow<'_, str> {
+    let len = name.chars().count();
+    let device = reserved_device_at(name);
+    let escaped = |(i, c): (usize, char)| needs_escape(c, i + 1 == len) || device == Some(i);
+    if name == "." || name == ".." || !name.chars().enumerate().any(escaped) {
+        return Cow::Borrowed(name);
+    }
+    Cow::Owned(name.chars().enumerate()
+        .map(|(i, c)| if escaped((i, c)) { char::from_u32(NAME_ESCAPE_BASE + c as u32).expect("private-use code point") } else { c })
+        .collect())
+}
+
+// nome ricevuto da Windows → nome remoto
+fn from_windows_name(name: &str) -> Cow<'_, str> {
+    let unescape = |c: char| (c as u32).checked_sub(NAME_ESCAPE_BASE).and_then(char::from_u32)
+        .filter(|&d| needs_escape(d, true) || d.is_ascii_alphanumeric());
+    if !name.chars().any(|c| unescape(c).is_some()) {
+        return Cow::Borrowed(name);
+    }
+    Cow::Owned(name.chars().map(|c| unescape(c).unwrap_or(c)).collect())
+}
+
+// path ricevuto da Windows → path remoto
+fn from_windows_path(path: &str) -> String {
+    let components: Vec<Cow<'_, str>> = path.split('\\').map(from_windows_name).collect();
+    components.join("/")
+}
+
+fn to_windows_path(path: &str) -> String {
+    let components: Vec<Cow<'_, str>> = path.split('/').map(to_windows_name).collect();
+    let path = components.join("\\");
+    if path.is_empty() { "\\".to_string() } else { path }
+}
+
+// target di un symlink del server come path di Windows relativo alla directory del link `link`: la root del
+// server è la root del volume, quindi un target assoluto risale fino a lì
+fn windows_link_target(link: &str, target: &str) -> String {
+    let target = match target.strip_prefix('/') {
+        Some(rest) => {
+            let depth = link.trim_matches('\\').split('\\').count().saturating_sub(1);
+            let up = "..\\".repeat(depth);
+            if rest.is_empty() { up } else { format!("{}{}", up, to_windows_path(rest)) }
+        }
+        None => to_windows_path(target),
+    };
+    let target = target.trim_end_matches('\\');
+    if target.is_empty() { ".".to_string() } else { target.to_string() }
+}
+
+// REPARSE_DATA_BUFFER di un symlink relativo: stesso nome come SubstituteName e come PrintName
+fn symlink_reparse_buffer(target: &str) -> Vec<u8> {
+    const SYMLINK_FLAG_RELATIVE: u32 = 1;
+    let name: Vec<u8> = target.encode_utf16().flat_map(u16::to_le_bytes).collect();
+    let len = name.len() as u16;
+    let mut data = Vec::with_capacity(20 + 2 * name.len());
+    data.extend(IO_REPARSE_TAG_SYMLINK.to_le_bytes());
+    data.extend((12 + 2 * len).to_le_bytes()); // ReparseDataLength, dopo l'intestazione di 8 byte
+    data.extend(0u16.to_le_bytes()); // Reserved
+    data.extend(0u16.to_le_bytes()); // SubstituteNameOffset
+    data.extend(len.to_le_bytes()); // SubstituteNameLength
+    data.extend(len.to_le_bytes()); // PrintNameOffset
+    data.extend(len.to_le_bytes()); // PrintNameLength
+    data.extend(SYMLINK_FLAG_RELATIVE.to_le_bytes());
+    data.extend(&name);
+    data.extend(&name);
+    data
+}
+
+fn send_notification(notifier: &Notifier, path: &str, filter: u32, action: u32) {
+    let mut info = NotifyInfo::<NOTIFY_PATH_CAP>::new();
+    info.filter = filter;
+    info.action = action;
+    if info.set_name(path).is_err() {
+        return; // nome troppo lungo per il buffer: la notifica viene persa, non il dato
+    }
+    notifier.notify(&info);
+}
+
+impl<B: RemoteBackend> RemoteFS<B> {
+    // confronta la nuova lista della directory con l'ultima servita e notifica le voci cambiate
+    fn notify_dir_changes(&self, notifier: &Notifier, ino: u64, dir_path: &str, previous: &HashMap<String, u64>) {
+        let entries = match self.backend.lock().expect("Mutex poisoned").list_dir(ino) {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Cannot list changed directory {}: {}", dir_path, e);
+                self.listings.lock().expect("Mutex poisoned").pop(&ino);
+                return;
+            }
+        };
+        let filter = FILE_NOTIFY_CHANGE_FILE_NAME | FILE_NOTIFY_CHANGE_DIR_NAME;
+        let child_path = |name: &str| {
+            let name = to_windows_name(name);
+            if dir_path.ends_with('\\') { format!("{}{}", dir_path, name) } else { format!("{}\\{}", dir_path, name) }
+        };
+        let current: HashMap<String, u64> = entries.iter().filter(|e| !self.junk.hides(&e.name)).map(|e| (e.name.clone(), e.ino)).collect();
+        for (name, child) in previous {
+            if current.get(name) != Some(child) {
+                let path = child_path(name);
+                self.forget_path(&path);
+                send_notification(notifier, &path, filter, FILE_ACTION_REMOVED);
+            }
+        }
+        for (name, child) in &current {
+            if previous.get(name) != Some(child) {
+                send_notification(notifier, &child_path(name), filter, FILE_ACTION_ADDED);
+            }
+        }
+        self.listings.lock().expect("Mutex poisoned").put(ino, (dir_path.to_string(), current));
+    }
+
+    // lease richiamato su un file: i dati già scaricati dagli handle aperti non valgono più. Gli stream
+    // ripartono dalla posizione corrente e la dimensione viene riletta dal server
+    fn break_open_handles(&self, ino: u64) {
+        let handles: Vec<u64> = self.fh_to_entry.lock().expect("Mutex poisoned").iter()
+            .filter(|(_, e)| e.ino == ino)
+            .map(|(&fh, _)| fh)
+            .collect();
+        if handles.is_empty() {
+            return;
+        }
+        for fh in &handles {
+            let read_state = self.read_file_handles.lock().expect("Mutex poisoned").get(fh).cloned();
+            if let Some(read_state) = read_state
+                && let ReadMode::LargeStream(state) = &mut *read_state.lock().expect("Mutex poisoned") {
+                *state = StreamState { pos: state.pos, ..StreamState::new() };
+            }
+        }
+        match self.backend.lock().expect("Mutex poisoned").get_attr(ino) {
+            Ok(entry) => {
+                // gli handle con scritture non ancora inviate tengono la propria dimensione
+                let dirty: HashSet<u64> = self.write_buffers.lock().expect("Mutex poisoned").iter()
+                    .filter(|(_, ranges)| !ranges.is_empty())
+                    .map(|(&fh, _)| fh)
+                    .collect();
+                let mut open = self.fh_to_entry.lock().expect("Mutex poisoned");
+                for fh in handles.into_iter().filter(|fh| !dirty.contains(fh)) {
+                    if let Some(e) = open.get_mut(&fh) {
+                        e.size = entry.size;
+                        e.mtime = entry.mtime;
+                    }
+                }
+            }
+            Err(e) => eprintln!("Cannot refresh recalled ino {}: {}", ino, e),
+        }
+    }
+
+    // path noti per un ino: quelli risolti di recente e quelli degli handle aperti
+    fn known_paths(&self, ino: u64) -> HashSet<String> {
+        let mut paths: HashSet<String> = self.lookup_ino.lock().expect("Mutex poisoned").iter()
+            .filter(|&(_, &i)| i == ino)
+            .map(|(path, _)| path.clone())
+            .collect();
+        paths.extend(self.fh_to_entry.lock().expect("Mutex poisoned").values()
+            .filter(|e| e.ino == ino)
+            .map(|e| to_windows_path(&e.path)));
+        paths
+    }
+}
+
+impl<B: RemoteBackend> NotifyingFileSystemContext<ChangeBatch> for RemoteFS<B> {
+    fn should_notify(&self) -> Option<ChangeBatch> {
+        let changes = self.changes.as_ref()?.lock().expect("Mutex poisoned");
+        let mut inos: ChangeBatch = changes.try_iter().collect();
+        inos.sort_unstable();
+        inos.dedup();
+        (!inos.is_empty()).then_some(inos)
+    }
+
+    fn notify(&self, inos: ChangeBatch, notifier: &Notifier) {
+        for ino in inos {
+            let listing = self.listings.lock().expect("Mutex poisoned").peek(&ino).cloned();
+            if let Some((dir_path, previous)) = listing {
+                self.notify_dir_changes(notifier, ino, &dir_path, &previous);
+                continue;
+            }
+            self.break_open_handles(ino);
+            // WinFsp invalida le cache del file (attributi e dati) per ogni path notificato
+            for path in self.known_paths(ino) {
+                send_notification(notifier, &path, FILE_NOTIFY_CHANGE_SIZE | FILE_NOTIFY_CHANGE_LAST_WRITE, FILE_ACTION_MODIFIED);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_windows_name, to_windows_name};
+
+    #[test]
+    fn windows_names_round_trip() {
+        for name in ["plain.txt", "a:b", "what?", "trailing.", "trailing ", "CON", "con.txt", "Nul", "aux.tar.gz", "COM1", "lpt9.log", "PRN .txt"] {
+            assert_eq!(from_windows_name(&to_windows_name(name)), name);
+        }
+    }
+
+    #[test]
+    fn reserved_device_names_are_escaped() {
+        for name in ["CON", "con.txt", "NUL", "aux.tar.gz", "COM1", "lpt9.log"] {
+            assert_ne!(to_windows_name(name), name);
+        }
+        for name in ["CONSOLE", "COM0", "COM10", "LPT", "nul_device", "my.con"] {
+            assert_eq!(to_windows_name(name), name);
+        }
+    }
+}
@@ -1,5 +1,7 @@
 import { Request, Response } from 'express';
-import { fileRepo,groupRepo,toFsPath,has_permissions, parseIno} from '../utilities';
+import { fileRepo,groupRepo,toFsPath,has_permissions, parseIno, ifMatchSatisfied, etagOf, streamRange, writeDenied, MAX_BLOCK_SIZE} from '../utilities';
+import { breakLeases } from './leaseController';
+import { mandatoryConflict } from './lockController';
 import { File } from '../entities/File';
 import { User } from '../entities/User';
 import { Group } from '../entities/Group';
@@ -7,6 +9,7 @@ import * as fsNode from 'node:fs/promises';
 import * as fs from 'fs'
 import path_manipulator from 'node:path'; 
 import { pipeline, Writable } from 'node:stream';
+import { createHash } from 'node:crypto';
 import { permission } from 'node:process';
 
 export function normalizePath(input?: string | string[]): string {
@@ -17,6 +20,18 @@ export function normalizePath(input?: string | string[]): string {
 }
 
 
+// append serializzati per ino: la dimensione letta prima della scrittura è l'offset in cui finiscono i dati
+const appendQueues = new Map<string, Promise<unknown>>();
+function serializedAppend<T>(ino: string, job: () => Promise<T>): Promise<T> {
+    const next = (appendQueues.get(ino) ?? Promise.resolve()).then(job, job);
+    appendQueues.set(ino, next);
+    next.finally(() => {
+        if (appendQueues.get(ino) === next)
+            appendQueues.delete(ino);
+    }).catch(() => {});
+    return next;
+}
+
 export class ReadWriteController {
     public writeStream = async (req: Request, res: Response) => {
         console.log("[writeStream] called with ino:", req.params.ino, "offset:", req.query.offset, "user:", (req.user as User)?.uid);
@@ -53,6 +68,21 @@ export class ReadWriteController {
                 return res.status(500).json({ error: 'File path not found for inode ' + ino });
             }
             const fullFsPath = toFsPath(dbPath);
+            if (await writeDenied(file, fullFsPath, offset)) {
+                console.log("[writeStream] status 403: Immutable or append-only file");
+                return res.status(403).json({ error: "EPERM", message: `${ino} is immutable or append-only` });
+            }
+            // senza Content-Length (corpo a chunk) vale tutto da offset in poi
+            if (mandatoryConflict(req, ino, offset, Number(req.header('content-length')) || Infinity)) {
+                console.log("[writeStream] status 423: Range locked by another client");
+                return res.status(423).json({ error: "EAGAIN", message: `${ino} is locked by another client` });
+            }
+            // prima i lease: chi ha write in sospeso le invia prima di rilasciarlo
+            await breakLeases(ino, req.sessionID);
+            if (!await ifMatchSatisfied(req.header('if-match'), fullFsPath)) {
+                console.log("[writeStream] status 412: If-Match precondition failed");
+                return res.status(412).json({ error: "EPRECONDITION", message: `${ino} was modified by another client` });
+            }
             const writeStream = fs.createWriteStream(fullFsPath, { flags: 'r+', start: offset, autoClose: true });
             let bytesWritten = 0;
             req.on('data', (chunk) => {
@@ -137,6 +167,7 @@ export class ReadWriteController {
 
 
                     console.log("[writeStream] status 200: Write finished, bytesWritten:", bytesWritten);
+                    res.setHeader('ETag', etagOf(await fsNode.lstat(fullFsPath)));
                     res.status(200).json({ bytes: bytesWritten });
                 }
             });
@@ -156,6 +187,12 @@ export class ReadWriteController {
             } else if (err.code === 'EACCES') {
                 console.log("[writeStream] status 403: Access denied");
                 res.status(403).json({ error: 'Access denied' });
+            } else if (err.code === 'ENOSPC' || err.code === 'EDQUOT') {
+                console.log("[writeStream] status 507: No space left");
+                res.status(507).json({ error: "ENOSPC", message: 'No space left to write into inode ' + ino });
+            } else if (err.code === 'EFBIG') {
+                console.log("[writeStream] status 413: File too large");
+                res.status(413).json({ error: "EFBIG", message: 'File too large' });
             } else {
                 console.log("[writeStream] status 500: Internal error");
                 res.status(500).json({ error: 'Not possible to write into inode ' + ino, details: err });
@@ -164,17 +201,17 @@ export class ReadWriteController {
     }
 
     public readStream = async (req: Request, res: Response) => {
-        console.log("[readStream] called with ino:", req.params.ino, "offset:", req.query.offset, "user:", (req.user as User)?.uid);
+        console.log("[readStream] called with ino:", req.params.ino, "offset:", req.query.offset, "length:", req.query.length, "user:", (req.user as User)?.uid);
         const ino = parseIno(req.params.ino);
-        const offset = Number(req.query.offset) || 0;
+        const range = streamRange(req.query);
         const user: User = req.user as User;
 
         if (!ino) {
             console.log("[readStream] status 400: Inode missing");
             return res.status(400).setHeader('Content-Type', 'application/octet-stream').end();
         }
-        if (offset < 0) {
-            console.log("[readStream] status 400: Invalid offset");
+        if (range === null) {
+            console.log("[readStream] status 400: Invalid offset or length");
             return res.status(400).setHeader('Content-Type', 'application/octet-stream').end();
         }
         if (user === null) {
@@ -211,7 +248,7 @@ export class ReadWriteController {
             }
             const fullFsPath = toFsPath(dbPath);
 
-            const readStream = fs.createReadStream(fullFsPath, { start: offset });
+            const readStream = fs.createReadStream(fullFsPath, range);
 
             readStream.on('error', (err) => {
                 console.error('[readStream] Stream error:', err);
@@ -280,6 +317,19 @@ export class ReadWriteController {
                 console.log("[write] status 403: No permission");
                 return res.status(403).json({ error: 'You have not the permission to write the content the file ' + ino });
             }
+            if (await writeDenied(file, fullFsPath, offset)) {
+                console.log("[write] status 403: Immutable or append-only file");
+                return res.status(403).json({ error: "EPERM", message: `${ino} is immutable or append-only` });
+            }
+            if (mandatoryConflict(req, ino, offset, buffer.length)) {
+                console.log("[write] status 423: Range locked by another client");
+                return res.status(423).json({ error: "EAGAIN", message: `${ino} is locked by another client` });
+            }
+            await breakLeases(ino, req.sessionID);
+            if (!await ifMatchSatisfied(req.header('if-match'), fullFsPath)) {
+                console.log("[write] status 412: If-Match precondition failed");
+                return res.status(412).json({ error: "EPRECONDITION", message: `${ino} was modified by another client` });
+            }
             const fh=await fsNode.open(fullFsPath, 'r+');
             try {
                 await fh.write(buffer, 0, buffer.length, offset);
@@ -360,6 +410,7 @@ export class ReadWriteController {
             }
 
             console.log("[write] status 200: Write finished, bytes:", buffer.length);
+            res.setHeader('ETag', etagOf(await fsNode.lstat(fullFsPath)));
             return res.status(200).json({ bytes: buffer.length });
 
         } catch (err: any) {
@@ -372,7 +423,13 @@ export class ReadWriteController {
                 return res.status(403).json({ error: 'Access denied' });
             } else if (err.code === 'EISDIR') {
                 console.log("[write] status 400: Is a directory");
-                return res.status(400).json({ error: 'Is a directory' });
+                return res.status(400).json({ error: "EISDIR", message: 'Is a directory' });
+            } else if (err.code === 'ENOSPC' || err.code === 'EDQUOT') {
+                console.log("[write] status 507: No space left");
+                return res.status(507).json({ error: "ENOSPC", message: 'No space left to write into the inode ' + ino });
+            } else if (err.code === 'EFBIG') {
+                console.log("[write] status 413: File too large");
+                return res.status(413).json({ error: "EFBIG", message: 'File too large' });
             } else {
                 console.log("[write] status 500: Internal error");
                 return res.status(500).json({ error: 'Not possible to write into the inode ' + ino, details: String(err) });
@@ -380,6 +437,71 @@ export class ReadWriteController {
         }
     }
 
+    // scrittura in coda al file (O_APPEND): l'offset lo decide il server, non la dimensione vista dal client
+    public append = async (req: Request, res: Response) => {
+        console.log("[append] called with ino:", req.params.ino, "user:", (req.user as User)?.uid);
+        const ino = parseIno(req.params.ino);
+        const user: User = req.user as User;
+
+        if (!ino) {
+            console.log("[append] status 400: Inode missing");
+            return res.status(400).json({ error: "EINVAL", message: "Inode missing" });
+        }
+        if (!Buffer.isBuffer(req.body)) {
+            console.log("[append] status 400: Invalid body");
+            return res.status(400).json({ error: 'Bad request: invalid body' });
+        }
+        const buffer: Buffer = req.body;
+
+        try {
+            const file = await fileRepo.findOne({
+                where:{ino},
+                relations:["owner", "group", "paths"]
+            }) as File;
+            if(!file) {
+                console.log("[append] status 404: File not found");
+                return res.status(404).json({ error: 'File not found' });
+            }
+            if (!has_permissions(file, 1, user)) {
+                console.log("[append] status 403: No permission");
+                return res.status(403).json({ error: 'You have not the permission to write the content the file ' + ino });
+            }
+            const fullFsPath = toFsPath(file.paths[0].path);
+            if (await writeDenied(file, fullFsPath)) {
+                console.log("[append] status 403: Immutable file");
+                return res.status(403).json({ error: "EPERM", message: `${ino} is immutable` });
+            }
+            await breakLeases(ino, req.sessionID);
+            const offset = await serializedAppend(ino, async () => {
+                const fh = await fsNode.open(fullFsPath, 'a');
+                try {
+                    const { size } = await fh.stat();
+                    await fh.write(buffer, 0, buffer.length);
+                    return size;
+                } finally {
+                    await fh.close();
+                }
+            });
+
+            console.log("[append] status 200: Append finished, bytes:", buffer.length, "offset:", offset);
+            res.setHeader('ETag', etagOf(await fsNode.lstat(fullFsPath)));
+            return res.status(200).json({ bytes: buffer.length, offset });
+        } catch (err: any) {
+            if (err.code === 'ENOENT') {
+                console.log("[append] status 404: File not found");
+                return res.status(404).json({ error: 'File not found' });
+            } else if (err.code === 'EISDIR') {
+                console.log("[append] status 400: Is a directory");
+                return res.status(400).json({ error: "EISDIR", message: 'Is a directory' });
+            } else if (err.code === 'ENOSPC' || err.code === 'EDQUOT') {
+                console.log("[append] status 507: No space left");
+                return res.status(507).json({ error: "ENOSPC", message: 'No space left to append to the inode ' + ino });
+            }
+            console.log("[append] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: 'Not possible to append to the inode ' + ino, details: String(err) });
+        }
+    }
+
     public read = async (req: Request, res: Response) => {
         console.log("[read] called with ino:", req.params.ino, "offset:", req.params.offset, "size:", req.query.size, "user:", (req.user as User)?.uid);
         const ino = parseIno(req.params.ino);
@@ -443,4 +565,63 @@ export class ReadWriteController {
         }
 
     }
-}
\ No newline at end of file
+
+    // hash sha256 dei blocchi richiesti, usati dal client per rivalidare solo i blocchi cambiati
+    public blockHashes = async (req: Request, res: Response) => {
+        console.log("[blockHashes] called with ino:", req.params.ino, "size:", req.query.size, "blocks:", req.query.blocks, "user:", (req.user as User)?.uid);
+        const ino = parseIno(req.params.ino);
+        const MAX_BLOCKS = 1024;
+        const blockSize = Number(req.query.size);
+        const blocks = String(req.query.blocks ?? "").split(",").filter(b => b.length > 0).map(Number);
+
+        if (!ino) {
+            console.log("[blockHashes] status 400: Inode missing");
+            return res.status(400).json({ error: "EINVAL", message: "Inode missing" });
+        }
+        if (!Number.isInteger(blockSize) || blockSize <= 0 || blockSize > MAX_BLOCK_SIZE) {
+            console.log("[blockHashes] status 400: Invalid block size");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid block size" });
+        }
+        if (blocks.length > MAX_BLOCKS || blocks.some(b => !Number.isInteger(b) || b < 0)) {
+            console.log("[blockHashes] status 400: Invalid block list");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid block list" });
+        }
+
+        try {
+            const file = await fileRepo.findOne({
+                where: { ino },
+                relations: ['owner', 'group', 'paths']
+            }) as File | null;
+            if (!file) {
+                console.log("[blockHashes] status 404: File not found");
+                return res.status(404).json({ error: "ENOENT", message: "File not found" });
+            }
+            if (!has_permissions(file, 0, req.user as User)) {
+                console.log("[blockHashes] status 403: No permission");
+                return res.status(403).json({ error: "EACCES", message: `You have not the permission to read ${ino}` });
+            }
+
+            const fullFsPath = toFsPath(file.paths[0].path);
+            const hashes: Record<string, string> = {};
+            const fd = await fsNode.open(fullFsPath, 'r');
+            try {
+                const buffer = Buffer.alloc(blockSize);
+                for (const block of blocks) {
+                    const { bytesRead } = await fd.read(buffer, 0, blockSize, block * blockSize);
+                    hashes[block] = createHash('sha256').update(buffer.subarray(0, bytesRead)).digest('hex');
+                }
+            } finally {
+                await fd.close();
+            }
+            console.log("[blockHashes] status 200: returning", blocks.length, "hashes");
+            return res.status(200).json({ hashes });
+        } catch (err: any) {
+            if (err?.code === 'ENOENT') {
+                console.log("[blockHashes] status 404: File not found");
+                return res.status(404).json({ error: "ENOENT", message: "File not found" });
+            }
+            console.log("[blockHashes] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: 'Not possible to hash the inode ' + ino, details: String(err?.message ?? err) });
+        }
+    }
+}
@@ -0,0 +1,216 @@
+import { Request, Response } from 'express';
+import { promises as fs } from 'node:fs';
+import { User } from '../entities/User';
+import { Group } from '../entities/Group';
+import { fileRepo, userRepo, groupRepo, pathRepo, ADMIN_UID, toFsPath } from '../utilities';
+import { AuthenticationController } from './authenticationController';
+
+const authenticationController = new AuthenticationController();
+
+function parseId(s: any): number | null {
+    const n = Number(s);
+    return Number.isInteger(n) && n > 0 ? n : null;
+}
+
+// nome di un utente o di un gruppo: come quelli di Unix, così i client li possono riusare in locale; null lo toglie
+function parseName(s: any): string | null | undefined {
+    if (s === null) return null;
+    return typeof s === 'string' && /^[a-z_][a-z0-9_.-]{0,31}$/i.test(s) ? s : undefined;
+}
+
+// gestione di utenti e gruppi, riservata all'amministratore (l'utente che possiede create-user.txt)
+export class AdminController {
+
+    public isAdmin = (req: Request, res: Response, next: () => any) => {
+        if ((req.user as User)?.uid === ADMIN_UID) return next();
+        console.log("[isAdmin] status 403: Not an administrator,", (req.user as User)?.uid);
+        return res.status(403).json({ error: "EACCES", message: "Administrator only" });
+    }
+
+    public listUsers = async (req: Request, res: Response) => {
+        console.log("[admin listUsers] called");
+        const users = await userRepo.find({ relations: ['group'], order: { uid: 'ASC' } });
+        console.log("[admin listUsers] status 200:", users.length, "users");
+        return res.status(200).json(users.map(u => ({ uid: u.uid, gid: u.group?.gid ?? null, name: u.name ?? null })));
+    }
+
+    public addUser = async (req: Request, res: Response) => {
+        console.log("[admin addUser] called with uid:", req.body?.uid, "gid:", req.body?.gid);
+        const uid = parseId(req.body?.uid);
+        const password = req.body?.password;
+        if (!uid || typeof password !== 'string' || password.length === 0) {
+            console.log("[admin addUser] status 400: Bad uid or password");
+            return res.status(400).json({ error: "EINVAL", message: "A positive integer uid and a password are required" });
+        }
+        const gid = req.body?.gid == null ? null : parseId(req.body.gid);
+        if (req.body?.gid != null && !gid) {
+            console.log("[admin addUser] status 400: Bad gid");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid gid" });
+        }
+        try {
+            const group = gid ? await groupRepo.findOneBy({ gid }) : null;
+            if (gid && !group) {
+                console.log("[admin addUser] status 404: Group not found");
+                return res.status(404).json({ error: "ENOENT", message: `Group ${gid} does not exist` });
+            }
+            if (!await authenticationController.createUser(uid, password)) {
+                console.log("[admin addUser] status 409: User already exists");
+                return res.status(409).json({ error: "EEXIST", message: `User ${uid} already exists` });
+            }
+            if (group) {
+                await userRepo.update({ uid }, { group });
+            }
+            console.log("[admin addUser] status 201: User created");
+            return res.status(201).json({ uid, gid });
+        } catch (err: any) {
+            console.log("[admin addUser] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: "Not possible to create the user" });
+        }
+    }
+
+    // rimuove un utente che non possiede più nulla oltre alla propria home vuota
+    public deleteUser = async (req: Request, res: Response) => {
+        console.log("[admin deleteUser] called with uid:", req.params.uid);
+        const uid = parseId(req.params.uid);
+        if (!uid) {
+            console.log("[admin deleteUser] status 400: Bad uid");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid uid" });
+        }
+        if (uid === ADMIN_UID) {
+            console.log("[admin deleteUser] status 403: Cannot delete the administrator");
+            return res.status(403).json({ error: "EPERM", message: "The administrator cannot be deleted" });
+        }
+        try {
+            const user = await userRepo.findOneBy({ uid });
+            if (!user) {
+                console.log("[admin deleteUser] status 404: User not found");
+                return res.status(404).json({ error: "ENOENT", message: `User ${uid} does not exist` });
+            }
+            const home = await pathRepo.findOne({ where: { path: `/${uid}` }, relations: ['file'] });
+            const owned = await fileRepo.count({ where: { owner: { uid } } });
+            const homeEntries = await fs.readdir(toFsPath(`/${uid}`)).catch(() => []);
+            if (owned > (home ? 1 : 0) || homeEntries.length > 0) {
+                console.log("[admin deleteUser] status 409: User still owns files");
+                return res.status(409).json({ error: "ENOTEMPTY", message: `User ${uid} still owns files` });
+            }
+            if (home) {
+                await pathRepo.delete({ path: home.path });
+                await fileRepo.delete({ ino: home.file.ino });
+                await fs.rmdir(toFsPath(home.path)).catch(() => undefined);
+            }
+            await userRepo.delete({ uid });
+            console.log("[admin deleteUser] status 200: User deleted");
+            return res.status(200).json({ uid });
+        } catch (err: any) {
+            console.log("[admin deleteUser] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: "Not possible to delete the user" });
+        }
+    }
+
+    public listGroups = async (req: Request, res: Response) => {
+        console.log("[admin listGroups] called");
+        const groups = await groupRepo.find({ relations: ['users'], order: { gid: 'ASC' } });
+        console.log("[admin listGroups] status 200:", groups.length, "groups");
+        return res.status(200).json(groups.map(g => ({ gid: g.gid, members: (g.users ?? []).map(u => u.uid), name: g.name ?? null })));
+    }
+
+    public addGroup = async (req: Request, res: Response) => {
+        console.log("[admin addGroup] called with gid:", req.body?.gid);
+        const gid = parseId(req.body?.gid);
+        if (!gid) {
+            console.log("[admin addGroup] status 400: Bad gid");
+            return res.status(400).json({ error: "EINVAL", message: "A positive integer gid is required" });
+        }
+        if (await groupRepo.findOneBy({ gid })) {
+            console.log("[admin addGroup] status 409: Group already exists");
+            return res.status(409).json({ error: "EEXIST", message: `Group ${gid} already exists` });
+        }
+        await groupRepo.save(groupRepo.create({ gid, users: [] }));
+        console.log("[admin addGroup] status 201: Group created");
+        return res.status(201).json({ gid, members: [] });
+    }
+
+    // rimuove un gruppo non più assegnato a file; i membri restano senza gruppo
+    public deleteGroup = async (req: Request, res: Response) => {
+        console.log("[admin deleteGroup] called with gid:", req.params.gid);
+        const gid = parseId(req.params.gid);
+        if (!gid) {
+            console.log("[admin deleteGroup] status 400: Bad gid");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid gid" });
+        }
+        const group = await groupRepo.findOne({ where: { gid }, relations: ['users'] });
+        if (!group) {
+            console.log("[admin deleteGroup] status 404: Group not found");
+            return res.status(404).json({ error: "ENOENT", message: `Group ${gid} does not exist` });
+        }
+        if (await fileRepo.count({ where: { group: { gid } } }) > 0) {
+            console.log("[admin deleteGroup] status 409: Group still used by files");
+            return res.status(409).json({ error: "EBUSY", message: `Group ${gid} is still assigned to files` });
+        }
+        for (const user of group.users ?? []) {
+            await userRepo.update({ uid: user.uid }, { group: null as unknown as Group });
+        }
+        await groupRepo.delete({ gid });
+        console.log("[admin deleteGroup] status 200: Group deleted");
+        return res.status(200).json({ gid });
+    }
+
+    // assegna (o toglie, con name null) il nome di un utente (/users/:uid/name) o di un gruppo (/groups/:gid/name)
+    public setName = async (req: Request, res: Response) => {
+        const isUser = req.params.uid !== undefined;
+        console.log("[admin setName] called with", isUser ? "uid:" : "gid:", req.params.uid ?? req.params.gid, "name:", req.body?.name);
+        const id = parseId(isUser ? req.params.uid : req.params.gid);
+        const name = parseName(req.body?.name);
+        if (!id || name === undefined) {
+            console.log("[admin setName] status 400: Bad id or name");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid id or name (letters, digits, _ . -, at most 32)" });
+        }
+        const taken = name !== null && await (isUser ? userRepo.findOneBy({ name }) : groupRepo.findOneBy({ name }));
+        if (taken && (isUser ? (taken as User).uid : (taken as Group).gid) !== id) {
+            console.log("[admin setName] status 409: Name already used");
+            return res.status(409).json({ error: "EEXIST", message: `The name ${name} is already used` });
+        }
+        const updated = isUser ? await userRepo.update({ uid: id }, { name }) : await groupRepo.update({ gid: id }, { name });
+        if (!updated.affected) {
+            console.log("[admin setName] status 404: Not found");
+            return res.status(404).json({ error: "ENOENT", message: `${isUser ? 'User' : 'Group'} ${id} does not exist` });
+        }
+        console.log("[admin setName] status 200:", isUser ? "user" : "group", id, "named", name);
+        return res.status(200).json(isUser ? { uid: id, name } : { gid: id, name });
+    }
+
+    // nomi di tutti gli utenti e i gruppi, per chiunque sia autenticato (come /etc/passwd): i client li mostrano
+    // al posto di uid e gid; chi non ha un nome resta numerico
+    public names = async (req: Request, res: Response) => {
+        const [users, groups] = await Promise.all([userRepo.find(), groupRepo.find()]);
+        return res.status(200).json({
+            users: users.filter(u => u.name).map(u => ({ uid: u.uid, name: u.name })),
+            groups: groups.filter(g => g.name).map(g => ({ gid: g.gid, name: g.name })),
+        });
+    }
+
+    // aggiunge (PUT) o toglie (DELETE) un utente da un gruppo; ogni utente ha un solo gruppo
+    public setMember = async (req: Request, res: Response) => {
+        const member = req.method === 'PUT';
+        console.log("[admin setMember] called with gid:", req.params.gid, "uid:", req.params.uid, "member:", member);
+        const gid = parseId(req.params.gid);
+        const uid = parseId(req.params.uid);
+        if (!gid || !uid) {
+            console.log("[admin setMember] status 400: Bad gid or uid");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid gid or uid" });
+        }
+        const group = await groupRepo.findOneBy({ gid });
+        const user = await userRepo.findOne({ where: { uid }, relations: ['group'] });
+        if (!group || !user) {
+            console.log("[admin setMember] status 404: Group or user not found");
+            return res.status(404).json({ error: "ENOENT", message: !group ? `Group ${gid} does not exist` : `User ${uid} does not exist` });
+        }
+        if (!member && user.group?.gid !== gid) {
+            console.log("[admin setMember] status 404: User not in group");
+            return res.status(404).json({ error: "ENOENT", message: `User ${uid} is not in group ${gid}` });
+        }
+        await userRepo.update({ uid }, { group: member ? group : null as unknown as Group });
+        console.log("[admin setMember] status 200: Membership updated");
+        return res.status(200).json({ uid, gid: member ? gid : null });
+    }
+}
@@ -1,20 +1,30 @@
 import { Request, Response } from 'express';
-import { fileRepo,userRepo,toFsPath,has_permissions,parseIno,toEntryJson,isBadName,childPathOf, pathRepo, getDirectorySize} from '../utilities';
+import { fileRepo,userRepo,toFsPath,has_permissions,parseIno,toEntryJson,isBadName,childPathOf, pathRepo, getDirectorySize, getDirectoryUsage, ifMatchSatisfied, etagOf, MAX_CHUNK_SIZE, MAX_BLOCK_SIZE, MAX_DIR_PAGE, FILE_FLAGS, FILE_FLAG_IMMUTABLE, FILE_FLAG_APPEND, MODE_BITS, wildcardToRegExp, PROTOCOL_VERSION, CAPABILITIES, ORPHANS_DIR} from '../utilities';
 import { File } from '../entities/File';
 import { User } from '../entities/User';
 import * as fs from 'node:fs/promises';
 import { Path } from '../entities/Path';
 import path from 'node:path';
 import disk from 'diskusage';
+import { breakLeases } from './leaseController';
 
 export class AttributeController{
     public readdir = async (req: Request, res: Response) => {
-        console.log("[readdir] called with ino:", req.params.ino, "user:", (req.user as User).uid);
+        console.log("[readdir] called with ino:", req.params.ino, "cursor:", req.query.cursor, "limit:", req.query.limit, "pattern:", req.query.pattern, "user:", (req.user as User).uid);
         const inoRec = parseIno(req.params.ino);
         if(!inoRec) {
             console.log("[readdir] status 400: Missing ino");
             return res.status(400).json({message:"Missing ino"});
         }
+        // paginazione opzionale: senza limit si restituisce l'intera directory come array
+        const paged = req.query.limit !== undefined;
+        const limit = Number(req.query.limit);
+        const cursor = typeof req.query.cursor === "string" ? req.query.cursor : undefined;
+        const pattern = typeof req.query.pattern === "string" ? wildcardToRegExp(req.query.pattern) : undefined;
+        if (paged && (!Number.isInteger(limit) || limit <= 0)) {
+            console.log("[readdir] status 400: Invalid limit");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid limit" });
+        }
 
         try{
             const dir=await fileRepo.findOne({where: {ino:inoRec}, relations: ['owner', 'group', 'paths'] }) as File | null;
@@ -32,7 +42,20 @@ export class AttributeController{
                 return res.status(403).json({ error: "EACCES", message: `You have not the permission to list ${inoRec}` });
             }
             const fullFsPath=toFsPath(dir.paths[0].path);
-            const names=await fs.readdir(fullFsPath);
+            let names=await fs.readdir(fullFsPath);
+            if (pattern) {
+                names = names.filter(name => pattern.test(name));
+            }
+            let next: string | null = null;
+            if (paged) {
+                // il cursore è l'ultimo nome della pagina precedente: l'ordine per nome resta stabile anche se la directory cambia
+                names = names.sort().filter(name => cursor === undefined || name > cursor);
+                const pageSize = Math.min(limit, MAX_DIR_PAGE);
+                if (names.length > pageSize) {
+                    names = names.slice(0, pageSize);
+                    next = names[names.length - 1];
+                }
+            }
 
             const rows= await Promise.all(
                 names.map(async (name) => {
@@ -55,8 +78,8 @@ export class AttributeController{
                 })
             );
             const content=rows.filter(Boolean);
-            console.log("[readdir] status 200: returning", content.length, "entries");
-            return res.status(200).json(content);
+            console.log("[readdir] status 200: returning", content.length, "entries", paged ? `(next: ${next})` : "");
+            return res.status(200).json(paged ? { entries: content, next } : content);
         }catch (err:any){
             if (err?.code === "ENOENT") {
                 console.log("[readdir] status 404: Directory not found on filesystem");
@@ -67,6 +90,89 @@ export class AttributeController{
         }
     }
 
+    // ricerca nel sottoalbero di una directory per nome (wildcard), dimensione e mtime, senza visitarlo dal client
+    public search = async (req: Request, res: Response) => {
+        console.log("[search] called with ino:", req.params.ino, "query:", req.query, "user:", (req.user as User).uid);
+        const inoRec = parseIno(req.params.ino);
+        if(!inoRec) {
+            console.log("[search] status 400: Missing ino");
+            return res.status(400).json({ error: "EINVAL", message: "Missing ino" });
+        }
+        const numberParam = (name: string): number | undefined | null => {
+            const raw = req.query[name];
+            if (raw === undefined) return undefined;
+            const value = Number(raw);
+            return Number.isFinite(value) && value >= 0 ? value : null;
+        };
+        const minSize = numberParam("minSize");
+        const maxSize = numberParam("maxSize");
+        const modifiedAfter = numberParam("modifiedAfter");
+        const modifiedBefore = numberParam("modifiedBefore");
+        const limit = numberParam("limit");
+        if ([minSize, maxSize, modifiedAfter, modifiedBefore, limit].includes(null) || limit === 0) {
+            console.log("[search] status 400: Invalid filter");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid search filter" });
+        }
+        const namePattern = typeof req.query.name === "string" ? wildcardToRegExp(req.query.name) : undefined;
+        const maxResults = Math.min(limit ?? MAX_DIR_PAGE, MAX_DIR_PAGE);
+
+        try{
+            const dir=await fileRepo.findOne({where: {ino:inoRec}, relations: ['owner', 'group', 'paths'] }) as File | null;
+            if (!dir) {
+                console.log("[search] status 404: Directory not found");
+                return res.status(404).json({ error: "ENOENT", message: `Directory with ino=${inoRec} not found` });
+            }
+            if (dir.type !==1 ) {
+                console.log("[search] status 400: Not a directory");
+                return res.status(400).json({ error: "ENOTDIR", message: `${inoRec} is not a directory` });
+            }
+            if (!has_permissions(dir, 0, req.user as User)) {
+                console.log("[search] status 403: No permission");
+                return res.status(403).json({ error: "EACCES", message: `You have not the permission to search ${inoRec}` });
+            }
+
+            // discendenti della directory dal DB; LIKE in sqlite ignora maiuscole/minuscole, il prefisso va ricontrollato
+            const prefix = dir.paths[0].path === '/' ? '/' : `${dir.paths[0].path}/`;
+            const escaped = prefix.replace(/[\\%_]/g, c => `\\${c}`);
+            const candidates = await pathRepo.createQueryBuilder("p")
+                .leftJoinAndSelect("p.file", "file")
+                .leftJoinAndSelect("file.owner", "owner")
+                .leftJoinAndSelect("file.group", "group")
+                .where("p.path LIKE :pattern ESCAPE '\\'", { pattern: `${escaped}%` })
+                .orderBy("p.path")
+                .getMany();
+
+            const results = [];
+            for (const pathObj of candidates) {
+                if (results.length >= maxResults) break;
+                if (pathObj.path === prefix || !pathObj.path.startsWith(prefix)) continue;
+                if (pathObj.path.startsWith(`${ORPHANS_DIR}/`)) continue; // cancellati ma ancora aperti
+                if (namePattern && !namePattern.test(path.posix.basename(pathObj.path))) continue;
+                if (!pathObj.file || !has_permissions(pathObj.file, 0, req.user as User)) continue;
+
+                let stats;
+                try {
+                    stats = await fs.lstat(toFsPath(pathObj.path), { bigint: true });
+                } catch (e: any) {
+                    if (e?.code === "ENOENT") continue; // rimosso durante la ricerca
+                    throw e;
+                }
+                const size = Number(stats.size);
+                const mtime = stats.mtime.getTime();
+                if (minSize !== undefined && size < minSize) continue;
+                if (maxSize !== undefined && size > maxSize) continue;
+                if (modifiedAfter !== undefined && mtime < modifiedAfter) continue;
+                if (modifiedBefore !== undefined && mtime > modifiedBefore) continue;
+                results.push(await toEntryJson(pathObj.file, stats, pathObj));
+            }
+            console.log("[search] status 200: returning", results.length, "entries");
+            return res.status(200).json(results);
+        }catch (err:any){
+            console.log("[search] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: `Not possible to search the folder (ino=${inoRec})`, details: String(err?.message ?? err) });
+        }
+    }
+
     public setattr = async (req: Request, res: Response) => {
         console.log("[setattr] called with ino:", req.params.ino, "body:", req.body, "user:", (req.user as User).uid);
         const inoRec=parseIno(req.params.ino);
@@ -79,7 +185,12 @@ export class AttributeController{
             perm: rawPerm,
             uid: rawUid,
             gid: rawGid,
-            size: rawSize
+            size: rawSize,
+            flags: rawFlags,
+            fileFlags: rawFileFlags,
+            atime: rawAtime,
+            mtime: rawMtime,
+            btime: rawBtime
         } = req.body ?? {};
 
         try{
@@ -100,6 +211,21 @@ export class AttributeController{
 
             const fullFsPath=toFsPath(file.paths[0].path); // every path is valid, so we can take the first one
 
+            // come chattr +i / +a: su un file immutabile si possono cambiare solo i flag, uno append-only non si tronca
+            const fileFlags = file.fileFlags ?? 0;
+            const otherChanges = [rawPerm, rawUid, rawGid, rawSize, rawFlags, rawAtime, rawMtime, rawBtime].some(v => v != null);
+            if ((fileFlags & FILE_FLAG_IMMUTABLE && otherChanges) || (fileFlags & FILE_FLAG_APPEND && rawSize != null)) {
+                console.log("[setattr] status 403: Immutable or append-only file");
+                return res.status(403).json({ error: "EPERM", message: `${inoRec} is immutable or append-only` });
+            }
+
+            // prima i lease: chi ha write in sospeso le invia prima di rilasciarlo
+            await breakLeases(inoRec, req.sessionID);
+            if (!await ifMatchSatisfied(req.header('if-match'), fullFsPath)) {
+                console.log("[setattr] status 412: If-Match precondition failed");
+                return res.status(412).json({ error: "EPRECONDITION", message: `${inoRec} was modified by another client` });
+            }
+
             if(rawUid!=null || rawGid != null){
                 const user=await userRepo.findOne({where:{uid:rawUid}});
                 if(!user){
@@ -115,13 +241,49 @@ export class AttributeController{
             let newPerm: number|undefined;
             if(rawPerm!=null){
                 const n = typeof rawPerm === "number" ? rawPerm : parseInt(String(rawPerm), 10);
-                if ( n < 0 || n > 0o777) {
+                if ( n < 0 || n > MODE_BITS) {
                     console.log("[setattr] status 400: Invalid mode");
-                    return res.status(400).json({ error: "EINVAL", message: "Invalid mode (0..0o777)" });
+                    return res.status(400).json({ error: "EINVAL", message: "Invalid mode (0..0o7777)" });
                 }
                 newPerm = n;
             }
 
+            let newFlags: number | undefined;
+            if (rawFlags != null) {
+                const n = typeof rawFlags === "number" ? rawFlags : parseInt(String(rawFlags), 10);
+                if (!Number.isInteger(n) || n < 0 || n > 0xffffffff) {
+                    console.log("[setattr] status 400: Invalid flags");
+                    return res.status(400).json({ error: "EINVAL", message: "Invalid flags" });
+                }
+                newFlags = n;
+            }
+
+            let newFileFlags: number | undefined;
+            if (rawFileFlags != null) {
+                const n = typeof rawFileFlags === "number" ? rawFileFlags : parseInt(String(rawFileFlags), 10);
+                if (!Number.isInteger(n) || n < 0 || (n & ~FILE_FLAGS) !== 0) {
+                    console.log("[setattr] status 400: Invalid file flags");
+                    return res.status(400).json({ error: "EINVAL", message: "Invalid file flags" });
+                }
+                newFileFlags = n;
+            }
+
+            // tempi in millisecondi dall'epoch
+            const times: (Date | undefined)[] = [];
+            for (const raw of [rawAtime, rawMtime, rawBtime]) {
+                if (raw == null) {
+                    times.push(undefined);
+                    continue;
+                }
+                const n = typeof raw === "number" ? raw : parseInt(String(raw), 10);
+                if (!Number.isFinite(n) || n < 0) {
+                    console.log("[setattr] status 400: Invalid time");
+                    return res.status(400).json({ error: "EINVAL", message: "Invalid time" });
+                }
+                times.push(new Date(n));
+            }
+            const [newAtime, newMtime, newBtime] = times;
+
             let newSize: number | undefined;
             if (rawSize != null) {
                 const n = typeof rawSize === "number" ? rawSize : parseInt(String(rawSize), 10);
@@ -137,6 +299,16 @@ export class AttributeController{
                 await fileRepo.update({ino:file.ino}, { permissions: newPerm });
             }
 
+            if(newFlags !== undefined && newFlags != file.flags){
+                file.flags=newFlags;
+                await fileRepo.update({ino:file.ino}, { flags: newFlags });
+            }
+
+            if(newFileFlags !== undefined && newFileFlags != file.fileFlags){
+                file.fileFlags=newFileFlags;
+                await fileRepo.update({ino:file.ino}, { fileFlags: newFileFlags });
+            }
+
             if(newSize != undefined){
                 if (file.type === 1) {
                     console.log("[setattr] status 400: Cannot truncate a directory");
@@ -144,10 +316,19 @@ export class AttributeController{
                 }
                 await fs.truncate(fullFsPath, newSize);
             }
+
+            // dopo il truncate, che altrimenti sovrascriverebbe mtime
+            if (newAtime !== undefined || newMtime !== undefined) {
+                const current = await fs.lstat(fullFsPath);
+                await fs.lutimes(fullFsPath, newAtime ?? current.atime, newMtime ?? current.mtime);
+            }
+            if (newBtime !== undefined)
+                console.log("[setattr] birth time not settable on this filesystem, ignored");
             
             const stats=await fs.lstat(fullFsPath);
+            res.setHeader('ETag', etagOf(stats));
             console.log("[setattr] status 200: returning updated entry");
-            return res.status(200).json(toEntryJson(file, stats, file.paths[0]));
+            return res.status(200).json(await toEntryJson(file, stats, file.paths[0]));
         } catch (err:any){
             if (err?.code === "ENOENT") {
                 console.log("[setattr] status 404: Filesystem path not found");
@@ -218,7 +399,7 @@ export class AttributeController{
             }
 
             console.log("[lookup] status 200: returning entry");
-            return res.status(200).json(toEntryJson(childFile, stats, childPathObj));
+            return res.status(200).json(await toEntryJson(childFile, stats, childPathObj));
         }catch (err:any){
             console.log("[lookup] status 500:", err?.message ?? err);
             return res.status(500).json({
@@ -271,9 +452,10 @@ export class AttributeController{
 
             const lastModifiedHttp=(new Date(lastModifiedSecond * 1000)).toUTCString();
             res.setHeader('Last-Modified', lastModifiedHttp);
+            res.setHeader('ETag', etagOf(stats));
 
             console.log("[getattr] status 200: returning entry");
-            return res.status(200).json(toEntryJson(file, stats, file.paths[0]));
+            return res.status(200).json(await toEntryJson(file, stats, file.paths[0]));
         }catch (err:any){
             if (err.code === 'ENOENT') {
                 console.log("[getattr] status 404: File not found");
@@ -288,6 +470,57 @@ export class AttributeController{
         }
     }
 
+    // dimensioni massime accettate, per adattare blocchi e soglia di streaming del client
+    public limits = async (req: Request, res: Response) => {
+        console.log("[limits] status 200: maxChunkSize =", MAX_CHUNK_SIZE, "maxBlockSize =", MAX_BLOCK_SIZE);
+        return res.status(200).json({ maxChunkSize: MAX_CHUNK_SIZE, maxBlockSize: MAX_BLOCK_SIZE });
+    }
+
+    // handshake al mount: il client abilita solo le funzionalità annunciate qui
+    public capabilities = async (req: Request, res: Response) => {
+        console.log("[capabilities] status 200: version =", PROTOCOL_VERSION);
+        return res.status(200).json({ version: PROTOCOL_VERSION, ...CAPABILITIES });
+    }
+
+    // spazio occupato dal sottoalbero di una directory (o da un singolo file)
+    public usage = async (req: Request, res: Response) => {
+        console.log("[usage] called with ino:", req.params.ino, "user:", (req.user as User).uid);
+        const inoRec = parseIno(req.params.ino);
+        if(!inoRec) {
+            console.log("[usage] status 400: Missing ino");
+            return res.status(400).json({ error: "EINVAL", message: "Missing ino" });
+        }
+
+        try{
+            const file=await fileRepo.findOne({where: {ino:inoRec}, relations: ['owner', 'group', 'paths'] }) as File | null;
+            if (!file) {
+                console.log("[usage] status 404: File not found");
+                return res.status(404).json({ error: "ENOENT", message: `File with ino=${inoRec} not found` });
+            }
+            if (!has_permissions(file, 0, req.user as User)) {
+                console.log("[usage] status 403: No permission");
+                return res.status(403).json({ error: "EACCES", message: `You have not the permission to read ${inoRec}` });
+            }
+            const fullFsPath = toFsPath(file.paths[0].path);
+            let usage;
+            if (file.type === 1) {
+                usage = await getDirectoryUsage(fullFsPath);
+            } else {
+                const stats = await fs.lstat(fullFsPath);
+                usage = { bytes: stats.size, files: 1, dirs: 0 };
+            }
+            console.log("[usage] status 200:", usage);
+            return res.status(200).json(usage);
+        }catch (err:any){
+            if (err?.code === "ENOENT") {
+                console.log("[usage] status 404: File not found on filesystem");
+                return res.status(404).json({ error: "ENOENT", message: "File not found on filesystem" });
+            }
+            console.log("[usage] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: `Not possible to compute the usage of ino=${inoRec}`, details: String(err?.message ?? err) });
+        }
+    }
+
     public fsSize = async (req: Request, res: Response) => {
         console.log("[fsSize] called");
         const root = path.resolve(process.env.FS_ROOT || './file-system');
@@ -6,9 +6,11 @@ import { AppDataSource } from '../data-source';
 import { promises as fs } from 'node:fs';
 import { File } from '../entities/File';
 import { Group } from '../entities/Group';
-import { pathRepo } from '../utilities';
+import { pathRepo, userRepo } from '../utilities';
+import { dropSession } from './leaseController';
 
 const scryptAsync = promisify(crypto.scrypt);
+const MIN_PASSWORD_LENGTH = 8;
 
 export class AuthenticationController {
 
@@ -22,13 +24,25 @@ export class AuthenticationController {
     public signup = async (req: Request, res: Response) => {
         console.log("[signup] called with uid:", req.body?.uid);
         const { uid, password } = req.body;
+        if (!await this.createUser(uid, password)) {
+            console.log("[signup] status 400: User already exists");
+            return res.status(400).json({ message: "User already exists" });
+        }
+
+        // clearing the file create-user
+        await fs.writeFile('./file-system/create-user.txt', 'User successfully created');
+        console.log("[signup] status 200: User created");
+        return res.status(200).json({ message: "User created" });
+    }
+
+    // crea l'utente con la sua home directory; false se esiste già
+    public createUser = async (uid: number, password: string): Promise<boolean> => {
         const userRepo = AppDataSource.getRepository(User);
         const fileRepo = AppDataSource.getRepository(File);
 
         const exists = await userRepo.findOneBy({ uid });
         if (exists) {
-            console.log("[signup] status 400: User already exists");
-            return res.status(400).json({ message: "User already exists" });
+            return false;
         }
         console.log("[signup] User does not exist, creating...");
 
@@ -58,25 +72,81 @@ export class AuthenticationController {
         });
         await pathRepo.save(userPath);
         console.log("[signup] User directory path saved in database");
-
-        // clearing the file create-user
-        await fs.writeFile('./file-system/create-user.txt', 'User successfully created');
-        console.log("[signup] status 200: User created");
-        return res.status(200).json({ message: "User created" });
+        return true;
     }
 
     // logout
     public logout = async (req: Request, res: Response) => {
         console.log("[logout] called for user:", (req.user as User)?.uid);
+        dropSession(req.sessionID);
         req.logout(() => {
-            console.log("[logout] status 200: User logged out");
-            res.end();
+            // la sessione salvata dal client non deve più essere valida
+            req.session.destroy(() => {
+                console.log("[logout] status 200: User logged out");
+                res.clearCookie('connect.sid');
+                res.end();
+            });
         });
     }
 
+    // cambio password dell'utente loggato: la sessione corrente viene rigenerata e le altre
+    // sessioni dello stesso utente (altri mount, sessioni salvate) smettono di valere
+    public passwd = async (req: Request, res: Response) => {
+        const uid = (req.user as User)?.uid;
+        console.log("[passwd] called for user:", uid);
+        const { oldPassword, newPassword } = req.body ?? {};
+        if (typeof oldPassword !== 'string' || typeof newPassword !== 'string') {
+            console.log("[passwd] status 400: Missing passwords");
+            return res.status(400).json({ error: "EINVAL", message: "oldPassword and newPassword are required" });
+        }
+        if (newPassword.length < MIN_PASSWORD_LENGTH) {
+            console.log("[passwd] status 400: Password too short");
+            return res.status(400).json({ error: "EINVAL", message: `The new password must be at least ${MIN_PASSWORD_LENGTH} characters long` });
+        }
+        try {
+            if (!await this.getUser(uid, oldPassword)) {
+                console.log("[passwd] status 403: Wrong password");
+                return res.status(403).json({ error: "EACCES", message: "Wrong password" });
+            }
+            const salt = crypto.randomBytes(16).toString('hex');
+            const hashedPassword = (await scryptAsync(newPassword, salt, 32) as Buffer).toString('hex');
+            await AppDataSource.getRepository(User).update({ uid }, { password: hashedPassword, salt });
+
+            const current = req.sessionID;
+            req.sessionStore.all?.((err, sessions) => {
+                if (err || !sessions) return;
+                for (const [sid, s] of Object.entries(sessions)) {
+                    if (sid !== current && (s as any)?.passport?.user === uid) req.sessionStore.destroy(sid);
+                }
+            });
+            // req.login rigenera la sessione: il client riceve un nuovo cookie
+            req.login(req.user as User, (err) => {
+                if (err) {
+                    console.log("[passwd] status 500: Cannot renew the session,", err);
+                    return res.status(500).json({ error: "EIO", message: "Password changed, but the session could not be renewed" });
+                }
+                console.log("[passwd] status 200: Password changed");
+                return res.status(200).json({ message: "Password changed" });
+            });
+        } catch (err: any) {
+            console.log("[passwd] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: "Not possible to change the password" });
+        }
+    }
+
+    // identità dell'utente loggato: uid, gruppo principale e gruppi di cui fa parte, usati dal
+    // client per valutare i permessi di gruppo in locale (mai password e salt)
     public logged = async (req: Request, res: Response) => {
-        console.log("[logged] called for user:", (req.user as User)?.uid);
-        res.json(req.user as User);
+        const uid = (req.user as User)?.uid;
+        console.log("[logged] called for user:", uid);
+        const user = await userRepo.findOne({ where: { uid }, relations: ['group'] });
+        if (!user) {
+            console.log("[logged] status 401: User no longer exists");
+            return res.status(401).json({ error: "EACCES", message: "Unauthorized" });
+        }
+        const gid = user.group?.gid ?? null;
+        console.log("[logged] status 200: gid", gid);
+        return res.status(200).json({ uid: user.uid, gid, groups: gid === null ? [] : [gid] });
     }
 
     public getUser = async (uid: number, password: string) => {
@@ -0,0 +1,81 @@
+import { Request, Response } from 'express';
+import { ReadWriteController } from './RWController';
+import { User } from '../entities/User';
+import { parseMultipart } from '../wire';
+
+// write piccole raccolte dal client in un'unica richiesta multipart: la parte "ops" elenca ino, offset e
+// If-Match di ognuna, la parte "data<i>" contiene i byte della i-esima. Le write vengono eseguite in ordine
+// dallo stesso controller delle write singole, e ognuna ha il proprio esito nella risposta.
+const MAX_BATCH_OPS = 1000;
+
+const rwController = new ReadWriteController();
+
+// risposta tenuta in memoria al posto di quella HTTP, per eseguire un controller su una parte del batch
+class CapturedResponse {
+    statusCode = 200;
+    headers: Record<string, string> = {};
+    body: any;
+
+    status(code: number) {
+        this.statusCode = code;
+        return this;
+    }
+    setHeader(name: string, value: string) {
+        this.headers[name.toLowerCase()] = String(value);
+        return this;
+    }
+    json(body: any) {
+        this.body = body;
+        return this;
+    }
+    send(body: any) {
+        this.body = body;
+        return this;
+    }
+}
+
+export class BatchController {
+    public writes = async (req: Request, res: Response) => {
+        const parts = Buffer.isBuffer(req.body) ? parseMultipart(req.body, req.get('Content-Type')) : null;
+        let ops: any;
+        try {
+            ops = JSON.parse(parts?.get('ops')?.toString('utf8') ?? '');
+        } catch {
+            ops = null;
+        }
+        if (!parts || !Array.isArray(ops) || ops.length > MAX_BATCH_OPS) {
+            console.log("[batch] status 400: Invalid batch body");
+            return res.status(400).json({ error: "EINVAL", message: `Invalid batch body (at most ${MAX_BATCH_OPS} writes)` });
+        }
+        console.log("[batch] called with", ops.length, "writes, user:", (req.user as User)?.uid);
+
+        const results = [];
+        for (const [i, op] of ops.entries()) {
+            const data = parts.get(`data${i}`);
+            if (data === undefined || op === null || typeof op !== 'object') {
+                results.push({ status: 400, error: `Write ${i} has no data` });
+                continue;
+            }
+            const headers = { ...req.headers };
+            delete headers['if-match'];
+            if (typeof op.ifMatch === 'string') headers['if-match'] = op.ifMatch;
+            const sub = Object.create(req, {
+                params: { value: { ino: String(op.ino) } },
+                query: { value: { offset: String(op.offset ?? 0) } },
+                body: { value: data },
+                headers: { value: headers },
+            }) as Request;
+            const captured = new CapturedResponse();
+            await rwController.write(sub, captured as unknown as Response);
+            const body = captured.body ?? {};
+            results.push({
+                status: captured.statusCode,
+                bytes: body.bytes,
+                etag: captured.headers['etag'],
+                error: captured.statusCode === 200 ? undefined : body.message ?? body.error,
+            });
+        }
+        console.log("[batch] status 200: Batch finished");
+        return res.status(200).json({ results });
+    }
+}
@@ -1,11 +1,87 @@
 import { Request, Response } from 'express';
-import { fileRepo,groupRepo,pathRepo,toFsPath,has_permissions,parseIno,toEntryJson,isBadName,childPathOf} from '../utilities';
+import { fileRepo,groupRepo,pathRepo,toFsPath,has_permissions,sticky_allows,parseIno,toEntryJson,isBadName,childPathOf,MODE_BITS,S_ISGID,ORPHANS_DIR} from '../utilities';
 import { File } from '../entities/File';
 import { User } from '../entities/User';
 import { Group } from '../entities/Group';
 import * as fs from 'node:fs/promises';
 import { Path } from '../entities/Path';
 import { permission } from 'node:process';
+import { breakLeases } from './leaseController';
+import { isOpen, orphan, orphanPathOf } from './openController';
+import { AppDataSource } from '../data-source';
+import { randomUUID } from 'node:crypto';
+
+// permessi richiesti dal client alla creazione (body {mode}), altrimenti il default; null se non validi
+function requestedMode(raw: any, fallback: number): number | null {
+    if (raw == null)
+        return fallback;
+    const n = typeof raw === "number" ? raw : parseInt(String(raw), 10);
+    return Number.isInteger(n) && n >= 0 && n <= MODE_BITS ? n : null;
+}
+
+// rinomina `entry` da `oldPath` a `newPath` con una sola rename(2), atomica anche sopra una destinazione
+// esistente (`replaced`): chi apre newPath trova il contenuto vecchio o quello nuovo, mai una via di mezzo.
+// Se la destinazione perde l'ultimo nome mentre qualche client la tiene aperta resta tra gli orfani.
+// Il DB si aggiorna in una transazione: se fallisce la rename su disco viene annullata
+async function renameEntry(entry: File, oldPath: string, newPath: string, replaced: File | null): Promise<Path> {
+    const fullOld = toFsPath(oldPath);
+    const fullNew = toFsPath(newPath);
+    // `paths` delle voci cercate per percorso contiene solo quel percorso: gli altri nomi vanno contati
+    const lastName = replaced !== null && await pathRepo.count({ where: { file: { ino: replaced.ino } } }) <= 1;
+    const keepOpen = replaced !== null && replaced.type !== 1 && lastName && isOpen(replaced.ino);
+    // la destinazione sostituita resta raggiungibile da un secondo nome finché il DB non è aggiornato
+    const backup = replaced === null || replaced.type === 1 ? null
+        : keepOpen ? orphanPathOf(replaced.ino) : `${orphanPathOf(replaced.ino)}-${randomUUID()}`;
+    const replacedDir = replaced?.type === 1 ? await fs.stat(fullNew) : null;
+    if (backup) {
+        await fs.mkdir(toFsPath(ORPHANS_DIR), { recursive: true });
+        await fs.link(fullNew, toFsPath(backup));
+    }
+    try {
+        await fs.rename(fullOld, fullNew);
+    } catch (err) {
+        if (backup)
+            await fs.unlink(toFsPath(backup)).catch(() => {});
+        throw err;
+    }
+
+    // una directory porta con sé i percorsi di tutti i discendenti
+    const escaped = `${oldPath}/`.replace(/[\\%_]/g, c => `\\${c}`);
+    const descendants = entry.type !== 1 ? [] : await pathRepo.createQueryBuilder("p")
+        .where("p.path LIKE :pattern ESCAPE '\\'", { pattern: `${escaped}%` })
+        .getMany();
+    const newPathObj = { path: newPath, file: entry } as Path;
+    try {
+        await AppDataSource.transaction(async manager => {
+            if (replaced) {
+                if (keepOpen) {
+                    await manager.update(Path, { path: newPath }, { path: orphanPathOf(replaced.ino) });
+                } else {
+                    await manager.delete(Path, { path: newPath });
+                    if (lastName)
+                        await manager.delete(File, { ino: replaced.ino });
+                }
+            }
+            await manager.delete(Path, { path: oldPath });
+            await manager.save(Path, newPathObj);
+            for (const child of descendants) {
+                if (child.path.startsWith(`${oldPath}/`)) // LIKE in sqlite ignora maiuscole/minuscole
+                    await manager.update(Path, { path: child.path }, { path: newPath + child.path.slice(oldPath.length) });
+            }
+        });
+    } catch (err) {
+        // il DB è rimasto com'era: il disco torna come prima
+        await fs.rename(fullNew, fullOld);
+        if (backup)
+            await fs.rename(toFsPath(backup), fullNew);
+        else if (replacedDir)
+            await fs.mkdir(fullNew, { mode: replacedDir.mode & MODE_BITS });
+        throw err;
+    }
+    if (backup && !keepOpen)
+        await fs.unlink(toFsPath(backup));
+    return newPathObj;
+}
 
 export class FileController {
     public mkdir = async (req: Request, res: Response) => {
@@ -21,6 +97,11 @@ export class FileController {
             console.log("[mkdir] status 400: Invalid directory name");
             return res.status(400).json({ error: "EINVAL", message: "Invalid directory name" });
         }
+        const mode = requestedMode(req.body?.mode, 0o755);
+        if (mode === null) {
+            console.log("[mkdir] status 400: Invalid mode");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid mode (0..0o7777)" });
+        }
 
         const user = req.user as User |undefined;
         if (!user) {
@@ -54,12 +135,14 @@ export class FileController {
             await fs.mkdir(childFsPath);
             const stats=await fs.lstat(childFsPath,{bigint:true});
             
+            // in una directory setgid le nuove voci prendono il gruppo della directory, e le sottodirectory anche il bit
+            const inherit = (parent.permissions & S_ISGID) !== 0;
             const directory = {
                 ino:stats.ino.toString(),
                 owner:user,
-                group: userGroup,
+                group: inherit ? parent.group : userGroup,
                 type: 1,
-                permissions: 0o755,
+                permissions: inherit ? mode | S_ISGID : mode,
             } as File;
             await fileRepo.save(directory);
 
@@ -69,8 +152,9 @@ export class FileController {
             } as Path;
             await pathRepo.save(childPathObject);
 
+            await breakLeases(parentIno, req.sessionID); // la lista della directory è cambiata
             console.log("[mkdir] status 201: Directory created");
-            return res.status(201).json(toEntryJson(directory, stats, childPathObject));
+            return res.status(201).json(await toEntryJson(directory, stats, childPathObject));
         }catch(err:any){
             if (err?.code === "EEXIST") {
                 console.log("[mkdir] status 409: Folder already exists");
@@ -129,6 +213,9 @@ export class FileController {
             if (child.type !== 1) {
                 return res.status(400).json({ error: "ENOTDIR", message: "The specified name is not a directory" });
             }
+            if (!sticky_allows(parent, child, user)) {
+                return res.status(403).json({ error: "EPERM", message: `${name} belongs to another user in a sticky directory` });
+            }
 
             try{
                 await fs.rmdir(childFsPath);
@@ -150,6 +237,7 @@ export class FileController {
             }
             else // should not happen, but just in case
                 return res.status(500).json({ error: "EIO", message: "Directory has multiple paths, manual cleanup required" });
+            await breakLeases(parentIno, req.sessionID);
             console.log("[rmdir] status 200: Directory removed");
             return res.status(200).end();
         } catch (err: any) {
@@ -171,6 +259,11 @@ export class FileController {
             return res.status(400).json({ error: "EINVAL", message: "Parent inode missing" });
         if (isBadName(name))
             return res.status(400).json({ error: "EINVAL", message: "Invalid directory name" });
+        const mode = requestedMode(req.body?.mode, 0o644);
+        if (mode === null) {
+            console.log("[create] status 400: Invalid mode");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid mode (0..0o7777)" });
+        }
 
         const user = req.user as User | undefined;
         if (!user) {
@@ -202,9 +295,9 @@ export class FileController {
             const file={
                 ino:stats.ino.toString(),
                 owner:user,
-                group: userGroup ?? null,
+                group: (parent.permissions & S_ISGID) !== 0 ? parent.group : userGroup ?? null,
                 type: 0,
-                permissions: 0o644,
+                permissions: mode,
             } as File;
             const pathObj={
                 file:file,
@@ -213,8 +306,9 @@ export class FileController {
             await fileRepo.save(file);
             await pathRepo.save(pathObj);
 
+            await breakLeases(parentIno, req.sessionID);
             console.log("[create] status 201: File created");
-            return res.status(201).json(toEntryJson(file, stats, pathObj));
+            return res.status(201).json(await toEntryJson(file, stats, pathObj));
         }catch(err:any){
             if (err?.code === "EEXIST") {
                 console.log("[create] status 409: File already exists");
@@ -271,7 +365,22 @@ export class FileController {
             if (!child){
                 return res.status(404).json({ error: "ENOENT", message: "File metadata not found in database" });
             }
+            // unlink(2) di una directory dà EISDIR solo su Linux, su macOS è EPERM
+            if (child.type === 1) {
+                return res.status(400).json({ error: "EISDIR", message: "Target is a directory" });
+            }
+            if (!sticky_allows(parent, child, user)) {
+                return res.status(403).json({ error: "EPERM", message: `${name} belongs to another user in a sticky directory` });
+            }
 
+            await breakLeases(child.ino, req.sessionID);
+            await breakLeases(parentIno, req.sessionID);
+            // ultimo nome di un file ancora aperto: il contenuto resta finché non viene chiuso
+            if (child.paths.length === 1 && isOpen(child.ino)) {
+                await orphan(child, childDbPath);
+                console.log("[unlink] status 200: File removed, kept open");
+                return res.status(200).end();
+            }
             try{
                 await fs.unlink(childFsPath);
             }catch(err:any){
@@ -336,40 +445,132 @@ export class FileController {
 
             const oldPath = childPathOf(oldParent.paths[0].path, oldName);
             const newPath = childPathOf(newParent.paths[0].path, newName);
-            const fullOld = toFsPath(oldPath);
-            const fullNew = toFsPath(newPath);
 
             const entry = await fileRepo.findOne({ where: { paths: {path: oldPath }}, relations: ["owner", "group", "paths"] }) as File | null;
             if (!entry) 
                 return res.status(404).json({ error: "ENOENT", message: "Source entry not found" });
+            if (!sticky_allows(oldParent, entry, user))
+                return res.status(403).json({ error: "EPERM", message: `${oldName} belongs to another user in a sticky directory` });
+            const replaced = await fileRepo.findOne({ where: { paths: { path: newPath } }, relations: ["owner", "group", "paths"] }) as File | null;
+            if (replaced && !sticky_allows(newParent, replaced, user))
+                return res.status(403).json({ error: "EPERM", message: `${newName} belongs to another user in a sticky directory` });
+            // due nomi dello stesso file: rename(2) non fa nulla
+            if (replaced?.ino === entry.ino) {
+                console.log("[rename] status 200: Source and target are the same file");
+                return res.status(200).json(await toEntryJson(entry, await fs.lstat(toFsPath(oldPath), { bigint: true }), entry.paths[0]));
+            }
+            await breakLeases(entry.ino, req.sessionID);
+            await breakLeases(oldParentIno, req.sessionID);
+            await breakLeases(newParentInode, req.sessionID);
+            if (replaced)
+                await breakLeases(replaced.ino, req.sessionID);
+            let newPathObj: Path;
             try{
-                await fs.rename(fullOld,fullNew);
+                newPathObj = await renameEntry(entry, oldPath, newPath, replaced);
             }catch(err:any){
                 if (err?.code === "ENOENT")   
                     return res.status(404).json({ error: "ENOENT", message: "Source or target dir missing" });
                 if (err?.code === "EEXIST")   
                     return res.status(409).json({ error: "EEXIST", message: "Target exists" });
+                if (err?.code === "ENOTEMPTY")
+                    return res.status(409).json({ error: "ENOTEMPTY", message: "Target directory not empty" });
+                if (err?.code === "EISDIR")
+                    return res.status(400).json({ error: "EISDIR", message: "Target is a directory" });
+                if (err?.code === "ENOTDIR")
+                    return res.status(400).json({ error: "ENOTDIR", message: "Target is not a directory" });
+                if (err?.code === "EINVAL")
+                    return res.status(400).json({ error: "EINVAL", message: "Cannot move a directory inside itself" });
                 throw err;
             }
-            const pathObj = entry.paths.find(p => p.path === oldPath);
-            if (!pathObj) 
-                return res.status(500).json({ error: "EIO", message: "Path data not found, manual cleanup required" });
-            
-            const newPathObj = {
-                path: newPath,
-                file: entry
-            } as Path;
-            await pathRepo.remove(pathObj);
-            await pathRepo.save(newPathObj);
-            const stats = await fs.lstat(fullNew,{bigint:true});
+            const stats = await fs.lstat(toFsPath(newPath),{bigint:true});
             console.log("[rename] status 200: Entry renamed");
-            return res.status(200).json(toEntryJson(entry, stats, newPathObj));
+            return res.status(200).json(await toEntryJson(entry, stats, newPathObj));
         }catch(err:any){
             console.log("[rename] status 500:", err?.message ?? err);
             return res.status(500).json({ error: "EIO", message: "Not possible to rename", details: String(err?.message ?? err) });
         }
     }
 
+    // salvataggio "sicuro" degli editor (scrivo un temporaneo e lo rinomino sopra l'originale), per i client
+    // che lo chiedono esplicitamente: è una rename atomica del temporaneo sopra la destinazione
+    public replaceContent = async (req: Request, res: Response) => {
+        console.log("[replaceContent] called with parentIno:", req.params.parentIno, "name:", req.params.name, "sourceParentIno:", req.body?.sourceParentIno, "sourceName:", req.body?.sourceName, "user:", (req.user as User).uid);
+        const parentIno = parseIno(req.params.parentIno);
+        const name = req.params.name;
+        const { sourceParentIno, sourceName } = req.body ?? {};
+        const sourceParentInode = parseIno(sourceParentIno);
+
+        if (!parentIno || !sourceParentInode) {
+            console.log("[replaceContent] status 400: Invalid parent inode(s)");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid parent inode(s)" });
+        }
+        if (isBadName(name) || isBadName(sourceName)) {
+            console.log("[replaceContent] status 400: Invalid name(s)");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid name(s)" });
+        }
+
+        const user = req.user as User;
+
+        try{
+            const [parent, sourceParent] = await Promise.all([
+                fileRepo.findOne({ where: { ino: parentIno }, relations: ["owner", "group", "paths"] }),
+                fileRepo.findOne({ where: { ino: sourceParentInode }, relations: ["owner", "group", "paths"] }),
+            ]);
+            if (!parent || !sourceParent) {
+                console.log("[replaceContent] status 404: Parent not found");
+                return res.status(404).json({ error: "ENOENT", message: "Parent not found" });
+            }
+            if (parent.type !== 1 || sourceParent.type !== 1) {
+                console.log("[replaceContent] status 400: Parent(s) must be directories");
+                return res.status(400).json({ error: "ENOTDIR", message: "Parent(s) must be directories" });
+            }
+            if (!has_permissions(parent, 1, user) || !has_permissions(sourceParent, 1, user)) {
+                console.log("[replaceContent] status 403: No permission");
+                return res.status(403).json({ error: "EACCES", message: "Insufficient permissions" });
+            }
+
+            const targetPath = childPathOf(parent.paths[0].path, name);
+            const sourcePath = childPathOf(sourceParent.paths[0].path, sourceName);
+            const [target, source] = await Promise.all([
+                fileRepo.findOne({ where: { paths: { path: targetPath } }, relations: ["owner", "group", "paths"] }),
+                fileRepo.findOne({ where: { paths: { path: sourcePath } }, relations: ["owner", "group", "paths"] }),
+            ]);
+            if (!target || !source) {
+                console.log("[replaceContent] status 404: Source or target not found");
+                return res.status(404).json({ error: "ENOENT", message: "Source or target not found" });
+            }
+            if (target.type !== 0 || source.type !== 0) {
+                console.log("[replaceContent] status 400: Source and target must be regular files");
+                return res.status(400).json({ error: "EINVAL", message: "Source and target must be regular files" });
+            }
+            if (target.ino === source.ino) {
+                console.log("[replaceContent] status 400: Source and target are the same file");
+                return res.status(400).json({ error: "EINVAL", message: "Source and target are the same file" });
+            }
+            if (!sticky_allows(parent, target, user) || !sticky_allows(sourceParent, source, user)) {
+                console.log("[replaceContent] status 403: Entry of another user in a sticky directory");
+                return res.status(403).json({ error: "EPERM", message: "Entry belongs to another user in a sticky directory" });
+            }
+
+            await breakLeases(target.ino, req.sessionID);
+            await breakLeases(source.ino, req.sessionID);
+            await breakLeases(parentIno, req.sessionID);
+            await breakLeases(sourceParentInode, req.sessionID); // il sorgente sparisce dalla sua directory
+            const pathObj = await renameEntry(source, sourcePath, targetPath, target);
+
+            const stats = await fs.lstat(toFsPath(targetPath), { bigint: true });
+            console.log("[replaceContent] status 200: Content replaced");
+            return res.status(200).json(await toEntryJson(source, stats, pathObj));
+        }catch(err:any){
+            if (err?.code === "ENOENT") {
+                console.log("[replaceContent] status 404: Source or target missing on disk");
+                return res.status(404).json({ error: "ENOENT", message: "Source or target missing on disk" });
+            }
+            console.log("[replaceContent] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: "Not possible to replace the content", details: String(err?.message ?? err) });
+        }
+    }
+
     public hardlink = async (req: Request, res: Response) => {
         console.log("[hardlink] called with targetIno:", req.params.targetIno, "linkParentIno:", req.body?.linkParentIno, "linkName:", req.body?.linkName, "user:", (req.user as User).uid);
         const targetIno = parseIno(req.params.targetIno);
@@ -423,6 +624,8 @@ export class FileController {
             const linkDbPath = childPathOf(dirLink.paths[0].path, linkName);
             const linkFsPath = toFsPath(linkDbPath);
 
+            await breakLeases(target.ino, req.sessionID); // cambia nlinks
+            await breakLeases(dirLinkIno, req.sessionID);
             await fs.link(targetFsPath, linkFsPath);
             const stats = await fs.lstat(linkFsPath,{bigint:true});
 
@@ -433,7 +636,7 @@ export class FileController {
             await pathRepo.save(linkPathObj);
 
             console.log("[hardlink] status 200: Hard link created");
-            return res.status(200).json(toEntryJson(target, stats, linkPathObj));
+            return res.status(200).json(await toEntryJson(target, stats, linkPathObj));
 
         }
         catch(err:any){
@@ -506,8 +709,9 @@ export class FileController {
             
             const linkStats = await fs.lstat(linkFsPath,{bigint:true});
 
+            await breakLeases(dirLinkIno, req.sessionID);
             console.log("[symlink] status 200: Symlink created");
-            return res.status(200).json(toEntryJson(link, linkStats, linkPathObj));
+            return res.status(200).json(await toEntryJson(link, linkStats, linkPathObj));
 
         }
         catch(err:any){
@@ -0,0 +1,243 @@
+import { NextFunction, Request, Response } from 'express';
+import { fileRepo, has_permissions, parseIno } from '../utilities';
+import { File } from '../entities/File';
+import { User } from '../entities/User';
+
+// durata di un lease: il client lo rinnova richiedendolo di nuovo prima della scadenza
+const LEASE_TTL_MS = 60 * 1000;
+// tempo massimo di attesa della long-poll sui recall
+const RECALL_POLL_MS = 25 * 1000;
+// una sessione che non chiede i recall da così tanto non li riceverà: i suoi lease non vanno aspettati
+const POLL_GONE_MS = 2 * RECALL_POLL_MS;
+// ogni quanto eliminare lease scaduti e recall di sessioni sparite
+const SWEEP_INTERVAL_MS = 60 * 1000;
+
+type LeaseType = "read" | "write";
+
+interface Lease {
+    type: LeaseType;
+    expires: number;
+    // richiamato: resta valido (e blocca le modifiche) finché il client non lo rilascia o scade
+    recalled: boolean;
+}
+
+// ino -> (session id -> lease), solo in memoria: al riavvio del server i client ripartono senza lease
+const leases = new Map<string, Map<string, Lease>>();
+// session id -> ino richiamati non ancora consegnati al client
+const pendingRecalls = new Map<string, Set<string>>();
+// session id -> long-poll in attesa
+const waiters = new Map<string, () => void>();
+// session id -> ultima long-poll ricevuta
+const lastPoll = new Map<string, number>();
+// ino -> modifiche in attesa che i lease richiamati vengano rilasciati
+const releaseWaiters = new Map<string, Set<() => void>>();
+
+function holdersOf(ino: string): Map<string, Lease> {
+    let holders = leases.get(ino);
+    if (!holders) {
+        holders = new Map();
+        leases.set(ino, holders);
+    }
+    const now = Date.now();
+    for (const [sid, lease] of holders) {
+        if (lease.expires <= now)
+            holders.delete(sid);
+    }
+    return holders;
+}
+
+// la sessione sta ascoltando i recall (long-poll in corso o appena conclusa)
+export function listening(sid: string): boolean {
+    return waiters.has(sid) || (lastPoll.get(sid) ?? 0) > Date.now() - POLL_GONE_MS;
+}
+
+function recall(ino: string, sid: string) {
+    const lease = leases.get(ino)?.get(sid);
+    if (lease)
+        lease.recalled = true;
+    let queue = pendingRecalls.get(sid);
+    if (!queue) {
+        queue = new Set();
+        pendingRecalls.set(sid, queue);
+    }
+    queue.add(ino);
+    waiters.get(sid)?.();
+}
+
+function dropLease(ino: string, sid: string) {
+    const holders = leases.get(ino);
+    if (!holders?.delete(sid))
+        return;
+    if (holders.size === 0)
+        leases.delete(ino);
+    const waiting = releaseWaiters.get(ino);
+    releaseWaiters.delete(ino);
+    waiting?.forEach(wake => wake());
+}
+
+// da chiamare prima di ogni modifica di un file: richiama i lease degli altri client e aspetta che li
+// rilascino (o che scadano), così nessuno usa la cache mentre il file cambia
+export async function breakLeases(ino: string, sessionID: string | undefined) {
+    const others = () => [...holdersOf(ino).entries()].filter(([sid]) => sid !== sessionID);
+    for (const [sid, lease] of others()) {
+        if (!listening(sid)) {
+            // nessuno riceverà il recall: il lease è già perso
+            dropLease(ino, sid);
+        } else if (!lease.recalled) {
+            console.log("[lease] recalling lease on", ino, "from session", sid);
+            recall(ino, sid);
+        }
+    }
+    let pending = others();
+    while (pending.length > 0) {
+        const wait = Math.max(0, Math.min(...pending.map(([, lease]) => lease.expires)) - Date.now());
+        await new Promise<void>(resolve => {
+            const timer = setTimeout(done, wait);
+            function done() {
+                clearTimeout(timer);
+                releaseWaiters.get(ino)?.delete(done);
+                resolve();
+            }
+            let waiting = releaseWaiters.get(ino);
+            if (!waiting) {
+                waiting = new Set();
+                releaseWaiters.set(ino, waiting);
+            }
+            waiting.add(done);
+        });
+        pending = others();
+    }
+    if (leases.get(ino)?.size === 0)
+        leases.delete(ino);
+}
+
+// la sessione è finita (logout, nuovo login): i suoi lease non valgono più
+export function dropSession(sid: string | undefined) {
+    if (!sid)
+        return;
+    for (const ino of [...leases.keys()])
+        dropLease(ino, sid);
+    pendingRecalls.delete(sid);
+    lastPoll.delete(sid);
+    waiters.get(sid)?.();
+}
+
+// middleware per il login: la sessione con cui arriva la richiesta sta per essere sostituita
+export function dropSessionLeases(req: Request, _res: Response, next: NextFunction) {
+    dropSession(req.sessionID);
+    next();
+}
+
+// lease scaduti e recall che nessuno verrà più a prendere
+function sweep() {
+    for (const ino of [...leases.keys()]) {
+        const holders = holdersOf(ino);
+        if (holders.size === 0)
+            leases.delete(ino);
+    }
+    for (const sid of [...pendingRecalls.keys()]) {
+        if (!listening(sid)) {
+            pendingRecalls.delete(sid);
+            lastPoll.delete(sid);
+        }
+    }
+    for (const [sid, at] of lastPoll) {
+        if (at <= Date.now() - POLL_GONE_MS && !waiters.has(sid))
+            lastPoll.delete(sid);
+    }
+}
+
+export function startLeaseSweeper() {
+    setInterval(sweep, SWEEP_INTERVAL_MS).unref();
+}
+
+export class LeaseController {
+    public acquire = async (req: Request, res: Response) => {
+        console.log("[acquireLease] called with ino:", req.params.ino, "type:", req.body?.type, "user:", (req.user as User).uid);
+        const ino = parseIno(req.params.ino);
+        const type = req.body?.type;
+        if (!ino) {
+            console.log("[acquireLease] status 400: Invalid inode");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid inode" });
+        }
+        if (type !== "read" && type !== "write") {
+            console.log("[acquireLease] status 400: Invalid lease type");
+            return res.status(400).json({ error: "EINVAL", message: "Lease type must be read or write" });
+        }
+
+        try {
+            const file = await fileRepo.findOne({ where: { ino }, relations: ["owner", "group", "paths"] }) as File | null;
+            if (!file) {
+                console.log("[acquireLease] status 404: File not found");
+                return res.status(404).json({ error: "ENOENT", message: "File not found" });
+            }
+            if (!has_permissions(file, type === "read" ? 0 : 1, req.user as User)) {
+                console.log("[acquireLease] status 403: No permission");
+                return res.status(403).json({ error: "EACCES", message: `No permission on ${ino}` });
+            }
+
+            const holders = holdersOf(ino);
+            const others = [...holders.entries()].filter(([sid]) => sid !== req.sessionID);
+            const conflict = others.some(([, lease]) => type === "write" || lease.type === "write");
+            if (conflict || holders.get(req.sessionID)?.recalled) {
+                // richiama i lease in conflitto: il client riproverà dopo
+                for (const [sid, lease] of others) {
+                    if (!lease.recalled)
+                        recall(ino, sid);
+                }
+                console.log("[acquireLease] status 409: Conflicting lease, recall started");
+                return res.status(409).json({ error: "EAGAIN", message: `Conflicting lease on ${ino}, retry later` });
+            }
+
+            holders.set(req.sessionID, { type, expires: Date.now() + LEASE_TTL_MS, recalled: false });
+            console.log("[acquireLease] status 200: Lease granted");
+            return res.status(200).json({ ino, type, ttl: LEASE_TTL_MS });
+        } catch (err: any) {
+            console.log("[acquireLease] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: "Not possible to grant the lease", details: String(err?.message ?? err) });
+        }
+    }
+
+    public release = async (req: Request, res: Response) => {
+        console.log("[releaseLease] called with ino:", req.params.ino, "user:", (req.user as User).uid);
+        const ino = parseIno(req.params.ino);
+        if (!ino) {
+            console.log("[releaseLease] status 400: Invalid inode");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid inode" });
+        }
+        dropLease(ino, req.sessionID);
+        console.log("[releaseLease] status 200: Lease released");
+        return res.status(200).end();
+    }
+
+    // long-poll: risponde appena c'è almeno un recall per la sessione, oppure vuota allo scadere
+    public recalls = async (req: Request, res: Response) => {
+        const sid = req.sessionID;
+
+        const flush = () => {
+            waiters.delete(sid);
+            lastPoll.set(sid, Date.now());
+            const queue = pendingRecalls.get(sid);
+            pendingRecalls.delete(sid);
+            const recalled = queue ? [...queue] : [];
+            if (recalled.length > 0)
+                console.log("[recalls] status 200: delivering", recalled.length, "recall(s)");
+            return res.status(200).json({ recalled });
+        };
+
+        if (pendingRecalls.get(sid)?.size)
+            return flush();
+
+        const timer = setTimeout(flush, RECALL_POLL_MS);
+        const waiter = () => {
+            clearTimeout(timer);
+            flush();
+        };
+        waiters.set(sid, waiter);
+        res.on('close', () => {
+            clearTimeout(timer);
+            if (waiters.get(sid) === waiter)
+                waiters.delete(sid);
+        });
+    }
+}
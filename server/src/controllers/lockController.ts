@@ -0,0 +1,160 @@
+import { Request, Response } from 'express';
+import { fileRepo, has_permissions, parseIno } from '../utilities';
+import { File } from '../entities/File';
+import { User } from '../entities/User';
+import { listening } from './leaseController';
+
+// Lock su intervalli di byte (fcntl F_SETLK/F_GETLK) condivisi fra tutti i client: un client li chiede
+// prima di prenderli localmente, così un lock preso su una macchina blocca gli altri mount.
+// Sono advisory come su POSIX: le write normali non li controllano. Le richieste con l'header
+// X-Mandatory-Locks (i mount Windows, dove i lock sono obbligatori) vengono invece rifiutate con 423
+// se scrivono su un intervallo bloccato da un'altra sessione.
+export const MANDATORY_LOCKS_HEADER = 'x-mandatory-locks';
+
+type LockType = "read" | "write";
+
+interface ByteLock {
+    sid: string;
+    // lock owner del client: u64, tenuto come stringa perché supera 2^53
+    owner: string;
+    type: LockType;
+    start: number;
+    // incluso; Infinity = fino alla fine del file, anche se cresce
+    end: number;
+    pid: number;
+}
+
+// ino -> lock, solo in memoria: al riavvio del server nessun lock è più valido, come dopo un crash
+const locks = new Map<string, ByteLock[]>();
+
+// lock dell'ino ancora validi: quelli delle sessioni che non ascoltano più i recall (client spento,
+// logout) vengono lasciati cadere, altrimenti un client sparito bloccherebbe il file per sempre
+function locksOf(ino: string): ByteLock[] {
+    const held = (locks.get(ino) ?? []).filter(lock => listening(lock.sid));
+    if (held.length > 0)
+        locks.set(ino, held);
+    else
+        locks.delete(ino);
+    return held;
+}
+
+function overlaps(lock: ByteLock, start: number, end: number): boolean {
+    return lock.start <= end && start <= lock.end;
+}
+
+function conflictOf(ino: string, sid: string, owner: string | null, type: LockType, start: number, end: number): ByteLock | undefined {
+    return locksOf(ino).find(lock =>
+        !(lock.sid === sid && (owner === null || lock.owner === owner))
+        && overlaps(lock, start, end)
+        && (type === "write" || lock.type === "write"));
+}
+
+// un lock (o un unlock) dello stesso owner sostituisce i suoi lock sull'intervallo, spezzandoli se serve
+function setLock(ino: string, sid: string, owner: string, type: LockType | "unlock", start: number, end: number, pid: number) {
+    const next: ByteLock[] = [];
+    for (const lock of locksOf(ino)) {
+        if (lock.sid !== sid || lock.owner !== owner || !overlaps(lock, start, end)) {
+            next.push(lock);
+            continue;
+        }
+        if (lock.start < start)
+            next.push({ ...lock, end: start - 1 });
+        if (lock.end > end)
+            next.push({ ...lock, start: end + 1 });
+    }
+    if (type !== "unlock")
+        next.push({ sid, owner, type, start, end, pid });
+    if (next.length > 0)
+        locks.set(ino, next);
+    else
+        locks.delete(ino);
+}
+
+// una write da `offset` per `length` byte va rifiutata: solo per le richieste con X-Mandatory-Locks
+export function mandatoryConflict(req: Request, ino: string, offset: number, length: number): boolean {
+    if (!req.header(MANDATORY_LOCKS_HEADER) || length <= 0)
+        return false;
+    return conflictOf(ino, req.sessionID, null, "write", offset, offset + length - 1) !== undefined;
+}
+
+// start e end (incluso, null = fine del file) della query o del corpo
+function parseRange(start: any, end: any): [number, number] | null {
+    const from = Number(start ?? 0);
+    const to = end === undefined || end === null || end === "" ? Infinity : Number(end);
+    if (!Number.isSafeInteger(from) || from < 0 || !(Number.isSafeInteger(to) || to === Infinity) || to < from)
+        return null;
+    return [from, to];
+}
+
+function describe(lock: ByteLock) {
+    return { type: lock.type, start: lock.start, end: lock.end === Infinity ? null : lock.end, pid: lock.pid };
+}
+
+export class LockController {
+    // F_GETLK: il primo lock di un altro owner che impedirebbe quello descritto, o null
+    public test = async (req: Request, res: Response) => {
+        console.log("[testLock] called with ino:", req.params.ino, "type:", req.query.type, "start:", req.query.start, "end:", req.query.end, "user:", (req.user as User).uid);
+        const ino = parseIno(req.params.ino);
+        const type = req.query.type;
+        const range = parseRange(req.query.start, req.query.end);
+        const owner = typeof req.query.owner === "string" ? req.query.owner : null;
+        if (!ino) {
+            console.log("[testLock] status 400: Invalid inode");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid inode" });
+        }
+        if ((type !== "read" && type !== "write") || range === null || owner === null) {
+            console.log("[testLock] status 400: Invalid lock");
+            return res.status(400).json({ error: "EINVAL", message: "Lock needs type read or write, a valid range and an owner" });
+        }
+        const conflict = conflictOf(ino, req.sessionID, owner, type, range[0], range[1]);
+        console.log("[testLock] status 200:", conflict ? "conflicting lock found" : "no conflict");
+        return res.status(200).json({ conflict: conflict ? describe(conflict) : null });
+    }
+
+    // F_SETLK: prende, cambia o rilascia (type "unlock") un lock; 423 se un altro owner lo impedisce
+    public set = async (req: Request, res: Response) => {
+        console.log("[setLock] called with ino:", req.params.ino, "type:", req.body?.type, "start:", req.body?.start, "end:", req.body?.end, "user:", (req.user as User).uid);
+        const ino = parseIno(req.params.ino);
+        const type = req.body?.type;
+        const range = parseRange(req.body?.start, req.body?.end);
+        const owner = req.body?.owner;
+        const pid = Number(req.body?.pid) || 0;
+        if (!ino) {
+            console.log("[setLock] status 400: Invalid inode");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid inode" });
+        }
+        if ((type !== "read" && type !== "write" && type !== "unlock") || range === null || typeof owner !== "string") {
+            console.log("[setLock] status 400: Invalid lock");
+            return res.status(400).json({ error: "EINVAL", message: "Lock needs type read, write or unlock, a valid range and an owner" });
+        }
+        const [start, end] = range;
+
+        if (type === "unlock") {
+            setLock(ino, req.sessionID, owner, type, start, end, pid);
+            console.log("[setLock] status 200: Unlocked");
+            return res.status(200).json({});
+        }
+        try {
+            const file = await fileRepo.findOne({ where: { ino }, relations: ["owner", "group", "paths"] }) as File | null;
+            if (!file) {
+                console.log("[setLock] status 404: File not found");
+                return res.status(404).json({ error: "ENOENT", message: "File not found" });
+            }
+            // come fcntl: un lock in lettura richiede di poter leggere, uno in scrittura di poter scrivere
+            if (!has_permissions(file, type === "read" ? 0 : 1, req.user as User)) {
+                console.log("[setLock] status 403: No permission");
+                return res.status(403).json({ error: "EACCES", message: `No permission on ${ino}` });
+            }
+            if (conflictOf(ino, req.sessionID, owner, type, start, end)) {
+                console.log("[setLock] status 423: Conflicting lock");
+                return res.status(423).json({ error: "EAGAIN", message: `${ino} is locked by another client` });
+            }
+            setLock(ino, req.sessionID, owner, type, start, end, pid);
+            console.log("[setLock] status 200: Lock granted");
+            return res.status(200).json({});
+        } catch (err: any) {
+            console.log("[setLock] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: "Not possible to set the lock", details: String(err?.message ?? err) });
+        }
+    }
+}
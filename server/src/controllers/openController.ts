@@ -0,0 +1,135 @@
+import { Request, Response } from 'express';
+import { promises as fs } from 'fs';
+import { fileRepo, pathRepo, has_permissions, parseIno, toFsPath, ORPHANS_DIR } from '../utilities';
+import { File } from '../entities/File';
+import { User } from '../entities/User';
+
+// un file cancellato mentre qualche client lo tiene aperto (unlink di POSIX) non sparisce subito: il
+// contenuto viene spostato in ORPHANS_DIR, fuori da ogni directory visibile, e resta leggibile e
+// scrivibile per ino finché l'ultimo client non lo chiude
+
+// durata di una registrazione: il client la rinnova finché il file resta aperto
+const OPEN_TTL_MS = 5 * 60 * 1000;
+// ogni quanto cercare orfani senza più client che li tengono aperti
+const REAP_INTERVAL_MS = 60 * 1000;
+
+// ino -> (session id -> scadenza), solo in memoria: al riavvio gli orfani vengono eliminati
+const opens = new Map<string, Map<string, number>>();
+
+function holdersOf(ino: string): Map<string, number> | undefined {
+    const holders = opens.get(ino);
+    if (!holders)
+        return undefined;
+    const now = Date.now();
+    for (const [sid, expires] of holders) {
+        if (expires <= now)
+            holders.delete(sid);
+    }
+    if (holders.size === 0) {
+        opens.delete(ino);
+        return undefined;
+    }
+    return holders;
+}
+
+// qualche client (compreso chi lo sta cancellando) ha ancora l'ino aperto
+export function isOpen(ino: string): boolean {
+    return holdersOf(ino) !== undefined;
+}
+
+export function orphanPathOf(ino: string): string {
+    return `${ORPHANS_DIR}/${ino}`;
+}
+
+// sposta l'ultimo nome di `file` tra gli orfani invece di cancellarlo
+export async function orphan(file: File, dbPath: string) {
+    const orphanPath = orphanPathOf(file.ino);
+    await fs.mkdir(toFsPath(ORPHANS_DIR), { recursive: true });
+    await fs.rename(toFsPath(dbPath), toFsPath(orphanPath));
+    await pathRepo.update({ path: dbPath }, { path: orphanPath });
+    console.log("[orphan]", dbPath, "kept as", orphanPath, "until closed");
+}
+
+// elimina l'orfano `ino`, se nessuno lo tiene più aperto
+async function reap(ino: string) {
+    if (isOpen(ino))
+        return;
+    const orphanPath = orphanPathOf(ino);
+    const pathObj = await pathRepo.findOne({ where: { path: orphanPath }, relations: ["file"] });
+    if (!pathObj)
+        return;
+    await fs.rm(toFsPath(orphanPath), { force: true });
+    await pathRepo.remove(pathObj);
+    if (pathObj.file && await pathRepo.count({ where: { file: { ino } } }) === 0)
+        await fileRepo.remove(pathObj.file);
+    console.log("[orphan] removed", orphanPath);
+}
+
+async function reapAll() {
+    const orphans = await pathRepo.createQueryBuilder("p")
+        .leftJoinAndSelect("p.file", "file")
+        .where("p.path LIKE :prefix", { prefix: `${ORPHANS_DIR}/%` })
+        .getMany();
+    for (const pathObj of orphans) {
+        if (pathObj.file)
+            await reap(pathObj.file.ino).catch(err => console.log("[orphan] cleanup of", pathObj.path, "failed:", err?.message ?? err));
+    }
+}
+
+// all'avvio nessun client ha più file aperti: gli orfani rimasti vanno eliminati
+export function startOrphanReaper() {
+    reapAll().catch(err => console.log("[orphan] cleanup failed:", err?.message ?? err));
+    setInterval(() => {
+        reapAll().catch(err => console.log("[orphan] cleanup failed:", err?.message ?? err));
+    }, REAP_INTERVAL_MS).unref();
+}
+
+export class OpenController {
+    // registra (o rinnova) un handle aperto della sessione sull'ino
+    public open = async (req: Request, res: Response) => {
+        const ino = parseIno(req.params.ino);
+        if (!ino) {
+            console.log("[open] status 400: Invalid inode");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid inode" });
+        }
+        try {
+            const file = await fileRepo.findOne({ where: { ino }, relations: ["owner", "group", "paths"] }) as File | null;
+            if (!file) {
+                console.log("[open] status 404: File not found");
+                return res.status(404).json({ error: "ENOENT", message: "File not found" });
+            }
+            const user = req.user as User;
+            if (!has_permissions(file, 0, user) && !has_permissions(file, 1, user)) {
+                console.log("[open] status 403: No permission");
+                return res.status(403).json({ error: "EACCES", message: `No permission on ${ino}` });
+            }
+            let holders = holdersOf(ino);
+            if (!holders) {
+                holders = new Map();
+                opens.set(ino, holders);
+            }
+            holders.set(req.sessionID, Date.now() + OPEN_TTL_MS);
+            return res.status(200).json({ ino, ttl: OPEN_TTL_MS });
+        } catch (err: any) {
+            console.log("[open] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: "Not possible to register the open file", details: String(err?.message ?? err) });
+        }
+    }
+
+    // l'ultimo handle della sessione è stato chiuso: un orfano senza altri client viene eliminato
+    public close = async (req: Request, res: Response) => {
+        const ino = parseIno(req.params.ino);
+        if (!ino) {
+            console.log("[close] status 400: Invalid inode");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid inode" });
+        }
+        opens.get(ino)?.delete(req.sessionID);
+        try {
+            await reap(ino);
+            return res.status(200).end();
+        } catch (err: any) {
+            console.log("[close] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: "Not possible to remove the deleted file", details: String(err?.message ?? err) });
+        }
+    }
+}
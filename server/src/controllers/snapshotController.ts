@@ -0,0 +1,342 @@
+import { Request, Response } from 'express';
+import { has_permissions, parseIno, childPathOf, pathRepo, etagOf, streamRange, MAX_DIR_PAGE, wildcardToRegExp } from '../utilities';
+import { File } from '../entities/File';
+import { User } from '../entities/User';
+import { Path } from '../entities/Path';
+import * as fs from 'node:fs/promises';
+import { createReadStream, BigIntStats } from 'node:fs';
+import path from 'node:path';
+
+// Viste in sola lettura del filesystem a un istante passato. Le copie vengono prodotte fuori dal server
+// (snapshot ZFS/btrfs, `cp -a`, rsync...) in SNAPSHOT_ROOT, una sottocartella per copia chiamata con
+// l'istante in cui è stata presa (secondi Unix). Una richiesta con l'header X-Snapshot (millisecondi Unix)
+// viene servita dalla copia più recente presa entro quell'istante; le richieste che modificano vengono rifiutate.
+
+export const SNAPSHOT_HEADER = 'x-snapshot';
+
+// owner e permessi vengono dal DB attuale: una voce che non c'è più (o che ora è un file di altro tipo)
+// non è accessibile, per non prestarle i permessi di un antenato o del file che ne ha preso il posto
+type SnapshotEntry = { dbPath: string, stats: BigIntStats, meta: File };
+
+class Snapshot {
+    private byIno: Map<string, string> | null = null; // ino -> path, costruita alla prima richiesta
+
+    constructor(public readonly root: string, public readonly takenAt: number) {}
+
+    fsPath(dbPath: string): string {
+        return path.join(this.root, dbPath);
+    }
+
+    async pathOf(ino: string): Promise<string | undefined> {
+        if (ino === '1') return '/';
+        if (!this.byIno) {
+            // la copia non cambia più: l'indice si costruisce una volta sola
+            const index = new Map<string, string>();
+            const walk = async (dbPath: string) => {
+                for (const name of await fs.readdir(this.fsPath(dbPath))) {
+                    const child = childPathOf(dbPath, name);
+                    const stats = await fs.lstat(this.fsPath(child), { bigint: true });
+                    if (!index.has(stats.ino.toString())) index.set(stats.ino.toString(), child);
+                    if (stats.isDirectory()) await walk(child);
+                }
+            };
+            await walk('/');
+            this.byIno = index;
+        }
+        return this.byIno.get(ino);
+    }
+}
+
+function snapshotRoot(): string {
+    return path.resolve(process.env.SNAPSHOT_ROOT || './snapshots');
+}
+
+// copia più recente presa entro `at` (millisecondi)
+async function findSnapshot(at: number): Promise<Snapshot | null> {
+    let best: number | null = null;
+    for (const name of await fs.readdir(snapshotRoot()).catch(() => [] as string[])) {
+        if (!/^\d+$/.test(name)) continue;
+        const takenAt = Number(name) * 1000;
+        if (takenAt <= at && (best === null || takenAt > best)) best = takenAt;
+    }
+    if (best === null) return null;
+    const cached = snapshots.get(best);
+    if (cached) return cached;
+    const snapshot = new Snapshot(path.join(snapshotRoot(), String(best / 1000)), best);
+    snapshots.set(best, snapshot);
+    return snapshot;
+}
+
+const snapshots = new Map<number, Snapshot>();
+
+function typeOf(stats: BigIntStats): number {
+    return stats.isDirectory() ? 1 : stats.isSymbolicLink() ? 2 : 0;
+}
+
+export class SnapshotController {
+
+    // risolve la copia richiesta dall'header; risponde direttamente con l'errore se non c'è
+    private snapshotOf = async (req: Request, res: Response, op: string): Promise<Snapshot | null> => {
+        const at = Number(req.header(SNAPSHOT_HEADER));
+        if (!Number.isFinite(at) || at < 0) {
+            console.log(`[${op}] status 400: Invalid snapshot time`);
+            res.status(400).json({ error: "EINVAL", message: "Invalid snapshot time" });
+            return null;
+        }
+        const snapshot = await findSnapshot(at);
+        if (!snapshot) {
+            console.log(`[${op}] status 404: No snapshot before`, new Date(at).toISOString());
+            res.status(404).json({ error: "ENOENT", message: `No snapshot taken before ${new Date(at).toISOString()}` });
+            return null;
+        }
+        res.setHeader('X-Snapshot-Taken', String(snapshot.takenAt));
+        return snapshot;
+    }
+
+    private entryAt = async (snapshot: Snapshot, dbPath: string): Promise<SnapshotEntry | null> => {
+        let stats: BigIntStats;
+        try {
+            stats = await fs.lstat(snapshot.fsPath(dbPath), { bigint: true });
+        } catch (e: any) {
+            if (e?.code === "ENOENT") return null;
+            throw e;
+        }
+        const pathObj = await pathRepo.findOne({ where: { path: dbPath }, relations: ["file", "file.owner", "file.group"] }) as Path | null;
+        if (!pathObj?.file || pathObj.file.type !== typeOf(stats)) return null;
+        return { dbPath, stats, meta: pathObj.file };
+    }
+
+    private entryJson(ino: string, entry: SnapshotEntry) {
+        const { stats, meta, dbPath } = entry;
+        return {
+            ino,
+            name: path.posix.basename(dbPath),
+            path: dbPath,
+            type: typeOf(stats),
+            permissions: meta.permissions,
+            owner: meta.owner.uid,
+            group: meta.group?.gid ?? null,
+            size: stats.size.toString(),
+            atime: stats.atime.getTime(),
+            mtime: stats.mtime.getTime(),
+            ctime: stats.ctime.getTime(),
+            btime: stats.birthtime.getTime(),
+
+            nlinks: Number(stats.nlink),
+            etag: etagOf(stats),
+            flags: meta.flags ?? 0,
+            fileFlags: meta.fileFlags ?? 0,
+        };
+    }
+
+    private inoOf(entry: SnapshotEntry): string {
+        return entry.dbPath === '/' ? '1' : entry.stats.ino.toString();
+    }
+
+    // voce della copia per ino, già controllata per il permesso di lettura
+    private readable = async (req: Request, res: Response, op: string): Promise<{ snapshot: Snapshot, entry: SnapshotEntry } | null> => {
+        const ino = parseIno(req.params.ino);
+        if (!ino) {
+            console.log(`[${op}] status 400: Invalid inode`);
+            res.status(400).json({ error: "EINVAL", message: "Invalid inode" });
+            return null;
+        }
+        const snapshot = await this.snapshotOf(req, res, op);
+        if (!snapshot) return null;
+        const dbPath = await snapshot.pathOf(ino);
+        const entry = dbPath === undefined ? null : await this.entryAt(snapshot, dbPath);
+        if (!entry) {
+            console.log(`[${op}] status 404: File not found in snapshot`);
+            res.status(404).json({ error: "ENOENT", message: `File ${ino} not found in the snapshot` });
+            return null;
+        }
+        if (!has_permissions(entry.meta, 0, req.user as User)) {
+            console.log(`[${op}] status 403: No permission`);
+            res.status(403).json({ error: "EACCES", message: `You have not the permission to read ${ino}` });
+            return null;
+        }
+        return { snapshot, entry };
+    }
+
+    public getattr = async (req: Request, res: Response) => {
+        console.log("[snapshot getattr] called with ino:", req.params.ino, "at:", req.header(SNAPSHOT_HEADER), "user:", (req.user as User).uid);
+        try {
+            const found = await this.readable(req, res, "snapshot getattr");
+            if (!found) return;
+            const { entry } = found;
+            // la copia non cambia: basta confrontare con la data già nota al client
+            const since = Date.parse(req.header('if-modified-since') ?? '');
+            if (!Number.isNaN(since) && Math.floor(entry.stats.mtime.getTime() / 1000) <= Math.floor(since / 1000)) {
+                console.log("[snapshot getattr] status 304: Not Modified");
+                return res.status(304).end();
+            }
+            res.setHeader('ETag', etagOf(entry.stats));
+            console.log("[snapshot getattr] status 200: returning entry");
+            return res.status(200).json(this.entryJson(this.inoOf(entry), entry));
+        } catch (err: any) {
+            console.log("[snapshot getattr] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: "Not possible to read the snapshot", details: String(err?.message ?? err) });
+        }
+    }
+
+    public lookup = async (req: Request, res: Response) => {
+        console.log("[snapshot lookup] called with parentIno:", req.params.parentIno, "name:", req.query.name, "at:", req.header(SNAPSHOT_HEADER));
+        req.params.ino = req.params.parentIno;
+        const name = req.query.name ? String(req.query.name) : '';
+        try {
+            const found = await this.readable(req, res, "snapshot lookup");
+            if (!found) return;
+            const { snapshot, entry: parent } = found;
+            if (!parent.stats.isDirectory()) {
+                console.log("[snapshot lookup] status 400: Parent is not a directory");
+                return res.status(400).json({ error: "ENOTDIR", message: "Parent is not a directory" });
+            }
+            const child = name === '' || name.includes('/') ? null : await this.entryAt(snapshot, childPathOf(parent.dbPath, name));
+            if (!child) {
+                console.log("[snapshot lookup] status 404: File not found in parent");
+                return res.status(404).json({ error: "ENOENT", message: `File ${name} not found in ${parent.dbPath}` });
+            }
+            console.log("[snapshot lookup] status 200: returning entry");
+            return res.status(200).json(this.entryJson(this.inoOf(child), child));
+        } catch (err: any) {
+            console.log("[snapshot lookup] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: "Lookup failed", details: String(err?.message ?? err) });
+        }
+    }
+
+    public readdir = async (req: Request, res: Response) => {
+        console.log("[snapshot readdir] called with ino:", req.params.ino, "cursor:", req.query.cursor, "limit:", req.query.limit, "at:", req.header(SNAPSHOT_HEADER));
+        const paged = req.query.limit !== undefined;
+        const limit = Number(req.query.limit);
+        const cursor = typeof req.query.cursor === "string" ? req.query.cursor : undefined;
+        const pattern = typeof req.query.pattern === "string" ? wildcardToRegExp(req.query.pattern) : undefined;
+        if (paged && (!Number.isInteger(limit) || limit <= 0)) {
+            console.log("[snapshot readdir] status 400: Invalid limit");
+            return res.status(400).json({ error: "EINVAL", message: "Invalid limit" });
+        }
+        try {
+            const found = await this.readable(req, res, "snapshot readdir");
+            if (!found) return;
+            const { snapshot, entry: dir } = found;
+            if (!dir.stats.isDirectory()) {
+                console.log("[snapshot readdir] status 400: Not a directory");
+                return res.status(400).json({ error: "ENOTDIR", message: `${req.params.ino} is not a directory` });
+            }
+            let names = (await fs.readdir(snapshot.fsPath(dir.dbPath))).sort();
+            if (pattern) names = names.filter(name => pattern.test(name));
+            let next: string | null = null;
+            if (paged) {
+                names = names.filter(name => cursor === undefined || name > cursor);
+                const pageSize = Math.min(limit, MAX_DIR_PAGE);
+                if (names.length > pageSize) {
+                    names = names.slice(0, pageSize);
+                    next = names[names.length - 1];
+                }
+            }
+            const content = [];
+            for (const name of names) {
+                const child = await this.entryAt(snapshot, childPathOf(dir.dbPath, name));
+                if (child && has_permissions(child.meta, 0, req.user as User)) content.push(this.entryJson(this.inoOf(child), child));
+            }
+            console.log("[snapshot readdir] status 200: returning", content.length, "entries");
+            return res.status(200).json(paged ? { entries: content, next } : content);
+        } catch (err: any) {
+            console.log("[snapshot readdir] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: "Not possible to read the folder", details: String(err?.message ?? err) });
+        }
+    }
+
+    public read = async (req: Request, res: Response) => {
+        console.log("[snapshot read] called with ino:", req.params.ino, "offset:", req.query.offset, "size:", req.query.size, "at:", req.header(SNAPSHOT_HEADER));
+        const offset = Number(req.query.offset) || 0;
+        const size = Math.min(Number(req.query.size) || 4096, 1024 * 1024);
+        if (offset < 0 || size <= 0) {
+            console.log("[snapshot read] status 400: Invalid offset or size");
+            return res.status(400).json({ error: 'Bad request: invalid offset or size' });
+        }
+        try {
+            const found = await this.readable(req, res, "snapshot read");
+            if (!found) return;
+            const fd = await fs.open(found.snapshot.fsPath(found.entry.dbPath), 'r');
+            try {
+                const buffer = Buffer.alloc(size);
+                const { bytesRead } = await fd.read(buffer, 0, size, offset);
+                console.log("[snapshot read] status 200: Read finished, bytesRead:", bytesRead);
+                res.status(200);
+                res.setHeader('Content-Type', 'application/octet-stream');
+                res.setHeader('Content-Length', String(bytesRead));
+                res.end(buffer.subarray(0, bytesRead));
+            } finally {
+                await fd.close();
+            }
+        } catch (err: any) {
+            console.log("[snapshot read] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: "Not possible to read the file", details: String(err?.message ?? err) });
+        }
+    }
+
+    public readStream = async (req: Request, res: Response) => {
+        console.log("[snapshot readStream] called with ino:", req.params.ino, "offset:", req.query.offset, "length:", req.query.length, "at:", req.header(SNAPSHOT_HEADER));
+        const range = streamRange(req.query);
+        if (range === null) {
+            console.log("[snapshot readStream] status 400: Invalid offset or length");
+            return res.status(400).setHeader('Content-Type', 'application/octet-stream').end();
+        }
+        try {
+            const found = await this.readable(req, res, "snapshot readStream");
+            if (!found) return;
+            const readStream = createReadStream(found.snapshot.fsPath(found.entry.dbPath), range);
+            readStream.on('error', (err) => {
+                console.error('[snapshot readStream] Stream error:', err);
+                if (!res.headersSent) {
+                    res.status(500).setHeader('Content-Type', 'application/octet-stream').end();
+                } else {
+                    res.destroy();
+                }
+            });
+            readStream.pipe(res);
+            console.log("[snapshot readStream] status 200: Streaming file");
+        } catch (err: any) {
+            console.log("[snapshot readStream] status 500:", err?.message ?? err);
+            res.status(500).setHeader('Content-Type', 'application/octet-stream').end();
+        }
+    }
+
+    public readlink = async (req: Request, res: Response) => {
+        console.log("[snapshot readlink] called with ino:", req.params.ino, "at:", req.header(SNAPSHOT_HEADER));
+        try {
+            const found = await this.readable(req, res, "snapshot readlink");
+            if (!found) return;
+            if (!found.entry.stats.isSymbolicLink()) {
+                console.log("[snapshot readlink] status 400: Not a symlink");
+                return res.status(400).json({ error: "EINVAL", message: "File is not a symlink" });
+            }
+            let target = await fs.readlink(found.snapshot.fsPath(found.entry.dbPath));
+            // i link assoluti puntano dentro la radice del filesystem: li riportiamo relativi alla radice remota
+            for (const root of [found.snapshot.root, path.resolve(process.env.FS_ROOT || './file-system')]) {
+                if (target.startsWith(root)) {
+                    target = target.slice(root.length);
+                    if (!target.startsWith('/')) target = '/' + target;
+                    break;
+                }
+            }
+            console.log("[snapshot readlink] status 200: Symlink target returned");
+            return res.status(200).json({ target });
+        } catch (err: any) {
+            console.log("[snapshot readlink] status 500:", err?.message ?? err);
+            return res.status(500).json({ error: "EIO", message: "Not possible to read the symlink", details: String(err?.message ?? err) });
+        }
+    }
+
+    // tutto il resto: login e informazioni sul volume passano alle route normali, le modifiche sono rifiutate
+    public fallback = (req: Request, res: Response, next: () => any) => {
+        const passthrough = ['/api/login', '/api/logout', '/api/me', '/api/size', '/api/limits', '/api/capabilities'];
+        if (passthrough.includes(req.path)) return next();
+        if (req.method !== 'GET') {
+            console.log("[snapshot] status 403: Read-only snapshot,", req.method, req.path);
+            return res.status(403).json({ error: "EROFS", message: "Snapshots are read-only" });
+        }
+        console.log("[snapshot] status 400: Not supported on snapshots,", req.path);
+        return res.status(400).json({ error: "ENOTSUP", message: `${req.path} is not available on snapshots` });
+    }
+}
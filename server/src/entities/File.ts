@@ -21,6 +21,12 @@ export class File {
   @Column({nullable:false})
   permissions: number;
 
+  @Column({nullable:false, default:0})
+  flags: number; // attributi DOS impostati dai client Windows (hidden, system)
+
+  @Column({nullable:false, default:0})
+  fileFlags: number; // flag chflags (immutable, append-only, nodump), fatti rispettare da setattr e dalle write
+
   @ManyToOne(() => Group, (group) => group.files, { nullable: true })
   @JoinColumn({ name: "group", referencedColumnName: "gid" })
   group: Group;
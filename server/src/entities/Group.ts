@@ -1,4 +1,4 @@
-import { Entity, JoinTable, OneToMany, PrimaryColumn } from "typeorm";
+import { Column, Entity, JoinTable, OneToMany, PrimaryColumn } from "typeorm";
 import { User } from "./User";
 import { File } from "./File";
 
@@ -7,6 +7,10 @@ export class Group {
   @PrimaryColumn()
   gid: number;
 
+  // nome mostrato dai client al posto del gid, facoltativo
+  @Column({ type: "varchar", nullable: true })
+  name: string | null;
+
   @OneToMany(() => User, (user) => user.group)
   @JoinTable()
   users: User[];
@@ -13,6 +13,10 @@ export class User {
   @Column()
   salt: string;
 
+  // nome mostrato dai client al posto dell'uid (ls -l), facoltativo
+  @Column({ type: "varchar", nullable: true })
+  name: string | null;
+
   @OneToMany(() => File, (file) => file.owner)
   files: File[];
 
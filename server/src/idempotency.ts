@@ -0,0 +1,96 @@
+import { Request, Response, NextFunction } from 'express';
+import { createHash } from 'node:crypto';
+
+// Idempotency-Key delle richieste che modificano il filesystem: il client ripete la stessa chiave quando
+// ritenta una richiesta dopo un timeout, e se l'originale era già stata applicata riceve la risposta di
+// allora invece di un Conflict. Le risposte restano in memoria per IDEMPOTENCY_TTL, per utente, insieme
+// all'impronta della richiesta: una chiave riusata per una richiesta diversa riceve 422, non la risposta
+// dell'altra.
+const IDEMPOTENCY_TTL = 10 * 60 * 1000;
+const MAX_ENTRIES = 10000;
+
+interface StoredResponse {
+    status: number;
+    contentType?: string;
+    etag?: string;
+    body?: Buffer;
+}
+
+interface Entry {
+    expires: number;
+    fingerprint: string;
+    // risolta alla fine della richiesta originale; undefined se la risposta non è stata conservata
+    done: Promise<StoredResponse | undefined>;
+}
+
+const entries = new Map<string, Entry>();
+
+function purge(now: number) {
+    for (const [id, entry] of entries) {
+        if (entry.expires <= now || entries.size > MAX_ENTRIES) entries.delete(id);
+    }
+}
+
+// metodo, path con la query e body. I body binari non sono ancora letti a questo punto (li legge la rotta):
+// di quelli entrano tipo e lunghezza
+function fingerprint(req: Request): string {
+    const hash = createHash('sha256');
+    hash.update(`${req.method} ${req.originalUrl}\n${req.get('Content-Type') ?? ''} ${req.get('Content-Length') ?? ''}\n`);
+    if (req.body !== undefined && !Buffer.isBuffer(req.body)) hash.update(JSON.stringify(req.body));
+    return hash.digest('hex');
+}
+
+function replay(res: Response, stored: StoredResponse) {
+    res.status(stored.status);
+    if (stored.contentType) res.setHeader('Content-Type', stored.contentType);
+    if (stored.etag) res.setHeader('ETag', stored.etag);
+    res.setHeader('Idempotent-Replayed', 'true');
+    res.end(stored.body);
+}
+
+export function idempotency(req: Request, res: Response, next: NextFunction) {
+    const key = req.get('Idempotency-Key');
+    const user = req.user as { uid?: number } | undefined;
+    if (req.method === 'GET' || req.method === 'HEAD' || !key || !/^[\w.-]{1,64}$/.test(key) || user?.uid === undefined) {
+        return next();
+    }
+    const id = `${user.uid}:${key}`;
+    const print = fingerprint(req);
+    const now = Date.now();
+    const previous = entries.get(id);
+    if (previous && previous.expires > now && previous.fingerprint !== print) {
+        console.log("[idempotency] status 422: Idempotency-Key reused for a different request");
+        return res.status(422).json({ error: "EINVAL", message: "Idempotency-Key already used for a different request" });
+    }
+    if (previous && previous.expires > now) {
+        // la richiesta originale può essere ancora in corso: si aspetta il suo esito
+        previous.done.then(stored => stored ? replay(res, stored) : next(), next);
+        return;
+    }
+    if (entries.size >= MAX_ENTRIES) purge(now);
+
+    let resolve!: (stored: StoredResponse | undefined) => void;
+    entries.set(id, { expires: now + IDEMPOTENCY_TTL, fingerprint: print, done: new Promise(r => resolve = r) });
+
+    let body: Buffer | undefined;
+    const send = res.send.bind(res);
+    res.send = (data?: any) => {
+        if (typeof data === 'string') body = Buffer.from(data);
+        else if (Buffer.isBuffer(data)) body = data;
+        return send(data);
+    };
+    res.on('close', () => {
+        // 401 e errori del server non sono esiti definitivi: il tentativo successivo va eseguito davvero
+        if (!res.writableFinished || res.statusCode === 401 || res.statusCode >= 500) {
+            entries.delete(id);
+            return resolve(undefined);
+        }
+        resolve({
+            status: res.statusCode,
+            contentType: res.getHeader('Content-Type')?.toString(),
+            etag: res.getHeader('ETag')?.toString(),
+            body,
+        });
+    });
+    next();
+}
@@ -12,12 +12,20 @@ import { File } from './entities/File';
 import { promises as fs } from 'fs';
 import { pathRepo } from './utilities';
 import { Path } from './entities/Path';
+import { requestId, prefixLogsWithRequestId } from './requestId';
+import { msgpackBodies } from './wire';
+import { idempotency } from './idempotency';
+import { startOrphanReaper } from './controllers/openController';
+import { startLeaseSweeper } from './controllers/leaseController';
 
 const app = express();
 const PORT = process.env.PORT || 3000;
 process.env.PORT = PORT.toString();
 process.env
 
+prefixLogsWithRequestId();
+app.use(requestId);
+app.use(msgpackBodies);
 app.use(express.json());
 
 app.listen(PORT, () => {
@@ -26,13 +34,18 @@ app.listen(PORT, () => {
 
 app.use(passport.initialize());
 
-// session in express
+// session in express: il cookie scade dopo una settimana di inattività, i client lo salvano
+// per rimontare senza login
+const SESSION_MAX_AGE = 7 * 24 * 60 * 60 * 1000;
 app.use(session({
   secret: "shh",
   resave: false,
-  saveUninitialized: false
+  saveUninitialized: false,
+  rolling: true,
+  cookie: { maxAge: SESSION_MAX_AGE },
 }));
 app.use(passport.authenticate('session'));
+app.use(idempotency);
 
 passport.use(new LocalStrategy(
   async function verify(username: string, password: string, cb) {
@@ -157,6 +170,8 @@ async function db() {
       await pathRepo.save(new_group_path);
       
     }
+    startOrphanReaper();
+    startLeaseSweeper();
 
   } catch (error) {
     console.error("Error during Data Source initialization: ", error);
@@ -0,0 +1,57 @@
+import { Request, Response, NextFunction } from 'express';
+
+// Limite ai login falliti: dopo MAX_FAILURES password sbagliate entro FAILURE_WINDOW l'utente viene bloccato
+// per LOCKOUT e ogni login riceve 429 con Retry-After. Le risposte dicono al client quanti tentativi restano
+// (Login-Attempts-Remaining), così può avvisare prima del blocco. Lo stato resta in memoria, per utente e
+// indirizzo del client: chi sbaglia la password di un altro da un'altra macchina non gli blocca l'accesso.
+const MAX_FAILURES = 5;
+const FAILURE_WINDOW = 15 * 60 * 1000;
+const LOCKOUT = 15 * 60 * 1000;
+const MAX_ENTRIES = 10000;
+
+interface Failures {
+    count: number;
+    first: number;
+    lockedUntil?: number;
+}
+
+const failures = new Map<string, Failures>();
+
+function purge(now: number) {
+    for (const [key, entry] of failures) {
+        if ((entry.lockedUntil ?? entry.first + FAILURE_WINDOW) <= now) failures.delete(key);
+    }
+}
+
+export function loginThrottle(req: Request, res: Response, next: NextFunction) {
+    const user = typeof req.body?.username === 'string' ? req.body.username : undefined;
+    if (!user) return next();
+    const key = `${user}\0${req.ip ?? ''}`;
+    const now = Date.now();
+    let entry = failures.get(key);
+    if (entry && (entry.lockedUntil ?? entry.first + FAILURE_WINDOW) <= now) {
+        failures.delete(key);
+        entry = undefined;
+    }
+    if (entry?.lockedUntil) {
+        const seconds = Math.ceil((entry.lockedUntil - now) / 1000);
+        console.log("[login] status 429: Too many failed logins for", user, "from", req.ip);
+        res.setHeader('Retry-After', String(seconds));
+        return res.status(429).json({ error: "ETOOMANY", message: `Too many failed logins for ${user}, retry in ${seconds} seconds` });
+    }
+    if (failures.size >= MAX_ENTRIES) purge(now);
+
+    // tentativi rimasti se questo fallisce
+    res.setHeader('Login-Attempts-Remaining', String(MAX_FAILURES - (entry?.count ?? 0) - 1));
+    res.on('finish', () => {
+        if (res.statusCode === 401) {
+            const current = failures.get(key) ?? { count: 0, first: now };
+            current.count += 1;
+            if (current.count >= MAX_FAILURES) current.lockedUntil = Date.now() + LOCKOUT;
+            failures.set(key, current);
+        } else if (res.statusCode < 400) {
+            failures.delete(key);
+        }
+    });
+    next();
+}
@@ -0,0 +1,25 @@
+import { AsyncLocalStorage } from 'async_hooks';
+import { randomUUID } from 'crypto';
+import { Request, Response, NextFunction } from 'express';
+
+// X-Request-Id inviato dal client (o generato qui): viene ripetuto nella risposta e anteposto
+// a ogni riga di log scritta durante la richiesta, così un errore visto dal client si ritrova nel log
+const requestIds = new AsyncLocalStorage<string>();
+
+export function requestId(req: Request, res: Response, next: NextFunction) {
+    const header = req.get('X-Request-Id');
+    const id = header && /^[\w.-]{1,64}$/.test(header) ? header : randomUUID();
+    res.setHeader('X-Request-Id', id);
+    requestIds.run(id, () => next());
+}
+
+export function prefixLogsWithRequestId() {
+    for (const level of ['log', 'warn', 'error'] as const) {
+        const original = console[level].bind(console);
+        console[level] = (...args: any[]) => {
+            const id = requestIds.getStore();
+            if (id === undefined) original(...args);
+            else original(`[req ${id}]`, ...args);
+        };
+    }
+}
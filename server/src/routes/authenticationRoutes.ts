@@ -2,19 +2,37 @@ import { Router } from 'express';
 import { Express } from 'express-serve-static-core';
 import passport from 'passport';
 import { AuthenticationController } from '../controllers/authenticationController';
+import { AdminController } from '../controllers/adminController';
+import { loginThrottle } from '../loginThrottle';
+import { dropSessionLeases } from '../controllers/leaseController';
 
 const router = Router();
 const authenticationController = new AuthenticationController();
+const adminController = new AdminController();
 
 export function setRoutes(app: Express) {
 
     app.use('/', router);
     
-    router.post('/api/login', passport.authenticate('local'), authenticationController.login);
+    router.post('/api/login', loginThrottle, dropSessionLeases, passport.authenticate('local'), authenticationController.login);
     router.post('/api/signup', authenticationController.isLoggedIn, authenticationController.signup);
     router.post('/api/logout', authenticationController.logout);
+    router.post('/api/passwd', authenticationController.isLoggedIn, authenticationController.passwd);
     router.get('/api/me', authenticationController.isLoggedIn, authenticationController.logged);
+    router.get('/api/names', authenticationController.isLoggedIn, adminController.names);
 
     router.post('/api/group', authenticationController.isLoggedIn, authenticationController.newgroup);
+
+    const admin = [authenticationController.isLoggedIn, adminController.isAdmin];
+    router.get('/api/admin/users', ...admin, adminController.listUsers);
+    router.post('/api/admin/users', ...admin, adminController.addUser);
+    router.delete('/api/admin/users/:uid', ...admin, adminController.deleteUser);
+    router.get('/api/admin/groups', ...admin, adminController.listGroups);
+    router.post('/api/admin/groups', ...admin, adminController.addGroup);
+    router.delete('/api/admin/groups/:gid', ...admin, adminController.deleteGroup);
+    router.put('/api/admin/users/:uid/name', ...admin, adminController.setName);
+    router.put('/api/admin/groups/:gid/name', ...admin, adminController.setName);
+    router.put('/api/admin/groups/:gid/members/:uid', ...admin, adminController.setMember);
+    router.delete('/api/admin/groups/:gid/members/:uid', ...admin, adminController.setMember);
     
 }
\ No newline at end of file
@@ -5,21 +5,46 @@ import { ReadWriteController } from '../controllers/RWController';
 import { AttributeController } from '../controllers/attrController';
 import { Express } from 'express-serve-static-core';
 import { AuthenticationController } from '../controllers/authenticationController';
+import { LeaseController } from '../controllers/leaseController';
+import { SnapshotController, SNAPSHOT_HEADER } from '../controllers/snapshotController';
+import { BatchController } from '../controllers/batchController';
+import { OpenController } from '../controllers/openController';
+import { LockController } from '../controllers/lockController';
+import { MAX_CHUNK_SIZE } from '../utilities';
 
 const router = Router();
 const fileController = new FileController();
 const rwController = new ReadWriteController();
 const attrController = new AttributeController();
+const leaseController = new LeaseController();
+const snapshotController = new SnapshotController();
+const batchController = new BatchController();
+const openController = new OpenController();
+const lockController = new LockController();
 const isLoggedIn = (new AuthenticationController).isLoggedIn;
 
+// richieste con l'header X-Snapshot: lettura di una copia passata, in sola lettura
+const snapshotRouter = Router();
+snapshotRouter.get('/api/files/:ino/attributes', isLoggedIn, snapshotController.getattr);
+snapshotRouter.get('/api/directories/:parentIno/entries/lookup', isLoggedIn, snapshotController.lookup);
+snapshotRouter.get('/api/directories/:ino/entries', isLoggedIn, snapshotController.readdir);
+snapshotRouter.get('/api/files/stream/:ino', isLoggedIn, snapshotController.readStream);
+snapshotRouter.get('/api/files/:ino', isLoggedIn, snapshotController.read);
+snapshotRouter.get('/api/symlinks/:ino', isLoggedIn, snapshotController.readlink);
+snapshotRouter.use(snapshotController.fallback);
+
 export function setRoutes(app: Express) {
     app.use('/', router);
 
+    router.use((req, res, next) => req.header(SNAPSHOT_HEADER) ? snapshotRouter(req, res, next) : next());
+
     router.get('/api/files/:ino/attributes', isLoggedIn, attrController.getattr);
     router.patch('/api/files/:ino/attributes', isLoggedIn, attrController.setattr);
 
     router.get('/api/directories/:parentIno/entries/lookup', isLoggedIn, attrController.lookup);    
     router.get('/api/directories/:ino/entries', isLoggedIn, attrController.readdir);
+    router.get('/api/directories/:ino/search', isLoggedIn, attrController.search);
+    router.get('/api/files/:ino/usage', isLoggedIn, attrController.usage);
 
     router.post('/api/directories/:parentIno/dirs/:name', isLoggedIn, fileController.mkdir);
     router.delete('/api/directories/:parentIno/dirs/:name', isLoggedIn, fileController.rmdir);
@@ -27,16 +52,32 @@ export function setRoutes(app: Express) {
     router.delete('/api/directories/:parentIno/files/:name', isLoggedIn, fileController.unlink);
 
     router.patch('/api/directories/:oldParentIno/entries/:oldName', isLoggedIn, fileController.rename); // rename
+    router.put('/api/directories/:parentIno/files/:name/content', isLoggedIn, fileController.replaceContent); // salvataggio atomico
 
     router.put('/api/files/stream/:ino', isLoggedIn, rwController.writeStream);
     router.get('/api/files/stream/:ino', isLoggedIn, rwController.readStream);
-    router.put('/api/files/:ino', isLoggedIn, express.raw({type:'application/octet-stream', limit: '1gb'}), rwController.write);
+    router.put('/api/files/:ino', isLoggedIn, express.raw({type:'application/octet-stream', limit: MAX_CHUNK_SIZE}), rwController.write);
+    router.post('/api/files/:ino/append', isLoggedIn, express.raw({type:'application/octet-stream', limit: MAX_CHUNK_SIZE}), rwController.append);
+    router.post('/api/batch', isLoggedIn, express.raw({type:'multipart/form-data', limit: MAX_CHUNK_SIZE}), batchController.writes); // più write piccole insieme
     router.get('/api/files/:ino', isLoggedIn, rwController.read);
+    router.get('/api/files/:ino/blocks', isLoggedIn, rwController.blockHashes);
 
     router.post('/api/links/:targetIno', isLoggedIn, fileController.hardlink);
     router.post('/api/symlinks', isLoggedIn, fileController.symlink);
     router.get('/api/symlinks/:ino', isLoggedIn, fileController.readlink);
 
     router.get('/api/size', isLoggedIn, attrController.fsSize);
+    router.get('/api/limits', isLoggedIn, attrController.limits);
+    router.get('/api/capabilities', isLoggedIn, attrController.capabilities);
+
+    router.post('/api/files/:ino/lease', isLoggedIn, leaseController.acquire);
+    router.delete('/api/files/:ino/lease', isLoggedIn, leaseController.release);
+    router.get('/api/leases/recalls', isLoggedIn, leaseController.recalls);
+
+    router.put('/api/files/:ino/open', isLoggedIn, openController.open);
+    router.delete('/api/files/:ino/open', isLoggedIn, openController.close);
+
+    router.get('/api/files/:ino/locks', isLoggedIn, lockController.test);
+    router.post('/api/files/:ino/locks', isLoggedIn, lockController.set);
     
 }
\ No newline at end of file
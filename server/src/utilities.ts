@@ -14,6 +14,46 @@ export const userRepo = AppDataSource.getRepository(User);
 export const groupRepo = AppDataSource.getRepository(Group)
 export const pathRepo = AppDataSource.getRepository(Path);
 
+// limiti sulle richieste, comunicati ai client da GET /api/limits
+export const MAX_CHUNK_SIZE = 1024 * 1024 * 1024; // body massimo di una write non in streaming
+export const MAX_BLOCK_SIZE = 1024 * 1024; // blocco massimo per gli hash dei blocchi
+export const MAX_DIR_PAGE = 10000; // voci massime restituite da una pagina di readdir
+export const FILE_FLAGS = 0x7; // flag chflags noti ai client: immutable (1), append-only (2), nodump (4)
+export const FILE_FLAG_IMMUTABLE = 0x1;
+export const FILE_FLAG_APPEND = 0x2;
+export const MODE_BITS = 0o7777; // permessi con setuid (0o4000), setgid (0o2000) e sticky (0o1000)
+export const S_ISGID = 0o2000;
+export const S_ISVTX = 0o1000;
+
+// file cancellati ancora aperti da qualche client (vedi openController), fuori dalle directory visibili
+export const ORPHANS_DIR = "/.rfs-orphans";
+
+export const ADMIN_UID = 5000; // amministratore creato al primo avvio, unico abilitato alle API /api/admin
+
+// versione del protocollo e funzionalità annunciate da GET /api/capabilities
+export const PROTOCOL_VERSION = 1;
+export const CAPABILITIES = {
+  streams: true,
+  links: true,
+  xattrs: false,
+  conditional: true, // If-Match e If-Modified-Since
+  pagination: true, // readdir con cursore
+  msgpack: true, // corpi in application/msgpack
+  admin: true, // gestione di utenti e gruppi (/api/admin)
+  idempotency: true, // Idempotency-Key sulle richieste che modificano
+  batch: true, // più write in un'unica richiesta multipart (/api/batch)
+  dirSizes: true, // la dimensione di una directory è il numero di voci che contiene
+  names: true, // nomi di utenti e gruppi (/api/names)
+  openHandles: true, // un file cancellato resta accessibile finché i client che lo hanno aperto non lo chiudono
+  locks: true, // lock su intervalli di byte condivisi fra i client (/api/files/:ino/locks)
+};
+
+// pattern con wildcard `*` e `?` (ricerche di Windows) -> RegExp sull'intero nome
+export function wildcardToRegExp(pattern: string): RegExp {
+    const body = Array.from(pattern).map(c => c === '*' ? '.*' : c === '?' ? '.' : c.replace(/[.+^${}()|[\]\\]/g, '\\$&')).join('');
+    return new RegExp(`^${body}$`, 'su');
+}
+
 export function toFsPath(dbPath: string): string {
   return path_manipulator.join(process.env.FS_ROOT ?? "/", dbPath);
 }
@@ -24,7 +64,18 @@ export function parseIno(s:any): string|null{
     return s.toString();
 }
 
-export function toEntryJson(file:File, stats: Stats|BigIntStats, path: Path) {
+// la dimensione di una directory sul disco (4096 su ext4) non dice nulla al client: riportiamo il numero di voci,
+// che il client può aggiornare da solo quando crea o cancella
+async function entrySize(stats: Stats|BigIntStats, path: Path): Promise<string> {
+    if (!stats.isDirectory()) return stats.size.toString();
+    try {
+        return (await fs.readdir(toFsPath(path.path))).length.toString();
+    } catch {
+        return stats.size.toString();
+    }
+}
+
+export async function toEntryJson(file:File, stats: Stats|BigIntStats, path: Path) {
     return {
         ino: file.ino.toString(),
         name: path_manipulator.basename(path.path),
@@ -33,24 +84,60 @@ export function toEntryJson(file:File, stats: Stats|BigIntStats, path: Path) {
         permissions: file.permissions,
         owner: file.owner.uid,
         group: file.group?.gid && null,
-        size: stats.size.toString(),
+        size: await entrySize(stats, path),
         atime: stats.atime.getTime(),
         mtime: stats.mtime.getTime(),
         ctime: stats.ctime.getTime(),
         btime: stats.birthtime.getTime(),
 
         nlinks: Number(stats.nlink),
+        etag: etagOf(stats),
+        flags: file.flags ?? 0,
+        fileFlags: file.fileFlags ?? 0,
     };
 }
 
+// byte da mandare in una lettura in streaming: da `offset` fino alla fine, o solo `length` byte se indicato.
+// null se offset o length non sono validi
+export function streamRange(query: any): { start: number, end?: number } | null {
+    const start = Number(query.offset) || 0;
+    if (start < 0) return null;
+    if (query.length === undefined) return { start };
+    const length = Number(query.length);
+    if (!Number.isSafeInteger(length) || length <= 0) return null;
+    return { start, end: start + length - 1 }; // end di createReadStream è incluso
+}
+
+// versione del contenuto usata per If-Match: cambia con size o mtime
+export function etagOf(stats: Stats|BigIntStats): string {
+    return `"${stats.size.toString(16)}-${stats.mtime.getTime().toString(16)}"`;
+}
+
+// true se la richiesta non ha If-Match oppure se combacia con lo stato attuale del file
+export async function ifMatchSatisfied(ifMatch: string | undefined, fullFsPath: string): Promise<boolean> {
+    if (!ifMatch || ifMatch.trim() === "*")
+        return true;
+    const stats = await fs.lstat(fullFsPath);
+    const current = etagOf(stats);
+    return ifMatch.split(",").some(tag => tag.trim() === current);
+}
+
 export function isBadName(name: any): boolean {
-    return typeof name !== "string" || name.length === 0 || name === "." || name === ".." || name.includes("/");
+    return typeof name !== "string" || name.length === 0 || name === "." || name === ".." || name.includes("/")
+        || `/${name}` === ORPHANS_DIR; // riservato, anche fuori dalla radice per semplicità
 }
 
 export function childPathOf(parentPath: string, name: string): string {
     return parentPath === "/" ? `/${name}` : `${parentPath}/${name}`;
 }
 
+// in una directory sticky (come /tmp) solo il proprietario della voce o della directory la può cancellare o rinominare
+export function sticky_allows(parent: File, child: File, user: User): boolean {
+    if (user.uid == ADMIN_UID || (parent.permissions & S_ISVTX) === 0)
+        return true;
+    return user.uid === child.owner?.uid || user.uid === parent.owner?.uid;
+}
+
 // operation:  0: read, 1: write, 2: execute
 export function has_permissions(file: File, operation: number, user: User): boolean {
     let mask = 0;
@@ -79,6 +166,41 @@ export function has_permissions(file: File, operation: number, user: User): bool
     return false;
 }
 
+// come chattr +i / +a: un file immutabile non si scrive, uno append-only solo in coda. Senza offset la
+// scrittura è un append deciso dal server, con offset deve partire almeno dalla dimensione attuale
+export async function writeDenied(file: File, fullFsPath: string, offset?: number): Promise<boolean> {
+    const flags = file.fileFlags ?? 0;
+    if (flags & FILE_FLAG_IMMUTABLE)
+        return true;
+    if (!(flags & FILE_FLAG_APPEND) || offset === undefined)
+        return false;
+    const { size } = await fs.stat(fullFsPath);
+    return offset < size;
+}
+
+// spazio occupato da un sottoalbero: i file con più hard link vengono contati una volta sola
+export async function getDirectoryUsage(dirPath: string, seen: Set<bigint> = new Set()): Promise<{ bytes: number, files: number, dirs: number }> {
+    const usage = { bytes: 0, files: 0, dirs: 1 };
+    const entries = await fs.readdir(dirPath, { withFileTypes: true });
+
+    for (const entry of entries) {
+        const fullPath = path.join(dirPath, entry.name);
+        if (entry.isDirectory()) {
+            const sub = await getDirectoryUsage(fullPath, seen);
+            usage.bytes += sub.bytes;
+            usage.files += sub.files;
+            usage.dirs += sub.dirs;
+        } else if (entry.isFile()) {
+            const stats = await fs.lstat(fullPath, { bigint: true });
+            if (seen.has(stats.ino)) continue;
+            seen.add(stats.ino);
+            usage.bytes += Number(stats.size);
+            usage.files += 1;
+        }
+    }
+    return usage;
+}
+
 export async function getDirectorySize(dirPath: string): Promise<number> {
     let totalSize = 0;
     const entries = await fs.readdir(dirPath, { withFileTypes: true });
@@ -0,0 +1,51 @@
+import express, { Request, Response, NextFunction } from 'express';
+import { encode, decode } from '@msgpack/msgpack';
+import { MAX_CHUNK_SIZE } from './utilities';
+
+// codifica binaria dei corpi, alternativa al JSON: le richieste con Content-Type application/msgpack
+// vengono decodificate in req.body, e res.json risponde in MessagePack ai client che lo accettano
+export const MSGPACK = 'application/msgpack';
+
+const rawMsgpack = express.raw({ type: MSGPACK, limit: MAX_CHUNK_SIZE });
+
+export function msgpackBodies(req: Request, res: Response, next: NextFunction) {
+    if (req.accepts(['application/json', MSGPACK]) === MSGPACK) {
+        res.json = (body: any) => {
+            res.type(MSGPACK);
+            return res.send(Buffer.from(encode(body, { ignoreUndefined: true, useBigInt64: true })));
+        };
+    }
+    if (!req.is(MSGPACK)) return next();
+    rawMsgpack(req, res, (err?: any) => {
+        if (err) return next(err);
+        try {
+            req.body = decode(req.body as Buffer);
+        } catch (e: any) {
+            console.log("[msgpack] status 400: Invalid body,", e?.message ?? e);
+            return res.status(400).json({ error: "EINVAL", message: "Invalid MessagePack body" });
+        }
+        next();
+    });
+}
+
+// parti di un corpo multipart/form-data, per nome; null se il corpo non è ben formato
+export function parseMultipart(body: Buffer, contentType: string | undefined): Map<string, Buffer> | null {
+    const match = /boundary=(?:"([^"]+)"|([^;\s]+))/i.exec(contentType ?? '');
+    if (!match) return null;
+    const delimiter = Buffer.from(`--${match[1] ?? match[2]}`);
+    const parts = new Map<string, Buffer>();
+    let pos = body.indexOf(delimiter);
+    if (pos < 0) return null;
+    for (;;) {
+        pos += delimiter.length;
+        if (body.subarray(pos, pos + 2).toString() === '--') return parts;
+        const headersEnd = body.indexOf('\r\n\r\n', pos);
+        if (headersEnd < 0) return null;
+        const next = body.indexOf(delimiter, headersEnd + 4);
+        if (next < 0) return null;
+        const name = /name="([^"]*)"/i.exec(body.subarray(pos, headersEnd).toString('utf8'))?.[1];
+        // i dati finiscono con il CRLF che precede il delimitatore successivo
+        if (name !== undefined) parts.set(name, body.subarray(headersEnd + 4, next - 2));
+        pos = next;
+    }
+}